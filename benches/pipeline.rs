@@ -0,0 +1,228 @@
+//! Throughput and per-chunk latency of the streaming pipeline, driven
+//! through the library API. Runs in dry run mode, so documents are built
+//! and serialized as usual but never leave the process.
+//!
+//! Run with `cargo bench`, or `cargo bench -- gzip` for a single group.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prism::config::Config;
+use prism::Prism;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Size of the chunks bodies are received in, as a proxy would pass them.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+const SIZES: [(&str, usize); 3] = [
+    ("1KB", 1024),
+    ("100KB", 100 * 1024),
+    ("10MB", 10 * 1024 * 1024),
+];
+
+/// Markup-like payload, which compresses about as well as real pages.
+fn compressible(size: usize) -> Vec<u8> {
+    const WORDS: [&str; 8] = [
+        "<div class=\"item\">",
+        "prism ",
+        "streaming ",
+        "response ",
+        "body ",
+        "<a href=\"/catalog/item\">",
+        "</a>",
+        "</div>\n",
+    ];
+    let mut body = Vec::with_capacity(size + 32);
+    let mut state = 1u32;
+    while body.len() < size {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        body.extend_from_slice(WORDS[(state >> 16) as usize % WORDS.len()].as_bytes());
+    }
+    body.truncate(size);
+    body
+}
+
+/// Pseudo-random payload, which does not compress, like media or archives.
+fn incompressible(size: usize) -> Vec<u8> {
+    let mut body = Vec::with_capacity(size + 8);
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    while body.len() < size {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        body.extend_from_slice(&state.to_le_bytes());
+    }
+    body.truncate(size);
+    body
+}
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn prism() -> Prism {
+    let config = Config {
+        dry_run: true,
+        ..Config::default()
+    };
+    Prism::new(config).expect("benchmark configuration is valid")
+}
+
+fn headers(gzipped: bool) -> &'static [(&'static str, &'static str)] {
+    if gzipped {
+        &[("Content-Type", "text/html"), ("Content-Encoding", "gzip")]
+    } else {
+        &[("Content-Type", "text/html")]
+    }
+}
+
+/// Runs a whole transaction: begin, receive the body chunk by chunk while
+/// handing output back, done, and drain until finished. Returns the bytes
+/// handed back.
+fn lifecycle(prism: &Prism, id: i64, body: &[u8], gzipped: bool, output: &mut [u8]) -> usize {
+    let mut handle = prism.begin(id, "GET", "http://bench.example.com/page", headers(gzipped));
+    handle.status(200);
+    let mut sent = 0;
+    for chunk in body.chunks(CHUNK_SIZE) {
+        handle.receive(chunk).unwrap();
+        sent += handle.poll_output(output);
+    }
+    handle.done();
+    while !handle.finished() {
+        sent += handle.poll_output(output);
+    }
+    sent
+}
+
+fn gzip_bodies(c: &mut Criterion) {
+    let prism = prism();
+    let mut output = vec![0; CHUNK_SIZE];
+    let mut id = 0;
+    let mut group = c.benchmark_group("gzip");
+    for (label, size) in SIZES {
+        if size >= 1024 * 1024 {
+            group.sample_size(10);
+        }
+        group.throughput(Throughput::Bytes(size as u64));
+        for (kind, payload) in [
+            ("compressible", compressible(size)),
+            ("incompressible", incompressible(size)),
+        ] {
+            let body = gzip(&payload);
+            group.bench_with_input(BenchmarkId::new(kind, label), &body, |b, body| {
+                b.iter(|| {
+                    id += 1;
+                    black_box(lifecycle(&prism, id, body, true, &mut output))
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn identity_bodies(c: &mut Criterion) {
+    let prism = prism();
+    let mut output = vec![0; CHUNK_SIZE];
+    let mut id = 0;
+    let mut group = c.benchmark_group("identity");
+    for (label, size) in SIZES {
+        if size >= 1024 * 1024 {
+            group.sample_size(10);
+        }
+        group.throughput(Throughput::Bytes(size as u64));
+        let body = compressible(size);
+        group.bench_with_input(BenchmarkId::from_parameter(label), &body, |b, body| {
+            b.iter(|| {
+                id += 1;
+                black_box(lifecycle(&prism, id, body, false, &mut output))
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A large gzip body received whole before any output is polled, so the
+/// decoder reads its input from a deep queue of chunks.
+fn queued_input(c: &mut Criterion) {
+    let prism = prism();
+    let mut output = vec![0; CHUNK_SIZE];
+    let mut id = 0;
+    let size = 10 * 1024 * 1024;
+    let body = gzip(&compressible(size));
+    let mut group = c.benchmark_group("queued");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(size as u64));
+    group.bench_function("gzip/10MB", |b| {
+        b.iter(|| {
+            id += 1;
+            let mut handle = prism.begin(id, "GET", "http://bench.example.com/page", headers(true));
+            for chunk in body.chunks(CHUNK_SIZE) {
+                handle.receive(chunk).unwrap();
+            }
+            handle.done();
+            let mut sent = 0;
+            while !handle.finished() {
+                sent += handle.poll_output(&mut output);
+            }
+            black_box(sent)
+        })
+    });
+    group.finish();
+}
+
+/// Time to receive one chunk and hand back what it produced, in the middle
+/// of a large body. Transactions are renewed whenever the fixture runs out,
+/// outside of the measured time.
+fn chunk_latency(c: &mut Criterion) {
+    let prism = prism();
+    let mut output = vec![0; CHUNK_SIZE];
+    let mut id = 0;
+    let payload = compressible(10 * 1024 * 1024);
+    let mut group = c.benchmark_group("chunk");
+    group.throughput(Throughput::Bytes(CHUNK_SIZE as u64));
+    for (kind, gzipped, body) in [("gzip", true, gzip(&payload)), ("identity", false, payload)] {
+        group.bench_with_input(BenchmarkId::from_parameter(kind), &body, |b, body| {
+            b.iter_custom(|iterations| {
+                let mut elapsed = Duration::ZERO;
+                let mut chunks = body.chunks(CHUNK_SIZE);
+                id += 1;
+                let mut handle =
+                    prism.begin(id, "GET", "http://bench.example.com/page", headers(gzipped));
+                for _ in 0..iterations {
+                    let chunk = match chunks.next() {
+                        Some(chunk) => chunk,
+                        None => {
+                            chunks = body.chunks(CHUNK_SIZE);
+                            id += 1;
+                            handle = prism.begin(
+                                id,
+                                "GET",
+                                "http://bench.example.com/page",
+                                headers(gzipped),
+                            );
+                            chunks.next().unwrap()
+                        }
+                    };
+                    let start = Instant::now();
+                    handle.receive(chunk).unwrap();
+                    black_box(handle.poll_output(&mut output));
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    gzip_bodies,
+    identity_bodies,
+    queued_input,
+    chunk_latency
+);
+criterion_main!(benches);
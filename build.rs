@@ -0,0 +1,64 @@
+#[allow(dead_code)]
+#[path = "src/error.rs"]
+mod error;
+
+use std::path::Path;
+use std::process::Command;
+
+/// Header with the error codes, for C callers of the library.
+const HEADER: &str = "include/prism_errors.h";
+/// Header with the exported functions and types. The committed copy is only
+/// replaced when `PRISM_UPDATE_HEADER` is set, tests check it against the
+/// one generated in `OUT_DIR`.
+const BINDINGS: &str = "include/prism.h";
+
+/// Short hash of the commit being built, or `unknown` outside of a git
+/// checkout.
+fn git_hash() -> String {
+    Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Generates the header declaring the exported functions, see
+/// `cbindgen.toml`.
+fn bindings() {
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let bindings = match cbindgen::generate(".") {
+        Ok(bindings) => bindings,
+        Err(e) => {
+            println!("cargo:warning=Failed generating {}: {}", BINDINGS, e);
+            return;
+        }
+    };
+    // Both only written when changed.
+    bindings.write_to_file(Path::new(&out_dir).join("prism.h"));
+    if std::env::var_os("PRISM_UPDATE_HEADER").is_some() {
+        bindings.write_to_file(BINDINGS);
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=PRISM_UPDATE_HEADER");
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+    println!("cargo:rustc-env=PRISM_GIT_HASH={}", git_hash());
+
+    let header = error::c_header();
+    // Only written when changed, to keep the file's mtime stable.
+    if std::fs::read_to_string(HEADER).ok().as_deref() != Some(header.as_str()) {
+        std::fs::create_dir_all("include").unwrap();
+        std::fs::write(HEADER, header).unwrap();
+    }
+    bindings();
+}
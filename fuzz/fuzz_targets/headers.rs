@@ -0,0 +1,69 @@
+//! Passes arbitrary header names and values, URIs and methods through the
+//! exported functions, as the adapter would hand them over from the origin.
+//!
+//! The input is split on NUL bytes, which C strings cannot hold: the first
+//! part is the URI, the second the method, the rest alternate between
+//! header names and values. Headers at even positions are reported before
+//! `uri()`, those at odd ones after. A short body follows, to run the
+//! transaction through with the headers it ended up with.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prism::memory;
+use std::ffi::{c_void, CString};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Once;
+
+const BODY: &[u8] = b"<html><body><a href=\"http://fuzz.example.com/\">prism</a></body></html>";
+
+static CONFIGURE: Once = Once::new();
+static ID: AtomicI64 = AtomicI64::new(0);
+
+fn configure() {
+    CONFIGURE.call_once(|| {
+        let path = std::env::temp_dir().join(format!("prism-fuzz-{}.toml", std::process::id()));
+        std::fs::write(&path, "[backend]\ntype = \"memory\"\n").unwrap();
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(prism::configure(path.as_ptr()), 0);
+    });
+}
+
+fn string(bytes: &[u8]) -> CString {
+    CString::new(bytes).unwrap()
+}
+
+fn drain(id: i64) {
+    while prism::send(id, 0, 0).size > 0 {}
+}
+
+fuzz_target!(|data: &[u8]| {
+    configure();
+    let mut parts = data.split(|byte| *byte == 0).map(string);
+    let uri = parts.next().unwrap_or_default();
+    let method = parts.next().unwrap_or_default();
+    let mut headers = Vec::new();
+    while let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+        headers.push((name, value));
+    }
+
+    let id = ID.fetch_add(1, Ordering::Relaxed);
+    for (name, value) in headers.iter().step_by(2) {
+        prism::header(id, name.as_ptr(), value.as_ptr());
+    }
+    prism::status(id, 200);
+    prism::uri(id, uri.as_ptr(), 1, method.as_ptr());
+    for (name, value) in headers.iter().skip(1).step_by(2) {
+        prism::header(id, name.as_ptr(), value.as_ptr());
+    }
+    for (name, _) in &headers {
+        prism::get_response_header(id, name.as_ptr());
+    }
+
+    prism::receive(id, BODY.as_ptr() as *const c_void, BODY.len());
+    drain(id);
+    prism::done(id);
+    drain(id);
+    prism::cleanup(id);
+    memory::clear();
+});
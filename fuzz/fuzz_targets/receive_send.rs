@@ -0,0 +1,115 @@
+//! Drives a transaction through `receive()` and `send()` with arbitrary
+//! bodies split at arbitrary places.
+//!
+//! The first byte of the input picks the response:
+//!
+//! - bits 0-2: the `Content-Encoding`, from `ENCODINGS`
+//! - bit 3: the body is gzip compressed first, so decoding goes past the
+//!   header
+//! - bit 4: the body is prefixed with a gzip header, so it decodes for a
+//!   while before failing
+//! - bit 5: `text/html`, which the rewrite rule applies to
+//! - bit 6: output is polled after every chunk rather than only at the end
+//!
+//! The second seeds the chunk sizes, from 0 to 1024 bytes. The rest is the
+//! body.
+
+#![no_main]
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use libfuzzer_sys::fuzz_target;
+use prism::config::Config;
+use prism::{memory, Prism, TransactionHandle};
+use std::io::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::OnceLock;
+
+const ENCODINGS: [Option<&str>; 8] = [
+    None,
+    Some("gzip"),
+    Some("GZIP"),
+    Some("gzip, identity"),
+    Some("identity"),
+    Some("br"),
+    Some("x-gzip"),
+    Some(""),
+];
+/// Member header of a gzip stream, with no optional fields.
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0x00, 0xff];
+
+static PRISM: OnceLock<Prism> = OnceLock::new();
+static ID: AtomicI64 = AtomicI64::new(0);
+
+fn prism() -> &'static Prism {
+    PRISM.get_or_init(|| {
+        let mut config = Config::load(None).unwrap();
+        config.backend.kind = "memory".to_string();
+        let prism = Prism::new(config).unwrap();
+        prism.rewrite_rule(b"http://".to_vec(), b"https://".to_vec());
+        prism
+    })
+}
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn poll(handle: &mut TransactionHandle, buffer: &mut [u8]) {
+    while handle.poll_output(buffer) > 0 {}
+}
+
+fuzz_target!(|data: &[u8]| {
+    let [flags, seed, body @ ..] = data else {
+        return;
+    };
+
+    let mut headers = vec![(
+        "Content-Type",
+        if flags & 0x20 != 0 {
+            "text/html"
+        } else {
+            "application/octet-stream"
+        },
+    )];
+    if let Some(encoding) = ENCODINGS[usize::from(flags & 0x07)] {
+        headers.push(("Content-Encoding", encoding));
+    }
+    let mut body = if flags & 0x08 != 0 {
+        gzip(body)
+    } else {
+        body.to_vec()
+    };
+    if flags & 0x10 != 0 {
+        body.splice(0..0, GZIP_HEADER);
+    }
+
+    let id = ID.fetch_add(1, Ordering::Relaxed);
+    let mut handle = prism().begin(id, "GET", "http://fuzz.example.com/", &headers);
+    handle.status(200);
+
+    let mut buffer = vec![0; 16 * 1024];
+    let mut state = u32::from(*seed) | 0x100;
+    let mut rest = &body[..];
+    while !rest.is_empty() {
+        // xorshift, for chunk sizes that vary without using up the input.
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let size = (state % 1025) as usize;
+        let (chunk, tail) = rest.split_at(size.min(rest.len()));
+        let _ = handle.receive(chunk);
+        rest = tail;
+        if flags & 0x40 != 0 {
+            poll(&mut handle, &mut buffer);
+        }
+    }
+    handle.done();
+    while !handle.finished() {
+        poll(&mut handle, &mut buffer);
+    }
+    drop(handle);
+    memory::clear();
+});
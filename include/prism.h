@@ -0,0 +1,225 @@
+/* Generated from the exported functions by build.rs, do not edit. */
+
+#ifndef PRISM_H
+#define PRISM_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include "prism_errors.h"
+
+typedef struct Chunk {
+  size_t size;
+  /**
+   * Points to `size` bytes, which stay valid as long as each function
+   * returning a chunk documents.
+   */
+  const void *bytes;
+} Chunk;
+
+/**
+ * A chunk returned by `send_checked()`: a `Chunk`, followed by the CRC32
+ * of its bytes.
+ */
+typedef struct CheckedChunk {
+  size_t size;
+  /**
+   * Points to `size` bytes, valid as with `send()`.
+   */
+  const void *bytes;
+  /**
+   * CRC32 (IEEE, as zlib computes it) of the bytes, `0` for none.
+   */
+  uint32_t crc32;
+} CheckedChunk;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Attaches caller supplied metadata to a live transaction, persisted in
+ * its `annotations` field. Number and size of annotations are capped.
+ */
+void annotate(int64_t id, const char *key, const char *value);
+
+/**
+ * Blocks a transaction: from now on `send()` returns a block page instead of
+ * the origin content, while the original body is still captured and
+ * persisted along with the reason.
+ */
+void block(int64_t id, const char *reason);
+
+void cleanup(int64_t id);
+
+/**
+ * Records the address of the client a transaction is made for.
+ */
+void client_address(int64_t id, const char *address);
+
+/**
+ * Returns the problems found by the last configuration load, one
+ * `path: reason` per line, or nothing. The returned bytes stay valid until
+ * the next configuration load.
+ */
+Chunk config_errors(void);
+
+/**
+ * Loads the configuration file at `path`, layered over the built-in
+ * defaults and under the `PRISM_*` environment variables. Must be called
+ * before `init()` to take effect everywhere. Returns `0`, or
+ * `PRISM_E_INVALID_CONFIG` with the problems available from
+ * `config_errors()`, in which case the previous configuration is kept.
+ */
+int32_t configure(const char *path);
+
+void done(int64_t id);
+
+/**
+ * Writes a JSON snapshot of the live transactions, the persistence queue
+ * and the counters to `path`, for diagnosing a wedged proxy. Bodies are
+ * left out, and other calls wait for one transaction at most while it is
+ * taken. Returns `0`, or `PRISM_E_IO` when the file could not be written.
+ */
+int32_t dump_state(const char *path);
+
+/**
+ * Returns the value a response header should be changed to.
+ *
+ * A null `bytes` pointer means the header is unchanged; a non-null pointer
+ * with a size of 0 means the header must be removed. The `:status` name
+ * reports a replacement status code.
+ *
+ * When the body is altered, `Content-Length` starts out removed with
+ * `Transfer-Encoding: chunked` requested, and reports the number of bytes
+ * emitted once `send()` handed back the whole body. The value stays valid
+ * until the header changes again or the transaction is cleaned up.
+ */
+Chunk get_response_header(int64_t id, const char *name);
+
+/**
+ * Records a response header, as `response_header()` does. Kept for
+ * adapters that pass request and response headers alike.
+ */
+void header(int64_t id, const char *name, const char *value);
+
+void init(void);
+
+/**
+ * Creates Elasticsearch indices with the current mapping, named after the
+ * schema version, for the indices in use, and logs how to reindex into
+ * them. Meant to be run once by an operator after an upgrade changing the
+ * mapping. Returns `0`, or `PRISM_E_BACKEND_UNAVAILABLE` when an index
+ * could not be created.
+ */
+int32_t migrate_mapping(void);
+
+void receive(int64_t id, const void *chunk, size_t size);
+
+/**
+ * Feeds body bytes as `receive()` does, once their CRC32 matched `crc32`.
+ * Returns `0`, or the code of the error the bytes were dropped for:
+ * `PRISM_E_CHECKSUM_MISMATCH` when they were altered on the way.
+ */
+int32_t receive_checked(int64_t id, const void *chunk, size_t size, uint32_t crc32);
+
+/**
+ * Reads the configuration file again and applies it to transactions started
+ * from now on; transactions in flight keep the settings they started with.
+ * The file is validated fully first: on `PRISM_E_INVALID_CONFIG` nothing
+ * changes. Settings only read at init, such as the backend or the metrics
+ * address, are logged as needing a restart and keep their running value.
+ * The rules file is reloaded as well, see `reload_rules()`.
+ */
+int32_t reload_config(void);
+
+/**
+ * Compiles the rules file again and applies it to transactions started
+ * from now on. On `PRISM_E_INVALID_CONFIG` the rules in use are kept, and
+ * `config_errors()` names the offending rules.
+ */
+int32_t reload_rules(void);
+
+/**
+ * Records a header of the request a transaction is for. Request headers
+ * are persisted apart from the response headers, and only the latter
+ * tell the encoding of the body.
+ */
+void request_header(int64_t id, const char *name, const char *value);
+
+/**
+ * Records a response header of a transaction.
+ */
+void response_header(int64_t id, const char *name, const char *value);
+
+/**
+ * Registers a find/replace rule applied to textual response bodies of
+ * transactions started after this call.
+ */
+void rewrite_rule(const char *find, const char *replace);
+
+Chunk send(int64_t id, size_t _offset, size_t _size);
+
+/**
+ * Returns the next chunk of the body as `send()` does, along with the
+ * CRC32 of its bytes, for callers checking that what they forward is what
+ * was handed back.
+ */
+CheckedChunk send_checked(int64_t id, size_t _offset, size_t _size);
+
+/**
+ * Replaces the log filter, using the same `level,module=level` syntax as
+ * the `PRISM_LOG` environment variable. Invalid directives are ignored with
+ * a warning.
+ */
+void set_log_filter(const char *filter);
+
+/**
+ * Stops background services started by `init()`, and the decode workers.
+ * Transactions still in flight keep working, but metrics are no longer
+ * served nor summarized, and bodies are decoded in `send()`.
+ */
+void shutdown(void);
+
+/**
+ * Returns runtime statistics as a JSON document. The returned bytes stay
+ * valid until the next call.
+ */
+Chunk stats(void);
+
+/**
+ * Records the response status code of a transaction. It may be reported
+ * before or after `uri()`, like headers.
+ */
+void status(int64_t id, int64_t code);
+
+/**
+ * Enables or disables detailed, chunk level logging for one transaction.
+ * Transactions whose uri matches `PRISM_TRACE_URIS` are traced from the
+ * start.
+ */
+void trace(int64_t id, bool enabled);
+
+/**
+ * Begins a transaction, with the headers received so far. `mode` is `0`
+ * for REQMOD, `1` for RESPMOD and `2` for OPTIONS requests, which have no
+ * body: those are only acknowledged, `send()` reporting the end of the body
+ * right away, unless `sampling.options` asks for them to be persisted.
+ */
+void uri(int64_t id, const char *uri_str, int64_t mode, const char *method_str);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#ifdef __cplusplus
+static_assert(sizeof(Chunk) == 2 * sizeof(size_t), "Chunk layout changed");
+static_assert(alignof(Chunk) == alignof(size_t), "Chunk layout changed");
+static_assert(offsetof(CheckedChunk, crc32) == 2 * sizeof(size_t), "CheckedChunk layout changed");
+#else
+_Static_assert(sizeof(Chunk) == 2 * sizeof(size_t), "Chunk layout changed");
+_Static_assert(_Alignof(Chunk) == _Alignof(size_t), "Chunk layout changed");
+_Static_assert(offsetof(CheckedChunk, crc32) == 2 * sizeof(size_t), "CheckedChunk layout changed");
+#endif
+
+#endif /* PRISM_H */
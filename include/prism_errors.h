@@ -0,0 +1,28 @@
+/* Generated from src/error.rs by build.rs, do not edit. */
+#ifndef PRISM_ERRORS_H
+#define PRISM_ERRORS_H
+
+/* invalid argument */
+#define PRISM_E_INVALID_ARGUMENT 1
+/* unknown transaction */
+#define PRISM_E_UNKNOWN_TRANSACTION 2
+/* failed decoding body */
+#define PRISM_E_DECODE 3
+/* failed encoding body */
+#define PRISM_E_ENCODE 4
+/* persistence backend unavailable */
+#define PRISM_E_BACKEND_UNAVAILABLE 5
+/* persistence queue unavailable */
+#define PRISM_E_QUEUE_OVERFLOW 6
+/* internal panic */
+#define PRISM_E_PANIC 7
+/* invalid configuration */
+#define PRISM_E_INVALID_CONFIG 8
+/* failed writing a file */
+#define PRISM_E_IO 9
+/* transaction deadline passed */
+#define PRISM_E_TIMEOUT 10
+/* chunk checksum mismatch */
+#define PRISM_E_CHECKSUM_MISMATCH 11
+
+#endif
@@ -0,0 +1,932 @@
+//! Rust API over the transaction pipeline, for proxies embedding prism
+//! without going through the C exports. The exports are a thin layer over
+//! one shared `Prism`, so both behave the same.
+
+use crate::checksum;
+use crate::clock;
+use crate::config::{self, Config, ConfigError};
+use crate::decoding::DecodePool;
+use crate::document::Document;
+use crate::dump;
+use crate::error::PrismError;
+use crate::headers::{Direction, Headers, PendingHeaders};
+use crate::logging::{self, event, throttled, trace_transaction, Fields};
+use crate::metrics;
+use crate::mode::Mode;
+use crate::observer;
+use crate::recorder;
+use crate::rewrite::{BodyRewriter, ReplaceRewriter, ReplaceRule, RewriteChain};
+use crate::rules;
+use crate::scanner;
+use crate::shrink::Shrinker;
+use crate::stats;
+#[cfg(feature = "metrics")]
+use crate::statsd;
+use crate::transaction::{self, Transaction, CLIENT_HEADER, STATUS_HEADER};
+use crate::watchdog::Watchdog;
+use crate::worker::{self, PendingDocument, QueueSnapshot, Worker};
+use log::{info, Level};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Host the statistics of transactions without one in their uri go to.
+const UNKNOWN_HOST: &str = "unknown";
+
+/// Live transactions, and what they share.
+struct Registry {
+    responses: HashMap<i64, Transaction>,
+    /// Headers received before their transaction began.
+    headers: HashMap<i64, PendingHeaders>,
+    /// ICAP OPTIONS requests, acknowledged without a transaction as they
+    /// have no body.
+    options: HashSet<i64>,
+    rewrite_rules: Vec<ReplaceRule>,
+    worker: Worker,
+    /// Decodes bodies ahead of `send()`, when enabled.
+    decoders: Option<DecodePool>,
+    watchdog: Watchdog,
+    shrinker: Shrinker,
+}
+
+/// An instance of the pipeline, owning its transactions and persistence
+/// worker. Clones share the same instance, and can be used from any thread:
+/// calls are serialized by the instance.
+///
+/// The configuration stays process wide, as the backends and enrichment
+/// read it: `new()` installs it as the current one. Background services
+/// such as logging and metrics are started by `init()`.
+///
+/// Observers must not call back into the instance they observe.
+#[derive(Clone)]
+pub struct Prism {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Prism {
+    /// Validates and installs `config`, loads its rules file, starts a run,
+    /// see `clock::run_id()`, and a persistence worker.
+    pub fn new(config: Config) -> Result<Prism, Vec<ConfigError>> {
+        config::set(config.validated()?);
+        rules::reload()?;
+        clock::start_run();
+        Ok(Prism::with_current_config())
+    }
+
+    /// An instance using the configuration installed already.
+    pub(crate) fn with_current_config() -> Prism {
+        Prism {
+            registry: Arc::new(Mutex::new(Registry {
+                responses: HashMap::new(),
+                headers: HashMap::new(),
+                options: HashSet::new(),
+                rewrite_rules: Vec::new(),
+                worker: Worker::new(),
+                decoders: DecodePool::new(config::get().limits.decode_workers),
+                watchdog: Watchdog::new(),
+                shrinker: Shrinker::new(),
+            })),
+        }
+    }
+
+    /// Locks the registry. A panic in a previous call does not make the
+    /// instance unavailable.
+    fn registry(&self) -> MutexGuard<'_, Registry> {
+        match self.registry.lock() {
+            Ok(registry) => registry,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Begins a response transaction. The headers are the response headers
+    /// received so far; more can be added through the handle. The
+    /// transaction is cleaned up when the handle is dropped.
+    pub fn begin(
+        &self,
+        id: i64,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> TransactionHandle {
+        for (name, value) in headers {
+            self.header(id, Direction::Response, name.to_string(), value.to_string());
+        }
+        self.start(id, method, uri, Mode::RESPMOD);
+        TransactionHandle {
+            prism: self.clone(),
+            id,
+            output: Vec::new(),
+        }
+    }
+
+    /// Registers a find/replace rule applied to textual response bodies of
+    /// transactions started after this call.
+    pub fn rewrite_rule(&self, find: Vec<u8>, replace: Vec<u8>) {
+        let mut registry = self.registry();
+        info!(
+            "Registered rewrite rule #{} ({} -> {} bytes)",
+            registry.rewrite_rules.len(),
+            find.len(),
+            replace.len()
+        );
+        registry.rewrite_rules.push(ReplaceRule { find, replace });
+    }
+
+    /// Number of live transactions.
+    pub fn active_transactions(&self) -> usize {
+        self.registry().responses.len()
+    }
+
+    /// Number of transactions with headers received, but not begun yet.
+    pub(crate) fn pending_headers(&self) -> usize {
+        self.registry().headers.len()
+    }
+
+    /// Bytes of the headers received for transactions not begun yet.
+    pub(crate) fn pending_header_bytes(&self) -> usize {
+        self.registry()
+            .headers
+            .values()
+            .map(PendingHeaders::bytes)
+            .sum()
+    }
+
+    /// Ids of the live transactions, in no particular order.
+    pub(crate) fn transaction_ids(&self) -> Vec<i64> {
+        self.registry().responses.keys().copied().collect()
+    }
+
+    pub(crate) fn queue(&self) -> QueueSnapshot {
+        self.registry().worker.snapshot()
+    }
+
+    /// Writes a JSON snapshot of the live transactions, the persistence
+    /// queue and the counters to `path`, for diagnosing a wedged instance.
+    /// Bodies are left out. Transactions are read one at a time, so calls
+    /// made meanwhile wait for one transaction at most, and the snapshot may
+    /// not be consistent across transactions.
+    pub fn dump_state(&self, path: &Path) -> Result<(), PrismError> {
+        dump::write(self, path)
+    }
+
+    /// Creates indices with the current mapping for the ones whose mapping
+    /// predates it, see `Backend::migrate_mapping()`, and returns their
+    /// names. Reindexing and switching over are left to the operator, as
+    /// logged. Backends without a mapping have nothing to migrate.
+    pub fn migrate_mapping(&self) -> Result<Vec<String>, PrismError> {
+        match worker::backend(false) {
+            Some(backend) => backend.migrate_mapping(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub(crate) fn start(&self, id: i64, method: &str, uri: &str, mode: Mode) {
+        let mut registry = self.registry();
+        let registry = &mut *registry;
+        recorder::start(id, method, uri, mode);
+        if mode == Mode::OPTIONS && !config::get().sampling.options {
+            registry.headers.remove(&id);
+            registry.options.insert(id);
+            metrics::increment(&metrics::COUNTERS.options_requests);
+            event!(
+                Level::Debug,
+                Fields::transaction(id).uri(uri),
+                "Transaction {} is an OPTIONS request for {}, acknowledged without capture",
+                id,
+                uri
+            );
+            return;
+        }
+        metrics::increment(&metrics::TRANSACTIONS_STARTED);
+        let (headers, request_headers) = registry
+            .headers
+            .remove(&id)
+            .map(|pending| (pending.headers, pending.request_headers))
+            .unwrap_or_default();
+        let rewriters = rewriters(&registry.rewrite_rules, &headers);
+        let mut transaction = Transaction::new(
+            id,
+            method.to_string(),
+            uri.to_string(),
+            mode,
+            headers,
+            request_headers,
+            rewriters,
+        );
+        let config = transaction.config.clone();
+        let limits = &config.limits;
+        if let Some(max) = limits.max_transactions {
+            if registry.responses.len() >= max {
+                throttled!(
+                    Level::Warn,
+                    "max-transactions",
+                    "{} transactions in flight, relaying transaction {} without capture",
+                    registry.responses.len(),
+                    id
+                );
+                transaction.shed();
+            }
+        }
+        if let Some(budget) = limits.memory_budget {
+            let in_use = memory_in_use(registry);
+            if !transaction.shed && in_use >= budget {
+                throttled!(
+                    Level::Warn,
+                    "memory-budget",
+                    "{} bytes in use, above the budget of {}, relaying transaction {} without capture",
+                    in_use,
+                    budget,
+                    id
+                );
+                transaction.shed();
+            }
+        }
+        if transaction.shed {
+            metrics::increment(&metrics::COUNTERS.shed_transactions);
+        }
+        registry.responses.insert(id, transaction);
+        observer::notify(|observer| {
+            observer.on_transaction_start(&observer::TransactionStart { id, method, uri })
+        });
+        registry.watchdog.check(&mut registry.responses);
+        registry
+            .shrinker
+            .check(&mut registry.responses, &mut registry.headers);
+
+        event!(
+            Level::Info,
+            Fields::transaction(id).uri(uri),
+            "Transaction {} initialized with mode {} for {} uri {}",
+            id,
+            mode,
+            method,
+            uri
+        );
+    }
+
+    /// Adds a request or response header, to the transaction if it began
+    /// already. Values are truncated to `limits.max_header_value`, and
+    /// headers are dropped past `limits.max_header_bytes`, counting both
+    /// directions, or for a transaction not begun yet past
+    /// `limits.max_pending_headers`.
+    pub(crate) fn header(&self, id: i64, direction: Direction, name: String, mut value: String) {
+        let mut registry = self.registry();
+        let registry = &mut *registry;
+        let begun = registry.responses.contains_key(&id);
+        recorder::header(id, direction, &name, &value, begun);
+        if registry.options.contains(&id) {
+            return;
+        }
+        let config = config::get();
+        let limits = &config.limits;
+        if value.len() > limits.max_header_value {
+            let length = value.len();
+            transaction::truncate(&mut value, limits.max_header_value);
+            value.shrink_to_fit();
+            metrics::increment(&metrics::COUNTERS.header_values_truncated);
+            throttled!(
+                Level::Warn,
+                "header-limits",
+                "Truncated a header value of transaction {} from {} bytes to {}",
+                id,
+                length,
+                value.len()
+            );
+        }
+        if !begun {
+            // Expires the headers of transactions that never began.
+            registry
+                .shrinker
+                .check(&mut registry.responses, &mut registry.headers);
+            let pending = registry.headers.len();
+            if pending >= limits.max_pending_headers && !registry.headers.contains_key(&id) {
+                metrics::increment(&metrics::COUNTERS.pending_headers_dropped);
+                throttled!(
+                    Level::Warn,
+                    "header-limits",
+                    "Dropping a header of transaction {}, {} transactions have headers waiting for them to begin",
+                    id,
+                    pending
+                );
+                return;
+            }
+        }
+
+        let status = name == STATUS_HEADER;
+        let (response, request) = match registry.responses.get_mut(&id) {
+            Some(transaction) => (&mut transaction.headers, &mut transaction.request_headers),
+            None => {
+                let pending = registry
+                    .headers
+                    .entry(id)
+                    .or_insert_with(|| PendingHeaders {
+                        headers: Headers::new(),
+                        request_headers: Headers::new(),
+                        received: clock::now(),
+                    });
+                (&mut pending.headers, &mut pending.request_headers)
+            }
+        };
+        let held = response.bytes() + request.bytes();
+        // The status and client address are always kept.
+        if !name.starts_with(':') && held + name.len() + value.len() > limits.max_header_bytes {
+            metrics::increment(&metrics::COUNTERS.headers_dropped);
+            throttled!(
+                Level::Warn,
+                "header-limits",
+                "Dropping a header of {} bytes of transaction {}, its headers hold {} bytes already",
+                name.len() + value.len(),
+                id,
+                held
+            );
+            return;
+        }
+        // Pseudo-headers are prism's own, kept with the response whichever
+        // way they came.
+        let headers = match direction {
+            Direction::Request if !name.starts_with(':') => request,
+            _ => response,
+        };
+
+        // Pseudo-headers hold a single value, repeated header lines are combined.
+        if name.starts_with(':') {
+            headers.insert(name, value);
+        } else {
+            headers.append(name, value);
+        }
+        if let (true, Some(transaction)) = (status, registry.responses.get_mut(&id)) {
+            transaction.status_reported();
+        }
+    }
+
+    pub(crate) fn receive(&self, id: i64, data: &[u8]) -> Result<(), PrismError> {
+        let mut registry = self.registry();
+        let registry = &mut *registry;
+        match registry.responses.get_mut(&id) {
+            Some(buffer) => {
+                recorder::receive(id, data);
+                if expired(&registry.worker, buffer) {
+                    return Err(PrismError::Timeout);
+                }
+                let size = data.len();
+                trace_transaction!(
+                    buffer.trace,
+                    id,
+                    "received {} bytes{}",
+                    size,
+                    checksum_note(buffer, data)
+                );
+                metrics::raise(&metrics::LARGEST_IN_FLIGHT, buffer.bytes_total + size);
+                buffer.write_bytes(data);
+                if let (Some(decoders), true) = (&registry.decoders, buffer.pipeline.decode) {
+                    decoders.submit(buffer.pipeline.data_reader.clone());
+                }
+                observer::notify(|observer| {
+                    observer.on_chunk_received(&observer::ChunkReceived {
+                        id,
+                        size,
+                        total: buffer.bytes_total,
+                    })
+                });
+                for id in registry.watchdog.check(&mut registry.responses) {
+                    if let Some(transaction) = registry.responses.get_mut(&id) {
+                        time_out(&registry.worker, transaction);
+                    }
+                }
+                Ok(())
+            }
+            // OPTIONS requests have no body to capture.
+            None if registry.options.contains(&id) => Ok(()),
+            None => Err(PrismError::UnknownTransaction),
+        }
+    }
+
+    /// Receives `data` as `receive()` does, once its CRC32 matched
+    /// `expected`. Chunks that don't are dropped before anything is
+    /// buffered.
+    pub(crate) fn receive_checked(
+        &self,
+        id: i64,
+        data: &[u8],
+        expected: u32,
+    ) -> Result<(), PrismError> {
+        let actual = checksum::crc32(data);
+        if actual != expected {
+            metrics::increment(&metrics::COUNTERS.checksum_mismatches);
+            throttled!(
+                Level::Warn,
+                "checksum-mismatch",
+                "Dropping {} bytes received for transaction {}: crc32 {:08x}, expected {:08x}",
+                data.len(),
+                id,
+                actual,
+                expected
+            );
+            return Err(PrismError::ChecksumMismatch);
+        }
+        self.receive(id, data)
+    }
+
+    /// Produces the next chunk of the body handed back to the client, and
+    /// calls `f` with it. The chunk is empty when nothing is available yet,
+    /// or after the whole body was handed back.
+    pub(crate) fn send<R>(&self, id: i64, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let mut registry = self.registry();
+        let registry = &mut *registry;
+        if registry.options.contains(&id) {
+            return Some(f(&[]));
+        }
+        let buffer = registry.responses.get_mut(&id)?;
+        if expired(&registry.worker, buffer) {
+            recorder::send(id, 0);
+            return Some(f(&[]));
+        }
+        let first_chunk = buffer.bytes_sent == 0;
+        // Bodyless responses have nothing to poll the pipeline for, and
+        // end as soon as they are done.
+        let size = if buffer.bodyless && buffer.blocked.is_none() {
+            0
+        } else {
+            next_output(buffer)
+        };
+        recorder::send(id, size);
+        if size > 0 {
+            buffer.output_waiting = Some(clock::now());
+        }
+        buffer.account_memory();
+        if first_chunk && size > 0 {
+            tracing::event!(parent: &buffer.span, tracing::Level::DEBUG, "first byte sent");
+        }
+        trace_transaction!(
+            buffer.trace,
+            id,
+            "send returned {} bytes{} ({} total, {} chunks queued, done: {}, error: {})",
+            size,
+            checksum_note(buffer, &buffer.pipeline.transfer_chunk[0..size]),
+            buffer.bytes_sent,
+            buffer.pipeline.queued(),
+            buffer.is_done,
+            buffer.error
+        );
+        if size == 0 && buffer.is_done {
+            // Everything was handed back, the emitted length is final.
+            buffer.stream_finished();
+            persist(&registry.worker, buffer);
+        }
+        Some(f(&buffer.pipeline.transfer_chunk[0..size]))
+    }
+
+    pub(crate) fn done(&self, id: i64) {
+        let mut registry = self.registry();
+        if let Some(buffer) = registry.responses.get_mut(&id) {
+            recorder::done(id);
+            // Already persisted, and cleaned up as aborted.
+            if buffer.timed_out {
+                return;
+            }
+            if buffer.blocked.is_some() {
+                // Nothing is streamed to the client any more, drain what is
+                // pending so the persisted body is complete.
+                buffer.pipeline.discard();
+            }
+            // Persisted once `send()` handed back the whole body, or at
+            // cleanup if that never happens.
+            buffer.done();
+            observer::notify(|observer| {
+                observer.on_done(&observer::Done {
+                    id,
+                    bytes_received: buffer.bytes_total,
+                })
+            });
+        }
+    }
+
+    /// Whether the whole body of a transaction was handed back, or nothing
+    /// more will be as it timed out.
+    pub(crate) fn finished(&self, id: i64) -> bool {
+        match self.registry().responses.get(&id) {
+            Some(transaction) => transaction.is_finished || transaction.timed_out,
+            None => true,
+        }
+    }
+
+    /// Calls `f` with a live transaction.
+    pub(crate) fn with_transaction<R>(
+        &self,
+        id: i64,
+        f: impl FnOnce(&mut Transaction) -> R,
+    ) -> Result<R, PrismError> {
+        match self.registry().responses.get_mut(&id) {
+            Some(transaction) => Ok(f(transaction)),
+            None => Err(PrismError::UnknownTransaction),
+        }
+    }
+
+    /// Stops the decode workers once they are through with what they were
+    /// given. Transactions then decode their bodies in `send()`. Then stops
+    /// the persistence worker, once it flushed the queue, logging how many
+    /// documents were flushed and dropped. Documents of transactions
+    /// completing afterwards are dropped.
+    pub fn shutdown(&self) {
+        // Both are waited for without the registry locked, so transactions
+        // keep going meanwhile.
+        let (decoders, mut worker) = {
+            let mut registry = self.registry();
+            let stand_in = registry.worker.stand_in();
+            let worker = std::mem::replace(&mut registry.worker, stand_in);
+            (registry.decoders.take(), worker)
+        };
+        drop(decoders);
+        worker.stop();
+    }
+
+    pub(crate) fn cleanup(&self, id: i64) {
+        let mut registry = self.registry();
+        let registry = &mut *registry;
+        if let Some(mut buffer) = registry.responses.remove(&id) {
+            recorder::cleanup(id, buffer.is_finished);
+            // Taken before persisting moves the captured body out.
+            let footprint = buffer.footprint();
+            if buffer.is_done {
+                metrics::increment(&metrics::TRANSACTIONS_COMPLETED);
+                persist(&registry.worker, &mut buffer);
+            } else {
+                metrics::increment(&metrics::TRANSACTIONS_ABORTED);
+            }
+            #[cfg(feature = "metrics")]
+            if statsd::enabled() {
+                let outcome = if buffer.is_done {
+                    "completed"
+                } else {
+                    "aborted"
+                };
+                let status_class = statsd::status_class(buffer.status());
+                let tags = [
+                    ("encoding", buffer.encoding.as_deref().unwrap_or("identity")),
+                    ("status_class", status_class.as_str()),
+                    ("outcome", outcome),
+                ];
+                statsd::timing("transaction.duration", clock::since(buffer.started), &tags);
+                statsd::count("transactions", 1, &tags);
+                statsd::count("bytes.received", buffer.bytes_total as u64, &tags);
+                statsd::count("bytes.sent", buffer.bytes_sent as u64, &tags);
+            }
+            let host = buffer.host();
+            stats::record(stats::Sample {
+                host: host.as_deref().unwrap_or(UNKNOWN_HOST),
+                bytes_in: buffer.bytes_total,
+                bytes_out: buffer.bytes_sent,
+                // Transactions cleaned up before completing were aborted.
+                error: buffer.error || !buffer.is_done,
+                duration: clock::since(buffer.started),
+            });
+            event!(
+                Level::Info,
+                Fields::transaction(id).bytes(footprint.total()),
+                "Transaction {} held {} bytes at cleanup: {} pending in the decoder, {} captured, {} queued, {} in the transfer chunk",
+                id,
+                footprint.total(),
+                footprint.decoder_pending,
+                footprint.captured,
+                footprint.queued,
+                footprint.transfer_chunk
+            );
+            buffer.release_memory();
+            // Counted per transaction and folded in here, rather than on
+            // every chunk.
+            metrics::add(&metrics::BYTES_RECEIVED, buffer.bytes_total);
+            metrics::add(&metrics::BYTES_SENT, buffer.bytes_sent);
+            observer::notify(|observer| {
+                observer.on_cleanup(&observer::Cleanup {
+                    id,
+                    completed: buffer.is_done,
+                    bytes_received: buffer.bytes_total,
+                    bytes_sent: buffer.bytes_sent,
+                    duration: clock::since(buffer.started),
+                })
+            });
+        }
+        registry.headers.remove(&id);
+        registry.options.remove(&id);
+        recorder::forget(id);
+        registry
+            .shrinker
+            .check(&mut registry.responses, &mut registry.headers);
+
+        event!(
+            Level::Info,
+            Fields::transaction(id),
+            "Cleanup {}: {} & {} transactions currently active. Capacities @ {} & {}",
+            id,
+            registry.responses.len(),
+            registry.headers.len(),
+            registry.responses.capacity(),
+            registry.headers.capacity()
+        );
+    }
+}
+
+/// A transaction begun through `Prism::begin()`.
+pub struct TransactionHandle {
+    prism: Prism,
+    id: i64,
+    /// Output produced by the pipeline but not yet copied out by
+    /// `poll_output()`.
+    output: Vec<u8>,
+}
+
+impl TransactionHandle {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    /// Adds a response header received after the transaction began.
+    pub fn header(&self, name: &str, value: &str) {
+        self.prism.header(
+            self.id,
+            Direction::Response,
+            name.to_string(),
+            value.to_string(),
+        );
+    }
+
+    /// Adds a header of the request the response is for.
+    pub fn request_header(&self, name: &str, value: &str) {
+        self.prism.header(
+            self.id,
+            Direction::Request,
+            name.to_string(),
+            value.to_string(),
+        );
+    }
+
+    /// Records the response status code.
+    pub fn status(&self, code: u16) {
+        self.prism.header(
+            self.id,
+            Direction::Response,
+            STATUS_HEADER.to_string(),
+            code.to_string(),
+        );
+    }
+
+    /// Records the address of the client the transaction is made for.
+    pub fn client_address(&self, address: &str) {
+        self.prism.header(
+            self.id,
+            Direction::Response,
+            CLIENT_HEADER.to_string(),
+            address.to_string(),
+        );
+    }
+
+    /// Feeds body bytes received from the origin.
+    pub fn receive(&self, data: &[u8]) -> Result<(), PrismError> {
+        self.prism.receive(self.id, data)
+    }
+
+    /// Feeds body bytes once their CRC32 matched `checksum`, see
+    /// `receive_checked()`.
+    pub fn receive_checked(&self, data: &[u8], checksum: u32) -> Result<(), PrismError> {
+        self.prism.receive_checked(self.id, data, checksum)
+    }
+
+    /// Copies body bytes to hand back to the client into `buf`, returning
+    /// how many. `0` means nothing is available until more is received, or,
+    /// once `finished()`, that the whole body was handed back.
+    pub fn poll_output(&mut self, buf: &mut [u8]) -> usize {
+        if self.output.is_empty() {
+            self.output = self
+                .prism
+                .send(self.id, |chunk| chunk.to_vec())
+                .unwrap_or_default();
+        }
+        let size = min(buf.len(), self.output.len());
+        buf[0..size].copy_from_slice(&self.output[0..size]);
+        self.output.drain(0..size);
+        size
+    }
+
+    /// Signals that the whole body was received.
+    pub fn done(&self) {
+        self.prism.done(self.id);
+    }
+
+    /// Whether the whole body was handed back through `poll_output()`.
+    pub fn finished(&self) -> bool {
+        self.output.is_empty() && self.prism.finished(self.id)
+    }
+
+    /// Replaces the rest of the response with a block page, see `block()`.
+    pub fn block(&self, reason: &str) -> Result<(), PrismError> {
+        self.prism
+            .with_transaction(self.id, |transaction| transaction.block(reason.to_string()))
+    }
+
+    /// Attaches metadata persisted with the transaction, see `annotate()`.
+    pub fn annotate(&self, key: &str, value: &str) -> Result<(), PrismError> {
+        self.prism.with_transaction(self.id, |transaction| {
+            transaction.annotate(key.to_string(), value.to_string())
+        })
+    }
+
+    /// Enables or disables detailed, chunk level logging.
+    pub fn set_trace(&self, enabled: bool) -> Result<(), PrismError> {
+        self.prism
+            .with_transaction(self.id, |transaction| transaction.set_trace(enabled))
+    }
+
+    /// Value a response header should be changed to, see
+    /// `get_response_header()`: `None` when unchanged, empty when it must
+    /// be removed.
+    pub fn response_header(&self, name: &str) -> Option<String> {
+        self.prism
+            .with_transaction(self.id, |transaction| {
+                transaction.modified_headers.get(name).map(str::to_string)
+            })
+            .ok()
+            .flatten()
+    }
+}
+
+impl Drop for TransactionHandle {
+    fn drop(&mut self) {
+        self.prism.cleanup(self.id);
+    }
+}
+
+/// Bytes held by live transactions, as last accounted by each, and by the
+/// documents waiting to be persisted.
+fn memory_in_use(registry: &Registry) -> usize {
+    metrics::get(&metrics::RETAINED_BYTES) as usize + registry.worker.queued_bytes()
+}
+
+/// Builds the rewriter chain for a response. Rewriting is limited to textual
+/// content, binary bodies are always passed through untouched, as are all
+/// bodies in dry runs.
+fn rewriters(rules: &[ReplaceRule], headers: &Headers) -> RewriteChain {
+    if config::get().dry_run {
+        return RewriteChain::new(Vec::new());
+    }
+
+    let is_text = match headers.get("Content-Type") {
+        Some(content_type) => content_type.to_ascii_lowercase().starts_with("text/"),
+        None => false,
+    };
+
+    let mut rewriters: Vec<Box<dyn BodyRewriter>> = Vec::new();
+    if is_text && !rules.is_empty() {
+        rewriters.push(Box::new(ReplaceRewriter::new(rules.to_vec())));
+    }
+    RewriteChain::new(rewriters)
+}
+
+/// Hands a transaction over to the persistence worker, once.
+fn persist(worker: &Worker, transaction: &mut Transaction) {
+    if transaction.persisted {
+        return;
+    }
+    transaction.persisted = true;
+    if transaction.shed {
+        trace_transaction!(
+            transaction.trace,
+            transaction.id,
+            "relayed past the transaction limit, skipping persistence"
+        );
+        return;
+    }
+    if !transaction.sampled {
+        trace_transaction!(
+            transaction.trace,
+            transaction.id,
+            "not sampled, skipping persistence"
+        );
+        return;
+    }
+    if !transaction.config.dry_run && !worker::backend_available() {
+        trace_transaction!(
+            transaction.trace,
+            transaction.id,
+            "no {} backend compiled in, skipping persistence",
+            worker::backend_name()
+        );
+        return;
+    }
+    if transaction.actions.skip_persist {
+        trace_transaction!(
+            transaction.trace,
+            transaction.id,
+            "skipping persistence as asked by the rules"
+        );
+        return;
+    }
+
+    let scan = matches!(scanner::get(), Some(scanner) if scanner.matches(transaction));
+    let document = Document::new(transaction);
+    recorder::document(&document);
+    worker.submit(PendingDocument {
+        document,
+        scan,
+        span: tracing::info_span!(parent: &transaction.span, "persist"),
+        dry_run: transaction.config.dry_run,
+        replayed: None,
+    });
+}
+
+/// Whether a transaction timed out, timing it out first if it just went
+/// past its deadline.
+fn expired(worker: &Worker, transaction: &mut Transaction) -> bool {
+    if transaction.past_deadline(clock::now()) {
+        time_out(worker, transaction);
+    }
+    transaction.timed_out
+}
+
+/// Gives up on a transaction past its deadline: it is persisted with the
+/// body captured so far, and holds nothing more until cleaned up.
+fn time_out(worker: &Worker, transaction: &mut Transaction) {
+    transaction.time_out();
+    persist(worker, transaction);
+    transaction.release_memory();
+}
+
+/// The CRC32 of a chunk for the trace line of `transaction`, when
+/// `logging.chunk_checksums` asks for it.
+fn checksum_note(transaction: &Transaction, chunk: &[u8]) -> String {
+    if transaction.config.logging.chunk_checksums {
+        format!(", crc32 {:08x}", checksum::crc32(chunk))
+    } else {
+        String::new()
+    }
+}
+
+/// Produces the next chunk of the body handed back to the client into the
+/// transfer chunk, returning its size.
+fn next_output(buffer: &mut Transaction) -> usize {
+    if buffer.relay_aborted {
+        buffer.pipeline.discard();
+        return 0;
+    }
+    if buffer.blocked.is_some() {
+        buffer.pipeline.discard();
+        return match buffer.block_page.take() {
+            Some(page) => {
+                buffer.bytes_sent += page.len();
+                buffer.pipeline.transfer_chunk = page;
+                buffer.pipeline.transfer_chunk.len()
+            }
+            None => 0,
+        };
+    }
+
+    buffer.pipeline.flush_expired();
+    if !buffer.pipeline.decode {
+        let size = buffer.pipeline.drain_chunks();
+        buffer.bytes_sent += size;
+        return size;
+    }
+
+    if buffer.error {
+        return 0;
+    }
+
+    let _encode = tracing::debug_span!(parent: &buffer.span, "encode").entered();
+    let bytes = match buffer.pipeline.encode(buffer.is_done) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            let error = if buffer.pipeline.data_reader.decode_failed() {
+                PrismError::Decode
+            } else {
+                PrismError::Encode
+            };
+            if let Some(suppressed) = logging::throttle("decode") {
+                event!(
+                    Level::Error,
+                    Fields::transaction(buffer.id)
+                        .uri(&buffer.uri)
+                        .error_kind(error.label()),
+                    "Failed reading for id {} (uri: {}): {}. Will return 0 bytes. Error: {}{}",
+                    buffer.id,
+                    buffer.uri,
+                    error,
+                    e,
+                    suppressed
+                );
+            }
+            buffer.error = true;
+            metrics::increment(&metrics::COUNTERS.decode_errors);
+            observer::error(Some(buffer.id), error);
+            0
+        }
+    };
+
+    buffer.bytes_sent += bytes;
+    let pipeline = &mut buffer.pipeline;
+    pipeline.output_produced(bytes);
+    pipeline.transfer_chunk.clear();
+    pipeline
+        .transfer_chunk
+        .extend_from_slice(&pipeline.output_buffer[0..bytes]);
+    bytes
+}
@@ -0,0 +1,93 @@
+use crate::clock;
+use crate::config;
+use crate::document::Document;
+use crate::metrics;
+use crate::persistence::Backend;
+use crate::rotation::RotatingFile;
+use log::{info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+#[derive(Serialize)]
+struct Line {
+    timestamp: String,
+    document_id: String,
+    backend: &'static str,
+    destination: String,
+    transaction_id: i64,
+    host: Option<String>,
+    body_sha256: String,
+}
+
+/// Append-only record of the documents persisted, one JSON line each.
+static AUDIT_LOG: OnceLock<Option<Mutex<RotatingFile>>> = OnceLock::new();
+/// Set once a failure was logged, so a broken audit log warns only once.
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+fn open() -> Option<Mutex<RotatingFile>> {
+    let config = config::get();
+    let path = config.audit.path.clone()?;
+    let mut audit_log = RotatingFile::new(path, config.audit.max_size);
+    // Opened again on the next line when it fails now.
+    match audit_log.open() {
+        Ok(_) => info!("Writing audit log to {}", audit_log.path().display()),
+        Err(e) => failed(&format!(
+            "Failed opening audit log {}: {}",
+            audit_log.path().display(),
+            e
+        )),
+    }
+    Some(Mutex::new(audit_log))
+}
+
+fn failed(message: &str) {
+    metrics::increment(&metrics::COUNTERS.audit_failures);
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        warn!("{} (further audit log failures are only counted)", message);
+    }
+}
+
+fn body_hash(document: &Document) -> String {
+    Sha256::digest(document.body.bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Records a document successfully persisted by `backend`. Called from the
+/// persistence worker.
+pub fn record(backend: &dyn Backend, document: &Document) {
+    let audit_log = match AUDIT_LOG.get_or_init(open) {
+        Some(audit_log) => audit_log,
+        None => return,
+    };
+
+    let line = Line {
+        timestamp: clock::utc().to_rfc3339(),
+        document_id: backend.document_id(document),
+        backend: backend.name(),
+        destination: backend.document_destination(document),
+        transaction_id: document.id,
+        host: document.host.clone(),
+        body_sha256: body_hash(document),
+    };
+    let mut line = match serde_json::to_vec(&line) {
+        Ok(line) => line,
+        Err(e) => {
+            failed(&format!("Failed serializing audit log line: {}", e));
+            return;
+        }
+    };
+    line.push(b'\n');
+
+    let mut audit_log = audit_log.lock().unwrap();
+    if let Err(e) = audit_log.append(&line) {
+        failed(&format!(
+            "Failed writing audit log {}: {}",
+            audit_log.path().display(),
+            e
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+//! Reads back files written with `encryption` on: spooled bodies, dead
+//! letter files and HAR files, printing their plaintext.
+//!
+//! Usage: `prism-decrypt --key FILE [--key FILE]... [PATH...]`
+//!
+//! Each key file holds a key as configured, 32 bytes base64 encoded. Every
+//! record is opened with the key whose id it names, so files sealed before
+//! a key rotation can be read by giving the earlier keys as well. Files
+//! are read in turn, standard input when none is given. Tampered records,
+//! and those of keys not given, fail the whole run.
+
+use prism::encryption::{self, Key};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+struct Options {
+    keys: Vec<PathBuf>,
+    paths: Vec<PathBuf>,
+}
+
+fn options() -> Result<Options, String> {
+    let mut options = Options {
+        keys: Vec::new(),
+        paths: Vec::new(),
+    };
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--key") => {
+                options
+                    .keys
+                    .push(args.next().ok_or("--key needs a file")?.into());
+            }
+            Some("--help") | Some("-h") => {
+                return Err(String::new());
+            }
+            _ => options.paths.push(arg.into()),
+        }
+    }
+    if options.keys.is_empty() {
+        return Err("no key given".to_string());
+    }
+    Ok(options)
+}
+
+fn key(path: &Path) -> Result<Key, String> {
+    let encoded =
+        std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Key::parse(&encoded).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn main() -> ExitCode {
+    let options = match options() {
+        Ok(options) => options,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("{}", message);
+            }
+            eprintln!("Usage: prism-decrypt --key FILE [--key FILE]... [PATH...]");
+            return ExitCode::FAILURE;
+        }
+    };
+    let keys = match options
+        .keys
+        .iter()
+        .map(|path| key(path))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let opened = if options.paths.is_empty() {
+        encryption::open(std::io::stdin().lock(), &keys, usize::MAX)
+            .map_err(|e| format!("standard input: {}", e))
+    } else {
+        options
+            .paths
+            .iter()
+            .try_fold(Vec::new(), |mut plaintext, path| {
+                let file =
+                    std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                let opened = encryption::open(BufReader::new(file), &keys, usize::MAX)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                plaintext.extend(opened);
+                Ok(plaintext)
+            })
+    };
+    let plaintext = match opened {
+        Ok(plaintext) => plaintext,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    // Nothing is printed unless every record opened.
+    if let Err(e) = std::io::stdout().lock().write_all(&plaintext) {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
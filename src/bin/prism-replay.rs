@@ -0,0 +1,297 @@
+//! Replays recorded transactions through prism, to reproduce on a dev
+//! machine what happened in production.
+//!
+//! A recording is a directory holding a `manifest.json` and the body chunks
+//! as received, one file each:
+//!
+//! ```json
+//! {
+//!     "method": "GET",
+//!     "uri": "http://example.com/",
+//!     "status": 200,
+//!     "headers": [["Content-Type", "text/html"], ["Content-Encoding", "gzip"]],
+//!     "chunks": ["chunk-000.bin", "chunk-001.bin"]
+//! }
+//! ```
+//!
+//! Each path given is a recording, or a directory of recordings replayed in
+//! name order. Every transaction prints one JSON line: what `send()` handed
+//! back and the persisted document. Chunks are fed in their recorded sizes,
+//! coalescing is disabled and the document date is left out, so the output
+//! of two builds can be diffed.
+//!
+//! Usage: `prism-replay [--config FILE] [--loop N] PATH...`
+//!
+//! `--config` loads a configuration file, whose backend is replaced by the
+//! memory backend. `--loop N` replays the corpus N more times, `0` forever,
+//! printing one summary line per pass instead, for soak testing.
+
+use prism::config::Config;
+use prism::{memory, Prism};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+/// Size of the buffer output is copied into, as an adapter would.
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+/// How long to wait for a document to be persisted.
+const PERSIST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default = "default_method")]
+    method: String,
+    uri: String,
+    status: Option<u16>,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    chunks: Vec<PathBuf>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+struct Recording {
+    name: String,
+    manifest: Manifest,
+    chunks: Vec<Vec<u8>>,
+}
+
+impl Recording {
+    fn load(dir: &Path) -> Result<Recording, String> {
+        let manifest_path = dir.join("manifest.json");
+        let manifest = std::fs::read(&manifest_path)
+            .map_err(|e| format!("{}: {}", manifest_path.display(), e))?;
+        let manifest: Manifest = serde_json::from_slice(&manifest)
+            .map_err(|e| format!("{}: {}", manifest_path.display(), e))?;
+        let chunks = manifest
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let path = dir.join(chunk);
+                std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))
+            })
+            .collect::<Result<_, _>>()?;
+        let name = dir.file_name().map_or_else(
+            || dir.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        Ok(Recording {
+            name,
+            manifest,
+            chunks,
+        })
+    }
+}
+
+/// The recordings under `path`, itself a recording or a directory of them.
+fn recordings(path: &Path) -> Result<Vec<Recording>, String> {
+    if path.join("manifest.json").is_file() {
+        return Ok(vec![Recording::load(path)?]);
+    }
+    let mut dirs: Vec<PathBuf> = std::fs::read_dir(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|dir| dir.join("manifest.json").is_file())
+        .collect();
+    dirs.sort();
+    dirs.iter().map(|dir| Recording::load(dir)).collect()
+}
+
+/// What replaying a recording produced.
+struct Outcome {
+    output: Vec<u8>,
+    /// Non-empty chunks `send()` handed back.
+    output_chunks: usize,
+}
+
+fn replay(prism: &Prism, id: i64, recording: &Recording) -> Outcome {
+    let manifest = &recording.manifest;
+    let headers: Vec<(&str, &str)> = manifest
+        .headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    let mut handle = prism.begin(id, &manifest.method, &manifest.uri, &headers);
+    if let Some(status) = manifest.status {
+        handle.status(status);
+    }
+
+    let mut buffer = vec![0; OUTPUT_BUFFER_SIZE];
+    let mut outcome = Outcome {
+        output: Vec::new(),
+        output_chunks: 0,
+    };
+    let mut poll = |handle: &mut prism::TransactionHandle| {
+        let size = handle.poll_output(&mut buffer);
+        if size > 0 {
+            outcome.output.extend_from_slice(&buffer[0..size]);
+            outcome.output_chunks += 1;
+        }
+        size
+    };
+    for chunk in &recording.chunks {
+        if let Err(e) = handle.receive(chunk) {
+            eprintln!("{}: receiving failed: {}", recording.name, e);
+        }
+        while poll(&mut handle) > 0 {}
+    }
+    handle.done();
+    while !handle.finished() {
+        poll(&mut handle);
+    }
+    outcome
+}
+
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn report(id: i64, recording: &Recording, outcome: &Outcome) -> serde_json::Value {
+    let mut document = match memory::wait(id, PERSIST_TIMEOUT) {
+        Some(persisted) => persisted.json,
+        None => serde_json::Value::Null,
+    };
+    if let Some(document) = document.as_object_mut() {
+        document.remove("date");
+    }
+    serde_json::json!({
+        "recording": recording.name,
+        "bytes_received": recording.chunks.iter().map(Vec::len).sum::<usize>(),
+        "chunks_received": recording.chunks.len(),
+        "bytes_sent": outcome.output.len(),
+        "chunks_sent": outcome.output_chunks,
+        "output_sha256": sha256(&outcome.output),
+        "document": document,
+    })
+}
+
+struct Options {
+    config: Option<PathBuf>,
+    /// Passes over the corpus after the first, `Some(0)` for ever.
+    repeat: Option<u64>,
+    paths: Vec<PathBuf>,
+}
+
+fn options() -> Result<Options, String> {
+    let mut options = Options {
+        config: None,
+        repeat: None,
+        paths: Vec::new(),
+    };
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--config") => {
+                options.config = Some(args.next().ok_or("--config needs a file")?.into());
+            }
+            Some("--loop") => {
+                let count = args.next().ok_or("--loop needs a count")?;
+                let count = count
+                    .to_str()
+                    .and_then(|count| count.parse().ok())
+                    .ok_or("--loop needs a count")?;
+                options.repeat = Some(count);
+            }
+            Some("--help") | Some("-h") => {
+                return Err(String::new());
+            }
+            _ => options.paths.push(arg.into()),
+        }
+    }
+    if options.paths.is_empty() {
+        return Err("no recording given".to_string());
+    }
+    Ok(options)
+}
+
+fn config(path: Option<&Path>) -> Result<Config, String> {
+    let mut config = Config::load(path).map_err(|errors| {
+        errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+    config.dry_run = false;
+    config.backend.kind = "memory".to_string();
+    // Gathering chunks depends on timing, which would make the output vary.
+    config.limits.coalesce_size = 0;
+    Ok(config)
+}
+
+fn main() -> ExitCode {
+    let options = match options() {
+        Ok(options) => options,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("{}", message);
+            }
+            eprintln!("Usage: prism-replay [--config FILE] [--loop N] PATH...");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut corpus = Vec::new();
+    for path in &options.paths {
+        match recordings(path) {
+            Ok(recordings) => corpus.extend(recordings),
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let prism = match config(options.config.as_deref()).and_then(|config| {
+        Prism::new(config).map_err(|errors| {
+            errors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+    }) {
+        Ok(prism) => prism,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut id = 0;
+    for recording in &corpus {
+        id += 1;
+        let outcome = replay(&prism, id, recording);
+        println!("{}", report(id, recording, &outcome));
+    }
+
+    let Some(repeat) = options.repeat else {
+        return ExitCode::SUCCESS;
+    };
+    let mut pass = 0;
+    while repeat == 0 || pass < repeat {
+        pass += 1;
+        memory::clear();
+        let started = Instant::now();
+        let mut bytes_sent = 0;
+        for recording in &corpus {
+            id += 1;
+            bytes_sent += replay(&prism, id, recording).output.len();
+        }
+        println!(
+            "{}",
+            serde_json::json!({
+                "pass": pass,
+                "transactions": corpus.len(),
+                "bytes_sent": bytes_sent,
+                "elapsed_ms": started.elapsed().as_millis() as u64,
+            })
+        );
+    }
+    ExitCode::SUCCESS
+}
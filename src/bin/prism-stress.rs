@@ -0,0 +1,426 @@
+//! Runs many concurrent transactions through prism for a while, to measure
+//! throughput and memory before deploying a change.
+//!
+//! Every thread keeps its share of the concurrent transactions going, each
+//! one a randomized lifecycle: a body of a mixed size, identity or gzip
+//! encoded, received in chunks of random sizes and handed back to a client
+//! that may be slow, with some transactions cleaned up before the end of
+//! their body. Documents go to the dry run backend, which only serializes
+//! them.
+//!
+//! Usage: `prism-stress [--config FILE] [--threads N] [--concurrency N]
+//! [--duration SECONDS] [--memory-budget BYTES] [--seed N]`
+//!
+//! Progress goes to stderr every 10 seconds. Once done, one JSON summary is
+//! printed: throughput, resident memory along the run, the most
+//! transactions live at once, errors by kind and the library counters.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prism::config::Config;
+use prism::observer::{self, LifecycleObserver};
+use prism::{Prism, TransactionHandle};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Sizes of the bodies transactions pick from.
+const BODY_SIZES: [usize; 7] = [
+    0,
+    512,
+    8 * 1024,
+    64 * 1024,
+    256 * 1024,
+    1024 * 1024,
+    4 * 1024 * 1024,
+];
+/// Largest chunk received at once.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+/// Size of the buffer output is copied into, as an adapter would.
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+static ID: AtomicI64 = AtomicI64::new(1);
+static ERRORS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+
+/// Counts the errors the library reports, by kind.
+struct Errors;
+
+impl LifecycleObserver for Errors {
+    fn on_error(&self, event: &observer::ErrorEvent) {
+        let mut errors = match ERRORS.lock() {
+            Ok(errors) => errors,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *errors.entry(event.error.label()).or_default() += 1;
+    }
+}
+
+/// xorshift, enough to vary lifecycles without a dependency.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    /// Whether an event of probability `percent`% happens.
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+/// A body, as received identity and gzip encoded.
+struct Payload {
+    identity: Arc<Vec<u8>>,
+    gzip: Arc<Vec<u8>>,
+}
+
+fn payloads() -> Vec<Payload> {
+    BODY_SIZES
+        .iter()
+        .map(|&size| {
+            let mut body = Vec::with_capacity(size + 64);
+            let mut line = 0;
+            while body.len() < size {
+                writeln!(body, "<p>line {} of a stress test page</p>", line).unwrap();
+                line += 1;
+            }
+            body.truncate(size);
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body).unwrap();
+            Payload {
+                identity: Arc::new(body),
+                gzip: Arc::new(encoder.finish().unwrap()),
+            }
+        })
+        .collect()
+}
+
+/// What stepping a lifecycle led to.
+enum Step {
+    Running,
+    Completed,
+    Aborted,
+}
+
+struct Lifecycle {
+    handle: TransactionHandle,
+    body: Arc<Vec<u8>>,
+    received: usize,
+    /// Output is only taken every this many steps, as by a slow client.
+    patience: usize,
+    steps: usize,
+    /// Body bytes after which the transaction is cleaned up unfinished.
+    abort_at: Option<usize>,
+    done: bool,
+}
+
+/// What a thread did, added up at the end.
+#[derive(Default)]
+struct Totals {
+    completed: u64,
+    aborted: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+    receive_errors: u64,
+}
+
+impl Lifecycle {
+    fn begin(prism: &Prism, payloads: &[Payload], random: &mut Random) -> Lifecycle {
+        let payload = &payloads[random.below(payloads.len())];
+        let gzip = random.chance(50);
+        let mut headers = vec![("Content-Type", "text/html")];
+        if gzip {
+            headers.push(("Content-Encoding", "gzip"));
+        }
+        let id = ID.fetch_add(1, Ordering::Relaxed);
+        let handle = prism.begin(id, "GET", "http://stress.example.com/page", &headers);
+        handle.status(200);
+        let body = if gzip {
+            payload.gzip.clone()
+        } else {
+            payload.identity.clone()
+        };
+        let abort_at = random.chance(10).then(|| random.below(body.len() + 1));
+        Lifecycle {
+            handle,
+            body,
+            received: 0,
+            patience: if random.chance(20) { 16 } else { 1 },
+            steps: 0,
+            abort_at,
+            done: false,
+        }
+    }
+
+    fn step(&mut self, random: &mut Random, buffer: &mut [u8], totals: &mut Totals) -> Step {
+        self.steps += 1;
+        if !self.done {
+            if self.abort_at.is_some_and(|at| self.received >= at) {
+                return Step::Aborted;
+            }
+            let size = (1 + random.below(MAX_CHUNK_SIZE)).min(self.body.len() - self.received);
+            let chunk = &self.body[self.received..self.received + size];
+            if self.handle.receive(chunk).is_err() {
+                totals.receive_errors += 1;
+            }
+            self.received += size;
+            totals.bytes_received += size as u64;
+            if self.received == self.body.len() {
+                self.handle.done();
+                self.done = true;
+            }
+        }
+        if self.steps % self.patience == 0 || self.done {
+            loop {
+                let size = self.handle.poll_output(buffer);
+                if size == 0 {
+                    break;
+                }
+                totals.bytes_sent += size as u64;
+            }
+        }
+        if self.done && self.handle.finished() {
+            return Step::Completed;
+        }
+        Step::Running
+    }
+}
+
+/// Keeps `concurrency` lifecycles going until `stop` is set.
+fn run(
+    prism: &Prism,
+    payloads: &[Payload],
+    concurrency: usize,
+    seed: u64,
+    stop: &AtomicBool,
+) -> Totals {
+    let mut random = Random(seed | 1);
+    let mut buffer = vec![0; OUTPUT_BUFFER_SIZE];
+    let mut totals = Totals::default();
+    let mut lifecycles: Vec<Lifecycle> = (0..concurrency)
+        .map(|_| Lifecycle::begin(prism, payloads, &mut random))
+        .collect();
+    while !stop.load(Ordering::Relaxed) {
+        for lifecycle in lifecycles.iter_mut() {
+            match lifecycle.step(&mut random, &mut buffer, &mut totals) {
+                Step::Running => continue,
+                Step::Completed => totals.completed += 1,
+                Step::Aborted => totals.aborted += 1,
+            }
+            // Dropping the handle cleans the transaction up.
+            *lifecycle = Lifecycle::begin(prism, payloads, &mut random);
+        }
+    }
+    totals
+}
+
+/// A field of `/proc/self/status`, in kB. Only available on Linux.
+fn memory_status(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+fn counters() -> serde_json::Value {
+    let stats = prism::stats();
+    if stats.size == 0 {
+        return serde_json::Value::Null;
+    }
+    let stats = unsafe { std::slice::from_raw_parts(stats.bytes as *const u8, stats.size) };
+    serde_json::from_slice::<serde_json::Value>(stats)
+        .map(|stats| stats["counters"].clone())
+        .unwrap_or_default()
+}
+
+struct Options {
+    config: Option<PathBuf>,
+    threads: usize,
+    concurrency: usize,
+    duration: Duration,
+    memory_budget: Option<usize>,
+    seed: u64,
+}
+
+fn options() -> Result<Options, String> {
+    let mut options = Options {
+        config: None,
+        threads: std::thread::available_parallelism().map_or(4, |threads| threads.get()),
+        concurrency: 1000,
+        duration: Duration::from_secs(60),
+        memory_budget: None,
+        seed: 1,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Err(String::new());
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?;
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{} needs a number", arg))
+        };
+        match arg.as_str() {
+            "--config" => options.config = Some(value.clone().into()),
+            "--threads" => options.threads = number()?.max(1) as usize,
+            "--concurrency" => options.concurrency = number()? as usize,
+            "--duration" => options.duration = Duration::from_secs(number()?),
+            "--memory-budget" => options.memory_budget = Some(number()? as usize),
+            "--seed" => options.seed = number()?,
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+fn config(options: &Options) -> Result<Config, String> {
+    let mut config = Config::load(options.config.as_deref()).map_err(|errors| {
+        errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+    // Documents are only serialized, the backend is not what is measured.
+    config.dry_run = true;
+    if options.memory_budget.is_some() {
+        config.limits.memory_budget = options.memory_budget;
+    }
+    Ok(config)
+}
+
+fn main() -> ExitCode {
+    let options = match options() {
+        Ok(options) => options,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("{}", message);
+            }
+            eprintln!(
+                "Usage: prism-stress [--config FILE] [--threads N] [--concurrency N] \
+                 [--duration SECONDS] [--memory-budget BYTES] [--seed N]"
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    let _ = observer::register(Box::new(Errors));
+    let prism = match config(&options).and_then(|config| {
+        Prism::new(config).map_err(|errors| {
+            errors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+    }) {
+        Ok(prism) => prism,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let payloads = Arc::new(payloads());
+    let rss_start = memory_status("VmRSS");
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let started = Instant::now();
+    let threads: Vec<_> = (0..options.threads)
+        .map(|index| {
+            // The concurrency is shared out, the first threads taking the
+            // remainder.
+            let concurrency = options.concurrency / options.threads
+                + usize::from(index < options.concurrency % options.threads);
+            let prism = prism.clone();
+            let payloads = payloads.clone();
+            let stop = stop.clone();
+            let seed = options.seed.wrapping_add(index as u64 * 0x9e37_79b9);
+            std::thread::spawn(move || run(&prism, &payloads, concurrency, seed, &stop))
+        })
+        .collect();
+
+    let mut high_water = 0;
+    let mut rss_samples = Vec::new();
+    let mut next_progress = started + PROGRESS_INTERVAL;
+    while started.elapsed() < options.duration {
+        std::thread::sleep(SAMPLE_INTERVAL);
+        high_water = high_water.max(prism.active_transactions());
+        if Instant::now() >= next_progress {
+            next_progress += PROGRESS_INTERVAL;
+            let rss = memory_status("VmRSS");
+            rss_samples.extend(rss);
+            eprintln!(
+                "{}s: {} transactions live, {} kB resident",
+                started.elapsed().as_secs(),
+                prism.active_transactions(),
+                rss.map_or("?".to_string(), |rss| rss.to_string())
+            );
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+
+    let mut totals = Totals::default();
+    let mut panicked = 0;
+    for thread in threads {
+        match thread.join() {
+            Ok(thread) => {
+                totals.completed += thread.completed;
+                totals.aborted += thread.aborted;
+                totals.bytes_received += thread.bytes_received;
+                totals.bytes_sent += thread.bytes_sent;
+                totals.receive_errors += thread.receive_errors;
+            }
+            Err(_) => panicked += 1,
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    let errors = match ERRORS.lock() {
+        Ok(errors) => errors.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    let summary = serde_json::json!({
+        "duration_s": elapsed,
+        "threads": options.threads,
+        "concurrency": options.concurrency,
+        "transactions_completed": totals.completed,
+        "transactions_aborted": totals.aborted,
+        "transactions_per_s": (totals.completed + totals.aborted) as f64 / elapsed,
+        "bytes_received": totals.bytes_received,
+        "bytes_sent": totals.bytes_sent,
+        "received_bytes_per_s": totals.bytes_received as f64 / elapsed,
+        "active_transactions_high_water": high_water,
+        "rss_start_kb": rss_start,
+        "rss_end_kb": memory_status("VmRSS"),
+        "rss_peak_kb": memory_status("VmHWM"),
+        "rss_samples_kb": rss_samples,
+        "receive_errors": totals.receive_errors,
+        "panicked_threads": panicked,
+        "errors": errors,
+        "counters": counters(),
+    });
+    println!("{}", summary);
+
+    if panicked > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,57 @@
+use log::warn;
+use std::path::Path;
+
+/// Status code reported for blocked responses.
+pub const BLOCK_STATUS: &str = "403";
+
+const DEFAULT_BLOCK_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Blocked</title></head>
+<body>
+<h1>This content has been blocked</h1>
+<p>Reason: {reason}</p>
+<p>Reference: {id}</p>
+</body>
+</html>
+"#;
+
+fn template(path: Option<&Path>) -> String {
+    match path {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(template) => template,
+            Err(e) => {
+                warn!(
+                    "Failed reading block page template {}, using the default one: {}",
+                    path.display(),
+                    e
+                );
+                DEFAULT_BLOCK_PAGE.to_string()
+            }
+        },
+        None => DEFAULT_BLOCK_PAGE.to_string(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders the block page for a transaction, from the template at `path`
+/// or the default one.
+pub fn render(path: Option<&Path>, id: i64, reason: &str) -> Vec<u8> {
+    template(path)
+        .replace("{reason}", &escape(reason))
+        .replace("{id}", &id.to_string())
+        .into_bytes()
+}
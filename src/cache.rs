@@ -0,0 +1,130 @@
+use crate::headers::Headers;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Formats HTTP dates may come in, after IMF-fixdate which is handled as
+/// RFC 2822: the obsolete RFC 850 and asctime forms.
+const OBSOLETE_DATE_FORMATS: [&str; 2] = ["%A, %d-%b-%y %H:%M:%S GMT", "%a %b %e %H:%M:%S %Y"];
+
+/// Cache validation headers of a response. Values that couldn't be parsed
+/// are kept as received in the `_raw` fields.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified_raw: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cache_control: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_control_raw: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_raw: Option<String>,
+}
+
+impl CacheMetadata {
+    pub fn new(headers: &Headers) -> Self {
+        let mut metadata = CacheMetadata {
+            etag: headers
+                .get("ETag")
+                .map(|etag| etag.trim().to_string())
+                .filter(|etag| !etag.is_empty()),
+            ..Default::default()
+        };
+
+        if let Some(value) = headers.get("Last-Modified") {
+            match parse_date(value) {
+                Some(date) => {
+                    metadata.last_modified = Some(date.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+                }
+                None => metadata.last_modified_raw = Some(value.to_string()),
+            }
+        }
+
+        if let Some(value) = headers.get("Cache-Control") {
+            match parse_directives(value) {
+                Some(directives) => metadata.cache_control = directives,
+                None => metadata.cache_control_raw = Some(value.to_string()),
+            }
+        }
+
+        if let Some(value) = headers.get("Age") {
+            match value.trim().parse() {
+                Ok(age) => metadata.age = Some(age),
+                Err(_) => metadata.age_raw = Some(value.to_string()),
+            }
+        }
+
+        metadata
+    }
+}
+
+/// Parses an HTTP date in any of the formats allowed by RFC 9110.
+pub fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(date) = DateTime::parse_from_rfc2822(value) {
+        return Some(date.with_timezone(&Utc));
+    }
+
+    OBSOLETE_DATE_FORMATS.iter().find_map(|format| {
+        NaiveDateTime::parse_from_str(value, format)
+            .ok()
+            .map(|date| Utc.from_utc_datetime(&date))
+    })
+}
+
+/// Splits a Cache-Control value into its directives, with lowercase names
+/// and unquoted arguments (`max-age=60`, `private=set-cookie`). Returns
+/// `None` when a directive is malformed.
+pub fn parse_directives(value: &str) -> Option<Vec<String>> {
+    let mut directives = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in value.chars().chain(std::iter::once(',')) {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                let directive = current.trim();
+                if !directive.is_empty() {
+                    directives.push(directive_token(directive)?);
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+
+    if quoted {
+        None
+    } else {
+        Some(directives)
+    }
+}
+
+fn directive_token(directive: &str) -> Option<String> {
+    let (name, argument) = match directive.split_once('=') {
+        Some((name, argument)) => (name.trim(), Some(argument.trim())),
+        None => (directive, None),
+    };
+
+    let is_token = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c))
+    };
+    if !is_token(name) {
+        return None;
+    }
+
+    let name = name.to_ascii_lowercase();
+    match argument {
+        Some(argument) => Some(format!("{}={}", name, argument)),
+        None => Some(name),
+    }
+}
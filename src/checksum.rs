@@ -0,0 +1,11 @@
+//! Checksums of the chunks crossing the FFI boundary, for telling which
+//! side altered bytes, see `send_checked()` and `receive_checked()`.
+
+use flate2::Crc;
+
+/// CRC32 (IEEE) of `bytes`, as zlib computes it.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
@@ -0,0 +1,154 @@
+//! Where time is read: document dates, transaction timestamps, the
+//! watchdog and shrinker sweeps, backoffs and log throttling, along with
+//! the run id document ids are derived from. The system clock by default;
+//! tests install a `ManualClock` to freeze and advance time, and pick the
+//! run id, so documents come out the same on every run.
+//!
+//! Waits, and the pacing of background threads, which sleep, keep to real
+//! time.
+
+use chrono::{DateTime, Utc};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// A source of time.
+pub trait Clock: Send + Sync {
+    /// The current instant, for measuring durations.
+    fn now(&self) -> Instant;
+    /// The current date.
+    fn utc(&self) -> DateTime<Utc>;
+}
+
+/// The system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock standing still until advanced.
+pub struct ManualClock {
+    instant: Instant,
+    date: DateTime<Utc>,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// A clock frozen at `date`.
+    pub fn at(date: DateTime<Utc>) -> Arc<ManualClock> {
+        Arc::new(ManualClock {
+            instant: Instant::now(),
+            date,
+            elapsed: Mutex::new(Duration::ZERO),
+        })
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, by: Duration) {
+        *self.lock() += by;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Duration> {
+        match self.elapsed.lock() {
+            Ok(elapsed) => elapsed,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.instant + *self.lock()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        let elapsed = chrono::Duration::from_std(*self.lock()).unwrap_or(chrono::Duration::zero());
+        self.date + elapsed
+    }
+}
+
+static CLOCK: RwLock<Option<Arc<dyn Clock>>> = RwLock::new(None);
+/// Set while a clock other than the system one is installed, so reading the
+/// time does not even take the lock otherwise.
+static REPLACED: AtomicBool = AtomicBool::new(false);
+static RUN_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Installs `clock` in place of the system clock.
+pub fn set(clock: Arc<dyn Clock>) {
+    *CLOCK.write().unwrap() = Some(clock);
+    REPLACED.store(true, Ordering::Relaxed);
+}
+
+/// Goes back to the system clock.
+pub fn reset() {
+    REPLACED.store(false, Ordering::Relaxed);
+    *CLOCK.write().unwrap() = None;
+}
+
+fn installed() -> Option<Arc<dyn Clock>> {
+    if !REPLACED.load(Ordering::Relaxed) {
+        return None;
+    }
+    match CLOCK.read() {
+        Ok(clock) => clock.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+pub fn now() -> Instant {
+    match installed() {
+        Some(clock) => clock.now(),
+        None => Instant::now(),
+    }
+}
+
+pub fn utc() -> DateTime<Utc> {
+    match installed() {
+        Some(clock) => clock.utc(),
+        None => Utc::now(),
+    }
+}
+
+/// Time elapsed since `earlier`, zero if it is later than now, as it can
+/// be across a clock change.
+pub fn since(earlier: Instant) -> Duration {
+    now().saturating_duration_since(earlier)
+}
+
+/// Identifies the run documents are persisted by, telling them apart from
+/// those of previous runs and instances, as transaction ids start over:
+/// the time the run started, in seconds since the epoch. Started by the
+/// first call when no run was.
+pub fn run_id() -> u64 {
+    match RUN_ID.load(Ordering::Relaxed) {
+        0 => {
+            let started = utc().timestamp().max(1) as u64;
+            match RUN_ID.compare_exchange(0, started, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => started,
+                Err(current) => current,
+            }
+        }
+        run_id => run_id,
+    }
+}
+
+/// Starts a run, on initializing. Run ids only ever increase, so runs
+/// started within the same second still get different ones.
+pub fn start_run() {
+    let started = utc().timestamp().max(1) as u64;
+    let _ = RUN_ID.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |run_id| {
+        Some(started.max(run_id + 1))
+    });
+}
+
+/// Sets the run id documents built from now on carry.
+pub fn set_run_id(run_id: u64) {
+    RUN_ID.store(run_id, Ordering::Relaxed);
+}
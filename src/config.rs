@@ -0,0 +1,1531 @@
+//! Settings of the library, layered as built-in defaults, overridden by a
+//! TOML file, overridden by `PRISM_*` environment variables.
+//!
+//! The file is given to the `configure()` export, or through `PRISM_CONFIG`
+//! when `configure()` isn't called before `init()`. Sections mirror the
+//! structs below, for example:
+//!
+//! ```toml
+//! [logging]
+//! backend = "file"
+//! file = "/var/log/prism.log"
+//!
+//! [backend]
+//! type = "elasticsearch"
+//!
+//! [backend.elasticsearch]
+//! hostname = "search"
+//! index = "lens-{hostname}"
+//!
+//! [backend.elasticsearch.fields]
+//! uri = "url.full"
+//! method = "http.request.method"
+//!
+//! [sampling]
+//! rate = 0.5
+//! ```
+
+use crate::decoding;
+use crate::document;
+use crate::encryption;
+use crate::logging::Filter;
+use crate::persistence;
+use crate::watchdog;
+use crate::worker;
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Environment variable with the path of the configuration file, used when
+/// `configure()` isn't called.
+pub const PATH_VARIABLE: &str = "PRISM_CONFIG";
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub logging: Logging,
+    pub backend: Backend,
+    pub limits: Limits,
+    pub filters: Filters,
+    pub sampling: Sampling,
+    pub query_params: QueryParams,
+    pub scanner: Scanner,
+    pub geoip: GeoIp,
+    pub telemetry: Telemetry,
+    pub audit: Audit,
+    pub recorder: Recorder,
+    pub retention: Retention,
+    pub rotation: Rotation,
+    pub encryption: Encryption,
+    pub offline: Offline,
+    /// Processes transactions fully, but only checks that documents
+    /// serialize instead of persisting them, and leaves bodies unchanged.
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    /// Where logs go: `syslog`, `stderr` or `file`.
+    pub backend: String,
+    /// Path logs are appended to by the `file` backend.
+    pub file: Option<PathBuf>,
+    /// Comma separated `level` and `module=level` directives, such as
+    /// `warn,persistence=debug`.
+    pub filter: String,
+    /// Format of log records: `text` or `json`, one object per line.
+    pub format: String,
+    /// Window repeated errors are throttled over, in seconds.
+    pub throttle_window: u64,
+    /// Regular expression of uris whose transactions are traced from the
+    /// start.
+    pub trace_uris: Option<String>,
+    /// Whether the trace lines of chunks received and sent carry the CRC32
+    /// of their bytes, to compare with what the caller saw.
+    pub chunk_checksums: bool,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            backend: "syslog".to_string(),
+            file: None,
+            filter: "info".to_string(),
+            format: "text".to_string(),
+            throttle_window: 60,
+            trace_uris: None,
+            chunk_checksums: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backend {
+    /// Persistence backend: `elasticsearch`, `har`, or `memory` which keeps
+    /// documents in the process, for tests.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Times a document is persisted before giving up on it, trying again
+    /// right away when the backend is unavailable. Documents keep their id
+    /// across attempts, so one that reached the backend before failing is
+    /// overwritten rather than duplicated.
+    pub persist_attempts: usize,
+    /// Builds documents as this older schema version did, for indices and
+    /// dashboards not migrated yet, see `document::SCHEMA_VERSION`.
+    pub schema_version: Option<u32>,
+    pub elasticsearch: Elasticsearch,
+    pub har: Har,
+    /// Where documents go instead of the default index, by content type or
+    /// tag, the first matching route winning. See `routing`.
+    #[serde(rename = "route")]
+    pub routes: Vec<Route>,
+}
+
+impl Backend {
+    /// Schema version of the documents built.
+    pub fn schema_version(&self) -> u32 {
+        self.schema_version.unwrap_or(document::SCHEMA_VERSION)
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend {
+            kind: "elasticsearch".to_string(),
+            persist_attempts: 1,
+            schema_version: None,
+            elasticsearch: Elasticsearch::default(),
+            har: Har::default(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Elasticsearch {
+    pub hostname: String,
+    pub port: u16,
+    /// `http` or `https`.
+    pub protocol: String,
+    /// `{hostname}` is replaced by the name of the host prism runs on.
+    pub index: String,
+    /// Names to store document fields under, by field, for deployments
+    /// following their own schema. Applies to the installed mapping too.
+    pub fields: BTreeMap<String, String>,
+    pub username: Option<String>,
+    /// Secrets are never serialized, so they stay out of fingerprints and
+    /// dumps. Each can instead be read from a file, see `Config::load`.
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    /// Sent as an `ApiKey` authorization header.
+    #[serde(skip_serializing)]
+    pub api_key: Option<String>,
+    pub api_key_file: Option<PathBuf>,
+    /// Persist requests sent concurrently, when built with the
+    /// `async-persistence` feature.
+    pub max_in_flight: usize,
+}
+
+impl Default for Elasticsearch {
+    fn default() -> Self {
+        Elasticsearch {
+            hostname: "search".to_string(),
+            port: 9200,
+            protocol: "https".to_string(),
+            index: "lens".to_string(),
+            fields: BTreeMap::new(),
+            username: None,
+            password: None,
+            password_file: None,
+            api_key: None,
+            api_key_file: None,
+            max_in_flight: 32,
+        }
+    }
+}
+
+/// A route to another index, taken by documents matching all of its
+/// conditions, at least one of which is given:
+///
+/// ```toml
+/// [[backend.route]]
+/// content_types = ["application/octet-stream", "application/zip"]
+/// index = "lens-downloads"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Route {
+    /// Prefixes of the response content type, any of which matches.
+    pub content_types: Vec<String>,
+    /// Tag the rules added to the transaction, see `rules`.
+    pub tag: Option<String>,
+    /// Index the documents are persisted to.
+    pub index: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Har {
+    /// Directory HAR files are written to, the temp directory by default.
+    pub directory: Option<PathBuf>,
+    /// Number of entries per HAR file before a new file is started.
+    pub entries_per_file: Option<usize>,
+}
+
+impl Har {
+    pub const DEFAULT_ENTRIES_PER_FILE: usize = 100;
+
+    pub fn entries_per_file(&self) -> usize {
+        self.entries_per_file
+            .unwrap_or(Self::DEFAULT_ENTRIES_PER_FILE)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Directory bodies are spilled to, the temp directory by default.
+    pub spool_dir: Option<PathBuf>,
+    /// Size past which a captured body is spilled to disk, in bytes.
+    pub spill_threshold: usize,
+    /// Largest chunk handed back by `send()` for encoded bodies, in bytes.
+    /// Chunks start at 16KB and grow up to it as the body streams.
+    pub output_buffer_size: usize,
+    /// Input buffer of the decoder, in bytes.
+    pub input_buffer_size: usize,
+    /// Output buffer of the encoder, in bytes.
+    pub encoder_buffer_size: usize,
+    /// How encoded bodies are decoded: `streaming` decodes them as they are
+    /// relayed, `lazy` relays them as received and decodes their captured
+    /// copy once, when it is persisted. Bodies rewritten by the rules are
+    /// always decoded as they stream.
+    pub decode_mode: String,
+    /// Number of characters kept in a body preview.
+    pub preview_length: usize,
+    /// Number of hosts tracked by the per-host statistics.
+    pub stats_max_hosts: usize,
+    /// Bytes a single transaction may hold in memory before a warning is
+    /// logged.
+    pub footprint_warning: Option<usize>,
+    /// Seconds a transaction may go on without `done()` before it is
+    /// reported as slow.
+    pub slow_threshold: Option<u64>,
+    /// Seconds output may wait for the client to take it, `send()` handing
+    /// nothing back meanwhile, before the client is reported as stalled.
+    pub stalled_consumer_timeout: Option<u64>,
+    /// What happens to a stalled client's transaction: `log` only reports
+    /// it, `abort` also stops relaying its body, dropping the output held
+    /// for it. Its body is still captured and persisted either way.
+    pub stalled_consumer_action: String,
+    /// Seconds a transaction may take from its start before prism stops
+    /// relaying it: `receive()` and `send()` then fail, and the transaction
+    /// is persisted as timed out with the body captured so far. Rules can
+    /// set their own, see `rules`. Unbounded when unset.
+    pub transaction_deadline: Option<u64>,
+    pub max_annotations: usize,
+    pub max_annotation_key: usize,
+    /// Longer annotation values are truncated.
+    pub max_annotation_value: usize,
+    /// Longer header values are truncated.
+    pub max_header_value: usize,
+    /// Bytes of header names and values a transaction holds. Headers
+    /// received past it are dropped, but for the status and client address.
+    pub max_header_bytes: usize,
+    /// Transactions with headers received before they began. Past it, the
+    /// headers of further ones are dropped until some begin or expire.
+    pub max_pending_headers: usize,
+    /// Seconds headers wait for their transaction to begin before they
+    /// are dropped. Kept until cleanup when unset.
+    pub pending_headers_timeout: Option<u64>,
+    /// Transactions tracked at once. Past it, new transactions are still
+    /// relayed, but their bodies are neither captured nor persisted.
+    pub max_transactions: Option<usize>,
+    /// Bytes all transactions and the persistence queue may hold together.
+    /// Past it, new transactions are relayed as past `max_transactions`.
+    pub memory_budget: Option<usize>,
+    /// Bytes of a body captured for persistence. Larger bodies are
+    /// truncated, and still relayed whole.
+    pub max_body_size: Option<usize>,
+    /// Chunks a transaction queues between `receive()` and `send()`.
+    /// Chunks received past it are dropped.
+    pub channel_capacity: Option<usize>,
+    /// Documents waiting for the persistence worker. Past it, or past
+    /// `queue_memory_budget`, `queue_overflow` applies.
+    pub max_queued_documents: usize,
+    /// Threads decoding compressed bodies as they are received, instead of
+    /// in `send()`. `0` keeps decoding in `send()`.
+    pub decode_workers: usize,
+    /// Output buffers kept from finished transactions for the next ones to
+    /// reuse, each at most `output_buffer_size`. `0` disables pooling.
+    pub buffer_pool_size: usize,
+    /// Threads persisting documents. `0` runs half as many as there are
+    /// CPUs, up to 8. Lowering it on reload winds workers down once they
+    /// are done with their document.
+    pub persistence_workers: usize,
+    /// Bytes held by the documents waiting for the persistence worker. An
+    /// empty queue takes any document, so it must only exceed the largest
+    /// document when `max_body_size` bounds it.
+    pub queue_memory_budget: usize,
+    /// Transactions the registry keeps room for once a traffic burst is
+    /// over.
+    pub registry_capacity_floor: usize,
+    /// Received chunks smaller than this are gathered into chunks of at
+    /// least this size before entering the pipeline, in bytes. `0` queues
+    /// every chunk as received.
+    pub coalesce_size: usize,
+    /// Milliseconds gathered chunks wait for more before they are handed
+    /// back anyway.
+    pub coalesce_delay_ms: u64,
+    /// What gives way when the persistence queue is full: `drop_newest`
+    /// drops the document submitted, `drop_oldest` the documents queued
+    /// longest, and `dead_letter` writes the document submitted to
+    /// `dead_letter_path` instead. Whatever the policy, the document
+    /// submitted is spooled when `offline.directory` is set.
+    pub queue_overflow: String,
+    pub dead_letter_path: Option<PathBuf>,
+    /// Documents persisted per second at most, over all the workers, with
+    /// bursts of up to a second worth. Documents past it wait in the queue,
+    /// where `queue_overflow` applies once it is full. Unlimited when unset.
+    pub persist_rate: Option<f64>,
+    /// Bytes of documents, as held in memory, persisted per second at most,
+    /// as `persist_rate`. A larger document goes once a second worth is
+    /// available, holding back those after it for longer.
+    pub persist_byte_rate: Option<usize>,
+}
+
+impl Limits {
+    /// Largest document a transaction produces, in memory: its body, as
+    /// it is only encoded when the document is serialized. Unbounded
+    /// without `max_body_size`.
+    pub fn max_document_size(&self) -> Option<usize> {
+        self.max_body_size
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            spool_dir: None,
+            spill_threshold: 16 * 1024 * 1024,
+            output_buffer_size: 1024 * 1024,
+            input_buffer_size: 32 * 1024,
+            encoder_buffer_size: 1024 * 1024,
+            decode_mode: decoding::STREAMING.to_string(),
+            preview_length: 512,
+            stats_max_hosts: 1000,
+            footprint_warning: None,
+            slow_threshold: None,
+            stalled_consumer_timeout: None,
+            stalled_consumer_action: watchdog::STALL_LOG.to_string(),
+            transaction_deadline: None,
+            max_annotations: 32,
+            max_annotation_key: 64,
+            max_annotation_value: 1024,
+            max_header_value: 16 * 1024,
+            max_header_bytes: 256 * 1024,
+            max_pending_headers: 10_000,
+            pending_headers_timeout: Some(60),
+            max_transactions: None,
+            memory_budget: None,
+            max_body_size: None,
+            channel_capacity: None,
+            max_queued_documents: 1000,
+            decode_workers: 0,
+            persistence_workers: 0,
+            buffer_pool_size: 32,
+            queue_memory_budget: 512 * 1024 * 1024,
+            registry_capacity_floor: 1024,
+            coalesce_size: 8 * 1024,
+            coalesce_delay_ms: 10,
+            queue_overflow: worker::DROP_NEWEST.to_string(),
+            dead_letter_path: None,
+            persist_rate: None,
+            persist_byte_rate: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filters {
+    /// HTML template used for block pages. `{reason}` and `{id}` are
+    /// replaced with the block reason and the transaction id.
+    pub block_page: Option<PathBuf>,
+    /// File with extra user agent rules, checked before the built-in ones.
+    /// Each line holds a kind (`browser`, `os` or `device`), a name and a
+    /// regular expression, separated by whitespace. For browsers and
+    /// operating systems the first capture group, if any, is the version.
+    pub user_agent_rules: Option<PathBuf>,
+    /// File with the filtering, redaction and tagging rules, see the `rules`
+    /// module.
+    pub rules: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sampling {
+    /// Share of transactions persisted, from 0 to 1.
+    pub rate: f64,
+    /// Runs ICAP OPTIONS requests as transactions with no body, persisted
+    /// as any other. Otherwise they are only acknowledged.
+    pub options: bool,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling {
+            rate: 1.0,
+            options: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryParams {
+    /// Query parameters persisted at most in `query_params`. Those past it
+    /// are left out, and `query_params_overflow` is set. `0` leaves query
+    /// strings unparsed.
+    pub max: usize,
+    /// Lowercases parameter names, so that `Q` and `q` are one parameter.
+    pub lowercase_keys: bool,
+}
+
+impl Default for QueryParams {
+    fn default() -> Self {
+        QueryParams {
+            max: 64,
+            lowercase_keys: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scanner {
+    /// Command run for each scanned body, through `sh -c`. The body is
+    /// written to its stdin, `{id}` is replaced with the transaction id and
+    /// the uri is available in the `PRISM_URI` environment variable. No
+    /// scanning when unset.
+    pub command: Option<String>,
+    /// Milliseconds after which a scanner process is killed.
+    pub timeout_ms: u64,
+    /// Maximum number of scanner processes running at once.
+    pub max_processes: usize,
+    /// Content type prefixes to scan, everything when empty.
+    pub content_types: Vec<String>,
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Scanner {
+            command: None,
+            timeout_ms: 10_000,
+            max_processes: 4,
+            content_types: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIp {
+    /// MaxMind country database.
+    pub country_db: Option<PathBuf>,
+    /// MaxMind ASN database.
+    pub asn_db: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Telemetry {
+    /// Address, such as `127.0.0.1:9464`, Prometheus metrics are served on.
+    pub metrics_address: Option<String>,
+    /// `host:port` of a StatsD agent.
+    pub statsd_address: Option<String>,
+    pub statsd_prefix: String,
+    /// Whether Datadog style tags are added to StatsD metrics.
+    pub statsd_tags: bool,
+    /// Seconds between summary lines.
+    pub summary_interval: Option<u64>,
+    /// Seconds between logs of the busiest hosts.
+    pub stats_log_interval: Option<u64>,
+    /// Whether `stats()` reports persist latencies since its previous call
+    /// rather than since start.
+    pub stats_reset_latency: bool,
+    /// Seconds between heartbeat lines, `0` for one at init only.
+    pub heartbeat_interval: u64,
+    /// OTLP/HTTP endpoint spans are exported to.
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Telemetry {
+            metrics_address: None,
+            statsd_address: None,
+            statsd_prefix: "prism.".to_string(),
+            statsd_tags: false,
+            summary_interval: None,
+            stats_log_interval: None,
+            stats_reset_latency: false,
+            heartbeat_interval: 5 * 60,
+            otlp_endpoint: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Audit {
+    /// Audit log of persisted documents. None is written when unset.
+    pub path: Option<PathBuf>,
+    /// Size past which the audit log is rotated, in bytes, instead of
+    /// `rotation.max_size`.
+    pub max_size: Option<u64>,
+}
+
+/// Deletion of old documents from the backend in use, see `retention`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// Seconds documents are kept. Documents are kept until deleted by
+    /// other means, such as an index lifecycle policy, when unset.
+    pub max_age: Option<u64>,
+    /// Seconds between retention sweeps.
+    pub interval: u64,
+    /// Documents deleted per request to the backend. Sweeps go on with
+    /// another batch a second later for as long as batches come back full.
+    pub batch_size: usize,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            max_age: None,
+            interval: 60 * 60,
+            batch_size: 1000,
+        }
+    }
+}
+
+/// Rotation of the files written line by line, the audit log and the dead
+/// letter file, see `rotation`. Rotated files are named after the time
+/// they were rotated at, as `<path>.<time>`, or `<path>.<time>.gz`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rotation {
+    /// Size past which files are rotated, in bytes.
+    pub max_size: u64,
+    /// Seconds after which files are rotated, counted from when they were
+    /// opened. Files are rotated by size only when unset.
+    pub max_age: Option<u64>,
+    /// Rotated files kept of each file, the oldest are removed past it.
+    pub keep: usize,
+    /// Compresses rotated files with gzip, on a background thread.
+    pub compress: bool,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation {
+            max_size: 100 * 1024 * 1024,
+            max_age: None,
+            keep: 5,
+            compress: false,
+        }
+    }
+}
+
+/// Encryption at rest of the files bodies are written to: spooled bodies,
+/// the dead letter file and HAR files, see `encryption`. Off unless a key
+/// is given, in one of the three ways.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Encryption {
+    /// AES-256 key, 32 bytes base64 encoded.
+    #[serde(skip_serializing)]
+    pub key: Option<String>,
+    pub key_file: Option<PathBuf>,
+    /// Shell command printing the key, such as a call to a key management
+    /// service, run when the configuration is loaded.
+    pub key_command: Option<String>,
+}
+
+/// Spooling to disk of the documents the backend can't take, to replay
+/// them once it recovers, see `offline`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Offline {
+    /// Directory documents are spooled to. When set, documents the queue
+    /// has no room for are spooled rather than `limits.queue_overflow`
+    /// applying, as are those failing to persist once the backend is found
+    /// unavailable. Read at startup to replay the documents spooled then.
+    pub directory: Option<PathBuf>,
+    /// Bytes the spool holds at most, the oldest documents are removed
+    /// past it.
+    pub max_size: u64,
+    /// Spooled documents queued again per second at most, once the backend
+    /// is available, along with the documents of live traffic.
+    pub replay_rate: f64,
+}
+
+impl Default for Offline {
+    fn default() -> Self {
+        Offline {
+            directory: None,
+            max_size: 1024 * 1024 * 1024,
+            replay_rate: 100.0,
+        }
+    }
+}
+
+/// Bodies recorded whole, base64 encoded, as their SHA-256, or as their
+/// size only. See `Recorder::bodies`.
+pub const RECORD_FULL: &str = "full";
+pub const RECORD_HASH: &str = "hash";
+pub const RECORD_NONE: &str = "none";
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Recorder {
+    /// Trace file the calls made for recorded transactions are appended to,
+    /// one JSON line each. Nothing is recorded when unset.
+    pub path: Option<PathBuf>,
+    /// Regular expression of uris whose transactions are recorded, all of
+    /// them when unset.
+    pub uris: Option<String>,
+    /// How received chunks are recorded: `full`, `hash` or `none`.
+    pub bodies: String,
+    /// Bytes of each chunk recorded with `full`, the rest is left out.
+    pub max_chunk_size: Option<usize>,
+    /// Headers whose values are recorded as their SHA-256, compared
+    /// ignoring case.
+    pub redact_headers: Vec<String>,
+    /// Transactions recorded at most. Those started past it are not.
+    pub max_transactions: Option<usize>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Recorder {
+            path: None,
+            uris: None,
+            bodies: RECORD_HASH.to_string(),
+            max_chunk_size: None,
+            redact_headers: ["authorization", "cookie", "set-cookie"]
+                .map(String::from)
+                .to_vec(),
+            max_transactions: None,
+        }
+    }
+}
+
+/// A problem with a setting, with the path of the setting (or the
+/// environment variable it came from) and what is wrong with it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigError {
+    pub path: String,
+    pub reason: String,
+}
+
+impl ConfigError {
+    pub fn new(path: &str, reason: impl Into<String>) -> Self {
+        ConfigError {
+            path: path.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.reason)
+    }
+}
+
+/// Sets `value`, the secret at `path`, from the content of `file` without
+/// its trailing newline. Setting both forms is an error, as it is unclear
+/// which one is meant.
+fn secret(
+    path: &str,
+    value: &mut Option<String>,
+    file: Option<&Path>,
+    errors: &mut Vec<ConfigError>,
+) {
+    let file = match file {
+        Some(file) => file,
+        None => return,
+    };
+    let file_path = format!("{}_file", path);
+    if value.is_some() {
+        errors.push(ConfigError::new(
+            &file_path,
+            format!(
+                "{} and {} are mutually exclusive, set only one",
+                path, file_path
+            ),
+        ));
+        return;
+    }
+    match std::fs::read_to_string(file) {
+        Ok(content) => {
+            check_permissions(file);
+            *value = Some(content.trim_end_matches(['\r', '\n']).to_string());
+        }
+        Err(e) => errors.push(ConfigError::new(
+            &file_path,
+            format!("failed reading {}: {}", file.display(), e),
+        )),
+    }
+}
+
+/// Runs `command` through the shell, returning what it printed without its
+/// trailing newline.
+fn key_command(command: &str) -> Result<String, String> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| format!("failed running \"{}\": {}", command, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "\"{}\" failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    String::from_utf8(output.stdout)
+        .map(|key| key.trim_end_matches(['\r', '\n']).to_string())
+        .map_err(|_| format!("\"{}\" printed no text", command))
+}
+
+/// Warns when a secret file can be read by others than its owner.
+#[cfg(unix)]
+fn check_permissions(file: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = std::fs::metadata(file) {
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            deferred_warning(format!(
+                "Secret file {} has permissions {:o}, expected 600 or stricter",
+                file.display(),
+                mode
+            ));
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_file: &Path) {}
+
+/// Checks the field name mapping: fields must be document fields, and no
+/// two fields may end up under the same name.
+fn validate_fields(fields: &BTreeMap<String, String>, errors: &mut Vec<ConfigError>) {
+    let known = persistence::document_fields();
+    for (field, name) in fields {
+        let path = format!("backend.elasticsearch.fields.{}", field);
+        if !known.contains(field) {
+            errors.push(ConfigError::new(&path, "unknown document field"));
+        } else if name.is_empty() {
+            errors.push(ConfigError::new(&path, "must not be empty"));
+        } else if let Some(other) = known
+            .iter()
+            .filter(|other| *other != field)
+            .find(|other| fields.get(*other).unwrap_or(*other) == name)
+        {
+            errors.push(ConfigError::new(
+                &path,
+                format!("\"{}\" is also the name of field {}", name, other),
+            ));
+        }
+    }
+}
+
+/// Applies environment variable overrides, recording the ones that can't
+/// be parsed.
+struct Env<'a> {
+    errors: &'a mut Vec<ConfigError>,
+}
+
+impl Env<'_> {
+    fn string(&mut self, variable: &str, value: &mut String) {
+        if let Ok(set) = std::env::var(variable) {
+            *value = set;
+        }
+    }
+
+    fn optional<T: FromStr>(&mut self, variable: &str, value: &mut Option<T>)
+    where
+        T::Err: fmt::Display,
+    {
+        if let Ok(set) = std::env::var(variable) {
+            match set.parse() {
+                Ok(parsed) => *value = Some(parsed),
+                Err(e) => self.errors.push(ConfigError::new(variable, e.to_string())),
+            }
+        }
+    }
+
+    fn parsed<T: FromStr>(&mut self, variable: &str, value: &mut T)
+    where
+        T::Err: fmt::Display,
+    {
+        if let Ok(set) = std::env::var(variable) {
+            match set.parse() {
+                Ok(parsed) => *value = parsed,
+                Err(e) => self.errors.push(ConfigError::new(variable, e.to_string())),
+            }
+        }
+    }
+
+    /// Flags are enabled by `1`.
+    fn flag(&mut self, variable: &str, value: &mut bool) {
+        if let Ok(set) = std::env::var(variable) {
+            *value = set == "1";
+        }
+    }
+
+    /// Comma separated lists.
+    fn list(&mut self, variable: &str, value: &mut Vec<String>) {
+        if let Ok(set) = std::env::var(variable) {
+            *value = set
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect();
+        }
+    }
+}
+
+impl Config {
+    /// Overrides settings with the `PRISM_*` environment variables, which
+    /// take precedence over the file.
+    fn apply_env(&mut self, errors: &mut Vec<ConfigError>) {
+        let mut env = Env { errors };
+
+        let logging = &mut self.logging;
+        env.string("PRISM_LOG_BACKEND", &mut logging.backend);
+        env.optional("PRISM_LOG_FILE", &mut logging.file);
+        env.string("PRISM_LOG", &mut logging.filter);
+        env.string("PRISM_LOG_FORMAT", &mut logging.format);
+        env.parsed("PRISM_LOG_THROTTLE_WINDOW", &mut logging.throttle_window);
+        env.optional("PRISM_TRACE_URIS", &mut logging.trace_uris);
+        env.flag("PRISM_CHUNK_CHECKSUMS", &mut logging.chunk_checksums);
+
+        let backend = &mut self.backend;
+        env.string("PRISM_BACKEND", &mut backend.kind);
+        env.parsed("PRISM_PERSIST_ATTEMPTS", &mut backend.persist_attempts);
+        env.optional("PRISM_SCHEMA_VERSION", &mut backend.schema_version);
+        env.string("PRISM_ES_HOSTNAME", &mut backend.elasticsearch.hostname);
+        env.parsed("PRISM_ES_PORT", &mut backend.elasticsearch.port);
+        env.string("PRISM_ES_PROTOCOL", &mut backend.elasticsearch.protocol);
+        env.string("PRISM_ES_INDEX", &mut backend.elasticsearch.index);
+        env.optional("PRISM_ES_USERNAME", &mut backend.elasticsearch.username);
+        env.optional("PRISM_ES_PASSWORD", &mut backend.elasticsearch.password);
+        env.optional(
+            "PRISM_ES_PASSWORD_FILE",
+            &mut backend.elasticsearch.password_file,
+        );
+        env.optional("PRISM_ES_API_KEY", &mut backend.elasticsearch.api_key);
+        env.optional(
+            "PRISM_ES_API_KEY_FILE",
+            &mut backend.elasticsearch.api_key_file,
+        );
+        let encryption = &mut self.encryption;
+        env.optional("PRISM_ENCRYPTION_KEY", &mut encryption.key);
+        env.optional("PRISM_ENCRYPTION_KEY_FILE", &mut encryption.key_file);
+        env.optional("PRISM_ENCRYPTION_KEY_COMMAND", &mut encryption.key_command);
+        env.parsed(
+            "PRISM_ES_MAX_IN_FLIGHT",
+            &mut backend.elasticsearch.max_in_flight,
+        );
+        env.optional("PRISM_HAR_DIR", &mut backend.har.directory);
+        env.optional("PRISM_HAR_ENTRIES", &mut backend.har.entries_per_file);
+
+        let limits = &mut self.limits;
+        env.optional("PRISM_SPOOL_DIR", &mut limits.spool_dir);
+        env.parsed("PRISM_SPILL_THRESHOLD", &mut limits.spill_threshold);
+        env.string("PRISM_DECODE_MODE", &mut limits.decode_mode);
+        env.parsed("PRISM_PREVIEW_LENGTH", &mut limits.preview_length);
+        env.parsed("PRISM_STATS_MAX_HOSTS", &mut limits.stats_max_hosts);
+        env.optional("PRISM_FOOTPRINT_WARNING", &mut limits.footprint_warning);
+        env.optional("PRISM_SLOW_THRESHOLD", &mut limits.slow_threshold);
+        env.optional(
+            "PRISM_STALLED_CONSUMER_TIMEOUT",
+            &mut limits.stalled_consumer_timeout,
+        );
+        env.string(
+            "PRISM_STALLED_CONSUMER_ACTION",
+            &mut limits.stalled_consumer_action,
+        );
+        env.optional(
+            "PRISM_TRANSACTION_DEADLINE",
+            &mut limits.transaction_deadline,
+        );
+        env.parsed("PRISM_MAX_HEADER_VALUE", &mut limits.max_header_value);
+        env.parsed("PRISM_MAX_HEADER_BYTES", &mut limits.max_header_bytes);
+        env.parsed("PRISM_MAX_PENDING_HEADERS", &mut limits.max_pending_headers);
+        env.optional(
+            "PRISM_PENDING_HEADERS_TIMEOUT",
+            &mut limits.pending_headers_timeout,
+        );
+        env.optional("PRISM_MAX_TRANSACTIONS", &mut limits.max_transactions);
+        env.optional("PRISM_MEMORY_BUDGET", &mut limits.memory_budget);
+        env.optional("PRISM_MAX_BODY_SIZE", &mut limits.max_body_size);
+        env.optional("PRISM_CHANNEL_CAPACITY", &mut limits.channel_capacity);
+        env.parsed(
+            "PRISM_MAX_QUEUED_DOCUMENTS",
+            &mut limits.max_queued_documents,
+        );
+        env.parsed("PRISM_QUEUE_MEMORY_BUDGET", &mut limits.queue_memory_budget);
+        env.parsed("PRISM_PERSISTENCE_WORKERS", &mut limits.persistence_workers);
+        env.parsed("PRISM_BUFFER_POOL_SIZE", &mut limits.buffer_pool_size);
+        env.parsed(
+            "PRISM_REGISTRY_CAPACITY_FLOOR",
+            &mut limits.registry_capacity_floor,
+        );
+        env.parsed("PRISM_COALESCE_SIZE", &mut limits.coalesce_size);
+        env.parsed("PRISM_COALESCE_DELAY_MS", &mut limits.coalesce_delay_ms);
+        env.string("PRISM_QUEUE_OVERFLOW", &mut limits.queue_overflow);
+        env.optional("PRISM_DEAD_LETTER_PATH", &mut limits.dead_letter_path);
+        env.optional("PRISM_PERSIST_RATE", &mut limits.persist_rate);
+        env.optional("PRISM_PERSIST_BYTE_RATE", &mut limits.persist_byte_rate);
+
+        env.optional("PRISM_BLOCK_PAGE", &mut self.filters.block_page);
+        env.optional("PRISM_UA_RULES", &mut self.filters.user_agent_rules);
+        env.optional("PRISM_RULES", &mut self.filters.rules);
+        env.parsed("PRISM_SAMPLING_RATE", &mut self.sampling.rate);
+        env.flag("PRISM_SAMPLING_OPTIONS", &mut self.sampling.options);
+        env.parsed("PRISM_QUERY_PARAMS_MAX", &mut self.query_params.max);
+        env.flag(
+            "PRISM_QUERY_PARAMS_LOWERCASE_KEYS",
+            &mut self.query_params.lowercase_keys,
+        );
+
+        let scanner = &mut self.scanner;
+        env.optional("PRISM_SCANNER_COMMAND", &mut scanner.command);
+        env.parsed("PRISM_SCANNER_TIMEOUT_MS", &mut scanner.timeout_ms);
+        env.parsed("PRISM_SCANNER_MAX_PROCESSES", &mut scanner.max_processes);
+        env.list("PRISM_SCANNER_CONTENT_TYPES", &mut scanner.content_types);
+
+        env.optional("PRISM_GEOIP_COUNTRY_DB", &mut self.geoip.country_db);
+        env.optional("PRISM_GEOIP_ASN_DB", &mut self.geoip.asn_db);
+
+        let telemetry = &mut self.telemetry;
+        env.optional("PRISM_METRICS_ADDRESS", &mut telemetry.metrics_address);
+        env.optional("PRISM_STATSD_ADDRESS", &mut telemetry.statsd_address);
+        env.string("PRISM_STATSD_PREFIX", &mut telemetry.statsd_prefix);
+        env.flag("PRISM_STATSD_TAGS", &mut telemetry.statsd_tags);
+        env.optional("PRISM_SUMMARY_INTERVAL", &mut telemetry.summary_interval);
+        env.optional(
+            "PRISM_STATS_LOG_INTERVAL",
+            &mut telemetry.stats_log_interval,
+        );
+        env.flag(
+            "PRISM_STATS_RESET_LATENCY",
+            &mut telemetry.stats_reset_latency,
+        );
+        env.parsed(
+            "PRISM_HEARTBEAT_INTERVAL",
+            &mut telemetry.heartbeat_interval,
+        );
+        env.optional("PRISM_OTLP_ENDPOINT", &mut telemetry.otlp_endpoint);
+
+        env.optional("PRISM_AUDIT_LOG", &mut self.audit.path);
+        env.optional("PRISM_AUDIT_LOG_MAX_SIZE", &mut self.audit.max_size);
+        let retention = &mut self.retention;
+        env.optional("PRISM_RETENTION_MAX_AGE", &mut retention.max_age);
+        env.parsed("PRISM_RETENTION_INTERVAL", &mut retention.interval);
+        env.parsed("PRISM_RETENTION_BATCH_SIZE", &mut retention.batch_size);
+        let rotation = &mut self.rotation;
+        env.parsed("PRISM_ROTATION_MAX_SIZE", &mut rotation.max_size);
+        env.optional("PRISM_ROTATION_MAX_AGE", &mut rotation.max_age);
+        env.parsed("PRISM_ROTATION_KEEP", &mut rotation.keep);
+        env.flag("PRISM_ROTATION_COMPRESS", &mut rotation.compress);
+        let offline = &mut self.offline;
+        env.optional("PRISM_OFFLINE_DIR", &mut offline.directory);
+        env.parsed("PRISM_OFFLINE_MAX_SIZE", &mut offline.max_size);
+        env.parsed("PRISM_OFFLINE_REPLAY_RATE", &mut offline.replay_rate);
+        let recorder = &mut self.recorder;
+        env.optional("PRISM_RECORDER_PATH", &mut recorder.path);
+        env.optional("PRISM_RECORDER_URIS", &mut recorder.uris);
+        env.string("PRISM_RECORDER_BODIES", &mut recorder.bodies);
+        env.optional(
+            "PRISM_RECORDER_MAX_TRANSACTIONS",
+            &mut recorder.max_transactions,
+        );
+        env.flag("PRISM_DRY_RUN", &mut self.dry_run);
+    }
+
+    /// Checks values that parse but make no sense.
+    fn validate(&self, errors: &mut Vec<ConfigError>) {
+        let logging = &self.logging;
+        if !["syslog", "stderr", "file"].contains(&logging.backend.as_str()) {
+            errors.push(ConfigError::new(
+                "logging.backend",
+                format!(
+                    "unknown backend \"{}\", expected syslog, stderr or file",
+                    logging.backend
+                ),
+            ));
+        }
+        if logging.backend == "file" && logging.file.is_none() {
+            errors.push(ConfigError::new(
+                "logging.file",
+                "required when logging.backend is \"file\"",
+            ));
+        }
+        if !["text", "json"].contains(&logging.format.as_str()) {
+            errors.push(ConfigError::new(
+                "logging.format",
+                format!(
+                    "unknown format \"{}\", expected text or json",
+                    logging.format
+                ),
+            ));
+        }
+        for warning in Filter::parse(&logging.filter).1 {
+            errors.push(ConfigError::new("logging.filter", warning));
+        }
+        if let Some(pattern) = &logging.trace_uris {
+            if let Err(e) = Regex::new(pattern) {
+                errors.push(ConfigError::new("logging.trace_uris", e.to_string()));
+            }
+        }
+
+        let backend = &self.backend;
+        if !["elasticsearch", "har", "memory"].contains(&backend.kind.as_str()) {
+            errors.push(ConfigError::new(
+                "backend.type",
+                format!(
+                    "unknown backend \"{}\", expected elasticsearch, har or memory",
+                    backend.kind
+                ),
+            ));
+        }
+        if backend.persist_attempts == 0 {
+            errors.push(ConfigError::new(
+                "backend.persist_attempts",
+                "must be at least 1",
+            ));
+        }
+        if let Some(version) = backend.schema_version {
+            if !(1..=document::SCHEMA_VERSION).contains(&version) {
+                errors.push(ConfigError::new(
+                    "backend.schema_version",
+                    format!("expected 1 to {}", document::SCHEMA_VERSION),
+                ));
+            }
+        }
+        if !["http", "https"].contains(&backend.elasticsearch.protocol.as_str()) {
+            errors.push(ConfigError::new(
+                "backend.elasticsearch.protocol",
+                "expected http or https",
+            ));
+        }
+        if backend.elasticsearch.hostname.is_empty() {
+            errors.push(ConfigError::new(
+                "backend.elasticsearch.hostname",
+                "must not be empty",
+            ));
+        }
+        validate_fields(&backend.elasticsearch.fields, errors);
+        if backend.elasticsearch.max_in_flight == 0 {
+            errors.push(ConfigError::new(
+                "backend.elasticsearch.max_in_flight",
+                "must be at least 1",
+            ));
+        }
+        if backend.har.entries_per_file == Some(0) {
+            errors.push(ConfigError::new(
+                "backend.har.entries_per_file",
+                "must be at least 1",
+            ));
+        }
+        for (index, route) in backend.routes.iter().enumerate() {
+            let path = format!("backend.route[{}]", index);
+            if route.content_types.is_empty() && route.tag.is_none() {
+                errors.push(ConfigError::new(
+                    &path,
+                    "needs content_types or a tag to match",
+                ));
+            }
+            if route.index.is_empty() {
+                errors.push(ConfigError::new(
+                    &format!("{}.index", path),
+                    "must not be empty",
+                ));
+            } else if route.index != route.index.to_ascii_lowercase() {
+                errors.push(ConfigError::new(
+                    &format!("{}.index", path),
+                    "index names must be lowercase",
+                ));
+            }
+        }
+
+        let limits = &self.limits;
+        for (path, value) in [
+            ("limits.output_buffer_size", limits.output_buffer_size),
+            ("limits.input_buffer_size", limits.input_buffer_size),
+            ("limits.encoder_buffer_size", limits.encoder_buffer_size),
+            ("limits.stats_max_hosts", limits.stats_max_hosts),
+            ("limits.max_queued_documents", limits.max_queued_documents),
+            ("limits.max_header_value", limits.max_header_value),
+            ("limits.max_header_bytes", limits.max_header_bytes),
+            ("limits.max_pending_headers", limits.max_pending_headers),
+        ] {
+            if value == 0 {
+                errors.push(ConfigError::new(path, "must be at least 1"));
+            }
+        }
+        for (path, value) in [
+            ("limits.max_transactions", limits.max_transactions),
+            ("limits.memory_budget", limits.memory_budget),
+            ("limits.max_body_size", limits.max_body_size),
+            ("limits.channel_capacity", limits.channel_capacity),
+            ("limits.persist_byte_rate", limits.persist_byte_rate),
+        ] {
+            if value == Some(0) {
+                errors.push(ConfigError::new(path, "must be at least 1"));
+            }
+        }
+        if limits.pending_headers_timeout == Some(0) {
+            errors.push(ConfigError::new(
+                "limits.pending_headers_timeout",
+                "must be at least 1",
+            ));
+        }
+        if limits.stalled_consumer_timeout == Some(0) {
+            errors.push(ConfigError::new(
+                "limits.stalled_consumer_timeout",
+                "must be at least 1",
+            ));
+        }
+        if limits.transaction_deadline == Some(0) {
+            errors.push(ConfigError::new(
+                "limits.transaction_deadline",
+                "must be at least 1",
+            ));
+        }
+        if ![decoding::STREAMING, decoding::LAZY].contains(&limits.decode_mode.as_str()) {
+            errors.push(ConfigError::new(
+                "limits.decode_mode",
+                format!(
+                    "unknown mode \"{}\", expected streaming or lazy",
+                    limits.decode_mode
+                ),
+            ));
+        }
+        if ![watchdog::STALL_LOG, watchdog::STALL_ABORT]
+            .contains(&limits.stalled_consumer_action.as_str())
+        {
+            errors.push(ConfigError::new(
+                "limits.stalled_consumer_action",
+                format!(
+                    "unknown action \"{}\", expected log or abort",
+                    limits.stalled_consumer_action
+                ),
+            ));
+        }
+        if let Some(rate) = limits.persist_rate {
+            if !(rate.is_finite() && rate > 0.0) {
+                errors.push(ConfigError::new(
+                    "limits.persist_rate",
+                    format!("{} is not a positive rate", rate),
+                ));
+            }
+        }
+        let policies = [
+            worker::DROP_NEWEST,
+            worker::DROP_OLDEST,
+            worker::DEAD_LETTER,
+        ];
+        if !policies.contains(&limits.queue_overflow.as_str()) {
+            errors.push(ConfigError::new(
+                "limits.queue_overflow",
+                format!(
+                    "unknown policy \"{}\", expected drop_newest, drop_oldest or dead_letter",
+                    limits.queue_overflow
+                ),
+            ));
+        }
+        if limits.queue_overflow == worker::DEAD_LETTER && limits.dead_letter_path.is_none() {
+            errors.push(ConfigError::new(
+                "limits.dead_letter_path",
+                "required when limits.queue_overflow is \"dead_letter\"",
+            ));
+        }
+        if let Some(size) = limits.max_document_size() {
+            if limits.queue_memory_budget <= size {
+                errors.push(ConfigError::new(
+                    "limits.queue_memory_budget",
+                    format!(
+                        "must exceed the largest document, {} bytes with limits.max_body_size at {}",
+                        size,
+                        limits.max_body_size.unwrap_or_default()
+                    ),
+                ));
+            }
+        }
+
+        if !(0.0..=1.0).contains(&self.sampling.rate) {
+            errors.push(ConfigError::new(
+                "sampling.rate",
+                format!("{} is not between 0 and 1", self.sampling.rate),
+            ));
+        }
+
+        if self.scanner.max_processes == 0 {
+            errors.push(ConfigError::new(
+                "scanner.max_processes",
+                "must be at least 1",
+            ));
+        }
+
+        if let Some(key) = &self.encryption.key {
+            if let Err(reason) = encryption::Key::parse(key) {
+                errors.push(ConfigError::new("encryption.key", reason));
+            }
+        }
+
+        let recorder = &self.recorder;
+        if let Some(pattern) = &recorder.uris {
+            if let Err(e) = Regex::new(pattern) {
+                errors.push(ConfigError::new("recorder.uris", e.to_string()));
+            }
+        }
+        if ![RECORD_FULL, RECORD_HASH, RECORD_NONE].contains(&recorder.bodies.as_str()) {
+            errors.push(ConfigError::new(
+                "recorder.bodies",
+                format!(
+                    "unknown mode \"{}\", expected full, hash or none",
+                    recorder.bodies
+                ),
+            ));
+        }
+        if recorder.max_transactions == Some(0) {
+            errors.push(ConfigError::new(
+                "recorder.max_transactions",
+                "must be at least 1",
+            ));
+        }
+
+        let retention = &self.retention;
+        for (path, value) in [
+            ("retention.max_age", retention.max_age.unwrap_or(1)),
+            ("retention.interval", retention.interval),
+            ("retention.batch_size", retention.batch_size as u64),
+            ("audit.max_size", self.audit.max_size.unwrap_or(1)),
+            ("rotation.max_size", self.rotation.max_size),
+            ("rotation.max_age", self.rotation.max_age.unwrap_or(1)),
+            ("offline.max_size", self.offline.max_size),
+        ] {
+            if value == 0 {
+                errors.push(ConfigError::new(path, "must be at least 1"));
+            }
+        }
+        let replay_rate = self.offline.replay_rate;
+        if !(replay_rate.is_finite() && replay_rate > 0.0) {
+            errors.push(ConfigError::new(
+                "offline.replay_rate",
+                format!("{} is not a positive rate", replay_rate),
+            ));
+        }
+    }
+
+    /// Defaults with the environment overrides that parse, ignoring the
+    /// others, used until a configuration is installed.
+    fn from_env() -> Config {
+        let mut config = Config::default();
+        config.apply_env(&mut Vec::new());
+        config.load_secrets(&mut Vec::new());
+        config
+    }
+
+    /// Reads the secrets given as `*_file` settings.
+    fn load_secrets(&mut self, errors: &mut Vec<ConfigError>) {
+        let elasticsearch = &mut self.backend.elasticsearch;
+        secret(
+            "backend.elasticsearch.password",
+            &mut elasticsearch.password,
+            elasticsearch.password_file.as_deref(),
+            errors,
+        );
+        secret(
+            "backend.elasticsearch.api_key",
+            &mut elasticsearch.api_key,
+            elasticsearch.api_key_file.as_deref(),
+            errors,
+        );
+        let encryption = &mut self.encryption;
+        secret(
+            "encryption.key",
+            &mut encryption.key,
+            encryption.key_file.as_deref(),
+            errors,
+        );
+        if let Some(command) = &encryption.key_command {
+            if encryption.key.is_some() {
+                errors.push(ConfigError::new(
+                    "encryption.key_command",
+                    "encryption.key, encryption.key_file and encryption.key_command are mutually exclusive, set only one",
+                ));
+            } else {
+                match key_command(command) {
+                    Ok(key) => encryption.key = Some(key),
+                    Err(reason) => errors.push(ConfigError::new("encryption.key_command", reason)),
+                }
+            }
+        }
+    }
+
+    /// Loads the configuration: defaults, overridden by the file at `path`
+    /// if any, overridden by the environment. Secrets given as `*_file`
+    /// settings are then read from their files. All problems are returned,
+    /// not just the first one.
+    pub fn load(path: Option<&Path>) -> Result<Config, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let mut config = match path {
+            Some(path) => Config::read(path)?,
+            None => Config::default(),
+        };
+        config.apply_env(&mut errors);
+        match config.validated() {
+            Ok(config) if errors.is_empty() => Ok(config),
+            Ok(_) => Err(errors),
+            Err(more) => {
+                errors.extend(more);
+                Err(errors)
+            }
+        }
+    }
+
+    /// Reads the secrets given as `*_file` settings from their files and
+    /// validates the configuration, for configurations built in code.
+    pub fn validated(mut self) -> Result<Config, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        self.load_secrets(&mut errors);
+        self.validate(&mut errors);
+        if errors.is_empty() {
+            Ok(self)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Keeps the settings of `running` that are only read at init, so the
+    /// configuration reflects what is in effect. Returns the settings that
+    /// differed and need a restart to change.
+    fn keep_fixed(&mut self, running: &Config) -> Vec<&'static str> {
+        fn keep<T: Clone + PartialEq>(
+            path: &'static str,
+            value: &mut T,
+            running: &T,
+            changed: &mut Vec<&'static str>,
+        ) {
+            if value != running {
+                *value = running.clone();
+                changed.push(path);
+            }
+        }
+
+        let mut changed = Vec::new();
+        let logging = &mut self.logging;
+        keep(
+            "logging.backend",
+            &mut logging.backend,
+            &running.logging.backend,
+            &mut changed,
+        );
+        keep(
+            "logging.file",
+            &mut logging.file,
+            &running.logging.file,
+            &mut changed,
+        );
+        keep(
+            "logging.format",
+            &mut logging.format,
+            &running.logging.format,
+            &mut changed,
+        );
+        keep(
+            "logging.throttle_window",
+            &mut logging.throttle_window,
+            &running.logging.throttle_window,
+            &mut changed,
+        );
+        keep("backend", &mut self.backend, &running.backend, &mut changed);
+        keep(
+            "limits.stats_max_hosts",
+            &mut self.limits.stats_max_hosts,
+            &running.limits.stats_max_hosts,
+            &mut changed,
+        );
+        keep(
+            "limits.decode_workers",
+            &mut self.limits.decode_workers,
+            &running.limits.decode_workers,
+            &mut changed,
+        );
+        keep("scanner", &mut self.scanner, &running.scanner, &mut changed);
+        keep("geoip", &mut self.geoip, &running.geoip, &mut changed);
+        keep(
+            "telemetry",
+            &mut self.telemetry,
+            &running.telemetry,
+            &mut changed,
+        );
+        keep("audit", &mut self.audit, &running.audit, &mut changed);
+        keep(
+            "recorder",
+            &mut self.recorder,
+            &running.recorder,
+            &mut changed,
+        );
+        changed
+    }
+
+    fn read(path: &Path) -> Result<Config, Vec<ConfigError>> {
+        let location = path.display().to_string();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| vec![ConfigError::new(&location, e.to_string())])?;
+        toml::from_str(&content).map_err(|e| {
+            // The message names the offending key and what was expected.
+            vec![ConfigError::new(
+                &location,
+                e.to_string().trim_end().replace('\n', " "),
+            )]
+        })
+    }
+}
+
+static CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+/// Warnings raised while loading, kept until `log_warnings()` as the
+/// configuration is loaded before the logger is installed.
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+/// File the configuration was loaded from, read again on reload.
+static SOURCE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// The configuration in use. Until one is installed, this is the defaults
+/// with the environment overrides that parse.
+pub fn get() -> Arc<Config> {
+    if let Some(config) = CONFIG.read().unwrap().as_ref() {
+        return config.clone();
+    }
+    let mut config = CONFIG.write().unwrap();
+    config
+        .get_or_insert_with(|| Arc::new(Config::from_env()))
+        .clone()
+}
+
+/// Installs a configuration.
+pub fn set(config: Config) {
+    *CONFIG.write().unwrap() = Some(Arc::new(config));
+}
+
+/// Loads and installs the configuration from the file at `path`. Nothing
+/// changes when it is invalid.
+pub fn configure(path: &Path) -> Result<(), Vec<ConfigError>> {
+    let config = Config::load(Some(path))?;
+    *SOURCE.lock().unwrap() = Some(path.to_path_buf());
+    set(config);
+    Ok(())
+}
+
+/// Loads and installs the configuration from the file named by
+/// `PRISM_CONFIG`, unless one was installed through `configure()` already.
+/// When it is invalid, the defaults with the environment overrides that
+/// parse are used instead, and the problems returned.
+pub fn init() -> Vec<ConfigError> {
+    if CONFIG.read().unwrap().is_some() {
+        return Vec::new();
+    }
+    let path = std::env::var_os(PATH_VARIABLE).map(PathBuf::from);
+    *SOURCE.lock().unwrap() = path.clone();
+    match Config::load(path.as_deref()) {
+        Ok(config) => {
+            set(config);
+            Vec::new()
+        }
+        Err(errors) => {
+            set(Config::from_env());
+            errors
+        }
+    }
+}
+
+/// Reads the configuration file again and, if it is valid, installs it for
+/// transactions started from now on. Settings only read at init keep their
+/// running value; the ones the file changes are returned, as they need a
+/// restart to apply.
+pub fn reload() -> Result<Vec<&'static str>, Vec<ConfigError>> {
+    let path = SOURCE.lock().unwrap().clone();
+    let mut config = Config::load(path.as_deref())?;
+    let changed = config.keep_fixed(&get());
+    set(config);
+    Ok(changed)
+}
+
+fn deferred_warning(message: String) {
+    WARNINGS.lock().unwrap().push(message);
+}
+
+/// Logs the warnings raised by the loads since the previous call.
+pub fn log_warnings() {
+    for message in WARNINGS.lock().unwrap().drain(..) {
+        warn!("{}", message);
+    }
+}
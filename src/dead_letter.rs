@@ -0,0 +1,82 @@
+//! File documents are written to when the persistence queue is full and
+//! `limits.queue_overflow` is `dead_letter`, one JSON document per line, so
+//! they can be replayed into the backend later. The file is rotated as
+//! `rotation` configures, and holds one record per document when
+//! `encryption` is on.
+
+use crate::config;
+use crate::document::Document;
+use crate::encryption;
+use crate::logging::throttled;
+use crate::rotation::RotatingFile;
+use log::{info, warn, Level};
+use std::sync::{Mutex, OnceLock};
+
+static FILE: OnceLock<Option<Mutex<RotatingFile>>> = OnceLock::new();
+
+fn open() -> Option<Mutex<RotatingFile>> {
+    let path = config::get().limits.dead_letter_path.clone()?;
+    let mut file = RotatingFile::new(path, None);
+    // Opened again on the next document when it fails now.
+    match file.open() {
+        Ok(_) => info!(
+            "Writing dead-lettered documents to {}",
+            file.path().display()
+        ),
+        Err(e) => warn!(
+            "Failed opening dead letter file {}, overflowing documents will be dropped: {}",
+            file.path().display(),
+            e
+        ),
+    }
+    Some(Mutex::new(file))
+}
+
+/// Appends a document, with the field names configured for the backend.
+/// Returns whether it was written, documents that could not be are
+/// counted as dropped.
+pub fn write(document: &Document) -> bool {
+    let Some(file) = FILE.get_or_init(open) else {
+        return false;
+    };
+    let fields = &config::get().backend.elasticsearch.fields;
+    let mut line = match document.to_json(fields) {
+        Ok(line) => line,
+        Err(e) => {
+            warn!("Failed serializing document {}: {}", document.id, e);
+            return false;
+        }
+    };
+    line.push(b'\n');
+    let line = match encryption::seal(line) {
+        Ok(line) => line,
+        Err(e) => {
+            throttled!(
+                Level::Warn,
+                "dead-letter",
+                "Failed encrypting document {}: {}",
+                document.id,
+                e
+            );
+            return false;
+        }
+    };
+    let mut file = match file.lock() {
+        Ok(file) => file,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    match file.append(&line) {
+        Ok(()) => true,
+        Err(e) => {
+            throttled!(
+                Level::Warn,
+                "dead-letter",
+                "Failed writing document {} to the dead letter file {}: {}",
+                document.id,
+                file.path().display(),
+                e
+            );
+            false
+        }
+    }
+}
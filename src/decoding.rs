@@ -0,0 +1,144 @@
+//! Threads decoding compressed bodies as they are received, off the thread
+//! calling `send()`. A transaction still decodes itself whatever the pool
+//! did not get to yet, so the pool only ever moves work ahead of `send()`.
+//!
+//! In lazy mode, see `limits.decode_mode`, bodies are relayed as received
+//! instead, and their captured copy decoded at once by `decode()` when
+//! their document is persisted.
+
+use crate::pipeline::RawDataReader;
+use flate2::read::GzDecoder;
+use log::info;
+use std::io::{ErrorKind, Read};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// `limits.decode_mode` decoding bodies as they are relayed, and encoding
+/// them again for the client.
+pub const STREAMING: &str = "streaming";
+/// `limits.decode_mode` relaying bodies untouched, and decoding their
+/// captured copy in the persistence worker.
+pub const LAZY: &str = "lazy";
+
+/// Size of the reads `decode()` makes.
+const READ_SIZE: usize = 64 * 1024;
+
+/// The coding applied last of a `Content-Encoding` list, the one decoding
+/// undoes. Repeated header lines make a list of a single coding as well.
+pub fn outermost(encoding: &str) -> &str {
+    encoding.rsplit(',').next().unwrap_or_default().trim()
+}
+
+/// Whether `decode()` takes bodies of `encoding`.
+pub fn decodes(encoding: &str) -> bool {
+    let coding = outermost(encoding);
+    coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("br")
+}
+
+/// A body decoded by `decode()`.
+pub struct Decoded {
+    pub body: Vec<u8>,
+    /// Set when decoding stopped at the limit.
+    pub truncated: bool,
+    /// Error decoding ran into, after decoding `body`.
+    pub error: Option<std::io::Error>,
+}
+
+/// Decodes a whole body of `encoding`, keeping at most `limit` bytes of it.
+/// A `partial` body, cut short when it was captured, decodes as far as it
+/// goes without that counting as an error.
+pub fn decode(encoding: &str, encoded: &[u8], limit: Option<usize>, partial: bool) -> Decoded {
+    let mut reader: Box<dyn Read + '_> = if outermost(encoding).eq_ignore_ascii_case("br") {
+        Box::new(brotli_decompressor::Decompressor::new(encoded, READ_SIZE))
+    } else {
+        Box::new(GzDecoder::new(encoded))
+    };
+    let mut decoded = Decoded {
+        body: Vec::new(),
+        truncated: false,
+        error: None,
+    };
+    let mut buffer = vec![0; READ_SIZE];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return decoded,
+            Ok(size) => {
+                let room = limit.map_or(size, |limit| limit - decoded.body.len());
+                decoded.body.extend_from_slice(&buffer[..size.min(room)]);
+                if size > room {
+                    decoded.truncated = true;
+                    return decoded;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if partial && e.kind() == ErrorKind::UnexpectedEof => return decoded,
+            Err(e) => {
+                decoded.error = Some(e);
+                return decoded;
+            }
+        }
+    }
+}
+
+pub struct DecodePool {
+    sender: Option<Sender<Arc<RawDataReader>>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+fn work(receiver: &Mutex<Receiver<Arc<RawDataReader>>>) {
+    loop {
+        let reader = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(poisoned) => poisoned.into_inner().recv(),
+        };
+        match reader {
+            Ok(reader) => reader.decode_ahead(),
+            Err(_) => break,
+        }
+    }
+}
+
+impl DecodePool {
+    /// Starts `workers` threads, or nothing when `0`.
+    pub fn new(workers: usize) -> Option<Self> {
+        if workers == 0 {
+            return None;
+        }
+        let (sender, receiver) = channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..workers)
+            .map(|index| {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("prism-decode-{}", index))
+                    .spawn(move || work(&receiver))
+                    .unwrap()
+            })
+            .collect();
+        info!("Started {} decode workers", workers);
+        Some(DecodePool {
+            sender: Some(sender),
+            threads,
+        })
+    }
+
+    /// Asks for the data a transaction received so far to be decoded. A
+    /// transaction's data is decoded in order however many workers pick it.
+    pub fn submit(&self, reader: Arc<RawDataReader>) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(reader);
+        }
+    }
+}
+
+impl Drop for DecodePool {
+    /// Lets the workers finish what was submitted, then waits for them.
+    fn drop(&mut self) {
+        self.sender = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        info!("Decode workers stopped");
+    }
+}
@@ -0,0 +1,86 @@
+//! Tokio runtime running the persistence worker's asynchronous persists, so
+//! a single worker keeps many backend requests in flight.
+
+use log::{info, warn};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::Semaphore;
+
+/// How long stopping waits for the persists in flight.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Dispatcher {
+    runtime: Option<Runtime>,
+    /// One per persist allowed in flight.
+    permits: Arc<Semaphore>,
+    max_in_flight: usize,
+}
+
+impl Dispatcher {
+    pub fn new(max_in_flight: usize) -> Self {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("prism-dispatch")
+            .enable_all()
+            .build()
+            .unwrap();
+        info!(
+            "Started persistence runtime, up to {} persists in flight",
+            max_in_flight
+        );
+        Dispatcher {
+            runtime: Some(runtime),
+            permits: Arc::new(Semaphore::new(max_in_flight)),
+            max_in_flight,
+        }
+    }
+
+    /// Runs `persist` on the runtime, first waiting for fewer than the
+    /// maximum to be in flight, so the queue backs up as it would with
+    /// blocking persists.
+    pub fn spawn(&self, persist: impl Future<Output = ()> + Send + 'static) {
+        let Some(runtime) = &self.runtime else {
+            return;
+        };
+        let Ok(permit) = runtime.block_on(self.permits.clone().acquire_owned()) else {
+            return;
+        };
+        runtime.spawn(async move {
+            persist.await;
+            drop(permit);
+        });
+    }
+
+    /// Number of persists running.
+    pub fn in_flight(&self) -> usize {
+        self.max_in_flight - self.permits.available_permits()
+    }
+}
+
+impl Drop for Dispatcher {
+    /// Waits for the persists in flight, for at most `SHUTDOWN_TIMEOUT`,
+    /// then stops the runtime.
+    fn drop(&mut self) {
+        let Some(runtime) = self.runtime.take() else {
+            return;
+        };
+        let permits = self.permits.clone();
+        let all = self.max_in_flight as u32;
+        let drained = runtime.block_on(async {
+            tokio::time::timeout(SHUTDOWN_TIMEOUT, permits.acquire_many(all))
+                .await
+                .is_ok()
+        });
+        if !drained {
+            warn!(
+                "Abandoning {} persists still in flight after {}s",
+                self.in_flight(),
+                SHUTDOWN_TIMEOUT.as_secs()
+            );
+        }
+        runtime.shutdown_background();
+        info!("Persistence runtime stopped");
+    }
+}
@@ -0,0 +1,598 @@
+use crate::cache::CacheMetadata;
+use crate::clock;
+use crate::config::Limits;
+use crate::decoding;
+use crate::headers::Headers;
+use crate::navigation::Navigation;
+use crate::preview;
+use crate::routing;
+use crate::scanner::ScanResult;
+use crate::transaction::{Transaction, CLIENT_HEADER};
+use crate::uri::{self, QueryValue};
+use crate::user_agent::UserAgent;
+use base64::display::Base64Display;
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Version of the shape of documents, persisted with them and recorded in
+/// the index mapping. Bumped, with an entry in `MIGRATIONS`, whenever
+/// fields are added, renamed or change meaning.
+pub const SCHEMA_VERSION: u32 = 9;
+
+/// How a schema version changed documents from the one before.
+struct Migration {
+    version: u32,
+    added: &'static [&'static str],
+    /// From their old name to their new one.
+    renamed: &'static [(&'static str, &'static str)],
+}
+
+/// Version 1 is the shape documents had before they were versioned.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        added: &[
+            "schema_version",
+            "document_id",
+            "has_body",
+            "compression_ratio",
+            "bytes_saved",
+            "output_compression_ratio",
+            "output_bytes_saved",
+        ],
+        renamed: &[],
+    },
+    Migration {
+        version: 3,
+        added: &["run_id"],
+        renamed: &[],
+    },
+    Migration {
+        version: 4,
+        added: &["referer", "referer_host", "fetch_kind"],
+        renamed: &[],
+    },
+    Migration {
+        version: 5,
+        added: &["routed_index"],
+        renamed: &[],
+    },
+    Migration {
+        version: 6,
+        added: &["request_headers", "response_headers"],
+        renamed: &[],
+    },
+    Migration {
+        version: 7,
+        added: &["client_stalled"],
+        renamed: &[],
+    },
+    Migration {
+        version: 8,
+        added: &["timed_out"],
+        renamed: &[],
+    },
+    Migration {
+        version: 9,
+        added: &["path"],
+        renamed: &[],
+    },
+];
+
+/// Reshapes the top level entries of a document, or of the mapping, as
+/// they were at schema `version`, undoing the migrations since, latest
+/// first.
+pub fn migrate(object: &mut serde_json::Map<String, serde_json::Value>, version: u32) {
+    for migration in MIGRATIONS.iter().rev() {
+        if migration.version <= version {
+            break;
+        }
+        for field in migration.added {
+            object.remove(*field);
+        }
+        for (old, new) in migration.renamed {
+            if let Some(value) = object.remove(*new) {
+                object.insert(old.to_string(), value);
+            }
+        }
+    }
+}
+
+/// Value persisted in place of redacted headers.
+const REDACTED: &str = "[REDACTED]";
+
+/// Cap on compression ratios, about deflate's own limit of 1032:1, so a
+/// few encoded bytes cannot make up an absurd ratio.
+const MAX_COMPRESSION_RATIO: f64 = 1032.0;
+
+/// Id a transaction's document is stored under: a UUID (version 8) hashed
+/// from the run id and the transaction id, so the same document always
+/// gets the same one, however many times it is persisted or replayed.
+fn document_id(run_id: u64, id: i64) -> String {
+    let mut hash = Sha256::digest(format!("{}-{}", run_id, id));
+    hash[6] = (hash[6] & 0x0f) | 0x80;
+    hash[8] = (hash[8] & 0x3f) | 0x80;
+    let hex: String = hash[0..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Decoded size over encoded size, 1.0 when either is zero: there was
+/// nothing to compress.
+fn compression_ratio(decoded: usize, encoded: usize) -> f64 {
+    if decoded == 0 || encoded == 0 {
+        return 1.0;
+    }
+    (decoded as f64 / encoded as f64).min(MAX_COMPRESSION_RATIO)
+}
+
+/// Bytes the encoding saved, negative when it grew the body.
+fn bytes_saved(decoded: usize, encoded: usize) -> i64 {
+    decoded as i64 - encoded as i64
+}
+
+/// Decoded body of a document, shared by its `body` and `raw_body` fields.
+/// Both are serialized straight from the bytes, so persisting a large body
+/// builds it neither as text nor as base64 on the side.
+#[derive(Clone, Default)]
+pub struct Body(Arc<Vec<u8>>);
+
+impl Body {
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The body as text, `None` when it is not valid UTF-8.
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn base64(&self) -> String {
+        general_purpose::STANDARD.encode(self.bytes())
+    }
+}
+
+/// Serializes a body as text, empty when it is not valid UTF-8.
+fn body_text<S: Serializer>(body: &Body, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(body.text().unwrap_or_default())
+}
+
+/// Serializes a body base64 encoded, written out as it is encoded.
+fn body_base64<S: Serializer>(body: &Body, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&Base64Display::new(
+        body.bytes(),
+        &general_purpose::STANDARD,
+    ))
+}
+
+/// Reads back a body serialized by `body_base64()`.
+fn body_from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Body, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    general_purpose::STANDARD
+        .decode(encoded)
+        .map(|bytes| Body(Arc::new(bytes)))
+        .map_err(serde::de::Error::custom)
+}
+
+/// The persisted form of a transaction.
+///
+/// Documents own all of their data so they can be handed over to the
+/// persistence worker once a transaction is done. They deserialize back
+/// from their JSON, shaped as the current schema with no field renamed,
+/// but for the fields that are not persisted, see `offline`.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Document {
+    /// Transaction id, used by backends to derive the document id.
+    #[serde(skip)]
+    pub id: i64,
+    /// Stable id of the document, see `document_id()`. Backends store the
+    /// document under it, overwriting any earlier copy.
+    pub document_id: String,
+    /// Run of the process the document was persisted by, see
+    /// `clock::run_id()`.
+    pub run_id: u64,
+    /// Schema version the document is shaped as, `SCHEMA_VERSION` unless
+    /// an older one is configured.
+    pub schema_version: u32,
+    pub method: String,
+    pub uri: String,
+    /// Path of the uri, without its query, for exact matches.
+    pub path: String,
+    /// Persisted as text, empty when the body is not valid UTF-8. Left out,
+    /// as is `raw_body`, when nothing was captured.
+    #[serde(
+        serialize_with = "body_text",
+        skip_serializing_if = "Body::is_empty",
+        skip_deserializing
+    )]
+    pub body: Body,
+    /// First characters of the body as plain text.
+    pub body_preview: String,
+    /// The same body, persisted base64 encoded.
+    #[serde(
+        serialize_with = "body_base64",
+        deserialize_with = "body_from_base64",
+        skip_serializing_if = "Body::is_empty"
+    )]
+    pub raw_body: Body,
+    /// Cleared for HEAD requests, 204 and 304 responses, and responses
+    /// done without body bytes.
+    pub has_body: bool,
+    pub encoding: String,
+    pub date: String,
+    pub truncated: bool,
+    /// Content-Length announced by the origin.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_length: Option<u64>,
+    /// Number of body bytes handed back to the client.
+    pub emitted_length: usize,
+    /// How much the origin's encoding compressed the body, see
+    /// `compression_ratio()`. Bodies prism does not decode count as 1.0.
+    pub compression_ratio: f64,
+    pub bytes_saved: i64,
+    /// The same for the body handed back to the client, re-encoded from
+    /// the decoded one. Left out for blocked transactions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_compression_ratio: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_bytes_saved: Option<i64>,
+    pub blocked: bool,
+    pub block_reason: String,
+    /// Set when the transaction was reported by the slow transaction watchdog.
+    pub slow: bool,
+    /// Set when the client stopped taking the body handed back, see
+    /// `limits.stalled_consumer_timeout`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub client_stalled: bool,
+    /// Set when the transaction went past its deadline, and was persisted
+    /// with the body captured by then, see `limits.transaction_deadline`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub timed_out: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Parameters of the query string, decoded, see `config::QueryParams`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub query_params: BTreeMap<String, QueryValue>,
+    /// Set when parameters of the query string were left out of
+    /// `query_params`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub query_params_overflow: bool,
+    /// Set for 3xx responses with a `Location` header.
+    pub is_redirect: bool,
+    /// Absolute URL a redirect points to, resolved against the request URI.
+    /// Left out when the request URI has no scheme and authority to resolve
+    /// a relative location against.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    /// ISO country code of the client, filled in by GeoIP enrichment.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_asn: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_as_org: Option<String>,
+    #[serde(flatten)]
+    pub navigation: Navigation,
+    #[serde(flatten)]
+    pub cache: CacheMetadata,
+    #[serde(flatten)]
+    pub user_agent: Option<UserAgent>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    /// Tags added by the rules the transaction matched.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Index a route sent the document to, `None` for the default one, see
+    /// `routing`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub routed_index: Option<String>,
+    /// Headers of the request, with the casing they were received with,
+    /// redacted as the rules ask.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub request_headers: BTreeMap<String, String>,
+    /// The same for the response, pseudo-headers left out.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub response_headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scanner_result: Option<ScanResult>,
+    /// Size of the decoded body.
+    #[serde(skip)]
+    pub body_size: usize,
+    /// Number of body bytes received from the origin.
+    #[serde(skip)]
+    pub bytes_received: usize,
+    #[serde(skip)]
+    pub started_at: DateTime<Utc>,
+    /// Time between the start of the transaction and its first body bytes.
+    #[serde(skip)]
+    pub wait: Duration,
+    /// Time spent receiving the body.
+    #[serde(skip)]
+    pub receive: Duration,
+    /// Set while the body is still encoded as received, to be decoded by
+    /// `decode_body()`, see `limits.decode_mode`.
+    #[serde(skip)]
+    pub decode_pending: bool,
+}
+
+impl Document {
+    /// Builds the document for a completed transaction, taking its captured
+    /// body rather than copying it.
+    pub fn new(transaction: &mut Transaction) -> Self {
+        let preview_length = transaction.config.limits.preview_length;
+        // Previewed once decoded, when persisted.
+        let body_preview = if transaction.lazy_decode {
+            String::new()
+        } else {
+            transaction.with_body_head(preview::head_size(preview_length), |head| {
+                preview::preview(
+                    head,
+                    transaction.headers.get("Content-Type"),
+                    preview_length,
+                )
+            })
+        };
+        // Bodies passed through undecoded count as not compressed.
+        let (decoded, received, emitted) = if transaction.pipeline.decode {
+            (
+                transaction.decoded_size(),
+                transaction.bytes_total,
+                transaction.bytes_sent,
+            )
+        } else {
+            (0, 0, 0)
+        };
+        let blocked = transaction.blocked.is_some();
+        let body = Body(Arc::new(transaction.take_body()));
+        let body_size = body.bytes().len();
+        let status = transaction.status();
+        let location = match status {
+            Some(300..=399) => transaction.headers.get("Location"),
+            _ => None,
+        };
+        let redirect_location =
+            location.and_then(|location| uri::resolve(&transaction.uri, location));
+        let settings = &transaction.config.query_params;
+        let parsed = uri::parse(&transaction.uri);
+        let query_params = match parsed.query {
+            Some(query) if settings.max > 0 => {
+                uri::query_params(query, settings.max, settings.lowercase_keys)
+            }
+            _ => uri::QueryParams::default(),
+        };
+        let elapsed = clock::since(transaction.started);
+        let wait = match transaction.first_byte {
+            Some(first_byte) => first_byte.duration_since(transaction.started),
+            None => elapsed,
+        };
+        let run_id = clock::run_id();
+
+        Document {
+            id: transaction.id,
+            document_id: document_id(run_id, transaction.id),
+            run_id,
+            schema_version: transaction.config.backend.schema_version(),
+            method: transaction.method.clone(),
+            uri: transaction.uri.clone(),
+            path: match parsed.path {
+                "" => "/".to_string(),
+                path => path.to_string(),
+            },
+            body: body.clone(),
+            body_preview,
+            raw_body: body,
+            has_body: !transaction.bodyless,
+            encoding: match &transaction.encoding {
+                Some(encoding) => encoding.to_string(),
+                None => "".to_string(),
+            },
+            date: clock::utc().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            truncated: transaction.body_truncated(),
+            original_length: transaction
+                .headers
+                .get("Content-Length")
+                .and_then(|length| length.trim().parse().ok()),
+            emitted_length: transaction.bytes_sent,
+            compression_ratio: compression_ratio(decoded, received),
+            bytes_saved: bytes_saved(decoded, received),
+            output_compression_ratio: (!blocked).then(|| compression_ratio(decoded, emitted)),
+            output_bytes_saved: (!blocked).then(|| bytes_saved(decoded, emitted)),
+            blocked,
+            block_reason: match &transaction.blocked {
+                Some(reason) => reason.to_string(),
+                None => "".to_string(),
+            },
+            slow: transaction.slow,
+            client_stalled: transaction.client_stalled,
+            timed_out: transaction.timed_out,
+            status,
+            host: transaction.host(),
+            query_params: query_params.params,
+            query_params_overflow: query_params.overflow,
+            is_redirect: location.is_some(),
+            redirect_location,
+            client_ip: transaction
+                .headers
+                .get(CLIENT_HEADER)
+                .map(|address| address.trim().to_string()),
+            client_country: None,
+            client_asn: None,
+            client_as_org: None,
+            navigation: navigation(transaction),
+            cache: CacheMetadata::new(&transaction.headers),
+            user_agent: transaction
+                .request_header("User-Agent")
+                .map(UserAgent::parse),
+            annotations: transaction.annotations.clone(),
+            tags: transaction.actions.tags.clone(),
+            routed_index: routing::route(
+                &transaction.config.backend.routes,
+                &transaction.headers,
+                &transaction.actions.tags,
+            ),
+            request_headers: persisted_headers(transaction, &transaction.request_headers),
+            response_headers: persisted_headers(transaction, &transaction.headers),
+            scanner_result: None,
+            body_size,
+            bytes_received: transaction.bytes_total,
+            started_at: transaction.started_at,
+            wait,
+            receive: elapsed.saturating_sub(wait),
+            decode_pending: transaction.lazy_decode && body_size > 0,
+        }
+    }
+
+    /// Decodes a body captured as received, see `decode_pending`, keeping
+    /// at most `limits.max_body_size` bytes of it, and fills in the fields
+    /// derived from the decoded body. A body failing to decode keeps what
+    /// was decoded before the error, which is returned.
+    pub fn decode_body(&mut self, limits: &Limits) -> Option<std::io::Error> {
+        if !std::mem::take(&mut self.decode_pending) {
+            return None;
+        }
+        let captured = self.body.bytes().len();
+        let decoded = decoding::decode(
+            &self.encoding,
+            self.body.bytes(),
+            limits.max_body_size,
+            self.truncated || self.timed_out,
+        );
+        // A truncated capture only tells how much the bytes captured
+        // compressed.
+        let received = if self.truncated {
+            captured
+        } else {
+            self.bytes_received
+        };
+        let size = decoded.body.len();
+        self.truncated |= decoded.truncated;
+        self.compression_ratio = compression_ratio(size, received);
+        self.bytes_saved = bytes_saved(size, received);
+        if self.output_compression_ratio.is_some() {
+            self.output_compression_ratio = Some(compression_ratio(size, self.emitted_length));
+            self.output_bytes_saved = Some(bytes_saved(size, self.emitted_length));
+        }
+        let content_type = self
+            .response_headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, value)| value.as_str());
+        let head = &decoded.body[..size.min(preview::head_size(limits.preview_length))];
+        self.body_preview = preview::preview(head, content_type, limits.preview_length);
+        self.body = Body(Arc::new(decoded.body));
+        self.raw_body = self.body.clone();
+        self.body_size = size;
+        decoded.error
+    }
+}
+
+/// Headers of a transaction as persisted, without pseudo-headers and with
+/// the values of those the rules redact replaced.
+fn persisted_headers(transaction: &Transaction, headers: &Headers) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .filter(|(name, _)| !name.starts_with(':'))
+        .map(|(name, value)| {
+            let value = if transaction.actions.redacts(name) {
+                REDACTED
+            } else {
+                value
+            };
+            (name.to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// Navigation context of a transaction, with the referer left out when
+/// the rules redact its header.
+fn navigation(transaction: &Transaction) -> Navigation {
+    let mut navigation = Navigation::new(&Headers::combined(
+        &transaction.request_headers,
+        &transaction.headers,
+    ));
+    if navigation.referer.is_some() && transaction.actions.redacts("Referer") {
+        navigation.referer = Some(REDACTED.to_string());
+        navigation.referer_host = None;
+    }
+    navigation
+}
+
+/// Renames the top level entries of `object` as in `fields`, which maps
+/// document field names to the names to use instead. Entries are all taken
+/// out before being put back, so fields can swap names.
+pub fn rename_fields(
+    object: &mut serde_json::Map<String, serde_json::Value>,
+    fields: &BTreeMap<String, String>,
+) {
+    let renamed: Vec<(&String, serde_json::Value)> = fields
+        .iter()
+        .filter_map(|(field, name)| object.remove(field).map(|value| (name, value)))
+        .collect();
+    for (name, value) in renamed {
+        object.insert(name.clone(), value);
+    }
+}
+
+impl Document {
+    /// Rough number of bytes the document holds, counting its body and
+    /// other variable length fields.
+    pub fn memory_size(&self) -> usize {
+        self.body.bytes().len()
+            + self.body_preview.len()
+            + self.uri.len()
+            + self
+                .request_headers
+                .iter()
+                .chain(&self.response_headers)
+                .map(|(name, value)| name.len() + value.len())
+                .sum::<usize>()
+    }
+
+    /// Serializes the document, shaped as its schema version and with the
+    /// field names configured for the deployment, into a buffer sized for
+    /// it up front. Reshaping goes through a `serde_json::Value`, which
+    /// holds the body as strings again.
+    pub fn to_json(&self, fields: &BTreeMap<String, String>) -> serde_json::Result<Vec<u8>> {
+        if fields.is_empty() && self.schema_version == SCHEMA_VERSION {
+            let mut json = Vec::with_capacity(self.json_size());
+            serde_json::to_writer(&mut json, self)?;
+            return Ok(json);
+        }
+        let mut value = serde_json::to_value(self)?;
+        if let Some(object) = value.as_object_mut() {
+            migrate(object, self.schema_version);
+            rename_fields(object, fields);
+        }
+        serde_json::to_vec(&value)
+    }
+
+    /// Expected size of the serialized document: the body as text and
+    /// base64, plus room for the other fields.
+    fn json_size(&self) -> usize {
+        let body = self.body.bytes().len();
+        body + body.div_ceil(3) * 4 + self.memory_size() - body + 4096
+    }
+}
@@ -0,0 +1,141 @@
+//! Snapshot of the state of an instance, written by `dump_state()` to
+//! diagnose transactions that are stuck without attaching a debugger.
+
+use crate::api::Prism;
+use crate::clock;
+use crate::error::PrismError;
+use crate::heartbeat;
+use crate::metrics;
+use crate::mode::Mode;
+use crate::pipeline::Footprint;
+use crate::transaction::Transaction;
+use crate::worker::{self, QueueSnapshot};
+use log::{error, info};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize)]
+struct Dump {
+    version: &'static str,
+    git: &'static str,
+    taken_at: String,
+    config: String,
+    backend: &'static str,
+    pending_headers: usize,
+    pending_header_bytes: usize,
+    queue: QueueSnapshot,
+    counters: metrics::CountersSnapshot,
+    /// By id.
+    transactions: Vec<TransactionState>,
+}
+
+#[derive(Serialize)]
+struct TransactionState {
+    id: i64,
+    method: String,
+    uri: String,
+    mode: Mode,
+    encoding: Option<String>,
+    status: Option<u16>,
+    bytes_received: usize,
+    bytes_sent: usize,
+    /// Bytes of the header names and values held.
+    header_bytes: usize,
+    /// Chunks written to the pipeline but not taken off it yet.
+    queued_chunks: usize,
+    footprint: Footprint,
+    done: bool,
+    finished: bool,
+    error: bool,
+    blocked: bool,
+    block_reason: Option<String>,
+    shed: bool,
+    sampled: bool,
+    slow: bool,
+    client_stalled: bool,
+    relay_aborted: bool,
+    timed_out: bool,
+    persisted: bool,
+    trace: bool,
+    /// Milliseconds since the transaction started.
+    age: u128,
+    /// Milliseconds since body bytes were last received.
+    idle: u128,
+    /// Milliseconds output has been waiting for the client to take it.
+    output_waiting: Option<u128>,
+}
+
+impl TransactionState {
+    fn of(transaction: &Transaction) -> Self {
+        TransactionState {
+            id: transaction.id,
+            method: transaction.method.clone(),
+            uri: transaction.uri.clone(),
+            mode: transaction.mode,
+            encoding: transaction.encoding.clone(),
+            status: transaction.status(),
+            bytes_received: transaction.bytes_total,
+            bytes_sent: transaction.bytes_sent,
+            header_bytes: transaction.headers.bytes() + transaction.request_headers.bytes(),
+            queued_chunks: transaction.pipeline.queued(),
+            footprint: transaction.pipeline.footprint(),
+            done: transaction.is_done,
+            finished: transaction.is_finished,
+            error: transaction.error,
+            blocked: transaction.blocked.is_some(),
+            block_reason: transaction.blocked.clone(),
+            shed: transaction.shed,
+            sampled: transaction.sampled,
+            slow: transaction.slow,
+            client_stalled: transaction.client_stalled,
+            relay_aborted: transaction.relay_aborted,
+            timed_out: transaction.timed_out,
+            persisted: transaction.persisted,
+            trace: transaction.trace,
+            age: clock::since(transaction.started).as_millis(),
+            idle: clock::since(transaction.last_activity).as_millis(),
+            output_waiting: transaction
+                .output_waiting
+                .map(|since| clock::since(since).as_millis()),
+        }
+    }
+}
+
+/// Takes the snapshot, locking the instance for one transaction at a time,
+/// then writes it with nothing locked.
+pub fn write(prism: &Prism, path: &Path) -> Result<(), PrismError> {
+    let mut ids = prism.transaction_ids();
+    ids.sort_unstable();
+    // Transactions cleaned up since their ids were taken are left out.
+    let transactions = ids
+        .into_iter()
+        .filter_map(|id| prism.with_transaction(id, |t| TransactionState::of(t)).ok())
+        .collect();
+    let dump = Dump {
+        version: env!("CARGO_PKG_VERSION"),
+        git: env!("PRISM_GIT_HASH"),
+        taken_at: clock::utc().to_rfc3339(),
+        config: heartbeat::config_fingerprint(),
+        backend: worker::backend_name(),
+        pending_headers: prism.pending_headers(),
+        pending_header_bytes: prism.pending_header_bytes(),
+        queue: prism.queue(),
+        counters: metrics::COUNTERS.snapshot(),
+        transactions,
+    };
+
+    let json = serde_json::to_vec_pretty(&dump).map_err(|e| {
+        error!("Failed serializing state dump: {}", e);
+        PrismError::Encode
+    })?;
+    std::fs::write(path, json).map_err(|e| {
+        error!("Failed writing state dump {}: {}", path.display(), e);
+        PrismError::Io
+    })?;
+    info!(
+        "Dumped the state of {} transactions to {}",
+        dump.transactions.len(),
+        path.display()
+    );
+    Ok(())
+}
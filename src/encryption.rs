@@ -0,0 +1,169 @@
+//! Encryption at rest of the files bodies are written to, with AES-256-GCM
+//! under the key of `encryption`.
+//!
+//! Files are written as records, one JSON line each, holding the id of the
+//! key that sealed it, a random nonce and the ciphertext:
+//!
+//! ```json
+//! {"key_id":"3f2a9c4e5d6b7a81","nonce":"…","ciphertext":"…"}
+//! ```
+//!
+//! Line oriented files get a record per line, spooled bodies one per chunk
+//! and HAR files one for the whole file. The key id, derived from the key,
+//! is authenticated with the ciphertext, so files written under earlier
+//! keys can be told apart and read with them, see `prism-decrypt`.
+
+use crate::config;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::BufRead;
+use std::sync::{Arc, Mutex};
+
+/// Size of keys, in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// Size of nonces, in bytes.
+const NONCE_SIZE: usize = 12;
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key_id: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// An AES-256-GCM key, with its id.
+pub struct Key {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+/// The key in use, with the configured value it was parsed from.
+static CURRENT: Mutex<Option<(String, Arc<Key>)>> = Mutex::new(None);
+
+impl Key {
+    /// A key from its base64 encoding. Its id is the start of the SHA-256
+    /// of the key, in hex.
+    pub fn parse(encoded: &str) -> Result<Key, String> {
+        let bytes = general_purpose::STANDARD
+            .decode(encoded.trim())
+            .map_err(|e| format!("not base64: {}", e))?;
+        if bytes.len() != KEY_SIZE {
+            return Err(format!(
+                "expected {} bytes, base64 encoded, got {}",
+                KEY_SIZE,
+                bytes.len()
+            ));
+        }
+        let cipher = Aes256Gcm::new_from_slice(&bytes)
+            .map_err(|_| format!("expected {} bytes", KEY_SIZE))?;
+        let id = Sha256::digest(&bytes)[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Ok(Key { id, cipher })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Seals `plaintext` as one record, newline terminated.
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: self.id.as_bytes(),
+        };
+        // Only fails for plaintexts of more than 64GB.
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .expect("plaintext fits a record");
+        let record = Record {
+            key_id: self.id.clone(),
+            nonce: general_purpose::STANDARD.encode(nonce.as_slice()),
+            ciphertext: general_purpose::STANDARD.encode(ciphertext),
+        };
+        let mut line = serde_json::to_vec(&record).expect("records serialize");
+        line.push(b'\n');
+        line
+    }
+
+    fn open(&self, record: &Record) -> Result<Vec<u8>, String> {
+        let nonce = general_purpose::STANDARD
+            .decode(&record.nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == NONCE_SIZE)
+            .ok_or("malformed nonce")?;
+        let ciphertext = general_purpose::STANDARD
+            .decode(&record.ciphertext)
+            .map_err(|_| "malformed ciphertext")?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: record.key_id.as_bytes(),
+        };
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| "authentication failed, the record was tampered with".to_string())
+    }
+}
+
+/// Opens the records read from `reader`, with whichever of `keys` sealed
+/// each, until at least `limit` bytes of plaintext were read.
+pub fn open(reader: impl BufRead, keys: &[Key], limit: usize) -> Result<Vec<u8>, String> {
+    let mut plaintext = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        if plaintext.len() >= limit {
+            break;
+        }
+        let line = line.map_err(|e| e.to_string())?;
+        if line.is_empty() {
+            continue;
+        }
+        // Records are numbered from 1, as lines are.
+        let failed = |reason: String| format!("record {}: {}", index + 1, reason);
+        let record: Record =
+            serde_json::from_str(&line).map_err(|e| failed(format!("not a record: {}", e)))?;
+        let key = keys
+            .iter()
+            .find(|key| key.id == record.key_id)
+            .ok_or_else(|| failed(format!("no key with id {}", record.key_id)))?;
+        plaintext.extend(key.open(&record).map_err(failed)?);
+    }
+    Ok(plaintext)
+}
+
+/// The key of `encryption`, `None` when encryption is off. Errors only
+/// when the configuration was installed unvalidated.
+pub fn key() -> Result<Option<Arc<Key>>, String> {
+    let config = config::get();
+    let Some(encoded) = &config.encryption.key else {
+        return Ok(None);
+    };
+    let mut current = CURRENT.lock().unwrap();
+    match &*current {
+        Some((parsed, key)) if parsed == encoded => Ok(Some(key.clone())),
+        _ => {
+            let key = Arc::new(Key::parse(encoded)?);
+            *current = Some((encoded.clone(), key.clone()));
+            Ok(Some(key))
+        }
+    }
+}
+
+/// `plaintext` sealed with the key in use, as it is when encryption is
+/// off. Writers fail rather than write plaintext when the key is unusable.
+pub fn seal(plaintext: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    match key() {
+        Ok(Some(key)) => Ok(key.seal(&plaintext)),
+        Ok(None) => Ok(plaintext),
+        Err(e) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("encryption.key: {}", e),
+        )),
+    }
+}
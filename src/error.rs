@@ -0,0 +1,126 @@
+//! Errors reported by prism, with numeric codes that stay stable across
+//! releases. This module only depends on std, as the build script includes
+//! it to generate the C header with the codes.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrismError {
+    /// An argument passed over FFI was missing or malformed.
+    InvalidArgument,
+    /// No live transaction has the given id.
+    UnknownTransaction,
+    /// The response body could not be decoded.
+    Decode,
+    /// The response body could not be re-encoded.
+    Encode,
+    /// The persistence backend could not store a document.
+    BackendUnavailable,
+    /// A document could not be queued for persistence.
+    QueueOverflow,
+    /// A panic was caught.
+    Panic,
+    /// The configuration could not be loaded or failed validation.
+    InvalidConfig,
+    /// A file could not be written.
+    Io,
+    /// The transaction went past its deadline, see `limits.transaction_deadline`.
+    Timeout,
+    /// A chunk did not match the checksum passed along with it.
+    ChecksumMismatch,
+}
+
+impl PrismError {
+    /// All variants, in code order.
+    pub const ALL: [PrismError; 11] = [
+        PrismError::InvalidArgument,
+        PrismError::UnknownTransaction,
+        PrismError::Decode,
+        PrismError::Encode,
+        PrismError::BackendUnavailable,
+        PrismError::QueueOverflow,
+        PrismError::Panic,
+        PrismError::InvalidConfig,
+        PrismError::Io,
+        PrismError::Timeout,
+        PrismError::ChecksumMismatch,
+    ];
+
+    /// Stable numeric code. Codes are never reused or renumbered.
+    pub fn code(self) -> i32 {
+        match self {
+            PrismError::InvalidArgument => 1,
+            PrismError::UnknownTransaction => 2,
+            PrismError::Decode => 3,
+            PrismError::Encode => 4,
+            PrismError::BackendUnavailable => 5,
+            PrismError::QueueOverflow => 6,
+            PrismError::Panic => 7,
+            PrismError::InvalidConfig => 8,
+            PrismError::Io => 9,
+            PrismError::Timeout => 10,
+            PrismError::ChecksumMismatch => 11,
+        }
+    }
+
+    /// Label the error is reported under in metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            PrismError::InvalidArgument => "invalid_argument",
+            PrismError::UnknownTransaction => "unknown_transaction",
+            PrismError::Decode => "decode",
+            PrismError::Encode => "encode",
+            PrismError::BackendUnavailable => "backend_unavailable",
+            PrismError::QueueOverflow => "queue_overflow",
+            PrismError::Panic => "panic",
+            PrismError::InvalidConfig => "invalid_config",
+            PrismError::Io => "io",
+            PrismError::Timeout => "timeout",
+            PrismError::ChecksumMismatch => "checksum_mismatch",
+        }
+    }
+
+    /// Position of the error in `ALL`.
+    pub fn index(self) -> usize {
+        self.code() as usize - 1
+    }
+}
+
+impl fmt::Display for PrismError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            PrismError::InvalidArgument => "invalid argument",
+            PrismError::UnknownTransaction => "unknown transaction",
+            PrismError::Decode => "failed decoding body",
+            PrismError::Encode => "failed encoding body",
+            PrismError::BackendUnavailable => "persistence backend unavailable",
+            PrismError::QueueOverflow => "persistence queue unavailable",
+            PrismError::Panic => "internal panic",
+            PrismError::InvalidConfig => "invalid configuration",
+            PrismError::Io => "failed writing a file",
+            PrismError::Timeout => "transaction deadline passed",
+            PrismError::ChecksumMismatch => "chunk checksum mismatch",
+        };
+        f.write_str(message)
+    }
+}
+
+/// Renders the error codes as C preprocessor constants.
+#[allow(dead_code)]
+pub fn c_header() -> String {
+    let mut header = String::from(
+        "/* Generated from src/error.rs by build.rs, do not edit. */\n\
+         #ifndef PRISM_ERRORS_H\n\
+         #define PRISM_ERRORS_H\n\n",
+    );
+    for error in PrismError::ALL {
+        header.push_str(&format!(
+            "/* {} */\n#define PRISM_E_{} {}\n",
+            error,
+            error.label().to_ascii_uppercase(),
+            error.code()
+        ));
+    }
+    header.push_str("\n#endif\n");
+    header
+}
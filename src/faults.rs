@@ -0,0 +1,264 @@
+//! Failures injected on demand, for tests of how prism recovers from them.
+//! Only built with the `fault-injection` feature: without it, none of this
+//! exists and backends and decoders are used as they are.
+//!
+//! Backends are wrapped so persists fail when asked to through
+//! `backend()`, and decoders read their input through `decoder()`'s
+//! corruptions, set per transaction before it starts. Faults are global and
+//! stay until used up or cleared, so tests injecting them run one at a time.
+
+use crate::document::Document;
+use crate::error::PrismError;
+use crate::persistence::Backend;
+#[cfg(feature = "async-persistence")]
+use crate::persistence::PersistFuture;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Failures of the persistence backend.
+pub struct BackendFaults {
+    /// Persists left to fail, whatever the document, and how.
+    next: Mutex<Option<(usize, PrismError)>>,
+    /// Documents failing every time they are persisted.
+    ids: Mutex<BTreeMap<i64, PrismError>>,
+    /// Persists left to fail once the document was stored, and how.
+    stored: Mutex<Option<(usize, PrismError)>>,
+    /// Set while the backend reports it is not ready, holding documents
+    /// back.
+    held: AtomicBool,
+    /// How long the next persists take, in order.
+    delays: Mutex<VecDeque<Duration>>,
+}
+
+static BACKEND: BackendFaults = BackendFaults {
+    next: Mutex::new(None),
+    ids: Mutex::new(BTreeMap::new()),
+    stored: Mutex::new(None),
+    held: AtomicBool::new(false),
+    delays: Mutex::new(VecDeque::new()),
+};
+
+/// Controls the failures of the backend documents are persisted to.
+pub fn backend() -> &'static BackendFaults {
+    &BACKEND
+}
+
+impl BackendFaults {
+    /// Fails the next `count` persists with `error`.
+    pub fn fail_next(&self, count: usize, error: PrismError) {
+        *lock(&self.next) = Some((count, error)).filter(|(count, _)| *count > 0);
+    }
+
+    /// Fails every persist of document `id` with `error`.
+    pub fn fail_id(&self, id: i64, error: PrismError) {
+        lock(&self.ids).insert(id, error);
+    }
+
+    /// Fails the next `count` persists with `error` after the document was
+    /// stored, as a backend timing out on a write it made does.
+    pub fn fail_next_stored(&self, count: usize, error: PrismError) {
+        *lock(&self.stored) = Some((count, error)).filter(|(count, _)| *count > 0);
+    }
+
+    /// Has the backend report it is not ready, or ready again.
+    pub fn hold(&self, held: bool) {
+        self.held.store(held, Ordering::Relaxed);
+    }
+
+    /// Slows the next persists down, the first one by `delays[0]` and so
+    /// on, whether they fail or not.
+    pub fn delay_next(&self, delays: &[Duration]) {
+        lock(&self.delays).extend(delays);
+    }
+
+    pub fn clear(&self) {
+        *lock(&self.next) = None;
+        lock(&self.ids).clear();
+        *lock(&self.stored) = None;
+        lock(&self.delays).clear();
+        self.hold(false);
+    }
+
+    /// The error persisting document `id` fails with, if any, once the
+    /// persist was delayed as asked to.
+    fn take(&self, id: i64) -> Option<PrismError> {
+        let delay = lock(&self.delays).pop_front();
+        if let Some(delay) = delay {
+            std::thread::sleep(delay);
+        }
+        if let Some(error) = lock(&self.ids).get(&id) {
+            return Some(*error);
+        }
+        take_next(&self.next)
+    }
+
+    /// What a persist that went through reports.
+    fn stored(&self, result: Result<(), PrismError>) -> Result<(), PrismError> {
+        match result {
+            Ok(()) => take_next(&self.stored).map_or(Ok(()), Err),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Takes one of the failures left in `next`.
+fn take_next(next: &Mutex<Option<(usize, PrismError)>>) -> Option<PrismError> {
+    let mut next = lock(next);
+    let (count, error) = (*next)?;
+    *next = Some((count - 1, error)).filter(|(count, _)| *count > 0);
+    Some(error)
+}
+
+/// A backend failing as asked to through `backend()`.
+struct Faulty(Arc<dyn Backend>);
+
+/// Wraps a backend built for persisting.
+pub(crate) fn wrap(backend: Arc<dyn Backend>) -> Arc<dyn Backend> {
+    Arc::new(Faulty(backend))
+}
+
+impl Backend for Faulty {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn destination(&self) -> String {
+        self.0.destination()
+    }
+
+    fn document_destination(&self, document: &Document) -> String {
+        self.0.document_destination(document)
+    }
+
+    fn document_id(&self, document: &Document) -> String {
+        self.0.document_id(document)
+    }
+
+    fn persist(&self, document: &Document) -> Result<(), PrismError> {
+        match BACKEND.take(document.id) {
+            Some(error) => Err(error),
+            None => BACKEND.stored(self.0.persist(document)),
+        }
+    }
+
+    fn ready(&self) -> bool {
+        !BACKEND.held.load(Ordering::Relaxed) && self.0.ready()
+    }
+
+    fn prune(&self, cutoff: DateTime<Utc>, batch_size: usize) -> Result<usize, PrismError> {
+        self.0.prune(cutoff, batch_size)
+    }
+
+    fn migrate_mapping(&self) -> Result<Vec<String>, PrismError> {
+        self.0.migrate_mapping()
+    }
+
+    #[cfg(feature = "async-persistence")]
+    fn persist_async(&self, document: &Document) -> Option<PersistFuture> {
+        match BACKEND.take(document.id) {
+            Some(error) => Some(Box::pin(async move { Err(error) })),
+            None => {
+                let persist = self.0.persist_async(document)?;
+                Some(Box::pin(async move { BACKEND.stored(persist.await) }))
+            }
+        }
+    }
+}
+
+/// A change made to the body a decoder reads, at an offset counted from
+/// the start of the body as received.
+#[derive(Clone, Copy, Debug)]
+pub enum Corruption {
+    /// Flips the bits of `mask` in the byte at `offset`.
+    FlipBits { offset: usize, mask: u8 },
+    /// Drops everything past the first `after` bytes.
+    Truncate { after: usize },
+}
+
+/// Corruptions of the bodies decoders read.
+pub struct DecoderFaults {
+    pending: Mutex<Vec<(i64, Corruption)>>,
+}
+
+static DECODER: DecoderFaults = DecoderFaults {
+    pending: Mutex::new(Vec::new()),
+};
+
+/// Controls the corruption of decoder input.
+pub fn decoder() -> &'static DecoderFaults {
+    &DECODER
+}
+
+impl DecoderFaults {
+    /// Corrupts the body transaction `id` receives. Only applies to a
+    /// transaction started after this call.
+    pub fn corrupt(&self, id: i64, corruption: Corruption) {
+        lock(&self.pending).push((id, corruption));
+    }
+
+    pub fn clear(&self) {
+        lock(&self.pending).clear();
+    }
+
+    /// Takes the corruptions of transaction `id`, as it starts.
+    pub(crate) fn input(&self, id: i64) -> Input {
+        let mut pending = lock(&self.pending);
+        let corruptions = pending
+            .iter()
+            .filter(|(corrupted, _)| *corrupted == id)
+            .map(|(_, corruption)| *corruption)
+            .collect();
+        pending.retain(|(corrupted, _)| *corrupted != id);
+        Input {
+            corruptions,
+            offset: 0,
+        }
+    }
+}
+
+/// Corruptions applied to one decoder's input as it is read.
+pub(crate) struct Input {
+    corruptions: Vec<Corruption>,
+    /// Bytes of the body seen so far.
+    offset: usize,
+}
+
+impl Input {
+    /// Corrupts the next chunk of the body.
+    pub(crate) fn apply(&mut self, mut chunk: Vec<u8>) -> Vec<u8> {
+        let start = self.offset;
+        self.offset += chunk.len();
+        for corruption in &self.corruptions {
+            match *corruption {
+                Corruption::FlipBits { offset, mask } => {
+                    if let Some(byte) = offset
+                        .checked_sub(start)
+                        .and_then(|index| chunk.get_mut(index))
+                    {
+                        *byte ^= mask;
+                    }
+                }
+                Corruption::Truncate { after } => {
+                    chunk.truncate(after.saturating_sub(start));
+                }
+            }
+        }
+        chunk
+    }
+}
+
+/// Clears every fault injected.
+pub fn clear() {
+    BACKEND.clear();
+    DECODER.clear();
+}
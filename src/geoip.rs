@@ -0,0 +1,132 @@
+use crate::config;
+use crate::document::Document;
+use log::{info, warn};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often database files are checked for changes.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A database file, reloaded when its modification time changes.
+struct Database {
+    path: PathBuf,
+    reader: Option<Reader<Vec<u8>>>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl Database {
+    fn open(path: Option<&Path>) -> Option<Self> {
+        let mut database = Database {
+            path: path?.to_path_buf(),
+            reader: None,
+            modified: None,
+            checked: Instant::now(),
+        };
+        database.load();
+        Some(database)
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    fn load(&mut self) {
+        self.modified = self.modified();
+        match Reader::open_readfile(&self.path) {
+            Ok(reader) => {
+                info!("Loaded GeoIP database {}", self.path.display());
+                self.reader = Some(reader);
+            }
+            Err(e) => {
+                // Keep using the previous version, if there was one.
+                warn!(
+                    "Failed loading GeoIP database {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn reader(&mut self) -> Option<&Reader<Vec<u8>>> {
+        if self.checked.elapsed() >= RELOAD_CHECK_INTERVAL {
+            self.checked = Instant::now();
+            if self.modified() != self.modified {
+                self.load();
+            }
+        }
+        self.reader.as_ref()
+    }
+}
+
+struct GeoIp {
+    country: Option<Database>,
+    asn: Option<Database>,
+}
+
+static GEOIP: Mutex<Option<GeoIp>> = Mutex::new(None);
+
+/// Loads the configured databases, so the first transaction doesn't pay for it.
+pub fn init() {
+    let mut geoip = GEOIP.lock().unwrap();
+    if geoip.is_none() {
+        let config = config::get();
+        *geoip = Some(GeoIp {
+            country: Database::open(config.geoip.country_db.as_deref()),
+            asn: Database::open(config.geoip.asn_db.as_deref()),
+        });
+    }
+}
+
+fn lookup_failed(address: IpAddr, e: MaxMindDBError) {
+    match e {
+        MaxMindDBError::AddressNotFoundError(_) => {}
+        e => warn!("GeoIP lookup of {} failed: {}", address, e),
+    }
+}
+
+/// Fills the client location fields of a document. Lookups that fail leave
+/// the fields absent.
+pub fn enrich(document: &mut Document) {
+    let address: IpAddr = match document.client_ip.as_deref().map(str::parse) {
+        Some(Ok(address)) => address,
+        _ => return,
+    };
+
+    init();
+    let mut geoip = GEOIP.lock().unwrap();
+    let geoip = match geoip.as_mut() {
+        Some(geoip) => geoip,
+        None => return,
+    };
+
+    if let Some(reader) = geoip.country.as_mut().and_then(Database::reader) {
+        match reader.lookup::<geoip2::Country>(address) {
+            Ok(country) => {
+                document.client_country = country
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .map(|code| code.to_string());
+            }
+            Err(e) => lookup_failed(address, e),
+        }
+    }
+
+    if let Some(reader) = geoip.asn.as_mut().and_then(Database::reader) {
+        match reader.lookup::<geoip2::Asn>(address) {
+            Ok(asn) => {
+                document.client_asn = asn.autonomous_system_number;
+                document.client_as_org = asn
+                    .autonomous_system_organization
+                    .map(|org| org.to_string());
+            }
+            Err(e) => lookup_failed(address, e),
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use crate::config;
+use crate::document::Document;
+use crate::encryption;
+use crate::error::PrismError;
+use crate::logging::throttled;
+use crate::persistence::Backend;
+use chrono::{DateTime, Utc};
+use log::{info, Level};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// End of a HAR file, after its last entry.
+const TRAILER: &[u8] = b"]}}";
+
+#[derive(Serialize)]
+struct Har<'a> {
+    log: Log<'a>,
+}
+
+#[derive(Serialize)]
+struct Log<'a> {
+    version: &'static str,
+    creator: Creator,
+    entries: &'a [Entry],
+}
+
+#[derive(Serialize)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    /// Custom field, so a document persisted again replaces its entry.
+    #[serde(rename = "_documentId")]
+    document_id: String,
+    started_date_time: String,
+    time: f64,
+    request: Request,
+    response: Response,
+    cache: Cache,
+    timings: Timings,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    method: String,
+    url: String,
+    http_version: &'static str,
+    cookies: Vec<Header>,
+    headers: Vec<Header>,
+    query_string: Vec<Header>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    status: u16,
+    status_text: &'static str,
+    http_version: &'static str,
+    cookies: Vec<Header>,
+    headers: Vec<Header>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Serialize)]
+struct Header {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: usize,
+    compression: i64,
+    mime_type: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Cache {}
+
+#[derive(Serialize)]
+struct Timings {
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+struct HarState {
+    entries: Vec<Entry>,
+    sequence: usize,
+    /// Bytes of the file being written before its trailer, where the next
+    /// entry goes. None when it has to be written whole, such as after a
+    /// failed write.
+    appended: Option<u64>,
+    /// Whether the file being written is sealed.
+    sealed: bool,
+}
+
+/// Entries of the HAR file currently being written, shared by all
+/// `HarFile` instances of the process.
+static HAR_STATE: Mutex<HarState> = Mutex::new(HarState {
+    entries: Vec::new(),
+    sequence: 0,
+    appended: None,
+    sealed: false,
+});
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Backend writing completed transactions as HAR 1.2 files, which can be
+/// loaded into browser developer tools.
+///
+/// Each persisted transaction is appended to the current file ahead of its
+/// trailer, so the file is always complete on disk, and a new file is
+/// started once it holds the configured number of entries. Only a document
+/// persisted again has the file rewritten, replacing its entry. With
+/// `encryption` on, what is appended is sealed as records of their own,
+/// the trailer included, to be read back through `prism-decrypt`.
+pub struct HarFile {
+    directory: PathBuf,
+    entries_per_file: usize,
+}
+
+impl HarFile {
+    pub fn new() -> Self {
+        let config = config::get();
+        let har = &config.backend.har;
+        HarFile {
+            directory: har.directory.clone().unwrap_or_else(std::env::temp_dir),
+            entries_per_file: har.entries_per_file().max(1),
+        }
+    }
+
+    fn entry(document: &Document) -> Entry {
+        let headers = |headers: &BTreeMap<String, String>| -> Vec<Header> {
+            headers
+                .iter()
+                .map(|(name, value)| Header {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect()
+        };
+        let mime_type = document
+            .response_headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_default();
+
+        // Bodies that aren't valid text are only available base64 encoded.
+        let (text, encoding) = match document.body.text() {
+            Some(text) => (text.to_string(), None),
+            None => (document.body.base64(), Some("base64")),
+        };
+
+        let wait = document.wait.as_secs_f64() * 1000.0;
+        let receive = document.receive.as_secs_f64() * 1000.0;
+
+        Entry {
+            document_id: document.document_id.clone(),
+            started_date_time: document.started_at.to_rfc3339(),
+            time: wait + receive,
+            request: Request {
+                method: document.method.clone(),
+                url: document.uri.clone(),
+                http_version: "HTTP/1.1",
+                cookies: Vec::new(),
+                headers: headers(&document.request_headers),
+                query_string: Vec::new(),
+                headers_size: -1,
+                body_size: -1,
+            },
+            response: Response {
+                status: document.status.unwrap_or(0),
+                status_text: "",
+                http_version: "HTTP/1.1",
+                cookies: Vec::new(),
+                headers: headers(&document.response_headers),
+                content: Content {
+                    size: document.body_size,
+                    compression: document.body_size as i64 - document.bytes_received as i64,
+                    mime_type,
+                    text,
+                    encoding,
+                },
+                redirect_url: document.redirect_location.clone().unwrap_or_default(),
+                headers_size: -1,
+                body_size: document.bytes_received as i64,
+            },
+            cache: Cache {},
+            timings: Timings {
+                send: 0.0,
+                wait,
+                receive,
+            },
+        }
+    }
+
+    /// Whether `name` is that of a HAR file written by this backend, by
+    /// this process or an earlier one.
+    fn is_har_file(name: &str) -> bool {
+        name.starts_with("prism-") && name.ends_with(".har")
+    }
+
+    /// Number of entries of the HAR file at `path`, none when it can't be
+    /// read, such as when sealed under an earlier key.
+    fn entries(path: &Path) -> usize {
+        let json = match encryption::key() {
+            Ok(Some(key)) => File::open(path).ok().and_then(|file| {
+                let keys = std::slice::from_ref(&*key);
+                encryption::open(BufReader::new(file), keys, usize::MAX).ok()
+            }),
+            _ => std::fs::read(path).ok(),
+        };
+        json.and_then(|json| serde_json::from_slice::<serde_json::Value>(&json).ok())
+            .and_then(|har| har.pointer("/log/entries")?.as_array().map(Vec::len))
+            .unwrap_or_default()
+    }
+
+    fn path(&self, sequence: usize) -> PathBuf {
+        self.directory
+            .join(format!("prism-{}-{}.har", std::process::id(), sequence))
+    }
+
+    /// Writes a whole file of `entries`, returning its bytes before the
+    /// trailer.
+    fn write(&self, path: &Path, entries: &[Entry]) -> std::io::Result<u64> {
+        let har = Har {
+            log: Log {
+                version: "1.2",
+                creator: Creator {
+                    name: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                entries,
+            },
+        };
+        let mut json = serde_json::to_vec(&har)?;
+        json.truncate(json.len() - TRAILER.len());
+        let mut file = encryption::seal(json)?;
+        let appended = file.len() as u64;
+        file.extend(encryption::seal(TRAILER.to_vec())?);
+
+        // Write aside and rename, so readers never see a partial file.
+        let temp_path = path.with_extension("har.tmp");
+        std::fs::write(&temp_path, file)?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(appended)
+    }
+
+    /// Appends `entry` to the file at `path` of `appended` bytes before its
+    /// trailer, returning its bytes before the trailer after that.
+    fn append(path: &Path, appended: u64, entry: &Entry) -> std::io::Result<u64> {
+        let mut json = b",".to_vec();
+        serde_json::to_writer(&mut json, entry)?;
+        let mut tail = encryption::seal(json)?;
+        let length = tail.len() as u64;
+        tail.extend(encryption::seal(TRAILER.to_vec())?);
+
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(appended)?;
+        file.seek(SeekFrom::Start(appended))?;
+        file.write_all(&tail)?;
+        Ok(appended + length)
+    }
+}
+
+impl Backend for HarFile {
+    fn name(&self) -> &'static str {
+        "har"
+    }
+
+    fn destination(&self) -> String {
+        self.directory.display().to_string()
+    }
+
+    /// Removes the HAR files last written before `cutoff`, those of earlier
+    /// processes included, but the one being written. Their entries are
+    /// counted as the documents removed.
+    fn prune(&self, cutoff: DateTime<Utc>, _batch_size: usize) -> Result<usize, PrismError> {
+        let current = self.path(lock(&HAR_STATE).sequence);
+        let files = match std::fs::read_dir(&self.directory) {
+            Ok(files) => files,
+            Err(e) => {
+                throttled!(
+                    Level::Warn,
+                    "har-prune",
+                    "Failed listing HAR files in {}: {}",
+                    self.directory.display(),
+                    e
+                );
+                return Err(PrismError::BackendUnavailable);
+            }
+        };
+
+        let mut removed = 0;
+        for file in files.flatten() {
+            let path = file.path();
+            let is_har_file = file.file_name().to_str().is_some_and(HarFile::is_har_file);
+            if !is_har_file || path == current {
+                continue;
+            }
+            let Ok(modified) = file.metadata().and_then(|metadata| metadata.modified()) else {
+                continue;
+            };
+            if DateTime::<Utc>::from(modified) >= cutoff {
+                continue;
+            }
+            let entries = HarFile::entries(&path);
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    info!(
+                        "Removed HAR file {} of {} entries, last written {}",
+                        path.display(),
+                        entries,
+                        DateTime::<Utc>::from(modified).to_rfc3339()
+                    );
+                    removed += entries;
+                }
+                Err(e) => throttled!(
+                    Level::Warn,
+                    "har-prune",
+                    "Failed removing HAR file {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        Ok(removed)
+    }
+
+    fn persist(&self, document: &Document) -> Result<(), PrismError> {
+        // Built before locking, so other workers only wait on the write.
+        let entry = HarFile::entry(document);
+        let sealed = matches!(encryption::key(), Ok(Some(_)));
+        let mut state = lock(&HAR_STATE);
+        let path = self.path(state.sequence);
+        // Documents are only told apart within the file being written.
+        let result = match state
+            .entries
+            .iter()
+            .position(|written| written.document_id == entry.document_id)
+        {
+            Some(index) => {
+                state.entries[index] = entry;
+                self.write(&path, &state.entries)
+            }
+            None => {
+                state.entries.push(entry);
+                match state.appended {
+                    Some(appended) if state.sealed == sealed => {
+                        HarFile::append(&path, appended, &state.entries[state.entries.len() - 1])
+                    }
+                    _ => self.write(&path, &state.entries),
+                }
+            }
+        };
+        state.sealed = sealed;
+        state.appended = result.as_ref().ok().copied();
+        let result = result.map(|_| ());
+
+        if state.entries.len() >= self.entries_per_file {
+            info!(
+                "HAR file {} complete with {} entries",
+                path.display(),
+                state.entries.len()
+            );
+            state.entries.clear();
+            state.sequence += 1;
+            state.appended = None;
+        }
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                throttled!(
+                    Level::Warn,
+                    "har-write",
+                    "Failed writing transaction {} to HAR file {}: {}",
+                    document.id,
+                    path.display(),
+                    e
+                );
+                Err(PrismError::BackendUnavailable)
+            }
+        }
+    }
+}
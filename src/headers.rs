@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// HTTP header map with case-insensitive lookups.
+///
+/// Names are normalized to lowercase for lookups, while the casing a header
+/// was first received with is kept so it can be persisted as sent.
+#[derive(Clone, Default)]
+pub struct Headers {
+    entries: HashMap<String, (String, String)>,
+    /// Bytes of the names and values held.
+    bytes: usize,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers {
+            entries: HashMap::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn insert(&mut self, name: String, value: String) {
+        let key = name.to_ascii_lowercase();
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                self.bytes = self.bytes - entry.1.len() + value.len();
+                entry.1 = value;
+            }
+            None => {
+                self.bytes += name.len() + value.len();
+                self.entries.insert(key, (name, value));
+            }
+        }
+    }
+
+    /// Adds a value to a header, joining it to any previous value the way
+    /// repeated header lines combine in HTTP. `Set-Cookie` values can hold
+    /// commas and don't combine, so they are kept one per line instead.
+    pub fn append(&mut self, name: String, value: String) {
+        let key = name.to_ascii_lowercase();
+        let separator = if key == "set-cookie" { "\n" } else { ", " };
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                self.bytes += separator.len() + value.len();
+                entry.1.push_str(separator);
+                entry.1.push_str(&value);
+            }
+            None => {
+                self.bytes += name.len() + value.len();
+                self.entries.insert(key, (name, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        if let Some((name, value)) = self.entries.remove(&name.to_ascii_lowercase()) {
+            self.bytes -= name.len() + value.len();
+        }
+    }
+
+    /// Bytes of the names and values held, see `limits.max_header_bytes`.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .get(&name.to_ascii_lowercase())
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Iterates over headers with the casing they were received with.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .values()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Headers of both sides of an exchange, for lookups that may concern
+    /// either, such as rules. Response headers win over request headers of
+    /// the same name.
+    pub fn combined(request: &Headers, response: &Headers) -> Headers {
+        let mut combined = request.clone();
+        for (name, value) in response.iter() {
+            combined.insert(name.to_string(), value.to_string());
+        }
+        combined
+    }
+}
+
+/// Which side of an exchange a header was sent by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+/// Headers received for a transaction that has not begun yet.
+pub struct PendingHeaders {
+    /// Response headers, as `Transaction::headers`.
+    pub headers: Headers,
+    pub request_headers: Headers,
+    /// When the first of them was received, for them to expire if the
+    /// transaction never begins, see `limits.pending_headers_timeout`.
+    pub received: Instant,
+}
+
+impl PendingHeaders {
+    /// Bytes of the headers held, of both directions.
+    pub fn bytes(&self) -> usize {
+        self.headers.bytes() + self.request_headers.bytes()
+    }
+}
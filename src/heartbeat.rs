@@ -0,0 +1,83 @@
+use crate::config;
+use crate::worker;
+use log::info;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the ticker checks whether it should stop.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static STOP: AtomicBool = AtomicBool::new(false);
+static TICKER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Short hash of the effective configuration. Secrets are not serialized,
+/// so they are left out.
+pub fn config_fingerprint() -> String {
+    let serialized = serde_json::to_string(&*config::get()).unwrap_or_default();
+    Sha256::digest(serialized)
+        .iter()
+        .take(6)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn beat() {
+    let uptime = STARTED.get_or_init(Instant::now).elapsed();
+    info!(
+        "Heartbeat version={} git={} uptime={}s backend={} schema={} config={}",
+        env!("CARGO_PKG_VERSION"),
+        env!("PRISM_GIT_HASH"),
+        uptime.as_secs(),
+        worker::backend_name(),
+        config::get().backend.schema_version(),
+        config_fingerprint()
+    );
+}
+
+fn tick(interval: Duration) {
+    let mut last = Instant::now();
+    while !STOP.load(Ordering::Relaxed) {
+        thread::sleep(STOP_POLL_INTERVAL);
+        if last.elapsed() >= interval {
+            beat();
+            last = Instant::now();
+        }
+    }
+}
+
+/// Logs the heartbeat line, and keeps logging it periodically unless the
+/// interval is set to `0`.
+pub fn start() {
+    STARTED.get_or_init(Instant::now);
+    beat();
+
+    let interval = match config::get().telemetry.heartbeat_interval {
+        0 => return,
+        seconds => Duration::from_secs(seconds),
+    };
+
+    let mut ticker = TICKER.lock().unwrap();
+    if ticker.is_some() {
+        return;
+    }
+
+    STOP.store(false, Ordering::Relaxed);
+    *ticker = Some(
+        thread::Builder::new()
+            .name("prism-heartbeat".to_string())
+            .spawn(move || tick(interval))
+            .unwrap(),
+    );
+}
+
+/// Stops the heartbeat and waits for the ticker to exit.
+pub fn stop() {
+    if let Some(ticker) = TICKER.lock().unwrap().take() {
+        STOP.store(true, Ordering::Relaxed);
+        let _ = ticker.join();
+    }
+}
@@ -0,0 +1,172 @@
+use serde::Serialize;
+#[cfg(feature = "metrics")]
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the buckets, in milliseconds, roughly log-scaled from
+/// 1ms to 30s. Slower observations land in a final overflow bucket.
+pub const BOUNDS: [u64; 15] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000, 20000, 30000,
+];
+const BUCKETS: usize = BOUNDS.len() + 1;
+
+/// Observations of one period, either since start or since the last read.
+struct Counts {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Counts {
+    const fn new() -> Self {
+        Counts {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, bucket: usize, micros: u64) {
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn read(&self, reset: bool) -> ([u64; BUCKETS], u64, u64) {
+        let load = |counter: &AtomicU64| {
+            if reset {
+                counter.swap(0, Ordering::Relaxed)
+            } else {
+                counter.load(Ordering::Relaxed)
+            }
+        };
+        let mut buckets = [0; BUCKETS];
+        for (bucket, counter) in buckets.iter_mut().zip(self.buckets.iter()) {
+            *bucket = load(counter);
+        }
+        (buckets, load(&self.count), load(&self.sum_micros))
+    }
+}
+
+/// Fixed-bucket latency histogram, updated with atomics only. Totals since
+/// start feed the Prometheus exporter, while a second set of counts can be
+/// read and reset independently for `stats()`.
+pub struct Histogram {
+    total: Counts,
+    interval: Counts,
+}
+
+/// Summary of a histogram as returned by `stats()`, in milliseconds.
+#[derive(Serialize)]
+pub struct HistogramSnapshot {
+    count: u64,
+    sum_ms: f64,
+    p50_ms: Option<f64>,
+    p95_ms: Option<f64>,
+    p99_ms: Option<f64>,
+    /// Observations per bucket, keyed by upper bound (`inf` for overflow).
+    buckets: Vec<(String, u64)>,
+}
+
+fn bucket_for(millis: u64) -> usize {
+    BOUNDS
+        .iter()
+        .position(|bound| millis <= *bound)
+        .unwrap_or(BOUNDS.len())
+}
+
+/// Estimates a quantile by interpolating linearly within the bucket it
+/// falls in. Observations past the last bound are reported at that bound.
+fn quantile(buckets: &[u64; BUCKETS], count: u64, q: f64) -> Option<f64> {
+    if count == 0 {
+        return None;
+    }
+    let rank = q * count as f64;
+    let mut seen = 0;
+    for (index, observed) in buckets.iter().enumerate() {
+        if *observed == 0 {
+            continue;
+        }
+        if (seen + observed) as f64 >= rank {
+            let lower = if index == 0 { 0 } else { BOUNDS[index - 1] } as f64;
+            let upper = match BOUNDS.get(index) {
+                Some(bound) => *bound as f64,
+                None => return Some(lower),
+            };
+            let fraction = (rank - seen as f64) / *observed as f64;
+            return Some(lower + (upper - lower) * fraction);
+        }
+        seen += observed;
+    }
+    Some(BOUNDS[BOUNDS.len() - 1] as f64)
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Histogram {
+            total: Counts::new(),
+            interval: Counts::new(),
+        }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let bucket = bucket_for(micros.div_ceil(1000));
+        self.total.record(bucket, micros);
+        self.interval.record(bucket, micros);
+    }
+
+    /// Summarizes observations since start or, with `reset`, since the
+    /// previous reset. Resets do not affect the Prometheus output.
+    pub fn snapshot(&self, reset: bool) -> HistogramSnapshot {
+        let (buckets, count, sum_micros) = if reset {
+            self.interval.read(true)
+        } else {
+            self.total.read(false)
+        };
+        HistogramSnapshot {
+            count,
+            sum_ms: sum_micros as f64 / 1000.0,
+            p50_ms: quantile(&buckets, count, 0.50),
+            p95_ms: quantile(&buckets, count, 0.95),
+            p99_ms: quantile(&buckets, count, 0.99),
+            buckets: buckets
+                .iter()
+                .enumerate()
+                .map(|(index, observed)| match BOUNDS.get(index) {
+                    Some(bound) => (bound.to_string(), *observed),
+                    None => ("inf".to_string(), *observed),
+                })
+                .collect(),
+        }
+    }
+
+    /// Writes the histogram's series in the Prometheus text format, in
+    /// seconds, with `labels` such as `backend="har"` on every series.
+    #[cfg(feature = "metrics")]
+    pub fn render(&self, output: &mut String, name: &str, labels: &str) {
+        let (buckets, count, sum_micros) = self.total.read(false);
+        let mut cumulative = 0;
+        for (index, observed) in buckets.iter().enumerate() {
+            cumulative += observed;
+            let bound = match BOUNDS.get(index) {
+                Some(bound) => (*bound as f64 / 1000.0).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                output,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            output,
+            "{}_sum{{{}}} {}",
+            name,
+            labels,
+            sum_micros as f64 / 1_000_000.0
+        );
+        let _ = writeln!(output, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
@@ -1,103 +1,194 @@
-use log::{error, info, LevelFilter};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::backtrace::{Backtrace, BacktraceStatus};
 use std::boxed::Box;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::convert::From;
 use std::ffi::{c_char, c_void, CStr};
-use std::io::prelude::*;
+use std::path::Path;
 use std::ptr::null;
-use std::sync::Once;
-use syslog::{BasicLogger, Facility, Formatter3164, Logger, LoggerBackend};
+use std::sync::{Mutex, MutexGuard, OnceLock};
 
+use error::PrismError;
+use headers::Direction;
 use mode::Mode;
-use persistence::{Backend, Elasticsearch};
-use transaction::Transaction;
+use transaction::{CLIENT_HEADER, STATUS_HEADER};
 
-mod mode;
+pub use api::{Prism, TransactionHandle};
+
+mod api;
+mod audit;
+mod block;
+mod cache;
+pub mod checksum;
+pub mod clock;
+pub mod config;
+mod dead_letter;
+mod decoding;
+#[cfg(feature = "async-persistence")]
+mod dispatch;
+mod document;
+mod dump;
+pub mod encryption;
+pub mod error;
+#[cfg(feature = "fault-injection")]
+pub mod faults;
+mod geoip;
+mod har;
+mod headers;
+mod heartbeat;
+mod histogram;
+mod logging;
+pub mod memory;
+mod metrics;
+pub mod mode;
+mod navigation;
+pub mod observer;
+mod offline;
 mod persistence;
+mod pipeline;
+mod pool;
+mod preview;
+mod rate_limit;
+mod recorder;
+pub mod retention;
+mod rewrite;
+mod rotation;
+mod routing;
+mod rules;
+mod scanner;
+mod shrink;
+mod spool;
+mod stats;
+#[cfg(feature = "metrics")]
+mod statsd;
+mod summary;
+mod telemetry;
 mod transaction;
+mod uri;
+mod user_agent;
+mod watchdog;
+mod worker;
 
-static mut TRANSACTIONS: Option<Transactions> = None;
-static ONCE_TRANSACTIONS: Once = Once::new();
-
-const OUTPUT_BUFFER_SIZE: usize = 1024 * 1024;
+static PRISM: OnceLock<Prism> = OnceLock::new();
+/// Last JSON document returned by `stats()`.
+static STATS: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+/// Problems found by the last configuration load, one per line.
+static CONFIG_ERRORS: Mutex<Vec<u8>> = Mutex::new(Vec::new());
 
 fn setup_hooks() {
     let panic_hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |panic_info| {
-        if let Some(message) = panic_info.payload().downcast_ref::<&str>() {
-            info!("Hooked panic with massage: {}", message);
-        }
+        metrics::increment(&metrics::COUNTERS.panics);
+        metrics::error(PrismError::Panic);
 
-        if let Some(location) = panic_info.location() {
-            info!(
-                "panic occurred in file '{}' at line {}",
+        let payload = panic_info.payload();
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message,
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.as_str(),
+                None => "<non-string payload>",
+            },
+        };
+        let location = match panic_info.location() {
+            Some(location) => format!(
+                "{}:{}:{}",
                 location.file(),
                 location.line(),
-            );
-        } else {
-            info!("panic occurred but can't get location information...");
+                location.column()
+            ),
+            None => "<unknown location>".to_string(),
+        };
+        error!("Panic at {}: {}", location, message);
+
+        // Only captured when enabled through RUST_BACKTRACE or RUST_LIB_BACKTRACE.
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            error!("Panic backtrace:\n{}", backtrace);
         }
         panic_hook(panic_info);
     }));
 }
 
-trait Instance<T> {
-    fn new() -> Option<T>;
-}
-
 #[repr(C)]
 pub struct Chunk {
-    size: usize,
-    bytes: *const c_void,
+    pub size: usize,
+    /// Points to `size` bytes, which stay valid as long as each function
+    /// returning a chunk documents.
+    pub bytes: *const c_void,
 }
 
-struct Transactions {
-    responses: HashMap<i64, Transaction>,
-    headers: HashMap<i64, HashMap<String, String>>,
+// The layout C callers are compiled against, asserted in include/prism.h
+// as well.
+const _: () = assert!(std::mem::size_of::<Chunk>() == 2 * std::mem::size_of::<usize>());
+const _: () = assert!(std::mem::align_of::<Chunk>() == std::mem::align_of::<usize>());
+const _: () = assert!(std::mem::offset_of!(Chunk, bytes) == std::mem::size_of::<usize>());
+
+/// A chunk returned by `send_checked()`: a `Chunk`, followed by the CRC32
+/// of its bytes.
+#[repr(C)]
+pub struct CheckedChunk {
+    pub size: usize,
+    /// Points to `size` bytes, valid as with `send()`.
+    pub bytes: *const c_void,
+    /// CRC32 (IEEE, as zlib computes it) of the bytes, `0` for none.
+    pub crc32: u32,
 }
 
-impl Instance<Transactions> for Transactions {
-    fn new() -> Option<Transactions> {
-        Some(Transactions {
-            responses: HashMap::new(),
-            headers: HashMap::new(),
-        })
+const _: () =
+    assert!(std::mem::offset_of!(CheckedChunk, crc32) == 2 * std::mem::size_of::<usize>());
+
+/// Locks one of the buffers handed back to the caller. A panic in a previous
+/// call does not make it unavailable.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
     }
 }
 
-fn get_buffers() -> &'static mut Transactions {
-    unsafe {
-        ONCE_TRANSACTIONS.call_once(|| {
-            TRANSACTIONS = Transactions::new();
-        });
-        match &mut TRANSACTIONS {
-            Some(b) => b,
-            None => panic!("Transactions not available"),
-        }
+/// Calls `f` with the global instance. No lock is taken here: calls are
+/// serialized by the registry lock of the instance.
+fn with_prism<R>(f: impl FnOnce(&Prism) -> R) -> R {
+    f(PRISM.get_or_init(Prism::with_current_config))
+}
+
+/// Reads a C string handed over by the caller, `None` for a null pointer.
+/// Origins send whatever bytes they like, invalid UTF-8 is replaced rather
+/// than trusted.
+fn c_string(ptr: *const c_char) -> Option<String> {
+    c_bytes(ptr).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn c_bytes(ptr: *const c_char) -> Option<Vec<u8>> {
+    if ptr.is_null() {
+        return None;
     }
+    // SAFETY: callers pass NUL terminated strings, valid for the duration
+    // of the call.
+    Some(unsafe { CStr::from_ptr(ptr) }.to_bytes().to_vec())
 }
 
-fn append(id: i64, chunk: *const c_void, size: usize) {
-    let ptr = chunk as *const u8;
-    let buffers = get_buffers();
-    match buffers.responses.get_mut(&id) {
-        Some(buffer) => unsafe {
-            buffer.write_bytes(std::slice::from_raw_parts(ptr, size));
-        },
-        None => {
-            panic!("Unexpected condition: asking to transfer data to uninitialized transaction with id {}", id);
-        }
-    };
+/// The `size` bytes at `ptr`, copied by the callee if kept. An empty chunk
+/// may come with any pointer, null included, which `from_raw_parts` does
+/// not accept; `None` for a null pointer to anything else.
+fn raw_bytes<'a>(ptr: *const c_void, size: usize) -> Option<&'a [u8]> {
+    if size == 0 {
+        return Some(&[]);
+    }
+    if ptr.is_null() {
+        return None;
+    }
+    // SAFETY: callers pass `size` initialized bytes, valid and left alone
+    // for the duration of the call.
+    Some(unsafe { std::slice::from_raw_parts(ptr as *const u8, size) })
 }
 
-/*
-fn brotli_decompress(buffer: &[u8]) -> Vec<u8> {
-    let mut decompressor = brotli_decompressor::Decompressor::new(buffer, buffer.len());
-    let mut decoded = Vec::new();
-    decompressor.read_to_end(&mut decoded).unwrap();
-    decoded
+/// Counts a null pointer passed where a value is required.
+fn null_argument(id: Option<i64>, function: &str) {
+    observer::error(id, PrismError::InvalidArgument);
+    error!("Null pointer passed to {}()", function);
 }
-*/
 
 fn transform(bytes: usize, content: &mut [u8]) -> Chunk {
     Chunk {
@@ -107,214 +198,495 @@ fn transform(bytes: usize, content: &mut [u8]) -> Chunk {
     //Chunk { size: 0, bytes: null(), }
 }
 
+/// Begins a transaction, with the headers received so far. `mode` is `0`
+/// for REQMOD, `1` for RESPMOD and `2` for OPTIONS requests, which have no
+/// body: those are only acknowledged, `send()` reporting the end of the body
+/// right away, unless `sampling.options` asks for them to be persisted.
 #[no_mangle]
 pub extern "C" fn uri(id: i64, uri_str: *const c_char, mode: i64, method_str: *const c_char) {
-    let uri = unsafe { CStr::from_ptr(uri_str) }
-        .to_str()
-        .unwrap()
-        .to_owned();
-    let method = unsafe { CStr::from_ptr(method_str) }
-        .to_str()
-        .unwrap()
-        .to_owned();
-    let buffers = get_buffers();
-    let encoding = match buffers.headers.get(&id) {
-        Some(headers) => headers.get("Content-Encoding"),
-        _ => None,
+    let (uri, method) = match (c_string(uri_str), c_string(method_str)) {
+        (Some(uri), Some(method)) => (uri, method),
+        _ => return null_argument(Some(id), "uri"),
     };
-    buffers.responses.insert(
-        id,
-        Transaction::new(id, method.to_string(), uri.to_string(), encoding),
-    );
-
-    info!(
-        "Transaction {} initialized with mode {} for {} uri {}",
-        id,
-        Mode::from(mode),
-        method,
-        uri
-    );
+    with_prism(|prism| prism.start(id, &method, &uri, Mode::from(mode)));
 }
 
 #[no_mangle]
 pub extern "C" fn send(id: i64, _offset: usize, _size: usize) -> Chunk {
-    //const MIN : usize = 1024;
-    let buffers = get_buffers();
-    match buffers.responses.get_mut(&id) {
-        Some(buffer) => {
-            match &buffer.encoding {
-                Some(encoding) => {
-                    if encoding != "gzip" {
-                        match buffer.bytes_receiver.try_recv() {
-                            Ok(bytes) => {
-                                buffer.transfer_chunk = bytes;
-                                return transform(
-                                    buffer.transfer_chunk.len(),
-                                    &mut buffer.transfer_chunk,
-                                );
-                            }
-                            Err(_) => {
-                                return Chunk {
-                                    size: 0,
-                                    bytes: null(),
-                                };
-                            }
-                        }
-                    }
-                }
-                None => match buffer.bytes_receiver.try_recv() {
-                    Ok(bytes) => {
-                        buffer.transfer_chunk = bytes;
-                        return transform(buffer.transfer_chunk.len(), &mut buffer.transfer_chunk);
-                    }
-                    Err(_) => {
-                        return Chunk {
-                            size: 0,
-                            bytes: null(),
-                        };
-                    }
-                },
-            }
-
-            if buffer.error {
-                return Chunk {
-                    size: 0,
-                    bytes: null(),
-                };
-            }
-
-            let mut output_buffer: [u8; OUTPUT_BUFFER_SIZE] = [0; OUTPUT_BUFFER_SIZE];
-            let result = {
-                if buffer.is_done {
-                    buffer.encoder.finish(&mut output_buffer)
-                } else {
-                    buffer.encoder.read(&mut output_buffer)
-                }
-            };
+    let chunk = with_prism(|prism| {
+        prism.send(id, |chunk| Chunk {
+            size: chunk.len(),
+            bytes: chunk.as_ptr() as *const c_void,
+        })
+    });
+    match chunk {
+        Some(chunk) if chunk.size > 0 => chunk,
+        _ => Chunk {
+            size: 0,
+            bytes: null(),
+        },
+    }
+}
 
-            let bytes = match result {
-                Ok(bytes) => bytes,
-                Err(e) => {
-                    error!(
-                        "Failed reading for id {} (uri: {}). Will return 0 bytes. Error: {}",
-                        buffer.id, buffer.uri, e
-                    );
-                    buffer.error = true;
-                    0
-                }
-            };
+#[no_mangle]
+pub extern "C" fn receive(id: i64, chunk: *const c_void, size: usize) {
+    let data = match raw_bytes(chunk, size) {
+        Some(data) => data,
+        None => return null_argument(Some(id), "receive"),
+    };
+    if let Err(e) = with_prism(|prism| prism.receive(id, data)) {
+        observer::error(Some(id), e);
+        error!(
+            "Dropping {} bytes received for transaction {}: {}",
+            size, id, e
+        );
+    }
+}
 
-            buffer.transfer_chunk = output_buffer[0..bytes].to_vec();
-            transform(bytes, buffer.transfer_chunk.as_mut_slice())
-        }
-        None => Chunk {
+/// Returns the next chunk of the body as `send()` does, along with the
+/// CRC32 of its bytes, for callers checking that what they forward is what
+/// was handed back.
+#[no_mangle]
+pub extern "C" fn send_checked(id: i64, _offset: usize, _size: usize) -> CheckedChunk {
+    let chunk = with_prism(|prism| {
+        prism.send(id, |chunk| CheckedChunk {
+            size: chunk.len(),
+            bytes: chunk.as_ptr() as *const c_void,
+            crc32: checksum::crc32(chunk),
+        })
+    });
+    match chunk {
+        Some(chunk) if chunk.size > 0 => chunk,
+        _ => CheckedChunk {
             size: 0,
             bytes: null(),
+            crc32: 0,
         },
     }
 }
 
+/// Feeds body bytes as `receive()` does, once their CRC32 matched `crc32`.
+/// Returns `0`, or the code of the error the bytes were dropped for:
+/// `PRISM_E_CHECKSUM_MISMATCH` when they were altered on the way.
 #[no_mangle]
-pub extern "C" fn receive(id: i64, chunk: *const c_void, size: usize) {
-    append(id, chunk, size);
+pub extern "C" fn receive_checked(id: i64, chunk: *const c_void, size: usize, crc32: u32) -> i32 {
+    let data = match raw_bytes(chunk, size) {
+        Some(data) => data,
+        None => {
+            null_argument(Some(id), "receive_checked");
+            return PrismError::InvalidArgument.code();
+        }
+    };
+    match with_prism(|prism| prism.receive_checked(id, data, crc32)) {
+        Ok(()) => 0,
+        Err(e) => {
+            observer::error(Some(id), e);
+            // Mismatches are logged along with both checksums.
+            if e != PrismError::ChecksumMismatch {
+                error!(
+                    "Dropping {} bytes received for transaction {}: {}",
+                    size, id, e
+                );
+            }
+            e.code()
+        }
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn cleanup(id: i64) {
-    let buffers = get_buffers();
-    match buffers.responses.remove(&id) {
-        Some(buffer) => {
-            drop(buffer);
-        }
-        None => (),
+    with_prism(|prism| prism.cleanup(id));
+}
+
+/// Records a response header, as `response_header()` does. Kept for
+/// adapters that pass request and response headers alike.
+#[no_mangle]
+pub extern "C" fn header(id: i64, name: *const c_char, value: *const c_char) {
+    directed_header(id, Direction::Response, name, value, "header");
+}
+
+/// Records a header of the request a transaction is for. Request headers
+/// are persisted apart from the response headers, and only the latter
+/// tell the encoding of the body.
+#[no_mangle]
+pub extern "C" fn request_header(id: i64, name: *const c_char, value: *const c_char) {
+    directed_header(id, Direction::Request, name, value, "request_header");
+}
+
+/// Records a response header of a transaction.
+#[no_mangle]
+pub extern "C" fn response_header(id: i64, name: *const c_char, value: *const c_char) {
+    directed_header(id, Direction::Response, name, value, "response_header");
+}
+
+fn directed_header(
+    id: i64,
+    direction: Direction,
+    name: *const c_char,
+    value: *const c_char,
+    function: &str,
+) {
+    let (name, value) = match (c_string(name), c_string(value)) {
+        (Some(name), Some(value)) => (name, value),
+        _ => return null_argument(Some(id), function),
     };
+    with_prism(|prism| prism.header(id, direction, name, value));
+}
 
-    match buffers.headers.remove(&id) {
-        Some(headers) => {
-            drop(headers);
+/// Records the response status code of a transaction. It may be reported
+/// before or after `uri()`, like headers.
+#[no_mangle]
+pub extern "C" fn status(id: i64, code: i64) {
+    with_prism(|prism| {
+        prism.header(
+            id,
+            Direction::Response,
+            STATUS_HEADER.to_string(),
+            code.to_string(),
+        )
+    });
+}
+
+/// Records the address of the client a transaction is made for.
+#[no_mangle]
+pub extern "C" fn client_address(id: i64, address: *const c_char) {
+    let address = match c_string(address) {
+        Some(address) => address,
+        None => return null_argument(Some(id), "client_address"),
+    };
+    with_prism(|prism| prism.header(id, Direction::Response, CLIENT_HEADER.to_string(), address));
+}
+
+/// Keeps configuration problems for `config_errors()` and logs them.
+fn config_failed(errors: &[config::ConfigError]) {
+    let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    for message in &messages {
+        error!("Invalid configuration, {}", message);
+    }
+    *lock(&CONFIG_ERRORS) = messages.join("\n").into_bytes();
+}
+
+/// Loads the configuration file at `path`, layered over the built-in
+/// defaults and under the `PRISM_*` environment variables. Must be called
+/// before `init()` to take effect everywhere. Returns `0`, or
+/// `PRISM_E_INVALID_CONFIG` with the problems available from
+/// `config_errors()`, in which case the previous configuration is kept.
+#[no_mangle]
+pub extern "C" fn configure(path: *const c_char) -> i32 {
+    let path = match c_string(path) {
+        Some(path) => path,
+        None => {
+            null_argument(None, "configure");
+            return PrismError::InvalidArgument.code();
         }
-        None => (),
     };
+    let result = config::configure(Path::new(&path));
+    config::log_warnings();
+    match result {
+        Ok(()) => {
+            lock(&CONFIG_ERRORS).clear();
+            0
+        }
+        Err(errors) => {
+            config_failed(&errors);
+            PrismError::InvalidConfig.code()
+        }
+    }
+}
 
-    info!(
-        "Cleanup {}: {} & {} transactions currently active. Capacities @ {} & {}",
-        id,
-        buffers.responses.len(),
-        buffers.headers.len(),
-        buffers.responses.capacity(),
-        buffers.headers.capacity()
-    );
+/// Returns the problems found by the last configuration load, one
+/// `path: reason` per line, or nothing. The returned bytes stay valid until
+/// the next configuration load.
+#[no_mangle]
+pub extern "C" fn config_errors() -> Chunk {
+    let mut config_errors = lock(&CONFIG_ERRORS);
+    transform(config_errors.len(), &mut config_errors)
 }
 
+/// Reads the configuration file again and applies it to transactions started
+/// from now on; transactions in flight keep the settings they started with.
+/// The file is validated fully first: on `PRISM_E_INVALID_CONFIG` nothing
+/// changes. Settings only read at init, such as the backend or the metrics
+/// address, are logged as needing a restart and keep their running value.
+/// The rules file is reloaded as well, see `reload_rules()`.
 #[no_mangle]
-pub extern "C" fn header(id: i64, name: *const c_char, value: *const c_char) {
-    let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap().to_owned();
-    let value = unsafe { CStr::from_ptr(value) }
-        .to_str()
-        .unwrap()
-        .to_owned();
-    let buffers = get_buffers();
-    match buffers.headers.get_mut(&id) {
-        Some(headers) => {
-            headers.insert(name.clone(), value.clone());
+pub extern "C" fn reload_config() -> i32 {
+    let result = config::reload();
+    config::log_warnings();
+    match result {
+        Ok(changed) => {
+            lock(&CONFIG_ERRORS).clear();
+            for setting in changed {
+                warn!(
+                    "Configuration change of {} needs a restart to apply",
+                    setting
+                );
+            }
+            logging::reload();
+            user_agent::reload();
+            pool::trim();
+            info!("Configuration reloaded");
+            reload_rules()
         }
-        None => {
-            let mut headers = HashMap::new();
-            headers.insert(name.clone(), value.clone());
-            buffers.headers.insert(id, headers);
+        Err(errors) => {
+            config_failed(&errors);
+            PrismError::InvalidConfig.code()
+        }
+    }
+}
+
+/// Compiles the rules file again and applies it to transactions started
+/// from now on. On `PRISM_E_INVALID_CONFIG` the rules in use are kept, and
+/// `config_errors()` names the offending rules.
+#[no_mangle]
+pub extern "C" fn reload_rules() -> i32 {
+    match rules::reload() {
+        Ok(()) => 0,
+        Err(errors) => {
+            config_failed(&errors);
+            PrismError::InvalidConfig.code()
         }
     }
 }
 
 #[no_mangle]
 pub extern "C" fn init() {
-    let formatter: Formatter3164 = Formatter3164 {
-        facility: Facility::LOG_USER,
-        hostname: None,
-        process: "analyzer".to_string(),
-        pid: 0,
+    let errors = config::init();
+    logging::init();
+    config::log_warnings();
+    if !errors.is_empty() {
+        config_failed(&errors);
+    }
+    if let Err(errors) = rules::reload() {
+        config_failed(&errors);
+    }
+    if !worker::backend_available() {
+        warn!(
+            "The {} backend is not compiled in, documents won't be persisted",
+            worker::backend_name()
+        );
+    }
+    observer::seal();
+    clock::start_run();
+    setup_hooks();
+    stats::start_log_ticker();
+    user_agent::init();
+    geoip::init();
+    metrics::start();
+    #[cfg(feature = "metrics")]
+    statsd::init();
+    summary::start();
+    retention::start();
+    telemetry::init();
+    heartbeat::start();
+}
+
+#[no_mangle]
+pub extern "C" fn done(id: i64) {
+    with_prism(|prism| prism.done(id));
+}
+
+/// Registers a find/replace rule applied to textual response bodies of
+/// transactions started after this call.
+#[no_mangle]
+pub extern "C" fn rewrite_rule(find: *const c_char, replace: *const c_char) {
+    let (find, replace) = match (c_bytes(find), c_bytes(replace)) {
+        (Some(find), Some(replace)) => (find, replace),
+        _ => return null_argument(None, "rewrite_rule"),
     };
+    with_prism(|prism| prism.rewrite_rule(find, replace));
+}
 
-    let logger: Logger<LoggerBackend, Formatter3164> = match syslog::unix(formatter) {
-        Err(e) => {
-            println!("impossible to connect to syslog: {:?}", e);
-            None
+/// Blocks a transaction: from now on `send()` returns a block page instead of
+/// the origin content, while the original body is still captured and
+/// persisted along with the reason.
+#[no_mangle]
+pub extern "C" fn block(id: i64, reason: *const c_char) {
+    let reason = match c_string(reason) {
+        Some(reason) => reason,
+        None => return null_argument(Some(id), "block"),
+    };
+    if let Err(e) =
+        with_prism(|prism| prism.with_transaction(id, |transaction| transaction.block(reason)))
+    {
+        observer::error(Some(id), e);
+        error!("Asked to block unknown transaction {}", id);
+    }
+}
+
+/// Returns the value a response header should be changed to.
+///
+/// A null `bytes` pointer means the header is unchanged; a non-null pointer
+/// with a size of 0 means the header must be removed. The `:status` name
+/// reports a replacement status code.
+///
+/// When the body is altered, `Content-Length` starts out removed with
+/// `Transfer-Encoding: chunked` requested, and reports the number of bytes
+/// emitted once `send()` handed back the whole body. The value stays valid
+/// until the header changes again or the transaction is cleaned up.
+#[no_mangle]
+pub extern "C" fn get_response_header(id: i64, name: *const c_char) -> Chunk {
+    let name = match c_string(name) {
+        Some(name) => name,
+        None => {
+            null_argument(Some(id), "get_response_header");
+            return Chunk {
+                size: 0,
+                bytes: null(),
+            };
         }
-        Ok(_logger) => Some(_logger),
+    };
+    // Values live in the transaction's modified headers, so the pointer
+    // outlives the call.
+    let value = with_prism(|prism| {
+        prism.with_transaction(id, |transaction| {
+            transaction
+                .modified_headers
+                .get(&name)
+                .map(|value| (value.len(), value.as_ptr()))
+        })
+    });
+
+    match value {
+        Ok(Some((size, bytes))) => Chunk {
+            size,
+            bytes: bytes as *const c_void,
+        },
+        _ => Chunk {
+            size: 0,
+            bytes: null(),
+        },
     }
-    .unwrap();
+}
 
-    match log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
-        .map(|()| log::set_max_level(LevelFilter::Info))
-    {
+#[derive(Serialize)]
+struct Stats {
+    active_transactions: usize,
+    pending_headers: usize,
+    pending_header_bytes: usize,
+    counters: metrics::CountersSnapshot,
+    persist_latency: BTreeMap<&'static str, histogram::HistogramSnapshot>,
+    retained_bytes: u64,
+    hosts: BTreeMap<String, stats::HostSummary>,
+}
+
+/// Returns runtime statistics as a JSON document. The returned bytes stay
+/// valid until the next call.
+#[no_mangle]
+pub extern "C" fn stats() -> Chunk {
+    let stats = with_prism(|prism| Stats {
+        active_transactions: prism.active_transactions(),
+        pending_headers: prism.pending_headers(),
+        pending_header_bytes: prism.pending_header_bytes(),
+        counters: metrics::COUNTERS.snapshot(),
+        persist_latency: metrics::persist_latency(),
+        retained_bytes: metrics::get(&metrics::RETAINED_BYTES),
+        hosts: stats::hosts(),
+    });
+    let mut buffer = lock(&STATS);
+    *buffer = match serde_json::to_vec(&stats) {
+        Ok(json) => json,
         Err(e) => {
-            info!("Logger initialization errored with: {}", e);
-        }
-        _ => {
-            info!("Logger initialized");
+            error!("Failed serializing stats: {}", e);
+            Vec::new()
         }
     };
+    transform(buffer.len(), &mut buffer)
+}
 
-    setup_hooks();
+/// Writes a JSON snapshot of the live transactions, the persistence queue
+/// and the counters to `path`, for diagnosing a wedged proxy. Bodies are
+/// left out, and other calls wait for one transaction at most while it is
+/// taken. Returns `0`, or `PRISM_E_IO` when the file could not be written.
+#[no_mangle]
+pub extern "C" fn dump_state(path: *const c_char) -> i32 {
+    let path = match c_string(path) {
+        Some(path) => path,
+        None => {
+            null_argument(None, "dump_state");
+            return PrismError::InvalidArgument.code();
+        }
+    };
+    match with_prism(|prism| prism.dump_state(Path::new(&path))) {
+        Ok(()) => 0,
+        Err(e) => {
+            observer::error(None, e);
+            e.code()
+        }
+    }
 }
 
+/// Creates Elasticsearch indices with the current mapping, named after the
+/// schema version, for the indices in use, and logs how to reindex into
+/// them. Meant to be run once by an operator after an upgrade changing the
+/// mapping. Returns `0`, or `PRISM_E_BACKEND_UNAVAILABLE` when an index
+/// could not be created.
 #[no_mangle]
-pub extern "C" fn done(id: i64) {
-    let buffers = get_buffers();
-    match buffers.responses.get_mut(&id) {
-        Some(buffer) => {
-            let backend = Elasticsearch::new(
-                "admin:admin@search".to_string(),
-                9200,
-                "https".to_string(),
-                "lens".to_string(),
-            );
-            match backend.persist(buffer) {
-                Ok(()) => {}
-                Err(()) => {}
-            }
-            buffer.done();
+pub extern "C" fn migrate_mapping() -> i32 {
+    match with_prism(Prism::migrate_mapping) {
+        Ok(_) => 0,
+        Err(e) => {
+            observer::error(None, e);
+            e.code()
         }
-        None => (),
     }
 }
+
+/// Attaches caller supplied metadata to a live transaction, persisted in
+/// its `annotations` field. Number and size of annotations are capped.
+#[no_mangle]
+pub extern "C" fn annotate(id: i64, key: *const c_char, value: *const c_char) {
+    let (key, value) = match (c_string(key), c_string(value)) {
+        (Some(key), Some(value)) => (key, value),
+        _ => return null_argument(Some(id), "annotate"),
+    };
+    if let Err(e) = with_prism(|prism| {
+        prism.with_transaction(id, |transaction| transaction.annotate(key, value))
+    }) {
+        observer::error(Some(id), e);
+        error!("Asked to annotate unknown transaction {}", id);
+    }
+}
+
+/// Replaces the log filter, using the same `level,module=level` syntax as
+/// the `PRISM_LOG` environment variable. Invalid directives are ignored with
+/// a warning.
+#[no_mangle]
+pub extern "C" fn set_log_filter(filter: *const c_char) {
+    let filter = match c_string(filter) {
+        Some(filter) => filter,
+        None => return null_argument(None, "set_log_filter"),
+    };
+    logging::set_filter(&filter);
+    info!("Log filter set to {}", filter);
+}
+
+/// Enables or disables detailed, chunk level logging for one transaction.
+/// Transactions whose uri matches `PRISM_TRACE_URIS` are traced from the
+/// start.
+#[no_mangle]
+pub extern "C" fn trace(id: i64, enabled: bool) {
+    if let Err(e) =
+        with_prism(|prism| prism.with_transaction(id, |transaction| transaction.set_trace(enabled)))
+    {
+        observer::error(Some(id), e);
+        error!("Asked to trace unknown transaction {}", id);
+    }
+}
+
+/// Stops background services started by `init()`, and the decode workers.
+/// Transactions still in flight keep working, but metrics are no longer
+/// served nor summarized, and bodies are decoded in `send()`.
+#[no_mangle]
+pub extern "C" fn shutdown() {
+    with_prism(|prism| prism.shutdown());
+    metrics::stop();
+    summary::stop();
+    retention::stop();
+    rotation::finish();
+    heartbeat::stop();
+    #[cfg(feature = "metrics")]
+    statsd::flush();
+    telemetry::shutdown();
+    info!("Shut down");
+}
@@ -0,0 +1,487 @@
+use crate::clock;
+use crate::config;
+use log::{info, warn, LevelFilter, Log, Metadata, Record};
+use regex::Regex;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+#[cfg(feature = "syslog-logging")]
+use syslog::{BasicLogger, Facility, Formatter3164};
+
+/// Target of per-transaction trace lines. They are logged at info level so
+/// traced transactions show up without lowering the filter for all traffic,
+/// and can still be silenced with `trace=off`.
+pub const TRACE_TARGET: &str = "prism::trace";
+
+static TRACE_URIS: RwLock<Option<Option<Regex>>> = RwLock::new(None);
+
+/// Structured fields of transaction events, output as separate keys in the
+/// JSON format. See `event!`.
+#[derive(Clone, Default, Serialize)]
+pub struct Fields {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<&'static str>,
+}
+
+impl Fields {
+    pub fn transaction(id: i64) -> Self {
+        Fields {
+            transaction_id: Some(id),
+            ..Default::default()
+        }
+    }
+
+    pub fn uri(mut self, uri: &str) -> Self {
+        self.uri = Some(uri.to_string());
+        self
+    }
+
+    pub fn bytes(mut self, bytes: usize) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    pub fn error_kind(mut self, kind: &'static str) -> Self {
+        self.error_kind = Some(kind);
+        self
+    }
+}
+
+thread_local! {
+    /// Fields of the event being logged on this thread, picked up by the
+    /// logger as the `log` facade has no stable way to carry them.
+    static FIELDS: RefCell<Option<Fields>> = const { RefCell::new(None) };
+}
+
+#[doc(hidden)]
+pub fn with_fields(fields: Fields, f: impl FnOnce()) {
+    FIELDS.with(|current| *current.borrow_mut() = Some(fields));
+    f();
+    FIELDS.with(|current| current.borrow_mut().take());
+}
+
+/// Logs a transaction event along with structured fields:
+///
+/// `event!(Level::Info, Fields::transaction(id).uri(&uri), "Transaction {} done", id)`
+///
+/// Fields are only built when the level is enabled.
+macro_rules! event {
+    ($level:expr, $fields:expr, $($arg:tt)+) => {
+        if log::log_enabled!($level) {
+            $crate::logging::with_fields($fields, || log::log!($level, $($arg)+));
+        }
+    };
+}
+pub(crate) use event;
+
+/// Logs a detailed line about a traced transaction. Nothing is evaluated
+/// unless `$enabled` is set.
+macro_rules! trace_transaction {
+    ($enabled:expr, $id:expr, $($arg:tt)+) => {
+        if $enabled {
+            $crate::logging::with_fields($crate::logging::Fields::transaction($id), || {
+                log::info!(
+                    target: $crate::logging::TRACE_TARGET,
+                    "Transaction {} trace: {}",
+                    $id,
+                    format_args!($($arg)+)
+                )
+            });
+        }
+    };
+}
+pub(crate) use trace_transaction;
+
+struct Throttled {
+    window_start: Instant,
+    suppressed: u64,
+}
+
+struct Throttle {
+    window: Duration,
+    keys: HashMap<String, Throttled>,
+}
+
+static THROTTLE: Mutex<Option<Throttle>> = Mutex::new(None);
+
+/// Formats a count with thousands separators.
+fn separated(count: u64) -> String {
+    let digits = count.to_string();
+    let mut output = String::new();
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index) % 3 == 0 {
+            output.push(',');
+        }
+        output.push(digit);
+    }
+    output
+}
+
+/// Decides whether a record identified by `key` should be logged: at most
+/// once per window. Returns `None` when it is suppressed, otherwise a suffix
+/// to append, mentioning the records suppressed in the previous window.
+pub fn throttle(key: &str) -> Option<String> {
+    let mut throttle = THROTTLE.lock().unwrap();
+    let throttle = throttle.get_or_insert_with(|| Throttle {
+        window: Duration::from_secs(config::get().logging.throttle_window),
+        keys: HashMap::new(),
+    });
+    let window = throttle.window;
+
+    match throttle.keys.get_mut(key) {
+        Some(entry) if clock::since(entry.window_start) < window => {
+            entry.suppressed += 1;
+            None
+        }
+        Some(entry) => {
+            let suppressed = entry.suppressed;
+            entry.window_start = clock::now();
+            entry.suppressed = 0;
+            match suppressed {
+                0 => Some(String::new()),
+                1 => Some(" (...and 1 similar error suppressed)".to_string()),
+                n => Some(format!(
+                    " (...and {} similar errors suppressed)",
+                    separated(n)
+                )),
+            }
+        }
+        None => {
+            throttle.keys.insert(
+                key.to_string(),
+                Throttled {
+                    window_start: clock::now(),
+                    suppressed: 0,
+                },
+            );
+            Some(String::new())
+        }
+    }
+}
+
+/// Logs a record at most once per throttling window for a given key, for
+/// errors that can repeat for every transaction:
+///
+/// `throttled!(Level::Warn, "elasticsearch-persist", "Failed persisting: {}", e)`
+macro_rules! throttled {
+    ($level:expr, $key:expr, $($arg:tt)+) => {
+        if log::log_enabled!($level) {
+            if let Some(suppressed) = $crate::logging::throttle($key) {
+                log::log!($level, "{}{}", format_args!($($arg)+), suppressed);
+            }
+        }
+    };
+}
+pub(crate) use throttled;
+
+/// Whether transactions for `uri` should be traced from the start.
+pub fn trace_uri(uri: &str) -> bool {
+    if let Some(pattern) = TRACE_URIS.read().unwrap().as_ref() {
+        return pattern
+            .as_ref()
+            .is_some_and(|pattern| pattern.is_match(uri));
+    }
+    let pattern = trace_uris_pattern();
+    let matched = pattern
+        .as_ref()
+        .is_some_and(|pattern| pattern.is_match(uri));
+    *TRACE_URIS.write().unwrap() = Some(pattern);
+    matched
+}
+
+fn trace_uris_pattern() -> Option<Regex> {
+    let pattern = config::get().logging.trace_uris.clone()?;
+    match Regex::new(&pattern) {
+        Ok(pattern) => Some(pattern),
+        Err(e) => {
+            warn!("Ignoring invalid trace uris pattern: {}", e);
+            None
+        }
+    }
+}
+
+/// Applies the logging settings that can change at runtime: the filter and
+/// the uris traced from the start.
+pub fn reload() {
+    set_filter(&config::get().logging.filter);
+    *TRACE_URIS.write().unwrap() = Some(trace_uris_pattern());
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    #[serde(flatten)]
+    fields: Option<Fields>,
+}
+
+enum Sink {
+    #[cfg(feature = "syslog-logging")]
+    Syslog(BasicLogger),
+    Stderr,
+    File(Mutex<File>),
+}
+
+/// Maximum levels to log, overall and per module.
+pub struct Filter {
+    default: LevelFilter,
+    /// Module paths, without the crate name, with their level.
+    directives: Vec<(String, LevelFilter)>,
+}
+
+/// Filter in use, shared with the installed logger so it can be replaced
+/// at runtime.
+static FILTER: RwLock<Filter> = RwLock::new(Filter {
+    default: LevelFilter::Info,
+    directives: Vec::new(),
+});
+
+impl Filter {
+    /// Parses a filter specification. Invalid directives are skipped and
+    /// returned as warnings.
+    pub fn parse(spec: &str) -> (Self, Vec<String>) {
+        let mut filter = Filter {
+            default: LevelFilter::Info,
+            directives: Vec::new(),
+        };
+        let mut warnings = Vec::new();
+
+        for directive in spec.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+
+            match directive.split_once('=') {
+                Some((module, level)) => match level.trim().parse() {
+                    Ok(level) if !module.trim().is_empty() => {
+                        let module = module.trim();
+                        let module = module.strip_prefix("prism::").unwrap_or(module);
+                        filter.directives.push((module.to_string(), level));
+                    }
+                    _ => warnings.push(format!("Ignoring invalid log directive {}", directive)),
+                },
+                None => match directive.parse() {
+                    Ok(level) => filter.default = level,
+                    Err(_) => {
+                        warnings.push(format!("Ignoring invalid log directive {}", directive))
+                    }
+                },
+            }
+        }
+
+        // Most specific modules first, so the first match wins.
+        filter.directives.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        (filter, warnings)
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        let module = target.strip_prefix("prism::").unwrap_or(target);
+        for (name, level) in self.directives.iter() {
+            let matches = match module.strip_prefix(name.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with("::"),
+                None => false,
+            };
+            if matches {
+                return *level;
+            }
+        }
+        self.default
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, |max, level| max.max(level))
+    }
+}
+
+/// Replaces the filter in use. Invalid directives are logged and ignored.
+pub fn set_filter(spec: &str) {
+    let (filter, warnings) = Filter::parse(spec);
+    log::set_max_level(filter.max_level());
+    *FILTER.write().unwrap() = filter;
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+}
+
+struct PrismLogger {
+    sink: Sink,
+    format: Format,
+}
+
+impl Log for PrismLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.read().unwrap().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match &self.sink {
+            #[cfg(feature = "syslog-logging")]
+            Sink::Syslog(logger) => match self.format {
+                Format::Text => logger.log(record),
+                Format::Json => logger.log(
+                    &Record::builder()
+                        .args(format_args!("{}", json(record)))
+                        .level(record.level())
+                        .target(record.target())
+                        .build(),
+                ),
+            },
+            Sink::Stderr => {
+                eprintln!("{}", self.line(record));
+            }
+            Sink::File(file) => {
+                // Nowhere left to report a failure to.
+                let _ = writeln!(file.lock().unwrap(), "{}", self.line(record));
+            }
+        }
+    }
+
+    fn flush(&self) {
+        match &self.sink {
+            #[cfg(feature = "syslog-logging")]
+            Sink::Syslog(logger) => logger.flush(),
+            Sink::Stderr => {}
+            Sink::File(file) => {
+                let _ = file.lock().unwrap().flush();
+            }
+        }
+    }
+}
+
+impl PrismLogger {
+    fn line(&self, record: &Record) -> String {
+        match self.format {
+            Format::Text => format!(
+                "{} {} {}: {}",
+                timestamp(),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            Format::Json => json(record),
+        }
+    }
+}
+
+fn timestamp() -> String {
+    clock::utc().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn json(record: &Record) -> String {
+    let level = record.level().to_string().to_ascii_lowercase();
+    let json_record = JsonRecord {
+        timestamp: timestamp(),
+        level: &level,
+        target: record.target(),
+        message: record.args().to_string(),
+        fields: FIELDS.with(|current| current.borrow().clone()),
+    };
+    match serde_json::to_string(&json_record) {
+        Ok(json) => json,
+        Err(e) => format!(
+            "{{\"level\":\"error\",\"message\":\"Failed formatting log record: {}\"}}",
+            e
+        ),
+    }
+}
+
+#[cfg(feature = "syslog-logging")]
+fn syslog_sink() -> Result<Sink, String> {
+    if cfg!(miri) {
+        return Err("syslog is not available under Miri".to_string());
+    }
+    let formatter: Formatter3164 = Formatter3164 {
+        facility: Facility::LOG_USER,
+        hostname: None,
+        process: "analyzer".to_string(),
+        pid: 0,
+    };
+
+    match syslog::unix(formatter) {
+        Ok(logger) => Ok(Sink::Syslog(BasicLogger::new(logger))),
+        Err(e) => Err(format!("impossible to connect to syslog: {}", e)),
+    }
+}
+
+#[cfg(not(feature = "syslog-logging"))]
+fn syslog_sink() -> Result<Sink, String> {
+    Err("syslog support is not compiled in".to_string())
+}
+
+fn file_sink(path: Option<&Path>) -> Result<Sink, String> {
+    let path = match path {
+        Some(path) => path,
+        None => return Err("no log file is configured".to_string()),
+    };
+
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Ok(Sink::File(Mutex::new(file))),
+        Err(e) => Err(format!(
+            "impossible to open log file {}: {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+/// Installs the configured logger. Backends that can't be set up fall back
+/// to stderr rather than leaving the module without logs.
+pub fn init() {
+    let config = config::get();
+    let logging = &config.logging;
+    let sink = match logging.backend.as_str() {
+        "stderr" => Ok(Sink::Stderr),
+        "file" => file_sink(logging.file.as_deref()),
+        _ => syslog_sink(),
+    };
+    let (sink, fallback_reason) = match sink {
+        Ok(sink) => (sink, None),
+        Err(reason) => (Sink::Stderr, Some(reason)),
+    };
+
+    let format = match logging.format.as_str() {
+        "json" => Format::Json,
+        _ => Format::Text,
+    };
+
+    let result = log::set_boxed_logger(Box::new(PrismLogger { sink, format }));
+    set_filter(&logging.filter);
+    match result {
+        Err(e) => {
+            info!("Logger initialization errored with: {}", e);
+        }
+        _ => {
+            info!("Logger initialized");
+        }
+    };
+
+    if let Some(reason) = fallback_reason {
+        info!("Logging to stderr instead: {}", reason);
+    }
+}
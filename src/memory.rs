@@ -0,0 +1,138 @@
+//! Backend keeping persisted documents in the process, selected with
+//! `backend.type = "memory"`. Meant for tests, which inspect the documents
+//! through the functions below, and for trying prism out without a search
+//! cluster. Documents are kept until `clear()`.
+
+use crate::clock;
+use crate::config;
+use crate::document::Document;
+use crate::error::PrismError;
+use crate::persistence::Backend;
+use chrono::{DateTime, Utc};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A document as persisted by the memory backend.
+#[derive(Clone, Debug)]
+pub struct Persisted {
+    pub id: i64,
+    pub document_id: String,
+    /// Times the document was persisted, each replacing the previous copy.
+    pub writes: usize,
+    /// When the document was last persisted.
+    pub persisted_at: Instant,
+    /// Date of the document, which retention goes by.
+    pub date: DateTime<Utc>,
+    pub method: String,
+    pub uri: String,
+    pub status: Option<u16>,
+    /// Decoded body, as captured.
+    pub body: Vec<u8>,
+    pub encoding: String,
+    pub truncated: bool,
+    pub emitted_length: usize,
+    /// The document as the Elasticsearch backend would index it.
+    pub json: serde_json::Value,
+}
+
+static DOCUMENTS: Mutex<Vec<Persisted>> = Mutex::new(Vec::new());
+/// Signalled whenever a document is persisted.
+static PERSISTED: Condvar = Condvar::new();
+
+fn documents_lock() -> MutexGuard<'static, Vec<Persisted>> {
+    match DOCUMENTS.lock() {
+        Ok(documents) => documents,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+pub struct MemoryBackend;
+
+impl Backend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn destination(&self) -> String {
+        "memory".to_string()
+    }
+
+    fn persist(&self, document: &Document) -> Result<(), PrismError> {
+        let fields = &config::get().backend.elasticsearch.fields;
+        let json = document
+            .to_json(fields)
+            .and_then(|json| serde_json::from_slice(&json))
+            .map_err(|_| PrismError::Encode)?;
+        let mut documents = documents_lock();
+        let writes = match documents
+            .iter()
+            .position(|persisted| persisted.document_id == document.document_id)
+        {
+            Some(index) => documents.remove(index).writes + 1,
+            None => 1,
+        };
+        documents.push(Persisted {
+            id: document.id,
+            document_id: document.document_id.clone(),
+            writes,
+            persisted_at: Instant::now(),
+            date: document.date.parse().unwrap_or_else(|_| clock::utc()),
+            method: document.method.clone(),
+            uri: document.uri.clone(),
+            status: document.status,
+            body: document.body.bytes().to_vec(),
+            encoding: document.encoding.clone(),
+            truncated: document.truncated,
+            emitted_length: document.emitted_length,
+            json,
+        });
+        PERSISTED.notify_all();
+        Ok(())
+    }
+
+    fn prune(&self, cutoff: DateTime<Utc>, _batch_size: usize) -> Result<usize, PrismError> {
+        let mut documents = documents_lock();
+        let persisted = documents.len();
+        documents.retain(|document| document.date >= cutoff);
+        Ok(persisted - documents.len())
+    }
+}
+
+/// The documents persisted so far, in the order they were.
+pub fn documents() -> Vec<Persisted> {
+    documents_lock().clone()
+}
+
+/// The document persisted for transaction `id`, if any.
+pub fn find(id: i64) -> Option<Persisted> {
+    documents_lock()
+        .iter()
+        .find(|document| document.id == id)
+        .cloned()
+}
+
+/// Waits for the document of transaction `id` to be persisted, for at most
+/// `timeout`. Documents are persisted by the persistence workers, after
+/// the transaction handed back its body.
+pub fn wait(id: i64, timeout: Duration) -> Option<Persisted> {
+    let deadline = Instant::now() + timeout;
+    let mut documents = documents_lock();
+    loop {
+        if let Some(document) = documents.iter().find(|document| document.id == id) {
+            return Some(document.clone());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        documents = match PERSISTED.wait_timeout(documents, deadline - now) {
+            Ok((documents, _)) => documents,
+            Err(poisoned) => poisoned.into_inner().0,
+        };
+    }
+}
+
+/// Forgets the documents persisted so far.
+pub fn clear() {
+    documents_lock().clear();
+}
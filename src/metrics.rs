@@ -0,0 +1,778 @@
+use crate::config;
+use crate::error::PrismError;
+use crate::histogram::{Histogram, HistogramSnapshot};
+#[cfg(feature = "metrics")]
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+#[cfg(feature = "metrics")]
+use std::fmt::Write as _;
+#[cfg(feature = "metrics")]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(feature = "metrics")]
+use std::net::{TcpListener, TcpStream};
+use std::ops::Deref;
+#[cfg(feature = "metrics")]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "metrics")]
+use std::sync::Mutex;
+#[cfg(feature = "metrics")]
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often the listener checks whether it should stop.
+#[cfg(feature = "metrics")]
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+#[cfg(feature = "metrics")]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Backends persistence results are reported for.
+pub const BACKENDS: [&str; 4] = ["elasticsearch", "har", "memory", "dry_run"];
+
+/// Counter on a cache line of its own. Used for the counters updated on
+/// every chunk, so that threads updating them do not invalidate the line
+/// holding their neighbours.
+#[repr(align(64))]
+pub struct Padded(AtomicU64);
+
+impl Padded {
+    pub const fn new() -> Self {
+        Padded(AtomicU64::new(0))
+    }
+}
+
+impl Deref for Padded {
+    type Target = AtomicU64;
+
+    fn deref(&self) -> &AtomicU64 {
+        &self.0
+    }
+}
+
+pub static TRANSACTIONS_STARTED: AtomicU64 = AtomicU64::new(0);
+pub static TRANSACTIONS_COMPLETED: AtomicU64 = AtomicU64::new(0);
+pub static TRANSACTIONS_ABORTED: AtomicU64 = AtomicU64::new(0);
+/// Body bytes received by transactions that were cleaned up. Transactions
+/// count their own bytes, and add them here once done.
+pub static BYTES_RECEIVED: Padded = Padded::new();
+/// Body bytes handed back by transactions that were cleaned up.
+pub static BYTES_SENT: Padded = Padded::new();
+/// Documents submitted to the persistence worker and not processed yet.
+pub static QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+/// 1 while the document at the front of the persistence queue waits for the
+/// persistence rate limit, see `rate_limit`.
+pub static PERSIST_THROTTLED: AtomicU64 = AtomicU64::new(0);
+/// Largest body received by a transaction in flight since the counter was
+/// last reset by the summary ticker.
+pub static LARGEST_IN_FLIGHT: Padded = Padded::new();
+/// Bytes held in the pipeline buffers of live transactions.
+pub static RETAINED_BYTES: Padded = Padded::new();
+
+/// Outcomes of work that can fail or lose data, kept in one place so that
+/// failures show up in `stats()`, the Prometheus output and the summary
+/// rather than only as individual log lines.
+pub struct Counters {
+    pub persist_attempted: [AtomicU64; BACKENDS.len()],
+    pub persist_succeeded: [AtomicU64; BACKENDS.len()],
+    pub persist_failed: [AtomicU64; BACKENDS.len()],
+    /// Bodies that failed decoding or re-encoding.
+    pub decode_errors: AtomicU64,
+    /// Body chunks that could not be handed to a transaction's pipeline.
+    pub dropped_chunks: AtomicU64,
+    /// Hosts evicted from the per-host statistics.
+    pub evictions: AtomicU64,
+    /// Transactions relayed without capture, past `limits.max_transactions`
+    /// or `limits.memory_budget`.
+    pub shed_transactions: AtomicU64,
+    /// ICAP OPTIONS requests acknowledged without a transaction.
+    pub options_requests: AtomicU64,
+    /// Header values truncated to `limits.max_header_value`.
+    pub header_values_truncated: AtomicU64,
+    /// Headers dropped past `limits.max_header_bytes`.
+    pub headers_dropped: AtomicU64,
+    /// Headers dropped past `limits.max_pending_headers`.
+    pub pending_headers_dropped: AtomicU64,
+    /// Transactions whose headers expired before they began.
+    pub pending_headers_expired: AtomicU64,
+    /// Transactions whose client stopped taking output, see
+    /// `limits.stalled_consumer_timeout`.
+    pub stalled_consumers: AtomicU64,
+    /// Of those, the ones no longer relayed.
+    pub stalled_consumers_aborted: AtomicU64,
+    /// Transactions given up on past their deadline, see
+    /// `limits.transaction_deadline`.
+    pub transactions_timed_out: AtomicU64,
+    /// Chunks dropped by `receive_checked()` as they did not match their
+    /// checksum.
+    pub checksum_mismatches: AtomicU64,
+    /// Documents dropped when submitted to a full persistence queue.
+    pub dropped_newest: AtomicU64,
+    /// Queued documents dropped to make room for newer ones.
+    pub dropped_oldest: AtomicU64,
+    /// Documents written to the dead letter file instead of being queued.
+    pub dead_lettered: AtomicU64,
+    /// Documents written to the offline spool, see `offline`.
+    pub offline_spooled: AtomicU64,
+    /// Spooled documents queued again once the backend recovered.
+    pub offline_replayed: AtomicU64,
+    /// Spooled documents removed to keep the spool under its size.
+    pub offline_pruned: AtomicU64,
+    /// Documents held back in the queue by the persistence rate limit.
+    pub persist_delayed: AtomicU64,
+    /// Output buffers reused from the buffer pool, see `pool`.
+    pub pool_hits: AtomicU64,
+    /// Output buffers allocated for lack of a pooled one.
+    pub pool_misses: AtomicU64,
+    pub panics: AtomicU64,
+    /// Audit log lines that could not be written.
+    pub audit_failures: AtomicU64,
+    /// Rotated files that could not be compressed or removed.
+    pub rotation_failures: AtomicU64,
+    /// Errors reported, indexed by `PrismError::index`.
+    pub errors: [AtomicU64; PrismError::ALL.len()],
+}
+
+/// Latency of `Backend::persist` calls, per backend.
+pub static PERSIST_LATENCY: [Histogram; BACKENDS.len()] =
+    [const { Histogram::new() }; BACKENDS.len()];
+
+pub static COUNTERS: Counters = Counters {
+    persist_attempted: [const { AtomicU64::new(0) }; BACKENDS.len()],
+    persist_succeeded: [const { AtomicU64::new(0) }; BACKENDS.len()],
+    persist_failed: [const { AtomicU64::new(0) }; BACKENDS.len()],
+    decode_errors: AtomicU64::new(0),
+    dropped_chunks: AtomicU64::new(0),
+    evictions: AtomicU64::new(0),
+    shed_transactions: AtomicU64::new(0),
+    options_requests: AtomicU64::new(0),
+    header_values_truncated: AtomicU64::new(0),
+    headers_dropped: AtomicU64::new(0),
+    pending_headers_dropped: AtomicU64::new(0),
+    pending_headers_expired: AtomicU64::new(0),
+    stalled_consumers: AtomicU64::new(0),
+    stalled_consumers_aborted: AtomicU64::new(0),
+    transactions_timed_out: AtomicU64::new(0),
+    checksum_mismatches: AtomicU64::new(0),
+    dropped_newest: AtomicU64::new(0),
+    dropped_oldest: AtomicU64::new(0),
+    dead_lettered: AtomicU64::new(0),
+    offline_spooled: AtomicU64::new(0),
+    offline_replayed: AtomicU64::new(0),
+    offline_pruned: AtomicU64::new(0),
+    persist_delayed: AtomicU64::new(0),
+    pool_hits: AtomicU64::new(0),
+    pool_misses: AtomicU64::new(0),
+    panics: AtomicU64::new(0),
+    audit_failures: AtomicU64::new(0),
+    rotation_failures: AtomicU64::new(0),
+    errors: [const { AtomicU64::new(0) }; PrismError::ALL.len()],
+};
+
+#[derive(Serialize)]
+pub struct PersistSnapshot {
+    attempted: u64,
+    succeeded: u64,
+    failed: u64,
+}
+
+#[derive(Serialize)]
+pub struct TransactionsSnapshot {
+    started: u64,
+    completed: u64,
+    aborted: u64,
+}
+
+/// Point in time copy of the counters, as returned by `stats()`. Counters
+/// are read one by one without locking, so a snapshot taken under load may
+/// miss updates made while it was being taken.
+#[derive(Serialize)]
+pub struct CountersSnapshot {
+    transactions: TransactionsSnapshot,
+    bytes_received: u64,
+    bytes_sent: u64,
+    persist: BTreeMap<&'static str, PersistSnapshot>,
+    decode_errors: u64,
+    dropped_chunks: u64,
+    evictions: u64,
+    shed_transactions: u64,
+    options_requests: u64,
+    header_values_truncated: u64,
+    headers_dropped: u64,
+    pending_headers_dropped: u64,
+    pending_headers_expired: u64,
+    stalled_consumers: u64,
+    stalled_consumers_aborted: u64,
+    transactions_timed_out: u64,
+    checksum_mismatches: u64,
+    dropped_newest: u64,
+    dropped_oldest: u64,
+    dead_lettered: u64,
+    offline_spooled: u64,
+    offline_replayed: u64,
+    offline_pruned: u64,
+    persist_delayed: u64,
+    pool_hits: u64,
+    pool_misses: u64,
+    panics: u64,
+    audit_failures: u64,
+    rotation_failures: u64,
+    errors: BTreeMap<&'static str, u64>,
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> CountersSnapshot {
+        let persist = BACKENDS
+            .iter()
+            .enumerate()
+            .map(|(index, backend)| {
+                (
+                    *backend,
+                    PersistSnapshot {
+                        attempted: get(&self.persist_attempted[index]),
+                        succeeded: get(&self.persist_succeeded[index]),
+                        failed: get(&self.persist_failed[index]),
+                    },
+                )
+            })
+            .collect();
+        CountersSnapshot {
+            transactions: TransactionsSnapshot {
+                started: get(&TRANSACTIONS_STARTED),
+                completed: get(&TRANSACTIONS_COMPLETED),
+                aborted: get(&TRANSACTIONS_ABORTED),
+            },
+            bytes_received: get(&BYTES_RECEIVED),
+            bytes_sent: get(&BYTES_SENT),
+            persist,
+            decode_errors: get(&self.decode_errors),
+            dropped_chunks: get(&self.dropped_chunks),
+            evictions: get(&self.evictions),
+            shed_transactions: get(&self.shed_transactions),
+            options_requests: get(&self.options_requests),
+            header_values_truncated: get(&self.header_values_truncated),
+            headers_dropped: get(&self.headers_dropped),
+            pending_headers_dropped: get(&self.pending_headers_dropped),
+            pending_headers_expired: get(&self.pending_headers_expired),
+            stalled_consumers: get(&self.stalled_consumers),
+            stalled_consumers_aborted: get(&self.stalled_consumers_aborted),
+            transactions_timed_out: get(&self.transactions_timed_out),
+            checksum_mismatches: get(&self.checksum_mismatches),
+            dropped_newest: get(&self.dropped_newest),
+            dropped_oldest: get(&self.dropped_oldest),
+            dead_lettered: get(&self.dead_lettered),
+            offline_spooled: get(&self.offline_spooled),
+            offline_replayed: get(&self.offline_replayed),
+            offline_pruned: get(&self.offline_pruned),
+            persist_delayed: get(&self.persist_delayed),
+            pool_hits: get(&self.pool_hits),
+            pool_misses: get(&self.pool_misses),
+            panics: get(&self.panics),
+            audit_failures: get(&self.audit_failures),
+            rotation_failures: get(&self.rotation_failures),
+            errors: PrismError::ALL
+                .iter()
+                .map(|error| (error.label(), get(&self.errors[error.index()])))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+static STOP: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "metrics")]
+static LISTENER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+pub fn add(counter: &AtomicU64, value: usize) {
+    counter.fetch_add(value as u64, Ordering::Relaxed);
+}
+
+pub fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn sub(counter: &AtomicU64, value: usize) {
+    counter.fetch_sub(value as u64, Ordering::Relaxed);
+}
+
+pub fn decrement(counter: &AtomicU64) {
+    counter.fetch_sub(1, Ordering::Relaxed);
+}
+
+fn backend_index(backend: &str) -> Option<usize> {
+    BACKENDS.iter().position(|name| *name == backend)
+}
+
+/// Counts an attempt at persisting a document with the named backend.
+pub fn persisting(backend: &str) {
+    if let Some(index) = backend_index(backend) {
+        increment(&COUNTERS.persist_attempted[index]);
+    }
+}
+
+/// Counts the outcome of persisting a document with the named backend, and
+/// how long it took.
+pub fn persisted(backend: &str, success: bool, elapsed: Duration) {
+    if let Some(index) = backend_index(backend) {
+        PERSIST_LATENCY[index].record(elapsed);
+        let counters = if success {
+            &COUNTERS.persist_succeeded
+        } else {
+            &COUNTERS.persist_failed
+        };
+        increment(&counters[index]);
+    }
+}
+
+/// Counts an error under its label.
+pub fn error(error: PrismError) {
+    increment(&COUNTERS.errors[error.index()]);
+}
+
+/// Raises a high water mark counter to `value`.
+pub fn raise(counter: &AtomicU64, value: usize) {
+    counter.fetch_max(value as u64, Ordering::Relaxed);
+}
+
+pub fn get(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// Persist latencies per backend, as returned by `stats()`.
+pub fn persist_latency() -> BTreeMap<&'static str, HistogramSnapshot> {
+    let reset = config::get().telemetry.stats_reset_latency;
+    BACKENDS
+        .iter()
+        .zip(PERSIST_LATENCY.iter())
+        .map(|(backend, histogram)| (*backend, histogram.snapshot(reset)))
+        .collect()
+}
+
+/// Failed persist attempts, over all backends.
+pub fn persist_failures() -> u64 {
+    COUNTERS.persist_failed.iter().map(get).sum()
+}
+
+pub fn active_transactions() -> u64 {
+    get(&TRANSACTIONS_STARTED)
+        .saturating_sub(get(&TRANSACTIONS_COMPLETED) + get(&TRANSACTIONS_ABORTED))
+}
+
+#[cfg(feature = "metrics")]
+fn metric(output: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+    let _ = writeln!(output, "{} {}", name, value);
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+#[cfg(feature = "metrics")]
+pub fn render() -> String {
+    let mut output = String::new();
+    let started = get(&TRANSACTIONS_STARTED);
+    let completed = get(&TRANSACTIONS_COMPLETED);
+    let aborted = get(&TRANSACTIONS_ABORTED);
+
+    metric(
+        &mut output,
+        "prism_transactions_started_total",
+        "counter",
+        "Transactions started.",
+        started,
+    );
+    metric(
+        &mut output,
+        "prism_transactions_completed_total",
+        "counter",
+        "Transactions cleaned up after completing.",
+        completed,
+    );
+    metric(
+        &mut output,
+        "prism_transactions_aborted_total",
+        "counter",
+        "Transactions cleaned up before completing.",
+        aborted,
+    );
+    metric(
+        &mut output,
+        "prism_bytes_received_total",
+        "counter",
+        "Body bytes received from origins, by transactions cleaned up.",
+        get(&BYTES_RECEIVED),
+    );
+    metric(
+        &mut output,
+        "prism_bytes_sent_total",
+        "counter",
+        "Body bytes handed back to clients, by transactions cleaned up.",
+        get(&BYTES_SENT),
+    );
+    metric(
+        &mut output,
+        "prism_decode_errors_total",
+        "counter",
+        "Bodies that failed decoding or re-encoding.",
+        get(&COUNTERS.decode_errors),
+    );
+    metric(
+        &mut output,
+        "prism_dropped_chunks_total",
+        "counter",
+        "Body chunks that could not be handed to a transaction's pipeline.",
+        get(&COUNTERS.dropped_chunks),
+    );
+    metric(
+        &mut output,
+        "prism_host_evictions_total",
+        "counter",
+        "Hosts evicted from the per-host statistics.",
+        get(&COUNTERS.evictions),
+    );
+    metric(
+        &mut output,
+        "prism_shed_transactions_total",
+        "counter",
+        "Transactions relayed without capture, past the transaction or memory limit.",
+        get(&COUNTERS.shed_transactions),
+    );
+    metric(
+        &mut output,
+        "prism_options_requests_total",
+        "counter",
+        "ICAP OPTIONS requests acknowledged without a transaction.",
+        get(&COUNTERS.options_requests),
+    );
+    metric(
+        &mut output,
+        "prism_header_values_truncated_total",
+        "counter",
+        "Header values truncated to the header value limit.",
+        get(&COUNTERS.header_values_truncated),
+    );
+    metric(
+        &mut output,
+        "prism_headers_dropped_total",
+        "counter",
+        "Headers dropped past the header bytes limit of their transaction.",
+        get(&COUNTERS.headers_dropped),
+    );
+    metric(
+        &mut output,
+        "prism_pending_headers_dropped_total",
+        "counter",
+        "Headers dropped past the limit of transactions not begun yet.",
+        get(&COUNTERS.pending_headers_dropped),
+    );
+    metric(
+        &mut output,
+        "prism_pending_headers_expired_total",
+        "counter",
+        "Transactions whose headers expired before they began.",
+        get(&COUNTERS.pending_headers_expired),
+    );
+    metric(
+        &mut output,
+        "prism_stalled_consumers_total",
+        "counter",
+        "Transactions whose client stopped taking the output held for it.",
+        get(&COUNTERS.stalled_consumers),
+    );
+    metric(
+        &mut output,
+        "prism_stalled_consumers_aborted_total",
+        "counter",
+        "Transactions no longer relayed as their client stopped taking output.",
+        get(&COUNTERS.stalled_consumers_aborted),
+    );
+    metric(
+        &mut output,
+        "prism_transactions_timed_out_total",
+        "counter",
+        "Transactions no longer relayed past their deadline.",
+        get(&COUNTERS.transactions_timed_out),
+    );
+    metric(
+        &mut output,
+        "prism_chunk_checksum_mismatches_total",
+        "counter",
+        "Chunks received with a checksum they did not match, and dropped.",
+        get(&COUNTERS.checksum_mismatches),
+    );
+    metric(
+        &mut output,
+        "prism_queue_dropped_newest_total",
+        "counter",
+        "Documents dropped when submitted to a full persistence queue.",
+        get(&COUNTERS.dropped_newest),
+    );
+    metric(
+        &mut output,
+        "prism_queue_dropped_oldest_total",
+        "counter",
+        "Queued documents dropped to make room for newer ones.",
+        get(&COUNTERS.dropped_oldest),
+    );
+    metric(
+        &mut output,
+        "prism_dead_lettered_total",
+        "counter",
+        "Documents written to the dead letter file instead of being queued.",
+        get(&COUNTERS.dead_lettered),
+    );
+    metric(
+        &mut output,
+        "prism_offline_spooled_total",
+        "counter",
+        "Documents written to the offline spool.",
+        get(&COUNTERS.offline_spooled),
+    );
+    metric(
+        &mut output,
+        "prism_offline_replayed_total",
+        "counter",
+        "Spooled documents queued again once the backend recovered.",
+        get(&COUNTERS.offline_replayed),
+    );
+    metric(
+        &mut output,
+        "prism_offline_pruned_total",
+        "counter",
+        "Spooled documents removed to keep the spool under its size.",
+        get(&COUNTERS.offline_pruned),
+    );
+    metric(
+        &mut output,
+        "prism_persist_delayed_total",
+        "counter",
+        "Documents held back in the queue by the persistence rate limit.",
+        get(&COUNTERS.persist_delayed),
+    );
+    metric(
+        &mut output,
+        "prism_buffer_pool_hits_total",
+        "counter",
+        "Output buffers reused from the buffer pool.",
+        get(&COUNTERS.pool_hits),
+    );
+    metric(
+        &mut output,
+        "prism_buffer_pool_misses_total",
+        "counter",
+        "Output buffers allocated for lack of a pooled one.",
+        get(&COUNTERS.pool_misses),
+    );
+    metric(
+        &mut output,
+        "prism_panics_total",
+        "counter",
+        "Panics caught by the panic hook.",
+        get(&COUNTERS.panics),
+    );
+    metric(
+        &mut output,
+        "prism_audit_failures_total",
+        "counter",
+        "Audit log lines that could not be written.",
+        get(&COUNTERS.audit_failures),
+    );
+    metric(
+        &mut output,
+        "prism_rotation_failures_total",
+        "counter",
+        "Rotated files that could not be compressed or removed.",
+        get(&COUNTERS.rotation_failures),
+    );
+
+    let _ = writeln!(
+        output,
+        "# HELP prism_errors_total Errors reported, by kind."
+    );
+    let _ = writeln!(output, "# TYPE prism_errors_total counter");
+    for error in PrismError::ALL {
+        let _ = writeln!(
+            output,
+            "prism_errors_total{{kind=\"{}\",code=\"{}\"}} {}",
+            error.label(),
+            error.code(),
+            get(&COUNTERS.errors[error.index()])
+        );
+    }
+
+    let _ = writeln!(
+        output,
+        "# HELP prism_persist_attempts_total Attempts at persisting documents, by backend."
+    );
+    let _ = writeln!(output, "# TYPE prism_persist_attempts_total counter");
+    for (index, backend) in BACKENDS.iter().enumerate() {
+        let _ = writeln!(
+            output,
+            "prism_persist_attempts_total{{backend=\"{}\"}} {}",
+            backend,
+            get(&COUNTERS.persist_attempted[index])
+        );
+    }
+
+    let _ = writeln!(
+        output,
+        "# HELP prism_persist_total Documents persisted, by backend and result."
+    );
+    let _ = writeln!(output, "# TYPE prism_persist_total counter");
+    for (index, backend) in BACKENDS.iter().enumerate() {
+        let _ = writeln!(
+            output,
+            "prism_persist_total{{backend=\"{}\",result=\"success\"}} {}",
+            backend,
+            get(&COUNTERS.persist_succeeded[index])
+        );
+        let _ = writeln!(
+            output,
+            "prism_persist_total{{backend=\"{}\",result=\"failure\"}} {}",
+            backend,
+            get(&COUNTERS.persist_failed[index])
+        );
+    }
+
+    let _ = writeln!(
+        output,
+        "# HELP prism_persist_duration_seconds Time taken persisting documents, by backend."
+    );
+    let _ = writeln!(output, "# TYPE prism_persist_duration_seconds histogram");
+    for (backend, histogram) in BACKENDS.iter().zip(PERSIST_LATENCY.iter()) {
+        histogram.render(
+            &mut output,
+            "prism_persist_duration_seconds",
+            &format!("backend=\"{}\"", backend),
+        );
+    }
+
+    metric(
+        &mut output,
+        "prism_active_transactions",
+        "gauge",
+        "Transactions started and not cleaned up yet.",
+        active_transactions(),
+    );
+    metric(
+        &mut output,
+        "prism_persistence_queue_depth",
+        "gauge",
+        "Documents waiting for the persistence worker.",
+        get(&QUEUE_DEPTH),
+    );
+    metric(
+        &mut output,
+        "prism_persist_throttled",
+        "gauge",
+        "Whether the persistence rate limit holds documents back.",
+        get(&PERSIST_THROTTLED),
+    );
+    metric(
+        &mut output,
+        "prism_retained_bytes",
+        "gauge",
+        "Bytes held in the pipeline buffers of live transactions.",
+        get(&RETAINED_BYTES),
+    );
+    output
+}
+
+#[cfg(feature = "metrics")]
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, content_type, body) = if path == "/metrics" {
+        ("200 OK", "text/plain; version=0.0.4", render())
+    } else {
+        ("404 Not Found", "text/plain", "Not found\n".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(feature = "metrics")]
+fn serve(listener: TcpListener) {
+    while !STOP.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = respond(stream) {
+                    warn!("Failed serving metrics: {}", e);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            Err(e) => {
+                warn!("Failed accepting metrics connection: {}", e);
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+        }
+    }
+    info!("Metrics listener stopped");
+}
+
+/// Starts serving metrics, if an address is configured.
+#[cfg(feature = "metrics")]
+pub fn start() {
+    let address = match &config::get().telemetry.metrics_address {
+        Some(address) if !cfg!(miri) => address.clone(),
+        _ => return,
+    };
+
+    let mut handle = LISTENER.lock().unwrap();
+    if handle.is_some() {
+        return;
+    }
+
+    let listener = match TcpListener::bind(&address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed binding metrics listener to {}: {}", address, e);
+            return;
+        }
+    };
+    // Polled, so the listener notices when it is asked to stop.
+    if let Err(e) = listener.set_nonblocking(true) {
+        warn!("Failed setting up metrics listener: {}", e);
+        return;
+    }
+
+    STOP.store(false, Ordering::Relaxed);
+    match thread::Builder::new()
+        .name("prism-metrics".to_string())
+        .spawn(move || serve(listener))
+    {
+        Ok(thread) => {
+            info!("Serving metrics on {}", address);
+            *handle = Some(thread);
+        }
+        Err(e) => warn!("Failed starting metrics listener: {}", e),
+    }
+}
+
+/// Stops serving metrics and waits for the listener to exit.
+#[cfg(feature = "metrics")]
+pub fn stop() {
+    if let Some(thread) = LISTENER.lock().unwrap().take() {
+        STOP.store(true, Ordering::Relaxed);
+        let _ = thread.join();
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn start() {}
+
+#[cfg(not(feature = "metrics"))]
+pub fn stop() {}
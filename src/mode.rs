@@ -1,28 +1,84 @@
+//! ICAP mode of a transaction, as passed to `uri()`.
+
+use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter, Result};
+use std::str::FromStr;
 
+/// Serialized under the same names it is displayed with.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
 pub enum Mode {
     REQMOD,
     RESPMOD,
+    OPTIONS,
     UNKNOWN,
 }
 
+impl Mode {
+    pub const ALL: [Mode; 4] = [Mode::REQMOD, Mode::RESPMOD, Mode::OPTIONS, Mode::UNKNOWN];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::REQMOD => "REQMOD",
+            Mode::RESPMOD => "RESPMOD",
+            Mode::OPTIONS => "OPTIONS",
+            Mode::UNKNOWN => "UNKNOWN",
+        }
+    }
+}
+
 impl From<i64> for Mode {
     fn from(value: i64) -> Self {
         match value {
             0 => Mode::REQMOD,
             1 => Mode::RESPMOD,
+            2 => Mode::OPTIONS,
             _ => Mode::UNKNOWN,
         }
     }
 }
 
+/// The value `uri()` is passed for the mode, `-1` for an unknown one.
+impl From<Mode> for i64 {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::REQMOD => 0,
+            Mode::RESPMOD => 1,
+            Mode::OPTIONS => 2,
+            Mode::UNKNOWN => -1,
+        }
+    }
+}
+
 impl Display for Mode {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        let value = match self {
-            Mode::REQMOD => "0",
-            Mode::RESPMOD => "1",
-            Mode::UNKNOWN => "UNKNOWN",
-        };
-        write!(f, "{}", value)
+        f.write_str(self.as_str())
+    }
+}
+
+/// A mode name other than `REQMOD`, `RESPMOD`, `OPTIONS` or `UNKNOWN`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseModeError(String);
+
+impl Display for ParseModeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "unknown mode \"{}\", expected REQMOD, RESPMOD, OPTIONS or UNKNOWN",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseModeError {}
+
+/// Parses mode names, ignoring case.
+impl FromStr for Mode {
+    type Err = ParseModeError;
+
+    fn from_str(name: &str) -> std::result::Result<Self, Self::Err> {
+        Mode::ALL
+            .into_iter()
+            .find(|mode| mode.as_str().eq_ignore_ascii_case(name))
+            .ok_or_else(|| ParseModeError(name.to_string()))
     }
 }
@@ -0,0 +1,113 @@
+use crate::headers::Headers;
+use crate::uri;
+use serde::{Deserialize, Serialize};
+
+/// What a request fetched, as the browser would put it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchKind {
+    Document,
+    Script,
+    Style,
+    Image,
+    /// Fetched by scripts, through `fetch()` or `XMLHttpRequest`.
+    Xhr,
+}
+
+impl FetchKind {
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "document" => Some(FetchKind::Document),
+            "script" => Some(FetchKind::Script),
+            "style" => Some(FetchKind::Style),
+            "image" => Some(FetchKind::Image),
+            "xhr" => Some(FetchKind::Xhr),
+            _ => None,
+        }
+    }
+
+    /// The kind of a `Sec-Fetch-Dest` destination. Destinations of other
+    /// kinds, such as fonts or media, have none.
+    fn of_destination(destination: &str) -> Option<Self> {
+        match destination.trim().to_ascii_lowercase().as_str() {
+            "document" | "iframe" | "frame" => Some(FetchKind::Document),
+            "script" | "worker" | "sharedworker" | "serviceworker" => Some(FetchKind::Script),
+            "style" => Some(FetchKind::Style),
+            "image" => Some(FetchKind::Image),
+            "empty" => Some(FetchKind::Xhr),
+            _ => None,
+        }
+    }
+
+    /// The kind of content of a media type, parameters and all.
+    fn of_media_type(media_type: &str) -> Option<Self> {
+        let essence = media_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        match essence.as_str() {
+            "text/html" | "application/xhtml+xml" => Some(FetchKind::Document),
+            "text/css" => Some(FetchKind::Style),
+            _ if essence.starts_with("image/") => Some(FetchKind::Image),
+            _ if essence.contains("javascript") || essence.contains("ecmascript") => {
+                Some(FetchKind::Script)
+            }
+            _ if essence == "application/json" || essence.ends_with("+json") => {
+                Some(FetchKind::Xhr)
+            }
+            _ => None,
+        }
+    }
+
+    /// Guesses the kind of a transaction from its headers: `Sec-Fetch-Dest`
+    /// when the browser sent it, otherwise the first specific type the
+    /// request accepts, otherwise the content type of the response.
+    pub fn of(headers: &Headers) -> Option<Self> {
+        if let Some(destination) = headers.get("Sec-Fetch-Dest") {
+            return Self::of_destination(destination);
+        }
+        let accepted = headers.get("Accept").and_then(|accept| {
+            accept
+                .split(',')
+                .map(str::trim)
+                .find(|range| !range.is_empty() && !range.starts_with("*/*"))
+        });
+        match accepted.and_then(Self::of_media_type) {
+            Some(kind) => Some(kind),
+            None => headers.get("Content-Type").and_then(Self::of_media_type),
+        }
+    }
+}
+
+/// Where a transaction was requested from, and what it fetched.
+#[derive(Default, Deserialize, Serialize)]
+pub struct Navigation {
+    /// The `Referer` request header, as sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referer_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fetch_kind: Option<FetchKind>,
+}
+
+impl Navigation {
+    pub fn new(headers: &Headers) -> Self {
+        let referer = headers
+            .get("Referer")
+            .map(|referer| referer.trim().to_string())
+            .filter(|referer| !referer.is_empty());
+        Navigation {
+            referer_host: referer.as_deref().and_then(referer_host),
+            referer,
+            fetch_kind: FetchKind::of(headers),
+        }
+    }
+}
+
+/// Host of a `Referer` value, which is an absolute URL when well formed.
+pub fn referer_host(referer: &str) -> Option<String> {
+    uri::parse(referer.trim()).host_or(None)
+}
@@ -0,0 +1,107 @@
+//! Hooks for code embedding prism to observe transactions, such as custom
+//! metrics or tracing bridges, without patching the crate.
+
+use crate::error::PrismError;
+use crate::metrics;
+use log::{error, warn};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+pub struct TransactionStart<'a> {
+    pub id: i64,
+    pub method: &'a str,
+    pub uri: &'a str,
+}
+
+pub struct ChunkReceived {
+    pub id: i64,
+    pub size: usize,
+    /// Body bytes received so far, including this chunk.
+    pub total: usize,
+}
+
+pub struct Done {
+    pub id: i64,
+    pub bytes_received: usize,
+}
+
+pub struct Persisted {
+    pub id: i64,
+    pub backend: &'static str,
+    pub result: Result<(), PrismError>,
+    pub elapsed: Duration,
+}
+
+pub struct ErrorEvent {
+    /// Transaction the error relates to, if any.
+    pub id: Option<i64>,
+    pub error: PrismError,
+}
+
+pub struct Cleanup {
+    pub id: i64,
+    /// Whether `done()` was called before the transaction was cleaned up.
+    pub completed: bool,
+    pub bytes_received: usize,
+    pub bytes_sent: usize,
+    pub duration: Duration,
+}
+
+/// Receives lifecycle events of transactions. All methods do nothing by
+/// default. `on_persisted` is called from the persistence worker, all other
+/// methods from the thread calling into the library.
+pub trait LifecycleObserver: Send + Sync {
+    fn on_transaction_start(&self, _event: &TransactionStart) {}
+    fn on_chunk_received(&self, _event: &ChunkReceived) {}
+    fn on_done(&self, _event: &Done) {}
+    fn on_persisted(&self, _event: &Persisted) {}
+    fn on_error(&self, _event: &ErrorEvent) {}
+    fn on_cleanup(&self, _event: &Cleanup) {}
+}
+
+static OBSERVERS: RwLock<Vec<Box<dyn LifecycleObserver>>> = RwLock::new(Vec::new());
+/// Set when at least one observer is registered, so events are not even
+/// built otherwise.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Set by `init()`, after which no more observers are accepted.
+static SEALED: AtomicBool = AtomicBool::new(false);
+
+/// Registers an observer. Observers must be registered before `init()`.
+pub fn register(observer: Box<dyn LifecycleObserver>) -> Result<(), ()> {
+    if SEALED.load(Ordering::Relaxed) {
+        warn!("Observers must be registered before init(), ignoring observer");
+        return Err(());
+    }
+    OBSERVERS.write().unwrap().push(observer);
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub(crate) fn seal() {
+    SEALED.store(true, Ordering::Relaxed);
+}
+
+/// Calls `f` for every observer. A panicking observer is logged and does not
+/// affect the others, nor the transaction.
+pub(crate) fn notify(f: impl Fn(&dyn LifecycleObserver)) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let observers = match OBSERVERS.read() {
+        Ok(observers) => observers,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    for observer in observers.iter() {
+        if catch_unwind(AssertUnwindSafe(|| f(observer.as_ref()))).is_err() {
+            error!("Lifecycle observer panicked, event skipped");
+        }
+    }
+}
+
+/// Counts an error and passes it on to observers.
+pub(crate) fn error(id: Option<i64>, error: PrismError) {
+    metrics::error(error);
+    notify(|observer| observer.on_error(&ErrorEvent { id, error }));
+}
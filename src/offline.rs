@@ -0,0 +1,522 @@
+//! Spooling to disk of the documents the backend can't take, and their
+//! replay once it recovers, as `offline` configures.
+//!
+//! Documents are spooled when the persistence queue has no room for them,
+//! when they fail to persist with the backend unavailable, and when the
+//! worker stops with documents it could not persist. A failed persist also
+//! opens the circuit: until a probe, one document every `PROBE_INTERVAL`,
+//! persists again, live documents taken off the queue are spooled without
+//! being tried, see `worker::run()`.
+//!
+//! The spool is a directory of files named `offline-<sequence>.jsonl`, one
+//! JSON line per document, sealed when `encryption` is on. A new file is
+//! started past `FILE_SIZE`, and the oldest are removed past
+//! `offline.max_size`. The worker takes the oldest file, removing it, and
+//! queues its documents again at `offline.replay_rate` while the circuit is
+//! closed, or as probes while it is open, see `worker::replay()`. Spool
+//! files left by an earlier run are replayed the same way. Documents keep
+//! their id, document id and date, so the backend stores them as it would
+//! have. Those failing again once the backend recovered are given up on
+//! after `MAX_FAILURES`, as the backend rejects them rather than being
+//! unavailable.
+
+use crate::clock;
+use crate::config;
+use crate::document::Document;
+use crate::encryption;
+use crate::logging::throttled;
+use crate::metrics;
+use crate::worker::PendingDocument;
+use chrono::{DateTime, Utc};
+use log::{info, warn, Level};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Size past which a new spool file is started, in bytes.
+const FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How often a document is tried while the circuit is open.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Times a replayed document fails again, with the backend otherwise
+/// available, before it is given up on.
+const MAX_FAILURES: u32 = 5;
+
+const PREFIX: &str = "offline-";
+const EXTENSION: &str = ".jsonl";
+
+/// Extension spool files that could not be read are renamed with, so they
+/// are not tried again.
+const UNREADABLE: &str = ".unreadable";
+
+/// A document as spooled, with what it holds that is not persisted.
+#[derive(Serialize)]
+struct Spooled<'a> {
+    id: i64,
+    /// Whether the body is still to be handed to the scanner.
+    scan: bool,
+    /// See `PendingDocument::replayed`.
+    failures: u32,
+    bytes_received: usize,
+    started_at: String,
+    wait_us: u64,
+    receive_us: u64,
+    /// See `Document::decode_pending`.
+    decode_pending: bool,
+    document: &'a Document,
+}
+
+#[derive(Deserialize)]
+struct Restored {
+    id: i64,
+    scan: bool,
+    failures: u32,
+    bytes_received: usize,
+    started_at: String,
+    wait_us: u64,
+    receive_us: u64,
+    #[serde(default)]
+    decode_pending: bool,
+    document: Document,
+}
+
+impl Restored {
+    /// The document as it was spooled, to be queued again.
+    fn into_pending(self) -> PendingDocument {
+        let mut document = self.document;
+        document.id = self.id;
+        document.body = document.raw_body.clone();
+        document.body_size = document.body.bytes().len();
+        document.bytes_received = self.bytes_received;
+        document.started_at = DateTime::parse_from_rfc3339(&self.started_at)
+            .map(|started_at| started_at.with_timezone(&Utc))
+            .unwrap_or_else(|_| clock::utc());
+        document.wait = Duration::from_micros(self.wait_us);
+        document.receive = Duration::from_micros(self.receive_us);
+        document.decode_pending = self.decode_pending;
+        PendingDocument {
+            document,
+            scan: self.scan,
+            span: tracing::Span::none(),
+            dry_run: false,
+            replayed: Some(self.failures),
+        }
+    }
+}
+
+/// The file documents are spooled to.
+struct Current {
+    sequence: u64,
+    file: File,
+}
+
+struct Spool {
+    directory: PathBuf,
+    current: Option<Current>,
+    /// Sizes of the spool files, by sequence number, current one included.
+    files: BTreeMap<u64, u64>,
+    /// Sequence number of the next file started. Never reused, so a file
+    /// being replayed is not appended to again.
+    next: u64,
+}
+
+static SPOOL: Mutex<Option<Spool>> = Mutex::new(None);
+
+/// Set while the backend is found unavailable.
+static OPEN: AtomicBool = AtomicBool::new(false);
+/// When the next document is tried while the circuit is open.
+static PROBE_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Sequence number of a spool file named `name`.
+fn sequence(name: &str) -> Option<u64> {
+    name.strip_prefix(PREFIX)?
+        .strip_suffix(EXTENSION)?
+        .parse()
+        .ok()
+}
+
+impl Spool {
+    /// The spool in `directory`, with the files left in it.
+    fn open(directory: PathBuf) -> std::io::Result<Spool> {
+        std::fs::create_dir_all(&directory)?;
+        let mut files = BTreeMap::new();
+        for file in std::fs::read_dir(&directory)? {
+            let file = file?;
+            let Some(sequence) = file.file_name().to_str().and_then(sequence) else {
+                continue;
+            };
+            files.insert(sequence, file.metadata()?.len());
+        }
+        if !files.is_empty() {
+            info!(
+                "Found {} offline spool files in {}, to be replayed",
+                files.len(),
+                directory.display()
+            );
+        }
+        let next = files.keys().next_back().map_or(0, |last| last + 1);
+        Ok(Spool {
+            directory,
+            current: None,
+            files,
+            next,
+        })
+    }
+
+    fn path(&self, sequence: u64) -> PathBuf {
+        self.directory
+            .join(format!("{}{:010}{}", PREFIX, sequence, EXTENSION))
+    }
+
+    fn size(&self) -> u64 {
+        self.files.values().sum()
+    }
+
+    /// Appends a line to the current file, starting one as needed.
+    fn append(&mut self, line: &[u8], max_size: u64) -> std::io::Result<()> {
+        let file_size = (max_size / 4).clamp(1, FILE_SIZE);
+        if let Some(current) = &self.current {
+            if self.files.get(&current.sequence).copied().unwrap_or(0) >= file_size {
+                self.current = None;
+            }
+        }
+        if self.current.is_none() {
+            let sequence = self.next;
+            self.next += 1;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path(sequence))?;
+            self.files.insert(sequence, 0);
+            self.current = Some(Current { sequence, file });
+        }
+        let current = self.current.as_mut().unwrap();
+        let size = self.files.get(&current.sequence).copied().unwrap_or(0);
+        if let Err(e) = current.file.write_all(line) {
+            let _ = current.file.set_len(size);
+            return Err(e);
+        }
+        self.files
+            .insert(current.sequence, size + line.len() as u64);
+        Ok(())
+    }
+
+    /// Removes the oldest files, but the current one, until the spool is
+    /// back under `max_size`.
+    fn prune(&mut self, max_size: u64) {
+        while self.size() > max_size {
+            let current = self.current.as_ref().map(|current| current.sequence);
+            let Some(&oldest) = self
+                .files
+                .keys()
+                .find(|&&sequence| Some(sequence) != current)
+            else {
+                return;
+            };
+            self.files.remove(&oldest);
+            let path = self.path(oldest);
+            let documents = File::open(&path)
+                .map(|file| BufReader::new(file).lines().count())
+                .unwrap_or(0);
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    metrics::add(&metrics::COUNTERS.offline_pruned, documents);
+                    throttled!(
+                        Level::Warn,
+                        "offline-prune",
+                        "Removed {} spooled documents of {}, the offline spool is past {} bytes",
+                        documents,
+                        path.display(),
+                        max_size
+                    );
+                }
+                Err(e) => throttled!(
+                    Level::Warn,
+                    "offline-prune",
+                    "Failed removing offline spool file {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+/// The spool of the directory configured, `None` when spooling is off.
+fn spool<'a>(spool: &'a mut MutexGuard<'_, Option<Spool>>) -> Option<&'a mut Spool> {
+    let config = config::get();
+    let Some(directory) = &config.offline.directory else {
+        **spool = None;
+        return None;
+    };
+    if spool.as_ref().map(|spool| &spool.directory) != Some(directory) {
+        match Spool::open(directory.clone()) {
+            Ok(opened) => **spool = Some(opened),
+            Err(e) => {
+                throttled!(
+                    Level::Warn,
+                    "offline",
+                    "Failed opening offline spool {}: {}",
+                    directory.display(),
+                    e
+                );
+                **spool = None;
+                return None;
+            }
+        }
+    }
+    spool.as_mut()
+}
+
+/// Whether documents are spooled, see `offline.directory`.
+pub fn enabled() -> bool {
+    config::get().offline.directory.is_some()
+}
+
+/// Spools a document, returning whether it was. Documents of dry runs are
+/// not.
+pub fn write(pending: &PendingDocument) -> bool {
+    !pending.dry_run
+        && append(
+            &pending.document,
+            pending.scan,
+            pending.replayed.unwrap_or_default(),
+        )
+}
+
+fn append(document: &Document, scan: bool, failures: u32) -> bool {
+    let mut guard = lock(&SPOOL);
+    let Some(spool) = spool(&mut guard) else {
+        return false;
+    };
+    let spooled = Spooled {
+        id: document.id,
+        scan,
+        failures,
+        bytes_received: document.bytes_received,
+        started_at: document.started_at.to_rfc3339(),
+        wait_us: document.wait.as_micros() as u64,
+        receive_us: document.receive.as_micros() as u64,
+        decode_pending: document.decode_pending,
+        document,
+    };
+    let line = serde_json::to_vec(&spooled)
+        .map_err(std::io::Error::from)
+        .and_then(|mut line| {
+            line.push(b'\n');
+            encryption::seal(line)
+        });
+    let max_size = config::get().offline.max_size;
+    match line.and_then(|line| spool.append(&line, max_size)) {
+        Ok(()) => {
+            spool.prune(max_size);
+            metrics::increment(&metrics::COUNTERS.offline_spooled);
+            true
+        }
+        Err(e) => {
+            throttled!(
+                Level::Warn,
+                "offline",
+                "Failed spooling document {} to {}: {}",
+                document.id,
+                spool.directory.display(),
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Takes the documents of the oldest spool file, removing it. Files that
+/// can't be read are set aside. Both happen with the spool locked, so a
+/// document spooled meanwhile never goes to a file about to be removed.
+pub fn take() -> Vec<PendingDocument> {
+    let mut guard = lock(&SPOOL);
+    let Some(spool) = spool(&mut guard) else {
+        return Vec::new();
+    };
+    let Some((&oldest, _)) = spool.files.iter().next() else {
+        return Vec::new();
+    };
+    if spool
+        .current
+        .as_ref()
+        .is_some_and(|current| current.sequence == oldest)
+    {
+        spool.current = None;
+    }
+    spool.files.remove(&oldest);
+    let path = spool.path(oldest);
+    match read(&path) {
+        Ok(documents) => {
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!(
+                    "Failed removing replayed offline spool file {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+            documents
+        }
+        Err(e) => {
+            let mut aside = path.clone().into_os_string();
+            aside.push(UNREADABLE);
+            let _ = std::fs::rename(&path, &aside);
+            warn!(
+                "Failed reading offline spool file {}, set aside as {}: {}",
+                path.display(),
+                Path::new(&aside).display(),
+                e
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// The documents of a spool file. Lines that don't parse, such as one
+/// cut short by a crash, are left out, unless none parse.
+fn read(path: &Path) -> Result<Vec<PendingDocument>, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let content = match encryption::key()? {
+        Some(key) => {
+            let keys = std::slice::from_ref(&*key);
+            encryption::open(BufReader::new(file), keys, usize::MAX)?
+        }
+        None => {
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).map_err(|e| e.to_string())?;
+            content
+        }
+    };
+    let mut documents = Vec::new();
+    let mut failed = 0;
+    for line in content.split(|&byte| byte == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_slice::<Restored>(line) {
+            Ok(restored) => documents.push(restored.into_pending()),
+            Err(_) => failed += 1,
+        }
+    }
+    if failed > 0 && documents.is_empty() {
+        return Err("no document could be read".to_string());
+    }
+    if failed > 0 {
+        warn!(
+            "Skipped {} unreadable documents of offline spool file {}",
+            failed,
+            path.display()
+        );
+    }
+    Ok(documents)
+}
+
+/// Spools a document that failed to persist with the backend unavailable,
+/// opening the circuit, returning whether it was. A replayed document
+/// failing while the circuit is closed counts against `MAX_FAILURES`.
+pub fn fail(pending: &PendingDocument) -> bool {
+    if pending.dry_run || !enabled() {
+        return false;
+    }
+    let was_open = trip();
+    let failures = match pending.replayed {
+        Some(failures) if !was_open => failures + 1,
+        replayed => replayed.unwrap_or_default(),
+    };
+    if failures >= MAX_FAILURES {
+        throttled!(
+            Level::Warn,
+            "offline",
+            "Giving up on document {}, failing to persist {} times once replayed",
+            pending.document.id,
+            failures
+        );
+        return false;
+    }
+    // Already scanned.
+    append(&pending.document, false, failures)
+}
+
+/// Whether the circuit is open, documents being spooled without trying
+/// the backend.
+pub fn is_open() -> bool {
+    OPEN.load(Ordering::Relaxed)
+}
+
+/// Opens the circuit, the backend being found unavailable, returning
+/// whether it was open already.
+fn trip() -> bool {
+    *lock(&PROBE_AT) = Some(clock::now() + PROBE_INTERVAL);
+    let open = OPEN.swap(true, Ordering::Relaxed);
+    if !open {
+        warn!("The backend is unavailable, spooling documents until it recovers");
+    }
+    open
+}
+
+/// Whether to try a document while the circuit is open, once every
+/// `PROBE_INTERVAL`.
+pub fn probe() -> bool {
+    let mut probe_at = lock(&PROBE_AT);
+    let now = clock::now();
+    if probe_at.is_some_and(|probe_at| now < probe_at) {
+        return false;
+    }
+    *probe_at = Some(now + PROBE_INTERVAL);
+    true
+}
+
+/// Whether a probe is due, without taking it.
+pub fn probe_due() -> bool {
+    lock(&PROBE_AT).is_none_or(|probe_at| clock::now() >= probe_at)
+}
+
+/// Closes the circuit, a document having persisted.
+pub fn recovered() {
+    if OPEN.swap(false, Ordering::Relaxed) {
+        info!("The backend recovered, replaying spooled documents");
+    }
+}
+
+/// Closes the circuit quietly, for a new worker to try the backend first.
+pub fn reset() {
+    OPEN.store(false, Ordering::Relaxed);
+    *lock(&PROBE_AT) = None;
+}
+
+/// Paces the documents replayed at `offline.replay_rate`.
+pub struct Pace {
+    next: Instant,
+}
+
+impl Pace {
+    pub fn new() -> Self {
+        Pace { next: clock::now() }
+    }
+
+    /// How long to wait before replaying the next document, `None` when it
+    /// can go now, which schedules the one after.
+    pub fn wait(&mut self) -> Option<Duration> {
+        let now = clock::now();
+        if now < self.next {
+            return Some(self.next - now);
+        }
+        // From now rather than from the last, for no burst after a pause.
+        self.next = now + Duration::from_secs_f64(1.0 / config::get().offline.replay_rate);
+        None
+    }
+}
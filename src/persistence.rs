@@ -1,27 +1,369 @@
-use crate::transaction::Transaction;
-use base64::{engine::general_purpose, Engine};
-use chrono::Utc;
-use log::warn;
-use serde::Serialize;
+#[cfg(feature = "elasticsearch")]
+use crate::clock;
+#[cfg(feature = "elasticsearch")]
+use crate::config;
+use crate::document::Document;
+use crate::error::PrismError;
+#[cfg(feature = "elasticsearch")]
+use crate::logging::throttled;
+use chrono::{DateTime, Utc};
+#[cfg(feature = "elasticsearch")]
+use log::Level;
+use log::{debug, warn};
+#[cfg(feature = "elasticsearch")]
+use log::{error, info};
+#[cfg(feature = "elasticsearch")]
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use std::collections::BTreeMap;
 use std::result::Result;
+#[cfg(feature = "elasticsearch")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "elasticsearch")]
+use std::sync::{Mutex, OnceLock};
+#[cfg(feature = "elasticsearch")]
+use std::time::{Duration, Instant};
 
-pub trait Backend {
-    fn persist(&self, transaction: &Transaction) -> Result<(), ()>;
+/// A persistence backend. Instances are long lived and shared by all the
+/// persistence workers, see `worker::backend()`, so `persist()` may run on
+/// several threads at once. Backends that must persist one document at a
+/// time serialize internally, as `HarFile` does.
+pub trait Backend: Send + Sync {
+    /// Name the backend is reported as in metrics.
+    fn name(&self) -> &'static str;
+    /// Where documents end up, such as an index name, for the audit log.
+    fn destination(&self) -> String;
+    /// Where `document` ends up, for backends following its routing, see
+    /// `routing`.
+    fn document_destination(&self, _document: &Document) -> String {
+        self.destination()
+    }
+    /// Identifier a document is stored under. Persisting a document again
+    /// must replace what was stored under its id.
+    fn document_id(&self, document: &Document) -> String {
+        document.document_id.clone()
+    }
+    fn persist(&self, document: &Document) -> Result<(), PrismError>;
+    /// Whether documents can be persisted yet. Until then, the worker holds
+    /// them back, see `worker::run()`.
+    fn ready(&self) -> bool {
+        true
+    }
+    /// Removes the documents dated before `cutoff`, asking the backend for
+    /// at most `batch_size` at once, and returns how many went. Called by
+    /// the retention sweeps only, see `retention`. Backends without
+    /// retention keep everything.
+    fn prune(&self, _cutoff: DateTime<Utc>, _batch_size: usize) -> Result<usize, PrismError> {
+        Ok(0)
+    }
+    /// Creates storage shaped as the current mapping next to the one in
+    /// use, and logs how to move the documents over, returning what was
+    /// created. Called by `Prism::migrate_mapping()` only. Backends without
+    /// a mapping have nothing to migrate.
+    fn migrate_mapping(&self) -> Result<Vec<String>, PrismError> {
+        Ok(Vec::new())
+    }
+    /// Starts persisting `document` without blocking the worker, for
+    /// backends able to. `None` sends the document through `persist()`.
+    #[cfg(feature = "async-persistence")]
+    fn persist_async(&self, _document: &Document) -> Option<PersistFuture> {
+        None
+    }
+}
+
+/// A persist running on the worker's runtime, see `dispatch`.
+#[cfg(feature = "async-persistence")]
+pub type PersistFuture =
+    std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), PrismError>> + Send>>;
+
+/// Index mapping of documents, as installed by the Elasticsearch backend.
+const MAPPING: &str = r#"
+{
+    "mappings": {
+        "properties": {
+            "document_id": {"type": "keyword"},
+            "run_id": {"type": "long"},
+            "schema_version": {"type": "integer"},
+            "method": {"type": "keyword"},
+            "uri": {
+                "type": "text",
+                "analyzer": "simple",
+                "fields": {"keyword": {"type": "keyword", "ignore_above": 2048}}
+            },
+            "path": {"type": "keyword", "ignore_above": 2048},
+            "encoding": {"type": "keyword"},
+            "body": {"type": "text"},
+            "body_preview": {"type": "text"},
+            "raw_body": { "type": "binary", "store": true },
+            "has_body": {"type": "boolean"},
+            "date": {"type": "date"},
+            "truncated": {"type": "boolean"},
+            "original_length": {"type": "long"},
+            "emitted_length": {"type": "long"},
+            "compression_ratio": {"type": "float"},
+            "bytes_saved": {"type": "long"},
+            "output_compression_ratio": {"type": "float"},
+            "output_bytes_saved": {"type": "long"},
+            "blocked": {"type": "boolean"},
+            "block_reason": {"type": "keyword"},
+            "slow": {"type": "boolean"},
+            "client_stalled": {"type": "boolean"},
+            "timed_out": {"type": "boolean"},
+            "status": {"type": "integer"},
+            "host": {"type": "keyword"},
+            "query_params": {"type": "flattened"},
+            "query_params_overflow": {"type": "boolean"},
+            "is_redirect": {"type": "boolean"},
+            "redirect_location": {"type": "keyword"},
+            "etag": {"type": "keyword"},
+            "last_modified": {"type": "date"},
+            "last_modified_raw": {"type": "keyword"},
+            "cache_control": {"type": "keyword"},
+            "cache_control_raw": {"type": "keyword"},
+            "age": {"type": "integer"},
+            "age_raw": {"type": "keyword"},
+            "client_ip": {"type": "ip"},
+            "client_country": {"type": "keyword"},
+            "client_asn": {"type": "long"},
+            "client_as_org": {"type": "keyword"},
+            "referer": {"type": "keyword"},
+            "referer_host": {"type": "keyword"},
+            "fetch_kind": {"type": "keyword"},
+            "user_agent": {"type": "keyword"},
+            "ua_browser": {"type": "keyword"},
+            "ua_browser_version": {"type": "keyword"},
+            "ua_os": {"type": "keyword"},
+            "ua_device_type": {"type": "keyword"},
+            "annotations": {"type": "flattened"},
+            "tags": {"type": "keyword"},
+            "routed_index": {"type": "keyword"},
+            "request_headers": {"type": "flattened"},
+            "response_headers": {"type": "flattened"},
+            "scanner_result": {
+                "properties": {
+                    "verdict": {"type": "keyword"},
+                    "detail": {"type": "text"},
+                    "exit_code": {"type": "integer"}
+                }
+            }
+        }
+    }
+}
+"#;
+
+/// Names of the top level fields of documents, the ones field names can be
+/// configured for.
+pub fn document_fields() -> Vec<String> {
+    let mapping: serde_json::Value = serde_json::from_str(MAPPING).unwrap_or_default();
+    match mapping
+        .pointer("/mappings/properties")
+        .and_then(|p| p.as_object())
+    {
+        Some(properties) => properties.keys().cloned().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Longest keyword indexed from the values of `flattened` fields mapped as
+/// objects: Lucene indexes terms of up to 32766 bytes, at most 4 bytes a
+/// character.
+#[cfg(feature = "elasticsearch")]
+const OBJECT_IGNORE_ABOVE: u64 = 8191;
+
+/// Kind of cluster the Elasticsearch backend talks to, as told by the
+/// version it answers with, which decides the field types of the mapping.
+#[cfg(feature = "elasticsearch")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Flavor {
+    /// Elasticsearch 7.3 and later.
+    Elasticsearch,
+    /// Elasticsearch before 7.3, without the `flattened` type.
+    LegacyElasticsearch,
+    /// OpenSearch, without the `flattened` type either.
+    OpenSearch,
+}
+
+#[cfg(feature = "elasticsearch")]
+impl Flavor {
+    /// The flavor of the cluster answering `root` to a `GET /`. Answers
+    /// without a version are taken for a current Elasticsearch.
+    fn of(root: &serde_json::Value) -> Flavor {
+        let version = |field: &str| {
+            root.pointer(&format!("/version/{}", field))
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+        };
+        if version("distribution").eq_ignore_ascii_case("opensearch") {
+            return Flavor::OpenSearch;
+        }
+        let mut number = version("number")
+            .split('.')
+            .map(|part| part.parse::<u32>().ok());
+        match (number.next().flatten(), number.next().flatten()) {
+            (Some(major), Some(minor)) if (major, minor) < (7, 3) => Flavor::LegacyElasticsearch,
+            _ => Flavor::Elasticsearch,
+        }
+    }
+
+    fn has_flattened(self) -> bool {
+        self == Flavor::Elasticsearch
+    }
+}
+
+/// The index mapping of documents of schema `version`, with fields renamed
+/// as in `fields`, for a cluster of `flavor`. The version is recorded in
+/// the `_meta` of the mapping.
+#[cfg(feature = "elasticsearch")]
+fn mapping(fields: &BTreeMap<String, String>, version: u32, flavor: Flavor) -> String {
+    let mut mapping: serde_json::Value = serde_json::from_str(MAPPING).unwrap();
+    let mut templates = Vec::new();
+    if let Some(properties) = mapping
+        .pointer_mut("/mappings/properties")
+        .and_then(|properties| properties.as_object_mut())
+    {
+        crate::document::migrate(properties, version);
+        crate::document::rename_fields(properties, fields);
+        if !flavor.has_flattened() {
+            templates = unflattened(properties);
+        }
+    }
+    if let Some(mappings) = mapping
+        .get_mut("mappings")
+        .and_then(|mappings| mappings.as_object_mut())
+    {
+        mappings.insert(
+            "_meta".to_string(),
+            serde_json::json!({ "schema_version": version }),
+        );
+        if !templates.is_empty() {
+            mappings.insert(
+                "dynamic_templates".to_string(),
+                serde_json::Value::Array(templates),
+            );
+        }
+    }
+    mapping.to_string()
 }
 
-#[derive(Serialize)]
-struct Document {
-    method: String,
-    uri: String,
-    body: String,
-    raw_body: String,
-    encoding: String,
-    date: String,
+/// Maps the `flattened` fields of `properties` as objects instead, for
+/// clusters without the type, returning the dynamic templates indexing
+/// the values under them as keywords, exact matches as with `flattened`.
+#[cfg(feature = "elasticsearch")]
+fn unflattened(
+    properties: &mut serde_json::Map<String, serde_json::Value>,
+) -> Vec<serde_json::Value> {
+    let mut templates = Vec::new();
+    for (name, field) in properties.iter_mut() {
+        if field.get("type").and_then(|kind| kind.as_str()) != Some("flattened") {
+            continue;
+        }
+        *field = serde_json::json!({ "type": "object", "dynamic": true });
+        let path_match = format!("{}.*", name);
+        let mut template = serde_json::Map::new();
+        template.insert(
+            name.clone(),
+            serde_json::json!({
+                "path_match": path_match,
+                "match_mapping_type": "string",
+                "mapping": { "type": "keyword", "ignore_above": OBJECT_IGNORE_ABOVE },
+            }),
+        );
+        templates.push(serde_json::Value::Object(template));
+    }
+    templates
 }
 
-static mut ELASTICSEARCH_INITIALIZED: bool = false;
+/// Name of the index `index` is migrated to by `migrate_mapping()`: its
+/// name, without the version suffix of an earlier migration, suffixed with
+/// schema `version`.
+#[cfg(feature = "elasticsearch")]
+fn migrated_index(index: &str, version: u32) -> String {
+    let base = match index.rsplit_once("-v") {
+        Some((base, suffix))
+            if !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            base
+        }
+        _ => index,
+    };
+    format!("{}-v{}", base, version)
+}
+
+/// Schema version recorded in the mapping of an existing index, as
+/// returned by a `GET` of the index.
+#[cfg(feature = "elasticsearch")]
+fn recorded_schema_version(index: &serde_json::Value) -> Option<u64> {
+    index
+        .as_object()?
+        .values()
+        .next()?
+        .pointer("/mappings/_meta/schema_version")?
+        .as_u64()
+}
+
+/// Stand-in backend of dry runs, which only checks that documents
+/// serialize.
+pub struct DryRun {
+    /// Field names configured for the deployment.
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Backend for DryRun {
+    fn name(&self) -> &'static str {
+        "dry_run"
+    }
+
+    fn destination(&self) -> String {
+        "none".to_string()
+    }
+
+    fn persist(&self, document: &Document) -> Result<(), PrismError> {
+        match document.to_json(&self.fields) {
+            Ok(json) => {
+                debug!(
+                    "Dry run, not persisting document {} ({} {}, status {}, {} bytes)",
+                    document.id,
+                    document.method,
+                    document.uri,
+                    document
+                        .status
+                        .map_or("none".to_string(), |status| status.to_string()),
+                    json.len()
+                );
+                Ok(())
+            }
+            Err(e) => {
+                warn!(
+                    "Dry run, document {} failed to serialize: {}",
+                    document.id, e
+                );
+                Err(PrismError::Encode)
+            }
+        }
+    }
+}
+
+/// How long an Elasticsearch backend that failed initializing first waits
+/// before trying again. The wait doubles with every failure, up to
+/// `INITIALIZE_MAX_BACKOFF`.
+#[cfg(feature = "elasticsearch")]
+const INITIALIZE_BACKOFF: Duration = Duration::from_secs(1);
+#[cfg(feature = "elasticsearch")]
+const INITIALIZE_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Pause between the delete-by-query requests of a retention sweep, so
+/// deleting a backlog does not load the cluster as a burst would.
+#[cfg(feature = "elasticsearch")]
+const PRUNE_BATCH_PAUSE: Duration = Duration::from_secs(1);
+
+/// When to next try initializing the index.
+#[cfg(feature = "elasticsearch")]
+struct Retry {
+    at: Instant,
+    backoff: Duration,
+}
 
 /// Elasticsearch persistence backend.
+#[cfg(feature = "elasticsearch")]
 pub struct Elasticsearch {
     /// Hostname of the ES instance.
     hostname: String,
@@ -31,19 +373,64 @@ pub struct Elasticsearch {
     protocol: String,
     /// Index name to use as storage
     index: String,
-    /// Client used to communicate with ES.
+    /// Indices documents are routed to instead, see `routing`.
+    routes: Vec<String>,
+    /// Names documents fields are stored under, when not their own.
+    fields: BTreeMap<String, String>,
+    /// Schema version of the documents persisted.
+    schema_version: u32,
+    /// Client used to communicate with ES, pooling its connections.
     client: reqwest::blocking::Client,
-    /// An integer to differentiate newly persisted items in this run
-    /// from previously persisted ones, as the id counter for transactions
-    /// is reset between executions.
-    generation: u128,
+    /// Client of the asynchronous persists, set up like `client`.
+    #[cfg(feature = "async-persistence")]
+    async_client: reqwest::Client,
+    /// Whether the indices exist, with their mapping.
+    initialized: AtomicBool,
+    retry: Mutex<Retry>,
+    /// Flavor of the cluster, once it answered the version probe.
+    flavor: OnceLock<Flavor>,
 }
 
+#[cfg(feature = "elasticsearch")]
 impl Elasticsearch {
-    pub fn new(hostname: String, port: i64, protocol: String, index: String) -> Self {
-        let generation = std::time::UNIX_EPOCH.elapsed().unwrap().as_millis();
+    pub fn new(
+        hostname: String,
+        port: i64,
+        protocol: String,
+        index: String,
+        fields: BTreeMap<String, String>,
+        schema_version: u32,
+        api_key: Option<String>,
+    ) -> Self {
+        // Routed to by the documents, as configured when they were built.
+        let mut routes: Vec<String> = config::get()
+            .backend
+            .routes
+            .iter()
+            .map(|route| route.index.clone())
+            .filter(|route| *route != index)
+            .collect();
+        routes.sort();
+        routes.dedup();
+        let mut headers = HeaderMap::new();
+        if let Some(api_key) = api_key {
+            match HeaderValue::from_str(&format!("ApiKey {}", api_key)) {
+                Ok(mut value) => {
+                    value.set_sensitive(true);
+                    headers.insert(AUTHORIZATION, value);
+                }
+                Err(e) => warn!("Ignoring invalid Elasticsearch API key: {}", e),
+            }
+        }
+        #[cfg(feature = "async-persistence")]
+        let async_client = reqwest::ClientBuilder::new()
+            .danger_accept_invalid_certs(true)
+            .default_headers(headers.clone())
+            .build()
+            .unwrap();
         let client = reqwest::blocking::ClientBuilder::new()
             .danger_accept_invalid_certs(true)
+            .default_headers(headers)
             .build()
             .unwrap();
         let backend = Elasticsearch {
@@ -51,49 +438,117 @@ impl Elasticsearch {
             port,
             protocol,
             index,
+            routes,
+            fields,
+            schema_version,
             client,
-            generation,
+            #[cfg(feature = "async-persistence")]
+            async_client,
+            initialized: AtomicBool::new(false),
+            retry: Mutex::new(Retry {
+                at: clock::now(),
+                backoff: INITIALIZE_BACKOFF,
+            }),
+            flavor: OnceLock::new(),
         };
+        backend.ready();
+        backend
+    }
+
+    /// The default index, then the ones documents are routed to.
+    fn indices(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.index).chain(&self.routes)
+    }
 
-        if unsafe { ELASTICSEARCH_INITIALIZED } {
-            backend
-        } else {
-            backend.initialize()
+    fn endpoint(&self, path: &str) -> String {
+        format!(
+            "{}://{}:{}/{}",
+            self.protocol, self.hostname, self.port, path
+        )
+    }
+
+    /// Flavor of the cluster, probed the first time an index is created.
+    /// Until the cluster answers, it is taken for a current Elasticsearch.
+    fn flavor(&self) -> Flavor {
+        if let Some(flavor) = self.flavor.get() {
+            return *flavor;
+        }
+        match self.client.get(self.endpoint("")).send() {
+            Ok(response) if response.status() == reqwest::StatusCode::OK => {
+                let root = response.text().unwrap_or_default();
+                let flavor = Flavor::of(&serde_json::from_str(&root).unwrap_or_default());
+                info!("Elasticsearch cluster probed as {:?}", flavor);
+                *self.flavor.get_or_init(|| flavor)
+            }
+            Ok(response) => {
+                debug!(
+                    "Elasticsearch version probe answered with http {}, assuming a current Elasticsearch",
+                    response.status()
+                );
+                Flavor::Elasticsearch
+            }
+            Err(e) => {
+                debug!(
+                    "Failed probing the Elasticsearch version, assuming a current Elasticsearch: {}",
+                    e
+                );
+                Flavor::Elasticsearch
+            }
         }
     }
 
-    fn check_initialized(&self, endpoint: &str) -> bool {
+    fn check_initialized(&self, index: &str, endpoint: &str) -> bool {
         match self.client.get(endpoint).send() {
-            Ok(response) => response.status() == reqwest::StatusCode::OK,
-            Err(_) => false,
+            Ok(response) if response.status() == reqwest::StatusCode::OK => {
+                let found = response.text().unwrap_or_default();
+                self.check_schema_version(index, &serde_json::from_str(&found).unwrap_or_default());
+                true
+            }
+            _ => false,
         }
     }
 
-    fn initialize(self) -> Self {
-        let endpoint = format!(
-            "{}://{}:{}/{}",
-            self.protocol, self.hostname, self.port, self.index
-        );
+    /// Warns when the existing index holds documents of a newer schema than
+    /// the ones persisted, which the fields it was migrated to or renamed
+    /// would be missing from.
+    fn check_schema_version(&self, name: &str, index: &serde_json::Value) {
+        match recorded_schema_version(index) {
+            Some(recorded) if recorded > u64::from(self.schema_version) => error!(
+                "Elasticsearch index {} holds documents of schema version {}, newer than \
+                 version {} persisted here: documents will miss the fields added since, \
+                 upgrade prism or point it to another index",
+                name, recorded, self.schema_version
+            ),
+            Some(recorded) if recorded < u64::from(self.schema_version) => info!(
+                "Elasticsearch index {} was created for schema version {}, its mapping \
+                 lacks what changed up to version {}: migrate_mapping() creates an index \
+                 with the current mapping",
+                name, recorded, self.schema_version
+            ),
+            Some(_) => {}
+            None => debug!(
+                "Elasticsearch index {} records no schema version, assuming version 1",
+                name
+            ),
+        }
+    }
 
-        if self.check_initialized(&endpoint) {
-            unsafe { ELASTICSEARCH_INITIALIZED = true };
-            return self;
+    /// Creates the indices missing, each with the mapping of documents.
+    /// Initialized once all of them exist.
+    fn initialize(&self) {
+        if self.indices().all(|index| self.initialize_index(index)) {
+            self.initialized.store(true, Ordering::Relaxed);
         }
+    }
 
-        let mapping = r#"
-        {
-            "mappings": {
-                "properties": {
-                    "method": {"type": "keyword"},
-                    "uri": {"type": "text", "analyzer": "simple"},
-                    "encoding": {"type": "keyword"},
-                    "body": {"type": "text"},
-                    "raw_body": { "type": "binary", "store": true },
-                    "date": {"type": "date"}
-                }
-            }
+    fn initialize_index(&self, index: &str) -> bool {
+        let endpoint = self.endpoint(index);
+
+        if self.check_initialized(index, &endpoint) {
+            return true;
         }
-        "#;
+
+        let mapping = mapping(&self.fields, self.schema_version, self.flavor());
         match self
             .client
             .put(&endpoint)
@@ -104,58 +559,178 @@ impl Elasticsearch {
             Ok(response) => {
                 let status = response.status();
                 if status != reqwest::StatusCode::OK {
-                    warn!(
+                    throttled!(
+                        Level::Warn,
+                        "elasticsearch-initialize",
                         "Failed initializing elasticsearch backend, calls to persist transaction will fail (http {}) : {}",
                         status, response.text().unwrap()
                     );
+                    false
                 } else {
-                    unsafe { ELASTICSEARCH_INITIALIZED = true };
+                    true
                 }
             }
             Err(err) => {
-                warn!("Failed initializing elasticsearch backend, calls to persist transaction will fail: {}", err);
+                throttled!(
+                    Level::Warn,
+                    "elasticsearch-initialize",
+                    "Failed initializing elasticsearch backend, calls to persist transaction will fail: {}",
+                    err
+                );
+                false
             }
         }
+    }
+}
 
-        self
+#[cfg(feature = "elasticsearch")]
+impl Backend for Elasticsearch {
+    fn name(&self) -> &'static str {
+        "elasticsearch"
     }
 
-    fn date(&self) -> String {
-        Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+    fn destination(&self) -> String {
+        self.index.clone()
     }
 
-    fn raw_body(&self, body: &Vec<u8>) -> String {
-        general_purpose::STANDARD.encode(body)
+    fn document_destination(&self, document: &Document) -> String {
+        document
+            .routed_index
+            .clone()
+            .unwrap_or_else(|| self.destination())
     }
-}
 
-impl Backend for Elasticsearch {
-    fn persist(&self, transaction: &Transaction) -> Result<(), ()> {
-        if !unsafe { ELASTICSEARCH_INITIALIZED } {
-            return Err(());
+    /// Whether the indices are initialized, trying again when the backoff
+    /// since the last failure elapsed. Only ever called from the worker.
+    fn ready(&self) -> bool {
+        if self.initialized.load(Ordering::Relaxed) {
+            return true;
         }
-
-        let decoded_body = match String::from_utf8(transaction.body()) {
-            Ok(body) => body,
-            Err(_) => "".to_string(),
-        };
-        let document = Document {
-            method: transaction.method.clone(),
-            uri: transaction.uri.clone(),
-            raw_body: self.raw_body(&transaction.body()),
-            body: decoded_body,
-            encoding: match &transaction.encoding {
-                Some(encoding) => encoding.to_string(),
-                None => "".to_string(),
-            },
-            date: self.date(),
+        let mut retry = match self.retry.lock() {
+            Ok(retry) => retry,
+            Err(poisoned) => poisoned.into_inner(),
         };
-        let json = serde_json::to_string(&document).unwrap();
-        let id = format!("{}-{}", self.generation, transaction.id);
-        let endpoint = format!(
-            "{}://{}:{}/{}/_doc/{}",
-            self.protocol, self.hostname, self.port, self.index, id
+        if clock::now() < retry.at {
+            return false;
+        }
+        self.initialize();
+        if self.initialized.load(Ordering::Relaxed) {
+            info!("Elasticsearch index {} is ready", self.index);
+            if !self.routes.is_empty() {
+                info!(
+                    "Elasticsearch indices {} of routed documents are ready",
+                    self.routes.join(", ")
+                );
+            }
+            return true;
+        }
+        retry.at = clock::now() + retry.backoff;
+        debug!(
+            "Initializing the elasticsearch index again in {}s",
+            retry.backoff.as_secs()
+        );
+        retry.backoff = (retry.backoff * 2).min(INITIALIZE_MAX_BACKOFF);
+        false
+    }
+
+    /// Creates for each index an index named after the schema version, see
+    /// `migrated_index()`, with the current mapping, and logs how to
+    /// reindex the documents into it. Indices that exist already are left
+    /// as they are. Documents go on to the indices in use until the
+    /// configuration points to the new ones.
+    fn migrate_mapping(&self) -> Result<Vec<String>, PrismError> {
+        let mapping = mapping(&self.fields, self.schema_version, self.flavor());
+        let mut created = Vec::new();
+        for (position, index) in self.indices().enumerate() {
+            let target = migrated_index(index, self.schema_version);
+            if target == *index {
+                info!(
+                    "Elasticsearch index {} is named for schema version {} already, not migrating it",
+                    index, self.schema_version
+                );
+                continue;
+            }
+            let endpoint = self.endpoint(&target);
+            let exists = match self.client.get(&endpoint).send() {
+                Ok(response) => response.status() == reqwest::StatusCode::OK,
+                Err(e) => {
+                    error!("Failed migrating Elasticsearch index {}: {}", index, e);
+                    return Err(PrismError::BackendUnavailable);
+                }
+            };
+            if exists {
+                info!("Elasticsearch index {} exists already", target);
+            } else {
+                let response = self
+                    .client
+                    .put(&endpoint)
+                    .header("Content-Type", "application/json")
+                    .body(mapping.clone())
+                    .send()
+                    .map_err(|e| {
+                        error!("Failed creating Elasticsearch index {}: {}", target, e);
+                        PrismError::BackendUnavailable
+                    })?;
+                let status = response.status();
+                if status != reqwest::StatusCode::OK {
+                    error!(
+                        "Failed creating Elasticsearch index {} (http {}): {}",
+                        target,
+                        status,
+                        response.text().unwrap_or_default()
+                    );
+                    return Err(PrismError::BackendUnavailable);
+                }
+                info!(
+                    "Created Elasticsearch index {} with the mapping of schema version {}",
+                    target, self.schema_version
+                );
+                created.push(target.clone());
+            }
+            let setting = if position == 0 {
+                "backend.elasticsearch.index".to_string()
+            } else {
+                format!("the index of the routes to {}", index)
+            };
+            info!(
+                "To move the documents of {} over, run POST /_reindex with \
+                 {{\"source\": {{\"index\": \"{}\"}}, \"dest\": {{\"index\": \"{}\"}}}}, \
+                 then set {} to {}",
+                index, index, target, setting, target
+            );
+        }
+        Ok(created)
+    }
+
+    /// Deletes by query the documents whose date is before `cutoff`, from
+    /// the default index and the routed ones, in batches, until a batch
+    /// comes back short.
+    fn prune(&self, cutoff: DateTime<Utc>, batch_size: usize) -> Result<usize, PrismError> {
+        if !self.ready() {
+            return Err(PrismError::BackendUnavailable);
+        }
+
+        let date = self.fields.get("date").map_or("date", String::as_str);
+        let mut range = serde_json::Map::new();
+        range.insert(
+            date.to_string(),
+            serde_json::json!({ "lt": cutoff.format("%Y-%m-%dT%H:%M:%SZ").to_string() }),
         );
+        let query = serde_json::json!({ "query": { "range": range } }).to_string();
+
+        let mut deleted = 0;
+        for index in self.indices() {
+            deleted += self.prune_index(index, &query, batch_size)?;
+        }
+        Ok(deleted)
+    }
+
+    fn persist(&self, document: &Document) -> Result<(), PrismError> {
+        if !self.ready() {
+            return Err(PrismError::BackendUnavailable);
+        }
+
+        let (id, endpoint, json) = self.request(document);
         match self
             .client
             .put(endpoint)
@@ -165,27 +740,144 @@ impl Backend for Elasticsearch {
         {
             Ok(response) => {
                 let status = response.status();
-                let request_ok =
-                    [reqwest::StatusCode::OK, reqwest::StatusCode::CREATED].contains(&status);
-                if !request_ok {
-                    warn!(
-                        "Failed persisting transaction for transaction no. {} (http status {}): {}",
-                        id,
-                        status,
-                        response.text().unwrap()
-                    );
-                    Err(())
-                } else {
+                if persisted(status) {
                     Ok(())
+                } else {
+                    rejected(&id, status, response.text().unwrap_or_default())
                 }
             }
-            Err(err) => {
-                warn!(
-                    "Failed persisting transaction for transaction no. {} (error: {})",
-                    id, err
-                );
-                Err(())
+            Err(err) => unreachable(&id, err),
+        }
+    }
+
+    #[cfg(feature = "async-persistence")]
+    fn persist_async(&self, document: &Document) -> Option<PersistFuture> {
+        if !self.ready() {
+            return Some(Box::pin(async { Err(PrismError::BackendUnavailable) }));
+        }
+
+        let (id, endpoint, json) = self.request(document);
+        let request = self
+            .async_client
+            .put(endpoint)
+            .header("Content-Type", "application/json")
+            .body(json);
+        Some(Box::pin(async move {
+            match request.send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    if persisted(status) {
+                        Ok(())
+                    } else {
+                        rejected(&id, status, response.text().await.unwrap_or_default())
+                    }
+                }
+                Err(err) => unreachable(&id, err),
             }
+        }))
+    }
+}
+
+#[cfg(feature = "elasticsearch")]
+impl Elasticsearch {
+    /// Deletes the documents of `index` matching `query`, in batches.
+    fn prune_index(
+        &self,
+        index: &str,
+        query: &str,
+        batch_size: usize,
+    ) -> Result<usize, PrismError> {
+        let endpoint = format!(
+            "{}://{}:{}/{}/_delete_by_query?max_docs={}&scroll_size={}&conflicts=proceed",
+            self.protocol, self.hostname, self.port, index, batch_size, batch_size
+        );
+
+        let mut deleted = 0;
+        loop {
+            let batch = match self
+                .client
+                .post(&endpoint)
+                .header("Content-Type", "application/json")
+                .body(query.to_string())
+                .send()
+            {
+                Ok(response) if response.status().is_success() => {
+                    let body = response.text().unwrap_or_default();
+                    serde_json::from_str::<serde_json::Value>(&body)
+                        .ok()
+                        .and_then(|body| body["deleted"].as_u64())
+                        .unwrap_or_default() as usize
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    throttled!(
+                        Level::Warn,
+                        "elasticsearch-prune",
+                        "Failed deleting old documents from index {} (http {}): {}",
+                        index,
+                        status,
+                        response.text().unwrap_or_default()
+                    );
+                    return Err(PrismError::BackendUnavailable);
+                }
+                Err(err) => {
+                    throttled!(
+                        Level::Warn,
+                        "elasticsearch-prune",
+                        "Failed deleting old documents from index {}: {}",
+                        index,
+                        err
+                    );
+                    return Err(PrismError::BackendUnavailable);
+                }
+            };
+            deleted += batch;
+            if batch < batch_size {
+                return Ok(deleted);
+            }
+            std::thread::sleep(PRUNE_BATCH_PAUSE);
         }
     }
+
+    /// Id, endpoint and body of the request persisting `document`.
+    fn request(&self, document: &Document) -> (String, String, Vec<u8>) {
+        let json = document.to_json(&self.fields).unwrap();
+        let id = self.document_id(document);
+        let index = document.routed_index.as_ref().unwrap_or(&self.index);
+        let endpoint = format!(
+            "{}://{}:{}/{}/_doc/{}",
+            self.protocol, self.hostname, self.port, index, id
+        );
+        (id, endpoint, json)
+    }
+}
+
+#[cfg(feature = "elasticsearch")]
+fn persisted(status: reqwest::StatusCode) -> bool {
+    [reqwest::StatusCode::OK, reqwest::StatusCode::CREATED].contains(&status)
+}
+
+#[cfg(feature = "elasticsearch")]
+fn rejected(id: &str, status: reqwest::StatusCode, body: String) -> Result<(), PrismError> {
+    throttled!(
+        Level::Warn,
+        "elasticsearch-persist",
+        "Failed persisting transaction for transaction no. {} (http status {}): {}",
+        id,
+        status,
+        body
+    );
+    Err(PrismError::BackendUnavailable)
+}
+
+#[cfg(feature = "elasticsearch")]
+fn unreachable(id: &str, err: reqwest::Error) -> Result<(), PrismError> {
+    throttled!(
+        Level::Warn,
+        "elasticsearch-persist",
+        "Failed persisting transaction for transaction no. {} (error: {})",
+        id,
+        err
+    );
+    Err(PrismError::BackendUnavailable)
 }
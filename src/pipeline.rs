@@ -0,0 +1,689 @@
+use crate::clock;
+use crate::config::Limits;
+#[cfg(feature = "fault-injection")]
+use crate::faults;
+use crate::logging::trace_transaction;
+use crate::pool;
+use crate::rewrite::RewriteChain;
+use crate::spool::Spool;
+use log::warn;
+use serde::Serialize;
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use zstream::{Decoder, Encoder};
+
+/// A zstream decoder or encoder. They are not `Send`, as they hold their
+/// zlib stream through raw pointers, but each belongs to a single pipeline,
+/// is never shared and only reads from the pipeline's own `Send` readers.
+pub struct Codec<T>(T);
+
+// SAFETY: see `Codec`, the zlib stream is only ever used by one thread at a
+// time, the one owning the transaction.
+unsafe impl Send for Codec<Decoder> {}
+// SAFETY: as above.
+unsafe impl Send for Codec<Encoder> {}
+
+impl<T> Deref for Codec<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Codec<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// Locks one of the pipeline's mutexes. They are only ever taken by the
+/// thread driving the transaction, so they are uncontended and a panic
+/// while holding one leaves nothing half updated worth refusing.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+struct BufferReader {
+    receiver: Receiver<Vec<u8>>,
+    /// Data taken off the channel but not read yet.
+    pending: VecDeque<u8>,
+    queued: Arc<AtomicUsize>,
+    queued_bytes: Arc<AtomicUsize>,
+    /// Mirrors `pending.len()` for the pipeline's memory accounting.
+    pending_bytes: Arc<AtomicUsize>,
+    #[cfg(feature = "fault-injection")]
+    faults: faults::Input,
+}
+
+impl Read for BufferReader {
+    /// Takes queued chunks off the channel until `buf` can be filled, or
+    /// none are left, then fills it with as much as is pending.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pending.len() < buf.len() {
+            match self.receiver.try_recv() {
+                Ok(data) => {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                    self.queued_bytes.fetch_sub(data.len(), Ordering::Relaxed);
+                    #[cfg(feature = "fault-injection")]
+                    let data = self.faults.apply(data);
+                    self.pending.extend(data);
+                }
+                Err(_) => break,
+            }
+        }
+
+        let to_transfer = min(buf.len(), self.pending.len());
+        let (front, back) = self.pending.as_slices();
+        let from_front = min(to_transfer, front.len());
+        buf[0..from_front].copy_from_slice(&front[0..from_front]);
+        buf[from_front..to_transfer].copy_from_slice(&back[0..to_transfer - from_front]);
+        self.pending.drain(0..to_transfer);
+        let pending = self.pending.len();
+        self.pending_bytes.store(pending, Ordering::Relaxed);
+
+        Ok(to_transfer)
+    }
+}
+
+pub struct RawDataReader {
+    id: i64,
+    /// Whether detailed trace lines are logged, see `Pipeline::set_trace`.
+    trace: AtomicBool,
+    /// Unset for pass-through bodies, which are never decoded.
+    pub reader: Mutex<Option<Codec<Decoder>>>,
+    inner_buffer: Mutex<Spool>,
+    rewriters: Mutex<RewriteChain>,
+    /// Rewritten data not yet handed to the encoder.
+    rewritten: Mutex<Vec<u8>>,
+    /// Set once no more input will arrive, so rewriters can be flushed.
+    eof: AtomicBool,
+    /// Set when the decoder failed, as opposed to the encoder reading from it.
+    decode_failed: AtomicBool,
+    /// Data decoded ahead of `read()` by a decode worker, see
+    /// `decode_ahead()`. Only appended to with the decoder locked.
+    decoded: Mutex<Vec<u8>>,
+    /// Error a decode worker ran into, returned by the next `read()`.
+    decode_error: Mutex<Option<std::io::Error>>,
+    /// Size of the reads decode workers make.
+    input_buffer_size: usize,
+}
+
+impl RawDataReader {
+    pub fn new(
+        id: i64,
+        reader: Option<Codec<Decoder>>,
+        rewriters: RewriteChain,
+        limits: &Limits,
+    ) -> Self {
+        RawDataReader {
+            id,
+            trace: AtomicBool::new(false),
+            reader: Mutex::new(reader),
+            inner_buffer: Mutex::new(Spool::new(id, limits)),
+            rewriters: Mutex::new(rewriters),
+            rewritten: Mutex::new(Vec::new()),
+            eof: AtomicBool::new(false),
+            decode_failed: AtomicBool::new(false),
+            decoded: Mutex::new(Vec::new()),
+            decode_error: Mutex::new(None),
+            input_buffer_size: limits.input_buffer_size,
+        }
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Nested in the encode span of the transaction, when encoding.
+        let _decode = tracing::debug_span!("decode").entered();
+        if lock(&self.rewriters).is_empty() {
+            let bytes = self.decode(buf)?;
+            trace_transaction!(
+                self.trace.load(Ordering::Relaxed),
+                self.id,
+                "decoder returned {} of {} requested bytes",
+                bytes,
+                buf.len()
+            );
+            lock(&self.inner_buffer).write(&buf[0..bytes]);
+            return Ok(bytes);
+        }
+
+        let mut rewritten = lock(&self.rewritten);
+        if rewritten.is_empty() {
+            // Decoded straight into the rewrite buffer, whose allocation is
+            // kept from one read to the next.
+            rewritten.resize(buf.len(), 0);
+            let bytes = match self.decode(&mut rewritten) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    rewritten.clear();
+                    return Err(e);
+                }
+            };
+            rewritten.truncate(bytes);
+            lock(&self.inner_buffer).write(&rewritten);
+            self.rewrite(
+                &mut rewritten,
+                bytes == 0 && self.eof.load(Ordering::Relaxed),
+            );
+            trace_transaction!(
+                self.trace.load(Ordering::Relaxed),
+                self.id,
+                "decoder returned {} of {} requested bytes, {} after rewriting",
+                bytes,
+                buf.len(),
+                rewritten.len()
+            );
+        }
+
+        let to_transfer = min(buf.len(), rewritten.len());
+        buf[0..to_transfer].copy_from_slice(&rewritten[0..to_transfer]);
+        rewritten.drain(0..to_transfer);
+        Ok(to_transfer)
+    }
+
+    /// Hands out data decoded ahead first, then decodes what is left. The
+    /// decoder lock waits for a decode worker busy with this transaction, so
+    /// data always comes out in order.
+    fn decode(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut reader = lock(&self.reader);
+        {
+            let mut decoded = lock(&self.decoded);
+            if !decoded.is_empty() {
+                let to_transfer = min(buf.len(), decoded.len());
+                buf[0..to_transfer].copy_from_slice(&decoded[0..to_transfer]);
+                decoded.drain(0..to_transfer);
+                // Decoding ahead of a burst leaves a large buffer behind.
+                if decoded.is_empty() && decoded.capacity() > 4 * self.input_buffer_size {
+                    decoded.shrink_to(self.input_buffer_size);
+                }
+                return Ok(to_transfer);
+            }
+        }
+        if let Some(e) = lock(&self.decode_error).take() {
+            return Err(e);
+        }
+
+        let result = match reader.as_mut() {
+            Some(reader) => reader.read(buf),
+            None => Ok(0),
+        };
+        if result.is_err() {
+            self.decode_failed.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Decodes the data received so far, keeping it for `read()`. Called by
+    /// decode workers.
+    pub fn decode_ahead(&self) {
+        let mut reader = lock(&self.reader);
+        let Some(reader) = reader.as_mut() else {
+            return;
+        };
+        if self.decode_failed.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut chunk = vec![0; self.input_buffer_size];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(bytes) => lock(&self.decoded).extend_from_slice(&chunk[0..bytes]),
+                Err(e) => {
+                    self.decode_failed.store(true, Ordering::Relaxed);
+                    *lock(&self.decode_error) = Some(e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Whether the decoder failed reading the body.
+    pub fn decode_failed(&self) -> bool {
+        self.decode_failed.load(Ordering::Relaxed)
+    }
+
+    /// Captures data that does not flow through the decoder.
+    pub fn capture(&self, data: &[u8]) {
+        lock(&self.inner_buffer).write(data);
+    }
+
+    pub fn rewrite(&self, chunk: &mut Vec<u8>, eof: bool) {
+        lock(&self.rewriters).apply(chunk, eof);
+    }
+
+    pub fn finish(&self) {
+        self.eof.store(true, Ordering::Relaxed);
+    }
+
+    pub fn take_body(&self) -> Vec<u8> {
+        lock(&self.inner_buffer).take_body()
+    }
+
+    pub fn with_head<R>(&self, limit: usize, f: impl FnOnce(&[u8]) -> R) -> R {
+        lock(&self.inner_buffer).with_head(limit, f)
+    }
+
+    pub fn truncated(&self) -> bool {
+        lock(&self.inner_buffer).truncated
+    }
+
+    pub fn decoded_size(&self) -> usize {
+        lock(&self.inner_buffer).written
+    }
+
+    fn stop_capture(&self) {
+        lock(&self.inner_buffer).stop();
+    }
+
+    fn captured_in_memory(&self) -> usize {
+        lock(&self.inner_buffer).memory_size()
+    }
+
+    fn rewritten_pending(&self) -> usize {
+        lock(&self.rewritten).len()
+    }
+
+    fn decoded_pending(&self) -> usize {
+        lock(&self.decoded).len()
+    }
+}
+
+pub struct RawDataWrapper {
+    reader: Arc<RawDataReader>,
+}
+
+impl RawDataWrapper {
+    pub fn new(reader: Arc<RawDataReader>) -> Self {
+        RawDataWrapper { reader }
+    }
+}
+
+impl Read for RawDataWrapper {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Bytes a transaction holds in its pipeline buffers.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Footprint {
+    /// Data taken off the channel by the decoder, decoded ahead or
+    /// rewritten, but not yet consumed.
+    pub decoder_pending: usize,
+    /// Captured body held in memory.
+    pub captured: usize,
+    /// Data written but not yet taken off the channels.
+    pub queued: usize,
+    /// Last chunk handed back by `send()`, and the buffer encoded chunks are
+    /// written into.
+    pub transfer_chunk: usize,
+}
+
+impl Footprint {
+    pub fn total(&self) -> usize {
+        self.decoder_pending + self.captured + self.queued + self.transfer_chunk
+    }
+}
+
+/// Size of the first encoded chunk handed back by `send()`.
+const INITIAL_OUTPUT_SIZE: usize = 16 * 1024;
+
+/// Room for what finishing the encoder writes past its buffered data: the
+/// last deflate blocks and the gzip trailer.
+const FINISH_SLACK: usize = 64 * 1024;
+
+/// The streaming part of a transaction: the channels data is received on,
+/// the decoder/encoder pair used for encoded bodies and the captured body.
+///
+/// Data written to a pipeline either flows through the decoder and encoder
+/// (`decode` set), or is queued as-is to be handed back by `send()`. In both
+/// cases the decoded data passes through the rewriters before it is emitted.
+pub struct Pipeline {
+    pub decode: bool,
+    pub transfer_chunk: Vec<u8>,
+    /// Buffer the encoder writes into, taken from the buffer pool and
+    /// reused by every encoded `send()`, growing with `output_size`.
+    pub output_buffer: Vec<u8>,
+    /// Bytes the encoder is asked for on the next `send()`. Starts at
+    /// `INITIAL_OUTPUT_SIZE` and doubles whenever the encoder fills it, up
+    /// to `Limits::output_buffer_size`, so small bodies get small buffers
+    /// and large ones are handed back in large chunks.
+    pub output_size: usize,
+    max_output_size: usize,
+    pub bytes_sender: Sender<Vec<u8>>,
+    pub bytes_receiver: Receiver<Vec<u8>>,
+    /// Unset for pass-through bodies, which are never encoded.
+    pub encoder: Option<Codec<Encoder>>,
+    /// Output of finishing the encoder, handed back over the following
+    /// `encode()` calls. Set once the encoder was finished, after which it
+    /// is neither read nor finished again.
+    finished: Option<Cursor<Vec<u8>>>,
+    /// Room given to the encoder to finish in, see `FINISH_SLACK`.
+    finish_size: usize,
+    pub decoder_sender: Sender<Vec<u8>>,
+    /// Shared between the pipeline and the encoder, which reads decoded
+    /// data from it.
+    pub data_reader: Arc<RawDataReader>,
+    /// Number of chunks written but not yet taken off the channels.
+    queued: Arc<AtomicUsize>,
+    /// Size of those chunks.
+    queued_bytes: Arc<AtomicUsize>,
+    /// Data the decoder's input reader took off the channel but did not
+    /// hand to the decoder yet.
+    pending_bytes: Arc<AtomicUsize>,
+    input_buffer_size: usize,
+    /// Chunks that may be queued at once, see `Limits::channel_capacity`.
+    channel_capacity: Option<usize>,
+    /// Small chunks gathered before being queued, see
+    /// `Limits::coalesce_size`.
+    coalesced: Vec<u8>,
+    /// When the oldest of those chunks was written.
+    coalesced_since: Instant,
+    /// Rest of a pass-through chunk split by the output size, handed back
+    /// first by the next `send()`.
+    carried: Vec<u8>,
+    coalesce_size: usize,
+    coalesce_delay: Duration,
+}
+
+impl Pipeline {
+    pub fn new(id: i64, decode: bool, rewriters: RewriteChain, limits: &Limits) -> Self {
+        let (bytes_sender, bytes_receiver): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = channel();
+        let (decoder_sender, decoder_receiver): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let queued_bytes = Arc::new(AtomicUsize::new(0));
+        let pending_bytes = Arc::new(AtomicUsize::new(0));
+
+        // Pass-through bodies never go through zlib, so they get no
+        // streams.
+        let decoder = decode.then(|| {
+            Codec(Decoder::new_with_size(
+                BufferReader {
+                    receiver: decoder_receiver,
+                    pending: VecDeque::new(),
+                    queued: queued.clone(),
+                    queued_bytes: queued_bytes.clone(),
+                    pending_bytes: pending_bytes.clone(),
+                    #[cfg(feature = "fault-injection")]
+                    faults: faults::decoder().input(id),
+                },
+                limits.input_buffer_size,
+            ))
+        });
+        let data_reader = Arc::new(RawDataReader::new(id, decoder, rewriters, limits));
+        let encoder = decode.then(|| {
+            Codec(Encoder::new_with_size(
+                RawDataWrapper::new(data_reader.clone()),
+                limits.encoder_buffer_size,
+            ))
+        });
+        // Pass-through bodies hand back the chunks received as they are.
+        let (transfer_chunk, output_buffer) = if decode {
+            (pool::take(), pool::take())
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        Pipeline {
+            decode,
+            transfer_chunk,
+            output_buffer,
+            output_size: min(INITIAL_OUTPUT_SIZE, limits.output_buffer_size),
+            max_output_size: limits.output_buffer_size,
+            bytes_sender,
+            bytes_receiver,
+            encoder,
+            finished: None,
+            finish_size: limits.encoder_buffer_size + FINISH_SLACK,
+            decoder_sender,
+            data_reader,
+            queued,
+            queued_bytes,
+            pending_bytes,
+            input_buffer_size: limits.input_buffer_size,
+            channel_capacity: limits.channel_capacity,
+            coalesced: Vec::new(),
+            coalesced_since: clock::now(),
+            carried: Vec::new(),
+            coalesce_size: limits.coalesce_size,
+            coalesce_delay: Duration::from_millis(limits.coalesce_delay_ms),
+        }
+    }
+
+    /// Queues data to be handed back by `send()`. Data is refused once the
+    /// channel capacity is reached. Chunks smaller than the coalescing size
+    /// are gathered until they add up to it, or until `flush()`.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), SendError<Vec<u8>>> {
+        if self
+            .channel_capacity
+            .is_some_and(|capacity| self.queued.load(Ordering::Relaxed) >= capacity)
+        {
+            return Err(SendError(data.to_vec()));
+        }
+        if self.coalesced.is_empty() {
+            if data.len() >= self.coalesce_size {
+                return self.queue(data.to_vec());
+            }
+            self.coalesced_since = clock::now();
+        }
+        self.coalesced.extend_from_slice(data);
+        if self.coalesced.len() >= self.coalesce_size {
+            self.flush();
+        }
+        Ok(())
+    }
+
+    /// Queues the chunks gathered so far.
+    pub fn flush(&mut self) {
+        if !self.coalesced.is_empty() {
+            let data = std::mem::take(&mut self.coalesced);
+            // The channels' receivers belong to the pipeline, so this
+            // cannot fail.
+            let _ = self.queue(data);
+        }
+    }
+
+    /// Queues the chunks gathered so far once the oldest waited for the
+    /// coalescing delay, so a slow origin's data is still handed back.
+    pub fn flush_expired(&mut self) {
+        if !self.coalesced.is_empty() && clock::since(self.coalesced_since) >= self.coalesce_delay {
+            self.flush();
+        }
+    }
+
+    fn queue(&self, data: Vec<u8>) -> Result<(), SendError<Vec<u8>>> {
+        let size = data.len();
+        let result = if self.decode {
+            self.decoder_sender.send(data)
+        } else {
+            self.data_reader.capture(&data);
+            self.bytes_sender.send(data)
+        };
+        if result.is_ok() {
+            self.queued.fetch_add(1, Ordering::Relaxed);
+            self.queued_bytes.fetch_add(size, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Encodes the next chunk into `output_buffer`, returning its size.
+    /// Once the body is `done`, the encoder is read until it runs dry, then
+    /// finished once. What finishing wrote is handed out over the following
+    /// calls, however many chunks it spans, and `0` after that.
+    pub fn encode(&mut self, done: bool) -> std::io::Result<usize> {
+        let size = self.output_size;
+        if self.output_buffer.len() < size {
+            self.output_buffer.resize(size, 0);
+        }
+        let output = &mut self.output_buffer[0..size];
+        if let Some(finished) = &mut self.finished {
+            return finished.read(output);
+        }
+        let Some(encoder) = &mut self.encoder else {
+            return Ok(0);
+        };
+
+        let bytes = encoder.read(output)?;
+        if bytes > 0 || !done {
+            return Ok(bytes);
+        }
+        let mut finished = vec![0; self.finish_size];
+        let result = encoder.finish(&mut finished);
+        // Finished even when finishing failed, so it is never retried.
+        self.finished = Some(Cursor::new(Vec::new()));
+        let bytes = result?;
+        if bytes == finished.len() {
+            warn!(
+                "Transaction {} filled all {} bytes given to finish encoding, the body may be cut short",
+                self.data_reader.id, bytes
+            );
+        }
+        finished.truncate(bytes);
+        let finished = self.finished.insert(Cursor::new(finished));
+        finished.read(output)
+    }
+
+    /// Records that the encoder produced `bytes` on a `send()`, growing the
+    /// next read when it filled the whole buffer.
+    pub fn output_produced(&mut self, bytes: usize) {
+        if bytes == self.output_size {
+            self.output_size = min(self.output_size * 2, self.max_output_size);
+        }
+    }
+
+    /// Number of chunks written but not yet consumed.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn footprint(&self) -> Footprint {
+        Footprint {
+            decoder_pending: self.pending_bytes.load(Ordering::Relaxed)
+                + self.data_reader.rewritten_pending()
+                + self.data_reader.decoded_pending(),
+            captured: self.data_reader.captured_in_memory(),
+            queued: self.queued_bytes.load(Ordering::Relaxed)
+                + self.coalesced.len()
+                + self.carried.len(),
+            transfer_chunk: self.transfer_chunk.len() + self.output_buffer.len(),
+        }
+    }
+
+    /// Enables detailed trace lines for data flowing through the decoder.
+    pub fn set_trace(&self, enabled: bool) {
+        self.data_reader.trace.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Consumes everything received so far without emitting it. Data is
+    /// still captured, so the body remains available for persistence.
+    pub fn discard(&mut self) {
+        self.flush();
+        if self.decode {
+            let mut scratch = vec![0; self.input_buffer_size];
+            while let Ok(bytes) = self.data_reader.read(&mut scratch) {
+                if bytes == 0 {
+                    break;
+                }
+            }
+        } else {
+            self.carried.clear();
+            while self.next_chunk().is_some() {}
+        }
+    }
+
+    /// Moves the queued chunks of a pass-through body, once rewritten, into
+    /// `transfer_chunk`, in order and up to `Limits::output_buffer_size`
+    /// bytes, so a body received in many small chunks is handed back in
+    /// few. A chunk crossing the limit is split, and its rest handed back
+    /// first on the next call. Returns the size of the transfer chunk.
+    pub fn drain_chunks(&mut self) -> usize {
+        let limit = self.max_output_size;
+        let mut output = std::mem::take(&mut self.carried);
+        while output.len() < limit {
+            match self.next_chunk() {
+                Some(chunk) if output.is_empty() => output = chunk,
+                Some(chunk) => output.extend_from_slice(&chunk),
+                None => break,
+            }
+        }
+        if output.len() > limit {
+            self.carried = output.split_off(limit);
+        }
+        self.transfer_chunk = output;
+        self.transfer_chunk.len()
+    }
+
+    /// Takes the next queued chunk of a pass-through body, once rewritten.
+    fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        let chunk = match self.bytes_receiver.try_recv() {
+            Ok(mut bytes) => {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                self.queued_bytes.fetch_sub(bytes.len(), Ordering::Relaxed);
+                self.data_reader.rewrite(&mut bytes, false);
+                bytes
+            }
+            Err(_) => {
+                if !self.data_reader.eof.load(Ordering::Relaxed) {
+                    return None;
+                }
+                let mut bytes = Vec::new();
+                self.data_reader.rewrite(&mut bytes, true);
+                bytes
+            }
+        };
+
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(chunk)
+        }
+    }
+
+    /// Signals that no more data will be written to the pipeline, queuing
+    /// the chunks still gathered.
+    pub fn finish(&mut self) {
+        self.flush();
+        self.data_reader.finish();
+    }
+
+    pub fn take_body(&self) -> Vec<u8> {
+        self.data_reader.take_body()
+    }
+
+    pub fn with_head<R>(&self, limit: usize, f: impl FnOnce(&[u8]) -> R) -> R {
+        self.data_reader.with_head(limit, f)
+    }
+
+    pub fn body_truncated(&self) -> bool {
+        self.data_reader.truncated()
+    }
+
+    /// Size of the body decoded so far, including what was not captured.
+    pub fn decoded_size(&self) -> usize {
+        self.data_reader.decoded_size()
+    }
+
+    /// Stops capturing the body, which is still relayed.
+    pub fn stop_capture(&self) {
+        self.data_reader.stop_capture();
+    }
+}
+
+impl Drop for Pipeline {
+    /// Hands the output buffers back to the pool for later transactions.
+    fn drop(&mut self) {
+        pool::give(std::mem::take(&mut self.output_buffer));
+        pool::give(std::mem::take(&mut self.transfer_chunk));
+    }
+}
@@ -0,0 +1,56 @@
+//! Output buffers kept from finished transactions, so the next ones reuse
+//! their allocations rather than growing their own. The zstream decoders
+//! and encoders own the readers they are built on and cannot be reset, so
+//! they are not pooled.
+
+use crate::config;
+use crate::metrics;
+use std::sync::{Mutex, MutexGuard};
+
+static BUFFERS: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+fn buffers() -> MutexGuard<'static, Vec<Vec<u8>>> {
+    match BUFFERS.lock() {
+        Ok(buffers) => buffers,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// An empty buffer, reusing the allocation of a pooled one when there is
+/// one.
+pub fn take() -> Vec<u8> {
+    match buffers().pop() {
+        Some(buffer) => {
+            metrics::increment(&metrics::COUNTERS.pool_hits);
+            buffer
+        }
+        None => {
+            metrics::increment(&metrics::COUNTERS.pool_misses);
+            Vec::new()
+        }
+    }
+}
+
+/// Hands a buffer back for later transactions. It is cleared, so nothing
+/// one transaction wrote can be read by the next. Buffers that never grew,
+/// or grew past `limits.output_buffer_size`, are dropped, as are those past
+/// `limits.buffer_pool_size`.
+pub fn give(mut buffer: Vec<u8>) {
+    let config = config::get();
+    let limits = &config.limits;
+    if buffer.capacity() == 0 || buffer.capacity() > limits.output_buffer_size {
+        return;
+    }
+    buffer.clear();
+    let mut buffers = buffers();
+    if buffers.len() < limits.buffer_pool_size {
+        buffers.push(buffer);
+    }
+}
+
+/// Drops pooled buffers past `limits.buffer_pool_size`, after it was
+/// lowered.
+pub fn trim() {
+    let size = config::get().limits.buffer_pool_size;
+    buffers().truncate(size);
+}
@@ -0,0 +1,51 @@
+/// Number of body bytes needed to build a preview of `length` characters.
+pub fn head_size(length: usize) -> usize {
+    // UTF-8 characters take up to 4 bytes.
+    length * 4
+}
+
+fn charset(content_type: Option<&str>) -> Option<String> {
+    content_type?
+        .split(';')
+        .skip(1)
+        .filter_map(|parameter| parameter.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_ascii_lowercase())
+}
+
+fn decode(head: &[u8], charset: Option<&str>) -> Option<String> {
+    match charset {
+        Some("iso-8859-1") | Some("latin1") | Some("latin-1") | Some("windows-1252") => {
+            Some(head.iter().map(|&byte| byte as char).collect())
+        }
+        _ => match std::str::from_utf8(head) {
+            Ok(text) => Some(text.to_string()),
+            // The head may end in the middle of a character, anything
+            // else means the body isn't text.
+            Err(e) if e.error_len().is_none() => {
+                Some(String::from_utf8_lossy(&head[0..e.valid_up_to()]).into_owned())
+            }
+            Err(_) => None,
+        },
+    }
+}
+
+/// Builds a plain text preview from the first bytes of a body: decoded
+/// according to the content type's charset, with control characters
+/// stripped and limited to `length` characters. Binary bodies get an empty
+/// preview.
+pub fn preview(head: &[u8], content_type: Option<&str>, length: usize) -> String {
+    if head.contains(&0) {
+        return "".to_string();
+    }
+
+    match decode(head, charset(content_type).as_deref()) {
+        Some(text) => text
+            .chars()
+            .map(|c| if c.is_whitespace() { ' ' } else { c })
+            .filter(|c| !c.is_control())
+            .take(length)
+            .collect(),
+        None => "".to_string(),
+    }
+}
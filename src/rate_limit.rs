@@ -0,0 +1,117 @@
+//! Pacing of persistence, see `Limits::persist_rate` and
+//! `Limits::persist_byte_rate`: token buckets the persistence workers take
+//! from before taking a document off the queue.
+
+use crate::config::Limits;
+use crate::metrics;
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A bucket holding up to one second worth of tokens, refilled at the rate
+/// in use when it is taken from, so rates can be changed on reload.
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Bucket {
+            tokens: f64::INFINITY,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled = now;
+    }
+
+    /// How long until `cost` tokens can be taken, `None` when they can
+    /// now. A cost above what the bucket holds is taken once it is full,
+    /// leaving it in debt.
+    fn wait(&self, cost: f64, rate: f64) -> Option<Duration> {
+        let needed = cost.min(rate);
+        if self.tokens >= needed {
+            return None;
+        }
+        Some(Duration::from_secs_f64((needed - self.tokens) / rate))
+    }
+}
+
+struct Buckets {
+    documents: Bucket,
+    bytes: Bucket,
+    /// Whether the document at the front of the queue was held back.
+    throttled: bool,
+}
+
+/// Limits how fast documents are persisted, across all the workers.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            buckets: Mutex::new(Buckets {
+                documents: Bucket::new(now),
+                bytes: Bucket::new(now),
+                throttled: false,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Buckets> {
+        match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Takes what persisting a document of `size` bytes costs under
+    /// `limits`, returning how long to wait first when the rates do not
+    /// allow it yet. Nothing is taken then: the caller waits and asks
+    /// again, the document still queued.
+    pub fn acquire(&self, size: usize, limits: &Limits) -> Option<Duration> {
+        let now = Instant::now();
+        let mut buckets = self.lock();
+        let buckets = &mut *buckets;
+        let mut limited = [
+            (&mut buckets.documents, limits.persist_rate, 1.0),
+            (
+                &mut buckets.bytes,
+                limits.persist_byte_rate.map(|rate| rate as f64),
+                size as f64,
+            ),
+        ];
+
+        let mut wait = None;
+        for (bucket, rate, cost) in limited.iter_mut() {
+            match rate {
+                Some(rate) => {
+                    bucket.refill(*rate, now);
+                    wait = wait.max(bucket.wait(*cost, *rate));
+                }
+                None => **bucket = Bucket::new(now),
+            }
+        }
+        if wait.is_some() {
+            if !buckets.throttled {
+                buckets.throttled = true;
+                metrics::increment(&metrics::COUNTERS.persist_delayed);
+            }
+            metrics::PERSIST_THROTTLED.store(1, Ordering::Relaxed);
+            return wait;
+        }
+        for (bucket, _, cost) in limited.iter_mut() {
+            bucket.tokens -= *cost;
+        }
+        buckets.throttled = false;
+        metrics::PERSIST_THROTTLED.store(0, Ordering::Relaxed);
+        None
+    }
+}
@@ -0,0 +1,414 @@
+//! Trace of the calls made for some transactions, to reproduce a bug met in
+//! production by replaying them. Enabled by `recorder.path`, it appends one
+//! JSON line per call to the trace file:
+//!
+//! ```json
+//! {"version":1,"started":"2024-01-01T00:00:00.123456789Z","bodies":"full"}
+//! {"at":0,"id":7,"call":"header","name":"Content-Type","value":"text/html"}
+//! {"at":12,"id":7,"call":"uri","method":"GET","uri":"http://example.com/","mode":"RESPMOD"}
+//! {"at":40,"id":7,"call":"receive","size":512,"data":"PGh0bWw+..."}
+//! {"at":95,"id":7,"call":"send","size":512}
+//! {"at":96,"id":7,"call":"done"}
+//! {"at":130,"id":7,"call":"document","sha256":"9f86d0..."}
+//! {"at":131,"id":7,"call":"cleanup","finished":true}
+//! ```
+//!
+//! The first line is written when the trace is opened, `at` counts
+//! microseconds from it. Received chunks hold their bytes, their SHA-256 or
+//! only their size depending on `recorder.bodies`, and the values of the
+//! headers in `recorder.redact_headers` are replaced by their SHA-256.
+//! Request headers are marked `"request":true`, other headers are response
+//! headers.
+//! `document` is not a call: it holds the digest of the document handed for
+//! persistence, so a replay can check it ends up with the same one.
+//!
+//! Which transactions are recorded is decided as they start, by their uri,
+//! so calls made before are held until then.
+
+use crate::clock;
+use crate::config::{self, RECORD_FULL, RECORD_HASH};
+use crate::document::Document;
+use crate::headers::Direction;
+use crate::mode::Mode;
+use base64::{engine::general_purpose, Engine};
+use log::{info, warn};
+use regex::Regex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Instant;
+
+/// Version of the trace format, in the first line.
+const VERSION: u32 = 1;
+
+struct Recorder {
+    path: PathBuf,
+    uris: Option<Regex>,
+    bodies: String,
+    max_chunk_size: Option<usize>,
+    /// Lowercased.
+    redact_headers: Vec<String>,
+    max_transactions: Option<usize>,
+    opened: Instant,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    /// Transactions being recorded.
+    recorded: HashSet<i64>,
+    /// Lines of the calls made for transactions not started yet.
+    pending: HashMap<i64, Vec<Vec<u8>>>,
+    /// Transactions recorded so far.
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct Header<'a> {
+    version: u32,
+    started: String,
+    bodies: &'a str,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    at: u128,
+    id: i64,
+    #[serde(flatten)]
+    call: Call<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+enum Call<'a> {
+    Uri {
+        method: &'a str,
+        uri: &'a str,
+        mode: Mode,
+    },
+    Header {
+        name: &'a str,
+        value: String,
+        #[serde(skip_serializing_if = "is_false")]
+        redacted: bool,
+        #[serde(skip_serializing_if = "is_false")]
+        request: bool,
+    },
+    Receive {
+        size: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>,
+        #[serde(skip_serializing_if = "is_false")]
+        truncated: bool,
+    },
+    Send {
+        size: usize,
+    },
+    Done,
+    Block {
+        reason: &'a str,
+    },
+    Annotate {
+        key: &'a str,
+        value: &'a str,
+    },
+    Document {
+        sha256: String,
+    },
+    Cleanup {
+        finished: bool,
+    },
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+static RECORDER: OnceLock<Option<Recorder>> = OnceLock::new();
+/// Set once a failure was logged, so a broken trace file warns only once.
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+fn failed(message: &str) {
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        warn!("{} (further failures are not logged)", message);
+    }
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl Recorder {
+    fn from_config() -> Option<Self> {
+        let config = config::get();
+        let settings = &config.recorder;
+        let path = settings.path.clone()?;
+        // Checked when the configuration was loaded.
+        let uris = settings
+            .uris
+            .as_deref()
+            .and_then(|pattern| Regex::new(pattern).ok());
+        let mut file = match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                failed(&format!("Failed opening trace {}: {}", path.display(), e));
+                return None;
+            }
+        };
+        let header = Header {
+            version: VERSION,
+            started: clock::utc().to_rfc3339(),
+            bodies: &settings.bodies,
+        };
+        let written = serde_json::to_vec(&header)
+            .map_err(|e| e.to_string())
+            .and_then(|mut line| {
+                line.push(b'\n');
+                file.write_all(&line).map_err(|e| e.to_string())
+            });
+        if let Err(e) = written {
+            failed(&format!("Failed writing trace {}: {}", path.display(), e));
+            return None;
+        }
+        info!("Recording transactions to {}", path.display());
+        Some(Recorder {
+            path,
+            uris,
+            bodies: settings.bodies.clone(),
+            max_chunk_size: settings.max_chunk_size,
+            redact_headers: settings
+                .redact_headers
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            max_transactions: settings.max_transactions,
+            opened: clock::now(),
+            state: Mutex::new(State {
+                file,
+                recorded: HashSet::new(),
+                pending: HashMap::new(),
+                count: 0,
+            }),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn line(&self, id: i64, call: Call) -> Option<Vec<u8>> {
+        let entry = Entry {
+            at: clock::since(self.opened).as_micros(),
+            id,
+            call,
+        };
+        match serde_json::to_vec(&entry) {
+            Ok(mut line) => {
+                line.push(b'\n');
+                Some(line)
+            }
+            Err(e) => {
+                failed(&format!("Failed serializing trace entry: {}", e));
+                None
+            }
+        }
+    }
+
+    fn write(&self, state: &mut State, line: &[u8]) {
+        if let Err(e) = state.file.write_all(line) {
+            failed(&format!(
+                "Failed writing trace {}: {}",
+                self.path.display(),
+                e
+            ));
+        }
+    }
+
+    /// Records a call made for a transaction being recorded.
+    fn record<'a>(&self, id: i64, call: impl FnOnce() -> Call<'a>) {
+        let mut state = self.lock();
+        if !state.recorded.contains(&id) {
+            return;
+        }
+        if let Some(line) = self.line(id, call()) {
+            self.write(&mut state, &line);
+        }
+    }
+
+    fn full(&self) -> bool {
+        self.max_transactions
+            .is_some_and(|max| self.lock().count >= max)
+    }
+}
+
+fn get() -> Option<&'static Recorder> {
+    RECORDER.get_or_init(Recorder::from_config).as_ref()
+}
+
+/// Records the start of a transaction, along with the calls held for it,
+/// if it is to be recorded.
+pub fn start(id: i64, method: &str, uri: &str, mode: Mode) {
+    let Some(recorder) = get() else {
+        return;
+    };
+    let mut state = recorder.lock();
+    let pending = state.pending.remove(&id).unwrap_or_default();
+    let matches = recorder
+        .uris
+        .as_ref()
+        .is_none_or(|pattern| pattern.is_match(uri));
+    let room = recorder
+        .max_transactions
+        .is_none_or(|max| state.count < max);
+    if !matches || !room {
+        return;
+    }
+    state.count += 1;
+    state.recorded.insert(id);
+    for line in pending {
+        recorder.write(&mut state, &line);
+    }
+    let call = Call::Uri { method, uri, mode };
+    if let Some(line) = recorder.line(id, call) {
+        recorder.write(&mut state, &line);
+    }
+}
+
+/// Records a header, held until the transaction starts if it did not yet.
+pub fn header(id: i64, direction: Direction, name: &str, value: &str, started: bool) {
+    let Some(recorder) = get() else {
+        return;
+    };
+    let redacted = recorder
+        .redact_headers
+        .iter()
+        .any(|redacted| name.eq_ignore_ascii_case(redacted));
+    let call = || Call::Header {
+        name,
+        value: if redacted {
+            sha256(value.as_bytes())
+        } else {
+            value.to_string()
+        },
+        redacted,
+        request: direction == Direction::Request,
+    };
+    if started {
+        return recorder.record(id, call);
+    }
+    if recorder.full() {
+        return;
+    }
+    if let Some(line) = recorder.line(id, call()) {
+        recorder.lock().pending.entry(id).or_default().push(line);
+    }
+}
+
+pub fn receive(id: i64, data: &[u8]) {
+    let Some(recorder) = get() else {
+        return;
+    };
+    recorder.record(id, || {
+        let size = data.len();
+        let (data, sha256, truncated) = match recorder.bodies.as_str() {
+            RECORD_FULL => {
+                let kept = recorder
+                    .max_chunk_size
+                    .map_or(data.len(), |max| max.min(data.len()));
+                (
+                    Some(general_purpose::STANDARD.encode(&data[0..kept])),
+                    None,
+                    kept < data.len(),
+                )
+            }
+            RECORD_HASH => (None, Some(sha256(data)), false),
+            _ => (None, None, false),
+        };
+        Call::Receive {
+            size,
+            data,
+            sha256,
+            truncated,
+        }
+    });
+}
+
+pub fn send(id: i64, size: usize) {
+    if let Some(recorder) = get() {
+        recorder.record(id, || Call::Send { size });
+    }
+}
+
+pub fn done(id: i64) {
+    if let Some(recorder) = get() {
+        recorder.record(id, || Call::Done);
+    }
+}
+
+pub fn block(id: i64, reason: &str) {
+    if let Some(recorder) = get() {
+        recorder.record(id, || Call::Block { reason });
+    }
+}
+
+pub fn annotate(id: i64, key: &str, value: &str) {
+    if let Some(recorder) = get() {
+        recorder.record(id, || Call::Annotate { key, value });
+    }
+}
+
+/// Records the digest of the document of a transaction, handed for
+/// persistence. It covers the document as the backend would store it but
+/// for its date, id and run id, which a replay in another process cannot
+/// reproduce.
+pub fn document(document: &Document) {
+    let Some(recorder) = get() else {
+        return;
+    };
+    recorder.record(document.id, || {
+        let fields = &config::get().backend.elasticsearch.fields;
+        let field = |name| fields.get(name).map_or(name, String::as_str);
+        let sha256 = document
+            .to_json(fields)
+            .and_then(|json| serde_json::from_slice::<serde_json::Value>(&json))
+            .map(|mut json| {
+                if let Some(json) = json.as_object_mut() {
+                    json.remove(field("date"));
+                    json.remove(field("document_id"));
+                    json.remove(field("run_id"));
+                }
+                sha256(json.to_string().as_bytes())
+            })
+            .unwrap_or_default();
+        Call::Document { sha256 }
+    });
+}
+
+/// Records the cleanup of a transaction. `forget()` ends its recording
+/// once cleaned up.
+pub fn cleanup(id: i64, finished: bool) {
+    if let Some(recorder) = get() {
+        recorder.record(id, || Call::Cleanup { finished });
+    }
+}
+
+pub fn forget(id: i64) {
+    if let Some(recorder) = get() {
+        let mut state = recorder.lock();
+        state.recorded.remove(&id);
+        state.pending.remove(&id);
+    }
+}
@@ -0,0 +1,118 @@
+//! Deletion of documents older than `retention.max_age`, by sweeps run on
+//! a background ticker, never on the request path. Each backend removes
+//! documents its own way, see `Backend::prune`.
+
+use crate::clock;
+use crate::config;
+use crate::error::PrismError;
+use crate::logging::throttled;
+use crate::worker;
+use log::{debug, info, Level};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the ticker checks whether it should stop.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest age honored, about 136 years, keeping the cutoff within the
+/// dates that can be represented.
+const MAX_AGE: u64 = u32::MAX as u64;
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static TICKER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Removes the documents older than `retention.max_age` from the backend
+/// in use, returning how many went. Dry runs persist nothing, so there is
+/// nothing to remove.
+pub fn sweep() -> Result<usize, PrismError> {
+    let config = config::get();
+    let retention = &config.retention;
+    let Some(max_age) = retention.max_age else {
+        return Ok(0);
+    };
+    if config.dry_run {
+        return Ok(0);
+    }
+    let Some(backend) = worker::backend(false) else {
+        return Ok(0);
+    };
+
+    let cutoff = clock::utc() - chrono::Duration::seconds(max_age.min(MAX_AGE) as i64);
+    let result = backend.prune(cutoff, retention.batch_size);
+    let cutoff = cutoff.format("%Y-%m-%dT%H:%M:%SZ");
+    match result {
+        Ok(0) => {
+            debug!(
+                "Retention sweep found no document older than {} in {} {}",
+                cutoff,
+                backend.name(),
+                backend.destination()
+            );
+            Ok(0)
+        }
+        Ok(removed) => {
+            info!(
+                "Retention sweep removed {} documents older than {} from {} {}",
+                removed,
+                cutoff,
+                backend.name(),
+                backend.destination()
+            );
+            Ok(removed)
+        }
+        Err(e) => {
+            throttled!(
+                Level::Warn,
+                "retention",
+                "Retention sweep of {} {} failed: {}",
+                backend.name(),
+                backend.destination(),
+                e
+            );
+            Err(e)
+        }
+    }
+}
+
+/// Sweeps every `retention.interval` seconds, as configured at the time.
+fn tick() {
+    let mut last = Instant::now();
+    while !STOP.load(Ordering::Relaxed) {
+        thread::sleep(STOP_POLL_INTERVAL);
+        if last.elapsed() < Duration::from_secs(config::get().retention.interval) {
+            continue;
+        }
+        let _ = sweep();
+        last = Instant::now();
+    }
+}
+
+/// Starts sweeping, if a maximum age is configured at init.
+pub fn start() {
+    if config::get().retention.max_age.is_none() {
+        return;
+    }
+
+    let mut ticker = TICKER.lock().unwrap();
+    if ticker.is_some() {
+        return;
+    }
+
+    STOP.store(false, Ordering::Relaxed);
+    *ticker = Some(
+        thread::Builder::new()
+            .name("prism-retention".to_string())
+            .spawn(tick)
+            .unwrap(),
+    );
+}
+
+/// Stops sweeping and waits for the ticker to exit.
+pub fn stop() {
+    if let Some(ticker) = TICKER.lock().unwrap().take() {
+        STOP.store(true, Ordering::Relaxed);
+        let _ = ticker.join();
+    }
+}
@@ -0,0 +1,90 @@
+/// A stage that may modify decoded body data before it is handed back to
+/// the client.
+///
+/// `rewrite` is called with each decoded chunk in order, and once more with
+/// `eof` set (and a possibly empty chunk) when the body is complete, so
+/// implementations holding back data can flush it.
+pub trait BodyRewriter: Send {
+    fn rewrite(&mut self, chunk: &mut Vec<u8>, eof: bool);
+}
+
+/// A find/replace rule applied by `ReplaceRewriter`.
+#[derive(Clone)]
+pub struct ReplaceRule {
+    pub find: Vec<u8>,
+    pub replace: Vec<u8>,
+}
+
+/// Rewriter replacing every occurrence of the configured patterns.
+///
+/// Matches may span chunk boundaries: trailing bytes that could be the start
+/// of a match are held back until the next chunk arrives or the body ends.
+pub struct ReplaceRewriter {
+    rules: Vec<ReplaceRule>,
+    carry: Vec<u8>,
+}
+
+impl ReplaceRewriter {
+    pub fn new(rules: Vec<ReplaceRule>) -> Self {
+        let rules = rules
+            .into_iter()
+            .filter(|rule| !rule.find.is_empty())
+            .collect();
+        ReplaceRewriter {
+            rules,
+            carry: Vec::new(),
+        }
+    }
+
+    fn matching_rule(&self, data: &[u8]) -> Option<&ReplaceRule> {
+        self.rules.iter().find(|rule| data.starts_with(&rule.find))
+    }
+
+    fn is_partial_match(&self, data: &[u8]) -> bool {
+        self.rules.iter().any(|rule| rule.find.starts_with(data))
+    }
+}
+
+impl BodyRewriter for ReplaceRewriter {
+    fn rewrite(&mut self, chunk: &mut Vec<u8>, eof: bool) {
+        let mut data = std::mem::take(&mut self.carry);
+        data.append(chunk);
+
+        let mut position = 0;
+        while position < data.len() {
+            let rest = &data[position..];
+            if let Some(rule) = self.matching_rule(rest) {
+                chunk.extend_from_slice(&rule.replace);
+                position += rule.find.len();
+            } else if !eof && self.is_partial_match(rest) {
+                self.carry = rest.to_vec();
+                break;
+            } else {
+                chunk.push(data[position]);
+                position += 1;
+            }
+        }
+    }
+}
+
+/// Ordered chain of rewriters applied to each chunk.
+#[derive(Default)]
+pub struct RewriteChain {
+    rewriters: Vec<Box<dyn BodyRewriter>>,
+}
+
+impl RewriteChain {
+    pub fn new(rewriters: Vec<Box<dyn BodyRewriter>>) -> Self {
+        RewriteChain { rewriters }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rewriters.is_empty()
+    }
+
+    pub fn apply(&mut self, chunk: &mut Vec<u8>, eof: bool) {
+        for rewriter in self.rewriters.iter_mut() {
+            rewriter.rewrite(chunk, eof);
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Files written line by line, the audit log and the dead letter file,
+//! rotated by size and age as `rotation` configures.
+//!
+//! Lines are appended whole, and cut back off when they could not be, so
+//! files only ever hold whole lines. Rotated files are renamed out of the
+//! way, then compressed aside on a background thread and renamed again,
+//! so readers never see a partial file either.
+
+use crate::clock;
+use crate::config::{self, Rotation};
+use crate::logging::throttled;
+use crate::metrics;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::Level;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+/// Format of the time rotated files are named after.
+const ROTATED_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Extension of compressed rotated files.
+const COMPRESSED: &str = ".gz";
+
+/// Extension of files being compressed.
+const TEMPORARY: &str = ".tmp";
+
+/// Compressions running in the background.
+static COMPRESSIONS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// A file appended to line by line, rotated when it grows past its size or
+/// age limit.
+pub struct RotatingFile {
+    path: PathBuf,
+    /// Size past which the file is rotated, instead of `rotation.max_size`.
+    max_size: Option<u64>,
+    /// None until opened, and once a rotation closed it, until reopened.
+    file: Option<File>,
+    size: u64,
+    opened: DateTime<Utc>,
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path: OsString = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Time a file was rotated at, formatted as `ROTATED_FORMAT` so keys sort
+/// as times do, and the sequence number telling apart files rotated within
+/// the same second.
+type Key = (String, u32);
+
+/// The key in the suffix of a rotated file, `None` for files that are not
+/// rotated ones.
+fn rotated_key(suffix: &str) -> Option<Key> {
+    let suffix = suffix.strip_suffix(COMPRESSED).unwrap_or(suffix);
+    let (time, sequence) = match suffix.split_once('-') {
+        Some((time, sequence)) => (time, sequence.parse().ok()?),
+        None => (suffix, 0),
+    };
+    NaiveDateTime::parse_from_str(time, ROTATED_FORMAT).ok()?;
+    Some((time.to_string(), sequence))
+}
+
+fn rotated_suffix((time, sequence): &Key) -> String {
+    match sequence {
+        0 => format!(".{}", time),
+        sequence => format!(".{}-{}", time, sequence),
+    }
+}
+
+/// The rotated files of `path`, oldest first, compressed ones included.
+/// Files being compressed are not.
+fn rotated_files(path: &Path) -> Vec<(Key, PathBuf)> {
+    let (Some(directory), Some(name)) = (path.parent(), path.file_name()) else {
+        return Vec::new();
+    };
+    let directory = if directory.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        directory
+    };
+    let prefix = format!("{}.", name.to_string_lossy());
+    let Ok(files) = std::fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let mut rotated: Vec<_> = files
+        .filter_map(|file| {
+            let file = file.ok()?;
+            let name = file.file_name().into_string().ok()?;
+            let key = rotated_key(name.strip_prefix(&prefix)?)?;
+            Some((key, file.path()))
+        })
+        .collect();
+    rotated.sort();
+    rotated
+}
+
+/// Removes the oldest rotated files of `path` past the `keep` newest. A
+/// file and its compressed copy, both there while it is being compressed,
+/// count as one.
+fn prune(path: &Path, keep: usize) {
+    let rotated = rotated_files(path);
+    let mut keys: Vec<Key> = rotated.iter().map(|(key, _)| key.clone()).collect();
+    keys.dedup();
+    let old = &keys[..keys.len().saturating_sub(keep)];
+    if old.is_empty() {
+        return;
+    }
+    remove(
+        rotated
+            .into_iter()
+            .filter(|(key, _)| old.binary_search(key).is_ok())
+            .collect(),
+    );
+}
+
+fn remove(rotated: Vec<(Key, PathBuf)>) {
+    for (_, path) in rotated {
+        if let Err(e) = std::fs::remove_file(&path) {
+            failed(&format!(
+                "Failed removing rotated file {}: {}",
+                path.display(),
+                e
+            ));
+        }
+    }
+}
+
+fn failed(message: &str) {
+    metrics::increment(&metrics::COUNTERS.rotation_failures);
+    throttled!(Level::Warn, "rotation", "{}", message);
+}
+
+/// Compresses a rotated file aside, then replaces it with the compressed
+/// copy. The file is left as it is when that fails.
+fn compress(path: PathBuf) {
+    let compressed = with_suffix(&path, COMPRESSED);
+    let temporary = with_suffix(&compressed, TEMPORARY);
+    let result = File::open(&path)
+        .and_then(|mut input| {
+            let mut encoder = GzEncoder::new(File::create(&temporary)?, Compression::default());
+            std::io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&temporary, &compressed));
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temporary);
+        failed(&format!("Failed compressing {}: {}", path.display(), e));
+        return;
+    }
+    match std::fs::remove_file(&path) {
+        Ok(()) => {}
+        // Pruned while being compressed.
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let _ = std::fs::remove_file(&compressed);
+        }
+        Err(e) => failed(&format!("Failed removing {}: {}", path.display(), e)),
+    }
+}
+
+/// Compresses a rotated file on a background thread.
+fn compress_later(path: PathBuf) {
+    let mut compressions = COMPRESSIONS.lock().unwrap();
+    compressions.retain(|compression| !compression.is_finished());
+    match thread::Builder::new()
+        .name("prism-compress".to_string())
+        .spawn(move || compress(path))
+    {
+        Ok(compression) => compressions.push(compression),
+        Err(e) => failed(&format!("Failed starting compression: {}", e)),
+    }
+}
+
+/// Waits for the compressions running in the background.
+pub fn finish() {
+    let compressions = std::mem::take(&mut *COMPRESSIONS.lock().unwrap());
+    for compression in compressions {
+        let _ = compression.join();
+    }
+}
+
+impl RotatingFile {
+    /// A file at `path`, opened on the first append.
+    pub fn new(path: PathBuf, max_size: Option<u64>) -> Self {
+        RotatingFile {
+            path,
+            max_size,
+            file: None,
+            size: 0,
+            opened: clock::utc(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens the file, if it isn't.
+    pub fn open(&mut self) -> std::io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.size = file.metadata()?.len();
+            self.opened = clock::utc();
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn due(&self, length: u64, rotation: &Rotation) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let max_size = self.max_size.unwrap_or(rotation.max_size);
+        let too_old = rotation.max_age.is_some_and(|max_age| {
+            clock::utc() - self.opened
+                >= chrono::Duration::seconds(max_age.min(u32::MAX as u64) as i64)
+        });
+        self.size + length > max_size || too_old
+    }
+
+    /// Renames the file after the current time, then removes the rotated
+    /// files past `rotation.keep` and compresses the new one if asked to.
+    /// Rotated files are named to sort after the ones before, even when
+    /// the clock went back.
+    fn rotate(&mut self, rotation: &Rotation) -> std::io::Result<()> {
+        self.file = None;
+        let time = clock::utc().format(ROTATED_FORMAT).to_string();
+        let key = match rotated_files(&self.path).pop() {
+            Some(((latest, sequence), _)) if latest >= time => (latest, sequence + 1),
+            _ => (time, 0),
+        };
+        let rotated = with_suffix(&self.path, &rotated_suffix(&key));
+        std::fs::rename(&self.path, &rotated)?;
+        prune(&self.path, rotation.keep);
+        if rotation.compress && rotation.keep > 0 {
+            compress_later(rotated);
+        }
+        Ok(())
+    }
+
+    /// Appends a line, rotating the file first when it would otherwise
+    /// grow past its limits. On errors the line is left out whole.
+    pub fn append(&mut self, line: &[u8]) -> std::io::Result<()> {
+        let config = config::get();
+        self.open()?;
+        if self.due(line.len() as u64, &config.rotation) {
+            self.rotate(&config.rotation)?;
+        }
+        let size = self.size;
+        let file = self.open()?;
+        if let Err(e) = file.write_all(line) {
+            let _ = file.set_len(size);
+            return Err(e);
+        }
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
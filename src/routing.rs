@@ -0,0 +1,42 @@
+//! Routing of documents to other indices than the default one, by the
+//! content type of their response or the tags the rules gave them, as
+//! `backend.route` configures.
+//!
+//! Documents are routed as they are built, before being queued, and record
+//! the index they were routed to as `routed_index`. Documents no route
+//! matches are left to the default index and record none.
+
+use crate::config::Route;
+use crate::headers::Headers;
+
+impl Route {
+    fn matches(&self, content_type: Option<&str>, tags: &[String]) -> bool {
+        if !self.content_types.is_empty() {
+            let content_type = content_type.map(|c| c.trim().to_ascii_lowercase());
+            let matched = content_type.is_some_and(|content_type| {
+                self.content_types
+                    .iter()
+                    .any(|prefix| content_type.starts_with(&prefix.trim().to_ascii_lowercase()))
+            });
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(tag) = &self.tag {
+            if !tags.contains(tag) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Index of the first of `routes` matching a transaction, `None` when the
+/// document stays in the default index.
+pub fn route(routes: &[Route], headers: &Headers, tags: &[String]) -> Option<String> {
+    let content_type = headers.get("Content-Type");
+    routes
+        .iter()
+        .find(|route| route.matches(content_type, tags))
+        .map(|route| route.index.clone())
+}
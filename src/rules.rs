@@ -0,0 +1,392 @@
+//! Filtering, redaction and tagging rules, kept in their own TOML file so
+//! they can be edited apart from the main configuration, and reloaded on
+//! their own through `reload_rules()`.
+//!
+//! Rules are checked in order when a transaction starts, against its uri
+//! and the response headers received so far. Every matching rule applies
+//! its actions, until one with `last = true` matches:
+//!
+//! ```toml
+//! [[rule]]
+//! name = "static assets"
+//! host = "*.cdn.example.com"
+//! content_type = "image/"
+//! skip_persist = true
+//! last = true
+//!
+//! [[rule]]
+//! name = "embedded"
+//! fetch_kind = "document"
+//! referer_host = "*.partner.example.com"
+//! tags = ["embedded"]
+//!
+//! [[rule]]
+//! name = "sessions"
+//! path = "^/(login|account)"
+//! status = "200-299"
+//! redact_headers = ["Set-Cookie"]
+//! tags = ["session"]
+//! trace = true
+//!
+//! [[rule]]
+//! name = "downloads"
+//! host = "downloads.example.com"
+//! deadline = 1800
+//! ```
+
+use crate::config::{self, ConfigError};
+use crate::headers::Headers;
+use crate::navigation::{self, FetchKind};
+use crate::transaction::STATUS_HEADER;
+use crate::uri;
+use log::info;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    rule: Vec<RuleSpec>,
+}
+
+/// A rule as written in the file. Conditions left out match anything.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    name: Option<String>,
+    /// Host glob, where `*` matches any run of characters.
+    host: Option<String>,
+    /// Regular expression searched in the uri path.
+    path: Option<String>,
+    /// Content type prefix.
+    content_type: Option<String>,
+    /// Status code, or inclusive range such as `500-599`.
+    status: Option<String>,
+    /// What the request fetched, see `FetchKind`.
+    fetch_kind: Option<String>,
+    /// Glob of the host of the `Referer` header.
+    referer_host: Option<String>,
+    #[serde(default)]
+    skip_persist: bool,
+    /// Headers whose values are replaced before persistence.
+    #[serde(default)]
+    redact_headers: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    trace: bool,
+    /// Seconds the transaction may take, instead of
+    /// `limits.transaction_deadline`.
+    deadline: Option<u64>,
+    /// Stops checking the following rules once this one matched.
+    #[serde(default)]
+    last: bool,
+}
+
+struct Rule {
+    host: Option<Regex>,
+    path: Option<Regex>,
+    content_type: Option<String>,
+    status: Option<RangeInclusive<u16>>,
+    fetch_kind: Option<FetchKind>,
+    referer_host: Option<Regex>,
+    actions: Actions,
+    last: bool,
+}
+
+/// What the matching rules ask for a transaction.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Actions {
+    pub skip_persist: bool,
+    /// Lowercase names of the headers to redact.
+    pub redact_headers: Vec<String>,
+    pub tags: Vec<String>,
+    pub trace: bool,
+    /// Deadline of the transaction in seconds, set by the last matching
+    /// rule with one.
+    pub deadline: Option<u64>,
+}
+
+impl Actions {
+    fn merge(&mut self, other: &Actions) {
+        self.skip_persist |= other.skip_persist;
+        self.trace |= other.trace;
+        if other.deadline.is_some() {
+            self.deadline = other.deadline;
+        }
+        for header in &other.redact_headers {
+            if !self.redact_headers.contains(header) {
+                self.redact_headers.push(header.clone());
+            }
+        }
+        for tag in &other.tags {
+            if !self.tags.contains(tag) {
+                self.tags.push(tag.clone());
+            }
+        }
+    }
+
+    pub fn redacts(&self, header: &str) -> bool {
+        self.redact_headers
+            .iter()
+            .any(|redacted| redacted.eq_ignore_ascii_case(header))
+    }
+}
+
+/// Rules compiled from a file.
+#[derive(Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+fn glob(pattern: &str) -> Result<Regex, regex::Error> {
+    let mut expression = String::from("^");
+    for part in pattern.split('*') {
+        if expression.len() > 1 {
+            expression.push_str(".*");
+        }
+        expression.push_str(&regex::escape(part));
+    }
+    expression.push('$');
+    RegexBuilder::new(&expression)
+        .case_insensitive(true)
+        .build()
+}
+
+fn status_range(status: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = match status.split_once('-') {
+        Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+        None => {
+            let status = status.trim().parse().ok()?;
+            (status, status)
+        }
+    };
+    if start <= end {
+        Some(start..=end)
+    } else {
+        None
+    }
+}
+
+impl RuleSpec {
+    fn compile(self, index: usize, errors: &mut Vec<ConfigError>) -> Option<Rule> {
+        // Rules are numbered from 1, as they appear in the file.
+        let label = match &self.name {
+            Some(name) => format!("rule {} ({})", index + 1, name),
+            None => format!("rule {}", index + 1),
+        };
+        let mut failed = false;
+        let mut fail = |field: &str, reason: String| {
+            errors.push(ConfigError::new(&format!("{}: {}", label, field), reason));
+            failed = true;
+        };
+
+        let host = match self.host.as_deref().map(glob).transpose() {
+            Ok(host) => host,
+            Err(e) => {
+                fail("host", e.to_string());
+                None
+            }
+        };
+        let path = match self.path.as_deref().map(Regex::new).transpose() {
+            Ok(path) => path,
+            Err(e) => {
+                fail("path", e.to_string());
+                None
+            }
+        };
+        let status = match &self.status {
+            Some(status) => match status_range(status) {
+                Some(range) => Some(range),
+                None => {
+                    fail(
+                        "status",
+                        format!(
+                            "\"{}\" is neither a status code nor a range like 500-599",
+                            status
+                        ),
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        let fetch_kind = match &self.fetch_kind {
+            Some(kind) => match FetchKind::parse(kind) {
+                Some(kind) => Some(kind),
+                None => {
+                    fail(
+                        "fetch_kind",
+                        format!(
+                            "\"{}\" is none of document, script, style, image or xhr",
+                            kind
+                        ),
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        let referer_host = match self.referer_host.as_deref().map(glob).transpose() {
+            Ok(referer_host) => referer_host,
+            Err(e) => {
+                fail("referer_host", e.to_string());
+                None
+            }
+        };
+        if self.deadline == Some(0) {
+            fail("deadline", "must be at least 1".to_string());
+        }
+        if !self.skip_persist
+            && self.redact_headers.is_empty()
+            && self.tags.is_empty()
+            && !self.trace
+            && self.deadline.is_none()
+        {
+            fail("actions", "the rule has no action".to_string());
+        }
+        if failed {
+            return None;
+        }
+
+        Some(Rule {
+            host,
+            path,
+            content_type: self.content_type.map(|prefix| prefix.to_ascii_lowercase()),
+            status,
+            fetch_kind,
+            referer_host,
+            actions: Actions {
+                skip_persist: self.skip_persist,
+                redact_headers: self
+                    .redact_headers
+                    .iter()
+                    .map(|header| header.to_ascii_lowercase())
+                    .collect(),
+                tags: self.tags,
+                trace: self.trace,
+                deadline: self.deadline,
+            },
+            last: self.last,
+        })
+    }
+}
+
+impl Rule {
+    fn matches(&self, host: Option<&str>, path: &str, headers: &Headers) -> bool {
+        if let Some(pattern) = &self.host {
+            if !host.is_some_and(|host| pattern.is_match(host)) {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.path {
+            if !pattern.is_match(path) {
+                return false;
+            }
+        }
+        if let Some(prefix) = &self.content_type {
+            let content_type = headers.get("Content-Type").map(|c| c.to_ascii_lowercase());
+            if !content_type.is_some_and(|content_type| content_type.starts_with(prefix.as_str())) {
+                return false;
+            }
+        }
+        if let Some(range) = &self.status {
+            let status = headers
+                .get(STATUS_HEADER)
+                .and_then(|status| status.trim().parse().ok());
+            if !status.is_some_and(|status| range.contains(&status)) {
+                return false;
+            }
+        }
+        if let Some(kind) = self.fetch_kind {
+            if FetchKind::of(headers) != Some(kind) {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.referer_host {
+            let referer_host = headers.get("Referer").and_then(navigation::referer_host);
+            if !referer_host.is_some_and(|host| pattern.is_match(&host)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Rules {
+    /// Compiles the rules file at `path`. All invalid rules are reported,
+    /// each named by its position and name.
+    pub fn load(path: &Path) -> Result<Rules, Vec<ConfigError>> {
+        let location = path.display().to_string();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| vec![ConfigError::new(&location, e.to_string())])?;
+        let file: File = toml::from_str(&content).map_err(|e| {
+            vec![ConfigError::new(
+                &location,
+                e.to_string().trim_end().replace('\n', " "),
+            )]
+        })?;
+
+        let mut errors = Vec::new();
+        let rules: Vec<Rule> = file
+            .rule
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, spec)| spec.compile(index, &mut errors))
+            .collect();
+        if errors.is_empty() {
+            Ok(Rules { rules })
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Actions of the rules matching a transaction, in rule order.
+    pub fn evaluate(&self, uri: &str, headers: &Headers) -> Actions {
+        let mut actions = Actions::default();
+        if self.rules.is_empty() {
+            return actions;
+        }
+        let parsed = uri::parse(uri);
+        let host = parsed.host_or(headers.get("Host"));
+        for rule in &self.rules {
+            if rule.matches(host.as_deref(), parsed.path, headers) {
+                actions.merge(&rule.actions);
+                if rule.last {
+                    break;
+                }
+            }
+        }
+        actions
+    }
+}
+
+static RULES: RwLock<Option<Arc<Rules>>> = RwLock::new(None);
+
+/// The rules in use, none until `reload()` succeeded.
+pub fn get() -> Arc<Rules> {
+    RULES
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| Arc::new(Rules::default()))
+}
+
+/// Compiles the rules file of the configuration and installs it. On errors
+/// the rules in use are kept.
+pub fn reload() -> Result<(), Vec<ConfigError>> {
+    let rules = match &config::get().filters.rules {
+        Some(path) => {
+            let rules = Rules::load(path)?;
+            info!("Loaded {} rules from {}", rules.rules.len(), path.display());
+            rules
+        }
+        None => Rules::default(),
+    };
+    *RULES.write().unwrap() = Some(Arc::new(rules));
+    Ok(())
+}
@@ -0,0 +1,230 @@
+use crate::config;
+use crate::document::Body;
+use crate::transaction::Transaction;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::prelude::*;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_DETAIL_LENGTH: usize = 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The scanner built from the configuration in use, along with the scanner
+/// configuration it was built from.
+type SharedScanner = (config::Scanner, Option<Arc<ExternalScanner>>);
+
+static SCANNER: Mutex<Option<SharedScanner>> = Mutex::new(None);
+static ACTIVE_PROCESSES: AtomicUsize = AtomicUsize::new(0);
+
+/// Outcome of scanning a body, persisted as `scanner_result`.
+#[derive(Deserialize, Serialize)]
+pub struct ScanResult {
+    /// One of `clean`, `flagged`, `error`, `timeout` or `skipped`.
+    pub verdict: String,
+    /// First bytes of the scanner's output.
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+}
+
+impl ScanResult {
+    fn new(verdict: &str, detail: String, exit_code: Option<i32>) -> Self {
+        ScanResult {
+            verdict: verdict.to_string(),
+            detail,
+            exit_code,
+        }
+    }
+}
+
+enum Outcome {
+    Exited(ExitStatus),
+    TimedOut,
+    Failed(String),
+}
+
+/// Analyzer piping decoded bodies through an external command line scanner.
+///
+/// Exit code 0 means clean and 1 means flagged, following the clamdscan
+/// convention; anything else is reported as an error.
+pub struct ExternalScanner {
+    command: String,
+    timeout: Duration,
+    max_processes: usize,
+    content_types: Vec<String>,
+}
+
+/// Returns the configured scanner, if any. It is only rebuilt when the
+/// scanner configuration changes.
+pub fn get() -> Option<Arc<ExternalScanner>> {
+    let config = config::get();
+    let mut shared = match SCANNER.lock() {
+        Ok(shared) => shared,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if let Some((built_from, scanner)) = shared.as_ref() {
+        if *built_from == config.scanner {
+            return scanner.clone();
+        }
+    }
+    let scanner = ExternalScanner::from_config(&config.scanner).map(Arc::new);
+    *shared = Some((config.scanner.clone(), scanner.clone()));
+    scanner
+}
+
+impl ExternalScanner {
+    fn from_config(scanner: &config::Scanner) -> Option<Self> {
+        let command = scanner.command.clone()?;
+        let timeout = Duration::from_millis(scanner.timeout_ms);
+        let max_processes = scanner.max_processes;
+        let content_types = scanner
+            .content_types
+            .iter()
+            .map(|content_type| content_type.trim().to_ascii_lowercase())
+            .filter(|content_type| !content_type.is_empty())
+            .collect();
+
+        info!("External scanner enabled: {}", command);
+        Some(ExternalScanner {
+            command,
+            timeout,
+            max_processes,
+            content_types,
+        })
+    }
+
+    /// Whether the body of a transaction should be scanned.
+    pub fn matches(&self, transaction: &Transaction) -> bool {
+        if self.content_types.is_empty() {
+            return true;
+        }
+
+        match transaction.headers.get("Content-Type") {
+            Some(content_type) => {
+                let content_type = content_type.to_ascii_lowercase();
+                self.content_types
+                    .iter()
+                    .any(|prefix| content_type.starts_with(prefix))
+            }
+            None => false,
+        }
+    }
+
+    /// Scans a body, never running more than the configured number of
+    /// scanner processes at once.
+    pub fn scan(&self, id: i64, uri: &str, body: Body) -> ScanResult {
+        if ACTIVE_PROCESSES.fetch_add(1, Ordering::SeqCst) >= self.max_processes {
+            ACTIVE_PROCESSES.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "Skipping scan of transaction {}: {} scanner processes already running",
+                id, self.max_processes
+            );
+            return ScanResult::new("skipped", "".to_string(), None);
+        }
+
+        let result = self.run(id, uri, body);
+        ACTIVE_PROCESSES.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    fn run(&self, id: i64, uri: &str, body: Body) -> ScanResult {
+        let command = self.command.replace("{id}", &id.to_string());
+        let mut child = match Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .env("PRISM_URI", uri)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed starting scanner for transaction {}: {}", id, e);
+                return ScanResult::new("error", e.to_string(), None);
+            }
+        };
+
+        // Feed and drain the child from separate threads, so a scanner that
+        // stops reading can't block us past the timeout.
+        let mut stdin = child.stdin.take();
+        thread::spawn(move || {
+            if let Some(stdin) = stdin.as_mut() {
+                let _ = stdin.write_all(body.bytes());
+            }
+        });
+        let mut stdout = child.stdout.take();
+        let (read, output) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            if let Some(stdout) = stdout.as_mut() {
+                // Only the detail is kept, the rest is read and dropped so
+                // the scanner never blocks writing it.
+                let limit = MAX_DETAIL_LENGTH as u64;
+                let _ = stdout.by_ref().take(limit).read_to_end(&mut output);
+                let _ = io::copy(stdout, &mut io::sink());
+            }
+            let _ = read.send(output);
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let outcome = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Outcome::Exited(status),
+                Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                Ok(None) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    break Outcome::TimedOut;
+                }
+                Err(e) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    break Outcome::Failed(e.to_string());
+                }
+            }
+        };
+
+        match outcome {
+            Outcome::Exited(status) => {
+                // Processes the scanner left behind can hold its stdout open
+                // past its exit, so the output is only waited for until the
+                // timeout.
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let output = output.recv_timeout(remaining).unwrap_or_else(|_| {
+                    warn!(
+                        "Scanner output of transaction {} still open after {:?}, leaving it out",
+                        id, self.timeout
+                    );
+                    Vec::new()
+                });
+                let detail = String::from_utf8_lossy(&output).trim().to_string();
+                let verdict = match status.code() {
+                    Some(0) => "clean",
+                    Some(1) => "flagged",
+                    _ => "error",
+                };
+                ScanResult::new(verdict, detail, status.code())
+            }
+            Outcome::TimedOut => {
+                warn!(
+                    "Scanner timed out after {:?} for transaction {}",
+                    self.timeout, id
+                );
+                ScanResult::new("timeout", "".to_string(), None)
+            }
+            Outcome::Failed(reason) => {
+                warn!(
+                    "Failed waiting for scanner of transaction {}: {}",
+                    id, reason
+                );
+                ScanResult::new("error", reason, None)
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::clock;
+use crate::config;
+use crate::headers::PendingHeaders;
+use crate::logging::throttled;
+use crate::metrics;
+use log::{info, Level};
+use std::cmp::max;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two checks of the registry's capacity.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the maps must stay mostly empty before they are shrunk.
+const SHRINK_AFTER: Duration = Duration::from_secs(30);
+/// Maps holding less than a quarter of their capacity are mostly empty.
+const LOW_FRACTION: usize = 4;
+
+/// Gives back the capacity the registry maps grew to during a traffic
+/// burst, once the burst is over, and drops the headers of transactions
+/// that never began after `limits.pending_headers_timeout`.
+///
+/// Like the watchdog, it runs from the entry points, checking at most once
+/// per `CHECK_INTERVAL`, so it is covered by the registry lock. The floor,
+/// `limits.registry_capacity_floor`, and the timeout are read on every
+/// check, so reloading them applies from the next one.
+pub struct Shrinker {
+    last_check: Instant,
+    /// Since when the maps have been mostly empty.
+    low_since: Option<Instant>,
+}
+
+impl Shrinker {
+    pub fn new() -> Self {
+        Shrinker {
+            last_check: clock::now(),
+            low_since: None,
+        }
+    }
+
+    pub fn check<A>(
+        &mut self,
+        responses: &mut HashMap<i64, A>,
+        headers: &mut HashMap<i64, PendingHeaders>,
+    ) {
+        self.check_at(clock::now(), responses, headers);
+    }
+
+    fn check_at<A>(
+        &mut self,
+        now: Instant,
+        responses: &mut HashMap<i64, A>,
+        headers: &mut HashMap<i64, PendingHeaders>,
+    ) {
+        if now.duration_since(self.last_check) < CHECK_INTERVAL {
+            return;
+        }
+        self.last_check = now;
+        let config = config::get();
+        let limits = &config.limits;
+        if let Some(timeout) = limits.pending_headers_timeout {
+            expire(now, Duration::from_secs(timeout), headers);
+        }
+
+        let floor = limits.registry_capacity_floor;
+        if !oversized(responses, floor) && !oversized(headers, floor) {
+            self.low_since = None;
+            return;
+        }
+        let since = *self.low_since.get_or_insert(now);
+        if now.duration_since(since) < SHRINK_AFTER {
+            return;
+        }
+        self.low_since = None;
+
+        let before = (responses.capacity(), headers.capacity());
+        responses.shrink_to(max(responses.len() * 2, floor));
+        headers.shrink_to(max(headers.len() * 2, floor));
+        info!(
+            "Shrunk the transaction registry from capacities {} & {} to {} & {}",
+            before.0,
+            before.1,
+            responses.capacity(),
+            headers.capacity()
+        );
+    }
+}
+
+/// Whether `map` holds more than `floor` and is mostly empty.
+fn oversized<V>(map: &HashMap<i64, V>, floor: usize) -> bool {
+    map.capacity() > floor && map.len() * LOW_FRACTION < map.capacity()
+}
+
+/// Drops the headers that waited longer than `timeout` for their
+/// transaction to begin.
+fn expire(now: Instant, timeout: Duration, headers: &mut HashMap<i64, PendingHeaders>) {
+    let before = headers.len();
+    headers.retain(|_, pending| now.duration_since(pending.received) < timeout);
+    let expired = before - headers.len();
+    if expired > 0 {
+        metrics::add(&metrics::COUNTERS.pending_headers_expired, expired);
+        throttled!(
+            Level::Warn,
+            "pending-headers",
+            "Dropped the headers of {} transactions that did not begin within {}s",
+            expired,
+            timeout.as_secs()
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use crate::config::Limits;
+use crate::encryption::{self, Key};
+use log::{error, info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+struct SpoolFile {
+    path: PathBuf,
+    file: File,
+    /// Key the chunks are sealed with, one record each, when encryption is
+    /// on.
+    key: Option<Arc<Key>>,
+}
+
+impl SpoolFile {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match &self.key {
+            Some(_) if data.is_empty() => Ok(()),
+            Some(key) => self.file.write_all(&key.seal(data)),
+            None => self.file.write_all(data),
+        }
+    }
+
+    /// Reads back the body from its start, up to `limit` bytes or more as
+    /// sealed chunks are read whole, into a buffer of `capacity` bytes.
+    fn read(&self, limit: usize, capacity: usize) -> std::io::Result<Vec<u8>> {
+        let file = File::open(&self.path)?;
+        match &self.key {
+            Some(key) => {
+                encryption::open(BufReader::new(file), std::slice::from_ref(&**key), limit)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            }
+            None => {
+                let mut body = Vec::with_capacity(capacity);
+                file.take(limit as u64).read_to_end(&mut body)?;
+                Ok(body)
+            }
+        }
+    }
+}
+
+/// Captured body of a transaction.
+///
+/// Data is buffered in memory until it crosses the spill threshold, after
+/// which everything captured so far is moved to a temp file and further
+/// writes are appended to it. The file is removed when the spool is dropped.
+pub struct Spool {
+    id: i64,
+    dir: PathBuf,
+    threshold: usize,
+    /// Size past which capturing stops, see `Limits::max_body_size`.
+    max_size: Option<usize>,
+    memory: Vec<u8>,
+    file: Option<SpoolFile>,
+    size: usize,
+    /// Bytes written to the spool, captured or not.
+    pub written: usize,
+    /// Set when capturing stopped early, because the body reached the
+    /// maximum size or the spool file could not be created or written to
+    /// (e.g. disk full). The body is then truncated.
+    pub truncated: bool,
+}
+
+impl Spool {
+    pub fn new(id: i64, limits: &Limits) -> Self {
+        Spool {
+            id,
+            dir: match &limits.spool_dir {
+                Some(dir) => dir.clone(),
+                None => std::env::temp_dir(),
+            },
+            threshold: limits.spill_threshold,
+            max_size: limits.max_body_size,
+            memory: Vec::new(),
+            file: None,
+            size: 0,
+            written: 0,
+            truncated: false,
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        self.written += data.len();
+        if self.truncated {
+            return;
+        }
+
+        match self.max_size {
+            Some(max_size) if self.size + data.len() > max_size => {
+                self.append(&data[0..max_size - self.size]);
+                if !self.truncated {
+                    info!(
+                        "Transaction {} body is larger than {} bytes, capture is truncated",
+                        self.id, max_size
+                    );
+                    self.truncated = true;
+                }
+            }
+            _ => self.append(data),
+        }
+    }
+
+    /// Stops capturing, keeping what was captured so far as a truncated
+    /// body.
+    pub fn stop(&mut self) {
+        self.truncated = true;
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        if self.file.is_none() && self.memory.len() + data.len() > self.threshold {
+            self.spill();
+            if self.truncated {
+                return;
+            }
+        }
+
+        match &mut self.file {
+            Some(spool_file) => match spool_file.write(data) {
+                Ok(()) => {
+                    self.size += data.len();
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed writing to spool file {} for transaction {}, body will be truncated at {} bytes: {}",
+                        spool_file.path.display(),
+                        self.id,
+                        self.size,
+                        e
+                    );
+                    self.truncated = true;
+                }
+            },
+            None => {
+                self.memory.extend_from_slice(data);
+                self.size += data.len();
+            }
+        }
+    }
+
+    fn spill(&mut self) {
+        let path = self
+            .dir
+            .join(format!("prism-{}-{}.body", std::process::id(), self.id));
+        let result = encryption::key()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+            .and_then(|key| {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&path)?;
+                let mut spool_file = SpoolFile {
+                    path: path.clone(),
+                    file,
+                    key,
+                };
+                spool_file.write(&self.memory)?;
+                Ok(spool_file)
+            });
+
+        match result {
+            Ok(spool_file) => {
+                info!(
+                    "Transaction {} spilled {} bytes to {}",
+                    self.id,
+                    self.memory.len(),
+                    path.display()
+                );
+                self.memory = Vec::new();
+                self.file = Some(spool_file);
+            }
+            Err(e) => {
+                warn!(
+                    "Failed spilling transaction {} to {}, body will be truncated at {} bytes: {}",
+                    self.id,
+                    path.display(),
+                    self.size,
+                    e
+                );
+                let _ = std::fs::remove_file(&path);
+                self.truncated = true;
+            }
+        }
+    }
+
+    /// Bytes of the captured body held in memory, as opposed to on disk.
+    pub fn memory_size(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// Moves the captured body out of the spool, reading it back from disk
+    /// if it has been spilled. The spool is left empty, so the body is never
+    /// held twice.
+    pub fn take_body(&mut self) -> Vec<u8> {
+        match &self.file {
+            Some(spool_file) => self.read_file(spool_file),
+            None => std::mem::take(&mut self.memory),
+        }
+    }
+
+    /// Calls `f` with at most the first `limit` bytes of the captured body,
+    /// reading only that much back from disk if it has been spilled.
+    pub fn with_head<R>(&self, limit: usize, f: impl FnOnce(&[u8]) -> R) -> R {
+        match &self.file {
+            Some(spool_file) => {
+                let head = spool_file
+                    .read(limit, limit.min(self.size))
+                    .unwrap_or_else(|e| {
+                        error!(
+                            "Failed reading spool file {} for transaction {}: {}",
+                            spool_file.path.display(),
+                            self.id,
+                            e
+                        );
+                        Vec::new()
+                    });
+                f(&head[0..limit.min(head.len())])
+            }
+            None => f(&self.memory[0..limit.min(self.memory.len())]),
+        }
+    }
+
+    /// Reads back a spilled body from disk.
+    fn read_file(&self, spool_file: &SpoolFile) -> Vec<u8> {
+        match spool_file.read(usize::MAX, self.size) {
+            Ok(body) => body,
+            Err(e) => {
+                error!(
+                    "Failed reading spool file {} for transaction {}: {}",
+                    spool_file.path.display(),
+                    self.id,
+                    e
+                );
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Some(spool_file) = &self.file {
+            if let Err(e) = std::fs::remove_file(&spool_file.path) {
+                warn!(
+                    "Failed removing spool file {} for transaction {}: {}",
+                    spool_file.path.display(),
+                    self.id,
+                    e
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::config;
+use crate::metrics;
+use log::info;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+/// Number of hosts included in the periodic log line.
+const LOGGED_HOSTS: usize = 10;
+
+#[derive(Default)]
+struct HostEntry {
+    requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    errors: u64,
+    total_duration: Duration,
+    /// Value of the `HostStats` clock when the host was last updated.
+    last_seen: u64,
+}
+
+struct HostStats {
+    hosts: HashMap<String, HostEntry>,
+    max_hosts: usize,
+    clock: u64,
+}
+
+/// Per-host aggregates of completed transactions.
+static HOST_STATS: Mutex<Option<HostStats>> = Mutex::new(None);
+static LOG_TICKER: Once = Once::new();
+
+/// A finished transaction, as accounted for in the host aggregates.
+pub struct Sample<'a> {
+    pub host: &'a str,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub error: bool,
+    pub duration: Duration,
+}
+
+#[derive(Serialize)]
+pub struct HostSummary {
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub errors: u64,
+    pub average_duration_ms: f64,
+}
+
+impl From<&HostEntry> for HostSummary {
+    fn from(entry: &HostEntry) -> Self {
+        let average = match entry.requests {
+            0 => 0.0,
+            requests => entry.total_duration.as_secs_f64() * 1000.0 / requests as f64,
+        };
+        HostSummary {
+            requests: entry.requests,
+            bytes_in: entry.bytes_in,
+            bytes_out: entry.bytes_out,
+            errors: entry.errors,
+            average_duration_ms: average,
+        }
+    }
+}
+
+/// How many hosts are tracked at once. Past that, the least recently seen
+/// host is dropped.
+fn max_hosts() -> usize {
+    config::get().limits.stats_max_hosts.max(1)
+}
+
+fn with_stats<R>(f: impl FnOnce(&mut HostStats) -> R) -> R {
+    let mut stats = HOST_STATS.lock().unwrap();
+    let stats = stats.get_or_insert_with(|| HostStats {
+        hosts: HashMap::new(),
+        max_hosts: max_hosts(),
+        clock: 0,
+    });
+    f(stats)
+}
+
+impl HostStats {
+    fn evict(&mut self) {
+        let oldest = self
+            .hosts
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_seen)
+            .map(|(host, _)| host.clone());
+        if let Some(host) = oldest {
+            self.hosts.remove(&host);
+            metrics::increment(&metrics::COUNTERS.evictions);
+        }
+    }
+}
+
+/// Accounts a finished transaction to its host.
+pub fn record(sample: Sample) {
+    with_stats(|stats| {
+        stats.clock += 1;
+        if !stats.hosts.contains_key(sample.host) {
+            if stats.hosts.len() >= stats.max_hosts {
+                stats.evict();
+            }
+            stats
+                .hosts
+                .insert(sample.host.to_string(), HostEntry::default());
+        }
+
+        let clock = stats.clock;
+        let entry = stats.hosts.get_mut(sample.host).unwrap();
+        entry.requests += 1;
+        entry.bytes_in += sample.bytes_in as u64;
+        entry.bytes_out += sample.bytes_out as u64;
+        if sample.error {
+            entry.errors += 1;
+        }
+        entry.total_duration += sample.duration;
+        entry.last_seen = clock;
+    })
+}
+
+/// Snapshot of the tracked hosts, ordered by name.
+pub fn hosts() -> BTreeMap<String, HostSummary> {
+    with_stats(|stats| {
+        stats
+            .hosts
+            .iter()
+            .map(|(host, entry)| (host.clone(), HostSummary::from(entry)))
+            .collect()
+    })
+}
+
+fn log_busiest() {
+    let mut hosts: Vec<(String, HostSummary)> = hosts().into_iter().collect();
+    hosts.sort_by(|a, b| b.1.requests.cmp(&a.1.requests));
+    let busiest: Vec<String> = hosts
+        .iter()
+        .take(LOGGED_HOSTS)
+        .map(|(host, summary)| {
+            format!(
+                "{} ({} requests, {} bytes in, {} bytes out, {} errors, {:.1}ms avg)",
+                host,
+                summary.requests,
+                summary.bytes_in,
+                summary.bytes_out,
+                summary.errors,
+                summary.average_duration_ms
+            )
+        })
+        .collect();
+    info!(
+        "Host stats: {} hosts tracked, busiest: {}",
+        hosts.len(),
+        busiest.join(", ")
+    );
+}
+
+/// Starts logging the busiest hosts periodically, if configured to.
+pub fn start_log_ticker() {
+    let interval = match config::get().telemetry.stats_log_interval {
+        Some(seconds) if seconds > 0 => Duration::from_secs(seconds),
+        _ => return,
+    };
+
+    LOG_TICKER.call_once(|| {
+        thread::Builder::new()
+            .name("prism-stats".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                log_busiest();
+            })
+            .unwrap();
+    });
+}
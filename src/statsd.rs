@@ -0,0 +1,151 @@
+use crate::config;
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::net::UdpSocket;
+use std::sync::{Mutex, Once, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Largest datagram sent, to stay below common MTUs.
+const MAX_DATAGRAM: usize = 1432;
+/// How often buffered metrics are sent out.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Minimum time between two logged emission failures.
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    tags: bool,
+    buffer: Mutex<String>,
+    last_error: Mutex<Option<Instant>>,
+}
+
+static STATSD: OnceLock<Option<Statsd>> = OnceLock::new();
+static FLUSHER: Once = Once::new();
+
+impl Statsd {
+    fn from_config() -> Option<Self> {
+        let config = config::get();
+        let telemetry = &config.telemetry;
+        let address = telemetry.statsd_address.clone().filter(|_| !cfg!(miri))?;
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(&address).map(|()| socket))
+            .and_then(|socket| socket.set_nonblocking(true).map(|()| socket));
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Failed setting up StatsD emission to {}: {}", address, e);
+                return None;
+            }
+        };
+
+        info!("Emitting StatsD metrics to {}", address);
+        Some(Statsd {
+            socket,
+            prefix: telemetry.statsd_prefix.clone(),
+            tags: telemetry.statsd_tags,
+            buffer: Mutex::new(String::with_capacity(MAX_DATAGRAM)),
+            last_error: Mutex::new(None),
+        })
+    }
+
+    fn line(&self, name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) -> String {
+        let mut line = format!("{}{}:{}|{}", self.prefix, name, value, kind);
+        if self.tags && !tags.is_empty() {
+            line.push_str("|#");
+            for (index, (tag, value)) in tags.iter().enumerate() {
+                if index > 0 {
+                    line.push(',');
+                }
+                let _ = write!(line, "{}:{}", tag, value);
+            }
+        }
+        line
+    }
+
+    fn emit(&self, line: String) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if !buffer.is_empty() && buffer.len() + 1 + line.len() > MAX_DATAGRAM {
+            self.send(&mut buffer);
+        }
+        if !buffer.is_empty() {
+            buffer.push('\n');
+        }
+        buffer.push_str(&line);
+    }
+
+    fn send(&self, buffer: &mut String) {
+        if buffer.is_empty() {
+            return;
+        }
+        if let Err(e) = self.socket.send(buffer.as_bytes()) {
+            let mut last_error = self.last_error.lock().unwrap();
+            let log = match *last_error {
+                Some(logged) => logged.elapsed() >= ERROR_LOG_INTERVAL,
+                None => true,
+            };
+            if log {
+                warn!("Failed emitting StatsD metrics: {}", e);
+                *last_error = Some(Instant::now());
+            }
+        }
+        buffer.clear();
+    }
+
+    fn flush(&self) {
+        self.send(&mut self.buffer.lock().unwrap());
+    }
+}
+
+fn get() -> Option<&'static Statsd> {
+    STATSD.get_or_init(Statsd::from_config).as_ref()
+}
+
+/// Sets up emission, if configured, along with the thread flushing
+/// buffered metrics.
+pub fn init() {
+    if get().is_some() {
+        FLUSHER.call_once(|| {
+            thread::Builder::new()
+                .name("prism-statsd".to_string())
+                .spawn(|| loop {
+                    thread::sleep(FLUSH_INTERVAL);
+                    flush();
+                })
+                .unwrap();
+        });
+    }
+}
+
+pub fn enabled() -> bool {
+    get().is_some()
+}
+
+/// Sends out buffered metrics.
+pub fn flush() {
+    if let Some(statsd) = STATSD.get().and_then(Option::as_ref) {
+        statsd.flush();
+    }
+}
+
+pub fn count(name: &str, value: u64, tags: &[(&str, &str)]) {
+    if let Some(statsd) = get() {
+        statsd.emit(statsd.line(name, &value.to_string(), "c", tags));
+    }
+}
+
+pub fn timing(name: &str, duration: Duration, tags: &[(&str, &str)]) {
+    if let Some(statsd) = get() {
+        let milliseconds = format!("{:.3}", duration.as_secs_f64() * 1000.0);
+        statsd.emit(statsd.line(name, &milliseconds, "ms", tags));
+    }
+}
+
+/// Class of a status code as used in tags, such as `2xx`.
+pub fn status_class(status: Option<u16>) -> String {
+    match status {
+        Some(status) => format!("{}xx", status / 100),
+        None => "unknown".to_string(),
+    }
+}
@@ -0,0 +1,111 @@
+use crate::config;
+use crate::metrics::{self, get};
+use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the ticker checks whether it should stop.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static TICKER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Counter values at the previous summary.
+#[derive(Clone, Copy, Default, PartialEq)]
+struct Totals {
+    completed: u64,
+    aborted: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    persist_failures: u64,
+    decode_errors: u64,
+    dropped_chunks: u64,
+    evictions: u64,
+    panics: u64,
+}
+
+impl Totals {
+    fn now() -> Self {
+        Totals {
+            completed: get(&metrics::TRANSACTIONS_COMPLETED),
+            aborted: get(&metrics::TRANSACTIONS_ABORTED),
+            bytes_in: get(&metrics::BYTES_RECEIVED),
+            bytes_out: get(&metrics::BYTES_SENT),
+            persist_failures: metrics::persist_failures(),
+            decode_errors: get(&metrics::COUNTERS.decode_errors),
+            dropped_chunks: get(&metrics::COUNTERS.dropped_chunks),
+            evictions: get(&metrics::COUNTERS.evictions),
+            panics: get(&metrics::COUNTERS.panics),
+        }
+    }
+}
+
+fn summarize(interval: Duration, previous: Totals, current: Totals) {
+    let largest_in_flight = metrics::LARGEST_IN_FLIGHT.swap(0, Ordering::Relaxed);
+    if current == previous {
+        return;
+    }
+
+    info!(
+        "Summary interval={}s completed={} aborted={} bytes_in={} bytes_out={} persist_failures={} decode_errors={} dropped_chunks={} evictions={} panics={} active={} largest_in_flight={}",
+        interval.as_secs(),
+        current.completed - previous.completed,
+        current.aborted - previous.aborted,
+        current.bytes_in - previous.bytes_in,
+        current.bytes_out - previous.bytes_out,
+        current.persist_failures - previous.persist_failures,
+        current.decode_errors - previous.decode_errors,
+        current.dropped_chunks - previous.dropped_chunks,
+        current.evictions - previous.evictions,
+        current.panics - previous.panics,
+        metrics::active_transactions(),
+        largest_in_flight
+    );
+}
+
+fn tick(interval: Duration) {
+    let mut previous = Totals::now();
+    let mut last = Instant::now();
+    while !STOP.load(Ordering::Relaxed) {
+        thread::sleep(STOP_POLL_INTERVAL);
+        if last.elapsed() < interval {
+            continue;
+        }
+
+        let current = Totals::now();
+        summarize(interval, previous, current);
+        previous = current;
+        last = Instant::now();
+    }
+}
+
+/// Starts logging summaries, if an interval is configured.
+pub fn start() {
+    let interval = match config::get().telemetry.summary_interval {
+        Some(seconds) if seconds > 0 => Duration::from_secs(seconds),
+        _ => return,
+    };
+
+    let mut ticker = TICKER.lock().unwrap();
+    if ticker.is_some() {
+        return;
+    }
+
+    STOP.store(false, Ordering::Relaxed);
+    *ticker = Some(
+        thread::Builder::new()
+            .name("prism-summary".to_string())
+            .spawn(move || tick(interval))
+            .unwrap(),
+    );
+}
+
+/// Stops logging summaries and waits for the ticker to exit.
+pub fn stop() {
+    if let Some(ticker) = TICKER.lock().unwrap().take() {
+        STOP.store(true, Ordering::Relaxed);
+        let _ = ticker.join();
+    }
+}
@@ -0,0 +1,54 @@
+//! Export of transaction spans. Spans are always created through `tracing`,
+//! which costs next to nothing without a subscriber; they are only exported
+//! when built with the `otlp` feature and `PRISM_OTLP_ENDPOINT` is set.
+
+#[cfg(feature = "otlp")]
+pub fn init() {
+    use log::{info, warn};
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let endpoint = match &crate::config::get().telemetry.otlp_endpoint {
+        Some(endpoint) if !cfg!(miri) => endpoint.clone(),
+        _ => return,
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&endpoint),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
+            opentelemetry_sdk::Resource::new(vec![KeyValue::new("service.name", "prism")]),
+        ))
+        .install_simple();
+    let tracer = match tracer {
+        Ok(tracer) => tracer,
+        Err(e) => {
+            warn!("Failed setting up span export to {}: {}", endpoint, e);
+            return;
+        }
+    };
+
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    match tracing::subscriber::set_global_default(subscriber) {
+        Ok(()) => info!("Exporting transaction spans to {}", endpoint),
+        Err(e) => warn!("Failed installing span exporter: {}", e),
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn init() {}
+
+/// Exports spans still pending.
+#[cfg(feature = "otlp")]
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn shutdown() {}
@@ -1,168 +1,574 @@
-use log::{error, info};
-use std::cell::RefCell;
-use std::cmp::min;
-use std::io::prelude::*;
-use std::sync::mpsc::{channel, Receiver, SendError, Sender};
-use std::vec::Vec;
-use zstream::{Decoder, Encoder};
-
-const INPUT_BUFFER_SIZE: usize = 32 * 1024;
-const ENCODER_BUFFER_SIZE: usize = 1024 * 1024;
-
-struct BufferReader {
-    receiver: Receiver<Vec<u8>>,
-    pending: Vec<u8>,
-}
-
-impl Read for BufferReader {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let _n_data = match self.receiver.try_recv() {
-            Ok(data) => {
-                let n_data = data.len();
-                self.pending.extend(data);
-                n_data
-            }
-            Err(_) => 0,
-        };
+use crate::block;
+use crate::clock;
+use crate::config::{self, Config};
+use crate::decoding;
+use crate::error::PrismError;
+use crate::headers::Headers;
+use crate::logging::{self, event, throttled, trace_transaction, Fields};
+use crate::metrics;
+use crate::mode::Mode;
+use crate::observer;
+use crate::pipeline::{Footprint, Pipeline};
+use crate::recorder;
+use crate::rewrite::RewriteChain;
+use crate::rules::{self, Actions};
+use crate::uri;
+use chrono::{DateTime, Utc};
+use log::{warn, Level};
+use std::collections::BTreeMap;
+use std::sync::mpsc::SendError;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-        let to_transfer = min(buf.len(), self.pending.len());
-        let drained: Vec<u8> = self.pending.drain(0..to_transfer).collect();
-        buf[0..to_transfer].copy_from_slice(&drained[0..to_transfer]);
+/// Pseudo-header carrying the response status code.
+pub const STATUS_HEADER: &str = ":status";
+/// Pseudo-header carrying the address of the client.
+pub const CLIENT_HEADER: &str = ":client";
 
-        Ok(to_transfer)
+/// Whether transaction `id` is persisted under the sampling `rate`. The
+/// decision only depends on the id, so it is stable for a transaction.
+fn sampled(id: i64, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
     }
+    // Spreads consecutive ids over the whole range (splitmix64 finalizer).
+    let mut hash = id as u64;
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+    (hash % 10_000) < (rate * 10_000.0) as u64
 }
 
-pub struct RawDataReader {
-    pub reader: RefCell<Decoder>,
-    inner_buffer: RefCell<Vec<u8>>,
+/// Whether a response is known to have no body from its request method or
+/// status: responses to HEAD requests, 204 No Content and 304 Not Modified.
+fn expects_no_body(method: &str, headers: &Headers) -> bool {
+    let status = headers
+        .get(STATUS_HEADER)
+        .and_then(|status| status.trim().parse::<u16>().ok());
+    method.eq_ignore_ascii_case("HEAD") || matches!(status, Some(204 | 304))
 }
 
-impl RawDataReader {
-    pub fn new(reader: Decoder) -> Self {
-        RawDataReader {
-            reader: RefCell::new(reader),
-            inner_buffer: RefCell::new(Vec::<u8>::new()),
-        }
-    }
-
-    pub fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut temp_buf = vec![0; buf.len()];
-        let result = self.reader.borrow_mut().read(temp_buf.as_mut_slice());
-        match result {
-            Ok(bytes) => {
-                self.inner_buffer
-                    .borrow_mut()
-                    .extend(temp_buf[0..bytes].to_vec());
-                buf.copy_from_slice(temp_buf.as_slice());
-            }
-            _ => (),
-        };
-
-        result
-    }
-
-    pub fn extract(&self) -> Vec<u8> {
-        self.inner_buffer.borrow().to_vec()
-    }
-}
-
-pub struct RawDataWrapper {
-    reader: std::rc::Rc<RawDataReader>,
-}
-
-impl RawDataWrapper {
-    pub fn new(reader: std::rc::Rc<RawDataReader>) -> Self {
-        RawDataWrapper { reader: reader }
-    }
+/// Has the body sent chunked: re-encoding and rewriting change its size, so
+/// the origin's length no longer applies until its final length is known.
+fn reframe(modified_headers: &mut Headers) {
+    modified_headers.insert("Content-Length".to_string(), "".to_string());
+    modified_headers.insert("Transfer-Encoding".to_string(), "chunked".to_string());
 }
 
-impl Read for RawDataWrapper {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.reader.read(buf)
+/// Truncates `value` to at most `limit` bytes, on a character boundary.
+pub(crate) fn truncate(value: &mut String, limit: usize) {
+    if value.len() > limit {
+        let mut end = limit;
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        value.truncate(end);
     }
 }
 
+/// A response going through prism. Transactions are `Send`, so the thread
+/// receiving a body needn't be the one sending it back.
 pub struct Transaction {
     pub id: i64,
     pub uri: String,
     pub method: String,
+    pub mode: Mode,
     pub is_done: bool,
+    /// Whether `send()` handed back the whole body.
+    pub is_finished: bool,
+    /// Whether detailed trace lines are logged for this transaction.
+    pub trace: bool,
+    /// Whether the transaction was handed over to the persistence worker.
+    pub persisted: bool,
     pub encoding: Option<String>,
-    pub transfer_chunk: Vec<u8>,
+    /// Whether the response has no body, as expected from its method or
+    /// status, or found out when `done()` came without one. Cleared when
+    /// the origin sends a body anyway.
+    pub bodyless: bool,
+    /// Whether the body is re-encoded or rewritten, see `reframe()`.
+    reframed: bool,
+    /// Set when the body is relayed as received, and its captured copy
+    /// decoded by the persistence worker, see `limits.decode_mode`.
+    pub lazy_decode: bool,
+    /// Response headers, along with the `:status` and `:client`
+    /// pseudo-headers. Headers of callers that don't tell the direction
+    /// land here too.
+    pub headers: Headers,
+    /// Headers of the request the response is for.
+    pub request_headers: Headers,
+    /// Response headers the caller should change before forwarding the
+    /// response. An empty value means the header should be removed, and the
+    /// `:status` pseudo-header carries a replacement status code.
+    pub modified_headers: Headers,
+    /// Reason the transaction was blocked, if it was.
+    pub blocked: Option<String>,
+    /// Block page still to be handed back by `send()`.
+    pub block_page: Option<Vec<u8>>,
+    /// Caller supplied metadata, see `annotate()`.
+    pub annotations: BTreeMap<String, String>,
     pub bytes_total: usize,
-    pub bytes_sender: Sender<Vec<u8>>,
-    pub bytes_receiver: Receiver<Vec<u8>>,
-    pub encoder: Encoder,
-    pub decoder_sender: Sender<Vec<u8>>,
+    /// Number of body bytes handed back through `send()`.
+    pub bytes_sent: usize,
     pub error: bool,
-    pub data_reader: std::rc::Rc<RawDataReader>,
+    pub pipeline: Pipeline,
+    /// Wall clock time the transaction started at.
+    pub started_at: DateTime<Utc>,
+    pub started: Instant,
+    /// When the first body bytes were received.
+    pub first_byte: Option<Instant>,
+    /// When body bytes were last received.
+    pub last_activity: Instant,
+    /// Set by the watchdog once the transaction took too long to complete.
+    pub slow: bool,
+    /// Since when output has been waiting for the client to take it: when
+    /// `send()` last handed some back, or when output became available
+    /// again after the client took all of it. `None` while nothing waits.
+    pub output_waiting: Option<Instant>,
+    /// Set by the watchdog once output waited past
+    /// `limits.stalled_consumer_timeout`.
+    pub client_stalled: bool,
+    /// Set when the body is no longer relayed, see `abort_relay()`.
+    pub relay_aborted: bool,
+    /// When the transaction times out, from `limits.transaction_deadline`
+    /// or the rules it matched. None for deadlines too far out for an
+    /// `Instant`, which never come anyway.
+    pub deadline: Option<Instant>,
+    /// Set once the transaction went past its deadline, see `time_out()`.
+    pub timed_out: bool,
+    /// Span covering the transaction, closed when it is dropped at cleanup.
+    pub span: tracing::Span,
+    /// Configuration in use when the transaction started.
+    pub config: Arc<Config>,
+    /// Whether the transaction was picked by sampling to be persisted.
+    pub sampled: bool,
+    /// Set when the transaction is relayed without capture, see `shed()`.
+    pub shed: bool,
+    /// Actions of the rules the transaction matched when it started.
+    pub actions: Actions,
+    /// Bytes last accounted to `metrics::RETAINED_BYTES` for this transaction.
+    retained: usize,
+    /// Footprint above which a warning is logged, once.
+    footprint_warning: Option<usize>,
 }
 
+/// Fails the build if a field stops transactions from moving across threads.
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Transaction>();
+};
+
 impl Transaction {
-    pub fn new(id: i64, method: String, uri: String, encoding: Option<&String>) -> Self {
-        let (bytes_sender, bytes_receiver): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = channel();
-        let (decoder_sender, decoder_receiver): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = channel();
-
-        let data_reader = std::rc::Rc::new(RawDataReader::new(Decoder::new_with_size(
-            BufferReader {
-                receiver: decoder_receiver,
-                pending: Vec::<u8>::new(),
-            },
-            INPUT_BUFFER_SIZE,
-        )));
-        let wrapper = RawDataWrapper::new(data_reader.clone());
+    pub fn new(
+        id: i64,
+        method: String,
+        uri: String,
+        mode: Mode,
+        headers: Headers,
+        request_headers: Headers,
+        mut rewriters: RewriteChain,
+    ) -> Self {
+        let config = config::get();
+        let encoding = headers.get("Content-Encoding").map(|e| e.to_string());
+        let bodyless = expects_no_body(&method, &headers);
+        // Bodies no rewriter needs decoded are relayed as received in lazy
+        // mode, and their captured copy decoded when persisted.
+        let lazy_decode = match &encoding {
+            Some(encoding) => {
+                !bodyless
+                    && rewriters.is_empty()
+                    && config.limits.decode_mode == decoding::LAZY
+                    && decoding::decodes(encoding)
+            }
+            None => false,
+        };
+        // Bodyless responses get a pass-through pipeline, which takes no
+        // output buffers, and relays a body sent anyway untouched.
+        let decode = match &encoding {
+            Some(encoding) => {
+                !bodyless
+                    && !lazy_decode
+                    && decoding::outermost(encoding).eq_ignore_ascii_case("gzip")
+            }
+            None => false,
+        };
+        if bodyless {
+            rewriters = RewriteChain::new(Vec::new());
+        }
+
+        let mut modified_headers = Headers::new();
+        let reframed = decode || !rewriters.is_empty();
+        if reframed {
+            reframe(&mut modified_headers);
+        }
+
+        let combined = Headers::combined(&request_headers, &headers);
+        let span = tracing::info_span!(
+            "transaction",
+            id,
+            method = %method,
+            host = %uri::parse(&uri).host_or(combined.get("Host")).unwrap_or_default()
+        );
+        let actions = rules::get().evaluate(&uri, &combined);
+        let trace = actions.trace || logging::trace_uri(&uri);
+        let pipeline = Pipeline::new(id, decode, rewriters, &config.limits);
+        pipeline.set_trace(trace);
+        let started = clock::now();
+        let deadline = actions
+            .deadline
+            .or(config.limits.transaction_deadline)
+            .and_then(|seconds| started.checked_add(Duration::from_secs(seconds)));
 
         Transaction {
-            id: id,
-            uri: uri,
+            id,
+            uri,
+            mode,
             is_done: false,
-            method: method,
-            encoding: encoding.cloned(),
-            transfer_chunk: Vec::<u8>::new(),
+            is_finished: false,
+            trace,
+            persisted: false,
+            method,
+            encoding,
+            bodyless,
+            reframed,
+            lazy_decode,
+            headers,
+            request_headers,
+            modified_headers,
+            blocked: None,
+            block_page: None,
+            annotations: BTreeMap::new(),
             bytes_total: 0,
-            bytes_sender: bytes_sender,
-            bytes_receiver: bytes_receiver,
-            encoder: Encoder::new_with_size(wrapper, ENCODER_BUFFER_SIZE),
-            decoder_sender: decoder_sender,
+            bytes_sent: 0,
             error: false,
-            data_reader: data_reader,
+            pipeline,
+            started_at: clock::utc(),
+            started,
+            first_byte: None,
+            last_activity: clock::now(),
+            slow: false,
+            output_waiting: None,
+            client_stalled: false,
+            relay_aborted: false,
+            deadline,
+            timed_out: false,
+            span,
+            sampled: sampled(id, config.sampling.rate),
+            shed: false,
+            actions,
+            retained: 0,
+            footprint_warning: config.limits.footprint_warning,
+            config,
+        }
+    }
+
+    /// Relays the transaction without capturing its body or persisting it,
+    /// for transactions started past `limits.max_transactions`.
+    pub fn shed(&mut self) {
+        self.shed = true;
+        self.pipeline.stop_capture();
+    }
+
+    /// Enables or disables detailed trace lines for this transaction.
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+        self.pipeline.set_trace(enabled);
+    }
+
+    /// Called when the status is reported after the transaction started.
+    /// A status without a body, before any was received, leaves the body
+    /// unsent and the origin's framing as it is.
+    pub fn status_reported(&mut self) {
+        if self.bodyless
+            || self.bytes_total > 0
+            || self.blocked.is_some()
+            || !expects_no_body(&self.method, &self.headers)
+        {
+            return;
         }
+        self.bodyless = true;
+        self.modified_headers.remove("Content-Length");
+        self.modified_headers.remove("Transfer-Encoding");
     }
 
     pub fn done(&mut self) {
         self.is_done = true;
-        info!(
+        if self.bytes_total == 0 {
+            self.bodyless = true;
+        }
+        self.pipeline.finish();
+        tracing::event!(
+            parent: &self.span,
+            tracing::Level::DEBUG,
+            bytes = self.bytes_total,
+            "body received"
+        );
+        event!(
+            Level::Info,
+            Fields::transaction(self.id)
+                .uri(&self.uri)
+                .bytes(self.bytes_total),
             "Transaction {} is set as done for uri: {}",
-            self.id, self.uri
+            self.id,
+            self.uri
+        );
+    }
+
+    /// Called once the whole body was handed back through `send()`. If the
+    /// body was altered, its length is now known and reported through the
+    /// modified headers.
+    pub fn stream_finished(&mut self) {
+        if self.is_finished {
+            return;
+        }
+        self.is_finished = true;
+        tracing::event!(
+            parent: &self.span,
+            tracing::Level::DEBUG,
+            bytes = self.bytes_sent,
+            "body sent"
+        );
+
+        if self.modified_headers.get("Content-Length").is_some() {
+            self.modified_headers
+                .insert("Content-Length".to_string(), self.bytes_sent.to_string());
+            self.modified_headers
+                .insert("Transfer-Encoding".to_string(), "".to_string());
+        }
+    }
+
+    /// Replaces the rest of the response with a block page. Origin data keeps
+    /// being captured, but is no longer handed back to the client.
+    pub fn block(&mut self, reason: String) {
+        recorder::block(self.id, &reason);
+        if self.blocked.is_some() {
+            return;
+        }
+
+        event!(
+            Level::Warn,
+            Fields::transaction(self.id).uri(&self.uri),
+            "Transaction {} blocked for uri {}: {}",
+            self.id,
+            self.uri,
+            reason
+        );
+        let page = block::render(self.config.filters.block_page.as_deref(), self.id, &reason);
+        self.modified_headers
+            .insert(STATUS_HEADER.to_string(), block::BLOCK_STATUS.to_string());
+        self.modified_headers.insert(
+            "Content-Type".to_string(),
+            "text/html; charset=utf-8".to_string(),
         );
+        self.modified_headers
+            .insert("Content-Encoding".to_string(), "".to_string());
+        self.modified_headers
+            .insert("Content-Length".to_string(), page.len().to_string());
+        self.modified_headers
+            .insert("Transfer-Encoding".to_string(), "".to_string());
+        self.block_page = Some(page);
+        self.blocked = Some(reason);
+    }
+
+    pub fn footprint(&self) -> Footprint {
+        self.pipeline.footprint()
+    }
+
+    /// Stops handing the body back to a client that stopped taking it,
+    /// dropping the output held for it. The body is still captured, and
+    /// `send()` hands nothing back from now on.
+    pub fn abort_relay(&mut self) {
+        self.relay_aborted = true;
+        self.pipeline.discard();
+        self.account_memory();
+    }
+
+    /// Whether the transaction went past its deadline at `now` without
+    /// timing out yet. Transactions whose whole body was handed back are
+    /// complete, and never time out.
+    pub fn past_deadline(&self, now: Instant) -> bool {
+        !self.timed_out
+            && !self.is_finished
+            && self.deadline.is_some_and(|deadline| now >= deadline)
+    }
+
+    /// Stops relaying a transaction past its deadline. What is still queued
+    /// is drained into the captured body, which is persisted as it is.
+    pub fn time_out(&mut self) {
+        self.timed_out = true;
+        self.error = true;
+        self.abort_relay();
+        metrics::increment(&metrics::COUNTERS.transactions_timed_out);
+        observer::error(Some(self.id), PrismError::Timeout);
+        event!(
+            Level::Warn,
+            Fields::transaction(self.id)
+                .uri(&self.uri)
+                .bytes(self.bytes_total)
+                .error_kind(PrismError::Timeout.label()),
+            "Transaction {} for {} timed out after {:.1}s, {} bytes received, {} sent, done: {}",
+            self.id,
+            self.uri,
+            clock::since(self.started).as_secs_f64(),
+            self.bytes_total,
+            self.bytes_sent,
+            self.is_done
+        );
+    }
+
+    /// Brings the global retained bytes gauge up to date with the
+    /// transaction's current footprint, and `output_waiting` with the
+    /// output it holds.
+    pub fn account_memory(&mut self) {
+        let footprint = self.footprint();
+        if footprint.decoder_pending + footprint.queued == 0 {
+            self.output_waiting = None;
+        } else if self.output_waiting.is_none() {
+            self.output_waiting = Some(clock::now());
+        }
+        let retained = footprint.total();
+        if retained > self.retained {
+            metrics::add(&metrics::RETAINED_BYTES, retained - self.retained);
+        } else {
+            metrics::sub(&metrics::RETAINED_BYTES, self.retained - retained);
+        }
+        self.retained = retained;
+
+        if let Some(limit) = self.footprint_warning {
+            if retained > limit {
+                warn!(
+                    "Transaction {} holds {} bytes, above {}: {} pending in the decoder, {} captured, {} queued, {} in the transfer chunk",
+                    self.id,
+                    retained,
+                    limit,
+                    footprint.decoder_pending,
+                    footprint.captured,
+                    footprint.queued,
+                    footprint.transfer_chunk
+                );
+                self.footprint_warning = None;
+            }
+        }
+    }
+
+    /// Removes the transaction's bytes from the global retained bytes gauge.
+    pub fn release_memory(&mut self) {
+        metrics::sub(&metrics::RETAINED_BYTES, self.retained);
+        self.retained = 0;
     }
 
     pub fn write_bytes(&mut self, data: &[u8]) {
-        let sender = {
-            match &self.encoding {
-                Some(encoding) => {
-                    if encoding == "gzip" {
-                        &self.decoder_sender
-                    } else {
-                        &self.bytes_sender
-                    }
-                }
-                None => &self.bytes_sender,
+        self.last_activity = clock::now();
+        if self.bodyless {
+            throttled!(
+                Level::Warn,
+                "unexpected-body",
+                "Transaction {} received a body for a {} response expected without one, relaying it",
+                self.id,
+                self.status()
+                    .map_or_else(|| self.method.clone(), |status| status.to_string())
+            );
+            self.bodyless = false;
+            if self.reframed && self.blocked.is_none() {
+                reframe(&mut self.modified_headers);
             }
-        };
+        }
+        if self.first_byte.is_none() {
+            self.first_byte = Some(clock::now());
+            tracing::event!(parent: &self.span, tracing::Level::DEBUG, "first byte received");
+        }
 
-        match sender.send(data.to_vec()) {
+        match self.pipeline.write(data) {
             Ok(()) => {
                 self.bytes_total += data.len();
+                if self.relay_aborted {
+                    self.pipeline.discard();
+                }
+                trace_transaction!(
+                    self.trace,
+                    self.id,
+                    "wrote {} bytes to the pipeline ({} total, {} chunks queued)",
+                    data.len(),
+                    self.bytes_total,
+                    self.pipeline.queued()
+                );
             }
             Err(SendError(sent)) => {
-                error!("Failed to send {} bytes", sent.len());
+                metrics::increment(&metrics::COUNTERS.dropped_chunks);
+                event!(
+                    Level::Error,
+                    Fields::transaction(self.id)
+                        .bytes(sent.len())
+                        .error_kind("pipeline"),
+                    "Failed to send {} bytes",
+                    sent.len()
+                );
             }
         }
+        self.account_memory();
+    }
+
+    /// Moves the captured body out of the transaction, which is left
+    /// without one. Only done once the body is complete, to persist it.
+    pub fn take_body(&mut self) -> Vec<u8> {
+        self.pipeline.take_body()
+    }
+
+    /// Calls `f` with at most the first `limit` bytes of the captured body.
+    pub fn with_body_head<R>(&self, limit: usize, f: impl FnOnce(&[u8]) -> R) -> R {
+        self.pipeline.with_head(limit, f)
+    }
+
+    pub fn body_truncated(&self) -> bool {
+        self.pipeline.body_truncated()
+    }
+
+    pub fn decoded_size(&self) -> usize {
+        self.pipeline.decoded_size()
+    }
+
+    /// Attaches a key/value pair to the transaction, replacing any previous
+    /// value of the key.
+    pub fn annotate(&mut self, key: String, mut value: String) {
+        recorder::annotate(self.id, &key, &value);
+        let limits = &self.config.limits;
+        if key.is_empty() || key.len() > limits.max_annotation_key {
+            warn!(
+                "Ignoring annotation of transaction {} with invalid key length {}",
+                self.id,
+                key.len()
+            );
+            return;
+        }
+        if !self.annotations.contains_key(&key) && self.annotations.len() >= limits.max_annotations
+        {
+            warn!(
+                "Ignoring annotation {} of transaction {}: limit of {} reached",
+                key, self.id, limits.max_annotations
+            );
+            return;
+        }
+
+        truncate(&mut value, limits.max_annotation_value);
+        self.annotations.insert(key, value);
+    }
+
+    /// The response status reported by the host, if any.
+    pub fn status(&self) -> Option<u16> {
+        self.headers
+            .get(STATUS_HEADER)
+            .and_then(|status| status.trim().parse().ok())
+    }
+
+    /// A request header, looked up among the response headers as well for
+    /// callers that don't tell the direction of headers.
+    pub fn request_header(&self, name: &str) -> Option<&str> {
+        self.request_headers
+            .get(name)
+            .or_else(|| self.headers.get(name))
     }
 
-    pub fn body(&self) -> Vec<u8> {
-        self.data_reader.extract()
+    /// The host of the uri, or of the `Host` header for origin-form uris.
+    pub fn host(&self) -> Option<String> {
+        uri::parse(&self.uri).host_or(self.request_header("Host"))
     }
 }
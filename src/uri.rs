@@ -0,0 +1,250 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Components of a request URI, as described in RFC 3986. Origin-form
+/// URIs (`/path?query`) have neither scheme nor authority.
+pub struct Uri<'a> {
+    pub scheme: Option<&'a str>,
+    pub authority: Option<&'a str>,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+}
+
+fn is_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        }
+        _ => false,
+    }
+}
+
+pub fn parse(uri: &str) -> Uri<'_> {
+    let rest = match uri.split_once('#') {
+        Some((rest, _fragment)) => rest,
+        None => uri,
+    };
+
+    let (scheme, rest) = match rest.split_once(':') {
+        Some((scheme, rest)) if is_scheme(scheme) => (Some(scheme), rest),
+        _ => (None, rest),
+    };
+
+    let (authority, rest) = match rest.strip_prefix("//") {
+        Some(rest) => {
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            (Some(&rest[0..end]), &rest[end..])
+        }
+        None => (None, rest),
+    };
+
+    let (path, query) = match rest.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (rest, None),
+    };
+
+    Uri {
+        scheme,
+        authority,
+        path,
+        query,
+    }
+}
+
+/// Normalizes a host, lowercasing it and dropping any user info and port.
+pub fn normalize_host(authority: &str) -> Option<String> {
+    let host = match authority.rsplit_once('@') {
+        Some((_userinfo, host)) => host,
+        None => authority,
+    };
+    let host = if host.starts_with('[') {
+        // IPv6 literal, the port follows the closing bracket.
+        match host.find(']') {
+            Some(end) => &host[0..=end],
+            None => host,
+        }
+    } else {
+        match host.split_once(':') {
+            Some((host, _port)) => host,
+            None => host,
+        }
+    };
+
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_ascii_lowercase())
+    }
+}
+
+impl<'a> Uri<'a> {
+    /// Host of the uri, or of the `Host` header for origin-form uris,
+    /// which have none. The uri wins when both are present.
+    pub fn host_or(&self, host_header: Option<&str>) -> Option<String> {
+        match self.authority {
+            Some(authority) => normalize_host(authority),
+            None => normalize_host(host_header?.trim()),
+        }
+    }
+}
+
+fn remove_dot_segments(path: &str) -> String {
+    let mut output: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "." => {}
+            ".." => {
+                if output.len() > 1 {
+                    output.pop();
+                }
+            }
+            _ => output.push(segment),
+        }
+    }
+
+    let mut result = output.join("/");
+    if (path.ends_with("/.") || path.ends_with("/..")) && !result.ends_with('/') {
+        result.push('/');
+    }
+    result
+}
+
+fn compose(scheme: &str, authority: &str, path: &str, query: Option<&str>) -> String {
+    match query {
+        Some(query) => format!("{}://{}{}?{}", scheme, authority, path, query),
+        None => format!("{}://{}{}", scheme, authority, path),
+    }
+}
+
+/// Resolves a (possibly relative) reference against an absolute base URI,
+/// following RFC 3986 section 5.2. Returns `None` when the base isn't
+/// absolute, as there is then nothing to resolve against.
+pub fn resolve(base: &str, reference: &str) -> Option<String> {
+    let reference = reference.trim();
+    let target = parse(reference);
+    if let (Some(scheme), Some(authority)) = (target.scheme, target.authority) {
+        return Some(compose(
+            scheme,
+            authority,
+            &remove_dot_segments(target.path),
+            target.query,
+        ));
+    }
+
+    let base = parse(base);
+    let scheme = base.scheme?;
+    let authority = base.authority?;
+
+    if let Some(authority) = target.authority {
+        // Protocol relative reference.
+        return Some(compose(
+            scheme,
+            authority,
+            &remove_dot_segments(target.path),
+            target.query,
+        ));
+    }
+
+    if target.path.is_empty() {
+        let query = target.query.or(base.query);
+        return Some(compose(scheme, authority, base.path, query));
+    }
+
+    let path = if target.path.starts_with('/') {
+        remove_dot_segments(target.path)
+    } else {
+        let directory = match base.path.rfind('/') {
+            Some(end) => &base.path[0..=end],
+            None => "/",
+        };
+        remove_dot_segments(&format!("{}{}", directory, target.path))
+    };
+    Some(compose(scheme, authority, &path, target.query))
+}
+
+/// Value of a query parameter. Parameters repeated hold every value, in
+/// order.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum QueryValue {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl QueryValue {
+    fn push(&mut self, value: String) {
+        match self {
+            QueryValue::One(first) => *self = QueryValue::Many(vec![std::mem::take(first), value]),
+            QueryValue::Many(values) => values.push(value),
+        }
+    }
+}
+
+/// Parameters of a query string, decoded.
+#[derive(Default)]
+pub struct QueryParams {
+    pub params: BTreeMap<String, QueryValue>,
+    /// Set when parameters past the maximum were left out.
+    pub overflow: bool,
+}
+
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Decodes a form encoded query component: `+` stands for a space, and
+/// `%XX` for a byte. Malformed escapes are kept as they are, and invalid
+/// UTF-8 replaced.
+pub fn decode_component(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let digit = |at: usize| bytes.get(at).and_then(|digit| hex(*digit));
+                match (digit(i + 1), digit(i + 2)) {
+                    (Some(high), Some(low)) => {
+                        decoded.push(high << 4 | low);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Decodes the `key=value` parameters of a query string, `max` of them at
+/// most. A key without `=` has an empty value.
+pub fn query_params(query: &str, max: usize, lowercase_keys: bool) -> QueryParams {
+    let mut query_params = QueryParams::default();
+    for (index, param) in query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .enumerate()
+    {
+        if index >= max {
+            query_params.overflow = true;
+            break;
+        }
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let mut key = decode_component(key);
+        if lowercase_keys {
+            key = key.to_lowercase();
+        }
+        let value = decode_component(value);
+        match query_params.params.get_mut(&key) {
+            Some(values) => values.push(value),
+            None => {
+                query_params.params.insert(key, QueryValue::One(value));
+            }
+        }
+    }
+    query_params
+}
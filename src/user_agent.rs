@@ -0,0 +1,197 @@
+use crate::config;
+use log::{info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+
+/// Browser reported for agents no rule matches.
+const OTHER: &str = "other";
+
+const BROWSERS: [(&str, &str); 10] = [
+    ("Edge", r"Edg(?:e|A|iOS)?/([\d.]+)"),
+    ("Opera", r"(?:OPR|Opera)/([\d.]+)"),
+    ("Samsung Internet", r"SamsungBrowser/([\d.]+)"),
+    ("Chrome", r"(?:Chrome|CriOS)/([\d.]+)"),
+    ("Firefox", r"(?:Firefox|FxiOS)/([\d.]+)"),
+    ("Safari", r"Version/([\d.]+).*Safari/"),
+    ("curl", r"^curl/([\d.]+)"),
+    ("Wget", r"^Wget/([\d.]+)"),
+    ("Python", r"python-(?:requests|urllib\d?)/([\d.]+)"),
+    ("Internet Explorer", r"(?:MSIE |Trident/.*rv:)([\d.]+)"),
+];
+
+const OPERATING_SYSTEMS: [(&str, &str); 6] = [
+    ("iOS", r"(?:iPhone|iPad|iPod).*? OS ([\d_]+)"),
+    ("Android", r"Android ([\d.]+)"),
+    ("Windows", r"Windows NT ([\d.]+)"),
+    ("macOS", r"Mac OS X ([\d_.]+)"),
+    ("ChromeOS", r"CrOS"),
+    ("Linux", r"Linux"),
+];
+
+const DEVICES: [(&str, &str); 4] = [
+    ("bot", r"(?i)bot|crawler|spider|slurp"),
+    ("tablet", r"iPad|Tablet|Android(?:[^M]|M[^o]|Mo[^b])*$"),
+    ("mobile", r"Mobi|iPhone|iPod|Android"),
+    ("desktop", r"Windows NT|Macintosh|X11|CrOS"),
+];
+
+struct Rule {
+    name: String,
+    pattern: Regex,
+}
+
+struct Rules {
+    browsers: Vec<Rule>,
+    operating_systems: Vec<Rule>,
+    devices: Vec<Rule>,
+}
+
+static RULES: RwLock<Option<Arc<Rules>>> = RwLock::new(None);
+
+/// Fields derived from the `User-Agent` header.
+#[derive(Deserialize, Serialize)]
+pub struct UserAgent {
+    pub user_agent: String,
+    pub ua_browser: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ua_browser_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ua_os: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ua_device_type: Option<String>,
+}
+
+fn compile(name: &str, pattern: &str) -> Option<Rule> {
+    match Regex::new(pattern) {
+        Ok(pattern) => Some(Rule {
+            name: name.to_string(),
+            pattern,
+        }),
+        Err(e) => {
+            warn!("Ignoring user agent rule {}: {}", name, e);
+            None
+        }
+    }
+}
+
+fn built_in(rules: &[(&str, &str)]) -> Vec<Rule> {
+    rules
+        .iter()
+        .filter_map(|(name, pattern)| compile(name, pattern))
+        .collect()
+}
+
+impl Rules {
+    fn load() -> Self {
+        let mut rules = Rules {
+            browsers: Vec::new(),
+            operating_systems: Vec::new(),
+            devices: Vec::new(),
+        };
+
+        if let Some(path) = &config::get().filters.user_agent_rules {
+            match std::fs::read_to_string(path) {
+                Ok(contents) => rules.add_custom(&contents),
+                Err(e) => warn!(
+                    "Failed reading user agent rules from {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        rules.browsers.extend(built_in(&BROWSERS));
+        rules.operating_systems.extend(built_in(&OPERATING_SYSTEMS));
+        rules.devices.extend(built_in(&DEVICES));
+        rules
+    }
+
+    fn add_custom(&mut self, contents: &str) {
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.splitn(3, char::is_whitespace);
+            let (kind, name, pattern) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(kind), Some(name), Some(pattern)) => (kind, name, pattern.trim()),
+                _ => {
+                    warn!("Ignoring malformed user agent rule: {}", line);
+                    continue;
+                }
+            };
+
+            let rules = match kind {
+                "browser" => &mut self.browsers,
+                "os" => &mut self.operating_systems,
+                "device" => &mut self.devices,
+                _ => {
+                    warn!("Ignoring user agent rule of unknown kind {}", kind);
+                    continue;
+                }
+            };
+            if let Some(rule) = compile(name, pattern) {
+                rules.push(rule);
+            }
+        }
+
+        info!(
+            "Loaded custom user agent rules: {} browser, {} os, {} device",
+            self.browsers.len(),
+            self.operating_systems.len(),
+            self.devices.len()
+        );
+    }
+}
+
+/// Compiles the rules, so the cost isn't paid by the first transaction.
+pub fn init() {
+    rules();
+}
+
+fn rules() -> Arc<Rules> {
+    if let Some(rules) = RULES.read().unwrap().as_ref() {
+        return rules.clone();
+    }
+    RULES
+        .write()
+        .unwrap()
+        .get_or_insert_with(|| Arc::new(Rules::load()))
+        .clone()
+}
+
+/// Compiles the rules again, picking up a changed rules file.
+pub fn reload() {
+    *RULES.write().unwrap() = Some(Arc::new(Rules::load()));
+}
+
+/// Returns the name and version captured by the first matching rule.
+fn first_match(rules: &[Rule], agent: &str) -> Option<(String, Option<String>)> {
+    rules.iter().find_map(|rule| {
+        let captures = rule.pattern.captures(agent)?;
+        let version = captures
+            .get(1)
+            .map(|version| version.as_str().replace('_', "."));
+        Some((rule.name.clone(), version))
+    })
+}
+
+impl UserAgent {
+    pub fn parse(agent: &str) -> Self {
+        let rules = rules();
+        let agent = agent.trim();
+        let (browser, version) = match first_match(&rules.browsers, agent) {
+            Some((browser, version)) => (browser, version),
+            None => (OTHER.to_string(), None),
+        };
+
+        UserAgent {
+            user_agent: agent.to_string(),
+            ua_browser: browser,
+            ua_browser_version: version,
+            ua_os: first_match(&rules.operating_systems, agent).map(|(os, _)| os),
+            ua_device_type: first_match(&rules.devices, agent).map(|(device, _)| device),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::clock;
+use crate::config;
+use crate::metrics;
+use crate::transaction::Transaction;
+use log::warn;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two sweeps over the live transactions.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `limits.stalled_consumer_action` only reporting stalled clients.
+pub const STALL_LOG: &str = "log";
+/// `limits.stalled_consumer_action` also aborting their transactions.
+pub const STALL_ABORT: &str = "abort";
+
+/// Reports transactions that stall before their body is complete, and
+/// clients that stop taking the output held for them, and finds the
+/// transactions past their deadline.
+///
+/// Transactions are only reachable from the proxy's threads, so instead of
+/// a background thread the watchdog sweeps them from the FFI entry points,
+/// at most once per `CHECK_INTERVAL`.
+pub struct Watchdog {
+    threshold: Option<Duration>,
+    stall_timeout: Option<Duration>,
+    abort_stalled: bool,
+    last_check: Instant,
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        let config = config::get();
+        let limits = &config.limits;
+        let threshold = limits
+            .slow_threshold
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs);
+        Watchdog {
+            threshold,
+            stall_timeout: limits.stalled_consumer_timeout.map(Duration::from_secs),
+            abort_stalled: limits.stalled_consumer_action == STALL_ABORT,
+            last_check: clock::now(),
+        }
+    }
+
+    /// Sweeps the transactions, returning the ids of those past their
+    /// deadline for the caller to time out, see `Transaction::time_out()`.
+    pub fn check(&mut self, transactions: &mut HashMap<i64, Transaction>) -> Vec<i64> {
+        self.check_at(clock::now(), transactions)
+    }
+
+    fn check_at(&mut self, now: Instant, transactions: &mut HashMap<i64, Transaction>) -> Vec<i64> {
+        let mut expired = Vec::new();
+        if now.duration_since(self.last_check) < CHECK_INTERVAL {
+            return expired;
+        }
+        self.last_check = now;
+
+        for transaction in transactions.values_mut() {
+            // Deadlines come from the rules as well, so they are always
+            // checked.
+            if transaction.past_deadline(now) {
+                expired.push(transaction.id);
+            }
+            if let Some(timeout) = self.stall_timeout {
+                self.check_stalled(now, timeout, transaction);
+            }
+            let Some(threshold) = self.threshold else {
+                continue;
+            };
+            if transaction.is_done || transaction.slow {
+                continue;
+            }
+            let elapsed = now.duration_since(transaction.started);
+            if elapsed < threshold {
+                continue;
+            }
+            transaction.slow = true;
+            warn!(
+                "Transaction {} for {} is slow: not done after {:.1}s, {} bytes received, idle for {:.1}s",
+                transaction.id,
+                transaction.uri,
+                elapsed.as_secs_f64(),
+                transaction.bytes_total,
+                now.duration_since(transaction.last_activity).as_secs_f64()
+            );
+        }
+        expired
+    }
+
+    /// Reports the client of `transaction` once output waited `timeout`
+    /// for it, aborting the transaction if configured to.
+    fn check_stalled(&self, now: Instant, timeout: Duration, transaction: &mut Transaction) {
+        let Some(since) = transaction.output_waiting else {
+            return;
+        };
+        let waited = now.duration_since(since);
+        if transaction.client_stalled || waited < timeout {
+            return;
+        }
+        transaction.client_stalled = true;
+        metrics::increment(&metrics::COUNTERS.stalled_consumers);
+        let footprint = transaction.footprint();
+        warn!(
+            "Client of transaction {} for {} is stalled: {} bytes waiting for {:.1}s, {} bytes sent of {} received{}",
+            transaction.id,
+            transaction.uri,
+            footprint.decoder_pending + footprint.queued,
+            waited.as_secs_f64(),
+            transaction.bytes_sent,
+            transaction.bytes_total,
+            if self.abort_stalled {
+                ", no longer relaying its body"
+            } else {
+                ""
+            }
+        );
+        if self.abort_stalled {
+            metrics::increment(&metrics::COUNTERS.stalled_consumers_aborted);
+            transaction.abort_relay();
+        }
+    }
+}
@@ -0,0 +1,855 @@
+use crate::audit;
+use crate::config;
+use crate::dead_letter;
+#[cfg(feature = "async-persistence")]
+use crate::dispatch::Dispatcher;
+use crate::document::Document;
+use crate::error::PrismError;
+#[cfg(feature = "fault-injection")]
+use crate::faults;
+use crate::geoip;
+use crate::har::HarFile;
+use crate::logging::throttled;
+use crate::memory::MemoryBackend;
+use crate::metrics;
+use crate::observer;
+use crate::offline;
+#[cfg(feature = "elasticsearch")]
+use crate::persistence::Elasticsearch;
+use crate::persistence::{Backend, DryRun};
+use crate::rate_limit::RateLimiter;
+use crate::scanner;
+#[cfg(feature = "metrics")]
+use crate::statsd;
+use log::{debug, error, info, warn, Level};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A document waiting to be persisted.
+pub struct PendingDocument {
+    pub document: Document,
+    /// Whether the document's body is handed to the external scanner, set
+    /// when the transaction matched the scanner configuration.
+    pub scan: bool,
+    /// Span of the persistence phase, child of the transaction's span.
+    pub span: tracing::Span,
+    /// Whether the transaction ran in dry run mode, see `Config::dry_run`.
+    pub dry_run: bool,
+    /// Set for documents replayed from the offline spool, to the times they
+    /// failed again with the backend otherwise available, see `offline`.
+    pub replayed: Option<u32>,
+}
+
+impl PendingDocument {
+    /// Bytes the document holds while queued.
+    fn size(&self) -> usize {
+        self.document.memory_size()
+    }
+}
+
+/// Overflow policies, see `Limits::queue_overflow`.
+pub const DROP_NEWEST: &str = "drop_newest";
+pub const DROP_OLDEST: &str = "drop_oldest";
+pub const DEAD_LETTER: &str = "dead_letter";
+
+/// Documents waiting for the workers, checked against the queue limits.
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    /// Signalled when a document is queued, workers are scaled or exit, or
+    /// the worker is stopped.
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    documents: VecDeque<PendingDocument>,
+    bytes: usize,
+    stopped: bool,
+    /// Workers the configuration asks for. Those running past it exit.
+    workers: usize,
+    /// Worker threads running.
+    running: usize,
+    /// Worker threads started so far, numbering them.
+    spawned: usize,
+    /// Documents taken by the workers to be persisted.
+    flushed: u64,
+    /// Documents dropped for lack of room, or left over when stopping.
+    dropped: u64,
+    dead_lettered: u64,
+}
+
+/// State of the queue at one point, see `Worker::snapshot()`.
+#[derive(Serialize)]
+pub struct QueueSnapshot {
+    pub documents: usize,
+    pub bytes: usize,
+    pub workers: usize,
+    pub running: usize,
+    pub flushed: u64,
+    pub dropped: u64,
+    pub dead_lettered: u64,
+    pub stopped: bool,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Background threads persisting documents, so that analysis and backend
+/// round-trips happen outside of the proxy's request path.
+///
+/// Workers take documents from a shared queue in order, but persist them
+/// independently: with more than one worker, documents may be persisted in
+/// a different order than they were submitted.
+pub struct Worker {
+    context: Context,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    /// Queues spooled documents again, see `replay()`.
+    replay: Option<JoinHandle<()>>,
+    /// Set on the stand-in left in place while stopping, which starts no
+    /// workers of its own, see `stand_in()`.
+    stand_in: bool,
+}
+
+/// What the worker threads share.
+#[derive(Clone)]
+struct Context {
+    queue: Arc<Queue>,
+    /// Paces the documents taken off the queue, see `Limits::persist_rate`.
+    limiter: Arc<RateLimiter>,
+    /// Taken when stopping, see `Worker::stop()`.
+    #[cfg(feature = "async-persistence")]
+    dispatcher: Option<Arc<Dispatcher>>,
+}
+
+impl Worker {
+    pub fn new() -> Self {
+        let config = config::get();
+        let mut worker = Worker {
+            context: Context {
+                queue: Arc::new(Queue::default()),
+                limiter: Arc::new(RateLimiter::new()),
+                #[cfg(feature = "async-persistence")]
+                dispatcher: Some(Arc::new(Dispatcher::new(
+                    config.backend.elasticsearch.max_in_flight,
+                ))),
+            },
+            threads: Mutex::new(Vec::new()),
+            replay: None,
+            stand_in: false,
+        };
+        offline::reset();
+        worker.scale(workers(&config.limits));
+        if config.offline.directory.is_some() {
+            let context = worker.context.clone();
+            match thread::Builder::new()
+                .name("prism-offline-replay".to_string())
+                .spawn(move || replay(&context))
+            {
+                Ok(thread) => worker.replay = Some(thread),
+                Err(e) => error!("Failed starting offline replay: {}", e),
+            }
+        }
+        worker
+    }
+
+    /// A worker on the same queue, to stand in for this one while it stops
+    /// without holding up its callers. It starts no workers, drops what is
+    /// submitted once the queue is stopped and reports the same snapshot.
+    pub fn stand_in(&self) -> Self {
+        Worker {
+            context: self.context.clone(),
+            threads: Mutex::new(Vec::new()),
+            replay: None,
+            stand_in: true,
+        }
+    }
+
+    /// Bytes held by the documents waiting to be persisted.
+    pub fn queued_bytes(&self) -> usize {
+        self.context.queue.lock().bytes
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let state = self.context.queue.lock();
+        QueueSnapshot {
+            documents: state.documents.len(),
+            bytes: state.bytes,
+            workers: state.workers,
+            running: state.running,
+            flushed: state.flushed,
+            dropped: state.dropped,
+            dead_lettered: state.dead_lettered,
+            stopped: state.stopped,
+        }
+    }
+
+    /// Starts or winds down workers until `workers` run. Workers wound down
+    /// finish the document they hold first.
+    fn scale(&self, workers: usize) {
+        let queue = &self.context.queue;
+        let mut state = queue.lock();
+        if self.stand_in || state.stopped || state.workers == workers {
+            return;
+        }
+        info!(
+            "Running {} persistence workers, from {}",
+            workers, state.workers
+        );
+        state.workers = workers;
+
+        let mut threads = match self.threads.lock() {
+            Ok(threads) => threads,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        threads.retain(|thread| !thread.is_finished());
+        while state.running < workers {
+            state.spawned += 1;
+            let context = self.context.clone();
+            match thread::Builder::new()
+                .name(format!("prism-persistence-{}", state.spawned))
+                .spawn(move || run(&context))
+            {
+                Ok(thread) => {
+                    state.running += 1;
+                    threads.push(thread);
+                }
+                Err(e) => {
+                    error!("Failed starting persistence worker: {}", e);
+                    break;
+                }
+            }
+        }
+        drop(state);
+        // Wakes idle workers, so those past the count exit.
+        queue.changed.notify_all();
+    }
+
+    /// Queues a document. When the queue is full as per the limits in use
+    /// when it is submitted, `limits.queue_overflow` decides which document
+    /// gives way. An empty queue takes any document.
+    pub fn submit(&self, mut pending: PendingDocument) {
+        let size = pending.size();
+        let config = config::get();
+        let limits = &config.limits;
+        self.scale(workers(limits));
+        let full = |state: &QueueState| {
+            state.documents.len() >= limits.max_queued_documents
+                || (!state.documents.is_empty() && state.bytes + size > limits.queue_memory_budget)
+        };
+
+        let mut state = self.context.queue.lock();
+        if state.stopped {
+            state.dropped += 1;
+            drop(state);
+            observer::error(Some(pending.document.id), PrismError::QueueOverflow);
+            error!(
+                "Dropping document {}, the persistence worker is stopped: {}",
+                pending.document.id,
+                PrismError::QueueOverflow
+            );
+            return;
+        }
+
+        let mut evicted = Vec::new();
+        if full(&state) {
+            let queued = state.documents.len();
+            if offline::write(&pending) {
+                drop(state);
+                throttled!(
+                    Level::Warn,
+                    "queue-overflow",
+                    "Spooled document {} of {} bytes, {} documents are queued",
+                    pending.document.id,
+                    size,
+                    queued
+                );
+                return;
+            }
+            match limits.queue_overflow.as_str() {
+                DROP_OLDEST => {
+                    while full(&state) {
+                        let Some(oldest) = state.documents.pop_front() else {
+                            break;
+                        };
+                        state.bytes -= oldest.size();
+                        state.dropped += 1;
+                        evicted.push(oldest);
+                    }
+                }
+                DEAD_LETTER => {
+                    decode(&mut pending.document, limits);
+                    let written = dead_letter::write(&pending.document);
+                    if written {
+                        state.dead_lettered += 1;
+                    } else {
+                        state.dropped += 1;
+                    }
+                    drop(state);
+                    if written {
+                        metrics::increment(&metrics::COUNTERS.dead_lettered);
+                        throttled!(
+                            Level::Warn,
+                            "queue-overflow",
+                            "Dead-lettered document {} of {} bytes, {} documents are queued",
+                            pending.document.id,
+                            size,
+                            queued
+                        );
+                    } else {
+                        overflowed(&pending, &metrics::COUNTERS.dropped_newest, queued);
+                    }
+                    return;
+                }
+                _ => {
+                    state.dropped += 1;
+                    drop(state);
+                    overflowed(&pending, &metrics::COUNTERS.dropped_newest, queued);
+                    return;
+                }
+            }
+        }
+
+        metrics::increment(&metrics::QUEUE_DEPTH);
+        state.bytes += size;
+        state.documents.push_back(pending);
+        let queued = state.documents.len();
+        drop(state);
+        self.context.queue.changed.notify_one();
+
+        for oldest in evicted {
+            metrics::decrement(&metrics::QUEUE_DEPTH);
+            overflowed(&oldest, &metrics::COUNTERS.dropped_oldest, queued);
+        }
+    }
+
+    /// Stops taking documents, and waits for the workers to persist those
+    /// queued, or drop them when their backend is not ready. Workers still
+    /// busy after `STOP_TIMEOUT` are left behind.
+    pub fn stop(&mut self) {
+        let queue = self.context.queue.clone();
+        {
+            let mut state = queue.lock();
+            if state.stopped {
+                return;
+            }
+            state.stopped = true;
+        }
+        queue.changed.notify_all();
+
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        let mut state = queue.lock();
+        while state.running > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = match queue.changed.wait_timeout(state, deadline - now) {
+                Ok((state, _)) => state,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        let running = state.running;
+        drop(state);
+
+        let threads = match self.threads.get_mut() {
+            Ok(threads) => std::mem::take(threads),
+            Err(poisoned) => std::mem::take(poisoned.into_inner()),
+        };
+        if running == 0 {
+            for thread in threads {
+                let _ = thread.join();
+            }
+        } else {
+            warn!(
+                "Abandoning {} persistence workers still busy after {}s",
+                running,
+                STOP_TIMEOUT.as_secs()
+            );
+        }
+        // Waits for the persists in flight.
+        #[cfg(feature = "async-persistence")]
+        drop(self.context.dispatcher.take());
+        if let Some(replay) = self.replay.take() {
+            let _ = replay.join();
+        }
+
+        let state = queue.lock();
+        info!(
+            "Persistence workers stopped: {} documents flushed, {} dropped, {} dead-lettered",
+            state.flushed, state.dropped, state.dead_lettered
+        );
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Number of persistence workers `limits` ask for.
+fn workers(limits: &config::Limits) -> usize {
+    match limits.persistence_workers {
+        0 => thread::available_parallelism()
+            .map_or(1, |parallelism| parallelism.get().div_ceil(2))
+            .min(MAX_DEFAULT_WORKERS),
+        workers => workers,
+    }
+}
+
+/// Reports a document the queue had no room for.
+fn overflowed(pending: &PendingDocument, counter: &AtomicU64, queued: usize) {
+    metrics::increment(counter);
+    observer::error(Some(pending.document.id), PrismError::QueueOverflow);
+    throttled!(
+        Level::Error,
+        "queue-overflow",
+        "Dropping document {} of {} bytes, {} documents are queued: {}",
+        pending.document.id,
+        pending.size(),
+        queued,
+        PrismError::QueueOverflow
+    );
+}
+
+/// Decodes a body captured as received, see `Document::decode_body()`,
+/// reporting the bodies failing to decode.
+fn decode(document: &mut Document, limits: &config::Limits) {
+    if let Some(e) = document.decode_body(limits) {
+        metrics::increment(&metrics::COUNTERS.decode_errors);
+        observer::error(Some(document.id), PrismError::Decode);
+        throttled!(
+            Level::Error,
+            "decode",
+            "Failed decoding the body of document {} (uri: {}), keeping {} bytes decoded: {}",
+            document.id,
+            document.uri,
+            document.body_size,
+            e
+        );
+    }
+}
+
+/// How often documents held back for a backend that is not ready are
+/// checked again, when no new document arrives.
+const WAITING_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long stopping waits for the workers to empty the queue.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Workers run by default at most, half the available parallelism below it.
+const MAX_DEFAULT_WORKERS: usize = 8;
+
+/// Persists queued documents until the worker is stopped, or wound down.
+/// Documents are held back, in order, while their backend is not ready,
+/// such as an index still being initialized, or while the persistence rate
+/// limit is reached. They stay in the queue, so the queue limits bound
+/// them. Once stopped, the queue is flushed regardless of the rate limit,
+/// so stopping is not held up by it.
+fn run(context: &Context) {
+    let queue = &context.queue;
+    // Connects, and initializes the index, ahead of the first document.
+    if !config::get().dry_run {
+        backend(false);
+    }
+
+    loop {
+        // The backend is checked without the queue locked, as getting it
+        // ready may take a round-trip, and submitting must not wait on it.
+        let (dry_run, size, stopped) = {
+            let mut state = queue.lock();
+            loop {
+                if !state.stopped && state.running > state.workers {
+                    state.running -= 1;
+                    drop(state);
+                    queue.changed.notify_all();
+                    debug!("Persistence worker wound down");
+                    return;
+                }
+                if !state.documents.is_empty() || state.stopped {
+                    break;
+                }
+                state = match queue.changed.wait(state) {
+                    Ok(state) => state,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+            }
+            match state.documents.front() {
+                Some(pending) => (pending.dry_run, pending.size(), state.stopped),
+                None => break,
+            }
+        };
+        if !backend(dry_run).map_or(true, |backend| backend.ready()) {
+            let state = queue.lock();
+            if state.stopped {
+                break;
+            }
+            let _ = queue.changed.wait_timeout(state, WAITING_POLL_INTERVAL);
+            continue;
+        }
+        // Documents are spooled as fast as they come while the circuit is
+        // open.
+        if !dry_run && !stopped && !offline::is_open() {
+            if let Some(wait) = context.limiter.acquire(size, &config::get().limits) {
+                let state = queue.lock();
+                if !state.stopped {
+                    let _ = queue.changed.wait_timeout(state, wait);
+                }
+                continue;
+            }
+        }
+
+        let pending = {
+            let mut state = queue.lock();
+            match state.documents.pop_front() {
+                Some(pending) => {
+                    state.bytes -= pending.size();
+                    state.flushed += 1;
+                    pending
+                }
+                None => continue,
+            }
+        };
+        // Replayed documents are only queued while the circuit is open to
+        // probe the backend.
+        if pending.replayed.is_none()
+            && offline::is_open()
+            && !offline::probe()
+            && offline::write(&pending)
+        {
+            metrics::decrement(&metrics::QUEUE_DEPTH);
+            continue;
+        }
+        let Some((pending, backend)) = prepare(pending) else {
+            continue;
+        };
+        let attempts = config::get().backend.persist_attempts;
+        let started = Instant::now();
+        metrics::persisting(backend.name());
+        #[cfg(feature = "async-persistence")]
+        if let Some(dispatcher) = &context.dispatcher {
+            if let Some(persist) = backend.persist_async(&pending.document) {
+                dispatcher.spawn(async move {
+                    let mut result = persist.await;
+                    let mut attempt = 1;
+                    while retry(&pending.document, result, attempt, attempts) {
+                        let Some(persist) = backend.persist_async(&pending.document) else {
+                            break;
+                        };
+                        result = persist.await;
+                        attempt += 1;
+                    }
+                    finish(pending, backend.as_ref(), result, started.elapsed());
+                });
+                continue;
+            }
+        }
+        let mut result = backend.persist(&pending.document);
+        let mut attempt = 1;
+        while retry(&pending.document, result, attempt, attempts) {
+            result = backend.persist(&pending.document);
+            attempt += 1;
+        }
+        finish(pending, backend.as_ref(), result, started.elapsed());
+    }
+
+    // Spooled when they can be, rather than dropped.
+    let (spooled, dropped) = {
+        let mut state = queue.lock();
+        state.running -= 1;
+        state.bytes = 0;
+        let (spooled, dropped): (Vec<_>, Vec<_>) =
+            state.documents.drain(..).partition(offline::write);
+        state.dropped += dropped.len() as u64;
+        (spooled.len(), dropped)
+    };
+    queue.changed.notify_all();
+    if spooled > 0 {
+        info!(
+            "Spooled {} documents waiting for the {} backend to be ready",
+            spooled,
+            backend_name()
+        );
+    }
+    if !dropped.is_empty() {
+        warn!(
+            "Dropping {} documents waiting for the {} backend to be ready",
+            dropped.len(),
+            backend_name()
+        );
+    }
+    metrics::sub(&metrics::QUEUE_DEPTH, spooled);
+    for pending in dropped {
+        metrics::decrement(&metrics::QUEUE_DEPTH);
+        observer::error(Some(pending.document.id), PrismError::BackendUnavailable);
+    }
+    debug!("Persistence worker exiting");
+}
+
+/// How often spooled documents are looked for, and the queue checked for
+/// room for them.
+const REPLAY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Queues spooled documents again until the worker is stopped, oldest file
+/// first, at `offline.replay_rate`. Replayed documents take at most half of
+/// the queue limits, leaving the rest to live traffic. While the circuit is
+/// open, one is queued every `offline::PROBE_INTERVAL` when no live
+/// document is, to probe the backend. Those not queued when stopped are
+/// spooled again.
+fn replay(context: &Context) {
+    let queue = &context.queue;
+    let mut replaying = VecDeque::new();
+    let mut pace = offline::Pace::new();
+    loop {
+        if queue.lock().stopped {
+            break;
+        }
+        // Files are only taken while the circuit is open for a probe, so
+        // that they stay on disk, oldest pruned first.
+        if replaying.is_empty() && (!offline::is_open() || offline::probe_due()) {
+            replaying.extend(offline::take());
+        }
+        let Some(size) = replaying.front().map(PendingDocument::size) else {
+            thread::sleep(REPLAY_POLL_INTERVAL);
+            continue;
+        };
+        let open = offline::is_open();
+        if !open {
+            if let Some(wait) = pace.wait() {
+                thread::sleep(wait.min(REPLAY_POLL_INTERVAL));
+                continue;
+            }
+        }
+
+        let config = config::get();
+        let limits = &config.limits;
+        let mut state = queue.lock();
+        let room = if open {
+            state.documents.is_empty() && offline::probe()
+        } else {
+            state.documents.is_empty()
+                || (state.documents.len() < limits.max_queued_documents / 2
+                    && state.bytes + size <= limits.queue_memory_budget / 2)
+        };
+        if !room {
+            drop(state);
+            thread::sleep(REPLAY_POLL_INTERVAL);
+            continue;
+        }
+        let Some(pending) = replaying.pop_front() else {
+            continue;
+        };
+        metrics::increment(&metrics::QUEUE_DEPTH);
+        state.bytes += size;
+        state.documents.push_back(pending);
+        drop(state);
+        queue.changed.notify_one();
+        metrics::increment(&metrics::COUNTERS.offline_replayed);
+    }
+
+    let left = replaying.len();
+    for pending in replaying {
+        if !offline::write(&pending) {
+            observer::error(Some(pending.document.id), PrismError::BackendUnavailable);
+        }
+    }
+    if left > 0 {
+        info!("Spooled back {} documents left to replay", left);
+    }
+    debug!("Offline replay exiting");
+}
+
+/// Enriches a document and picks the backend it goes to, `None` when that
+/// backend is not compiled in.
+fn prepare(mut pending: PendingDocument) -> Option<(PendingDocument, Arc<dyn Backend>)> {
+    let span = pending.span.clone();
+    let _entered = span.enter();
+    decode(&mut pending.document, &config::get().limits);
+    geoip::enrich(&mut pending.document);
+
+    if let (Some(scanner), true) = (scanner::get(), pending.scan) {
+        let document = &mut pending.document;
+        let body = document.body.clone();
+        document.scanner_result = Some(scanner.scan(document.id, &document.uri, body));
+    }
+
+    match backend(pending.dry_run) {
+        Some(backend) => Some((pending, backend)),
+        None => {
+            debug!(
+                "No {} backend compiled in, skipping document {}",
+                backend_name(),
+                pending.document.id
+            );
+            metrics::decrement(&metrics::QUEUE_DEPTH);
+            None
+        }
+    }
+}
+
+/// Whether to persist a document again after `attempt` of `attempts`
+/// ended with `result`. Only failures of the backend are worth retrying,
+/// documents failing to encode would fail again.
+fn retry(
+    document: &Document,
+    result: Result<(), PrismError>,
+    attempt: usize,
+    attempts: usize,
+) -> bool {
+    if result != Err(PrismError::BackendUnavailable) || attempt >= attempts {
+        return false;
+    }
+    debug!(
+        "Persisting document {} failed, attempt {} of {}",
+        document.id, attempt, attempts
+    );
+    true
+}
+
+/// Records the outcome of a persist.
+fn finish(
+    pending: PendingDocument,
+    backend: &dyn Backend,
+    result: Result<(), PrismError>,
+    elapsed: Duration,
+) {
+    let _entered = pending.span.enter();
+    metrics::persisted(backend.name(), result.is_ok(), elapsed);
+    // Persisted once the backend recovers.
+    if result == Err(PrismError::BackendUnavailable) && offline::fail(&pending) {
+        metrics::decrement(&metrics::QUEUE_DEPTH);
+        return;
+    }
+    match result {
+        Ok(()) if !pending.dry_run => {
+            offline::recovered();
+            audit::record(backend, &pending.document);
+        }
+        Ok(()) => {}
+        Err(e) => observer::error(Some(pending.document.id), e),
+    }
+    observer::notify(|observer| {
+        observer.on_persisted(&observer::Persisted {
+            id: pending.document.id,
+            backend: backend.name(),
+            result,
+            elapsed,
+        })
+    });
+    #[cfg(feature = "metrics")]
+    {
+        let tags = [
+            ("backend", backend.name()),
+            ("result", if result.is_ok() { "success" } else { "failure" }),
+        ];
+        statsd::timing("persist.latency", elapsed, &tags);
+        statsd::count("persist", 1, &tags);
+    }
+    metrics::decrement(&metrics::QUEUE_DEPTH);
+}
+
+/// Name of the configured persistence backend.
+pub fn backend_name() -> &'static str {
+    match config::get().backend.kind.as_str() {
+        "har" => "har",
+        "memory" => "memory",
+        _ => "elasticsearch",
+    }
+}
+
+/// Whether the configured persistence backend is compiled in. Under Miri,
+/// which runs no network code, Elasticsearch is left out.
+pub fn backend_available() -> bool {
+    backend_name() != "elasticsearch" || cfg!(all(feature = "elasticsearch", not(miri)))
+}
+
+/// The backend built from the configuration in use, along with the backend
+/// configuration it was built from.
+type SharedBackend = (config::Backend, Arc<dyn Backend>);
+
+static BACKEND: Mutex<Option<SharedBackend>> = Mutex::new(None);
+
+/// The backend documents are persisted with. It is built once and shared by
+/// all workers, keeping its connections open, and only rebuilt when the
+/// backend configuration changes.
+pub(crate) fn backend(dry_run: bool) -> Option<Arc<dyn Backend>> {
+    let config = config::get();
+    if dry_run {
+        return Some(Arc::new(DryRun {
+            fields: config.backend.elasticsearch.fields.clone(),
+        }));
+    }
+    let mut shared = match BACKEND.lock() {
+        Ok(shared) => shared,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if let Some((built_from, backend)) = shared.as_ref() {
+        if *built_from == config.backend {
+            return Some(backend.clone());
+        }
+    }
+    let backend: Arc<dyn Backend> = match backend_name() {
+        "har" => Arc::new(HarFile::new()),
+        "memory" => Arc::new(MemoryBackend),
+        _ => elasticsearch()?,
+    };
+    #[cfg(feature = "fault-injection")]
+    let backend = faults::wrap(backend);
+    info!("Persisting to {} {}", backend.name(), backend.destination());
+    *shared = Some((config.backend.clone(), backend.clone()));
+    Some(backend)
+}
+
+/// Name of the host prism runs on, lowercased as index names must be.
+#[cfg(feature = "elasticsearch")]
+fn local_hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_ascii_lowercase())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(feature = "elasticsearch")]
+fn elasticsearch() -> Option<Arc<dyn Backend>> {
+    if cfg!(miri) {
+        return None;
+    }
+    let config = config::get();
+    let elasticsearch = &config.backend.elasticsearch;
+    // Credentials travel in the authority part of the url.
+    let hostname = match (&elasticsearch.username, &elasticsearch.password) {
+        (Some(username), Some(password)) => {
+            format!("{}:{}@{}", username, password, elasticsearch.hostname)
+        }
+        (Some(username), None) => format!("{}@{}", username, elasticsearch.hostname),
+        _ => elasticsearch.hostname.clone(),
+    };
+    Some(Arc::new(Elasticsearch::new(
+        hostname,
+        elasticsearch.port.into(),
+        elasticsearch.protocol.clone(),
+        elasticsearch.index.replace("{hostname}", &local_hostname()),
+        elasticsearch.fields.clone(),
+        config.backend.schema_version(),
+        elasticsearch.api_key.clone(),
+    )))
+}
+
+#[cfg(not(feature = "elasticsearch"))]
+fn elasticsearch() -> Option<Arc<dyn Backend>> {
+    None
+}
@@ -0,0 +1,127 @@
+//! Memory held and allocated for a large body, counted by an allocator of
+//! this test binary: the captured body is held once while the transaction
+//! runs, and persisting it builds the document without copying it.
+
+mod common;
+
+use common::{gzip, setup_ffi, state, stats, take};
+use prism::Prism;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts the bytes allocated, now and at most since `reset()`.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static COUNTING: Counting = Counting;
+
+fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+/// Starts counting the peak from the bytes allocated now.
+fn reset() {
+    PEAK.store(allocated(), Ordering::Relaxed);
+}
+
+fn peak() -> usize {
+    PEAK.load(Ordering::Relaxed)
+}
+
+fn retained() -> u64 {
+    stats()["retained_bytes"].as_u64().unwrap()
+}
+
+const MIB: usize = 1024 * 1024;
+const BODY: usize = 50 * MIB;
+
+/// Text that compresses well, so the gzip body received stays small.
+fn body() -> Vec<u8> {
+    let mut body = Vec::with_capacity(BODY);
+    let mut row = 0;
+    while body.len() < BODY {
+        body.extend_from_slice(format!("<li>allocation row {}</li>\n", row).as_bytes());
+        row += 1;
+    }
+    body.truncate(BODY);
+    body
+}
+
+#[test]
+fn large_bodies_are_held_once_and_persisted_without_copies() {
+    let _serial = setup_ffi();
+    let mut config = common::config();
+    config.dry_run = true;
+    config.limits.coalesce_size = 0;
+    config.limits.spill_threshold = 2 * BODY;
+    let prism = Prism::new(config).unwrap();
+    let body = body();
+    let encoded = gzip(&body);
+    let retained_before = retained();
+    let before = allocated();
+
+    let headers = [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+    let mut handle = prism.begin(52001, "GET", "http://allocations.example.com/", &headers);
+    handle.status(200);
+    for chunk in encoded.chunks(64 * 1024) {
+        handle.receive(chunk).unwrap();
+        take(&mut handle);
+    }
+
+    // Decoded bytes are captured once, rather than also buffered for the
+    // encoder: what the transaction holds is the body and its buffers.
+    let footprint = state(&prism, 52001)["footprint"].clone();
+    assert_eq!(footprint["captured"], BODY);
+    let held = footprint.as_object().unwrap().values();
+    let held: u64 = held.map(|bytes| bytes.as_u64().unwrap()).sum();
+    assert!(held <= (BODY + 4 * MIB) as u64, "{} bytes held", held);
+    assert_eq!(retained(), retained_before + held);
+    // The capture grows by doubling, up to 64MiB for the body.
+    let grown = allocated() - before;
+    assert!(grown < BODY * 3 / 2, "{} bytes allocated", grown);
+
+    handle.done();
+    while !handle.finished() {
+        take(&mut handle);
+    }
+    let persisting = allocated();
+    reset();
+    drop(handle);
+    prism.shutdown();
+
+    // The document takes the captured body, and is serialized in one
+    // buffer: the body as text and base64. A copy of the body would add
+    // another 50MiB.
+    let persist_peak = peak() - persisting;
+    assert!(
+        persist_peak < BODY * 5 / 2,
+        "{} bytes allocated persisting",
+        persist_peak
+    );
+    // Given back once persisted, but for pooled buffers.
+    assert_eq!(retained(), retained_before);
+    assert!(
+        allocated() < before + BODY / 10,
+        "{} bytes left",
+        allocated() - before
+    );
+}
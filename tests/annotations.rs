@@ -0,0 +1,96 @@
+//! Metadata attached by the caller with `annotate()`, persisted under
+//! `annotations` within the limits of `limits.max_annotations`,
+//! `limits.max_annotation_key` and `limits.max_annotation_value`.
+
+mod common;
+
+use common::{drain, take, TIMEOUT};
+use prism::clock::{self, ManualClock};
+use prism::error::PrismError;
+use prism::{memory, Prism, TransactionHandle};
+use serde_json::json;
+use std::sync::MutexGuard;
+use std::time::Duration;
+
+fn setup() -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.limits.max_annotations = 3;
+        config.limits.max_annotation_key = 8;
+        config.limits.max_annotation_value = 5;
+    })
+}
+
+fn begin(prism: &Prism, id: i64) -> TransactionHandle {
+    let uri = format!("http://annotations.example.com/{}", id);
+    let handle = prism.begin(id, "GET", &uri, &[("Content-Type", "text/plain")]);
+    handle.status(200);
+    handle
+}
+
+#[test]
+fn annotations_made_across_the_lifecycle_are_persisted() {
+    let (prism, _serial) = setup();
+    let mut handle = begin(&prism, 57001);
+    handle.annotate("user", "alice").unwrap();
+    handle.receive(b"hello").unwrap();
+    take(&mut handle);
+    handle.annotate("acl", "allow").unwrap();
+    handle.done();
+    drain(&mut handle);
+    // Replaces the earlier value.
+    handle.annotate("user", "bob").unwrap();
+    drop(handle);
+
+    let document = memory::wait(57001, TIMEOUT).expect("document persisted");
+    assert_eq!(
+        document.json["annotations"],
+        json!({"acl": "allow", "user": "bob"})
+    );
+}
+
+#[test]
+fn annotations_past_the_limits_are_cut_or_ignored() {
+    let (prism, _serial) = setup();
+    let mut handle = begin(&prism, 57101);
+    handle.annotate("", "empty key").unwrap();
+    handle.annotate("too-long-key", "ignored").unwrap();
+    handle.annotate("one", "1").unwrap();
+    handle.annotate("two", "two is cut").unwrap();
+    // Cut on a character boundary, the euro sign takes bytes 4 to 6.
+    handle.annotate("three", "abc€").unwrap();
+    handle.annotate("four", "past the count").unwrap();
+    // Keys already there can still be replaced.
+    handle.annotate("one", "uno").unwrap();
+    handle.receive(b"hello").unwrap();
+    handle.done();
+    drain(&mut handle);
+    drop(handle);
+
+    let document = memory::wait(57101, TIMEOUT).expect("document persisted");
+    assert_eq!(
+        document.json["annotations"],
+        json!({"one": "uno", "three": "abc", "two": "two i"})
+    );
+}
+
+#[test]
+fn timed_out_transactions_keep_their_annotations() {
+    let manual = ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap());
+    let (prism, _serial) = common::setup(|config| {
+        clock::set(manual.clone());
+        config.limits.coalesce_size = 0;
+        config.limits.transaction_deadline = Some(60);
+    });
+    let mut handle = begin(&prism, 57201);
+    handle.annotate("request", "squid-42").unwrap();
+    handle.receive(b"partial").unwrap();
+    take(&mut handle);
+    manual.advance(Duration::from_secs(61));
+    assert_eq!(handle.receive(b"late"), Err(PrismError::Timeout));
+
+    let document = memory::wait(57201, TIMEOUT).expect("document persisted");
+    assert_eq!(document.json["timed_out"], true);
+    assert_eq!(document.json["annotations"], json!({"request": "squid-42"}));
+    drop(handle);
+    clock::reset();
+}
@@ -0,0 +1,157 @@
+//! Asynchronous persists: a single worker keeping up to
+//! `backend.elasticsearch.max_in_flight` documents in flight against a mock
+//! cluster slow to answer them, and stopping only once they are answered.
+
+#![cfg(feature = "async-persistence")]
+
+mod common;
+
+use common::TIMEOUT;
+use prism::Prism;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the mock cluster takes to answer a document.
+const DELAY: Duration = Duration::from_millis(200);
+
+/// Documents the mock cluster is answering, the most it answered at once,
+/// and those it answered.
+#[derive(Default)]
+struct Documents {
+    in_flight: AtomicUsize,
+    most: AtomicUsize,
+    answered: AtomicUsize,
+}
+
+/// Answers index checks at once and documents after `DELAY`, on a thread
+/// per connection.
+fn start() -> (u16, Arc<Documents>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let documents = Arc::new(Documents::default());
+    let served = documents.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                break;
+            };
+            let documents = served.clone();
+            thread::spawn(move || serve(stream, &documents));
+        }
+    });
+    (port, documents)
+}
+
+fn serve(stream: TcpStream, documents: &Documents) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let document = line.starts_with("PUT ") && line.contains("/_doc/");
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        reader.read_exact(&mut vec![0; length]).unwrap();
+
+        let body = if document {
+            let in_flight = documents.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            documents.most.fetch_max(in_flight, Ordering::SeqCst);
+            thread::sleep(DELAY);
+            documents.answered.fetch_add(1, Ordering::SeqCst);
+            documents.in_flight.fetch_sub(1, Ordering::SeqCst);
+            r#"{"result":"created"}"#
+        } else {
+            "{}"
+        };
+        let status = if document { 201 } else { 200 };
+        let response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        if stream.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+/// A single worker persisting to the mock cluster on `port`, with up to
+/// `max_in_flight` documents in flight.
+fn setup(port: u16, max_in_flight: usize) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.backend.kind = "elasticsearch".to_string();
+        let elasticsearch = &mut config.backend.elasticsearch;
+        elasticsearch.hostname = "127.0.0.1".to_string();
+        elasticsearch.port = port;
+        elasticsearch.protocol = "http".to_string();
+        elasticsearch.index = "prism-async".to_string();
+        elasticsearch.max_in_flight = max_in_flight;
+        config.limits.persistence_workers = 1;
+        config.limits.coalesce_size = 0;
+    })
+}
+
+fn submit(prism: &Prism, ids: std::ops::RangeInclusive<i64>) {
+    for id in ids {
+        common::relay(
+            prism,
+            id,
+            &format!("http://async.example.com/{}", id),
+            &[("Content-Type", "text/plain")],
+            b"hello",
+        );
+    }
+}
+
+#[test]
+fn documents_are_persisted_concurrently_up_to_the_limit() {
+    let (port, documents) = start();
+    let (prism, _serial) = setup(port, 4);
+    let started = Instant::now();
+    submit(&prism, 44001..=44012);
+
+    let deadline = Instant::now() + TIMEOUT;
+    while documents.answered.load(Ordering::SeqCst) < 12 {
+        assert!(Instant::now() < deadline, "documents not persisted");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(documents.most.load(Ordering::SeqCst), 4);
+    // Three rounds of four, rather than twelve one after the other.
+    let elapsed = started.elapsed();
+    assert!(elapsed < DELAY * 8, "{:?}", elapsed);
+}
+
+#[test]
+fn shutdown_waits_for_the_documents_in_flight() {
+    let (port, documents) = start();
+    let (prism, _serial) = setup(port, 8);
+    submit(&prism, 44101..=44106);
+    let deadline = Instant::now() + TIMEOUT;
+    while documents.in_flight.load(Ordering::SeqCst) == 0 {
+        assert!(Instant::now() < deadline, "no document in flight");
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    prism.shutdown();
+    assert_eq!(documents.answered.load(Ordering::SeqCst), 6);
+    assert_eq!(documents.in_flight.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,139 @@
+//! The audit log of persisted documents: one line per document stored,
+//! rotated past `audit.max_size`, and failures to write it counted. The
+//! audit log is opened once per process, so a single test goes through it.
+
+mod common;
+
+use common::{counter, TIMEOUT};
+use prism::memory;
+use prism::Prism;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const MAX_SIZE: u64 = 600;
+
+fn directory() -> PathBuf {
+    std::env::temp_dir().join(format!("prism-audit-{}", std::process::id()))
+}
+
+/// The audit logs, oldest first.
+fn logs() -> Vec<PathBuf> {
+    let mut rotated: Vec<PathBuf> = std::fs::read_dir(directory())
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            name.starts_with("audit.log.")
+        })
+        .collect();
+    rotated.sort();
+    rotated.push(directory().join("audit.log"));
+    rotated
+}
+
+/// The lines of every audit log, oldest first.
+fn lines() -> Vec<Value> {
+    logs()
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|log| {
+            log.lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect::<Vec<Value>>()
+        })
+        .collect()
+}
+
+fn run(prism: &Prism, id: i64) {
+    common::run(
+        prism,
+        id,
+        &format!("http://audit.example.com/{}", id),
+        &[("Content-Type", "text/plain")],
+        b"hello",
+    );
+}
+
+/// Waits for the audit line of transaction `id`.
+fn audited(id: i64) -> Value {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(line) = lines()
+            .into_iter()
+            .find(|line| line["transaction_id"] == id)
+        {
+            return line;
+        }
+        assert!(Instant::now() < deadline, "transaction {} not audited", id);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn persisted_documents_are_audited() {
+    let _ = std::fs::remove_dir_all(directory());
+    std::fs::create_dir_all(directory()).unwrap();
+    let (prism, _serial) = common::setup(|config| {
+        config.limits.coalesce_size = 0;
+        config.audit.path = Some(directory().join("audit.log"));
+        config.audit.max_size = Some(MAX_SIZE);
+    });
+    common::capture_logs();
+
+    run(&prism, 36001);
+    let line = audited(36001);
+    let document = memory::wait(36001, TIMEOUT).expect("document persisted");
+    let hash: String = Sha256::digest(b"hello")
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    assert_eq!(line["document_id"], document.document_id);
+    assert_eq!(line["backend"], "memory");
+    assert_eq!(line["destination"], "memory");
+    assert_eq!(line["host"], "audit.example.com");
+    assert_eq!(line["body_sha256"], hash);
+    let timestamp = line["timestamp"].as_str().unwrap();
+    assert!(
+        chrono::DateTime::parse_from_rfc3339(timestamp).is_ok(),
+        "{}",
+        timestamp
+    );
+
+    // Rotated by the audit log's own limit rather than `rotation.max_size`.
+    for id in 36002..=36008 {
+        run(&prism, id);
+        audited(id);
+    }
+    let logs = logs();
+    assert!(logs.len() > 1, "{:?}", logs);
+    for path in &logs {
+        let size = std::fs::metadata(path).unwrap().len();
+        assert!(size <= MAX_SIZE, "{} is {} bytes", path.display(), size);
+    }
+    let ids: Vec<i64> = lines()
+        .iter()
+        .map(|line| line["transaction_id"].as_i64().unwrap())
+        .collect();
+    assert_eq!(ids, (36001..=36008).collect::<Vec<_>>());
+
+    // Documents are still persisted once the audit log can't be written.
+    let failures = counter(&prism, "audit_failures");
+    std::fs::remove_dir_all(directory()).unwrap();
+    for id in 36101..=36104 {
+        run(&prism, id);
+        assert!(memory::wait(id, TIMEOUT).is_some());
+    }
+    let deadline = Instant::now() + TIMEOUT;
+    while counter(&prism, "audit_failures") < failures + 3 {
+        assert!(Instant::now() < deadline, "audit failures not counted");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let warnings: Vec<String> = common::warnings()
+        .into_iter()
+        .filter(|warning| warning.contains("audit log"))
+        .collect();
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].ends_with("(further audit log failures are only counted)"));
+}
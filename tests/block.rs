@@ -0,0 +1,110 @@
+//! Blocking a transaction mid-stream: the client stream switches from the
+//! origin bytes to the block page, the response headers report the 403,
+//! and the document still holds the whole original body and the reason.
+
+mod common;
+
+use common::{drain, gzip, setup, take, TIMEOUT};
+use prism::{memory, TransactionHandle};
+
+const PLAIN: [(&str, &str); 1] = [("Content-Type", "text/html")];
+
+fn page() -> Vec<u8> {
+    (0..2000)
+        .map(|row| format!("<li>origin row {}</li>\n", row))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Receives the first half of `body`, blocks the transaction for `reason`,
+/// then receives the rest. Returns what was handed back before blocking
+/// and after.
+fn block_midway(handle: &mut TransactionHandle, body: &[u8], reason: &str) -> (Vec<u8>, Vec<u8>) {
+    let (head, tail) = body.split_at(body.len() / 2);
+    handle.status(200);
+    handle.receive(head).unwrap();
+    let before = take(handle);
+    handle.block(reason).unwrap();
+    for chunk in tail.chunks(1000) {
+        handle.receive(chunk).unwrap();
+    }
+    handle.done();
+    (before, drain(handle))
+}
+
+#[test]
+fn blocked_transactions_switch_to_the_block_page() {
+    let (prism, _serial) = setup(|config| config.limits.coalesce_size = 0);
+    let body = page();
+    let mut handle = prism.begin(51001, "GET", "http://block.example.com/", &PLAIN);
+    let (before, after) = block_midway(&mut handle, &body, "DLP <card number>");
+
+    // Origin bytes up to the block, then the page alone.
+    assert!(!before.is_empty());
+    assert!(body.starts_with(&before));
+    let page = String::from_utf8(after).unwrap();
+    assert!(page.starts_with("<!DOCTYPE html>"), "{}", page);
+    assert!(page.contains("<p>Reason: DLP &lt;card number&gt;</p>"));
+    assert!(page.contains("<p>Reference: 51001</p>"));
+    assert!(!page.contains("origin row"));
+
+    assert_eq!(handle.response_header(":status").as_deref(), Some("403"));
+    assert_eq!(
+        handle.response_header("Content-Type").as_deref(),
+        Some("text/html; charset=utf-8")
+    );
+    assert_eq!(
+        handle.response_header("Content-Length"),
+        Some(page.len().to_string())
+    );
+    drop(handle);
+
+    let document = memory::wait(51001, TIMEOUT).unwrap();
+    assert_eq!(document.body, body);
+    assert_eq!(document.json["blocked"], true);
+    assert_eq!(document.json["block_reason"], "DLP <card number>");
+}
+
+#[test]
+fn blocked_gzip_bodies_are_captured_decoded_and_replaced_unencoded() {
+    let (prism, _serial) = setup(|config| config.limits.coalesce_size = 0);
+    let body = page();
+    let headers = [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+    let mut handle = prism.begin(51101, "GET", "http://block.example.com/", &headers);
+    let (_, after) = block_midway(&mut handle, &gzip(&body), "denied category");
+
+    let page = String::from_utf8(after).unwrap();
+    assert!(page.contains("<p>Reason: denied category</p>"), "{}", page);
+    // The page goes out as it is, the origin encoding removed.
+    assert_eq!(
+        handle.response_header("Content-Encoding").as_deref(),
+        Some("")
+    );
+    assert_eq!(handle.response_header(":status").as_deref(), Some("403"));
+    drop(handle);
+
+    let document = memory::wait(51101, TIMEOUT).unwrap();
+    assert_eq!(document.body, body);
+    assert_eq!(document.json["blocked"], true);
+    assert_eq!(document.json["block_reason"], "denied category");
+}
+
+#[test]
+fn block_pages_follow_the_configured_template() {
+    let template = common::temporary("block.html");
+    std::fs::write(&template, "blocked {id}: {reason}").unwrap();
+    let (prism, _serial) = setup(|config| {
+        config.limits.coalesce_size = 0;
+        config.filters.block_page = Some(template.clone());
+    });
+    let mut handle = prism.begin(51201, "GET", "http://block.example.com/", &PLAIN);
+    let (_, after) = block_midway(&mut handle, &page(), "policy");
+    assert_eq!(after, b"blocked 51201: policy");
+    assert_eq!(
+        handle.response_header("Content-Length").as_deref(),
+        Some("21")
+    );
+    drop(handle);
+    assert_eq!(memory::wait(51201, TIMEOUT).unwrap().body, page());
+    std::fs::remove_file(&template).unwrap();
+}
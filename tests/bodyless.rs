@@ -0,0 +1,127 @@
+//! Responses without a body: to HEAD requests, 204 and 304 statuses, and
+//! origins sending a body for those anyway.
+
+mod common;
+
+use common::{drain, gzip, setup};
+use flate2::read::GzDecoder;
+use prism::TransactionHandle;
+use serde_json::Value;
+use std::io::prelude::*;
+
+fn persisted(handle: TransactionHandle) -> Value {
+    let id = handle.id();
+    drop(handle);
+    common::document(id)
+}
+
+const GZIP_HEADERS: [(&str, &str); 3] = [
+    ("Content-Type", "text/html"),
+    ("Content-Encoding", "gzip"),
+    ("Content-Length", "1234"),
+];
+
+#[test]
+fn head_responses_keep_their_framing_and_end_right_away() {
+    let (prism, _serial) = setup(|_| {});
+    let mut handle = prism.begin(7001, "HEAD", "http://bodyless.example.com/", &GZIP_HEADERS);
+    handle.status(200);
+    assert_eq!(handle.response_header("Content-Length"), None);
+    assert_eq!(handle.response_header("Transfer-Encoding"), None);
+
+    handle.done();
+    let mut buffer = [0; 64];
+    assert_eq!(handle.poll_output(&mut buffer), 0);
+    assert!(handle.finished());
+    assert_eq!(handle.response_header("Content-Length"), None);
+
+    let document = persisted(handle);
+    assert_eq!(document["has_body"], false);
+    assert_eq!(document["method"], "HEAD");
+    assert_eq!(document["original_length"], 1234);
+    assert_eq!(document["emitted_length"], 0);
+    assert!(document.get("body").is_none());
+    assert!(document.get("raw_body").is_none());
+}
+
+#[test]
+fn no_content_responses_have_no_body() {
+    let (prism, _serial) = setup(|_| {});
+    // Status known when the transaction starts.
+    let mut handle = prism.begin(
+        7002,
+        "GET",
+        "http://bodyless.example.com/",
+        &[(":status", "204"), ("Content-Encoding", "gzip")],
+    );
+    assert_eq!(handle.response_header("Transfer-Encoding"), None);
+    handle.done();
+    assert!(drain(&mut handle).is_empty());
+    let document = persisted(handle);
+    assert_eq!(document["status"], 204);
+    assert_eq!(document["has_body"], false);
+
+    // Status reported once the transaction started.
+    let mut handle = prism.begin(7003, "GET", "http://bodyless.example.com/", &GZIP_HEADERS);
+    assert_eq!(
+        handle.response_header("Transfer-Encoding").as_deref(),
+        Some("chunked")
+    );
+    handle.status(204);
+    assert_eq!(handle.response_header("Content-Length"), None);
+    assert_eq!(handle.response_header("Transfer-Encoding"), None);
+    handle.done();
+    assert!(drain(&mut handle).is_empty());
+    assert_eq!(persisted(handle)["has_body"], false);
+}
+
+#[test]
+fn empty_bodies_are_found_out_when_done() {
+    let (prism, _serial) = setup(|_| {});
+    let mut handle = prism.begin(7004, "GET", "http://bodyless.example.com/", &GZIP_HEADERS);
+    handle.status(200);
+    handle.done();
+    // Nothing is encoded, not even an empty gzip stream.
+    assert!(drain(&mut handle).is_empty());
+    assert_eq!(
+        handle.response_header("Content-Length").as_deref(),
+        Some("0")
+    );
+    assert_eq!(persisted(handle)["has_body"], false);
+}
+
+#[test]
+fn bodies_sent_anyway_are_relayed() {
+    let (prism, _serial) = setup(|_| {});
+    let body = gzip(b"<p>not expected</p>");
+
+    // Predicted bodyless: relayed untouched, without decoding.
+    let mut handle = prism.begin(7005, "HEAD", "http://bodyless.example.com/", &GZIP_HEADERS);
+    handle.status(200);
+    handle.receive(&body).unwrap();
+    handle.done();
+    assert_eq!(drain(&mut handle), body);
+    assert_eq!(handle.response_header("Content-Length"), None);
+    let document = persisted(handle);
+    assert_eq!(document["has_body"], true);
+    assert_eq!(document["emitted_length"], body.len());
+
+    // Found out from the status: re-encoded, and sent chunked again.
+    let mut handle = prism.begin(7006, "GET", "http://bodyless.example.com/", &GZIP_HEADERS);
+    handle.status(304);
+    handle.receive(&body).unwrap();
+    assert_eq!(
+        handle.response_header("Transfer-Encoding").as_deref(),
+        Some("chunked")
+    );
+    handle.done();
+    let output = drain(&mut handle);
+    let mut decoded = Vec::new();
+    GzDecoder::new(&output[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, b"<p>not expected</p>");
+    let document = persisted(handle);
+    assert_eq!(document["has_body"], true);
+    assert_eq!(document["body"], "<p>not expected</p>");
+}
@@ -0,0 +1,145 @@
+//! Cache validation headers captured as typed fields, kept raw when they
+//! can't be parsed, and headers repeated over several lines: combined into
+//! one list, except `Set-Cookie`, and the encoding of bodies told by the
+//! last coding listed.
+
+mod common;
+
+use common::{gunzip, gzip, setup};
+use prism::{memory, Prism};
+use serde_json::{json, Value};
+
+/// Runs a response with `headers` through, and returns its document.
+fn run(prism: &Prism, id: i64, headers: &[(&str, &str)]) -> Value {
+    let mut all = vec![("Content-Type", "text/plain")];
+    all.extend_from_slice(headers);
+    common::run(prism, id, "http://cache.example.com/", &all, b"hello")
+}
+
+#[test]
+fn repeated_encodings_decode_the_last_coding() {
+    let (prism, _serial) = setup(|_| {});
+    let page = b"<p>encoded twice</p>\n".repeat(64);
+    let once = gzip(&page);
+    let output = common::relay(
+        &prism,
+        25001,
+        "http://cache.example.com/",
+        &[
+            ("Content-Type", "text/html"),
+            ("Content-Encoding", "gzip"),
+            ("Content-Encoding", "gzip"),
+        ],
+        &gzip(&once),
+    );
+    assert_eq!(gunzip(&gunzip(&output)), page);
+
+    let document = memory::wait(25001, common::TIMEOUT).expect("document persisted");
+    assert_eq!(document.encoding, "gzip, gzip");
+    assert_eq!(document.body, once);
+}
+
+#[test]
+fn set_cookie_lines_are_kept_apart() {
+    let (prism, _serial) = setup(|_| {});
+    let document = common::run(
+        &prism,
+        25002,
+        "http://cache.example.com/",
+        &[
+            ("Content-Type", "text/plain"),
+            ("Set-Cookie", "a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT"),
+            ("Set-Cookie", "b=2; Path=/"),
+            ("Cache-Control", "public"),
+            ("Cache-Control", "max-age=60"),
+        ],
+        b"hello",
+    );
+    let headers = &document["response_headers"];
+    assert_eq!(
+        headers["Set-Cookie"],
+        "a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT\nb=2; Path=/"
+    );
+    assert_eq!(headers["Cache-Control"], "public, max-age=60");
+}
+
+#[test]
+fn etags_are_kept_quoted() {
+    let (prism, _serial) = setup(|_| {});
+    let strong = run(&prism, 25101, &[("ETag", " \"33a64df5\" ")]);
+    assert_eq!(strong["etag"], "\"33a64df5\"");
+    let weak = run(&prism, 25102, &[("ETag", "W/\"0815\"")]);
+    assert_eq!(weak["etag"], "W/\"0815\"");
+    let empty = run(&prism, 25103, &[("ETag", " ")]);
+    assert!(empty.get("etag").is_none());
+}
+
+#[test]
+fn dates_are_parsed_in_every_http_format() {
+    let (prism, _serial) = setup(|_| {});
+    let dates = [
+        "Wed, 21 Oct 2026 07:28:00 GMT",
+        "Wednesday, 21-Oct-26 07:28:00 GMT",
+        "Wed Oct 21 07:28:00 2026",
+    ];
+    for (id, date) in (25201..).zip(dates) {
+        let document = run(&prism, id, &[("Last-Modified", date)]);
+        assert_eq!(
+            document["last_modified"], "2026-10-21T07:28:00Z",
+            "{}",
+            date
+        );
+        assert!(document.get("last_modified_raw").is_none());
+    }
+}
+
+#[test]
+fn malformed_dates_are_kept_raw() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(&prism, 25204, &[("Last-Modified", "yesterday")]);
+    assert!(document.get("last_modified").is_none());
+    assert_eq!(document["last_modified_raw"], "yesterday");
+}
+
+#[test]
+fn directives_of_several_cache_control_headers_are_listed() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        25301,
+        &[
+            ("Cache-Control", "Public, max-age=60"),
+            ("Cache-Control", "no-cache=\"Set-Cookie, X-Id\""),
+        ],
+    );
+    assert_eq!(
+        document["cache_control"],
+        json!(["public", "max-age=60", "no-cache=Set-Cookie, X-Id"])
+    );
+    assert!(document.get("cache_control_raw").is_none());
+}
+
+#[test]
+fn malformed_cache_control_is_kept_raw() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        25302,
+        &[
+            ("Cache-Control", "max-age=60"),
+            ("Cache-Control", "private=\"x"),
+        ],
+    );
+    assert!(document.get("cache_control").is_none());
+    assert_eq!(document["cache_control_raw"], "max-age=60, private=\"x");
+}
+
+#[test]
+fn ages_are_integers_or_kept_raw() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(&prism, 25401, &[("Age", " 120 ")]);
+    assert_eq!(document["age"], 120);
+    let document = run(&prism, 25402, &[("Age", "soon")]);
+    assert!(document.get("age").is_none());
+    assert_eq!(document["age_raw"], "soon");
+}
@@ -0,0 +1,154 @@
+//! Chunk checksums across the exported functions: `send_checked()` hands
+//! back the CRC32 of each chunk, `receive_checked()` drops chunks not
+//! matching theirs, and `logging.chunk_checksums` adds them to the trace
+//! lines of traced transactions.
+
+mod common;
+
+use common::{bytes, stats, traced, TIMEOUT};
+use prism::checksum::crc32;
+use prism::error::PrismError;
+use prism::memory;
+use std::ffi::c_void;
+use std::sync::MutexGuard;
+
+const BODY: &[u8] = b"hello world";
+
+/// Installs a configuration logging chunk checksums or not, applying to
+/// the transactions started from then on.
+fn setup(chunk_checksums: bool) -> MutexGuard<'static, ()> {
+    let serial = common::serial();
+    common::capture_logs();
+    common::configure(&format!(
+        "{}\n[logging]\nchunk_checksums = {}\n",
+        common::MEMORY_BACKEND,
+        chunk_checksums
+    ));
+    serial
+}
+
+fn begin(id: i64, trace: bool) {
+    common::begin(
+        id,
+        &format!("http://checksums.example.com/{}", id),
+        &[("Content-Type", "text/plain")],
+    );
+    if trace {
+        prism::trace(id, true);
+    }
+}
+
+fn receive_checked(id: i64, data: &[u8], crc32: u32) -> i32 {
+    prism::receive_checked(id, data.as_ptr() as *const c_void, data.len(), crc32)
+}
+
+/// Ends the body, and takes the chunks handed back until the last one,
+/// checking each against its checksum.
+fn finish_checked(id: i64) -> Vec<Vec<u8>> {
+    prism::done(id);
+    let mut chunks = Vec::new();
+    loop {
+        let chunk = prism::send_checked(id, 0, 0);
+        let data = bytes(chunk.size, chunk.bytes);
+        assert_eq!(chunk.crc32, crc32(&data));
+        if data.is_empty() {
+            return chunks;
+        }
+        chunks.push(data);
+    }
+}
+
+#[test]
+fn chunks_sent_carry_their_checksum() {
+    let _serial = setup(false);
+    // The check value of CRC32 (IEEE).
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b""), 0);
+
+    let id = 22001;
+    begin(id, false);
+    assert_eq!(receive_checked(id, BODY, crc32(BODY)), 0);
+    let chunks = finish_checked(id);
+    prism::cleanup(id);
+
+    assert_eq!(chunks.concat(), BODY);
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.body, BODY);
+}
+
+#[test]
+fn chunks_not_matching_their_checksum_are_dropped() {
+    let _serial = setup(false);
+    let id = 22002;
+    let before = stats()["counters"]["checksum_mismatches"].as_u64().unwrap();
+    begin(id, false);
+    assert_eq!(receive_checked(id, b"hello ", crc32(b"hello ")), 0);
+    // Altered on the way from the caller.
+    assert_eq!(
+        receive_checked(id, b"wOrld", crc32(b"world")),
+        PrismError::ChecksumMismatch.code()
+    );
+    assert_eq!(
+        stats()["counters"]["checksum_mismatches"].as_u64(),
+        Some(before + 1)
+    );
+    // Nothing of the mismatching chunk was buffered, the caller sends it
+    // again.
+    assert_eq!(receive_checked(id, b"world", crc32(b"world")), 0);
+    let chunks = finish_checked(id);
+    prism::cleanup(id);
+
+    assert_eq!(chunks.concat(), BODY);
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.body, BODY);
+    assert_eq!(
+        receive_checked(22003, BODY, crc32(BODY)),
+        PrismError::UnknownTransaction.code()
+    );
+}
+
+#[test]
+fn checksums_are_traced_when_enabled() {
+    let _serial = setup(true);
+    let id = 22101;
+    begin(id, true);
+    prism::receive(id, BODY.as_ptr() as *const c_void, BODY.len());
+    let chunks = finish_checked(id);
+    prism::cleanup(id);
+
+    assert!(traced(&format!(
+        "Transaction {} trace: received {} bytes, crc32 {:08x}",
+        id,
+        BODY.len(),
+        crc32(BODY)
+    )));
+    assert!(!chunks.is_empty());
+    for chunk in chunks {
+        assert!(traced(&format!(
+            "send returned {} bytes, crc32 {:08x} (",
+            chunk.len(),
+            crc32(&chunk)
+        )));
+    }
+    memory::wait(id, TIMEOUT).expect("document persisted");
+}
+
+#[test]
+fn checksums_are_not_traced_when_disabled() {
+    let _serial = setup(false);
+    let id = 22201;
+    begin(id, true);
+    prism::receive(id, BODY.as_ptr() as *const c_void, BODY.len());
+    let output = common::finish(id);
+    prism::cleanup(id);
+
+    assert_eq!(output, BODY);
+    assert!(traced(&format!(
+        "Transaction {} trace: received {} bytes",
+        id,
+        BODY.len()
+    )));
+    assert!(traced("send returned"));
+    assert!(!traced("crc32"));
+    memory::wait(id, TIMEOUT).expect("document persisted");
+}
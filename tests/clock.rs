@@ -0,0 +1,96 @@
+//! Transactions run against a frozen clock, checking documents come out
+//! the same every time and follow the clock as it is advanced.
+
+mod common;
+
+use prism::clock::{self, ManualClock};
+use prism::{memory, Prism};
+use std::sync::{MutexGuard, OnceLock};
+use std::time::Duration;
+
+const DATE: &str = "2024-01-01T00:00:00Z";
+
+static PRISM: OnceLock<Prism> = OnceLock::new();
+
+/// Every test replaces the clock, so they all hold the lock.
+fn setup() -> (&'static Prism, MutexGuard<'static, ()>) {
+    let prism = PRISM.get_or_init(|| {
+        let mut config = common::config();
+        config.limits.coalesce_size = 0;
+        Prism::new(config).unwrap()
+    });
+    let serial = common::serial();
+    clock::reset();
+    memory::clear();
+    (prism, serial)
+}
+
+/// Runs a transaction through and returns its document as indexed.
+fn transaction(prism: &Prism, id: i64) -> serde_json::Value {
+    common::run(
+        prism,
+        id,
+        "http://clock.example.com/page",
+        &[("Content-Type", "text/plain")],
+        b"hello world",
+    )
+}
+
+#[test]
+fn documents_are_identical_under_a_frozen_clock() {
+    let (prism, _serial) = setup();
+    clock::set(ManualClock::at(DATE.parse().unwrap()));
+
+    let mut first = transaction(prism, 1);
+    let mut second = transaction(prism, 2);
+    clock::reset();
+    assert_eq!(first["date"], DATE);
+    // Ids are the one thing telling documents of different transactions
+    // apart.
+    assert_ne!(first["document_id"], second["document_id"]);
+    for document in [&mut first, &mut second] {
+        document.as_object_mut().unwrap().remove("document_id");
+    }
+    assert_eq!(
+        serde_json::to_vec(&first).unwrap(),
+        serde_json::to_vec(&second).unwrap()
+    );
+}
+
+#[test]
+fn documents_follow_the_clock_as_it_advances() {
+    let (prism, _serial) = setup();
+    let frozen = ManualClock::at(DATE.parse().unwrap());
+    clock::set(frozen.clone());
+
+    assert_eq!(transaction(prism, 3)["date"], DATE);
+    frozen.advance(Duration::from_secs(3600));
+    assert_eq!(transaction(prism, 4)["date"], "2024-01-01T01:00:00Z");
+    clock::reset();
+}
+
+#[test]
+fn documents_of_a_run_share_its_id() {
+    let (prism, _serial) = setup();
+    let run_id = clock::run_id();
+    assert_ne!(run_id, 0);
+    assert_eq!(transaction(prism, 5)["run_id"], run_id);
+    assert_eq!(transaction(prism, 6)["run_id"], run_id);
+    clock::set_run_id(42);
+    assert_eq!(transaction(prism, 7)["run_id"], 42);
+    clock::start_run();
+}
+
+#[test]
+fn every_start_begins_a_new_run() {
+    let (_prism, _serial) = setup();
+    let mut config = common::config();
+    config.limits.coalesce_size = 0;
+
+    let first = Prism::new(config.clone()).unwrap();
+    let run_id = clock::run_id();
+    first.shutdown();
+    let second = Prism::new(config).unwrap();
+    assert!(clock::run_id() > run_id);
+    second.shutdown();
+}
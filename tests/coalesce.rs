@@ -0,0 +1,99 @@
+//! Small received chunks gathered up to `limits.coalesce_size` before they
+//! enter the pipeline: few chunks queued for a trickled body, the gathered
+//! bytes handed back once they waited `limits.coalesce_delay_ms`, and none
+//! left behind by `done()`. The clock is manual, so nothing is handed back
+//! for having waited unless a test advances it.
+
+mod common;
+
+use common::{drain, gunzip, gzip, state, take, TIMEOUT};
+use prism::clock::{self, ManualClock};
+use prism::{memory, Prism};
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
+
+const COALESCE_SIZE: usize = 4096;
+const PLAIN: [(&str, &str); 1] = [("Content-Type", "text/plain")];
+
+fn setup() -> (Prism, Arc<ManualClock>, MutexGuard<'static, ()>) {
+    let manual = ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap());
+    let (prism, serial) = common::setup(|config| {
+        config.limits.coalesce_size = COALESCE_SIZE;
+        config.limits.coalesce_delay_ms = 10;
+    });
+    clock::set(manual.clone());
+    (prism, manual, serial)
+}
+
+fn body() -> Vec<u8> {
+    (0..10_000).map(|index| b'a' + (index % 26) as u8).collect()
+}
+
+#[test]
+fn trickled_bodies_are_queued_in_few_chunks() {
+    let (prism, _clock, _serial) = setup();
+    let body = body();
+    let mut handle = prism.begin(45001, "GET", "http://coalesce.example.com/", &PLAIN);
+    handle.status(200);
+    for chunk in body.chunks(100) {
+        handle.receive(chunk).unwrap();
+    }
+    // Gathered into two chunks of 4100 bytes, the last 1800 still waiting.
+    assert_eq!(state(&prism, 45001)["queued_chunks"], 2);
+    let mut output = take(&mut handle);
+    assert_eq!(output.len(), 2 * (COALESCE_SIZE + 4));
+
+    handle.done();
+    output.extend(drain(&mut handle));
+    drop(handle);
+    assert_eq!(output, body);
+    assert_eq!(memory::wait(45001, TIMEOUT).unwrap().body, body);
+    clock::reset();
+}
+
+#[test]
+fn trickled_gzip_bodies_decode_whole() {
+    let (prism, _clock, _serial) = setup();
+    let body = body();
+    let headers = [("Content-Type", "text/plain"), ("Content-Encoding", "gzip")];
+    let mut handle = prism.begin(45101, "GET", "http://coalesce.example.com/", &headers);
+    handle.status(200);
+    let mut output = Vec::new();
+    for chunk in gzip(&body).chunks(100) {
+        handle.receive(chunk).unwrap();
+        output.extend(take(&mut handle));
+    }
+    handle.done();
+    output.extend(drain(&mut handle));
+    drop(handle);
+    assert_eq!(gunzip(&output), body);
+    assert_eq!(memory::wait(45101, TIMEOUT).unwrap().body, body);
+    clock::reset();
+}
+
+#[test]
+fn gathered_bytes_are_handed_back_after_the_delay() {
+    let (prism, manual, _serial) = setup();
+    let mut handle = prism.begin(45201, "GET", "http://coalesce.example.com/", &PLAIN);
+    handle.status(200);
+    handle.receive(b"a slow origin").unwrap();
+    assert!(take(&mut handle).is_empty());
+
+    manual.advance(Duration::from_millis(10));
+    assert_eq!(take(&mut handle), b"a slow origin");
+    clock::reset();
+}
+
+#[test]
+fn done_hands_back_the_gathered_bytes() {
+    let (prism, _clock, _serial) = setup();
+    let mut handle = prism.begin(45301, "GET", "http://coalesce.example.com/", &PLAIN);
+    handle.status(200);
+    for chunk in [&b"one, "[..], b"two, ", b"three"] {
+        handle.receive(chunk).unwrap();
+    }
+    assert!(take(&mut handle).is_empty());
+    handle.done();
+    assert_eq!(drain(&mut handle), b"one, two, three");
+    clock::reset();
+}
@@ -0,0 +1,288 @@
+//! Scaffolding shared by the integration tests, each including it with
+//! `mod common;`: the lock serializing the tests of a binary, instances
+//! persisting to the memory backend and the transactions run through them,
+//! the exported functions called the way the adapter calls them, and a
+//! logger keeping what the library logs. Every test binary uses only part
+//! of it.
+
+#![allow(dead_code)]
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prism::config::Config;
+use prism::{memory, Prism, TransactionHandle};
+use serde_json::Value;
+use std::ffi::{c_char, c_void, CString};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, Once};
+use std::time::Duration;
+
+/// How long a test waits for the persistence workers.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+/// Configuration of the tests driving the exported functions.
+pub const MEMORY_BACKEND: &str = "[backend]\ntype = \"memory\"\n";
+
+/// Held by every test, as they share the library's global state or install
+/// their own configuration.
+static SERIAL: Mutex<()> = Mutex::new(());
+static CONFIGURE: Once = Once::new();
+static LOGGER_SETUP: Once = Once::new();
+static RECORDS: Mutex<Vec<Logged>> = Mutex::new(Vec::new());
+/// Numbers the temporary files of a test binary.
+static TEMPORARY: AtomicUsize = AtomicUsize::new(0);
+
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Serializes the tests of a binary, until the guard is dropped.
+pub fn serial() -> MutexGuard<'static, ()> {
+    lock(&SERIAL)
+}
+
+/// A path of the temporary directory no other test of any binary uses.
+pub fn temporary(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "prism-{}-{}-{}",
+        std::process::id(),
+        TEMPORARY.fetch_add(1, Ordering::Relaxed),
+        name
+    ))
+}
+
+/// A configuration persisting to the memory backend.
+pub fn config() -> Config {
+    let mut config = Config::load(None).unwrap();
+    config.backend.kind = "memory".to_string();
+    config
+}
+
+/// An instance persisting to the memory backend, configured further by
+/// `configure`, and the guard serializing the test.
+pub fn setup(configure: impl FnOnce(&mut Config)) -> (Prism, MutexGuard<'static, ()>) {
+    let serial = serial();
+    let mut config = config();
+    configure(&mut config);
+    (Prism::new(config).unwrap(), serial)
+}
+
+/// Hands output back until the end of the body, and returns it.
+pub fn drain(handle: &mut TransactionHandle) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut buffer = [0; 4096];
+    while !handle.finished() {
+        let size = handle.poll_output(&mut buffer);
+        output.extend_from_slice(&buffer[..size]);
+    }
+    output
+}
+
+/// Hands back the output available now, and returns it.
+pub fn take(handle: &mut TransactionHandle) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let size = handle.poll_output(&mut buffer);
+        if size == 0 {
+            return output;
+        }
+        output.extend_from_slice(&buffer[..size]);
+    }
+}
+
+/// Runs a GET of `uri` answered with `headers` and `body` through, and
+/// returns the output.
+pub fn relay(prism: &Prism, id: i64, uri: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut handle = prism.begin(id, "GET", uri, headers);
+    handle.status(200);
+    handle.receive(body).unwrap();
+    handle.done();
+    drain(&mut handle)
+}
+
+/// The document of transaction `id`, once persisted.
+pub fn document(id: i64) -> Value {
+    memory::wait(id, TIMEOUT).expect("document persisted").json
+}
+
+/// Runs a GET of `uri` answered with `headers` and `body` through, and
+/// returns its document.
+pub fn run(prism: &Prism, id: i64, uri: &str, headers: &[(&str, &str)], body: &[u8]) -> Value {
+    relay(prism, id, uri, headers, body);
+    document(id)
+}
+
+pub fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn gunzip(body: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    GzDecoder::new(body).read_to_end(&mut decoded).unwrap();
+    decoded
+}
+
+/// The state of `prism`, as `dump_state()` writes it.
+pub fn dump(prism: &Prism) -> Value {
+    let path = temporary("dump.json");
+    prism.dump_state(&path).unwrap();
+    let dump = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    serde_json::from_slice(&dump).unwrap()
+}
+
+/// The dumped state of transaction `id`.
+pub fn state(prism: &Prism, id: i64) -> Value {
+    dump(prism)["transactions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|transaction| transaction["id"] == id)
+        .cloned()
+        .unwrap()
+}
+
+pub fn counter(prism: &Prism, name: &str) -> u64 {
+    dump(prism)["counters"][name].as_u64().unwrap()
+}
+
+/// Configures the library from `toml` through `configure()`, as the
+/// adapter does with its configuration file.
+pub fn configure(toml: &str) {
+    let path = temporary("config.toml");
+    std::fs::write(&path, toml).unwrap();
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(prism::configure(c_path.as_ptr()), 0);
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Configures the library with the memory backend the first time, before
+/// anything else reads a configuration, and serializes the test.
+pub fn setup_ffi() -> MutexGuard<'static, ()> {
+    CONFIGURE.call_once(|| configure(MEMORY_BACKEND));
+    serial()
+}
+
+/// The `size` bytes at `bytes` of a chunk handed back by the library.
+pub fn bytes(size: usize, bytes: *const c_void) -> Vec<u8> {
+    if size == 0 {
+        return Vec::new();
+    }
+    unsafe { std::slice::from_raw_parts(bytes as *const u8, size) }.to_vec()
+}
+
+pub fn stats() -> Value {
+    let chunk = prism::stats();
+    serde_json::from_slice(&bytes(chunk.size, chunk.bytes)).unwrap()
+}
+
+/// One of the exported functions passing a header.
+pub type Export = extern "C" fn(i64, *const c_char, *const c_char);
+
+pub fn header(export: Export, id: i64, name: &str, value: &str) {
+    let name = CString::new(name).unwrap();
+    let value = CString::new(value).unwrap();
+    export(id, name.as_ptr(), value.as_ptr());
+}
+
+/// Begins a GET of `uri` answered with `headers`.
+pub fn begin(id: i64, uri: &str, headers: &[(&str, &str)]) {
+    for (name, value) in headers {
+        header(prism::header, id, name, value);
+    }
+    prism::status(id, 200);
+    let uri = CString::new(uri).unwrap();
+    let method = CString::new("GET").unwrap();
+    prism::uri(id, uri.as_ptr(), 1, method.as_ptr());
+}
+
+pub fn receive(id: i64, data: &[u8]) {
+    prism::receive(id, data.as_ptr() as *const c_void, data.len());
+}
+
+pub fn send(id: i64) -> Vec<u8> {
+    let chunk = prism::send(id, 0, 0);
+    bytes(chunk.size, chunk.bytes)
+}
+
+/// Ends the body, and returns the output handed back until the last chunk.
+pub fn finish(id: i64) -> Vec<u8> {
+    prism::done(id);
+    let mut output = Vec::new();
+    loop {
+        let chunk = send(id);
+        if chunk.is_empty() {
+            return output;
+        }
+        output.extend(chunk);
+    }
+}
+
+/// A line the library logged.
+pub struct Logged {
+    pub level: log::Level,
+    pub target: String,
+    pub message: String,
+}
+
+/// Keeps what the library logs, up to `Info`.
+struct Capture;
+
+impl log::Log for Capture {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            lock(&RECORDS).push(Logged {
+                level: record.level(),
+                target: record.target().to_string(),
+                message: record.args().to_string(),
+            });
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Capture = Capture;
+
+/// Keeps what the library logs from then on, forgetting what it logged
+/// before. `init()` is never called, so the logger stays installed.
+pub fn capture_logs() {
+    LOGGER_SETUP.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+    });
+    lock(&RECORDS).clear();
+}
+
+/// The messages kept that `matching` accepts.
+pub fn logged(matching: impl Fn(&Logged) -> bool) -> Vec<String> {
+    lock(&RECORDS)
+        .iter()
+        .filter(|logged| matching(logged))
+        .map(|logged| logged.message.clone())
+        .collect()
+}
+
+/// The warnings and errors kept.
+pub fn warnings() -> Vec<String> {
+    logged(|logged| logged.level <= log::Level::Warn)
+}
+
+/// Whether a trace line kept contains `text`.
+pub fn traced(text: &str) -> bool {
+    logged(|logged| logged.target == "prism::trace")
+        .iter()
+        .any(|line| line.contains(text))
+}
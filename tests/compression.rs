@@ -0,0 +1,91 @@
+//! Compression ratios and savings persisted with documents.
+
+mod common;
+
+use common::{gzip, setup};
+use prism::Prism;
+use serde_json::Value;
+
+/// Bytes that do not compress (xorshift64).
+fn random(size: usize) -> Vec<u8> {
+    let mut state = 0x2545f4914f6cdd1du64;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Runs `body` through a transaction and returns its document.
+fn run(prism: &Prism, id: i64, headers: &[(&str, &str)], body: &[u8]) -> Value {
+    let mut handle = prism.begin(id, "GET", "http://compression.example.com/", headers);
+    handle.status(200);
+    for chunk in body.chunks(4096) {
+        handle.receive(chunk).unwrap();
+    }
+    handle.done();
+    common::drain(&mut handle);
+    drop(handle);
+    common::document(id)
+}
+
+const GZIP_HEADERS: [(&str, &str); 2] = [
+    ("Content-Type", "application/octet-stream"),
+    ("Content-Encoding", "gzip"),
+];
+
+#[test]
+fn compressible_bodies_save_bytes() {
+    let (prism, _serial) = setup(|_| {});
+    let body = "<li>compressible markup</li>\n".repeat(4096).into_bytes();
+    let encoded = gzip(&body);
+    let document = run(&prism, 8001, &GZIP_HEADERS, &encoded);
+
+    let ratio = document["compression_ratio"].as_f64().unwrap();
+    assert_eq!(ratio, body.len() as f64 / encoded.len() as f64);
+    assert!(ratio > 50.0, "ratio {}", ratio);
+    assert_eq!(document["bytes_saved"], body.len() - encoded.len());
+
+    let emitted = document["emitted_length"].as_u64().unwrap() as usize;
+    assert!(document["output_compression_ratio"].as_f64().unwrap() > 1.0);
+    assert_eq!(
+        document["output_bytes_saved"].as_i64().unwrap(),
+        body.len() as i64 - emitted as i64
+    );
+}
+
+#[test]
+fn incompressible_bodies_grow() {
+    let (prism, _serial) = setup(|_| {});
+    let body = random(32 * 1024);
+    let encoded = gzip(&body);
+    let document = run(&prism, 8002, &GZIP_HEADERS, &encoded);
+
+    assert!(document["compression_ratio"].as_f64().unwrap() < 1.0);
+    assert_eq!(
+        document["bytes_saved"].as_i64().unwrap(),
+        body.len() as i64 - encoded.len() as i64
+    );
+    assert!(document["output_bytes_saved"].as_i64().unwrap() < 0);
+}
+
+#[test]
+fn identity_and_empty_bodies_are_not_compressed() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        8003,
+        &[("Content-Type", "text/plain")],
+        b"plain text, passed through",
+    );
+    assert_eq!(document["compression_ratio"], 1.0);
+    assert_eq!(document["bytes_saved"], 0);
+    assert_eq!(document["output_compression_ratio"], 1.0);
+    assert_eq!(document["output_bytes_saved"], 0);
+
+    let document = run(&prism, 8004, &GZIP_HEADERS, &gzip(b""));
+    assert_eq!(document["compression_ratio"], 1.0);
+}
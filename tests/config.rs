@@ -0,0 +1,423 @@
+//! Loading the configuration: built-in defaults, overridden by the file,
+//! overridden by the environment, the problems reported for invalid files,
+//! and reloads applying to the transactions started after them. Tests
+//! setting environment variables hold the serial lock.
+
+mod common;
+
+use common::{serial, temporary, TIMEOUT};
+use prism::config::{Config, ConfigError};
+use prism::memory;
+use std::ffi::CString;
+use std::path::PathBuf;
+
+/// A file holding `toml`.
+fn file(toml: &str) -> PathBuf {
+    let path = temporary("config.toml");
+    std::fs::write(&path, toml).unwrap();
+    path
+}
+
+fn load(toml: &str) -> Result<Config, Vec<ConfigError>> {
+    let path = file(toml);
+    let result = Config::load(Some(&path));
+    std::fs::remove_file(&path).unwrap();
+    result
+}
+
+/// The problems found loading `toml`, as `path: reason`.
+fn errors(toml: &str) -> Vec<String> {
+    load(toml)
+        .unwrap_err()
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+}
+
+#[test]
+fn full_files_set_every_section() {
+    let _serial = serial();
+    let config = load(
+        r#"
+dry_run = true
+
+[logging]
+backend = "stderr"
+filter = "warn,persistence=debug"
+format = "json"
+throttle_window = 10
+
+[backend]
+type = "elasticsearch"
+persist_attempts = 3
+
+[backend.elasticsearch]
+hostname = "search.example.com"
+port = 9243
+protocol = "https"
+index = "lens-{hostname}"
+
+[backend.elasticsearch.fields]
+uri = "url.full"
+
+[limits]
+spill_threshold = 1048576
+channel_capacity = 8
+
+[sampling]
+rate = 0.25
+"#,
+    )
+    .unwrap();
+    assert!(config.dry_run);
+    assert_eq!(config.logging.backend, "stderr");
+    assert_eq!(config.logging.filter, "warn,persistence=debug");
+    assert_eq!(config.logging.format, "json");
+    assert_eq!(config.logging.throttle_window, 10);
+    assert_eq!(config.backend.kind, "elasticsearch");
+    assert_eq!(config.backend.persist_attempts, 3);
+    let elasticsearch = &config.backend.elasticsearch;
+    assert_eq!(elasticsearch.hostname, "search.example.com");
+    assert_eq!(elasticsearch.port, 9243);
+    assert_eq!(elasticsearch.protocol, "https");
+    assert_eq!(elasticsearch.index, "lens-{hostname}");
+    assert_eq!(elasticsearch.fields["uri"], "url.full");
+    assert_eq!(config.limits.spill_threshold, 1024 * 1024);
+    assert_eq!(config.limits.channel_capacity, Some(8));
+    assert_eq!(config.sampling.rate, 0.25);
+}
+
+#[test]
+fn partial_files_keep_the_defaults() {
+    let _serial = serial();
+    let config = load("[logging]\nbackend = \"stderr\"\n").unwrap();
+    let defaults = Config::load(None).unwrap();
+    assert_eq!(config.logging.backend, "stderr");
+    assert_eq!(config.logging.filter, defaults.logging.filter);
+    assert_eq!(config.backend, defaults.backend);
+    assert_eq!(config.limits, defaults.limits);
+    assert_eq!(config.sampling, defaults.sampling);
+
+    assert_eq!(load("").unwrap(), defaults);
+}
+
+#[test]
+fn environment_overrides_the_file() {
+    let _serial = serial();
+    std::env::set_var("PRISM_LOG", "debug");
+    std::env::set_var("PRISM_PERSIST_ATTEMPTS", "5");
+    let config = load("[logging]\nfilter = \"warn\"\nformat = \"json\"\n");
+    std::env::remove_var("PRISM_LOG");
+    std::env::remove_var("PRISM_PERSIST_ATTEMPTS");
+    let config = config.unwrap();
+    assert_eq!(config.logging.filter, "debug");
+    // Left to the file where the environment says nothing.
+    assert_eq!(config.logging.format, "json");
+    assert_eq!(config.backend.persist_attempts, 5);
+
+    std::env::set_var("PRISM_PERSIST_ATTEMPTS", "many");
+    let reported = errors("");
+    std::env::remove_var("PRISM_PERSIST_ATTEMPTS");
+    assert_eq!(reported.len(), 1, "{:?}", reported);
+    assert!(
+        reported[0].starts_with("PRISM_PERSIST_ATTEMPTS: "),
+        "{}",
+        reported[0]
+    );
+}
+
+#[test]
+fn invalid_values_are_all_reported_by_path() {
+    let _serial = serial();
+    let reported = errors(
+        r#"
+[logging]
+backend = "console"
+format = "xml"
+
+[backend]
+type = "mongodb"
+persist_attempts = 0
+
+[backend.elasticsearch]
+protocol = "ftp"
+
+[sampling]
+rate = 2.0
+"#,
+    );
+    assert_eq!(
+        reported,
+        [
+            "logging.backend: unknown backend \"console\", expected syslog, stderr or file",
+            "logging.format: unknown format \"xml\", expected text or json",
+            "backend.type: unknown backend \"mongodb\", expected elasticsearch, har or memory",
+            "backend.persist_attempts: must be at least 1",
+            "backend.elasticsearch.protocol: expected http or https",
+            "sampling.rate: 2 is not between 0 and 1",
+        ]
+    );
+
+    let without_file = errors("[logging]\nbackend = \"file\"\n");
+    assert_eq!(
+        without_file,
+        ["logging.file: required when logging.backend is \"file\""]
+    );
+}
+
+#[test]
+fn unreadable_files_are_reported_by_location() {
+    let _serial = serial();
+    let path = file("[logging]\nbakend = \"stderr\"\n");
+    let location = path.display().to_string();
+    let unknown = Config::load(Some(&path)).unwrap_err();
+    assert_eq!(unknown.len(), 1);
+    assert_eq!(unknown[0].path, location);
+    assert!(
+        unknown[0].reason.contains("bakend"),
+        "{}",
+        unknown[0].reason
+    );
+
+    std::fs::write(&path, "[logging\nbackend = \"stderr\"\n").unwrap();
+    let syntax = Config::load(Some(&path)).unwrap_err();
+    assert_eq!(syntax.len(), 1);
+    assert_eq!(syntax[0].path, location);
+    assert!(!syntax[0].reason.contains('\n'));
+
+    std::fs::write(&path, "[limits]\nspill_threshold = \"large\"\n").unwrap();
+    let mistyped = Config::load(Some(&path)).unwrap_err();
+    assert!(
+        mistyped[0].reason.contains("spill_threshold"),
+        "{}",
+        mistyped[0].reason
+    );
+
+    std::fs::remove_file(&path).unwrap();
+    let missing = Config::load(Some(&path)).unwrap_err();
+    assert_eq!(missing[0].path, location);
+}
+
+#[test]
+fn configure_reports_problems_through_its_status() {
+    let _serial = serial();
+    let path = file("[backend]\ntype = \"mongodb\"\npersist_attempts = 0\n");
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(
+        prism::configure(c_path.as_ptr()),
+        prism::error::PrismError::InvalidConfig.code()
+    );
+    let chunk = prism::config_errors();
+    let reported = String::from_utf8(common::bytes(chunk.size, chunk.bytes)).unwrap();
+    assert_eq!(
+        reported,
+        "backend.type: unknown backend \"mongodb\", expected elasticsearch, har or memory\nbackend.persist_attempts: must be at least 1"
+    );
+    // The previous configuration is kept.
+    assert_ne!(prism::config::get().backend.kind, "mongodb");
+
+    std::fs::write(&path, common::MEMORY_BACKEND).unwrap();
+    assert_eq!(prism::configure(c_path.as_ptr()), 0);
+    assert_eq!(prism::config_errors().size, 0);
+    assert_eq!(prism::config::get().backend.kind, "memory");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn reloaded_sampling_applies_to_transactions_started_after() {
+    let _serial = serial();
+    let sampling = |rate: f64| {
+        format!(
+            "{}\n[sampling]\nrate = {:?}\n",
+            common::MEMORY_BACKEND,
+            rate
+        )
+    };
+    let path = file(&sampling(1.0));
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(prism::configure(c_path.as_ptr()), 0);
+    let finish = |id: i64| {
+        common::receive(id, b"sampled or not");
+        common::finish(id);
+        prism::cleanup(id);
+    };
+
+    common::begin(
+        53001,
+        "http://sampling.example.com/",
+        &[("Content-Type", "text/plain")],
+    );
+    std::fs::write(&path, sampling(0.0)).unwrap();
+    assert_eq!(prism::reload_config(), 0);
+    assert_eq!(prism::config::get().sampling.rate, 0.0);
+    common::begin(
+        53002,
+        "http://sampling.example.com/",
+        &[("Content-Type", "text/plain")],
+    );
+    // Not sampled, so never queued.
+    finish(53002);
+    assert!(memory::find(53002).is_none());
+    // Started before the reload, with every transaction sampled.
+    finish(53001);
+    assert!(memory::wait(53001, TIMEOUT).is_some());
+
+    std::fs::write(&path, sampling(1.0)).unwrap();
+    assert_eq!(prism::reload_config(), 0);
+    common::begin(
+        53003,
+        "http://sampling.example.com/",
+        &[("Content-Type", "text/plain")],
+    );
+    finish(53003);
+    assert!(memory::wait(53003, TIMEOUT).is_some());
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// A file holding the secret `content`, readable by `mode`.
+fn secret_file(name: &str, content: &str, mode: u32) -> PathBuf {
+    let path = temporary(name);
+    std::fs::write(&path, content).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    path
+}
+
+/// The warnings of the loads in `load`.
+fn load_warnings(load: impl FnOnce()) -> Vec<String> {
+    common::capture_logs();
+    prism::config::log_warnings();
+    common::capture_logs();
+    load();
+    prism::config::log_warnings();
+    common::warnings()
+}
+
+#[test]
+fn secrets_are_read_from_files_without_their_newline() {
+    let _serial = serial();
+    let password = secret_file("password", "from a file\n", 0o600);
+    let api_key = secret_file("api_key", "key from a file\r\n", 0o600);
+    let key = secret_file(
+        "encryption_key",
+        "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=\n",
+        0o400,
+    );
+    let toml = format!(
+        "[backend.elasticsearch]\npassword_file = \"{}\"\napi_key_file = \"{}\"\n\n[encryption]\nkey_file = \"{}\"\n",
+        password.display(),
+        api_key.display(),
+        key.display()
+    );
+    let mut config = None;
+    let warnings = load_warnings(|| config = Some(load(&toml).unwrap()));
+    assert_eq!(warnings, Vec::<String>::new());
+    let config = config.unwrap();
+    let elasticsearch = &config.backend.elasticsearch;
+    assert_eq!(elasticsearch.password.as_deref(), Some("from a file"));
+    assert_eq!(elasticsearch.api_key.as_deref(), Some("key from a file"));
+    assert_eq!(
+        config.encryption.key.as_deref(),
+        Some("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=")
+    );
+    for path in [password, api_key, key] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn secret_files_readable_by_others_are_warned_about() {
+    let _serial = serial();
+    let password = secret_file("password", "readable\n", 0o644);
+    let toml = format!(
+        "[backend.elasticsearch]\npassword_file = \"{}\"\n",
+        password.display()
+    );
+    let warnings = load_warnings(|| {
+        load(&toml).unwrap();
+    });
+    assert_eq!(
+        warnings,
+        [format!(
+            "Secret file {} has permissions 644, expected 600 or stricter",
+            password.display()
+        )]
+    );
+    std::fs::remove_file(password).unwrap();
+}
+
+#[test]
+fn missing_secret_files_are_reported() {
+    let missing = temporary("missing_password");
+    let errors = errors(&format!(
+        "[backend.elasticsearch]\npassword_file = \"{}\"\n",
+        missing.display()
+    ));
+    assert_eq!(errors.len(), 1);
+    let expected = format!(
+        "backend.elasticsearch.password_file: failed reading {}: ",
+        missing.display()
+    );
+    assert!(errors[0].starts_with(&expected), "{}", errors[0]);
+}
+
+#[test]
+fn inline_and_file_secrets_are_mutually_exclusive() {
+    let _serial = serial();
+    let password = secret_file("password", "from a file\n", 0o600);
+    let key = secret_file(
+        "encryption_key",
+        "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=\n",
+        0o600,
+    );
+    let both = errors(&format!(
+        "[backend.elasticsearch]\npassword = \"inline\"\npassword_file = \"{}\"\n\n[encryption]\nkey = \"MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=\"\nkey_file = \"{}\"\n",
+        password.display(),
+        key.display()
+    ));
+    assert_eq!(
+        both,
+        [
+            "backend.elasticsearch.password_file: backend.elasticsearch.password and backend.elasticsearch.password_file are mutually exclusive, set only one",
+            "encryption.key_file: encryption.key and encryption.key_file are mutually exclusive, set only one",
+        ]
+    );
+
+    let command = errors(&format!(
+        "[encryption]\nkey_file = \"{}\"\nkey_command = \"echo key\"\n",
+        key.display()
+    ));
+    assert_eq!(
+        command,
+        ["encryption.key_command: encryption.key, encryption.key_file and encryption.key_command are mutually exclusive, set only one"]
+    );
+    for path in [password, key] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn field_names_must_map_document_fields_to_free_names() {
+    let reported = errors(
+        "[backend.elasticsearch.fields]\nhost = \"path\"\nmethod = \"\"\nnope = \"elsewhere\"\n",
+    );
+    assert_eq!(
+        reported,
+        [
+            "backend.elasticsearch.fields.host: \"path\" is also the name of field path",
+            "backend.elasticsearch.fields.method: must not be empty",
+            "backend.elasticsearch.fields.nope: unknown document field",
+        ]
+    );
+
+    // Fields can swap names.
+    let swapped =
+        load("[backend.elasticsearch.fields]\nhost = \"path\"\npath = \"host\"\n").unwrap();
+    assert_eq!(swapped.backend.elasticsearch.fields["host"], "path");
+}
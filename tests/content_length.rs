@@ -0,0 +1,105 @@
+//! The length of bodies re-encoded by the library, reported through
+//! `get_response_header()` once the whole body was handed back, and
+//! persisted along with the length the origin announced.
+
+mod common;
+
+use common::{gzip, receive, send, setup_ffi as setup, TIMEOUT};
+use prism::memory;
+use std::ffi::CString;
+
+/// The value `get_response_header()` gives `name`, `None` when unchanged.
+fn response_header(id: i64, name: &str) -> Option<String> {
+    let name = CString::new(name).unwrap();
+    let chunk = prism::get_response_header(id, name.as_ptr());
+    if chunk.bytes.is_null() {
+        return None;
+    }
+    Some(String::from_utf8(common::bytes(chunk.size, chunk.bytes)).unwrap())
+}
+
+fn page() -> Vec<u8> {
+    (0..3000)
+        .map(|row| format!("<p>content length row {}</p>\n", row))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Relays `body` in chunks of 1000 bytes, and returns the size of every
+/// chunk handed back.
+fn relay(id: i64, headers: &[(&str, &str)], body: &[u8]) -> Vec<usize> {
+    common::begin(id, "http://length.example.com/", headers);
+    let mut sizes = Vec::new();
+    for chunk in body.chunks(1000) {
+        receive(id, chunk);
+        sizes.push(send(id).len());
+    }
+    prism::done(id);
+    loop {
+        let chunk = send(id);
+        if chunk.is_empty() {
+            return sizes;
+        }
+        sizes.push(chunk.len());
+    }
+}
+
+#[test]
+fn reencoded_bodies_report_the_bytes_emitted() {
+    let _serial = setup();
+    let encoded = gzip(&page());
+    let length = encoded.len().to_string();
+    let headers = [
+        ("Content-Type", "text/html"),
+        ("Content-Encoding", "gzip"),
+        ("Content-Length", &length),
+    ];
+
+    common::begin(32001, "http://length.example.com/", &headers);
+    // Streamed chunked, as the length isn't known before.
+    assert_eq!(
+        response_header(32001, "Content-Length").as_deref(),
+        Some("")
+    );
+    assert_eq!(
+        response_header(32001, "Transfer-Encoding").as_deref(),
+        Some("chunked")
+    );
+    prism::cleanup(32001);
+
+    let sizes = relay(32002, &headers, &encoded);
+    let emitted: usize = sizes.iter().sum();
+    assert_eq!(
+        response_header(32002, "Content-Length"),
+        Some(emitted.to_string())
+    );
+    assert_eq!(
+        response_header(32002, "Transfer-Encoding").as_deref(),
+        Some("")
+    );
+    prism::cleanup(32002);
+
+    let document = memory::wait(32002, TIMEOUT).unwrap();
+    assert_eq!(document.emitted_length, emitted);
+    assert_eq!(document.json["original_length"], encoded.len());
+    assert_eq!(document.json["emitted_length"], emitted);
+}
+
+#[test]
+fn passed_through_bodies_keep_their_length() {
+    let _serial = setup();
+    let page = page();
+    let length = page.len().to_string();
+    let headers = [("Content-Type", "text/html"), ("Content-Length", &length)];
+
+    let sizes = relay(32101, &headers, &page);
+    let emitted: usize = sizes.iter().sum();
+    assert_eq!(emitted, page.len());
+    assert_eq!(response_header(32101, "Content-Length"), None);
+    assert_eq!(response_header(32101, "Transfer-Encoding"), None);
+    prism::cleanup(32101);
+
+    let document = memory::wait(32101, TIMEOUT).unwrap();
+    assert_eq!(document.emitted_length, emitted);
+    assert_eq!(document.json["original_length"], page.len());
+}
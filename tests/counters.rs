@@ -0,0 +1,70 @@
+//! Counters bumped from many threads at once: transactions started, ended
+//! and the bytes they relayed add up exactly, with none lost to contention.
+
+mod common;
+
+use common::{dump, take};
+use serde_json::Value;
+use std::thread;
+
+const THREADS: i64 = 16;
+const TRANSACTIONS: i64 = 25;
+const CHUNK: usize = 1000;
+
+/// The counters that should add up, in the order they are checked.
+fn totals(counters: &Value) -> [u64; 5] {
+    [
+        counters["transactions"]["started"].as_u64().unwrap(),
+        counters["transactions"]["completed"].as_u64().unwrap(),
+        counters["transactions"]["aborted"].as_u64().unwrap(),
+        counters["bytes_received"].as_u64().unwrap(),
+        counters["bytes_sent"].as_u64().unwrap(),
+    ]
+}
+
+#[test]
+fn concurrent_transactions_add_up_exactly() {
+    let (prism, _serial) = common::setup(|config| config.limits.coalesce_size = 0);
+    let before = totals(&dump(&prism)["counters"]);
+    let threads: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let prism = prism.clone();
+            thread::spawn(move || {
+                let chunk = vec![b'a' + thread as u8; CHUNK];
+                for index in 0..TRANSACTIONS {
+                    let id = 46000 + thread * 100 + index;
+                    let uri = format!("http://counters.example.com/{}", id);
+                    let mut handle = prism.begin(id, "GET", &uri, &[]);
+                    handle.status(200);
+                    // Every other transaction is dropped after a chunk,
+                    // before completing.
+                    let chunks = if index % 2 == 0 { 3 } else { 1 };
+                    let mut output = Vec::new();
+                    for _ in 0..chunks {
+                        handle.receive(&chunk).unwrap();
+                        output.extend(take(&mut handle));
+                    }
+                    if index % 2 == 0 {
+                        handle.done();
+                        output.extend(common::drain(&mut handle));
+                    }
+                    assert_eq!(output.len(), chunks * CHUNK, "{}", id);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let after = totals(&dump(&prism)["counters"]);
+    // Even indexes complete, odd ones are aborted.
+    let completed = (THREADS * ((TRANSACTIONS + 1) / 2)) as u64;
+    let aborted = (THREADS * (TRANSACTIONS / 2)) as u64;
+    let bytes = (completed * 3 + aborted) * CHUNK as u64;
+    let deltas: Vec<u64> = after.iter().zip(before).map(|(a, b)| a - b).collect();
+    assert_eq!(
+        deltas,
+        [completed + aborted, completed, aborted, bytes, bytes]
+    );
+}
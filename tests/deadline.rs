@@ -0,0 +1,172 @@
+//! Transactions going past their deadline, `limits.transaction_deadline` or
+//! the one of the rules they matched: no longer relayed, and persisted as
+//! timed out with the body captured by then. Driven on a manual clock.
+
+mod common;
+
+use common::{counter, dump, take, TIMEOUT};
+use prism::clock::{self, ManualClock};
+use prism::error::PrismError;
+use prism::memory;
+use prism::{Prism, TransactionHandle};
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
+
+const DEADLINE: Duration = Duration::from_secs(120);
+const DOWNLOAD_DEADLINE: Duration = Duration::from_secs(1800);
+
+/// An instance with a deadline, and a longer one for the download host,
+/// on a manual clock.
+fn setup() -> (Prism, Arc<ManualClock>, MutexGuard<'static, ()>) {
+    let manual = ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap());
+    let rules = common::temporary("rules.toml");
+    std::fs::write(
+        &rules,
+        format!(
+            "[[rule]]\nname = \"downloads\"\nhost = \"downloads.example.com\"\ndeadline = {}\n",
+            DOWNLOAD_DEADLINE.as_secs()
+        ),
+    )
+    .unwrap();
+    let (prism, serial) = common::setup(|config| {
+        clock::set(manual.clone());
+        config.limits.coalesce_size = 0;
+        config.limits.transaction_deadline = Some(DEADLINE.as_secs());
+        config.filters.rules = Some(rules);
+    });
+    (prism, manual, serial)
+}
+
+fn begin(prism: &Prism, id: i64, host: &str) -> TransactionHandle {
+    let handle = prism.begin(
+        id,
+        "GET",
+        &format!("http://{}/{}", host, id),
+        &[("Content-Type", "text/plain")],
+    );
+    handle.status(200);
+    handle
+}
+
+#[test]
+fn transactions_past_their_deadline_time_out() {
+    let (prism, manual, _serial) = setup();
+    let id = 21001;
+    let before = counter(&prism, "transactions_timed_out");
+    let mut handle = begin(&prism, id, "deadline.example.com");
+    handle.receive(b"hello world\n").unwrap();
+    assert_eq!(take(&mut handle), b"hello world\n");
+
+    manual.advance(DEADLINE - Duration::from_secs(1));
+    handle.receive(b"in time\n").unwrap();
+    manual.advance(Duration::from_secs(1));
+    assert_eq!(handle.receive(b"too late\n"), Err(PrismError::Timeout));
+    // Nothing more is handed back, and there is nothing more to wait for.
+    assert!(take(&mut handle).is_empty());
+    assert!(handle.finished());
+    assert_eq!(handle.receive(b"too late\n"), Err(PrismError::Timeout));
+    assert_eq!(counter(&prism, "transactions_timed_out"), before + 1);
+
+    // Persisted right away, with what was captured.
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.json["timed_out"], true);
+    assert_eq!(document.body, b"hello world\nin time\n");
+    assert_eq!(document.emitted_length, 12);
+    handle.done();
+    drop(handle);
+    assert_eq!(counter(&prism, "transactions_timed_out"), before + 1);
+    clock::reset();
+}
+
+#[test]
+fn idle_transactions_time_out_and_rules_extend_deadlines() {
+    let (prism, manual, _serial) = setup();
+    let (idle, download) = (21101, 21102);
+    let mut stuck = begin(&prism, idle, "deadline.example.com");
+    stuck.receive(b"partial body\n").unwrap();
+    take(&mut stuck);
+    let mut handle = begin(&prism, download, "downloads.example.com");
+
+    // The download goes on past the default deadline, and the watchdog
+    // sweep it triggers times out the transaction that went quiet.
+    manual.advance(DEADLINE + Duration::from_secs(1));
+    handle.receive(b"chunk of a large download\n").unwrap();
+    let document = memory::wait(idle, TIMEOUT).expect("document persisted");
+    assert_eq!(document.json["timed_out"], true);
+    assert_eq!(document.body, b"partial body\n");
+    let transactions = dump(&prism)["transactions"].as_array().unwrap().clone();
+    let state = |id: i64| {
+        transactions
+            .iter()
+            .find(|transaction| transaction["id"] == id)
+            .cloned()
+            .unwrap()
+    };
+    assert_eq!(state(idle)["timed_out"], true);
+    assert_eq!(state(download)["timed_out"], false);
+    assert!(stuck.finished());
+    drop(stuck);
+
+    // Until its own deadline passes.
+    assert_eq!(take(&mut handle), b"chunk of a large download\n");
+    manual.advance(DOWNLOAD_DEADLINE - DEADLINE);
+    assert_eq!(
+        handle.receive(b"chunk of a large download\n"),
+        Err(PrismError::Timeout)
+    );
+    drop(handle);
+    let document = memory::wait(download, TIMEOUT).expect("document persisted");
+    assert_eq!(document.json["timed_out"], true);
+    assert_eq!(document.body, b"chunk of a large download\n");
+    clock::reset();
+}
+
+#[test]
+fn completed_transactions_never_time_out() {
+    let (prism, manual, _serial) = setup();
+    let id = 21201;
+    let before = counter(&prism, "transactions_timed_out");
+    let mut handle = begin(&prism, id, "deadline.example.com");
+    handle.receive(b"hello world\n").unwrap();
+    handle.done();
+    assert_eq!(take(&mut handle), b"hello world\n");
+    assert!(handle.finished());
+
+    // Past the deadline before cleanup, with a sweep from another
+    // transaction meanwhile.
+    manual.advance(DEADLINE * 2);
+    let other = begin(&prism, 21202, "downloads.example.com");
+    other.receive(b"hello\n").unwrap();
+    assert!(handle.finished());
+    drop(handle);
+    drop(other);
+
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert!(document.json.get("timed_out").is_none());
+    assert_eq!(document.body, b"hello world\n");
+    assert_eq!(counter(&prism, "transactions_timed_out"), before);
+    clock::reset();
+}
+
+#[test]
+fn deadlines_too_far_out_never_come() {
+    let manual = ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap());
+    let (prism, _serial) = common::setup(|config| {
+        clock::set(manual.clone());
+        config.limits.coalesce_size = 0;
+        config.limits.transaction_deadline = Some(u64::MAX);
+    });
+    let id = 21301;
+    let mut handle = begin(&prism, id, "deadline.example.com");
+    handle.receive(b"hello ").unwrap();
+    manual.advance(Duration::from_secs(100 * 365 * 24 * 60 * 60));
+    handle.receive(b"world\n").unwrap();
+    handle.done();
+    assert_eq!(take(&mut handle), b"hello world\n");
+    assert!(handle.finished());
+    drop(handle);
+
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert!(document.json.get("timed_out").is_none());
+    clock::reset();
+}
@@ -0,0 +1,107 @@
+//! Gzip bodies decoded by the pool of `limits.decode_workers`: output and
+//! captured bodies byte for byte those of the body received, however many
+//! transactions the workers decode at once, and through a shutdown.
+
+mod common;
+
+use common::{gunzip, gzip, take, TIMEOUT};
+use prism::{memory, Prism, TransactionHandle};
+use std::sync::MutexGuard;
+use std::thread;
+
+const GZIP: [(&str, &str); 2] = [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+
+fn setup(workers: usize) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.limits.coalesce_size = 0;
+        config.limits.decode_workers = workers;
+    })
+}
+
+/// A page of its own for each transaction.
+fn page(id: i64) -> Vec<u8> {
+    (0..1500)
+        .map(|row| format!("<li>transaction {} row {}</li>\n", id, row))
+        .collect::<String>()
+        .into_bytes()
+}
+
+fn begin(prism: &Prism, id: i64) -> TransactionHandle {
+    let handle = prism.begin(id, "GET", "http://decode.example.com/", &GZIP);
+    handle.status(200);
+    handle
+}
+
+#[test]
+fn concurrent_transactions_decode_byte_for_byte() {
+    let (prism, _serial) = setup(4);
+    let threads: Vec<_> = (42001..=42016)
+        .map(|id| {
+            let prism = prism.clone();
+            thread::spawn(move || {
+                let mut handle = begin(&prism, id);
+                let mut output = Vec::new();
+                for chunk in gzip(&page(id)).chunks(311) {
+                    handle.receive(chunk).unwrap();
+                    output.extend(take(&mut handle));
+                }
+                handle.done();
+                output.extend(common::drain(&mut handle));
+                output
+            })
+        })
+        .collect();
+    for (id, thread) in (42001..=42016).zip(threads) {
+        assert_eq!(gunzip(&thread.join().unwrap()), page(id), "{}", id);
+        assert_eq!(memory::wait(id, TIMEOUT).unwrap().body, page(id), "{}", id);
+    }
+}
+
+#[test]
+fn interleaved_chunks_keep_their_order() {
+    let (prism, _serial) = setup(3);
+    let ids = 42101..=42104;
+    let mut handles: Vec<TransactionHandle> = ids.clone().map(|id| begin(&prism, id)).collect();
+    let bodies: Vec<Vec<u8>> = ids.clone().map(|id| gzip(&page(id))).collect();
+    let mut outputs = vec![Vec::new(); handles.len()];
+    let longest = bodies.iter().map(Vec::len).max().unwrap();
+    // One small chunk of each transaction in turn, handed back only every
+    // few chunks so workers decode several at once.
+    for (round, offset) in (0..longest).step_by(97).enumerate() {
+        for (handle, body) in handles.iter().zip(&bodies) {
+            if offset < body.len() {
+                handle
+                    .receive(&body[offset..body.len().min(offset + 97)])
+                    .unwrap();
+            }
+        }
+        if round % 5 == 4 {
+            for (handle, output) in handles.iter_mut().zip(&mut outputs) {
+                output.extend(take(handle));
+            }
+        }
+    }
+    for (handle, output) in handles.iter_mut().zip(&mut outputs) {
+        handle.done();
+        output.extend(common::drain(handle));
+    }
+    for (id, output) in ids.zip(outputs) {
+        assert_eq!(gunzip(&output), page(id), "{}", id);
+    }
+}
+
+#[test]
+fn shutdown_leaves_bodies_to_decode_in_send() {
+    let (prism, _serial) = setup(2);
+    let mut handle = begin(&prism, 42201);
+    let body = gzip(&page(42201));
+    let (head, tail) = body.split_at(body.len() / 2);
+    handle.receive(head).unwrap();
+    let mut output = take(&mut handle);
+
+    prism.shutdown();
+    handle.receive(tail).unwrap();
+    handle.done();
+    output.extend(common::drain(&mut handle));
+    assert_eq!(gunzip(&output), page(42201));
+}
@@ -0,0 +1,91 @@
+//! Request and response headers passed apart through the exported
+//! functions: persisted in their own objects, and only response headers
+//! telling the encoding of the body. `header()` keeps adding response
+//! headers.
+
+mod common;
+
+use common::{gzip, header, receive, send, setup_ffi as setup, TIMEOUT};
+use prism::memory;
+
+const USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0";
+
+/// Begins transaction `id` for `uri` once its headers were passed, and
+/// relays `body`. Returns the output.
+fn exchange(id: i64, uri: &str, body: &[u8]) -> Vec<u8> {
+    common::begin(id, uri, &[]);
+    receive(id, body);
+    let mut output = send(id);
+    output.extend(common::finish(id));
+    output
+}
+
+#[test]
+fn request_and_response_headers_are_persisted_apart() {
+    let _serial = setup();
+    let id = 18001;
+    header(prism::request_header, id, "Host", "directions.example.com");
+    header(prism::request_header, id, "User-Agent", USER_AGENT);
+    header(prism::request_header, id, "Accept", "text/html");
+    header(prism::response_header, id, "Content-Type", "text/plain");
+    header(prism::header, id, "X-Legacy", "yes");
+    exchange(id, "/page", b"hello");
+    // Added after the transaction began, to the side they belong to.
+    header(prism::request_header, id, "Accept", "text/plain");
+    header(prism::response_header, id, "X-Late", "late");
+    prism::cleanup(id);
+
+    let document = memory::wait(id, TIMEOUT).expect("document persisted").json;
+    assert_eq!(
+        document["request_headers"],
+        serde_json::json!({
+            "Host": "directions.example.com",
+            "User-Agent": USER_AGENT,
+            "Accept": "text/html, text/plain",
+        })
+    );
+    assert_eq!(
+        document["response_headers"],
+        serde_json::json!({
+            "Content-Type": "text/plain",
+            "X-Legacy": "yes",
+            "X-Late": "late",
+        })
+    );
+    // Taken from the request headers.
+    assert_eq!(document["host"], "directions.example.com");
+    assert_eq!(document["user_agent"], USER_AGENT);
+    assert_eq!(document["ua_browser"], "Firefox");
+}
+
+#[test]
+fn only_response_headers_tell_the_encoding() {
+    let _serial = setup();
+    let body = b"<p>directions test body</p>\n".repeat(32);
+
+    // A request sent gzip encoded, with a response that isn't.
+    let id = 18002;
+    header(prism::request_header, id, "Content-Encoding", "gzip");
+    header(prism::response_header, id, "Content-Type", "text/html");
+    assert_eq!(exchange(id, "http://directions.example.com/", &body), body);
+    prism::cleanup(id);
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.encoding, "");
+    assert_eq!(document.body, body);
+    assert_eq!(document.json["request_headers"]["Content-Encoding"], "gzip");
+
+    // A response gzip encoded, for a request accepting it.
+    let id = 18003;
+    header(prism::request_header, id, "Accept-Encoding", "gzip");
+    header(prism::response_header, id, "Content-Type", "text/html");
+    header(prism::response_header, id, "Content-Encoding", "gzip");
+    let encoded = gzip(&body);
+    exchange(id, "http://directions.example.com/", &encoded);
+    prism::cleanup(id);
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.encoding, "gzip");
+    assert_eq!(document.body, body);
+    assert!(document.json["request_headers"]
+        .get("Content-Encoding")
+        .is_none());
+}
@@ -0,0 +1,150 @@
+//! Dry runs: transactions processed as usual, but documents only
+//! serialized and summarized in a debug line, never handed to the backend,
+//! and bodies relayed unchanged. Dry runs are switched on and off by
+//! reloading the configuration, so a single test goes through them.
+
+mod common;
+
+use common::{lock, receive, send, TIMEOUT};
+use prism::clock::{self, ManualClock};
+use prism::memory;
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static SUMMARIES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Keeps the summary lines of dry runs, logged at debug level.
+struct Summaries;
+
+impl log::Log for Summaries {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Debug
+    }
+
+    fn log(&self, record: &log::Record) {
+        let message = record.args().to_string();
+        if message.starts_with("Dry run") {
+            lock(&SUMMARIES).push(message);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Summaries = Summaries;
+
+/// Installs a configuration persisting to memory, in dry runs or not, and
+/// returns the status of loading it.
+fn configure(path: &Path, dry_run: bool) -> i32 {
+    std::fs::write(
+        path,
+        format!(
+            "dry_run = {}\n\n[logging]\nfilter = \"debug\"\n\n{}",
+            dry_run,
+            common::MEMORY_BACKEND
+        ),
+    )
+    .unwrap();
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    prism::configure(c_path.as_ptr())
+}
+
+/// Relays `body` through transaction `id`, returning what was handed back.
+fn relay(id: i64, body: &[u8]) -> Vec<u8> {
+    common::begin(
+        id,
+        &format!("http://dry-run.example.com/{}", id % 10),
+        &[("Content-Type", "text/plain")],
+    );
+    receive(id, body);
+    let mut output = send(id);
+    output.extend(common::finish(id));
+    prism::cleanup(id);
+    output
+}
+
+/// Waits for the summary of the dry run of transaction `id`.
+fn summary(id: i64) -> String {
+    let prefix = format!("Dry run, not persisting document {} ", id);
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(summary) = lock(&SUMMARIES)
+            .iter()
+            .find(|summary| summary.starts_with(&prefix))
+        {
+            return summary.clone();
+        }
+        assert!(Instant::now() < deadline, "no summary of {}", id);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn persisted(backend: &str) -> u64 {
+    common::stats()["counters"]["persist"][backend]["attempted"]
+        .as_u64()
+        .unwrap()
+}
+
+#[test]
+fn dry_runs_leave_the_backend_and_bodies_alone() {
+    let _serial = common::serial();
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+    // Both runs of a transaction then build the same document.
+    clock::set(ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap()));
+    clock::set_run_id(42);
+    let path = common::temporary("config.toml");
+    assert_eq!(configure(&path, true), 0);
+    let memory_before = persisted("memory");
+    let dry_run_before = persisted("dry_run");
+
+    let body = b"the same page, twice";
+    assert_eq!(relay(39001, body), body);
+    let dry_run = summary(39001);
+    assert!(
+        dry_run.starts_with(
+            "Dry run, not persisting document 39001 (GET http://dry-run.example.com/1, status 200, "
+        ),
+        "{}",
+        dry_run
+    );
+
+    // Switched off without a restart.
+    std::fs::write(
+        &path,
+        format!(
+            "[logging]\nfilter = \"debug\"\n\n{}",
+            common::MEMORY_BACKEND
+        ),
+    )
+    .unwrap();
+    assert_eq!(prism::reload_config(), 0);
+    assert_eq!(relay(39011, body), body);
+    let document = memory::wait(39011, TIMEOUT).expect("document persisted");
+    // Persisted in order, so the dry run would be there.
+    assert!(memory::find(39001).is_none());
+    assert_eq!(persisted("memory"), memory_before + 1);
+    assert_eq!(persisted("dry_run"), dry_run_before + 1);
+
+    // Ids of the same length, for the same length of document.
+    let json = serde_json::to_vec(&document.json).unwrap();
+    assert!(
+        dry_run.ends_with(&format!(", {} bytes)", json.len())),
+        "{} for {}",
+        dry_run,
+        document.json
+    );
+
+    // Bodies are only rewritten outside of dry runs.
+    let find = CString::new("same").unwrap();
+    let replace = CString::new("SAME").unwrap();
+    prism::rewrite_rule(find.as_ptr(), replace.as_ptr());
+    assert_eq!(relay(39012, body), b"the SAME page, twice");
+    assert_eq!(configure(&path, true), 0);
+    assert_eq!(relay(39002, body), body);
+    summary(39002);
+    std::fs::remove_file(&path).unwrap();
+    clock::reset();
+}
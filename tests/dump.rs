@@ -0,0 +1,118 @@
+//! State dumps of transactions left in various states.
+
+mod common;
+
+use common::{drain, dump};
+use prism::error::PrismError;
+use prism::{Prism, TransactionHandle};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::{MutexGuard, OnceLock};
+
+static PRISM: OnceLock<Prism> = OnceLock::new();
+
+/// Every test dumps every live transaction, so they all hold the lock.
+fn setup() -> (&'static Prism, MutexGuard<'static, ()>) {
+    let prism = PRISM.get_or_init(|| {
+        let mut config = common::config();
+        config.limits.coalesce_size = 0;
+        Prism::new(config).unwrap()
+    });
+    (prism, common::serial())
+}
+
+fn begin(prism: &Prism, id: i64, headers: &[(&str, &str)]) -> TransactionHandle {
+    let handle = prism.begin(
+        id,
+        "GET",
+        &format!("http://dump.example.com/{}", id),
+        headers,
+    );
+    handle.status(200);
+    handle
+}
+
+fn dump_ids(prism: &Prism) -> Vec<i64> {
+    dump(prism)["transactions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| t["id"].as_i64().unwrap())
+        .collect()
+}
+
+#[test]
+fn live_transactions_are_dumped_by_id() {
+    let (prism, _serial) = setup();
+    let text = [("Content-Type", "text/plain")];
+
+    let done = begin(prism, 5002, &text);
+    done.receive(b"hello world").unwrap();
+    done.done();
+    let mut finished = begin(prism, 5003, &text);
+    finished.receive(b"hello world").unwrap();
+    finished.done();
+    drain(&mut finished);
+    let receiving = begin(prism, 5001, &text);
+    receiving.receive(b"hello").unwrap();
+    let blocked = begin(prism, 5004, &text);
+    blocked.block("policy").unwrap();
+    let gzip = begin(
+        prism,
+        5005,
+        &[("Content-Type", "text/html"), ("Content-Encoding", "gzip")],
+    );
+
+    let dump = dump(prism);
+    for field in ["version", "git", "taken_at", "config", "backend"] {
+        assert!(dump[field].is_string(), "{}: {}", field, dump[field]);
+    }
+    assert_eq!(dump["backend"], "memory");
+    assert!(dump["queue"]["documents"].is_u64());
+    assert!(dump["queue"]["running"].is_u64());
+    assert!(dump["counters"]["errors"].is_object());
+
+    let transactions = dump["transactions"].as_array().unwrap();
+    let ids: Vec<_> = transactions
+        .iter()
+        .map(|t| t["id"].as_i64().unwrap())
+        .collect();
+    assert_eq!(ids, [5001, 5002, 5003, 5004, 5005]);
+    let [receiving_state, done_state, finished_state, blocked_state, gzip_state] =
+        &transactions[..]
+    else {
+        unreachable!()
+    };
+    for transaction in transactions {
+        assert_eq!(transaction["method"], "GET");
+        assert_eq!(transaction["mode"], "RESPMOD");
+        assert_eq!(transaction["status"], 200);
+        assert!(transaction["age"].is_u64());
+        assert!(transaction["footprint"]["captured"].is_u64());
+        assert!(transaction.get("body").is_none());
+    }
+    assert_eq!(receiving_state["uri"], "http://dump.example.com/5001");
+    assert_eq!(receiving_state["bytes_received"], 5);
+    assert_eq!(receiving_state["done"], false);
+    assert_eq!(done_state["done"], true);
+    assert_eq!(done_state["finished"], false);
+    assert_eq!(finished_state["finished"], true);
+    assert_eq!(finished_state["bytes_sent"], 11);
+    assert_eq!(finished_state["persisted"], true);
+    assert_eq!(blocked_state["blocked"], true);
+    assert_eq!(blocked_state["block_reason"], "policy");
+    assert_eq!(gzip_state["encoding"], "gzip");
+    assert_eq!(gzip_state["blocked"], false);
+    assert_eq!(gzip_state["block_reason"], Value::Null);
+
+    drop((receiving, done, finished, blocked, gzip));
+    assert!(dump_ids(prism).iter().all(|id| !(5001..=5005).contains(id)));
+}
+
+#[test]
+fn unwritable_paths_fail() {
+    let (prism, _serial) = setup();
+    let path = PathBuf::from("/nonexistent/prism/dump.json");
+    assert_eq!(prism.dump_state(&path), Err(PrismError::Io));
+    assert!(!path.exists());
+}
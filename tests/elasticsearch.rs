@@ -0,0 +1,814 @@
+//! Runs the Elasticsearch backend against a mock server on the loopback
+//! interface, answering each request as a test scripts it, so the index
+//! initialization and persist branches are covered without a cluster.
+
+#![cfg(feature = "elasticsearch")]
+
+mod common;
+
+use common::{lock, TIMEOUT};
+use prism::clock::{self, ManualClock};
+use prism::config::{self, Config, Route};
+use prism::error::PrismError;
+use prism::observer::{self, LifecycleObserver};
+use prism::{retention, Prism};
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Once};
+use std::time::{Duration, Instant};
+
+const INDEX: &str = "prism-test";
+/// Where the clock stands when documents are compared to golden files.
+const DATE: &str = "2024-01-01T00:00:00Z";
+/// Run documents are persisted by, the date in seconds.
+const RUN_ID: u64 = 1704067200;
+
+static SETUP: Once = Once::new();
+static PERSISTED: Mutex<Vec<(i64, Result<(), PrismError>)>> = Mutex::new(Vec::new());
+
+struct Results;
+
+impl LifecycleObserver for Results {
+    fn on_persisted(&self, event: &observer::Persisted) {
+        lock(&PERSISTED).push((event.id, event.result));
+    }
+}
+
+/// Every test shares the configuration, the persisted results and the
+/// warnings, which is how the backend reports failures, so they all hold
+/// the lock.
+fn setup() -> MutexGuard<'static, ()> {
+    SETUP.call_once(|| observer::register(Box::new(Results)).unwrap());
+    let serial = common::serial();
+    clock::reset();
+    common::capture_logs();
+    serial
+}
+
+/// Id the document of transaction `id` is stored under: a version 8 UUID
+/// hashed from the run id and the id.
+fn document_id(id: i64) -> String {
+    let mut hash = Sha256::digest(format!("{}-{}", RUN_ID, id));
+    hash[6] = (hash[6] & 0x0f) | 0x80;
+    hash[8] = (hash[8] & 0x3f) | 0x80;
+    let hex: String = hash[0..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// A request received by the mock server.
+#[derive(Clone, Debug)]
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn is(&self, method: &str, path: &str) -> bool {
+        self.method == method && self.path == path
+    }
+
+    fn is_document(&self) -> bool {
+        self.method == "PUT" && self.path.starts_with(&format!("/{}/_doc/", INDEX))
+    }
+}
+
+/// Status and body to answer with, `None` to close the connection instead.
+type Reply = Option<(u16, &'static str)>;
+
+struct MockServer {
+    port: u16,
+    requests: Arc<Mutex<Vec<Request>>>,
+    /// Connections accepted so far.
+    connections: Arc<AtomicUsize>,
+}
+
+impl MockServer {
+    fn start(respond: impl Fn(&Request) -> Reply + Send + Sync + 'static) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let respond = Arc::new(respond);
+        let received = requests.clone();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    break;
+                };
+                accepted.fetch_add(1, Ordering::Relaxed);
+                let requests = received.clone();
+                let respond = respond.clone();
+                std::thread::spawn(move || serve(stream, &requests, respond.as_ref()));
+            }
+        });
+        MockServer {
+            port,
+            requests,
+            connections,
+        }
+    }
+
+    fn requests(&self) -> Vec<Request> {
+        lock(&self.requests).clone()
+    }
+
+    /// Waits for a request matching `f`.
+    fn wait(&self, f: impl Fn(&Request) -> bool) -> Request {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if let Some(request) = self.requests().into_iter().find(&f) {
+                return request;
+            }
+            assert!(Instant::now() < deadline, "request not received");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Answers the requests of one connection, which the client keeps alive.
+fn serve(stream: TcpStream, requests: &Mutex<Vec<Request>>, respond: &dyn Fn(&Request) -> Reply) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        let mut request = Request {
+            method,
+            path,
+            headers,
+            body: Vec::new(),
+        };
+        let length = request
+            .header("Content-Length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0);
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).unwrap();
+
+        let reply = respond(&request);
+        lock(requests).push(request);
+        let Some((status, body)) = reply else {
+            return;
+        };
+        let response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        if stream.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Answers for an index that exists already, and documents persisted with
+/// `status`.
+fn existing_index(status: u16) -> impl Fn(&Request) -> Reply {
+    move |request| {
+        if request.is_document() {
+            Some((status, r#"{"result":"created"}"#))
+        } else {
+            Some((200, "{}"))
+        }
+    }
+}
+
+/// An instance persisting to the mock server listening on `port`.
+fn prism(port: u16) -> Prism {
+    configured(port, |_| {})
+}
+
+/// The same, configured further by `configure`.
+fn configured(port: u16, configure: impl FnOnce(&mut Config)) -> Prism {
+    let mut config = Config::load(None).unwrap();
+    config.backend.kind = "elasticsearch".to_string();
+    let elasticsearch = &mut config.backend.elasticsearch;
+    elasticsearch.hostname = "127.0.0.1".to_string();
+    elasticsearch.port = port;
+    elasticsearch.protocol = "http".to_string();
+    elasticsearch.index = INDEX.to_string();
+    config.logging.throttle_window = 0;
+    config.limits.coalesce_size = 0;
+    configure(&mut config);
+    let prism = Prism::new(config).unwrap();
+    clock::set_run_id(RUN_ID);
+    prism
+}
+
+/// Runs a small transaction through, whose document then gets persisted.
+fn transaction(prism: &Prism, id: i64) {
+    common::relay(
+        prism,
+        id,
+        "http://golden.example.com/page",
+        &[
+            ("Content-Type", "text/plain"),
+            ("Content-Length", "11"),
+            ("ETag", "\"v1\""),
+        ],
+        b"hello world",
+    );
+}
+
+/// Waits for the outcome of persisting the document of transaction `id`.
+fn persisted(id: i64) -> Result<(), PrismError> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some((_, result)) = lock(&PERSISTED)
+            .iter()
+            .find(|(persisted, _)| *persisted == id)
+        {
+            return *result;
+        }
+        assert!(Instant::now() < deadline, "document {} not persisted", id);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn warned(text: &str) -> bool {
+    common::warnings()
+        .iter()
+        .any(|warning| warning.contains(text))
+}
+
+/// Waits for a warning containing `text`.
+fn wait_warning(text: &str) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !warned(text) {
+        assert!(Instant::now() < deadline, "no warning about {:?}", text);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn fixture(name: &str) -> String {
+    std::fs::read_to_string(format!(
+        "{}/tests/fixtures/elasticsearch/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    ))
+    .unwrap()
+}
+
+#[test]
+fn existing_index_is_not_created_again() {
+    let _serial = setup();
+    let server = MockServer::start(existing_index(201));
+    let prism = prism(server.port);
+    transaction(&prism, 2001);
+
+    assert_eq!(persisted(2001), Ok(()));
+    let requests = server.requests();
+    assert!(requests[0].is("GET", &format!("/{}", INDEX)));
+    assert!(!requests
+        .iter()
+        .any(|request| request.is("PUT", &format!("/{}", INDEX))));
+    let document = server.wait(Request::is_document);
+    assert_eq!(
+        document.path,
+        format!("/{}/_doc/{}", INDEX, document_id(2001))
+    );
+}
+
+#[test]
+fn documents_share_one_client_and_connection() {
+    let _serial = setup();
+    let server = MockServer::start(existing_index(201));
+    // One worker persists the documents one after the other.
+    let prism = configured(server.port, |config| {
+        config.limits.persistence_workers = 1;
+    });
+    for id in 2011..=2020 {
+        transaction(&prism, id);
+    }
+    for id in 2011..=2020 {
+        assert_eq!(persisted(id), Ok(()));
+    }
+
+    // The index is checked once, not for every document.
+    let requests = server.requests();
+    let checks = requests
+        .iter()
+        .filter(|request| request.is("GET", &format!("/{}", INDEX)))
+        .count();
+    assert_eq!(checks, 1);
+    assert_eq!(
+        requests
+            .iter()
+            .filter(|request| request.is_document())
+            .count(),
+        10
+    );
+    assert_eq!(server.connections.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn missing_index_is_created_with_the_mapping() {
+    let _serial = setup();
+    let server = MockServer::start(|request| {
+        if request.method == "GET" {
+            Some((404, r#"{"error":"index_not_found_exception"}"#))
+        } else {
+            Some((200, r#"{"acknowledged":true}"#))
+        }
+    });
+    let prism = prism(server.port);
+    transaction(&prism, 2002);
+
+    assert_eq!(persisted(2002), Ok(()));
+    let create = server.wait(|request| request.is("PUT", &format!("/{}", INDEX)));
+    assert_eq!(create.header("Content-Type"), Some("application/json"));
+    let mapping: serde_json::Value = serde_json::from_slice(&create.body).unwrap();
+    let golden: serde_json::Value = serde_json::from_str(&fixture("mapping.json")).unwrap();
+    assert_eq!(mapping, golden);
+}
+
+#[test]
+fn document_matches_the_golden_snapshot() {
+    let _serial = setup();
+    clock::set(ManualClock::at(DATE.parse().unwrap()));
+    let server = MockServer::start(existing_index(201));
+    let prism = prism(server.port);
+    transaction(&prism, 2003);
+
+    assert_eq!(persisted(2003), Ok(()));
+    let document = server.wait(Request::is_document);
+    clock::reset();
+    assert_eq!(
+        document.path,
+        format!("/{}/_doc/8880993a-37ea-89d8-9450-35851c1737cb", INDEX)
+    );
+    assert_eq!(document_id(2003), "8880993a-37ea-89d8-9450-35851c1737cb");
+    assert_eq!(document.header("Content-Type"), Some("application/json"));
+    assert_eq!(
+        String::from_utf8(document.body).unwrap(),
+        fixture("document.json").trim_end()
+    );
+}
+
+#[test]
+fn failed_index_creation_holds_documents_back() {
+    let _serial = setup();
+    let accept = Arc::new(AtomicBool::new(false));
+    let accepting = accept.clone();
+    let server = MockServer::start(move |request| {
+        if request.method == "GET" {
+            Some((404, "{}"))
+        } else if accepting.load(Ordering::Relaxed) {
+            Some((200, "{}"))
+        } else {
+            Some((400, r#"{"error":"mapping rejected"}"#))
+        }
+    });
+    let prism = prism(server.port);
+    transaction(&prism, 2004);
+
+    wait_warning("Failed initializing elasticsearch backend");
+    assert!(warned("http 400"));
+    assert!(warned("mapping rejected"));
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(!server.requests().iter().any(Request::is_document));
+
+    // Tried again once the backoff elapsed, then the document goes out.
+    accept.store(true, Ordering::Relaxed);
+    assert_eq!(persisted(2004), Ok(()));
+    server.wait(Request::is_document);
+}
+
+#[test]
+fn slow_index_initialization_stays_off_the_request_path() {
+    let _serial = setup();
+    let delay = Duration::from_millis(500);
+    let server = MockServer::start(move |request| {
+        if request.is("GET", &format!("/{}", INDEX)) {
+            std::thread::sleep(delay);
+        }
+        existing_index(201)(request)
+    });
+    let prism = prism(server.port);
+
+    for id in 2031..=2033 {
+        let started = Instant::now();
+        transaction(&prism, id);
+        let elapsed = started.elapsed();
+        assert!(elapsed < delay / 5, "transaction {} took {:?}", id, elapsed);
+    }
+    // Held back until the index is checked, then all flushed.
+    for id in 2031..=2033 {
+        assert_eq!(persisted(id), Ok(()));
+    }
+    let requests = server.requests();
+    let check = requests
+        .iter()
+        .position(|request| request.is("GET", &format!("/{}", INDEX)))
+        .unwrap();
+    let first_document = requests.iter().position(Request::is_document).unwrap();
+    assert!(check < first_document);
+}
+
+#[test]
+fn rejected_documents_fail_with_a_warning() {
+    let _serial = setup();
+    let server = MockServer::start(move |request| {
+        if !request.is_document() {
+            Some((200, "{}"))
+        } else if request.path.ends_with(&document_id(2005)) {
+            Some((400, r#"{"error":"mapper_parsing_exception"}"#))
+        } else {
+            Some((503, r#"{"error":"unavailable"}"#))
+        }
+    });
+    let prism = prism(server.port);
+    transaction(&prism, 2005);
+    transaction(&prism, 2006);
+
+    assert_eq!(persisted(2005), Err(PrismError::BackendUnavailable));
+    assert_eq!(persisted(2006), Err(PrismError::BackendUnavailable));
+    wait_warning("http status 400 Bad Request");
+    assert!(warned("mapper_parsing_exception"));
+    wait_warning("http status 503 Service Unavailable");
+}
+
+#[test]
+fn unreachable_cluster_fails_initializing() {
+    let _serial = setup();
+    let port = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let _prism = prism(port);
+
+    wait_warning(
+        "Failed initializing elasticsearch backend, calls to persist transaction will fail: ",
+    );
+}
+
+#[test]
+fn connection_lost_while_persisting_fails_with_a_warning() {
+    let _serial = setup();
+    let server = MockServer::start(|request| {
+        if request.is_document() {
+            None
+        } else {
+            Some((200, "{}"))
+        }
+    });
+    let prism = prism(server.port);
+    transaction(&prism, 2007);
+
+    assert_eq!(persisted(2007), Err(PrismError::BackendUnavailable));
+    wait_warning("(error: ");
+}
+
+#[test]
+fn newer_index_schema_is_warned_about() {
+    let _serial = setup();
+    let server = MockServer::start(|request| {
+        if request.is_document() {
+            Some((201, r#"{"result":"created"}"#))
+        } else {
+            Some((
+                200,
+                r#"{"prism-test":{"mappings":{"_meta":{"schema_version":99}}}}"#,
+            ))
+        }
+    });
+    let prism = prism(server.port);
+    transaction(&prism, 2008);
+
+    assert_eq!(persisted(2008), Ok(()));
+    wait_warning("holds documents of schema version 99, newer than version 9");
+}
+
+#[test]
+fn old_documents_are_deleted_by_query() {
+    let _serial = setup();
+    let server = MockServer::start(|request| {
+        if request.path.contains("/_delete_by_query") {
+            Some((200, r#"{"deleted":2}"#))
+        } else {
+            Some((200, "{}"))
+        }
+    });
+    let _prism = prism(server.port);
+    let mut config = (*config::get()).clone();
+    config.retention.max_age = Some(24 * 60 * 60);
+    config::set(config);
+    clock::set(ManualClock::at(DATE.parse().unwrap()));
+
+    let removed = retention::sweep();
+    clock::reset();
+    assert_eq!(removed, Ok(2));
+    let delete = server.wait(|request| {
+        request.is(
+            "POST",
+            &format!(
+                "/{}/_delete_by_query?max_docs=1000&scroll_size=1000&conflicts=proceed",
+                INDEX
+            ),
+        )
+    });
+    let query: serde_json::Value = serde_json::from_slice(&delete.body).unwrap();
+    assert_eq!(
+        query,
+        serde_json::json!({"query": {"range": {"date": {"lt": "2023-12-31T00:00:00Z"}}}})
+    );
+}
+
+#[test]
+fn routed_documents_go_to_their_own_index() {
+    let _serial = setup();
+    let server = MockServer::start(|request| {
+        if request.method == "GET" {
+            Some((404, r#"{"error":"index_not_found_exception"}"#))
+        } else {
+            Some((200, r#"{"acknowledged":true}"#))
+        }
+    });
+    let routes = [
+        ("text/html", "prism-test-pages"),
+        ("application/json", "prism-test-api"),
+        ("application/octet-stream", "prism-test-downloads"),
+    ];
+    let prism = configured(server.port, |config| {
+        config.backend.routes = routes
+            .iter()
+            .map(|(content_type, index)| Route {
+                content_types: vec![content_type.to_string()],
+                tag: None,
+                index: index.to_string(),
+            })
+            .collect();
+    });
+
+    for (id, (content_type, index)) in (2101..).zip(routes) {
+        let mut handle = prism.begin(
+            id,
+            "GET",
+            "http://routed.example.com/",
+            &[("Content-Type", content_type)],
+        );
+        handle.status(200);
+        handle.receive(b"hello").unwrap();
+        handle.done();
+        let mut buffer = [0; 64];
+        while !handle.finished() {
+            handle.poll_output(&mut buffer);
+        }
+        drop(handle);
+
+        assert_eq!(persisted(id), Ok(()));
+        let path = format!("/{}/_doc/{}", index, document_id(id));
+        let document = server.wait(|request| request.is("PUT", &path));
+        let document: serde_json::Value = serde_json::from_slice(&document.body).unwrap();
+        assert_eq!(document["routed_index"], index);
+    }
+    // Created along with the default index, with the same mapping.
+    for (_, index) in routes {
+        let create = server.wait(|request| request.is("PUT", &format!("/{}", index)));
+        let mapping: serde_json::Value = serde_json::from_slice(&create.body).unwrap();
+        assert_eq!(mapping["mappings"]["_meta"]["schema_version"], 9);
+    }
+
+    // Documents no route matches stay in the default index.
+    transaction(&prism, 2104);
+    assert_eq!(persisted(2104), Ok(()));
+    let document = server.wait(Request::is_document);
+    assert_eq!(
+        document.path,
+        format!("/{}/_doc/{}", INDEX, document_id(2104))
+    );
+}
+
+/// The mapping the default index is created with, on a cluster answering
+/// the version probe with `root`.
+fn created_mapping(id: i64, root: &'static str) -> serde_json::Value {
+    let server = MockServer::start(move |request| {
+        if request.is("GET", "/") {
+            Some((200, root))
+        } else if request.method == "GET" {
+            Some((404, r#"{"error":"index_not_found_exception"}"#))
+        } else {
+            Some((200, r#"{"acknowledged":true}"#))
+        }
+    });
+    let prism = prism(server.port);
+    transaction(&prism, id);
+
+    assert_eq!(persisted(id), Ok(()));
+    let create = server.wait(|request| request.is("PUT", &format!("/{}", INDEX)));
+    serde_json::from_slice(&create.body).unwrap()
+}
+
+#[test]
+fn mapping_follows_the_cluster_flavor() {
+    let _serial = setup();
+    let golden: serde_json::Value = serde_json::from_str(&fixture("mapping.json")).unwrap();
+    let properties = &golden["mappings"]["properties"];
+    assert_eq!(properties["uri"]["fields"]["keyword"]["type"], "keyword");
+    assert_eq!(properties["path"]["type"], "keyword");
+    assert_eq!(properties["host"]["type"], "keyword");
+
+    let current = created_mapping(
+        2201,
+        r#"{"version":{"number":"8.11.1","build_flavor":"default"},"tagline":"You Know, for Search"}"#,
+    );
+    assert_eq!(current, golden);
+
+    // Without the flattened type, headers are objects whose values are
+    // indexed as keywords, and the mapping is otherwise the same.
+    let flattened = [
+        "request_headers",
+        "response_headers",
+        "query_params",
+        "annotations",
+    ];
+    for (id, root) in [
+        (
+            2202,
+            r#"{"version":{"distribution":"opensearch","number":"2.11.0"}}"#,
+        ),
+        (
+            2203,
+            r#"{"version":{"number":"7.2.1","build_flavor":"default"}}"#,
+        ),
+    ] {
+        let mut mapping = created_mapping(id, root);
+        let templates = mapping["mappings"]["dynamic_templates"]
+            .as_array()
+            .unwrap()
+            .clone();
+        assert_eq!(templates.len(), flattened.len());
+        for field in flattened {
+            assert_eq!(properties[field]["type"], "flattened");
+            assert_eq!(
+                mapping["mappings"]["properties"][field],
+                serde_json::json!({"type": "object", "dynamic": true})
+            );
+            let template = templates
+                .iter()
+                .find_map(|template| template.get(field))
+                .unwrap();
+            let path_match = format!("{}.*", field);
+            assert_eq!(
+                template,
+                &serde_json::json!({
+                    "path_match": path_match,
+                    "match_mapping_type": "string",
+                    "mapping": {"type": "keyword", "ignore_above": 8191},
+                })
+            );
+            mapping["mappings"]["properties"][field] = properties[field].clone();
+        }
+        let mappings = mapping["mappings"].as_object_mut().unwrap();
+        mappings.remove("dynamic_templates");
+        assert_eq!(mapping, golden);
+    }
+}
+
+#[test]
+fn mapping_is_migrated_to_a_new_index() {
+    let _serial = setup();
+    let server = MockServer::start(|request| {
+        if request.is("GET", &format!("/{}", INDEX)) {
+            Some((
+                200,
+                r#"{"prism-test":{"mappings":{"_meta":{"schema_version":8}}}}"#,
+            ))
+        } else if request.method == "GET" {
+            Some((404, r#"{"error":"index_not_found_exception"}"#))
+        } else {
+            Some((200, r#"{"acknowledged":true}"#))
+        }
+    });
+    let prism = prism(server.port);
+
+    assert_eq!(prism.migrate_mapping(), Ok(vec![format!("{}-v9", INDEX)]));
+    let create = server.wait(|request| request.is("PUT", &format!("/{}-v9", INDEX)));
+    let mapping: serde_json::Value = serde_json::from_slice(&create.body).unwrap();
+    let golden: serde_json::Value = serde_json::from_str(&fixture("mapping.json")).unwrap();
+    assert_eq!(mapping, golden);
+    // The index in use is left as it is.
+    assert!(!server
+        .requests()
+        .iter()
+        .any(|request| request.is("PUT", &format!("/{}", INDEX))));
+
+    // Indices migrated already are not created again.
+    let server = MockServer::start(|_| Some((200, "{}")));
+    let prism = configured(server.port, |_| {});
+    assert_eq!(prism.migrate_mapping(), Ok(Vec::new()));
+    assert!(!server
+        .requests()
+        .iter()
+        .any(|request| request.method == "PUT"));
+
+    // Nor can they be on a cluster refusing them.
+    let server = MockServer::start(|request| {
+        if request.method == "GET" {
+            Some((404, r#"{"error":"index_not_found_exception"}"#))
+        } else {
+            Some((400, r#"{"error":"mapper_parsing_exception"}"#))
+        }
+    });
+    let prism = configured(server.port, |_| {});
+    assert_eq!(prism.migrate_mapping(), Err(PrismError::BackendUnavailable));
+}
+
+#[test]
+fn renamed_fields_are_persisted_and_mapped_under_their_names() {
+    let _serial = setup();
+    let hostname = std::env::var_os("HOSTNAME");
+    std::env::set_var("HOSTNAME", "Site-A");
+    let server = MockServer::start(|request| {
+        if request.method == "GET" {
+            Some((404, r#"{"error":"index_not_found_exception"}"#))
+        } else {
+            Some((200, r#"{"acknowledged":true}"#))
+        }
+    });
+    let prism = configured(server.port, |config| {
+        let elasticsearch = &mut config.backend.elasticsearch;
+        elasticsearch.index = "prism-{hostname}".to_string();
+        for (field, name) in [
+            ("uri", "url.full"),
+            ("method", "http.request.method"),
+            ("host", "path"),
+            ("path", "host"),
+        ] {
+            elasticsearch
+                .fields
+                .insert(field.to_string(), name.to_string());
+        }
+    });
+    transaction(&prism, 2301);
+
+    // The index is named when the backend is built, for the first document.
+    assert_eq!(persisted(2301), Ok(()));
+    match hostname {
+        Some(hostname) => std::env::set_var("HOSTNAME", hostname),
+        None => std::env::remove_var("HOSTNAME"),
+    }
+    let document = server
+        .wait(|request| request.method == "PUT" && request.path.starts_with("/prism-site-a/_doc/"));
+    let document: serde_json::Value = serde_json::from_slice(&document.body).unwrap();
+    assert_eq!(document["url.full"], "http://golden.example.com/page");
+    assert_eq!(document["http.request.method"], "GET");
+    assert_eq!(document["host"], "/page");
+    assert_eq!(document["path"], "golden.example.com");
+    assert!(document.get("uri").is_none());
+    assert!(document.get("method").is_none());
+
+    let create = server.wait(|request| request.is("PUT", "/prism-site-a"));
+    let mapping: serde_json::Value = serde_json::from_slice(&create.body).unwrap();
+    let golden: serde_json::Value = serde_json::from_str(&fixture("mapping.json")).unwrap();
+    let (properties, golden) = (
+        &mapping["mappings"]["properties"],
+        &golden["mappings"]["properties"],
+    );
+    assert_eq!(properties["url.full"], golden["uri"]);
+    assert_eq!(properties["http.request.method"], golden["method"]);
+    assert_eq!(properties["host"], golden["path"]);
+    assert_eq!(properties["path"], golden["host"]);
+    assert!(properties.get("uri").is_none());
+    assert!(properties.get("method").is_none());
+}
@@ -0,0 +1,195 @@
+//! Encryption at rest: records sealed and opened again, tampering caught,
+//! and bodies kept off the disk in plaintext.
+
+mod common;
+
+use base64::{engine::general_purpose, Engine};
+use common::{drain, TIMEOUT};
+use prism::config::{self, Config};
+use prism::encryption::{self, Key};
+use prism::{memory, Prism};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::MutexGuard;
+use std::time::Duration;
+
+/// Found in the bodies captured, and nowhere on disk.
+const MARKER: &str = "plaintext-marker";
+
+fn encoded_key(byte: u8) -> String {
+    general_purpose::STANDARD.encode([byte; encryption::KEY_SIZE])
+}
+
+fn setup(configure: impl FnOnce(&mut Config)) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.limits.coalesce_size = 0;
+        config.encryption.key = Some(encoded_key(1));
+        configure(config);
+    })
+}
+
+fn directory(name: &str) -> PathBuf {
+    let directory =
+        std::env::temp_dir().join(format!("prism-encryption-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// The files in `directory` with `extension`, in name order.
+fn files(directory: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(directory)
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|found| found == extension))
+        .collect();
+    files.sort();
+    files
+}
+
+fn open(sealed: &[u8], keys: &[Key]) -> Result<Vec<u8>, String> {
+    encryption::open(sealed, keys, usize::MAX)
+}
+
+#[test]
+fn records_round_trip_with_the_key_they_name() {
+    let key = Key::parse(&encoded_key(1)).unwrap();
+    let other = Key::parse(&encoded_key(2)).unwrap();
+    assert_eq!(key.id().len(), 16);
+    assert_ne!(key.id(), other.id());
+
+    let mut sealed = key.seal(b"first\n");
+    sealed.extend(other.seal(b"second\n"));
+    assert!(!String::from_utf8_lossy(&sealed).contains("first"));
+    assert_eq!(open(&sealed, &[key, other]).unwrap(), b"first\nsecond\n");
+
+    let key = Key::parse(&encoded_key(1)).unwrap();
+    let error = open(&sealed, &[key]).unwrap_err();
+    assert!(error.starts_with("record 2: no key with id"), "{}", error);
+    assert!(Key::parse(&general_purpose::STANDARD.encode([1; 16])).is_err());
+}
+
+#[test]
+fn tampered_records_are_rejected() {
+    let key = Key::parse(&encoded_key(1)).unwrap();
+    let other = Key::parse(&encoded_key(2)).unwrap();
+    let record: Value = serde_json::from_slice(&key.seal(b"hello")).unwrap();
+
+    let mut ciphertext = general_purpose::STANDARD
+        .decode(record["ciphertext"].as_str().unwrap())
+        .unwrap();
+    ciphertext[0] ^= 1;
+    let mut tampered = record.clone();
+    tampered.as_object_mut().unwrap().insert(
+        "ciphertext".to_string(),
+        Value::String(general_purpose::STANDARD.encode(ciphertext)),
+    );
+    // Claiming another key is caught as well, as the key id is
+    // authenticated.
+    let mut relabeled = record.clone();
+    relabeled
+        .as_object_mut()
+        .unwrap()
+        .insert("key_id".to_string(), Value::String(other.id().to_string()));
+
+    let keys = [key, other];
+    assert_eq!(
+        open(record.to_string().as_bytes(), &keys).unwrap(),
+        b"hello"
+    );
+    for record in [tampered, relabeled] {
+        let error = open(record.to_string().as_bytes(), &keys).unwrap_err();
+        assert!(error.contains("authentication failed"), "{}", error);
+    }
+}
+
+#[test]
+fn spilled_bodies_never_hit_the_disk_in_plaintext() {
+    let spool = directory("spool");
+    let (prism, _serial) = setup(|config| {
+        config.limits.spool_dir = Some(spool.clone());
+        config.limits.spill_threshold = 16;
+    });
+    let mut handle = prism.begin(
+        14001,
+        "GET",
+        "http://encryption.example.com/",
+        &[("Content-Type", "text/plain")],
+    );
+    handle.status(200);
+    for _ in 0..4 {
+        handle.receive(format!("{} ", MARKER).as_bytes()).unwrap();
+    }
+
+    let spilled = files(&spool, "body");
+    assert_eq!(spilled.len(), 1, "{:?}", spilled);
+    let sealed = std::fs::read(&spilled[0]).unwrap();
+    assert!(!String::from_utf8_lossy(&sealed).contains(MARKER));
+    let key = Key::parse(&encoded_key(1)).unwrap();
+    let body = format!("{} ", MARKER).repeat(4);
+    assert_eq!(open(&sealed, &[key]).unwrap(), body.as_bytes());
+
+    handle.done();
+    drain(&mut handle);
+    drop(handle);
+    let document = memory::wait(14001, TIMEOUT).expect("document persisted");
+    assert_eq!(document.json["body"], body);
+    std::fs::remove_dir_all(&spool).unwrap();
+}
+
+#[test]
+fn har_files_are_sealed_across_key_rotations() {
+    let har = directory("har");
+    let (prism, _serial) = setup(|config| {
+        config.backend.kind = "har".to_string();
+        config.backend.har.directory = Some(har.clone());
+        config.backend.har.entries_per_file = Some(1);
+    });
+    let run = |id: i64| {
+        common::relay(
+            &prism,
+            id,
+            &format!("http://encryption.example.com/{}", id),
+            &[("Content-Type", "text/plain")],
+            MARKER.as_bytes(),
+        )
+    };
+    let wait = |count: usize| {
+        let deadline = std::time::Instant::now() + TIMEOUT;
+        while files(&har, "har").len() < count {
+            assert!(std::time::Instant::now() < deadline, "HAR file not written");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    };
+    run(14101);
+    wait(1);
+    let mut rotated = (*config::get()).clone();
+    rotated.encryption.key = Some(encoded_key(2));
+    config::set(rotated);
+    run(14102);
+    wait(2);
+
+    let keys = || {
+        [
+            Key::parse(&encoded_key(1)).unwrap(),
+            Key::parse(&encoded_key(2)).unwrap(),
+        ]
+    };
+    let written = files(&har, "har");
+    assert_eq!(written.len(), 2, "{:?}", written);
+    for (path, id) in written.iter().zip([14101, 14102]) {
+        let sealed = std::fs::read(path).unwrap();
+        assert!(!String::from_utf8_lossy(&sealed).contains(MARKER));
+        let opened: Value = serde_json::from_slice(&open(&sealed, &keys()).unwrap()).unwrap();
+        let entries = opened["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(
+            entry["request"]["url"],
+            format!("http://encryption.example.com/{}", id)
+        );
+    }
+    // The first file needs the key it was sealed with.
+    let [_, second] = keys();
+    assert!(open(&std::fs::read(&written[0]).unwrap(), &[second]).is_err());
+    std::fs::remove_dir_all(&har).unwrap();
+}
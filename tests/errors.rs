@@ -0,0 +1,201 @@
+//! Error codes, which must never change, and the errors the exported
+//! functions report to observers when they fail: exactly one each.
+
+mod common;
+
+use common::{lock, setup_ffi, TIMEOUT};
+use prism::error::PrismError;
+use prism::observer::{self, LifecycleObserver};
+use std::ffi::{c_void, CString};
+use std::ptr::null;
+use std::sync::{Mutex, MutexGuard, Once};
+
+static SETUP: Once = Once::new();
+static ERRORS: Mutex<Vec<(Option<i64>, PrismError)>> = Mutex::new(Vec::new());
+
+struct Errors;
+
+impl LifecycleObserver for Errors {
+    fn on_error(&self, event: &observer::ErrorEvent) {
+        lock(&ERRORS).push((event.id, event.error));
+    }
+}
+
+fn setup() -> MutexGuard<'static, ()> {
+    SETUP.call_once(|| observer::register(Box::new(Errors)).unwrap());
+    setup_ffi()
+}
+
+/// The errors reported while running `call`, along with what it returned.
+fn reported<R>(call: impl FnOnce() -> R) -> (R, Vec<(Option<i64>, PrismError)>) {
+    lock(&ERRORS).clear();
+    let result = call();
+    (result, std::mem::take(&mut *lock(&ERRORS)))
+}
+
+#[test]
+fn codes_labels_and_messages_are_stable() {
+    let expected = [
+        (
+            PrismError::InvalidArgument,
+            1,
+            "invalid_argument",
+            "invalid argument",
+        ),
+        (
+            PrismError::UnknownTransaction,
+            2,
+            "unknown_transaction",
+            "unknown transaction",
+        ),
+        (PrismError::Decode, 3, "decode", "failed decoding body"),
+        (PrismError::Encode, 4, "encode", "failed encoding body"),
+        (
+            PrismError::BackendUnavailable,
+            5,
+            "backend_unavailable",
+            "persistence backend unavailable",
+        ),
+        (
+            PrismError::QueueOverflow,
+            6,
+            "queue_overflow",
+            "persistence queue unavailable",
+        ),
+        (PrismError::Panic, 7, "panic", "internal panic"),
+        (
+            PrismError::InvalidConfig,
+            8,
+            "invalid_config",
+            "invalid configuration",
+        ),
+        (PrismError::Io, 9, "io", "failed writing a file"),
+        (
+            PrismError::Timeout,
+            10,
+            "timeout",
+            "transaction deadline passed",
+        ),
+        (
+            PrismError::ChecksumMismatch,
+            11,
+            "checksum_mismatch",
+            "chunk checksum mismatch",
+        ),
+    ];
+    assert_eq!(PrismError::ALL.len(), expected.len());
+    for (index, (error, code, label, message)) in expected.into_iter().enumerate() {
+        assert_eq!(PrismError::ALL[index], error);
+        assert_eq!(error.code(), code);
+        assert_eq!(error.index(), index);
+        assert_eq!(error.label(), label);
+        assert_eq!(error.to_string(), message);
+    }
+}
+
+#[test]
+fn committed_header_lists_every_code() {
+    let header = std::fs::read_to_string(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/include/prism_errors.h"
+    ))
+    .unwrap();
+    assert_eq!(header, prism::error::c_header());
+    let defines: Vec<(String, i32)> = header
+        .lines()
+        .filter_map(|line| line.strip_prefix("#define PRISM_E_"))
+        .map(|define| {
+            let (name, code) = define.split_once(' ').unwrap();
+            (name.to_string(), code.parse().unwrap())
+        })
+        .collect();
+    let expected: Vec<(String, i32)> = PrismError::ALL
+        .iter()
+        .map(|error| (error.label().to_ascii_uppercase(), error.code()))
+        .collect();
+    assert_eq!(defines, expected);
+}
+
+#[test]
+fn null_arguments_report_invalid_argument() {
+    let _serial = setup();
+    let id = 37001;
+    let uri = CString::new("http://errors.example.com/").unwrap();
+    let method = CString::new("GET").unwrap();
+    let name = CString::new("Content-Type").unwrap();
+
+    let (_, errors) = reported(|| prism::uri(id, null(), 1, method.as_ptr()));
+    assert_eq!(errors, [(Some(id), PrismError::InvalidArgument)]);
+    let (_, errors) = reported(|| prism::uri(id, uri.as_ptr(), 1, null()));
+    assert_eq!(errors, [(Some(id), PrismError::InvalidArgument)]);
+    let (_, errors) = reported(|| prism::receive(id, null(), 5));
+    assert_eq!(errors, [(Some(id), PrismError::InvalidArgument)]);
+    let (code, errors) = reported(|| prism::receive_checked(id, null(), 5, 0));
+    assert_eq!(code, PrismError::InvalidArgument.code());
+    assert_eq!(errors, [(Some(id), PrismError::InvalidArgument)]);
+    let (_, errors) = reported(|| prism::annotate(id, name.as_ptr(), null()));
+    assert_eq!(errors, [(Some(id), PrismError::InvalidArgument)]);
+    let (_, errors) = reported(|| prism::block(id, null()));
+    assert_eq!(errors, [(Some(id), PrismError::InvalidArgument)]);
+    let (code, errors) = reported(|| prism::dump_state(null()));
+    assert_eq!(code, PrismError::InvalidArgument.code());
+    assert_eq!(errors, [(None, PrismError::InvalidArgument)]);
+    let (code, errors) = reported(|| prism::configure(null()));
+    assert_eq!(code, PrismError::InvalidArgument.code());
+    assert_eq!(errors, [(None, PrismError::InvalidArgument)]);
+    let (_, errors) = reported(|| prism::set_log_filter(null()));
+    assert_eq!(errors, [(None, PrismError::InvalidArgument)]);
+}
+
+#[test]
+fn unknown_transactions_report_unknown_transaction() {
+    let _serial = setup();
+    let id = 37101;
+    let body = b"hello";
+    let data = body.as_ptr() as *const c_void;
+    let key = CString::new("key").unwrap();
+    let value = CString::new("value").unwrap();
+
+    let (_, errors) = reported(|| prism::receive(id, data, body.len()));
+    assert_eq!(errors, [(Some(id), PrismError::UnknownTransaction)]);
+    let crc32 = prism::checksum::crc32(body);
+    let (code, errors) = reported(|| prism::receive_checked(id, data, body.len(), crc32));
+    assert_eq!(code, PrismError::UnknownTransaction.code());
+    assert_eq!(errors, [(Some(id), PrismError::UnknownTransaction)]);
+    let (_, errors) = reported(|| prism::annotate(id, key.as_ptr(), value.as_ptr()));
+    assert_eq!(errors, [(Some(id), PrismError::UnknownTransaction)]);
+    let (_, errors) = reported(|| prism::block(id, value.as_ptr()));
+    assert_eq!(errors, [(Some(id), PrismError::UnknownTransaction)]);
+    let (_, errors) = reported(|| prism::trace(id, true));
+    assert_eq!(errors, [(Some(id), PrismError::UnknownTransaction)]);
+}
+
+#[test]
+fn failed_calls_report_their_error_once() {
+    let _serial = setup();
+    let id = 37201;
+    common::begin(
+        id,
+        "http://errors.example.com/",
+        &[("Content-Type", "text/plain")],
+    );
+    let body = b"hello";
+    let data = body.as_ptr() as *const c_void;
+
+    let crc32 = prism::checksum::crc32(body);
+    let (code, errors) = reported(|| prism::receive_checked(id, data, body.len(), crc32 ^ 1));
+    assert_eq!(code, PrismError::ChecksumMismatch.code());
+    assert_eq!(errors, [(Some(id), PrismError::ChecksumMismatch)]);
+
+    let (code, errors) = reported(|| prism::receive_checked(id, data, body.len(), crc32));
+    assert_eq!(code, 0);
+    assert!(errors.is_empty());
+    common::finish(id);
+    prism::cleanup(id);
+    assert!(prism::memory::wait(id, TIMEOUT).is_some());
+
+    let path = CString::new("/nonexistent/prism/state.json").unwrap();
+    let (code, errors) = reported(|| prism::dump_state(path.as_ptr()));
+    assert_eq!(code, PrismError::Io.code());
+    assert_eq!(errors, [(None, PrismError::Io)]);
+}
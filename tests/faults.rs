@@ -0,0 +1,539 @@
+//! Recovery from backend failures and corrupted bodies, injected through
+//! `prism::faults` into transactions persisted to the memory backend.
+
+#![cfg(feature = "fault-injection")]
+
+mod common;
+
+use common::{gzip, lock, TIMEOUT};
+use prism::config::{self, Config};
+use prism::error::PrismError;
+use prism::faults::{self, Corruption};
+use prism::observer::{self, LifecycleObserver};
+use prism::{memory, Prism};
+use std::sync::{Mutex, MutexGuard, Once};
+use std::time::{Duration, Instant};
+
+static SETUP: Once = Once::new();
+static PERSISTED: Mutex<Vec<(i64, Result<(), PrismError>)>> = Mutex::new(Vec::new());
+static ERRORS: Mutex<Vec<(Option<i64>, PrismError)>> = Mutex::new(Vec::new());
+
+struct Events;
+
+impl LifecycleObserver for Events {
+    fn on_persisted(&self, event: &observer::Persisted) {
+        lock(&PERSISTED).push((event.id, event.result));
+    }
+
+    fn on_error(&self, event: &observer::ErrorEvent) {
+        lock(&ERRORS).push((event.id, event.error));
+    }
+}
+
+/// Every test shares the faults and the events observed, so they all hold
+/// the lock.
+fn setup() -> MutexGuard<'static, ()> {
+    SETUP.call_once(|| observer::register(Box::new(Events)).unwrap());
+    let serial = common::serial();
+    faults::clear();
+    memory::clear();
+    serial
+}
+
+fn prism(configure: impl FnOnce(&mut Config)) -> Prism {
+    let mut config = common::config();
+    config.limits.coalesce_size = 0;
+    configure(&mut config);
+    Prism::new(config).unwrap()
+}
+
+/// Runs a transaction through, receiving `body` whole, and returns what was
+/// handed back.
+fn transaction(prism: &Prism, id: i64, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    common::relay(prism, id, "http://faults.example.com/", headers, body)
+}
+
+fn plain(prism: &Prism, id: i64) {
+    transaction(prism, id, &[("Content-Type", "text/plain")], b"hello world");
+}
+
+/// Waits for the outcome of persisting document `id`.
+fn persisted(id: i64) -> Result<(), PrismError> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some((_, result)) = lock(&PERSISTED)
+            .iter()
+            .find(|(persisted, _)| *persisted == id)
+        {
+            return *result;
+        }
+        assert!(Instant::now() < deadline, "document {} not persisted", id);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn failed(id: i64, error: PrismError) -> bool {
+    lock(&ERRORS).contains(&(Some(id), error))
+}
+
+fn page() -> Vec<u8> {
+    (0..2000)
+        .flat_map(|line| format!("<p>line {} of the page</p>\n", line).into_bytes())
+        .collect()
+}
+
+const GZIP_HEADERS: [(&str, &str); 2] =
+    [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+
+#[test]
+fn next_persists_fail_then_recover() {
+    let _serial = setup();
+    let prism = prism(|_| {});
+    faults::backend().fail_next(2, PrismError::BackendUnavailable);
+
+    let results: Vec<_> = (4001..=4003)
+        .map(|id| {
+            plain(&prism, id);
+            persisted(id)
+        })
+        .collect();
+    assert_eq!(
+        results,
+        [
+            Err(PrismError::BackendUnavailable),
+            Err(PrismError::BackendUnavailable),
+            Ok(())
+        ]
+    );
+    assert!(failed(4001, PrismError::BackendUnavailable));
+    assert!(memory::find(4002).is_none());
+    assert!(memory::find(4003).is_some());
+}
+
+#[test]
+fn documents_fail_by_id() {
+    let _serial = setup();
+    let prism = prism(|_| {});
+    faults::backend().fail_id(4004, PrismError::Encode);
+
+    plain(&prism, 4004);
+    plain(&prism, 4005);
+    assert_eq!(persisted(4004), Err(PrismError::Encode));
+    assert_eq!(persisted(4005), Ok(()));
+    assert!(memory::find(4004).is_none());
+}
+
+#[test]
+fn held_documents_overflow_to_the_dead_letter_file() {
+    let _serial = setup();
+    let path = std::env::temp_dir().join(format!("prism-faults-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let prism = prism(|config| {
+        config.limits.max_queued_documents = 1;
+        config.limits.queue_overflow = "dead_letter".to_string();
+        config.limits.dead_letter_path = Some(path.clone());
+    });
+    faults::backend().hold(true);
+
+    plain(&prism, 4006);
+    plain(&prism, 4007);
+    let dead_lettered = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(dead_lettered.lines().count(), 1);
+    assert!(dead_lettered.contains("hello world"));
+    assert!(memory::find(4006).is_none());
+
+    faults::backend().hold(false);
+    assert_eq!(persisted(4006), Ok(()));
+    assert!(memory::find(4007).is_none());
+    // Replaying the file would overwrite rather than duplicate documents.
+    assert!(dead_lettered.contains("\"document_id\":\""));
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// An instance whose single worker holds the first document it takes while
+/// the backend is held, with room for two more in the queue.
+fn stalled(policy: &str) -> Prism {
+    let prism = prism(|config| {
+        config.limits.persistence_workers = 1;
+        config.limits.max_queued_documents = 2;
+        config.limits.queue_overflow = policy.to_string();
+        config.logging.throttle_window = 0;
+    });
+    faults::backend().hold(true);
+    prism
+}
+
+/// Runs the transactions `ids` through, the first one only once the worker
+/// took it off the queue.
+fn fill(prism: &Prism, ids: std::ops::RangeInclusive<i64>) {
+    let mut ids = ids;
+    plain(prism, ids.next().unwrap());
+    let deadline = Instant::now() + TIMEOUT;
+    while common::dump(prism)["queue"]["documents"] != 0 {
+        assert!(Instant::now() < deadline, "document not taken");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    for id in ids {
+        plain(prism, id);
+    }
+}
+
+/// How much each overflow counter moved while running `f`.
+fn overflows(prism: &Prism, f: impl FnOnce()) -> [u64; 3] {
+    let counters = || {
+        let counters = common::dump(prism)["counters"].clone();
+        ["dropped_newest", "dropped_oldest", "dead_lettered"]
+            .map(|counter| counters[counter].as_u64().unwrap())
+    };
+    let before = counters();
+    f();
+    let after = counters();
+    [0, 1, 2].map(|index| after[index] - before[index])
+}
+
+#[test]
+fn overflowing_documents_are_dropped_newest_first() {
+    let _serial = setup();
+    common::capture_logs();
+    let prism = stalled("drop_newest");
+    let moved = overflows(&prism, || fill(&prism, 4301..=4305));
+    assert_eq!(moved, [2, 0, 0]);
+    assert!(failed(4304, PrismError::QueueOverflow));
+    assert!(failed(4305, PrismError::QueueOverflow));
+    let dropping = common::logged(|logged| logged.message.starts_with("Dropping document"));
+    assert_eq!(dropping.len(), 2, "{:?}", dropping);
+    assert!(dropping[1].starts_with("Dropping document 4305 "));
+
+    faults::backend().hold(false);
+    for id in 4301..=4303 {
+        assert_eq!(persisted(id), Ok(()));
+    }
+    prism.shutdown();
+    assert!(memory::find(4304).is_none());
+    assert!(memory::find(4305).is_none());
+    let stopped =
+        common::logged(|logged| logged.message.starts_with("Persistence workers stopped"));
+    assert_eq!(
+        stopped,
+        ["Persistence workers stopped: 3 documents flushed, 2 dropped, 0 dead-lettered"]
+    );
+}
+
+#[test]
+fn overflowing_documents_make_room_by_dropping_the_oldest() {
+    let _serial = setup();
+    common::capture_logs();
+    let prism = stalled("drop_oldest");
+    let moved = overflows(&prism, || fill(&prism, 4311..=4315));
+    assert_eq!(moved, [0, 2, 0]);
+    assert!(failed(4312, PrismError::QueueOverflow));
+    assert!(failed(4313, PrismError::QueueOverflow));
+
+    faults::backend().hold(false);
+    for id in [4311, 4314, 4315] {
+        assert_eq!(persisted(id), Ok(()));
+    }
+    prism.shutdown();
+    assert!(memory::find(4312).is_none());
+    assert!(memory::find(4313).is_none());
+    let stopped =
+        common::logged(|logged| logged.message.starts_with("Persistence workers stopped"));
+    assert_eq!(
+        stopped,
+        ["Persistence workers stopped: 3 documents flushed, 2 dropped, 0 dead-lettered"]
+    );
+}
+
+#[test]
+fn overflowing_documents_are_dead_lettered() {
+    let _serial = setup();
+    common::capture_logs();
+    let path = common::temporary("dead-letter.jsonl");
+    let prism = prism(|config| {
+        config.limits.persistence_workers = 1;
+        config.limits.max_queued_documents = 2;
+        config.limits.queue_overflow = "dead_letter".to_string();
+        config.limits.dead_letter_path = Some(path.clone());
+    });
+    faults::backend().hold(true);
+    let moved = overflows(&prism, || fill(&prism, 4321..=4325));
+    assert_eq!(moved, [0, 0, 2]);
+    let dead_lettered = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(dead_lettered.lines().count(), 2);
+
+    faults::backend().hold(false);
+    for id in 4321..=4323 {
+        assert_eq!(persisted(id), Ok(()));
+    }
+    prism.shutdown();
+    let stopped =
+        common::logged(|logged| logged.message.starts_with("Persistence workers stopped"));
+    assert_eq!(
+        stopped,
+        ["Persistence workers stopped: 3 documents flushed, 0 dropped, 2 dead-lettered"]
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn retried_persists_store_documents_once() {
+    let _serial = setup();
+    let prism = prism(|config| config.backend.persist_attempts = 3);
+    // Stored by the backend, but reported as failed.
+    faults::backend().fail_next_stored(2, PrismError::BackendUnavailable);
+
+    plain(&prism, 4011);
+    assert_eq!(persisted(4011), Ok(()));
+    let documents: Vec<_> = memory::documents()
+        .into_iter()
+        .filter(|document| document.id == 4011)
+        .collect();
+    assert_eq!(documents.len(), 1);
+    assert_eq!(documents[0].writes, 3);
+    assert_eq!(documents[0].json["document_id"], documents[0].document_id);
+
+    // Documents failing to encode are not tried again.
+    faults::backend().fail_id(4012, PrismError::Encode);
+    plain(&prism, 4012);
+    assert_eq!(persisted(4012), Err(PrismError::Encode));
+    assert!(memory::find(4012).is_none());
+}
+
+#[test]
+fn corrupted_gzip_fails_decoding() {
+    let _serial = setup();
+    let prism = prism(|_| {});
+    let body = gzip(&page());
+    // The second byte of the gzip magic number.
+    faults::decoder().corrupt(
+        4008,
+        Corruption::FlipBits {
+            offset: 1,
+            mask: 0x01,
+        },
+    );
+
+    transaction(&prism, 4008, &GZIP_HEADERS, &body);
+    assert!(failed(4008, PrismError::Decode));
+    assert_eq!(persisted(4008), Ok(()));
+
+    // Only the transaction it was injected for is corrupted.
+    let output = transaction(&prism, 4009, &GZIP_HEADERS, &body);
+    assert!(!failed(4009, PrismError::Decode));
+    assert!(!output.is_empty());
+}
+
+#[test]
+fn truncated_gzip_captures_a_prefix() {
+    let _serial = setup();
+    let prism = prism(|_| {});
+    let page = page();
+    let body = gzip(&page);
+    faults::decoder().corrupt(
+        4010,
+        Corruption::Truncate {
+            after: body.len() / 2,
+        },
+    );
+
+    transaction(&prism, 4010, &GZIP_HEADERS, &body);
+    let document = memory::wait(4010, TIMEOUT).expect("document persisted");
+    assert!(document.body.len() < page.len());
+    assert!(page.starts_with(&document.body));
+}
+
+#[test]
+fn failures_move_their_counters_once() {
+    let _serial = setup();
+    let prism = prism(|config| config.limits.channel_capacity = Some(1));
+    let counters = || common::dump(&prism)["counters"].clone();
+    let before = counters();
+
+    faults::backend().fail_next(1, PrismError::BackendUnavailable);
+    plain(&prism, 4101);
+    assert_eq!(persisted(4101), Err(PrismError::BackendUnavailable));
+    plain(&prism, 4102);
+    assert_eq!(persisted(4102), Ok(()));
+
+    faults::decoder().corrupt(
+        4103,
+        Corruption::FlipBits {
+            offset: 1,
+            mask: 0x01,
+        },
+    );
+    transaction(&prism, 4103, &GZIP_HEADERS, &gzip(&page()));
+    assert_eq!(persisted(4103), Ok(()));
+
+    // The second chunk finds the channel full.
+    let mut handle = prism.begin(4104, "GET", "http://faults.example.com/", &[]);
+    handle.status(200);
+    let _ = handle.receive(b"first");
+    let _ = handle.receive(b"second");
+    handle.done();
+    common::drain(&mut handle);
+    drop(handle);
+    assert_eq!(persisted(4104), Ok(()));
+
+    let after = counters();
+    let moved = |pointer: &str| {
+        after.pointer(pointer).unwrap().as_u64().unwrap()
+            - before.pointer(pointer).unwrap().as_u64().unwrap()
+    };
+    assert_eq!(moved("/persist/memory/attempted"), 4);
+    assert_eq!(moved("/persist/memory/succeeded"), 3);
+    assert_eq!(moved("/persist/memory/failed"), 1);
+    assert_eq!(moved("/errors/backend_unavailable"), 1);
+    assert_eq!(moved("/decode_errors"), 1);
+    assert_eq!(moved("/errors/decode"), 1);
+    assert_eq!(moved("/dropped_chunks"), 1);
+    assert_eq!(moved("/transactions/completed"), 4);
+    assert_eq!(moved("/panics"), 0);
+    assert_eq!(moved("/persist/elasticsearch/attempted"), 0);
+}
+
+#[test]
+fn persist_latencies_fill_their_buckets() {
+    let _serial = setup();
+    let prism = prism(|config| config.telemetry.stats_reset_latency = true);
+    // Reads, and so resets, what earlier tests persisted.
+    common::stats();
+    // Three in (20ms, 50ms] and one in (200ms, 500ms], as sleeps may only
+    // overrun.
+    let delays = [30, 30, 30, 300].map(Duration::from_millis);
+    faults::backend().delay_next(&delays);
+    faults::backend().fail_id(4204, PrismError::BackendUnavailable);
+    for id in 4201..=4204 {
+        plain(&prism, id);
+        persisted(id).ok();
+    }
+
+    let latency = common::stats()["persist_latency"]["memory"].clone();
+    assert_eq!(latency["count"], 4);
+    assert!(latency["sum_ms"].as_f64().unwrap() >= 390.0);
+    let buckets: Vec<(String, u64)> = latency["buckets"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|bucket| {
+            let bucket = bucket.as_array().unwrap();
+            (
+                bucket[0].as_str().unwrap().to_string(),
+                bucket[1].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(buckets.len(), 16);
+    assert_eq!(buckets[15].0, "inf");
+    for (bound, count) in &buckets {
+        let expected = match bound.as_str() {
+            "50" => 3,
+            "500" => 1,
+            _ => 0,
+        };
+        assert_eq!(*count, expected, "bucket {}", bound);
+    }
+    // Interpolated within the bucket the rank falls in.
+    assert_eq!(latency["p50_ms"], 40.0);
+    assert_eq!(latency["p95_ms"], 440.0);
+    assert_eq!(latency["p99_ms"], 488.0);
+
+    // Read again, nothing was persisted since.
+    let latency = common::stats()["persist_latency"]["memory"].clone();
+    assert_eq!(latency["count"], 0);
+    assert!(latency["p50_ms"].is_null());
+}
+
+#[test]
+fn slow_persists_proceed_on_every_worker() {
+    let _serial = setup();
+    let prism = prism(|config| config.limits.persistence_workers = 4);
+    faults::backend().delay_next(&[Duration::from_millis(300); 8]);
+    let started = Instant::now();
+    for id in 4401..=4408 {
+        plain(&prism, id);
+    }
+    for id in 4401..=4408 {
+        assert_eq!(persisted(id), Ok(()));
+    }
+    // Two rounds of four, rather than eight one after the other.
+    let elapsed = started.elapsed();
+    assert!(elapsed < Duration::from_millis(1200), "{:?}", elapsed);
+    assert_eq!(common::dump(&prism)["queue"]["running"], 4);
+}
+
+#[test]
+fn shutdown_drains_the_queue_across_workers() {
+    let _serial = setup();
+    common::capture_logs();
+    let prism = prism(|config| config.limits.persistence_workers = 3);
+    faults::backend().delay_next(&[Duration::from_millis(100); 9]);
+    for id in 4411..=4419 {
+        plain(&prism, id);
+    }
+
+    prism.shutdown();
+    for id in 4411..=4419 {
+        assert!(memory::find(id).is_some(), "{}", id);
+    }
+    let queue = common::dump(&prism)["queue"].clone();
+    assert_eq!(queue["documents"], 0);
+    assert_eq!(queue["running"], 0);
+    let stopped =
+        common::logged(|logged| logged.message.starts_with("Persistence workers stopped"));
+    assert_eq!(
+        stopped,
+        ["Persistence workers stopped: 9 documents flushed, 0 dropped, 0 dead-lettered"]
+    );
+}
+
+#[test]
+fn transactions_keep_going_while_shutdown_drains_the_queue() {
+    let _serial = setup();
+    let prism = prism(|config| config.limits.persistence_workers = 1);
+    faults::backend().delay_next(&[Duration::from_millis(500); 3]);
+    for id in 4431..=4433 {
+        plain(&prism, id);
+    }
+
+    let stopping = prism.clone();
+    let shutdown = std::thread::spawn(move || stopping.shutdown());
+    let deadline = Instant::now() + TIMEOUT;
+    while common::dump(&prism)["queue"]["stopped"] != true {
+        assert!(Instant::now() < deadline, "shutdown not started");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let started = Instant::now();
+    plain(&prism, 4434);
+    let elapsed = started.elapsed();
+    assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+
+    shutdown.join().unwrap();
+    for id in 4431..=4433 {
+        assert!(memory::find(id).is_some(), "{}", id);
+    }
+    assert!(failed(4434, PrismError::QueueOverflow));
+    assert!(memory::find(4434).is_none());
+}
+
+#[test]
+fn reloaded_worker_count_winds_workers_down() {
+    let _serial = setup();
+    let prism = prism(|config| config.limits.persistence_workers = 4);
+    plain(&prism, 4421);
+    assert_eq!(persisted(4421), Ok(()));
+    assert_eq!(common::dump(&prism)["queue"]["running"], 4);
+
+    let mut reloaded = (*config::get()).clone();
+    reloaded.limits.persistence_workers = 1;
+    config::set(reloaded);
+    // Scaled as the next document is queued.
+    plain(&prism, 4422);
+    assert_eq!(persisted(4422), Ok(()));
+    let deadline = Instant::now() + TIMEOUT;
+    while common::dump(&prism)["queue"]["running"] != 1 {
+        assert!(Instant::now() < deadline, "workers not wound down");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(common::dump(&prism)["queue"]["workers"], 1);
+}
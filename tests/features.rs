@@ -0,0 +1,95 @@
+//! Builds leaving subsystems out: transactions still run through, and what
+//! was left out is skipped rather than failing. Only the features turned
+//! off are checked here, the others are covered by their own tests.
+
+#![cfg(not(all(feature = "elasticsearch", feature = "metrics")))]
+
+mod common;
+
+use common::lock;
+use prism::observer::{self, LifecycleObserver};
+use std::net::TcpListener;
+use std::sync::{Mutex, MutexGuard, Once};
+
+static SETUP: Once = Once::new();
+static ADDRESS: Mutex<String> = Mutex::new(String::new());
+/// Transactions persisted or failing, by id.
+static OUTCOMES: Mutex<Vec<i64>> = Mutex::new(Vec::new());
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct Outcomes;
+
+impl LifecycleObserver for Outcomes {
+    fn on_persisted(&self, event: &observer::Persisted) {
+        lock(&OUTCOMES).push(event.id);
+    }
+
+    fn on_error(&self, event: &observer::ErrorEvent) {
+        if let Some(id) = event.id {
+            lock(&OUTCOMES).push(id);
+        }
+    }
+}
+
+/// Initializes the library once, persisting to the default backend and
+/// serving metrics on a free address, and keeps the warnings of `init()`.
+fn setup() -> MutexGuard<'static, ()> {
+    let serial = common::serial();
+    SETUP.call_once(|| {
+        observer::register(Box::new(Outcomes)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        common::capture_logs();
+        common::configure(&format!("[telemetry]\nmetrics_address = \"{}\"\n", address));
+        prism::init();
+        *lock(&ADDRESS) = address;
+        *lock(&WARNINGS) = common::warnings();
+    });
+    serial
+}
+
+#[cfg(not(feature = "elasticsearch"))]
+#[test]
+fn documents_for_a_backend_left_out_are_skipped() {
+    use common::{dump, TIMEOUT};
+    use std::time::Instant;
+
+    let _serial = setup();
+    assert!(lock(&WARNINGS).contains(
+        &"The elasticsearch backend is not compiled in, documents won't be persisted".to_string()
+    ));
+
+    let prism = prism::Prism::new(prism::config::get().as_ref().clone()).unwrap();
+    let id = 67001;
+    let body = b"left out".to_vec();
+    let handed = common::relay(
+        &prism,
+        id,
+        "http://features.example.com/",
+        &[("Content-Type", "text/plain")],
+        &body,
+    );
+    assert_eq!(handed, body);
+
+    let started = Instant::now();
+    loop {
+        let queue = &dump(&prism)["queue"];
+        if queue["documents"] == 0 && queue["running"] == 0 {
+            break;
+        }
+        assert!(started.elapsed() < TIMEOUT, "{}", queue);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    // Neither persisted nor failed.
+    assert!(!lock(&OUTCOMES).contains(&id));
+    assert_eq!(dump(&prism)["queue"]["dropped"], 0);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[test]
+fn metrics_are_not_served() {
+    let _serial = setup();
+    let address = lock(&ADDRESS).clone();
+    assert!(std::net::TcpStream::connect(address).is_err());
+}
@@ -0,0 +1,206 @@
+//! Drives the exported functions through whole transactions, the way the
+//! adapter calls them, with documents persisted to the memory backend.
+//! `init()` is never called, so logging stays uninstalled and nothing needs
+//! syslog or a network.
+
+mod common;
+
+use common::{gunzip, gzip, receive, send, setup_ffi as setup, stats, TIMEOUT};
+use prism::memory;
+use std::ffi::CString;
+use std::time::Duration;
+
+/// Checks that no transaction, nor headers waiting for one, are left.
+fn assert_registry_empty() {
+    let stats = stats();
+    assert_eq!(stats["active_transactions"].as_u64(), Some(0));
+    assert_eq!(stats["pending_headers"].as_u64(), Some(0));
+}
+
+fn header(id: i64, name: &str, value: &str) {
+    common::header(prism::header, id, name, value);
+}
+
+fn begin(id: i64, headers: &[(&str, &str)]) {
+    common::begin(id, &format!("http://test.example.com/{}", id), headers);
+}
+
+/// Runs a transaction receiving `body` in chunks of `chunk_size`, handing
+/// output back after each, then until the end of the body. Returns the
+/// output.
+fn exchange(id: i64, headers: &[(&str, &str)], body: &[u8], chunk_size: usize) -> Vec<u8> {
+    begin(id, headers);
+    let mut output = Vec::new();
+    for chunk in body.chunks(chunk_size) {
+        receive(id, chunk);
+        output.extend(send(id));
+    }
+    output.extend(common::finish(id));
+    output
+}
+
+fn text(size: usize) -> Vec<u8> {
+    let line = b"<p>prism integration test body</p>\n";
+    line.iter().cycle().take(size).copied().collect()
+}
+
+#[test]
+fn identity_body_is_relayed_and_persisted() {
+    let _serial = setup();
+    let id = 1001;
+    let body = text(100 * 1024);
+
+    let output = exchange(id, &[("Content-Type", "text/html")], &body, 4096);
+    assert_eq!(output, body);
+
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.body, body);
+    assert_eq!(document.status, Some(200));
+    assert_eq!(document.method, "GET");
+    assert_eq!(document.emitted_length, body.len());
+    assert!(!document.truncated);
+
+    prism::cleanup(id);
+    assert_registry_empty();
+}
+
+#[test]
+fn gzip_body_is_reencoded_and_persisted_decoded() {
+    let _serial = setup();
+    let id = 1002;
+    let body = text(256 * 1024);
+
+    let output = exchange(
+        id,
+        &[("Content-Type", "text/html"), ("Content-Encoding", "gzip")],
+        &gzip(&body),
+        8192,
+    );
+    assert_eq!(gunzip(&output), body);
+
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.body, body);
+    assert_eq!(document.encoding, "gzip");
+    assert_eq!(document.emitted_length, output.len());
+
+    prism::cleanup(id);
+    assert_registry_empty();
+}
+
+#[test]
+fn header_names_are_matched_in_any_case() {
+    let _serial = setup();
+    let body = text(64 * 1024);
+    let names = [
+        ("content-type", "content-encoding"),
+        ("CONTENT-TYPE", "CONTENT-ENCODING"),
+        ("Content-type", "cOnTeNt-EnCoDiNg"),
+    ];
+    for (id, (content_type, content_encoding)) in (1011..).zip(names) {
+        let output = exchange(
+            id,
+            &[(content_type, "text/html"), (content_encoding, "gzip")],
+            &gzip(&body),
+            8192,
+        );
+        assert_eq!(gunzip(&output), body, "{}", content_encoding);
+
+        let document = memory::wait(id, TIMEOUT).expect("document persisted");
+        assert_eq!(document.body, body, "{}", content_encoding);
+        assert_eq!(document.encoding, "gzip");
+        // Persisted with the casing received.
+        assert_eq!(document.json["response_headers"][content_encoding], "gzip");
+        prism::cleanup(id);
+    }
+    assert_registry_empty();
+}
+
+#[test]
+fn body_received_in_tiny_chunks_is_relayed_whole() {
+    let _serial = setup();
+    let id = 1003;
+    let body = text(10 * 1024);
+
+    let output = exchange(id, &[("Content-Type", "text/plain")], &body, 7);
+    assert_eq!(output, body);
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.body, body);
+
+    prism::cleanup(id);
+    assert_registry_empty();
+}
+
+#[test]
+fn aborted_transaction_is_cleaned_up_unpersisted() {
+    let _serial = setup();
+    let id = 1004;
+    begin(id, &[("Content-Type", "text/html")]);
+    receive(id, &text(1024));
+    assert_eq!(stats()["active_transactions"].as_u64(), Some(1));
+
+    prism::cleanup(id);
+    assert_registry_empty();
+    assert!(memory::find(id).is_none());
+}
+
+#[test]
+fn headers_of_a_transaction_never_started_are_dropped() {
+    let _serial = setup();
+    let id = 1005;
+    header(id, "Content-Type", "text/html");
+    assert_eq!(stats()["pending_headers"].as_u64(), Some(1));
+
+    prism::cleanup(id);
+    assert_registry_empty();
+}
+
+#[test]
+fn non_utf8_headers_and_uri_are_accepted() {
+    let _serial = setup();
+    let id = 1006;
+    let name = CString::new(b"X-Latin1".to_vec()).unwrap();
+    let value = CString::new(b"caf\xe9".to_vec()).unwrap();
+    prism::header(id, name.as_ptr(), value.as_ptr());
+    let uri = CString::new(b"http://test.example.com/\xff".to_vec()).unwrap();
+    let method = CString::new("GET").unwrap();
+    prism::uri(id, uri.as_ptr(), 1, method.as_ptr());
+    assert_eq!(stats()["active_transactions"].as_u64(), Some(1));
+    let chunk = prism::get_response_header(id, value.as_ptr());
+    assert_eq!(common::bytes(chunk.size, chunk.bytes), b"");
+
+    prism::cleanup(id);
+    assert_registry_empty();
+}
+
+#[test]
+fn options_requests_are_acknowledged_without_a_transaction() {
+    let _serial = setup();
+    let id = 1007;
+    let before = stats();
+    header(id, "Allow", "OPTIONS, GET");
+    let uri = CString::new("icap://test.example.com/respmod").unwrap();
+    let method = CString::new("OPTIONS").unwrap();
+    prism::uri(id, uri.as_ptr(), 2, method.as_ptr());
+    let started = stats();
+    assert_eq!(started["active_transactions"].as_u64(), Some(0));
+    assert_eq!(started["pending_headers"].as_u64(), Some(0));
+    assert_eq!(
+        started["counters"]["transactions"]["started"],
+        before["counters"]["transactions"]["started"]
+    );
+    assert_eq!(
+        started["counters"]["options_requests"].as_u64(),
+        before["counters"]["options_requests"]
+            .as_u64()
+            .map(|count| count + 1)
+    );
+    assert_eq!(started["retained_bytes"], before["retained_bytes"]);
+
+    receive(id, b"");
+    prism::done(id);
+    assert_eq!(send(id), b"");
+    prism::cleanup(id);
+    assert_registry_empty();
+    assert_eq!(stats()["counters"]["errors"], before["counters"]["errors"]);
+    assert!(memory::wait(id, Duration::from_millis(200)).is_none());
+}
@@ -0,0 +1,151 @@
+#!/usr/bin/env python3
+"""Writes the MaxMind format databases tests/geoip.rs looks addresses up in,
+country.mmdb and asn.mmdb, next to this script. Only documentation ranges
+are listed, so private and loopback addresses are never found.
+
+Run again after changing the networks below.
+"""
+
+import os
+import struct
+
+# Kept fixed, so the databases are only changed by changing the networks.
+BUILD_EPOCH = 1760486400
+
+
+def control(kind, size):
+    """Control byte(s) of a field of `kind` holding `size` bytes or items."""
+    if size < 29:
+        head, extra = size, b""
+    elif size < 285:
+        head, extra = 29, bytes([size - 29])
+    else:
+        head, extra = 30, struct.pack(">H", size - 285)
+    if kind <= 7:
+        return bytes([(kind << 5) | head]) + extra
+    return bytes([head, kind - 7]) + extra
+
+
+def encode(value):
+    if isinstance(value, str):
+        data = value.encode()
+        return control(2, len(data)) + data
+    if isinstance(value, dict):
+        fields = b"".join(encode(key) + encode(item) for key, item in value.items())
+        return control(7, len(value)) + fields
+    if isinstance(value, list):
+        return control(11, len(value)) + b"".join(encode(item) for item in value)
+    if isinstance(value, tuple):
+        # (type, number) for unsigned integers of a given type.
+        kind, number = value
+        data = number.to_bytes((number.bit_length() + 7) // 8, "big")
+        return control(kind, len(data)) + data
+    raise TypeError(value)
+
+
+def uint16(number):
+    return (5, number)
+
+
+def uint32(number):
+    return (6, number)
+
+
+def uint64(number):
+    return (9, number)
+
+
+COUNTRIES = {
+    "203.0.113.0/24": {"country": {"iso_code": "NL", "names": {"en": "Netherlands"}}},
+    "2001:db8::/32": {"country": {"iso_code": "DE", "names": {"en": "Germany"}}},
+}
+
+ASNS = {
+    "203.0.113.0/24": {
+        "autonomous_system_number": uint32(64496),
+        "autonomous_system_organization": "Example IPv4 Networks",
+    },
+    "2001:db8::/32": {
+        "autonomous_system_number": uint32(64497),
+        "autonomous_system_organization": "Example IPv6 Networks",
+    },
+}
+
+
+def bits(network):
+    """The bits of `network` as an IPv6 address, IPv4 ones mapped to ::/96."""
+    address, prefix = network.split("/")
+    prefix = int(prefix)
+    if ":" in address:
+        number = int.from_bytes(ipv6(address), "big")
+    else:
+        number = int.from_bytes(bytes(int(part) for part in address.split(".")), "big")
+        prefix += 96
+    return [(number >> (127 - index)) & 1 for index in range(prefix)]
+
+
+def ipv6(address):
+    head, _, tail = address.partition("::")
+    head = [int(group, 16) for group in head.split(":") if group]
+    tail = [int(group, 16) for group in tail.split(":") if group]
+    groups = head + [0] * (8 - len(head) - len(tail)) + tail
+    return b"".join(struct.pack(">H", group) for group in groups)
+
+
+def database(kind, records):
+    # Nodes hold two records, each a node index, a data offset or None for
+    # addresses not found.
+    nodes = [[None, None]]
+    data = b""
+    for network, record in records.items():
+        offset = len(data)
+        data += encode(record)
+        node = 0
+        path = bits(network)
+        for bit in path[:-1]:
+            if nodes[node][bit] is None:
+                nodes.append([None, None])
+                nodes[node][bit] = ("node", len(nodes) - 1)
+            node = nodes[node][bit][1]
+        nodes[node][path[-1]] = ("data", offset)
+
+    count = len(nodes)
+
+    def value(record):
+        if record is None:
+            return count
+        kind, number = record
+        return number if kind == "node" else count + 16 + number
+
+    tree = b"".join(
+        value(left).to_bytes(3, "big") + value(right).to_bytes(3, "big")
+        for left, right in nodes
+    )
+    metadata = encode(
+        {
+            "binary_format_major_version": uint16(2),
+            "binary_format_minor_version": uint16(0),
+            "build_epoch": uint64(BUILD_EPOCH),
+            "database_type": kind,
+            "description": {"en": "prism test database"},
+            "ip_version": uint16(6),
+            "languages": ["en"],
+            "node_count": uint32(count),
+            "record_size": uint16(24),
+        }
+    )
+    return tree + bytes(16) + data + b"\xab\xcd\xefMaxMind.com" + metadata
+
+
+def main():
+    directory = os.path.dirname(os.path.abspath(__file__))
+    for name, kind, records in [
+        ("country.mmdb", "GeoLite2-Country", COUNTRIES),
+        ("asn.mmdb", "GeoLite2-ASN", ASNS),
+    ]:
+        with open(os.path.join(directory, name), "wb") as file:
+            file.write(database(kind, records))
+
+
+if __name__ == "__main__":
+    main()
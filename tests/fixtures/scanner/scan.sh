@@ -0,0 +1,37 @@
+#!/bin/sh
+# Scanner run by tests/scanner.rs, with the transaction id as its argument,
+# following the clamdscan convention: 0 when clean, 1 when flagged.
+
+body=$(cat)
+
+case "$1" in
+23101)
+    # Exits, leaving a process behind that holds stdout open.
+    sleep 5 &
+    echo "clean, still running"
+    exit 0
+    ;;
+23102)
+    # Hangs past the timeout.
+    exec sleep 5
+    ;;
+23103)
+    echo "scanner database missing"
+    exit 2
+    ;;
+23104)
+    # Writes far more than a pipe holds.
+    head -c 4194304 /dev/zero | tr '\0' 'x'
+    exit 0
+    ;;
+esac
+
+case "$body" in
+*PRISM-TEST-SIGNATURE*)
+    echo "stdin: Prism.Test.Signature FOUND"
+    exit 1
+    ;;
+esac
+
+echo "clean: ${#body} bytes"
+exit 0
@@ -0,0 +1,82 @@
+//! The bytes each transaction holds, by where they are held: in the dumped
+//! state, in the cleanup line, in the `retained_bytes` stats gauge and in
+//! the warning past `limits.footprint_warning`. Followed through a body
+//! relayed as received, whose chunks stay whole.
+
+mod common;
+
+use common::{setup_ffi, state, stats};
+use prism::{Prism, TransactionHandle};
+use serde_json::{json, Value};
+
+const WARNING: usize = 250;
+
+fn retained() -> u64 {
+    stats()["retained_bytes"].as_u64().unwrap()
+}
+
+fn footprint(prism: &Prism, id: i64) -> Value {
+    state(prism, id)["footprint"].clone()
+}
+
+fn expected(captured: usize, queued: usize, transfer_chunk: usize) -> Value {
+    json!({
+        "decoder_pending": 0,
+        "captured": captured,
+        "queued": queued,
+        "transfer_chunk": transfer_chunk,
+    })
+}
+
+fn begin(prism: &Prism, id: i64) -> TransactionHandle {
+    let uri = format!("http://footprint.example.com/{}", id);
+    let handle = prism.begin(id, "GET", &uri, &[("Content-Type", "text/plain")]);
+    handle.status(200);
+    handle
+}
+
+#[test]
+fn footprints_follow_the_body_through_the_transaction() {
+    let _serial = setup_ffi();
+    let mut config = common::config();
+    config.limits.coalesce_size = 0;
+    config.limits.footprint_warning = Some(WARNING);
+    let prism = Prism::new(config).unwrap();
+    common::capture_logs();
+    let id = 65001;
+    let before = retained();
+
+    let mut handle = begin(&prism, id);
+    handle.receive(&[b'a'; 100]).unwrap();
+    assert_eq!(footprint(&prism, id), expected(100, 100, 0));
+    handle.receive(&[b'b'; 50]).unwrap();
+    assert_eq!(footprint(&prism, id), expected(150, 150, 0));
+    assert_eq!(retained(), before + 300);
+
+    // Both chunks are taken into the transfer chunk at once.
+    let mut output = [0; 4096];
+    assert_eq!(handle.poll_output(&mut output), 150);
+    assert_eq!(footprint(&prism, id), expected(150, 0, 150));
+    handle.receive(&[b'c'; 30]).unwrap();
+    assert_eq!(footprint(&prism, id), expected(180, 30, 150));
+
+    let warnings = common::warnings();
+    assert_eq!(
+        warnings,
+        [format!(
+            "Transaction {} holds 300 bytes, above {}: 0 pending in the decoder, 150 captured, 150 queued, 0 in the transfer chunk",
+            id, WARNING
+        )]
+    );
+
+    drop(handle);
+    let cleanup = common::logged(|logged| logged.message.contains(" at cleanup: "));
+    assert_eq!(
+        cleanup,
+        [format!(
+            "Transaction {} held 360 bytes at cleanup: 0 pending in the decoder, 180 captured, 30 queued, 150 in the transfer chunk",
+            id
+        )]
+    );
+    assert_eq!(retained(), before);
+}
@@ -0,0 +1,80 @@
+//! GeoIP enrichment of client addresses, looked up in the databases of
+//! `tests/fixtures/geoip`, written by its `generate.py`. They only list
+//! 203.0.113.0/24 and 2001:db8::/32.
+
+mod common;
+
+use common::{configure, serial, MEMORY_BACKEND};
+use serde_json::Value;
+use std::ffi::CString;
+use std::sync::{MutexGuard, Once};
+
+static CONFIGURE: Once = Once::new();
+
+/// The databases are loaded once per process, from the configuration
+/// loaded through `configure()`.
+fn setup() -> MutexGuard<'static, ()> {
+    CONFIGURE.call_once(|| {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/geoip");
+        configure(&format!(
+            "{}\n[geoip]\ncountry_db = \"{}/country.mmdb\"\nasn_db = \"{}/asn.mmdb\"\n",
+            MEMORY_BACKEND, fixtures, fixtures
+        ));
+    });
+    serial()
+}
+
+/// Runs a transaction made for a client at `address`, and returns its
+/// document.
+fn run(id: i64, address: &str) -> Value {
+    common::begin(
+        id,
+        "http://geoip.example.com/",
+        &[("Content-Type", "text/plain")],
+    );
+    let address = CString::new(address).unwrap();
+    prism::client_address(id, address.as_ptr());
+    common::receive(id, b"hello");
+    common::finish(id);
+    prism::cleanup(id);
+    common::document(id)
+}
+
+#[test]
+fn ipv4_clients_are_located() {
+    let _serial = setup();
+    let document = run(31001, "203.0.113.7");
+    assert_eq!(document["client_ip"], "203.0.113.7");
+    assert_eq!(document["client_country"], "NL");
+    assert_eq!(document["client_asn"], 64496);
+    assert_eq!(document["client_as_org"], "Example IPv4 Networks");
+}
+
+#[test]
+fn ipv6_clients_are_located() {
+    let _serial = setup();
+    let document = run(31002, "2001:db8::1");
+    assert_eq!(document["client_country"], "DE");
+    assert_eq!(document["client_asn"], 64497);
+    assert_eq!(document["client_as_org"], "Example IPv6 Networks");
+}
+
+#[test]
+fn private_addresses_are_left_unlocated() {
+    let _serial = setup();
+    for (id, address) in (31101..).zip(["10.1.2.3", "192.168.1.1", "::1", "fd00::1"]) {
+        let document = run(id, address);
+        assert_eq!(document["client_ip"], address);
+        for field in ["client_country", "client_asn", "client_as_org"] {
+            assert!(document.get(field).is_none(), "{} of {}", field, address);
+        }
+    }
+}
+
+#[test]
+fn addresses_that_do_not_parse_are_left_unlocated() {
+    let _serial = setup();
+    let document = run(31201, "not an address");
+    assert_eq!(document["client_ip"], "not an address");
+    assert!(document.get("client_country").is_none());
+}
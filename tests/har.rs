@@ -0,0 +1,243 @@
+//! HAR files written by the `har` backend. Which file is being written is
+//! shared by the whole process, so every test fills the files it starts.
+
+mod common;
+
+use base64::{engine::general_purpose, Engine};
+use prism::encryption::{self, Key};
+use prism::Prism;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant};
+
+const ENTRIES_PER_FILE: usize = 3;
+
+fn directory(name: &str) -> PathBuf {
+    let directory = common::temporary(name);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+fn setup(directory: &Path, key: Option<String>) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.backend.kind = "har".to_string();
+        config.backend.har.directory = Some(directory.to_path_buf());
+        config.backend.har.entries_per_file = Some(ENTRIES_PER_FILE);
+        config.encryption.key = key;
+    })
+}
+
+fn run(prism: &Prism, id: i64, body: &[u8]) {
+    relay(prism, id, "text/plain", body);
+}
+
+fn relay(prism: &Prism, id: i64, content_type: &str, body: &[u8]) {
+    common::relay(
+        prism,
+        id,
+        &format!("http://har.example.com/{}", id),
+        &[("Content-Type", content_type)],
+        body,
+    );
+}
+
+/// Checks `value` is an array of `name`/`value` pairs, as HAR headers,
+/// cookies and query strings are.
+fn assert_pairs(value: &Value) {
+    for pair in value.as_array().unwrap() {
+        assert!(pair["name"].is_string(), "{}", pair);
+        assert!(pair["value"].is_string(), "{}", pair);
+    }
+}
+
+/// Checks `entry` holds the fields HAR 1.2 requires, with their types.
+fn assert_entry(entry: &Value) {
+    let started = entry["startedDateTime"].as_str().unwrap();
+    assert!(
+        chrono::DateTime::parse_from_rfc3339(started).is_ok(),
+        "{}",
+        started
+    );
+    assert!(entry["time"].as_f64().unwrap() >= 0.0);
+
+    let request = &entry["request"];
+    assert_eq!(request["method"], "GET");
+    assert!(request["url"]
+        .as_str()
+        .unwrap()
+        .starts_with("http://har.example.com/"));
+    assert!(request["httpVersion"].is_string());
+    assert_pairs(&request["cookies"]);
+    assert_pairs(&request["headers"]);
+    assert_pairs(&request["queryString"]);
+    assert!(request["headersSize"].as_i64().is_some());
+    assert!(request["bodySize"].as_i64().is_some());
+
+    let response = &entry["response"];
+    assert_eq!(response["status"], 200);
+    assert!(response["statusText"].is_string());
+    assert!(response["httpVersion"].is_string());
+    assert_pairs(&response["cookies"]);
+    assert_pairs(&response["headers"]);
+    assert!(response["redirectURL"].is_string());
+    assert!(response["headersSize"].as_i64().is_some());
+    assert!(response["bodySize"].as_i64().is_some());
+    let content = &response["content"];
+    assert!(content["size"].is_u64());
+    assert!(content["mimeType"].is_string());
+    assert!(content["text"].is_string());
+
+    assert!(entry["cache"].is_object());
+    for timing in ["send", "wait", "receive"] {
+        assert!(
+            entry["timings"][timing].as_f64().unwrap() >= 0.0,
+            "{}",
+            timing
+        );
+    }
+}
+
+/// The HAR files in `directory`, in name order.
+fn har_files(directory: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(directory)
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "har"))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Waits for the only HAR file in `directory` to hold `entries` entries,
+/// read with `read`, and returns it.
+fn wait_entries(
+    directory: &Path,
+    entries: usize,
+    read: impl Fn(&Path) -> Option<Vec<u8>>,
+) -> Value {
+    let deadline = Instant::now() + common::TIMEOUT;
+    loop {
+        let files = har_files(directory);
+        assert!(files.len() <= 1, "{:?}", files);
+        let har = files
+            .first()
+            .and_then(|path| read(path))
+            .and_then(|json| serde_json::from_slice::<Value>(&json).ok());
+        if let Some(har) = har {
+            if har["log"]["entries"].as_array().map(Vec::len) == Some(entries) {
+                return har;
+            }
+        }
+        assert!(Instant::now() < deadline, "{} entries not written", entries);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn entries_are_appended_to_complete_files() {
+    let directory = directory("plain");
+    let (prism, _serial) = setup(&directory, None);
+    for (entries, id) in (1..=ENTRIES_PER_FILE).zip(26001..) {
+        run(&prism, id, b"hello");
+        let har = wait_entries(&directory, entries, |path| std::fs::read(path).ok());
+        let urls: Vec<&str> = har["log"]["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["request"]["url"].as_str().unwrap())
+            .collect();
+        let expected: Vec<String> = (26001..=id)
+            .map(|id| format!("http://har.example.com/{}", id))
+            .collect();
+        assert_eq!(urls, expected);
+        assert_eq!(har["log"]["version"], "1.2");
+    }
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn entries_follow_the_har_structure() {
+    let directory = directory("structure");
+    let (prism, _serial) = setup(&directory, None);
+    let binary: Vec<u8> = (0..=255).collect();
+    run(&prism, 26201, b"hello");
+    relay(&prism, 26202, "application/octet-stream", &binary);
+    run(&prism, 26203, b"world");
+    let har = wait_entries(&directory, 3, |path| std::fs::read(path).ok());
+
+    let log = &har["log"];
+    assert_eq!(log["version"], "1.2");
+    assert_eq!(log["creator"]["name"], "prism");
+    assert!(log["creator"]["version"].is_string());
+    let entries = log["entries"].as_array().unwrap();
+    for entry in entries {
+        assert_entry(entry);
+    }
+    // Persisted in any order.
+    let content = |id: i64| {
+        let url = format!("http://har.example.com/{}", id);
+        let entry = entries
+            .iter()
+            .find(|entry| entry["request"]["url"] == url.as_str());
+        &entry.unwrap()["response"]["content"]
+    };
+
+    let text = content(26201);
+    assert_eq!(text["text"], "hello");
+    assert_eq!(text["size"], 5);
+    assert_eq!(text["mimeType"], "text/plain");
+    assert!(text.get("encoding").is_none());
+
+    // Bodies that aren't text are base64 encoded, as the spec has it.
+    let encoded = content(26202);
+    assert_eq!(encoded["encoding"], "base64");
+    assert_eq!(encoded["size"], 256);
+    assert_eq!(encoded["mimeType"], "application/octet-stream");
+    let text = encoded["text"].as_str().unwrap();
+    assert_eq!(general_purpose::STANDARD.decode(text).unwrap(), binary);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn files_roll_over_by_entry_count() {
+    let directory = directory("rolled");
+    let (prism, _serial) = setup(&directory, None);
+    for id in 26301..26301 + 2 * ENTRIES_PER_FILE as i64 {
+        run(&prism, id, b"rolled");
+    }
+    let deadline = Instant::now() + common::TIMEOUT;
+    let counts = loop {
+        let counts: Vec<Option<usize>> = har_files(&directory)
+            .iter()
+            .map(|path| {
+                let har: Value = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+                har["log"]["entries"].as_array().map(Vec::len)
+            })
+            .collect();
+        if counts == [Some(ENTRIES_PER_FILE); 2] || Instant::now() >= deadline {
+            break counts;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(counts, [Some(ENTRIES_PER_FILE); 2]);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn entries_appended_to_sealed_files_open_whole() {
+    let directory = directory("sealed");
+    let encoded = general_purpose::STANDARD.encode([7; encryption::KEY_SIZE]);
+    let (prism, _serial) = setup(&directory, Some(encoded.clone()));
+    let open = |path: &Path| {
+        let sealed = std::fs::read(path).ok()?;
+        assert!(!String::from_utf8_lossy(&sealed).contains("har.example.com"));
+        let key = Key::parse(&encoded).unwrap();
+        encryption::open(&sealed[..], &[key], usize::MAX).ok()
+    };
+    for (entries, id) in (1..=ENTRIES_PER_FILE).zip(26101..) {
+        run(&prism, id, b"sealed");
+        wait_entries(&directory, entries, open);
+    }
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,14 @@
+//! Checks the committed C header against the one build.rs generates from
+//! the exported functions.
+
+#[test]
+fn committed_header_matches_the_exports() {
+    let generated = std::fs::read_to_string(concat!(env!("OUT_DIR"), "/prism.h"))
+        .expect("build.rs generates the header");
+    let committed =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/include/prism.h")).unwrap();
+    assert!(
+        generated == committed,
+        "include/prism.h does not match the exported functions, regenerate it with `make header`"
+    );
+}
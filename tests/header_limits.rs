@@ -0,0 +1,141 @@
+//! Limits on the headers a client can make prism hold: values truncated,
+//! headers dropped past the bytes of a transaction or the transactions not
+//! begun yet, and headers of transactions that never begin expired.
+//! Driven through the exported functions, as the adapter calls them.
+
+mod common;
+
+use common::stats;
+use prism::clock::{self, ManualClock};
+use std::ffi::CString;
+use std::sync::{Arc, MutexGuard, Once, OnceLock};
+use std::time::Duration;
+
+static CONFIGURE: Once = Once::new();
+static CLOCK: OnceLock<Arc<ManualClock>> = OnceLock::new();
+
+const MAX_HEADER_VALUE: usize = 64;
+const MAX_HEADER_BYTES: u64 = 256;
+const MAX_PENDING_HEADERS: u64 = 4;
+const PENDING_HEADERS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Installs the test configuration and a manual clock, before anything
+/// else reads them.
+fn setup() -> MutexGuard<'static, ()> {
+    CONFIGURE.call_once(|| {
+        common::configure(&format!(
+            "{}\n[limits]\nmax_header_value = {}\nmax_header_bytes = {}\nmax_pending_headers = {}\npending_headers_timeout = {}\n",
+            common::MEMORY_BACKEND,
+            MAX_HEADER_VALUE,
+            MAX_HEADER_BYTES,
+            MAX_PENDING_HEADERS,
+            PENDING_HEADERS_TIMEOUT.as_secs()
+        ));
+        let manual = ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap());
+        clock::set(manual.clone());
+        CLOCK.set(manual).ok().unwrap();
+    });
+    common::serial()
+}
+
+fn counter(stats: &serde_json::Value, name: &str) -> u64 {
+    stats["counters"][name].as_u64().unwrap()
+}
+
+fn header(id: i64, name: &str, value: &str) {
+    common::header(prism::header, id, name, value);
+}
+
+/// The state of the library, as `dump_state()` writes it.
+fn dump() -> serde_json::Value {
+    let path = common::temporary("dump.json");
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(prism::dump_state(c_path.as_ptr()), 0);
+    let dump = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    serde_json::from_slice(&dump).unwrap()
+}
+
+#[test]
+fn oversized_values_are_truncated_and_excess_headers_dropped() {
+    let _serial = setup();
+    let id = 17001;
+    let before = stats();
+
+    // 5 + 64 bytes once truncated, then 50 bytes each: the fourth goes
+    // past the limit, as does every one after it.
+    header(id, "X-Big", &"a".repeat(1024 * 1024));
+    for n in 1..=7 {
+        header(id, &format!("X-Header-{}", n), &"b".repeat(40));
+    }
+    // Always kept.
+    prism::status(id, 200);
+    let uri = CString::new("http://limits.example.com/").unwrap();
+    let method = CString::new("GET").unwrap();
+    prism::uri(id, uri.as_ptr(), 1, method.as_ptr());
+    header(id, "X-Late", &"c".repeat(40));
+
+    let after = stats();
+    assert_eq!(
+        counter(&after, "header_values_truncated"),
+        counter(&before, "header_values_truncated") + 1
+    );
+    assert_eq!(
+        counter(&after, "headers_dropped"),
+        counter(&before, "headers_dropped") + 5
+    );
+    let dump = dump();
+    let transactions = dump["transactions"].as_array().unwrap();
+    let transaction = transactions
+        .iter()
+        .find(|transaction| transaction["id"] == id)
+        .unwrap();
+    // The status, `:status` and `200`, on top of the four headers kept.
+    assert_eq!(transaction["header_bytes"], 69 + 3 * 50 + 10);
+    assert_eq!(transaction["status"], 200);
+
+    prism::cleanup(id);
+    assert_eq!(stats()["active_transactions"].as_u64(), Some(0));
+}
+
+#[test]
+fn orphaned_headers_are_bounded_and_expire() {
+    let _serial = setup();
+    let before = stats();
+    assert_eq!(before["pending_headers"].as_u64(), Some(0));
+
+    // Transactions that never begin, sending large values.
+    let orphans = 17101..17111;
+    for id in orphans.clone() {
+        header(id, "X-Big", &"a".repeat(1024 * 1024));
+    }
+    let held = stats();
+    assert_eq!(held["pending_headers"].as_u64(), Some(MAX_PENDING_HEADERS));
+    assert!(
+        held["pending_header_bytes"].as_u64().unwrap() <= MAX_PENDING_HEADERS * MAX_HEADER_BYTES,
+        "{}",
+        held["pending_header_bytes"]
+    );
+    assert_eq!(
+        counter(&held, "pending_headers_dropped"),
+        counter(&before, "pending_headers_dropped") + 6
+    );
+
+    // Expired once their timeout passed, making room for another.
+    CLOCK
+        .get()
+        .unwrap()
+        .advance(PENDING_HEADERS_TIMEOUT + Duration::from_secs(1));
+    header(17111, "X-Small", "value");
+    let expired = stats();
+    assert_eq!(expired["pending_headers"].as_u64(), Some(1));
+    assert_eq!(
+        counter(&expired, "pending_headers_expired"),
+        counter(&before, "pending_headers_expired") + MAX_PENDING_HEADERS
+    );
+
+    for id in orphans.chain([17111]) {
+        prism::cleanup(id);
+    }
+    assert_eq!(stats()["pending_headers"].as_u64(), Some(0));
+}
@@ -0,0 +1,81 @@
+//! The heartbeat line logged from `init()`: the build, uptime, backend and
+//! a fingerprint of the configuration, which leaves secrets out.
+
+mod common;
+
+use common::{configure, dump};
+use prism::Prism;
+use std::collections::HashMap;
+
+const PASSWORD: &str = "heartbeat-password-never-logged";
+const API_KEY: &str = "heartbeat-api-key-never-logged";
+const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+/// Fingerprint of the configuration `toml` is loaded into.
+fn fingerprint(toml: &str) -> String {
+    let path = common::temporary("fingerprint.toml");
+    std::fs::write(&path, toml).unwrap();
+    let config = prism::config::Config::load(Some(&path)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let prism = Prism::new(config).unwrap();
+    dump(&prism)["config"].as_str().unwrap().to_string()
+}
+
+fn secrets(password: &str, api_key: &str, key: &str) -> String {
+    format!(
+        "[backend]\ntype = \"memory\"\n\n[backend.elasticsearch]\nusername = \"prism\"\npassword = \"{}\"\napi_key = \"{}\"\n\n[encryption]\nkey = \"{}\"\n",
+        password, api_key, key
+    )
+}
+
+#[test]
+fn heartbeats_name_the_build_and_configuration_without_secrets() {
+    let _serial = common::serial();
+    common::capture_logs();
+    configure(&secrets(PASSWORD, API_KEY, KEY));
+    prism::init();
+
+    let heartbeats = common::logged(|logged| logged.message.starts_with("Heartbeat "));
+    assert_eq!(heartbeats.len(), 1, "{:?}", heartbeats);
+    let fields: HashMap<&str, &str> = heartbeats[0]
+        .split(' ')
+        .skip(1)
+        .filter_map(|pair| pair.split_once('='))
+        .collect();
+    let mut keys: Vec<&str> = fields.keys().copied().collect();
+    keys.sort();
+    assert_eq!(
+        keys,
+        ["backend", "config", "git", "schema", "uptime", "version"]
+    );
+    assert_eq!(fields["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(fields["uptime"], "0s");
+    assert_eq!(fields["backend"], "memory");
+    let config = fields["config"];
+    assert_eq!(config.len(), 12);
+    assert!(config.chars().all(|c| c.is_ascii_hexdigit()));
+
+    let prism = Prism::new((*prism::config::get()).clone()).unwrap();
+    let dumped = dump(&prism);
+    assert_eq!(dumped["git"], fields["git"]);
+    assert_eq!(dumped["config"], fields["config"]);
+
+    for logged in common::logged(|_| true) {
+        for secret in [PASSWORD, API_KEY, KEY] {
+            assert!(!logged.contains(secret), "{}", logged);
+        }
+    }
+}
+
+#[test]
+fn fingerprints_only_change_with_settings_other_than_secrets() {
+    let _serial = common::serial();
+    let configured = fingerprint(&secrets(PASSWORD, API_KEY, KEY));
+    let other_key = "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=";
+    assert_eq!(
+        fingerprint(&secrets("another password", "another key", other_key)),
+        configured
+    );
+    let other_username = secrets(PASSWORD, API_KEY, KEY).replace("\"prism\"", "\"other\"");
+    assert_ne!(fingerprint(&other_username), configured);
+}
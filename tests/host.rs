@@ -0,0 +1,51 @@
+//! Hosts taken from the uri, or from the `Host` header for origin-form uris.
+
+mod common;
+
+use common::setup;
+use prism::Prism;
+use serde_json::Value;
+
+/// Runs a transaction for `uri` through and returns its document.
+fn run(prism: &Prism, id: i64, uri: &str, headers: &[(&str, &str)]) -> Value {
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Type", "text/plain"));
+    common::run(prism, id, uri, &headers, b"hello")
+}
+
+#[test]
+fn origin_form_uris_take_the_host_header() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        6101,
+        "/path?x=1",
+        &[("host", " Origin.Example.COM:8080 ")],
+    );
+    assert_eq!(document["host"], "origin.example.com");
+
+    let document = run(&prism, 6102, "/path", &[("Host", "[::1]:8080")]);
+    assert_eq!(document["host"], "[::1]");
+}
+
+#[test]
+fn absolute_uris_win_over_the_host_header() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        6103,
+        "http://Absolute.example.com:81/path",
+        &[("Host", "header.example.com")],
+    );
+    assert_eq!(document["host"], "absolute.example.com");
+}
+
+#[test]
+fn hosts_are_absent_without_either() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(&prism, 6104, "/path", &[]);
+    assert!(document.get("host").is_none());
+
+    let document = run(&prism, 6105, "/path", &[("Host", "")]);
+    assert!(document.get("host").is_none());
+}
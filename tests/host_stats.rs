@@ -0,0 +1,96 @@
+//! Per-host aggregates of finished transactions, as reported under `hosts`
+//! by `stats()`. They are kept for the whole process and their bound is
+//! read once, so every test runs with the same `limits.stats_max_hosts`
+//! and looks at hosts of its own.
+
+mod common;
+
+use common::{drain, setup_ffi, stats};
+use prism::clock::{self, ManualClock};
+use prism::Prism;
+use serde_json::{json, Value};
+use std::sync::MutexGuard;
+use std::time::Duration;
+
+const MAX_HOSTS: usize = 3;
+
+fn setup() -> (Prism, MutexGuard<'static, ()>) {
+    let serial = setup_ffi();
+    let mut config = common::config();
+    config.limits.stats_max_hosts = MAX_HOSTS;
+    (Prism::new(config).unwrap(), serial)
+}
+
+fn uri(host: &str) -> String {
+    format!("http://{}/stats", host)
+}
+
+fn hosts() -> Value {
+    stats()["hosts"].clone()
+}
+
+#[test]
+fn transactions_are_accounted_to_their_host() {
+    let (prism, _serial) = setup();
+    let manual = ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap());
+    clock::set(manual.clone());
+    let headers = [("Content-Type", "text/plain")];
+
+    for (id, body, seconds) in [(56001, &b"hello"[..], 1), (56002, b"hello world", 3)] {
+        let mut handle = prism.begin(id, "GET", &uri("a.stats.example.com"), &headers);
+        handle.status(200);
+        handle.receive(body).unwrap();
+        manual.advance(Duration::from_secs(seconds));
+        handle.done();
+        drain(&mut handle);
+    }
+    // Never done, so an error.
+    let aborted = prism.begin(56003, "GET", &uri("b.stats.example.com"), &headers);
+    aborted.status(200);
+    aborted.receive(b"half").unwrap();
+    manual.advance(Duration::from_millis(500));
+    drop(aborted);
+    clock::reset();
+
+    let hosts = hosts();
+    assert_eq!(
+        hosts["a.stats.example.com"],
+        json!({
+            "requests": 2,
+            "bytes_in": 16,
+            "bytes_out": 16,
+            "errors": 0,
+            "average_duration_ms": 2000.0,
+        })
+    );
+    let aborted = &hosts["b.stats.example.com"];
+    assert_eq!(aborted["requests"], 1);
+    assert_eq!(aborted["bytes_in"], 4);
+    assert_eq!(aborted["errors"], 1);
+    assert_eq!(aborted["average_duration_ms"], 500.0);
+}
+
+#[test]
+fn the_least_recently_seen_host_gives_way() {
+    let (prism, _serial) = setup();
+    let run = |id: i64, host: &str| {
+        common::run(&prism, id, &uri(host), &[], b"lru");
+    };
+    run(56101, "one.lru.example.com");
+    run(56102, "two.lru.example.com");
+    run(56103, "three.lru.example.com");
+    run(56104, "one.lru.example.com");
+    run(56105, "four.lru.example.com");
+
+    let hosts = hosts();
+    let tracked: Vec<&String> = hosts.as_object().unwrap().keys().collect();
+    assert_eq!(
+        tracked,
+        [
+            "four.lru.example.com",
+            "one.lru.example.com",
+            "three.lru.example.com"
+        ]
+    );
+    assert_eq!(hosts["one.lru.example.com"]["requests"], 2);
+}
@@ -0,0 +1,155 @@
+//! Lazy decoding, see `limits.decode_mode`: encoded bodies relayed as
+//! received, and their captured copy decoded by the persistence worker into
+//! the same document streaming decoding gives.
+
+mod common;
+
+use common::{counter, gzip, TIMEOUT};
+use prism::{memory, Prism};
+use serde_json::Value;
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant};
+
+const HEADERS: [(&str, &str); 2] = [
+    ("Content-Type", "text/html; charset=utf-8"),
+    ("Content-Encoding", "gzip"),
+];
+
+fn setup(mode: &str) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| config.limits.decode_mode = mode.to_string())
+}
+
+fn instance(mode: &str) -> Prism {
+    let mut config = common::config();
+    config.limits.decode_mode = mode.to_string();
+    Prism::new(config).unwrap()
+}
+
+fn page(rows: usize) -> Vec<u8> {
+    (0..rows)
+        .map(|row| format!("<tr><td>{}</td><td>lazy decode row</td></tr>\n", row))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Relays `body` through a transaction, returning the output and the time
+/// spent in `receive()` and `poll_output()`.
+fn relay(prism: &Prism, id: i64, body: &[u8]) -> (Vec<u8>, Duration) {
+    let mut handle = prism.begin(id, "GET", "http://lazy.example.com/page", &HEADERS);
+    handle.status(200);
+    let mut output = Vec::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let started = Instant::now();
+    for chunk in body.chunks(16 * 1024) {
+        handle.receive(chunk).unwrap();
+        loop {
+            let size = handle.poll_output(&mut buffer);
+            if size == 0 {
+                break;
+            }
+            output.extend_from_slice(&buffer[..size]);
+        }
+    }
+    handle.done();
+    while !handle.finished() {
+        let size = handle.poll_output(&mut buffer);
+        output.extend_from_slice(&buffer[..size]);
+    }
+    let elapsed = started.elapsed();
+    drop(handle);
+    (output, elapsed)
+}
+
+/// The fields of a document that depend on neither the transaction id nor
+/// on how the output was relayed.
+fn comparable(mut document: Value) -> Value {
+    let object = document.as_object_mut().unwrap();
+    for field in [
+        "document_id",
+        "date",
+        "emitted_length",
+        "output_compression_ratio",
+        "output_bytes_saved",
+    ] {
+        object.remove(field);
+    }
+    document
+}
+
+#[test]
+fn lazy_documents_match_streaming_ones() {
+    let body = page(2000);
+    let encoded = gzip(&body);
+
+    let (prism, serial) = setup("streaming");
+    let (output, _) = relay(&prism, 20001, &encoded);
+    let streaming = memory::wait(20001, TIMEOUT).expect("document persisted");
+    drop(prism);
+    // Decoded, then encoded again for the client.
+    assert_ne!(output, encoded);
+
+    let prism = instance("lazy");
+    let (output, _) = relay(&prism, 20002, &encoded);
+    let lazy = memory::wait(20002, TIMEOUT).expect("document persisted");
+    // Relayed untouched.
+    assert_eq!(output, encoded);
+    drop(prism);
+    drop(serial);
+
+    assert_eq!(lazy.body, body);
+    assert_eq!(lazy.body, streaming.body);
+    assert_eq!(lazy.encoding, "gzip");
+    assert_eq!(lazy.emitted_length, encoded.len());
+    assert!(lazy.json["body_preview"]
+        .as_str()
+        .unwrap()
+        .starts_with("<tr><td>0</td>"));
+    assert_eq!(
+        lazy.json["output_compression_ratio"].as_f64().unwrap(),
+        body.len() as f64 / encoded.len() as f64
+    );
+    assert_eq!(comparable(lazy.json), comparable(streaming.json));
+}
+
+#[test]
+fn lazy_decoding_takes_work_off_the_request_path() {
+    let body = page(200_000);
+    let encoded = gzip(&body);
+
+    let (prism, serial) = setup("streaming");
+    let (_, streaming) = relay(&prism, 20011, &encoded);
+    memory::wait(20011, TIMEOUT).expect("document persisted");
+    drop(prism);
+
+    let prism = instance("lazy");
+    let (_, lazy) = relay(&prism, 20012, &encoded);
+    let document = memory::wait(20012, TIMEOUT).expect("document persisted");
+    drop(prism);
+    drop(serial);
+
+    assert_eq!(document.body, body);
+    assert!(
+        lazy < streaming,
+        "lazy {:?}, streaming {:?}",
+        lazy,
+        streaming
+    );
+}
+
+#[test]
+fn bodies_failing_to_decode_keep_what_was_decoded() {
+    let (prism, _serial) = setup("lazy");
+    let id = 20021;
+    let before = counter(&prism, "decode_errors");
+    let body = page(100);
+    // The trailer's checksum no longer matches the body.
+    let mut encoded = gzip(&body);
+    let checksum = encoded.len() - 8;
+    encoded[checksum] ^= 0xff;
+
+    let (output, _) = relay(&prism, id, &encoded);
+    assert_eq!(output, encoded);
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.body, body);
+    assert_eq!(counter(&prism, "decode_errors"), before + 1);
+}
@@ -0,0 +1,110 @@
+//! The Rust API: instances owning their transactions apart from each other
+//! and from the one behind the exported functions, and the documents they
+//! persist identical to those of the exported functions. The clock and run
+//! id are fixed, so documents only differ by their id.
+
+mod common;
+
+use common::{dump, gzip, setup_ffi, stats, take, TIMEOUT};
+use prism::clock::{self, ManualClock};
+use prism::{memory, Prism};
+use serde_json::Value;
+
+const PLAIN: [(&str, &str); 1] = [("Content-Type", "text/html")];
+const GZIP: [(&str, &str); 2] = [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+const URI: &str = "http://library.example.com/page";
+
+fn page() -> Vec<u8> {
+    (0..2000)
+        .map(|row| format!("<li>library row {}</li>\n", row))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// The document of transaction `id`, without the id it is stored under,
+/// nor the fields measuring the encoded output, which depends on where the
+/// encoder flushed.
+fn document(id: i64) -> Value {
+    let mut document = common::document(id);
+    let fields = document.as_object_mut().unwrap();
+    for field in [
+        "document_id",
+        "emitted_length",
+        "output_compression_ratio",
+        "output_bytes_saved",
+    ] {
+        fields.remove(field);
+    }
+    document
+}
+
+fn through_ffi(id: i64, headers: &[(&str, &str)], body: &[u8]) -> Value {
+    common::begin(id, URI, headers);
+    common::receive(id, body);
+    common::send(id);
+    common::finish(id);
+    prism::cleanup(id);
+    document(id)
+}
+
+fn through_handle(prism: &Prism, id: i64, headers: &[(&str, &str)], body: &[u8]) -> Value {
+    common::relay(prism, id, URI, headers, body);
+    document(id)
+}
+
+#[test]
+fn handles_persist_the_documents_of_the_exported_functions() {
+    let _serial = setup_ffi();
+    let prism = Prism::new(common::config()).unwrap();
+    clock::set(ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap()));
+    clock::set_run_id(412);
+    let page = page();
+
+    let plain = through_ffi(40101, &PLAIN, &page);
+    assert_eq!(plain["body"], String::from_utf8(page.clone()).unwrap());
+    assert_eq!(through_handle(&prism, 40102, &PLAIN, &page), plain);
+    let encoded = gzip(&page);
+    let decoded = through_ffi(40103, &GZIP, &encoded);
+    assert_eq!(decoded["encoding"], "gzip");
+    assert_eq!(through_handle(&prism, 40104, &GZIP, &encoded), decoded);
+    clock::reset();
+}
+
+#[test]
+fn instances_keep_their_own_transactions() {
+    let _serial = setup_ffi();
+    let first = Prism::new(common::config()).unwrap();
+    let second = Prism::new(common::config()).unwrap();
+
+    // The same id, in each instance.
+    let mut one = first.begin(40201, "GET", URI, &PLAIN);
+    let mut other = second.begin(40201, "GET", URI, &PLAIN);
+    one.status(200);
+    other.status(404);
+    one.receive(b"first").unwrap();
+    other.receive(b"second instance").unwrap();
+    assert_eq!(take(&mut one), b"first");
+    assert_eq!(take(&mut other), b"second instance");
+
+    let states = |prism: &Prism| dump(prism)["transactions"].as_array().unwrap().clone();
+    let [ref state] = states(&first)[..] else {
+        panic!("one transaction expected");
+    };
+    assert_eq!(state["status"], 200);
+    assert_eq!(state["bytes_received"], 5);
+    let [ref state] = states(&second)[..] else {
+        panic!("one transaction expected");
+    };
+    assert_eq!(state["status"], 404);
+    assert_eq!(state["bytes_received"], 15);
+    assert_eq!(stats()["active_transactions"], 0);
+
+    // Aborted, neither is persisted.
+    drop(one);
+    drop(other);
+    assert!(states(&first).is_empty());
+    assert!(states(&second).is_empty());
+    common::relay(&first, 40202, URI, &PLAIN, b"hello");
+    memory::wait(40202, TIMEOUT).expect("document persisted");
+    assert!(memory::find(40201).is_none());
+}
@@ -0,0 +1,161 @@
+//! The `limits` section: each limit enforced where it is documented, the
+//! limits checked against each other, and reloaded limits applying to the
+//! transactions started afterwards.
+
+mod common;
+
+use common::{counter, document, drain, dump, state, take, TIMEOUT};
+use prism::config::{self, Limits};
+use prism::{memory, Prism};
+use std::sync::MutexGuard;
+
+const HEADERS: [(&str, &str); 1] = [("Content-Type", "text/plain")];
+
+fn setup(configure: impl FnOnce(&mut Limits)) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.limits.coalesce_size = 0;
+        configure(&mut config.limits);
+    })
+}
+
+fn uri(id: i64) -> String {
+    format!("http://limits.example.com/{}", id)
+}
+
+#[test]
+fn transactions_past_the_limit_are_relayed_without_capture() {
+    let (prism, _serial) = setup(|limits| limits.max_transactions = Some(2));
+    let shed = counter(&prism, "shed_transactions");
+    let first = prism.begin(39101, "GET", &uri(39101), &HEADERS);
+    let second = prism.begin(39102, "GET", &uri(39102), &HEADERS);
+    let mut third = prism.begin(39103, "GET", &uri(39103), &HEADERS);
+    assert_eq!(state(&prism, 39102)["shed"], false);
+    assert_eq!(state(&prism, 39103)["shed"], true);
+    assert_eq!(counter(&prism, "shed_transactions"), shed + 1);
+
+    third.status(200);
+    third.receive(b"relayed all the same").unwrap();
+    third.done();
+    assert_eq!(drain(&mut third), b"relayed all the same");
+    for mut handle in [first, second] {
+        handle.status(200);
+        handle.receive(b"hello").unwrap();
+        handle.done();
+        drain(&mut handle);
+    }
+    drop(third);
+
+    // Back under the limit once the others are cleaned up.
+    common::run(&prism, 39104, &uri(39104), &HEADERS, b"hello");
+    assert!(memory::find(39101).is_some());
+    assert!(memory::find(39103).is_none());
+}
+
+#[test]
+fn bodies_are_captured_up_to_the_maximum_size() {
+    let (prism, _serial) = setup(|limits| limits.max_body_size = Some(10));
+    let body = b"twenty bytes of body";
+    let output = common::relay(&prism, 39201, &uri(39201), &HEADERS, body);
+    assert_eq!(output, body);
+    let document = document(39201);
+    assert_eq!(document["body"], "twenty byt");
+    assert_eq!(document["truncated"], true);
+
+    let fits = common::run(&prism, 39202, &uri(39202), &HEADERS, b"ten bytes!");
+    assert_eq!(fits["body"], "ten bytes!");
+    assert_eq!(fits["truncated"], false);
+}
+
+#[test]
+fn chunks_past_the_channel_capacity_are_dropped() {
+    let (prism, _serial) = setup(|limits| limits.channel_capacity = Some(1));
+    let dropped = counter(&prism, "dropped_chunks");
+    let mut handle = prism.begin(39301, "GET", &uri(39301), &HEADERS);
+    handle.status(200);
+    handle.receive(b"first").unwrap();
+    handle.receive(b"second").unwrap();
+    assert_eq!(take(&mut handle), b"first");
+    assert_eq!(counter(&prism, "dropped_chunks"), dropped + 1);
+
+    // Room again once handed back.
+    handle.receive(b"third").unwrap();
+    handle.done();
+    assert_eq!(drain(&mut handle), b"third");
+}
+
+#[test]
+fn documents_past_the_queued_documents_are_dropped() {
+    let (prism, _serial) = setup(|limits| {
+        // Only one document a second leaves the queue.
+        limits.persist_rate = Some(1.0);
+        limits.max_queued_documents = 2;
+    });
+    for id in 39401..=39405 {
+        common::relay(&prism, id, &uri(id), &HEADERS, b"hello");
+    }
+    memory::wait(39401, TIMEOUT).expect("document persisted");
+    let queue = &dump(&prism)["queue"];
+    assert_eq!(queue["documents"], 2);
+    assert_eq!(queue["dropped"], 2);
+}
+
+#[test]
+fn documents_past_the_queue_memory_budget_are_dropped() {
+    let (prism, _serial) = setup(|limits| {
+        limits.persist_rate = Some(1.0);
+        limits.queue_memory_budget = 1;
+    });
+    for id in 39411..=39414 {
+        common::relay(&prism, id, &uri(id), &HEADERS, b"hello");
+    }
+    memory::wait(39411, TIMEOUT).expect("document persisted");
+    // An empty queue takes any document, and nothing more past the budget.
+    let queue = &dump(&prism)["queue"];
+    assert_eq!(queue["documents"], 1);
+    assert_eq!(queue["dropped"], 2);
+}
+
+#[test]
+fn queue_budget_must_exceed_the_largest_document() {
+    let _serial = common::serial();
+    let mut config = common::config();
+    config.limits.max_body_size = Some(300);
+    config.limits.queue_memory_budget = 300;
+    let errors: Vec<String> = config
+        .validated()
+        .unwrap_err()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    assert_eq!(
+        errors,
+        ["limits.queue_memory_budget: must exceed the largest document, 300 bytes with limits.max_body_size at 300"]
+    );
+
+    let mut config = common::config();
+    config.limits.max_body_size = Some(300);
+    config.limits.queue_memory_budget = 301;
+    assert!(config.validated().is_ok());
+    // Unbounded documents leave the budget unchecked.
+    let mut config = common::config();
+    config.limits.queue_memory_budget = 1;
+    assert!(config.validated().is_ok());
+}
+
+#[test]
+fn reloaded_limits_apply_to_new_transactions() {
+    let (prism, _serial) = setup(|limits| limits.max_body_size = Some(5));
+    let mut started = prism.begin(39501, "GET", &uri(39501), &HEADERS);
+    started.status(200);
+
+    let mut reloaded = (*config::get()).clone();
+    reloaded.limits.max_body_size = None;
+    config::set(reloaded);
+    started.receive(b"hello world").unwrap();
+    started.done();
+    drain(&mut started);
+    assert_eq!(document(39501)["body"], "hello");
+
+    let document = common::run(&prism, 39502, &uri(39502), &HEADERS, b"hello world");
+    assert_eq!(document["body"], "hello world");
+}
@@ -0,0 +1,186 @@
+//! The logger `init()` installs, as configured by `PRISM_LOG_*`. A process
+//! installs one logger only, so each test runs `child` again in a process
+//! of its own, configured through its environment, and looks at the lines
+//! it logged and where they went.
+
+mod common;
+
+use common::MEMORY_BACKEND;
+use serde_json::Value;
+use std::ffi::CString;
+use std::process::{Command, Output};
+
+/// Set in the environment of the processes running `child`.
+const CHILD: &str = "PRISM_TEST_LOGGING_CHILD";
+/// Filter `child` switches to with `set_log_filter()`, if set.
+const RELOADED_FILTER: &str = "PRISM_TEST_RELOADED_FILTER";
+const ID: i64 = 58001;
+
+/// Initializes the library, runs a transaction through and logs a line per
+/// module and level, when run by `run()`. Does nothing otherwise.
+#[test]
+fn child() {
+    if std::env::var_os(CHILD).is_none() {
+        return;
+    }
+    common::configure(MEMORY_BACKEND);
+    prism::init();
+    common::begin(
+        ID,
+        "http://logging.example.com/",
+        &[("Content-Type", "text/plain")],
+    );
+    common::receive(ID, b"hello");
+    common::finish(ID);
+    prism::cleanup(ID);
+    modules("initial");
+    if let Some(filter) = std::env::var_os(RELOADED_FILTER) {
+        let filter = CString::new(filter.into_string().unwrap()).unwrap();
+        prism::set_log_filter(filter.as_ptr());
+        modules("reloaded");
+    }
+    log::logger().flush();
+}
+
+/// Logs a line for each of two modules at debug and warn levels.
+fn modules(phase: &str) {
+    for module in ["prism::persistence", "prism::pipeline"] {
+        log::debug!(target: module, "{} debug of {}", phase, module);
+        log::warn!(target: module, "{} warning of {}", phase, module);
+    }
+}
+
+/// Runs `child` in a new process with `variables` set, on top of the
+/// `stderr` backend and the default text format and filter.
+fn run(variables: &[(&str, &str)]) -> Output {
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args(["child", "--exact", "--nocapture", "--test-threads=1"])
+        .env(CHILD, "1")
+        .env("PRISM_LOG_BACKEND", "stderr")
+        .env("PRISM_LOG_FORMAT", "text")
+        .env("PRISM_LOG", "info")
+        .env_remove("PRISM_LOG_FILE")
+        .envs(variables.iter().copied())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    output
+}
+
+/// The lines of what the child wrote to stderr.
+fn stderr(output: &Output) -> Vec<String> {
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+fn contains(lines: &[String], text: &str) -> bool {
+    lines.iter().any(|line| line.contains(text))
+}
+
+#[test]
+fn stderr_gets_text_lines() {
+    let lines = stderr(&run(&[]));
+    let initialized = lines
+        .iter()
+        .find(|line| line.ends_with("INFO prism::logging: Logger initialized"))
+        .unwrap_or_else(|| panic!("{:?}", lines));
+    // Starts with the timestamp.
+    assert!(initialized.split(' ').next().unwrap().ends_with('Z'));
+    assert!(contains(&lines, &format!("Transaction {} held ", ID)));
+}
+
+#[test]
+fn the_file_backend_appends_to_the_file() {
+    let path = common::temporary("prism.log");
+    std::fs::write(&path, "kept\n").unwrap();
+    let output = run(&[
+        ("PRISM_LOG_BACKEND", "file"),
+        ("PRISM_LOG_FILE", path.to_str().unwrap()),
+    ]);
+    assert!(!contains(&stderr(&output), "Logger initialized"));
+
+    let logged = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<String> = logged.lines().map(str::to_string).collect();
+    assert_eq!(lines[0], "kept");
+    assert!(contains(&lines, "Logger initialized"));
+    assert!(contains(&lines, &format!("Transaction {} held ", ID)));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn backends_that_cannot_be_set_up_fall_back_to_stderr() {
+    let lines = stderr(&run(&[("PRISM_LOG_BACKEND", "file")]));
+    assert!(contains(&lines, "Logger initialized"), "{:?}", lines);
+    assert!(contains(
+        &lines,
+        "Logging to stderr instead: no log file is configured"
+    ));
+}
+
+#[cfg(not(feature = "syslog-logging"))]
+#[test]
+fn syslog_falls_back_to_stderr_when_left_out() {
+    let lines = stderr(&run(&[("PRISM_LOG_BACKEND", "syslog")]));
+    assert!(contains(&lines, &format!("Transaction {} held ", ID)));
+    assert!(contains(
+        &lines,
+        "Logging to stderr instead: syslog support is not compiled in"
+    ));
+}
+
+#[test]
+fn json_lines_carry_the_fields_of_transaction_events() {
+    let lines = stderr(&run(&[("PRISM_LOG_FORMAT", "json")]));
+    let records: Vec<Value> = lines
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {}", e, line)))
+        .collect();
+    for record in &records {
+        for key in ["timestamp", "level", "target", "message"] {
+            assert!(record[key].is_string(), "{} missing: {}", key, record);
+        }
+    }
+
+    let initialized = records
+        .iter()
+        .find(|record| record["message"] == "Logger initialized")
+        .unwrap();
+    assert_eq!(initialized["level"], "info");
+    assert_eq!(initialized["target"], "prism::logging");
+    assert!(initialized.get("transaction_id").is_none());
+
+    let cleanup = records
+        .iter()
+        .find(|record| {
+            record["message"]
+                .as_str()
+                .unwrap()
+                .starts_with(&format!("Transaction {} held ", ID))
+        })
+        .unwrap();
+    assert_eq!(cleanup["transaction_id"], ID);
+    assert!(cleanup["bytes"].is_u64());
+}
+
+#[test]
+fn module_filters_admit_and_suppress_records() {
+    let lines = stderr(&run(&[
+        ("PRISM_LOG", "warn,persistence=debug,pipeline=loud"),
+        (RELOADED_FILTER, "debug,persistence=off"),
+    ]));
+    let logged = |text: &str| contains(&lines, text);
+    assert!(logged("initial debug of prism::persistence"));
+    assert!(logged("initial warning of prism::persistence"));
+    assert!(!logged("initial debug of prism::pipeline"));
+    assert!(logged("initial warning of prism::pipeline"));
+    assert!(!logged("Logger initialized"));
+    // Only the invalid directive is left out.
+    assert!(logged("Ignoring invalid log directive pipeline=loud"));
+
+    assert!(!logged("reloaded debug of prism::persistence"));
+    assert!(!logged("reloaded warning of prism::persistence"));
+    assert!(logged("reloaded debug of prism::pipeline"));
+    assert!(logged("reloaded warning of prism::pipeline"));
+}
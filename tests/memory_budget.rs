@@ -0,0 +1,108 @@
+//! `limits.memory_budget`, shared by every transaction: checked when a
+//! transaction starts, those starting past it relayed without capture, and
+//! the bytes accounted to it given back on cleanup. The budget is read from
+//! the retained bytes of `stats()`, so tests configure the exported
+//! functions first.
+
+mod common;
+
+use common::{counter, drain, setup_ffi, state, stats, take, TIMEOUT};
+use prism::{memory, Prism};
+use std::thread;
+
+const HEADERS: [(&str, &str); 1] = [("Content-Type", "text/plain")];
+const KIB: usize = 1024;
+
+fn prism(budget: usize) -> Prism {
+    let mut config = common::config();
+    config.limits.coalesce_size = 0;
+    config.limits.memory_budget = Some(budget);
+    Prism::new(config).unwrap()
+}
+
+fn retained() -> u64 {
+    stats()["retained_bytes"].as_u64().unwrap()
+}
+
+fn uri(id: i64) -> String {
+    format!("http://budget.example.com/{}", id)
+}
+
+#[test]
+fn transactions_past_the_budget_are_relayed_without_capture() {
+    let _serial = setup_ffi();
+    let prism = prism(32 * KIB);
+    let baseline = retained();
+    let shed = counter(&prism, "shed_transactions");
+    let body = vec![b'x'; 48 * KIB];
+
+    // Held queued and captured, over the budget by itself.
+    let mut large = prism.begin(43001, "GET", &uri(43001), &HEADERS);
+    large.status(200);
+    large.receive(&body).unwrap();
+    assert!(retained() >= baseline + 48 * KIB as u64);
+    let mut past = prism.begin(43002, "GET", &uri(43002), &HEADERS);
+    assert_eq!(state(&prism, 43002)["shed"], true);
+    assert_eq!(counter(&prism, "shed_transactions"), shed + 1);
+    past.status(200);
+    past.receive(b"relayed all the same").unwrap();
+    past.done();
+    assert_eq!(drain(&mut past), b"relayed all the same");
+    drop(past);
+
+    large.done();
+    assert_eq!(drain(&mut large), body);
+    drop(large);
+    memory::wait(43001, TIMEOUT).expect("document persisted");
+    assert_eq!(retained(), baseline);
+    // Back under the budget, transactions are captured again.
+    let document = common::run(&prism, 43003, &uri(43003), &HEADERS, b"hello");
+    assert_eq!(document["body"], "hello");
+    assert!(memory::find(43002).is_none());
+}
+
+#[test]
+fn concurrent_transactions_stay_near_the_budget() {
+    let _serial = setup_ffi();
+    let budget = 256 * KIB;
+    let prism = prism(budget);
+    let baseline = retained();
+    let threads: Vec<_> = (0..8)
+        .map(|thread| {
+            let prism = prism.clone();
+            thread::spawn(move || {
+                let chunk = vec![b'a' + thread as u8; 4 * KIB];
+                for index in 0..10 {
+                    let id = 43100 + thread * 10 + index;
+                    let mut handle = prism.begin(id, "GET", &uri(id), &HEADERS);
+                    handle.status(200);
+                    let mut output = Vec::new();
+                    for _ in 0..4 {
+                        handle.receive(&chunk).unwrap();
+                        output.extend(take(&mut handle));
+                    }
+                    handle.done();
+                    output.extend(drain(&mut handle));
+                    assert_eq!(output, chunk.repeat(4));
+                }
+            })
+        })
+        .collect();
+    // Sampled from this thread only, as `stats()` hands back a buffer the
+    // next call replaces.
+    let mut highest = baseline;
+    while !threads.iter().all(|thread| thread.is_finished()) {
+        highest = highest.max(retained());
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    // Checked as transactions start, the budget is only exceeded by what
+    // each running transaction holds: its 16KiB body captured, and a chunk
+    // queued and handed back.
+    let highest = highest - baseline;
+    let bound = (budget + 8 * 24 * KIB) as u64;
+    assert!(highest <= bound, "{} bytes retained", highest);
+    assert_eq!(retained(), baseline);
+}
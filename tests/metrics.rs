@@ -0,0 +1,139 @@
+//! The Prometheus endpoint served from `init()` on
+//! `telemetry.metrics_address`, scraped after driving transactions through
+//! the exported functions. Counters are shared by the whole process, so a
+//! single test drives every transaction.
+
+#![cfg(feature = "metrics")]
+
+mod common;
+
+use common::{configure, gzip, receive, send, MEMORY_BACKEND, TIMEOUT};
+use prism::memory;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+/// An address of the loopback interface nothing listens on.
+fn free_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// The response to a GET of `path`.
+fn get(address: &str, path: &str) -> std::io::Result<String> {
+    let mut stream = TcpStream::connect(address)?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, address)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+/// The samples of a scrape by series, and the type of every metric.
+fn parse(body: &str) -> (HashMap<String, f64>, HashMap<String, String>) {
+    let mut samples = HashMap::new();
+    let mut types = HashMap::new();
+    for line in body.lines() {
+        if let Some(declaration) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = declaration.split_once(' ').unwrap();
+            types.insert(name.to_string(), kind.to_string());
+        } else if !line.starts_with('#') && !line.is_empty() {
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            samples.insert(series.to_string(), value.parse().unwrap());
+        }
+    }
+    (samples, types)
+}
+
+/// Relays `body` through transaction `id` to the end, returning the bytes
+/// handed back.
+fn exchange(id: i64, headers: &[(&str, &str)], body: &[u8]) -> usize {
+    common::begin(id, "http://metrics.example.com/", headers);
+    receive(id, body);
+    let mut sent = send(id).len();
+    sent += common::finish(id).len();
+    prism::cleanup(id);
+    sent
+}
+
+#[test]
+fn scrapes_report_transactions_until_shutdown() {
+    let address = free_address();
+    configure(&format!(
+        "{}\n[logging]\nbackend = \"stderr\"\n\n[telemetry]\nmetrics_address = \"{}\"\n",
+        MEMORY_BACKEND, address
+    ));
+    prism::init();
+
+    let page = b"<p>scraped</p>\n".repeat(1000);
+    let encoded = gzip(&page);
+    let mut corrupted = encoded.clone();
+    // The trailer's checksum no longer matches the body.
+    let checksum = corrupted.len() - 8;
+    corrupted[checksum] ^= 0xff;
+    let plain = [("Content-Type", "text/html")];
+    let encoding = [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+
+    let mut sent = exchange(33001, &plain, &page);
+    sent += exchange(33002, &encoding, &encoded);
+    sent += exchange(33003, &encoding, &corrupted);
+    common::begin(33004, "http://metrics.example.com/aborted", &plain);
+    receive(33004, &page[..100]);
+    prism::cleanup(33004);
+    for id in 33001..=33003 {
+        memory::wait(id, TIMEOUT).expect("document persisted");
+    }
+
+    // Persisting is counted once the document is stored.
+    let persisted = "prism_persist_total{backend=\"memory\",result=\"success\"}";
+    let deadline = Instant::now() + TIMEOUT;
+    let (head, samples, types) = loop {
+        let response = get(&address, "/metrics").unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let (samples, types) = parse(body);
+        if samples.get(persisted) == Some(&3.0) || Instant::now() >= deadline {
+            break (head.to_string(), samples, types);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+
+    for (name, kind) in [
+        ("prism_transactions_started_total", "counter"),
+        ("prism_transactions_completed_total", "counter"),
+        ("prism_transactions_aborted_total", "counter"),
+        ("prism_bytes_received_total", "counter"),
+        ("prism_bytes_sent_total", "counter"),
+        ("prism_decode_errors_total", "counter"),
+        ("prism_persist_total", "counter"),
+        ("prism_persist_duration_seconds", "histogram"),
+        ("prism_active_transactions", "gauge"),
+        ("prism_persistence_queue_depth", "gauge"),
+    ] {
+        assert_eq!(types.get(name).map(String::as_str), Some(kind), "{}", name);
+    }
+    assert_eq!(samples["prism_transactions_started_total"], 4.0);
+    assert_eq!(samples["prism_transactions_completed_total"], 3.0);
+    assert_eq!(samples["prism_transactions_aborted_total"], 1.0);
+    let received = 2 * encoded.len() + page.len() + 100;
+    assert_eq!(samples["prism_bytes_received_total"], received as f64);
+    assert_eq!(samples["prism_bytes_sent_total"], sent as f64);
+    assert_eq!(samples["prism_decode_errors_total"], 1.0);
+    assert_eq!(samples[persisted], 3.0);
+    assert_eq!(
+        samples["prism_persist_total{backend=\"memory\",result=\"failure\"}"],
+        0.0
+    );
+    assert_eq!(samples["prism_active_transactions"], 0.0);
+
+    let response = get(&address, "/other").unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found"),
+        "{}",
+        response
+    );
+
+    prism::shutdown();
+    assert!(get(&address, "/metrics").is_err());
+}
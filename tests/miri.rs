@@ -0,0 +1,120 @@
+//! Exercises the pointer handling of the exported functions with pointers
+//! made up the way a C caller would pass them, small enough to run under
+//! Miri with `make miri`. Transactions only relay identity bodies, which
+//! skip zlib: Miri cannot run it.
+
+mod common;
+
+use common::{bytes, setup_ffi, stats, TIMEOUT};
+use std::ffi::{c_char, c_void};
+use std::ptr::null;
+
+/// Passes a NUL terminated string the way C does.
+fn c(string: &[u8]) -> *const c_char {
+    assert_eq!(string.last(), Some(&0));
+    string.as_ptr() as *const c_char
+}
+
+fn errors(kind: &str) -> u64 {
+    stats()["counters"]["errors"][kind].as_u64().unwrap_or(0)
+}
+
+#[test]
+fn null_pointers_are_refused() {
+    let _serial = setup_ffi();
+    let before = errors("invalid_argument");
+
+    prism::header(3001, null(), c(b"value\0"));
+    prism::header(3001, c(b"name\0"), null());
+    prism::uri(3001, null(), 1, c(b"GET\0"));
+    prism::client_address(3001, null());
+    prism::rewrite_rule(c(b"find\0"), null());
+    prism::block(3001, null());
+    prism::annotate(3001, c(b"key\0"), null());
+    prism::set_log_filter(null());
+    prism::receive(3001, null(), 16);
+    let value = prism::get_response_header(3001, null());
+    assert_eq!(value.size, 0);
+    assert!(value.bytes.is_null());
+    assert_ne!(prism::configure(null()), 0);
+
+    assert_eq!(errors("invalid_argument") - before, 10);
+    assert_eq!(stats()["pending_headers"].as_u64(), Some(0));
+}
+
+#[test]
+fn empty_chunks_may_come_with_any_pointer() {
+    let _serial = setup_ffi();
+    let before = errors("invalid_argument");
+    let unknown = errors("unknown_transaction");
+    let dangling = std::ptr::NonNull::<u8>::dangling().as_ptr() as *const c_void;
+
+    prism::receive(3002, null(), 0);
+    prism::receive(3002, dangling, 0);
+    assert_eq!(errors("invalid_argument"), before);
+    // Received for a transaction never started, the bytes are dropped.
+    assert_eq!(errors("unknown_transaction") - unknown, 2);
+}
+
+#[test]
+fn received_bytes_are_read_within_bounds() {
+    let _serial = setup_ffi();
+    let unknown = errors("unknown_transaction");
+    let body = *b"<html>prism</html> and more";
+
+    prism::receive(3003, body.as_ptr() as *const c_void, 18);
+    prism::receive(3003, body[6..].as_ptr() as *const c_void, 5);
+    assert_eq!(errors("unknown_transaction") - unknown, 2);
+}
+
+#[test]
+fn strings_are_copied_before_the_call_returns() {
+    let _serial = setup_ffi();
+    let mut name = *b"X-Miri\0";
+    let mut value = *b"\xff\xfe valid after\0";
+
+    prism::header(3004, c(&name), c(&value));
+    name.fill(0);
+    value.fill(0);
+    assert_eq!(stats()["pending_headers"].as_u64(), Some(1));
+
+    // Never started, so there is no response header to read back.
+    let chunk = prism::get_response_header(3004, c(b"X-Miri\0"));
+    assert_eq!(chunk.size, 0);
+    prism::cleanup(3004);
+    assert_eq!(stats()["pending_headers"].as_u64(), Some(0));
+}
+
+#[test]
+fn returned_chunks_stay_valid_until_the_next_call() {
+    let _serial = setup_ffi();
+    let stats = prism::stats();
+    let json: serde_json::Value = serde_json::from_slice(&bytes(stats.size, stats.bytes)).unwrap();
+    assert!(json["counters"].is_object());
+
+    let config_errors = prism::config_errors();
+    assert_eq!(bytes(config_errors.size, config_errors.bytes), b"");
+}
+
+#[test]
+fn identity_transactions_go_through_the_whole_lifecycle() {
+    let _serial = setup_ffi();
+    let id = 3005;
+    let body = *b"<html>prism under miri</html>";
+
+    prism::header(id, c(b"Content-Type\0"), c(b"text/html\0"));
+    prism::status(id, 200);
+    prism::uri(id, c(b"http://miri.example.com/\0"), 1, c(b"GET\0"));
+    let chunk = prism::get_response_header(id, c(b"content-type\0"));
+    assert_eq!(bytes(chunk.size, chunk.bytes), b"text/html");
+    prism::receive(id, body.as_ptr() as *const c_void, 6);
+    let mut output = common::send(id);
+    prism::receive(id, body[6..].as_ptr() as *const c_void, body.len() - 6);
+    output.extend(common::finish(id));
+    assert_eq!(output, body);
+    prism::cleanup(id);
+
+    assert_eq!(stats()["active_transactions"].as_u64(), Some(0));
+    let document = prism::memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.body, body);
+}
@@ -0,0 +1,38 @@
+//! Conversions of `Mode` to and from the values it is passed and shown as.
+
+use prism::mode::Mode;
+
+#[test]
+fn modes_round_trip_through_i64() {
+    for mode in Mode::ALL {
+        assert_eq!(Mode::from(i64::from(mode)), mode);
+    }
+    assert_eq!(Mode::from(0), Mode::REQMOD);
+    assert_eq!(Mode::from(1), Mode::RESPMOD);
+    assert_eq!(Mode::from(2), Mode::OPTIONS);
+    assert_eq!(Mode::from(7), Mode::UNKNOWN);
+}
+
+#[test]
+fn modes_round_trip_through_names() {
+    for mode in Mode::ALL {
+        assert_eq!(mode.to_string(), mode.as_str());
+        assert_eq!(mode.as_str().parse::<Mode>(), Ok(mode));
+        assert_eq!(mode.as_str().to_lowercase().parse::<Mode>(), Ok(mode));
+    }
+    assert_eq!(Mode::RESPMOD.to_string(), "RESPMOD");
+    let error = "1".parse::<Mode>().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "unknown mode \"1\", expected REQMOD, RESPMOD, OPTIONS or UNKNOWN"
+    );
+}
+
+#[test]
+fn modes_round_trip_through_serde() {
+    for mode in Mode::ALL {
+        let json = serde_json::to_string(&mode).unwrap();
+        assert_eq!(json, format!("\"{}\"", mode));
+        assert_eq!(serde_json::from_str::<Mode>(&json).unwrap(), mode);
+    }
+}
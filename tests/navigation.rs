@@ -0,0 +1,145 @@
+//! Referer and fetch kind of transactions, and the rules matching on them.
+
+mod common;
+
+use prism::config::Config;
+use prism::Prism;
+use serde_json::Value;
+use std::sync::MutexGuard;
+
+fn setup(configure: impl FnOnce(&mut Config)) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.limits.coalesce_size = 0;
+        configure(config);
+    })
+}
+
+/// Runs a transaction with `headers` through and returns its document.
+fn run(prism: &Prism, id: i64, headers: &[(&str, &str)]) -> Value {
+    common::run(
+        prism,
+        id,
+        "http://navigation.example.com/",
+        headers,
+        b"hello",
+    )
+}
+
+#[test]
+fn sec_fetch_dest_decides_the_fetch_kind() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        12001,
+        &[
+            ("Content-Type", "text/html"),
+            ("Accept", "text/html"),
+            ("Sec-Fetch-Dest", "script"),
+            ("Referer", "https://WWW.Example.com:8443/page?from=home"),
+        ],
+    );
+    assert_eq!(document["fetch_kind"], "script");
+    assert_eq!(
+        document["referer"],
+        "https://WWW.Example.com:8443/page?from=home"
+    );
+    assert_eq!(document["referer_host"], "www.example.com");
+
+    // Destinations of no kind are not guessed at.
+    let document = run(
+        &prism,
+        12002,
+        &[("Content-Type", "font/woff2"), ("Sec-Fetch-Dest", "font")],
+    );
+    assert!(document.get("fetch_kind").is_none());
+}
+
+#[test]
+fn fetch_kind_falls_back_on_accept_then_content_type() {
+    let (prism, _serial) = setup(|_| {});
+    let kinds = [
+        (
+            12101,
+            "text/html,application/xhtml+xml,image/webp,*/*;q=0.8",
+            "text/plain",
+            "document",
+        ),
+        (12102, "text/css,*/*;q=0.1", "text/plain", "style"),
+        (12103, "*/*", "image/png", "image"),
+        (
+            12104,
+            "*/*",
+            "application/javascript; charset=utf-8",
+            "script",
+        ),
+        (12105, "application/json", "text/plain", "xhr"),
+    ];
+    for (id, accept, content_type, kind) in kinds {
+        let document = run(
+            &prism,
+            id,
+            &[("Content-Type", content_type), ("Accept", accept)],
+        );
+        assert_eq!(document["fetch_kind"], kind, "{}", accept);
+    }
+
+    let document = run(&prism, 12106, &[("Content-Type", "text/plain")]);
+    assert!(document.get("fetch_kind").is_none());
+}
+
+#[test]
+fn missing_referers_are_left_out() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(&prism, 12201, &[("Content-Type", "text/html")]);
+    assert!(document.get("referer").is_none());
+    assert!(document.get("referer_host").is_none());
+    assert_eq!(document["fetch_kind"], "document");
+
+    // Relative referers have no host.
+    let document = run(
+        &prism,
+        12202,
+        &[("Content-Type", "text/html"), ("Referer", "/index.html")],
+    );
+    assert_eq!(document["referer"], "/index.html");
+    assert!(document.get("referer_host").is_none());
+}
+
+#[test]
+fn rules_match_fetch_kind_and_referer_host() {
+    let path = std::env::temp_dir().join(format!(
+        "prism-navigation-rules-{}.toml",
+        std::process::id()
+    ));
+    std::fs::write(
+        &path,
+        r#"
+[[rule]]
+fetch_kind = "image"
+referer_host = "*.partner.example.com"
+tags = ["hotlinked"]
+"#,
+    )
+    .unwrap();
+    let (prism, _serial) = setup(|config| config.filters.rules = Some(path.clone()));
+    let hotlinked = run(
+        &prism,
+        12301,
+        &[
+            ("Content-Type", "image/png"),
+            ("Referer", "https://blog.partner.example.com/post"),
+        ],
+    );
+    let linked = run(
+        &prism,
+        12302,
+        &[
+            ("Content-Type", "image/png"),
+            ("Referer", "https://navigation.example.com/"),
+        ],
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(hotlinked["tags"], serde_json::json!(["hotlinked"]));
+    assert!(linked.get("tags").is_none());
+}
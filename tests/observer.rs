@@ -0,0 +1,155 @@
+//! The events observers registered through `prism::observer` see of a
+//! transaction, in order, with a panicking observer kept from the others.
+
+mod common;
+
+use common::{drain, lock, TIMEOUT};
+use prism::observer::{self, LifecycleObserver};
+use prism::Prism;
+use std::sync::{Mutex, MutexGuard, Once};
+use std::time::Instant;
+
+static SETUP: Once = Once::new();
+static EVENTS: Mutex<Vec<(i64, String)>> = Mutex::new(Vec::new());
+
+/// Records every event of a transaction as a line.
+struct Recording;
+
+fn record(id: i64, event: String) {
+    lock(&EVENTS).push((id, event));
+}
+
+impl LifecycleObserver for Recording {
+    fn on_transaction_start(&self, event: &observer::TransactionStart) {
+        record(event.id, format!("start {} {}", event.method, event.uri));
+    }
+
+    fn on_chunk_received(&self, event: &observer::ChunkReceived) {
+        record(event.id, format!("chunk {} {}", event.size, event.total));
+    }
+
+    fn on_done(&self, event: &observer::Done) {
+        record(event.id, format!("done {}", event.bytes_received));
+    }
+
+    fn on_persisted(&self, event: &observer::Persisted) {
+        record(
+            event.id,
+            format!("persisted {} {:?}", event.backend, event.result),
+        );
+    }
+
+    fn on_error(&self, event: &observer::ErrorEvent) {
+        if let Some(id) = event.id {
+            record(id, format!("error {}", event.error.label()));
+        }
+    }
+
+    fn on_cleanup(&self, event: &observer::Cleanup) {
+        record(
+            event.id,
+            format!(
+                "cleanup completed={} {} {}",
+                event.completed, event.bytes_received, event.bytes_sent
+            ),
+        );
+    }
+}
+
+/// Panics on every chunk, registered ahead of `Recording`.
+struct Panicking;
+
+impl LifecycleObserver for Panicking {
+    fn on_chunk_received(&self, _event: &observer::ChunkReceived) {
+        panic!("observer failure");
+    }
+}
+
+fn setup() -> (Prism, MutexGuard<'static, ()>) {
+    SETUP.call_once(|| {
+        observer::register(Box::new(Panicking)).unwrap();
+        observer::register(Box::new(Recording)).unwrap();
+    });
+    common::setup(|config| config.limits.coalesce_size = 0)
+}
+
+fn events(id: i64) -> Vec<String> {
+    lock(&EVENTS)
+        .iter()
+        .filter(|(event, _)| *event == id)
+        .map(|(_, event)| event.clone())
+        .collect()
+}
+
+/// The events of `id`, once it has been persisted.
+fn persisted(id: i64) -> Vec<String> {
+    let started = Instant::now();
+    while !events(id)
+        .iter()
+        .any(|event| event.starts_with("persisted "))
+    {
+        assert!(started.elapsed() < TIMEOUT, "{} not persisted", id);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    events(id)
+}
+
+#[test]
+fn events_follow_the_transaction_in_order() {
+    let (prism, _serial) = setup();
+    let id = 66001;
+    let uri = "http://observer.example.com/page";
+    let mut handle = prism.begin(id, "POST", uri, &[("Content-Type", "text/plain")]);
+    handle.status(200);
+    handle.receive(b"hello ").unwrap();
+    handle.receive(b"world").unwrap();
+    handle.done();
+    assert_eq!(drain(&mut handle), b"hello world");
+    drop(handle);
+
+    let mut events = persisted(id);
+    // Persisted on the worker, as soon as the whole body was handed back.
+    let persisted = events
+        .iter()
+        .position(|event| event.starts_with("persisted "))
+        .unwrap();
+    assert_eq!(events.remove(persisted), "persisted memory Ok(())");
+    assert!(persisted > 3, "{:?}", events);
+    assert_eq!(
+        events,
+        [
+            format!("start POST {}", uri),
+            "chunk 6 6".to_string(),
+            "chunk 5 11".to_string(),
+            "done 11".to_string(),
+            "cleanup completed=true 11 11".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn abandoned_transactions_are_cleaned_up_without_done_or_persisting() {
+    let (prism, _serial) = setup();
+    let id = 66101;
+    let handle = prism.begin(id, "GET", "http://observer.example.com/", &[]);
+    handle.status(200);
+    handle.receive(b"partial").unwrap();
+    drop(handle);
+
+    // Aborted, so never persisted.
+    assert_eq!(
+        events(id),
+        [
+            "start GET http://observer.example.com/",
+            "chunk 7 7",
+            "cleanup completed=false 7 0"
+        ]
+    );
+}
+
+#[test]
+fn observers_are_refused_after_init() {
+    let (_prism, _serial) = setup();
+    prism::init();
+    assert!(observer::register(Box::new(Recording)).is_err());
+}
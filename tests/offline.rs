@@ -0,0 +1,353 @@
+//! Offline spooling: documents the Elasticsearch backend can't take while
+//! it is down are spooled to disk, and replayed once it recovers, across a
+//! restart, each persisted once with its original id and date.
+
+#![cfg(feature = "elasticsearch")]
+
+mod common;
+
+use common::{lock, TIMEOUT};
+use prism::clock::{self, ManualClock};
+use prism::config::Config;
+use prism::Prism;
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const INDEX: &str = "prism-offline";
+/// Where the clock stands while documents are spooled.
+const DATE: &str = "2024-01-01T00:00:00Z";
+const RUN_ID: u64 = 1704067200;
+
+/// Id the document of transaction `id` is stored under, see
+/// `tests/elasticsearch.rs`.
+fn document_id(id: i64) -> String {
+    let mut hash = Sha256::digest(format!("{}-{}", RUN_ID, id));
+    hash[6] = (hash[6] & 0x0f) | 0x80;
+    hash[8] = (hash[8] & 0x3f) | 0x80;
+    let hex: String = hash[0..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// A document the mock cluster stored.
+#[derive(Clone)]
+struct Stored {
+    path: String,
+    body: Vec<u8>,
+}
+
+/// A cluster that stores documents while `up`, and closes the connection
+/// on them otherwise. Index requests always succeed.
+struct MockCluster {
+    port: u16,
+    up: Arc<AtomicBool>,
+    stored: Arc<Mutex<Vec<Stored>>>,
+}
+
+impl MockCluster {
+    fn start() -> MockCluster {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let up = Arc::new(AtomicBool::new(false));
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let (serving_up, serving_stored) = (up.clone(), stored.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    break;
+                };
+                let up = serving_up.clone();
+                let stored = serving_stored.clone();
+                std::thread::spawn(move || serve(stream, &up, &stored));
+            }
+        });
+        MockCluster { port, up, stored }
+    }
+
+    fn stored(&self) -> Vec<Stored> {
+        lock(&self.stored).clone()
+    }
+}
+
+/// Answers the requests of one connection, which the client keeps alive.
+fn serve(stream: TcpStream, up: &AtomicBool, stored: &Mutex<Vec<Stored>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let document = method == "PUT" && path.starts_with(&format!("/{}/_doc/", INDEX));
+        if document {
+            if !up.load(Ordering::Relaxed) {
+                return;
+            }
+            lock(stored).push(Stored { path, body });
+        }
+        let reply = if document {
+            r#"{"result":"created"}"#
+        } else {
+            "{}"
+        };
+        let response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            if document { 201 } else { 200 },
+            reply.len(),
+            reply
+        );
+        if stream.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn directory(name: &str) -> PathBuf {
+    let directory =
+        std::env::temp_dir().join(format!("prism-offline-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&directory);
+    directory
+}
+
+/// An instance persisting to `cluster`, spooling to `spool`.
+fn prism(cluster: &MockCluster, spool: &Path, configure: impl FnOnce(&mut Config)) -> Prism {
+    let mut config = Config::load(None).unwrap();
+    config.backend.kind = "elasticsearch".to_string();
+    let elasticsearch = &mut config.backend.elasticsearch;
+    elasticsearch.hostname = "127.0.0.1".to_string();
+    elasticsearch.port = cluster.port;
+    elasticsearch.protocol = "http".to_string();
+    elasticsearch.index = INDEX.to_string();
+    config.logging.throttle_window = 0;
+    config.limits.coalesce_size = 0;
+    config.offline.directory = Some(spool.to_path_buf());
+    config.offline.replay_rate = 1000.0;
+    configure(&mut config);
+    Prism::new(config).unwrap()
+}
+
+fn transaction(prism: &Prism, id: i64) {
+    common::relay(
+        prism,
+        id,
+        &format!("http://offline.example.com/{}", id),
+        &[("Content-Type", "text/plain")],
+        b"hello world",
+    );
+}
+
+/// The content of the spool files in `spool`, in order. Files removed
+/// meanwhile, pruned or replayed, are left out.
+fn spooled(spool: &Path) -> Vec<String> {
+    let Ok(files) = std::fs::read_dir(spool) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = files
+        .map(|file| file.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|found| found == "jsonl"))
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .collect()
+}
+
+fn wait(what: &str, f: impl Fn() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !f() {
+        assert!(Instant::now() < deadline, "{}", what);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn documents_spooled_while_the_backend_is_down_persist_once_it_recovers() {
+    let _serial = common::serial();
+    let spool = directory("recovery");
+    let cluster = MockCluster::start();
+    let ids: Vec<i64> = (16001..16011).collect();
+
+    // The clock stands still, so the circuit stays open once tripped.
+    clock::set(ManualClock::at(DATE.parse().unwrap()));
+    let down = prism(&cluster, &spool, |_| {});
+    clock::set_run_id(RUN_ID);
+    for &id in &ids {
+        transaction(&down, id);
+    }
+    let lines = || {
+        spooled(&spool)
+            .iter()
+            .map(|file| file.lines().count())
+            .sum::<usize>()
+    };
+    wait("documents not spooled", || lines() == ids.len());
+    drop(down);
+    assert_eq!(lines(), ids.len());
+    assert!(cluster.stored().is_empty());
+
+    // Another run, at another time, replays the spool on startup.
+    clock::reset();
+    cluster.up.store(true, Ordering::Relaxed);
+    let up = prism(&cluster, &spool, |_| {});
+    clock::set_run_id(RUN_ID + 1);
+    wait("documents not replayed", || {
+        cluster.stored().len() >= ids.len()
+    });
+    std::thread::sleep(Duration::from_millis(200));
+    drop(up);
+
+    let stored = cluster.stored();
+    let mut paths: Vec<&str> = stored.iter().map(|stored| stored.path.as_str()).collect();
+    paths.sort();
+    let mut expected: Vec<String> = ids
+        .iter()
+        .map(|&id| format!("/{}/_doc/{}", INDEX, document_id(id)))
+        .collect();
+    expected.sort();
+    assert_eq!(paths, expected);
+    for stored in &stored {
+        let document: serde_json::Value = serde_json::from_slice(&stored.body).unwrap();
+        assert_eq!(document["date"], DATE);
+        assert_eq!(document["run_id"], RUN_ID);
+        assert_eq!(document["raw_body"], "aGVsbG8gd29ybGQ=");
+    }
+    assert!(spooled(&spool).is_empty());
+    std::fs::remove_dir_all(&spool).unwrap();
+}
+
+#[test]
+fn oldest_spooled_documents_are_pruned_past_the_size_cap() {
+    let _serial = common::serial();
+    let spool = directory("pruned");
+    let cluster = MockCluster::start();
+
+    clock::set(ManualClock::at(DATE.parse().unwrap()));
+    let down = prism(&cluster, &spool, |config| {
+        config.offline.max_size = 4096;
+        config.limits.persistence_workers = 1;
+    });
+    clock::set_run_id(RUN_ID);
+    for id in 16101..16121 {
+        transaction(&down, id);
+    }
+    let contains = |id: i64| {
+        spooled(&spool)
+            .iter()
+            .any(|file| file.contains(&document_id(id)))
+    };
+    wait("documents not spooled", || contains(16120));
+    drop(down);
+    clock::reset();
+
+    let size: usize = spooled(&spool).iter().map(String::len).sum();
+    assert!(size <= 4096, "{} bytes spooled", size);
+    assert!(!contains(16101));
+    assert!(contains(16120));
+    std::fs::remove_dir_all(&spool).unwrap();
+}
+
+#[test]
+fn documents_spooled_while_a_file_is_replayed_are_kept() {
+    let _serial = common::serial();
+    let spool = directory("concurrent");
+    let cluster = MockCluster::start();
+    let spooled_ids: Vec<i64> = (16201..16221).collect();
+
+    clock::set(ManualClock::at(DATE.parse().unwrap()));
+    let down = prism(&cluster, &spool, |_| {});
+    clock::set_run_id(RUN_ID);
+    for &id in &spooled_ids {
+        transaction(&down, id);
+    }
+    wait("documents not spooled", || {
+        spooled(&spool)
+            .iter()
+            .map(|file| file.lines().count())
+            .sum::<usize>()
+            == spooled_ids.len()
+    });
+    drop(down);
+    clock::reset();
+
+    // The spool is replayed while transactions overflowing a queue of two
+    // are spooled from several threads.
+    cluster.up.store(true, Ordering::Relaxed);
+    let up = prism(&cluster, &spool, |config| {
+        config.limits.persistence_workers = 1;
+        config.limits.max_queued_documents = 2;
+    });
+    clock::set_run_id(RUN_ID);
+    let threads: Vec<_> = (0..4)
+        .map(|thread| {
+            let prism = up.clone();
+            std::thread::spawn(move || {
+                for index in 0..15 {
+                    transaction(&prism, 16301 + thread * 100 + index);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let ids: Vec<i64> = spooled_ids
+        .into_iter()
+        .chain((0..4).flat_map(|thread| (0..15).map(move |index| 16301 + thread * 100 + index)))
+        .collect();
+    wait("documents not replayed", || {
+        cluster.stored().len() >= ids.len()
+    });
+    std::thread::sleep(Duration::from_millis(200));
+    drop(up);
+
+    // Each stored once, none lost to a replayed file.
+    let mut paths: Vec<String> = cluster
+        .stored()
+        .into_iter()
+        .map(|stored| stored.path)
+        .collect();
+    paths.sort();
+    let mut expected: Vec<String> = ids
+        .iter()
+        .map(|&id| format!("/{}/_doc/{}", INDEX, document_id(id)))
+        .collect();
+    expected.sort();
+    assert_eq!(paths, expected);
+    assert!(spooled(&spool).is_empty());
+    std::fs::remove_dir_all(&spool).unwrap();
+}
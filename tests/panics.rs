@@ -0,0 +1,71 @@
+//! The panic hook installed by `init()`: the message of `&str` and `String`
+//! payloads logged with the location, counted in the stats, and handed on
+//! to the hook installed before.
+
+mod common;
+
+use common::{setup_ffi, stats};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{MutexGuard, Once};
+
+static INIT: Once = Once::new();
+/// Panics seen by the hook installed before `init()`.
+static CHAINED: AtomicUsize = AtomicUsize::new(0);
+
+fn setup() -> MutexGuard<'static, ()> {
+    let serial = setup_ffi();
+    INIT.call_once(|| {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            CHAINED.fetch_add(1, Ordering::SeqCst);
+            default(info);
+        }));
+        prism::init();
+    });
+    common::capture_logs();
+    serial
+}
+
+fn panics() -> u64 {
+    stats()["counters"]["panics"].as_u64().unwrap()
+}
+
+/// Runs `f`, which panics, and returns the last panic logged.
+fn panicked(f: impl FnOnce()) -> String {
+    let (before, chained) = (panics(), CHAINED.load(Ordering::SeqCst));
+    assert!(panic::catch_unwind(AssertUnwindSafe(f)).is_err());
+    assert_eq!(panics(), before + 1);
+    assert_eq!(CHAINED.load(Ordering::SeqCst), chained + 1);
+    let lines = common::logged(|logged| logged.message.starts_with("Panic at "));
+    lines.last().unwrap().clone()
+}
+
+#[test]
+fn str_payloads_are_logged_with_their_location() {
+    let _serial = setup();
+    let line = line!() + 1;
+    let logged = panicked(|| panic!("static message"));
+    let expected = format!("Panic at tests/panics.rs:{}:", line);
+    assert!(logged.starts_with(&expected), "{}", logged);
+    assert!(logged.ends_with(": static message"), "{}", logged);
+}
+
+#[test]
+fn string_payloads_are_logged() {
+    let _serial = setup();
+    let id = 42;
+    let logged = panicked(|| panic!("formatted message for {}", id));
+    assert!(logged.starts_with("Panic at tests/panics.rs:"));
+    assert!(logged.ends_with(": formatted message for 42"));
+
+    let logged = panicked(|| panic::panic_any(String::from("owned message")));
+    assert!(logged.ends_with(": owned message"), "{}", logged);
+}
+
+#[test]
+fn other_payloads_are_named() {
+    let _serial = setup();
+    let logged = panicked(|| panic::panic_any(7));
+    assert!(logged.ends_with(": <non-string payload>"), "{}", logged);
+}
@@ -0,0 +1,221 @@
+//! The streaming pipeline shared by every transaction: the same input
+//! sequence gives the same bytes from `send()`, once decoded for gzip
+//! bodies, and the same captured body, whether it is relayed through the
+//! exported functions or a handle. Where the encoder flushes depends on
+//! how far decoding got, so encoded output is only compared decoded.
+
+mod common;
+
+use common::{gunzip, gzip, setup_ffi, take, TIMEOUT};
+use prism::{memory, Prism};
+
+const PLAIN: [(&str, &str); 1] = [("Content-Type", "text/html")];
+const GZIP: [(&str, &str); 2] = [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+
+fn page() -> Vec<u8> {
+    (0..4000)
+        .map(|row| format!("<li>pipeline row {}</li>\n", row))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Chunks of `body` of varying sizes, always split the same way.
+fn pieces(body: &[u8]) -> Vec<&[u8]> {
+    let mut pieces = Vec::new();
+    let mut offset = 0;
+    for size in [1, 4096, 17, 65536, 3].iter().cycle() {
+        if offset == body.len() {
+            return pieces;
+        }
+        let end = (offset + size).min(body.len());
+        pieces.push(&body[offset..end]);
+        offset = end;
+    }
+    unreachable!()
+}
+
+/// Relays `body` through the exported functions, handing output back after
+/// each piece, and returns the output and the captured body.
+fn through_ffi(id: i64, headers: &[(&str, &str)], body: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    common::begin(id, "http://pipeline.example.com/", headers);
+    let mut output = Vec::new();
+    for piece in pieces(body) {
+        common::receive(id, piece);
+        output.extend(common::send(id));
+    }
+    output.extend(common::finish(id));
+    prism::cleanup(id);
+    (output, memory::wait(id, TIMEOUT).unwrap().body)
+}
+
+/// Relays `body` the same way through a handle of `prism`.
+fn through_handle(
+    prism: &Prism,
+    id: i64,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> (Vec<u8>, Vec<u8>) {
+    let mut handle = prism.begin(id, "GET", "http://pipeline.example.com/", headers);
+    handle.status(200);
+    let mut output = Vec::new();
+    for piece in pieces(body) {
+        handle.receive(piece).unwrap();
+        output.extend(take(&mut handle));
+    }
+    handle.done();
+    output.extend(common::drain(&mut handle));
+    drop(handle);
+    (output, memory::wait(id, TIMEOUT).unwrap().body)
+}
+
+#[test]
+fn identity_bodies_are_relayed_bit_for_bit() {
+    let _serial = setup_ffi();
+    let prism = Prism::new(common::config()).unwrap();
+    let page = page();
+
+    let (output, body) = through_ffi(29001, &PLAIN, &page);
+    assert_eq!(output, page);
+    assert_eq!(body, page);
+    assert_eq!(through_handle(&prism, 29002, &PLAIN, &page), (output, body));
+}
+
+#[test]
+fn gzip_bodies_decode_to_the_same_bytes() {
+    let _serial = setup_ffi();
+    let prism = Prism::new(common::config()).unwrap();
+    let page = page();
+    let encoded = gzip(&page);
+
+    let (output, body) = through_ffi(29101, &GZIP, &encoded);
+    assert_eq!(gunzip(&output), page);
+    assert_eq!(body, page);
+    let (output, body) = through_handle(&prism, 29102, &GZIP, &encoded);
+    assert_eq!(gunzip(&output), page);
+    assert_eq!(body, page);
+}
+
+/// Bytes that do not compress (xorshift64).
+fn random(size: usize) -> Vec<u8> {
+    let mut state = 0x2545f4914f6cdd1du64;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn trailers_larger_than_the_output_buffer_decode_whole() {
+    let _serial = setup_ffi();
+    // What finishing the encoder writes, the gzip trailer among it, spans
+    // many chunks handed back.
+    let mut config = common::config();
+    config.limits.output_buffer_size = 64;
+    let prism = Prism::new(config).unwrap();
+
+    for (id, body) in [
+        (29201, page()),
+        (29202, random(50_000)),
+        (29203, b"x".to_vec()),
+    ] {
+        let (output, captured) = through_handle(&prism, id, &GZIP, &gzip(&body));
+        assert_eq!(gunzip(&output), body, "{}", id);
+        assert_eq!(captured, body, "{}", id);
+        // Ends on the trailer, with nothing written past it.
+        let length = u32::from_le_bytes(output[output.len() - 4..].try_into().unwrap());
+        assert_eq!(length as usize, body.len(), "{}", id);
+    }
+}
+
+#[test]
+fn finished_transactions_hand_back_nothing_more() {
+    let _serial = setup_ffi();
+    let mut config = common::config();
+    config.limits.output_buffer_size = 64;
+    let prism = Prism::new(config).unwrap();
+    let page = page();
+
+    let mut handle = prism.begin(29301, "GET", "http://pipeline.example.com/", &GZIP);
+    handle.status(200);
+    handle.receive(&gzip(&page)).unwrap();
+    handle.done();
+    let output = common::drain(&mut handle);
+    assert_eq!(gunzip(&output), page);
+    for _ in 0..3 {
+        assert!(take(&mut handle).is_empty());
+    }
+}
+
+/// An instance queueing each chunk received as it is, handing back at most
+/// `output_size` bytes per `send()`.
+fn queueing(output_size: usize) -> Prism {
+    let mut config = common::config();
+    config.limits.coalesce_size = 0;
+    config.limits.output_buffer_size = output_size;
+    Prism::new(config).unwrap()
+}
+
+/// The chunks `send()` hands back until nothing is left for now, one per
+/// call, as the buffer polled takes any of them whole.
+fn sends(handle: &mut prism::TransactionHandle) -> Vec<Vec<u8>> {
+    let mut buffer = vec![0; 64 * 1024];
+    let mut sends = Vec::new();
+    loop {
+        let size = handle.poll_output(&mut buffer);
+        if size == 0 {
+            return sends;
+        }
+        sends.push(buffer[..size].to_vec());
+    }
+}
+
+/// `count` chunks of `size` bytes, each byte telling where it belongs.
+fn small_chunks(count: usize, size: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|chunk| (0..size).map(|byte| (chunk * size + byte) as u8).collect())
+        .collect()
+}
+
+#[test]
+fn many_small_chunks_are_handed_back_in_one_send() {
+    let _serial = setup_ffi();
+    let prism = queueing(1024 * 1024);
+    let chunks = small_chunks(500, 10);
+
+    let mut handle = prism.begin(29401, "GET", "http://pipeline.example.com/", &PLAIN);
+    handle.status(200);
+    for chunk in &chunks {
+        handle.receive(chunk).unwrap();
+    }
+    let sends = sends(&mut handle);
+    assert_eq!(sends.len(), 1);
+    assert_eq!(sends.concat(), chunks.concat());
+    handle.done();
+    assert!(common::drain(&mut handle).is_empty());
+}
+
+#[test]
+fn chunks_split_by_the_output_size_carry_over() {
+    let _serial = setup_ffi();
+    let prism = queueing(1000);
+    // Chunks of 7 bytes, so sends end within a chunk.
+    let chunks = small_chunks(500, 7);
+
+    let mut handle = prism.begin(29402, "GET", "http://pipeline.example.com/", &PLAIN);
+    handle.status(200);
+    for chunk in &chunks {
+        handle.receive(chunk).unwrap();
+    }
+    let sends = sends(&mut handle);
+    let sizes: Vec<usize> = sends.iter().map(Vec::len).collect();
+    assert_eq!(sizes, [1000, 1000, 1000, 500]);
+    assert_eq!(sends.concat(), chunks.concat());
+    handle.done();
+    assert!(common::drain(&mut handle).is_empty());
+    drop(handle);
+    assert_eq!(memory::wait(29402, TIMEOUT).unwrap().body, chunks.concat());
+}
@@ -0,0 +1,41 @@
+//! Output buffers reused across transactions through a pool of one: each
+//! transaction hands back and captures its own body only, whatever the
+//! transaction before it left in the buffer it reuses.
+
+mod common;
+
+use common::{counter, gunzip, gzip, relay, setup, TIMEOUT};
+use prism::memory;
+
+const GZIP: [(&str, &str); 2] = [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+
+fn page(id: i64, rows: usize) -> Vec<u8> {
+    (0..rows)
+        .map(|row| format!("<li>pooled {} row {}</li>\n", id, row))
+        .collect::<String>()
+        .into_bytes()
+}
+
+#[test]
+fn reused_buffers_keep_transactions_apart() {
+    let (prism, _serial) = setup(|config| {
+        config.limits.coalesce_size = 0;
+        config.limits.buffer_pool_size = 1;
+    });
+    let hits = counter(&prism, "pool_hits");
+    let misses = counter(&prism, "pool_misses");
+    // Long, short, then long again, so a short body is written over what a
+    // long one left.
+    let pages = [(48101, 2000), (48102, 3), (48103, 1500), (48104, 1)];
+    for (id, rows) in pages {
+        let page = page(id, rows);
+        let output = relay(&prism, id, "http://pool.example.com/", &GZIP, &gzip(&page));
+        assert_eq!(gunzip(&output), page, "{}", id);
+        assert_eq!(memory::wait(id, TIMEOUT).unwrap().body, page, "{}", id);
+    }
+
+    // Each transaction takes two buffers and the pool keeps one of them
+    // back, so every transaction after the first reuses one.
+    assert_eq!(counter(&prism, "pool_hits") - hits, 3);
+    assert_eq!(counter(&prism, "pool_misses") - misses, 5);
+}
@@ -0,0 +1,101 @@
+//! Body previews, see `limits.preview_length`: the first characters of the
+//! body as plain text, cut on character boundaries.
+
+mod common;
+
+use prism::Prism;
+use std::sync::MutexGuard;
+
+fn setup(length: usize) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| config.limits.preview_length = length)
+}
+
+fn preview(prism: &Prism, id: i64, content_type: &str, body: &[u8]) -> String {
+    let uri = format!("http://preview.example.com/{}", id);
+    let document = common::run(prism, id, &uri, &[("Content-Type", content_type)], body);
+    document["body_preview"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn html_and_json_keep_their_first_characters() {
+    let (prism, _serial) = setup(16);
+    let html = b"<html>\n\t<body>hello</body></html>";
+    assert_eq!(
+        preview(&prism, 55001, "text/html", html),
+        "<html>  <body>he"
+    );
+    let json = br#"{"greeting": "hello", "count": 2}"#;
+    assert_eq!(
+        preview(&prism, 55002, "application/json", json),
+        r#"{"greeting": "he"#
+    );
+}
+
+#[test]
+fn multibyte_characters_are_counted_whole() {
+    let (prism, _serial) = setup(3);
+    let body = "a€é€ü".as_bytes();
+    assert_eq!(
+        preview(&prism, 55101, "text/plain; charset=utf-8", body),
+        "a€é"
+    );
+}
+
+#[test]
+fn a_character_cut_at_the_end_is_left_out() {
+    let (prism, _serial) = setup(512);
+    // Ends in the middle of the second euro sign, which is left out rather
+    // than replaced or making the body binary.
+    let body = &"€€".as_bytes()[..5];
+    assert_eq!(preview(&prism, 55102, "text/plain", body), "€");
+}
+
+#[test]
+fn truncated_bodies_are_previewed_up_to_the_last_whole_character() {
+    let (prism, _serial) = common::setup(|config| config.limits.max_body_size = Some(5));
+    let document = common::run(
+        &prism,
+        55201,
+        "http://preview.example.com/55201",
+        &[("Content-Type", "text/plain; charset=utf-8")],
+        "€€€".as_bytes(),
+    );
+    assert_eq!(document["body_preview"], "€");
+}
+
+#[test]
+fn latin1_bodies_are_converted() {
+    let (prism, _serial) = setup(512);
+    let body = b"caf\xe9 cr\xe8me";
+    assert_eq!(
+        preview(&prism, 55301, "text/plain; charset=ISO-8859-1", body),
+        "café crème"
+    );
+}
+
+#[test]
+fn binary_bodies_get_an_empty_preview() {
+    let (prism, _serial) = setup(512);
+    assert_eq!(
+        preview(
+            &prism,
+            55401,
+            "application/octet-stream",
+            b"PK\x03\x04\0\0data"
+        ),
+        ""
+    );
+    assert_eq!(
+        preview(&prism, 55402, "text/plain", b"\xff\xfe\xfa text"),
+        ""
+    );
+}
+
+#[test]
+fn control_characters_are_stripped() {
+    let (prism, _serial) = setup(512);
+    assert_eq!(
+        preview(&prism, 55501, "text/plain", b"bell\x07 escape\x1b[0m"),
+        "bell escape[0m"
+    );
+}
@@ -0,0 +1,83 @@
+//! Query strings decoded into the `query_params` field of documents.
+
+mod common;
+
+use common::setup;
+use prism::Prism;
+use serde_json::{json, Value};
+
+/// Runs a transaction for `uri` through and returns its document.
+fn run(prism: &Prism, id: i64, uri: &str) -> Value {
+    common::run(prism, id, uri, &[("Content-Type", "text/plain")], b"hello")
+}
+
+#[test]
+fn repeated_keys_hold_every_value() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(&prism, 6001, "http://q.example.com/?tag=a&id=7&tag=b&tag=c");
+    assert_eq!(
+        document["query_params"],
+        json!({"tag": ["a", "b", "c"], "id": "7"})
+    );
+    assert!(document.get("query_params_overflow").is_none());
+}
+
+#[test]
+fn keys_and_values_are_decoded() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        6002,
+        "http://q.example.com/search?q=caf%C3%A9+cr%C3%A8me&search%20terms=a%2Bb&bad=%zz%4#top",
+    );
+    assert_eq!(
+        document["query_params"],
+        json!({"q": "café crème", "search terms": "a+b", "bad": "%zz%4"})
+    );
+}
+
+#[test]
+fn empty_values_are_kept() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(&prism, 6003, "http://q.example.com/?empty=&flag&&=orphan");
+    assert_eq!(
+        document["query_params"],
+        json!({"empty": "", "flag": "", "": "orphan"})
+    );
+
+    let document = run(&prism, 6004, "http://q.example.com/page?");
+    assert!(document.get("query_params").is_none());
+}
+
+#[test]
+fn parameters_past_the_maximum_are_left_out() {
+    let (prism, _serial) = setup(|config| config.query_params.max = 2);
+    let document = run(&prism, 6005, "http://q.example.com/?a=1&b=2&a=3&c=4");
+    assert_eq!(document["query_params"], json!({"a": "1", "b": "2"}));
+    assert_eq!(document["query_params_overflow"], true);
+
+    let document = run(&prism, 6006, "http://q.example.com/?a=1&b=2");
+    assert!(document.get("query_params_overflow").is_none());
+}
+
+#[test]
+fn keys_are_lowercased_when_configured() {
+    let (prism, _serial) = setup(|config| config.query_params.lowercase_keys = true);
+    let document = run(
+        &prism,
+        6007,
+        "http://q.example.com/?Q=Rust&q=Prism&%C3%89T%C3%89=1",
+    );
+    assert_eq!(
+        document["query_params"],
+        json!({"q": ["Rust", "Prism"], "été": "1"})
+    );
+}
+
+#[test]
+fn query_strings_are_left_unparsed_without_a_maximum() {
+    let (prism, _serial) = setup(|config| config.query_params.max = 0);
+    let document = run(&prism, 6008, "http://q.example.com/?a=1");
+    assert!(document.get("query_params").is_none());
+    assert!(document.get("query_params_overflow").is_none());
+}
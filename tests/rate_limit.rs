@@ -0,0 +1,83 @@
+//! Persistence paced by the rate limits, with documents waiting for them in
+//! the bounded queue.
+
+mod common;
+
+use common::{dump, TIMEOUT};
+use prism::config::{self, Limits};
+use prism::{memory, Prism};
+use std::ops::RangeInclusive;
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant};
+
+fn setup(configure: impl FnOnce(&mut Limits)) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.limits.coalesce_size = 0;
+        configure(&mut config.limits);
+    })
+}
+
+/// Runs the transactions `ids` through, whose documents then get queued.
+fn submit(prism: &Prism, ids: RangeInclusive<i64>) {
+    for id in ids {
+        common::relay(
+            prism,
+            id,
+            &format!("http://rate.example.com/{}", id),
+            &[("Content-Type", "text/plain")],
+            b"hello",
+        );
+    }
+}
+
+#[test]
+fn bursts_are_paced_past_a_second_worth() {
+    let (prism, _serial) = setup(|limits| limits.persist_rate = Some(20.0));
+    submit(&prism, 10001..=10030);
+
+    let mut persisted: Vec<Instant> = (10001..=10030)
+        .map(|id| memory::wait(id, TIMEOUT).expect("document persisted"))
+        .map(|document| document.persisted_at)
+        .collect();
+    persisted.sort();
+    // A second worth goes at once, the rest one every 50ms.
+    let paced = persisted[29] - persisted[19];
+    assert!(paced >= Duration::from_millis(400), "{:?}", paced);
+    let dump = dump(&prism);
+    assert!(dump["counters"]["persist_delayed"].as_u64().unwrap() >= 1);
+}
+
+#[test]
+fn documents_waiting_for_the_limit_count_against_the_queue() {
+    let (prism, _serial) = setup(|limits| {
+        limits.persist_rate = Some(1.0);
+        limits.max_queued_documents = 3;
+    });
+    submit(&prism, 10101..=10110);
+    memory::wait(10101, TIMEOUT).expect("document persisted");
+
+    // One document went, three wait for the next second and the rest
+    // overflowed the queue.
+    let queue = &dump(&prism)["queue"];
+    assert_eq!(queue["flushed"], 1);
+    assert_eq!(queue["documents"], 3);
+    assert_eq!(queue["dropped"], 6);
+}
+
+#[test]
+fn rates_follow_reloads() {
+    let (prism, _serial) = setup(|limits| limits.persist_rate = Some(0.5));
+    submit(&prism, 10201..=10204);
+    memory::wait(10201, TIMEOUT).expect("document persisted");
+    assert!(memory::find(10202).is_none());
+
+    let mut config = (*config::get()).clone();
+    config.limits.persist_rate = None;
+    config::set(config);
+    let reloaded = Instant::now();
+    for id in 10202..=10204 {
+        memory::wait(id, TIMEOUT).expect("document persisted");
+    }
+    // Paced, the last one would have taken another 6 seconds.
+    assert!(reloaded.elapsed() < Duration::from_secs(3));
+}
@@ -0,0 +1,150 @@
+//! Records a trace of the transactions `prism-stress` runs, then replays it
+//! through the library API, checking every document it recorded comes out
+//! the same.
+
+use base64::{engine::general_purpose, Engine};
+use prism::clock::{self, ManualClock};
+use prism::config::Config;
+use prism::{memory, Prism, TransactionHandle};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Large enough for any chunk `send()` hands back at once.
+const OUTPUT_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+fn temp(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("prism-recorder-{}-{}", std::process::id(), name))
+}
+
+/// Runs `prism-stress` for a second with `config`, returning its trace.
+fn record(config: &Path) -> Vec<Value> {
+    let output = Command::new(env!("CARGO_BIN_EXE_prism-stress"))
+        .arg("--config")
+        .arg(config)
+        .args(["--threads", "1", "--concurrency", "4", "--duration", "1"])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let config = Config::load(Some(config)).unwrap();
+    let trace = std::fs::read_to_string(config.recorder.path.unwrap()).unwrap();
+    trace
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn text(entry: &Value, field: &str) -> String {
+    entry[field].as_str().unwrap().to_string()
+}
+
+/// Digest of a persisted document, computed the way the recorder does.
+fn digest(mut json: Value) -> String {
+    let object = json.as_object_mut().unwrap();
+    object.remove("date");
+    object.remove("document_id");
+    object.remove("run_id");
+    Sha256::digest(json.to_string().as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Replays the calls of `trace` against a clock following it, returning
+/// the digests of the documents recorded, by transaction.
+fn replay(prism: &Prism, trace: &[Value]) -> Vec<(i64, String)> {
+    let (header, entries) = trace.split_first().unwrap();
+    assert_eq!(header["version"], 1);
+    assert_eq!(header["bodies"], "full");
+    let frozen = ManualClock::at(text(header, "started").parse().unwrap());
+    clock::set(frozen.clone());
+
+    let mut at = 0;
+    let mut headers: HashMap<i64, Vec<(String, String)>> = HashMap::new();
+    let mut handles: HashMap<i64, TransactionHandle> = HashMap::new();
+    let mut documents = Vec::new();
+    let mut buffer = vec![0; OUTPUT_BUFFER_SIZE];
+    for entry in entries {
+        let next = entry["at"].as_u64().unwrap();
+        frozen.advance(Duration::from_micros(next - at));
+        at = next;
+        let id = entry["id"].as_i64().unwrap();
+        match entry["call"].as_str().unwrap() {
+            "header" => {
+                let (name, value) = (text(entry, "name"), text(entry, "value"));
+                match handles.get(&id) {
+                    Some(handle) => handle.header(&name, &value),
+                    None => headers.entry(id).or_default().push((name, value)),
+                }
+            }
+            "uri" => {
+                let headers = headers.remove(&id).unwrap_or_default();
+                let headers: Vec<_> = headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str()))
+                    .collect();
+                let handle = prism.begin(id, &text(entry, "method"), &text(entry, "uri"), &headers);
+                handles.insert(id, handle);
+            }
+            "receive" => {
+                let data = general_purpose::STANDARD
+                    .decode(text(entry, "data"))
+                    .unwrap();
+                assert_eq!(data.len() as u64, entry["size"].as_u64().unwrap());
+                handles[&id].receive(&data).unwrap();
+            }
+            "send" => {
+                let size = handles.get_mut(&id).unwrap().poll_output(&mut buffer);
+                assert_eq!(size as u64, entry["size"].as_u64().unwrap());
+            }
+            "done" => handles[&id].done(),
+            "block" => handles[&id].block(&text(entry, "reason")).unwrap(),
+            "annotate" => handles[&id]
+                .annotate(&text(entry, "key"), &text(entry, "value"))
+                .unwrap(),
+            "document" => documents.push((id, text(entry, "sha256"))),
+            "cleanup" => drop(handles.remove(&id)),
+            call => panic!("unknown call {}", call),
+        }
+    }
+    clock::reset();
+    documents
+}
+
+#[test]
+fn stress_trace_replays_to_identical_documents() {
+    let trace = temp("trace.jsonl");
+    let config = temp("config.toml");
+    let _ = std::fs::remove_file(&trace);
+    std::fs::write(
+        &config,
+        format!(
+            "[recorder]\npath = \"{}\"\nbodies = \"full\"\nmax_transactions = 6\n\n\
+             [limits]\ncoalesce_size = 0\n",
+            trace.display()
+        ),
+    )
+    .unwrap();
+
+    let recorded = record(&config);
+    let mut replayed = Config::load(Some(&config)).unwrap();
+    replayed.recorder.path = None;
+    replayed.backend.kind = "memory".to_string();
+    let prism = Prism::new(replayed).unwrap();
+    let documents = replay(&prism, &recorded);
+    assert!(!documents.is_empty(), "no document recorded");
+    for (id, sha256) in documents {
+        let document = memory::wait(id, TIMEOUT).expect("document persisted");
+        assert_eq!(digest(document.json), sha256, "document {}", id);
+    }
+    std::fs::remove_file(&trace).unwrap();
+    std::fs::remove_file(&config).unwrap();
+}
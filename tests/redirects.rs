@@ -0,0 +1,127 @@
+//! Redirects: 3xx responses with a `Location` header, and the absolute URL
+//! they point to.
+
+mod common;
+
+use common::setup;
+use prism::Prism;
+use serde_json::Value;
+
+/// Runs a GET of `uri` answered with `status` and `headers` through, and
+/// returns its document.
+fn run(prism: &Prism, id: i64, uri: &str, status: u16, headers: &[(&str, &str)]) -> Value {
+    let mut handle = prism.begin(id, "GET", uri, headers);
+    handle.status(status);
+    handle.done();
+    common::drain(&mut handle);
+    drop(handle);
+    common::document(id)
+}
+
+#[test]
+fn relative_locations_of_origin_form_uris_are_redirects() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        24001,
+        "/old",
+        302,
+        &[("Host", "redirects.example.com"), ("Location", "/new")],
+    );
+    assert_eq!(document["is_redirect"], true);
+    assert!(document.get("redirect_location").is_none());
+}
+
+#[test]
+fn relative_locations_are_resolved_against_the_uri() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        24002,
+        "http://redirects.example.com/a/b/c?page=1",
+        301,
+        &[("Location", "../d?page=2")],
+    );
+    assert_eq!(document["is_redirect"], true);
+    assert_eq!(
+        document["redirect_location"],
+        "http://redirects.example.com/a/d?page=2"
+    );
+}
+
+#[test]
+fn protocol_relative_locations_keep_the_scheme() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        24003,
+        "https://redirects.example.com/asset",
+        302,
+        &[("Location", "//cdn.example.com/asset")],
+    );
+    assert_eq!(document["is_redirect"], true);
+    assert_eq!(
+        document["redirect_location"],
+        "https://cdn.example.com/asset"
+    );
+}
+
+#[test]
+fn absolute_locations_are_kept() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        24004,
+        "http://redirects.example.com/",
+        307,
+        &[("Location", "https://elsewhere.example.com/landing")],
+    );
+    assert_eq!(
+        document["redirect_location"],
+        "https://elsewhere.example.com/landing"
+    );
+}
+
+#[test]
+fn not_modified_responses_without_location_are_not_redirects() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        24005,
+        "http://redirects.example.com/cached",
+        304,
+        &[],
+    );
+    assert_eq!(document["is_redirect"], false);
+    assert!(document.get("redirect_location").is_none());
+}
+
+#[test]
+fn locations_of_other_statuses_are_not_redirects() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(
+        &prism,
+        24006,
+        "http://redirects.example.com/created",
+        201,
+        &[("Location", "/created/1")],
+    );
+    assert_eq!(document["is_redirect"], false);
+    assert!(document.get("redirect_location").is_none());
+}
+
+#[test]
+fn redirect_chains_link_by_location() {
+    let (prism, _serial) = setup(|_| {});
+    let first = run(
+        &prism,
+        24007,
+        "http://redirects.example.com/start",
+        301,
+        &[("Location", "/next")],
+    );
+    let next = run(&prism, 24008, "http://redirects.example.com/next", 200, &[]);
+    assert_eq!(first["redirect_location"], next["uri"]);
+    assert_eq!(first["host"], "redirects.example.com");
+    assert_eq!(first["host"], next["host"]);
+}
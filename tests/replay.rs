@@ -0,0 +1,52 @@
+//! Runs `prism-replay` over the recordings in `tests/fixtures/replay`.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+fn fixtures() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay")
+}
+
+fn replay() -> Vec<serde_json::Value> {
+    let output = Command::new(env!("CARGO_BIN_EXE_prism-replay"))
+        .arg(fixtures())
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn recordings_are_replayed_in_name_order() {
+    let reports = replay();
+    let names: Vec<_> = reports
+        .iter()
+        .map(|report| report["recording"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["gzip-page", "identity-page"]);
+    for report in &reports {
+        assert!(report["document"].is_object(), "{}", report);
+    }
+}
+
+#[test]
+fn identity_recording_is_relayed_unchanged() {
+    let reports = replay();
+    let report = &reports[1];
+    let page = std::fs::read(fixtures().join("page.html")).unwrap();
+    assert_eq!(report["bytes_received"].as_u64(), Some(page.len() as u64));
+    assert_eq!(report["bytes_sent"].as_u64(), Some(page.len() as u64));
+}
+
+#[test]
+fn replays_print_the_same_output() {
+    assert_eq!(replay(), replay());
+}
@@ -0,0 +1,121 @@
+//! Retention sweeps removing old documents from the backends supporting
+//! them, and keeping recent ones.
+
+mod common;
+
+use common::TIMEOUT;
+use prism::clock::{self, ManualClock};
+use prism::config::Config;
+use prism::{memory, retention, Prism};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant, SystemTime};
+
+const DATE: &str = "2024-01-01T00:00:00Z";
+const DAY: u64 = 24 * 60 * 60;
+
+fn setup(configure: impl FnOnce(&mut Config)) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        clock::reset();
+        memory::clear();
+        config.limits.coalesce_size = 0;
+        config.retention.max_age = Some(DAY);
+        configure(config);
+    })
+}
+
+/// Runs a transaction through, whose document then gets persisted.
+fn run(prism: &Prism, id: i64) {
+    common::relay(
+        prism,
+        id,
+        &format!("http://retention.example.com/{}", id),
+        &[("Content-Type", "text/plain")],
+        b"hello",
+    );
+}
+
+/// The HAR files in `directory`.
+fn har_files(directory: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(directory)
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "har"))
+        .collect()
+}
+
+/// Waits for the HAR file holding the document of transaction `id`.
+fn wait_har_file(directory: &Path, id: i64) -> PathBuf {
+    let deadline = Instant::now() + TIMEOUT;
+    let url = format!("http://retention.example.com/{}", id);
+    loop {
+        let written = har_files(directory)
+            .into_iter()
+            .find(|path| std::fs::read_to_string(path).is_ok_and(|har| har.contains(&url)));
+        if let Some(path) = written {
+            return path;
+        }
+        assert!(Instant::now() < deadline, "document {} not written", id);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn old_documents_are_removed_from_memory() {
+    let (prism, _serial) = setup(|_| {});
+    let frozen = ManualClock::at(DATE.parse().unwrap());
+    clock::set(frozen.clone());
+    run(&prism, 11001);
+    memory::wait(11001, TIMEOUT).expect("document persisted");
+    frozen.advance(Duration::from_secs(2 * DAY));
+    run(&prism, 11002);
+    memory::wait(11002, TIMEOUT).expect("document persisted");
+
+    let removed = retention::sweep();
+    clock::reset();
+    assert_eq!(removed, Ok(1));
+    assert!(memory::find(11001).is_none());
+    assert!(memory::find(11002).is_some());
+}
+
+#[test]
+fn documents_are_kept_without_a_maximum_age() {
+    let (prism, _serial) = setup(|config| config.retention.max_age = None);
+    let frozen = ManualClock::at(DATE.parse().unwrap());
+    clock::set(frozen.clone());
+    run(&prism, 11003);
+    memory::wait(11003, TIMEOUT).expect("document persisted");
+    frozen.advance(Duration::from_secs(365 * DAY));
+
+    let removed = retention::sweep();
+    clock::reset();
+    assert_eq!(removed, Ok(0));
+    assert!(memory::find(11003).is_some());
+}
+
+#[test]
+fn old_har_files_are_removed() {
+    let directory = std::env::temp_dir().join(format!("prism-retention-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let (prism, _serial) = setup(|config| {
+        config.backend.kind = "har".to_string();
+        config.backend.har.directory = Some(directory.clone());
+        config.backend.har.entries_per_file = Some(1);
+    });
+    run(&prism, 11101);
+    let old = wait_har_file(&directory, 11101);
+    run(&prism, 11102);
+    let recent = wait_har_file(&directory, 11102);
+    File::options()
+        .write(true)
+        .open(&old)
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(2 * DAY))
+        .unwrap();
+
+    assert_eq!(retention::sweep(), Ok(1));
+    assert!(!old.exists());
+    assert!(recent.exists());
+    std::fs::remove_dir_all(&directory).unwrap();
+}
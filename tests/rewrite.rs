@@ -0,0 +1,119 @@
+//! Find/replace rules registered with `rewrite_rule()`, applied to decoded
+//! text bodies before they are encoded again, matches spanning chunks
+//! included.
+
+mod common;
+
+use common::{gunzip, gzip, take, TIMEOUT};
+use prism::{memory, Prism};
+use std::sync::MutexGuard;
+
+const MARKER: &str = "<!-- compliance banner -->";
+const BANNER: &str = "<div class=\"banner\">This page is monitored</div>";
+
+fn setup() -> (Prism, MutexGuard<'static, ()>) {
+    let (prism, serial) = common::setup(|_| {});
+    prism.rewrite_rule(MARKER.into(), BANNER.into());
+    (prism, serial)
+}
+
+fn page(marker: &str) -> Vec<u8> {
+    format!(
+        "<html><body>{}\n{}</body></html>\n",
+        marker,
+        "<p>page content</p>\n".repeat(500)
+    )
+    .into_bytes()
+}
+
+#[test]
+fn matches_split_across_chunks_are_rewritten() {
+    let (prism, _serial) = setup();
+    let received = page(MARKER);
+    let length = received.len().to_string();
+    let mut handle = prism.begin(
+        30001,
+        "GET",
+        "http://rewrite.example.com/",
+        &[("Content-Type", "text/html"), ("Content-Length", &length)],
+    );
+    handle.status(200);
+    // Split inside the marker, one byte of it in a chunk of its own.
+    let start = received
+        .windows(MARKER.len())
+        .position(|window| window == MARKER.as_bytes())
+        .unwrap();
+    let splits = [start + 5, start + 6, start + 20, received.len()];
+    let mut output = Vec::new();
+    let mut offset = 0;
+    for end in splits {
+        handle.receive(&received[offset..end]).unwrap();
+        output.extend(take(&mut handle));
+        offset = end;
+    }
+    handle.done();
+    output.extend(common::drain(&mut handle));
+    // The emitted size no longer matches the origin's.
+    assert_eq!(
+        handle.response_header("Content-Length").as_deref(),
+        Some("")
+    );
+    drop(handle);
+
+    assert_eq!(output, page(BANNER));
+    // The captured body is the one received.
+    assert_eq!(memory::wait(30001, TIMEOUT).unwrap().body, received);
+}
+
+#[test]
+fn gzip_streams_decode_to_the_rewritten_page() {
+    let (prism, _serial) = setup();
+    let received = page(MARKER);
+    let encoded = gzip(&received);
+    let mut handle = prism.begin(
+        30002,
+        "GET",
+        "http://rewrite.example.com/encoded",
+        &[("Content-Type", "text/html"), ("Content-Encoding", "gzip")],
+    );
+    handle.status(200);
+    let mut output = Vec::new();
+    for chunk in encoded.chunks(7) {
+        handle.receive(chunk).unwrap();
+        output.extend(take(&mut handle));
+    }
+    handle.done();
+    output.extend(common::drain(&mut handle));
+    drop(handle);
+
+    assert_eq!(gunzip(&output), page(BANNER));
+    assert_eq!(memory::wait(30002, TIMEOUT).unwrap().body, received);
+}
+
+#[test]
+fn partial_matches_at_the_end_are_kept() {
+    let (prism, _serial) = setup();
+    let body = b"<p>cut short</p><!-- compliance".to_vec();
+    let output = common::relay(
+        &prism,
+        30003,
+        "http://rewrite.example.com/short",
+        &[("Content-Type", "text/html")],
+        &body,
+    );
+    assert_eq!(output, body);
+}
+
+#[test]
+fn binary_bodies_are_not_rewritten() {
+    let (prism, _serial) = setup();
+    let body = page(MARKER);
+    let output = common::relay(
+        &prism,
+        30004,
+        "http://rewrite.example.com/blob",
+        &[("Content-Type", "application/octet-stream")],
+        &body,
+    );
+    assert_eq!(output, body);
+}
@@ -0,0 +1,132 @@
+//! Rotation of the audit log by size and age, compression of the rotated
+//! files and removal of the oldest ones.
+
+mod common;
+
+use common::TIMEOUT;
+use flate2::read::GzDecoder;
+use prism::clock::{self, ManualClock};
+use prism::config::Rotation;
+use prism::Prism;
+use serde_json::Value;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant};
+
+/// Directory of the audit log, the same for all tests as the audit log is
+/// opened once per process.
+fn directory() -> PathBuf {
+    std::env::temp_dir().join(format!("prism-rotation-{}", std::process::id()))
+}
+
+fn setup(configure: impl FnOnce(&mut Rotation)) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        clock::reset();
+        std::fs::create_dir_all(directory()).unwrap();
+        config.limits.coalesce_size = 0;
+        config.audit.path = Some(directory().join("audit.log"));
+        configure(&mut config.rotation);
+    })
+}
+
+/// Runs a transaction through and waits for its audit log line.
+fn run(prism: &Prism, id: i64) {
+    common::run(
+        prism,
+        id,
+        &format!("http://rotation.example.com/{}", id),
+        &[("Content-Type", "text/plain")],
+        b"hello",
+    );
+
+    let deadline = Instant::now() + TIMEOUT;
+    let line = format!("\"transaction_id\":{},", id);
+    while !std::fs::read_to_string(directory().join("audit.log"))
+        .is_ok_and(|log| log.contains(&line))
+    {
+        assert!(Instant::now() < deadline, "transaction {} not audited", id);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// The rotated audit logs, oldest first.
+fn rotated() -> Vec<PathBuf> {
+    let mut rotated: Vec<PathBuf> = std::fs::read_dir(directory())
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            name.starts_with("audit.log.") && !name.ends_with(".tmp")
+        })
+        .collect();
+    rotated.sort();
+    rotated
+}
+
+/// The transactions audited in a log, compressed or not, checking that
+/// every line is whole.
+fn audited(path: &Path) -> Vec<i64> {
+    let mut log = String::new();
+    let mut file = std::fs::File::open(path).unwrap();
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        GzDecoder::new(file).read_to_string(&mut log).unwrap();
+    } else {
+        file.read_to_string(&mut log).unwrap();
+    }
+    assert!(log.ends_with('\n'), "{}", path.display());
+    log.lines()
+        .map(|line| {
+            let line: Value = serde_json::from_str(line).unwrap();
+            line["transaction_id"].as_i64().unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn logs_are_rotated_by_size_keeping_the_newest() {
+    let (prism, _serial) = setup(|rotation| {
+        rotation.max_size = 1;
+        rotation.keep = 3;
+    });
+    for id in 13001..=13006 {
+        run(&prism, id);
+    }
+
+    let rotated = rotated();
+    assert_eq!(rotated.len(), 3, "{:?}", rotated);
+    let logs: Vec<Vec<i64>> = rotated.iter().map(|path| audited(path)).collect();
+    assert_eq!(logs, [[13003], [13004], [13005]]);
+    assert_eq!(audited(&directory().join("audit.log")), [13006]);
+}
+
+#[test]
+fn logs_are_rotated_by_age_and_compressed() {
+    let (prism, _serial) = setup(|rotation| {
+        rotation.max_age = Some(60);
+        rotation.keep = 2;
+        rotation.compress = true;
+    });
+    // Ahead of the time the log was last opened at, by earlier tests.
+    let frozen = ManualClock::at(chrono::Utc::now() + chrono::Duration::seconds(24 * 60 * 60));
+    clock::set(frozen.clone());
+    for id in 13101..=13103 {
+        run(&prism, id);
+        frozen.advance(Duration::from_secs(61));
+    }
+    clock::reset();
+
+    let deadline = Instant::now() + TIMEOUT;
+    while !rotated()
+        .iter()
+        .all(|path| path.extension().is_some_and(|extension| extension == "gz"))
+    {
+        assert!(Instant::now() < deadline, "{:?} not compressed", rotated());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let rotated = rotated();
+    assert_eq!(rotated.len(), 2, "{:?}", rotated);
+    let logs: Vec<Vec<i64>> = rotated.iter().map(|path| audited(path)).collect();
+    assert_eq!(logs, [[13101], [13102]]);
+    assert_eq!(audited(&directory().join("audit.log")), [13103]);
+}
@@ -0,0 +1,210 @@
+//! Property tests feeding generated bodies through the pipeline in arbitrary
+//! chunks, checking that what `send()` hands back decodes to what was
+//! received, and that the captured body is the decoded one. Failing cases
+//! shrink to the smallest body and chunk sizes, and proptest keeps their
+//! seeds to run them first from then on.
+
+mod common;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prism::{memory, Prism};
+use proptest::prelude::*;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{MutexGuard, OnceLock};
+use std::time::Duration;
+
+/// How long a case waits for the persistence workers.
+const PERSIST_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BODY_SIZE: usize = 5 * 1024 * 1024;
+
+static PRISM: OnceLock<Prism> = OnceLock::new();
+static ID: AtomicI64 = AtomicI64::new(0);
+
+/// Every case clears the persisted documents, so they all hold the lock.
+fn setup() -> (&'static Prism, MutexGuard<'static, ()>) {
+    let prism = PRISM.get_or_init(|| {
+        let mut config = common::config();
+        config.limits.coalesce_size = 0;
+        Prism::new(config).unwrap()
+    });
+    let serial = common::serial();
+    memory::clear();
+    (prism, serial)
+}
+
+/// How well a generated body compresses.
+#[derive(Clone, Copy, Debug)]
+enum Content {
+    Random,
+    /// Random bytes out of 4.
+    Alphabet,
+    Text,
+    Zeros,
+}
+
+fn payload(size: usize, content: Content, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    match content {
+        Content::Random => (0..size).map(|_| next() as u8).collect(),
+        Content::Alphabet => (0..size).map(|_| b"acgt"[next() as usize % 4]).collect(),
+        Content::Text => {
+            let mut text = Vec::with_capacity(size + 64);
+            while text.len() < size {
+                writeln!(text, "<p>line {} of the generated page</p>", next() % 1000).unwrap();
+            }
+            text.truncate(size);
+            text
+        }
+        Content::Zeros => vec![0; size],
+    }
+}
+
+fn gzip(body: &[u8], level: u32) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn gunzip(body: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    GzDecoder::new(body).read_to_end(&mut decoded).unwrap();
+    decoded
+}
+
+/// Runs a transaction receiving `body` in chunks of the sizes in `splits`,
+/// over and over, handing output back after each. Returns the output, and
+/// the body as persisted.
+fn exchange(
+    prism: &Prism,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    splits: &[usize],
+) -> (Vec<u8>, Vec<u8>) {
+    let id = ID.fetch_add(1, Ordering::Relaxed);
+    let mut handle = prism.begin(id, "GET", "http://roundtrip.example.com/", headers);
+    handle.status(200);
+
+    let mut buffer = vec![0; 64 * 1024];
+    let mut output = Vec::new();
+    let mut poll = |handle: &mut prism::TransactionHandle| loop {
+        let size = handle.poll_output(&mut buffer);
+        if size == 0 {
+            break;
+        }
+        output.extend_from_slice(&buffer[0..size]);
+    };
+    let mut rest = body;
+    for size in splits.iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let (chunk, tail) = rest.split_at((*size).min(rest.len()));
+        handle.receive(chunk).unwrap();
+        poll(&mut handle);
+        rest = tail;
+    }
+    handle.done();
+    while !handle.finished() {
+        poll(&mut handle);
+    }
+
+    let document = memory::wait(id, PERSIST_TIMEOUT).expect("document persisted");
+    assert!(!document.truncated);
+    (output, document.body)
+}
+
+fn content() -> impl Strategy<Value = Content> {
+    prop_oneof![
+        Just(Content::Random),
+        Just(Content::Alphabet),
+        Just(Content::Text),
+        Just(Content::Zeros),
+    ]
+}
+
+/// Mostly small bodies, which shrink well, with the odd large one.
+fn size() -> impl Strategy<Value = usize> {
+    prop_oneof![
+        4 => 0..4096usize,
+        3 => 4096..256 * 1024usize,
+        1 => 256 * 1024..=MAX_BODY_SIZE,
+    ]
+}
+
+/// Chunk sizes, used in turn until the body is received.
+fn splits() -> impl Strategy<Value = Vec<usize>> {
+    prop::collection::vec(1..=64 * 1024usize, 1..16)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig {
+        cases: 24,
+        max_shrink_iters: 512,
+        ..ProptestConfig::default()
+    })]
+
+    #[test]
+    fn gzip_body_round_trips(
+        size in size(),
+        content in content(),
+        seed in any::<u64>(),
+        level in 0..=9u32,
+        splits in splits(),
+    ) {
+        let (prism, _serial) = setup();
+        let body = payload(size, content, seed);
+        let (output, captured) = exchange(
+            prism,
+            &[("Content-Type", "text/html"), ("Content-Encoding", "gzip")],
+            &gzip(&body, level),
+            &splits,
+        );
+        prop_assert!(gunzip(&output) == body, "output differs from the body");
+        prop_assert!(captured == body, "captured body differs");
+    }
+
+    #[test]
+    fn identity_body_round_trips(
+        size in size(),
+        content in content(),
+        seed in any::<u64>(),
+        splits in splits(),
+    ) {
+        let (prism, _serial) = setup();
+        let body = payload(size, content, seed);
+        let (output, captured) =
+            exchange(prism, &[("Content-Type", "text/html")], &body, &splits);
+        prop_assert!(output == body, "output differs from the body");
+        prop_assert!(captured == body, "captured body differs");
+    }
+}
+
+/// Chunks ending inside the gzip header, at the default input buffer size,
+/// and bodies spanning several encoder buffers.
+#[test]
+fn gzip_boundaries_round_trip() {
+    let (prism, _serial) = setup();
+    let headers = [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+    let cases: [(usize, Content, &[usize]); 5] = [
+        (0, Content::Text, &[1]),
+        (1, Content::Random, &[1]),
+        (32 * 1024, Content::Random, &[10, 32 * 1024]),
+        (64 * 1024, Content::Text, &[32 * 1024 - 1, 1]),
+        (3 * 1024 * 1024, Content::Random, &[64 * 1024]),
+    ];
+    for (size, content, splits) in cases {
+        let body = payload(size, content, 42);
+        let (output, captured) = exchange(prism, &headers, &gzip(&body, 6), splits);
+        assert!(gunzip(&output) == body, "{} bytes, {:?}", size, splits);
+        assert!(captured == body, "{} bytes, {:?}", size, splits);
+    }
+}
@@ -0,0 +1,126 @@
+//! Routing of documents to other indices by content type and tag, and the
+//! default index documents no route matches stay in.
+
+mod common;
+
+use prism::config::{Config, Route};
+use prism::Prism;
+use serde_json::Value;
+use std::sync::MutexGuard;
+
+fn route(content_types: &[&str], tag: Option<&str>, index: &str) -> Route {
+    Route {
+        content_types: content_types.iter().map(|c| c.to_string()).collect(),
+        tag: tag.map(str::to_string),
+        index: index.to_string(),
+    }
+}
+
+fn setup(configure: impl FnOnce(&mut Config)) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.limits.coalesce_size = 0;
+        config.backend.routes = vec![
+            route(&["text/html"], None, "lens-pages"),
+            route(&["application/json"], None, "lens-api"),
+            route(
+                &["application/octet-stream", "application/zip"],
+                None,
+                "lens-downloads",
+            ),
+        ];
+        configure(config);
+    })
+}
+
+/// Runs a transaction answered with `content_type` through and returns its
+/// document.
+fn run(prism: &Prism, id: i64, content_type: &str) -> Value {
+    common::run(
+        prism,
+        id,
+        "http://routing.example.com/",
+        &[("Content-Type", content_type)],
+        b"hello",
+    )
+}
+
+#[test]
+fn documents_are_routed_by_content_type() {
+    let (prism, _serial) = setup(|_| {});
+    for (id, content_type, index) in [
+        (15001, "text/html; charset=utf-8", "lens-pages"),
+        (15002, "Application/JSON", "lens-api"),
+        (15003, "application/octet-stream", "lens-downloads"),
+        (15004, "application/zip", "lens-downloads"),
+    ] {
+        let document = run(&prism, id, content_type);
+        assert_eq!(document["routed_index"], index, "{}", content_type);
+    }
+
+    // Left to the default index.
+    let document = run(&prism, 15005, "text/plain");
+    assert!(document.get("routed_index").is_none());
+}
+
+#[test]
+fn documents_are_routed_by_tag_first_route_winning() {
+    let path =
+        std::env::temp_dir().join(format!("prism-routing-rules-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"
+[[rule]]
+path = "^/private"
+tags = ["private"]
+"#,
+    )
+    .unwrap();
+    let (prism, _serial) = setup(|config| {
+        config.filters.rules = Some(path.clone());
+        config
+            .backend
+            .routes
+            .insert(0, route(&[], Some("private"), "lens-private"));
+    });
+    let private = common::run(
+        &prism,
+        15101,
+        "http://routing.example.com/private/page",
+        &[("Content-Type", "text/html")],
+        b"hello",
+    );
+    let public = run(&prism, 15102, "text/html");
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(private["routed_index"], "lens-private");
+    assert_eq!(public["routed_index"], "lens-pages");
+}
+
+#[test]
+fn routes_need_a_condition_and_an_index() {
+    let _serial = common::serial();
+    let mut config = Config::load(None).unwrap();
+    config.backend.routes = vec![
+        route(&[], None, "lens-everything"),
+        route(&["text/html"], None, ""),
+        route(&["text/html"], None, "Lens-Pages"),
+    ];
+    let errors: Vec<String> = config
+        .validated()
+        .unwrap_err()
+        .iter()
+        .map(|error| error.to_string())
+        .collect();
+    for path in [
+        "backend.route[0]",
+        "backend.route[1].index",
+        "backend.route[2].index",
+    ] {
+        assert!(
+            errors.iter().any(|error| error.starts_with(path)),
+            "{} in {:?}",
+            path,
+            errors
+        );
+    }
+}
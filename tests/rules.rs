@@ -0,0 +1,240 @@
+//! Rules of `filters.rules`: checked in order, every matching rule applying
+//! its actions until one marked `last`, reloaded on their own through
+//! `reload_rules()`, and named by position and name when invalid.
+
+mod common;
+
+use common::{drain, state};
+use prism::error::PrismError;
+use prism::memory;
+use prism::{Prism, TransactionHandle};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::MutexGuard;
+
+const RULES: &str = r#"
+[[rule]]
+name = "images"
+host = "*.cdn.example.com"
+content_type = "image/"
+tags = ["image"]
+last = true
+
+[[rule]]
+name = "cdn"
+host = "*.cdn.example.com"
+tags = ["cdn"]
+
+[[rule]]
+name = "health checks"
+path = "^/health"
+skip_persist = true
+
+[[rule]]
+name = "sessions"
+path = "^/(login|account)"
+status = "200-299"
+redact_headers = ["Set-Cookie"]
+tags = ["session"]
+trace = true
+
+[[rule]]
+name = "accounts"
+path = "^/account"
+tags = ["account", "session"]
+"#;
+
+fn setup(rules: &str) -> (Prism, PathBuf, MutexGuard<'static, ()>) {
+    let path = common::temporary("rules.toml");
+    std::fs::write(&path, rules).unwrap();
+    let (prism, serial) = common::setup(|config| {
+        config.limits.coalesce_size = 0;
+        config.filters.rules = Some(path.clone());
+    });
+    (prism, path, serial)
+}
+
+/// Begins a GET of `uri` answered with `status` and `headers`, the rules
+/// being checked against both.
+fn begin(
+    prism: &Prism,
+    id: i64,
+    uri: &str,
+    status: &str,
+    headers: &[(&str, &str)],
+) -> TransactionHandle {
+    let mut headers = headers.to_vec();
+    headers.push((":status", status));
+    prism.begin(id, "GET", uri, &headers)
+}
+
+/// Relays a body through a transaction begun by `begin()`.
+fn finish(mut handle: TransactionHandle) {
+    handle.receive(b"hello").unwrap();
+    handle.done();
+    drain(&mut handle);
+}
+
+fn run(prism: &Prism, id: i64, uri: &str, status: &str, headers: &[(&str, &str)]) -> Value {
+    finish(begin(prism, id, uri, status, headers));
+    common::document(id)
+}
+
+fn reload(path: &Path, rules: &str) -> (i32, String) {
+    std::fs::write(path, rules).unwrap();
+    let code = prism::reload_rules();
+    let chunk = prism::config_errors();
+    let errors = String::from_utf8(common::bytes(chunk.size, chunk.bytes)).unwrap();
+    (code, errors)
+}
+
+#[test]
+fn matching_rules_apply_in_order_until_the_last() {
+    let (prism, path, _serial) = setup(RULES);
+    let image = run(
+        &prism,
+        38001,
+        "http://static.cdn.example.com/logo.png",
+        "200",
+        &[("Content-Type", "image/png")],
+    );
+    let style = run(
+        &prism,
+        38002,
+        "http://static.cdn.example.com/site.css",
+        "200",
+        &[("Content-Type", "text/css")],
+    );
+    let elsewhere = run(
+        &prism,
+        38003,
+        "http://www.example.com/logo.png",
+        "200",
+        &[("Content-Type", "image/png")],
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    // Matching the images rule, the cdn one is never checked.
+    assert_eq!(image["tags"], json!(["image"]));
+    assert_eq!(style["tags"], json!(["cdn"]));
+    assert!(elsewhere.get("tags").is_none());
+}
+
+#[test]
+fn every_action_applies() {
+    let (prism, path, _serial) = setup(RULES);
+    let headers = [
+        ("Content-Type", "text/html"),
+        ("Set-Cookie", "session=secret"),
+    ];
+
+    let handle = begin(
+        &prism,
+        38101,
+        "http://www.example.com/login",
+        "200",
+        &headers,
+    );
+    assert_eq!(state(&prism, 38101)["trace"], true);
+    finish(handle);
+    let login = common::document(38101);
+    assert_eq!(login["tags"], json!(["session"]));
+    assert_eq!(login["response_headers"]["Set-Cookie"], "[REDACTED]");
+    assert_eq!(login["response_headers"]["Content-Type"], "text/html");
+
+    // Actions of several rules merge, tags kept once in rule order.
+    let account = run(
+        &prism,
+        38102,
+        "http://www.example.com/account",
+        "200",
+        &headers,
+    );
+    assert_eq!(account["tags"], json!(["session", "account"]));
+    assert_eq!(account["response_headers"]["Set-Cookie"], "[REDACTED]");
+
+    // Out of the status range, only the accounts rule matches.
+    let handle = begin(
+        &prism,
+        38103,
+        "http://www.example.com/account",
+        "503",
+        &headers,
+    );
+    assert_eq!(state(&prism, 38103)["trace"], false);
+    finish(handle);
+    let failed = common::document(38103);
+    assert_eq!(failed["tags"], json!(["account", "session"]));
+    assert_eq!(failed["response_headers"]["Set-Cookie"], "session=secret");
+
+    run(&prism, 38104, "http://www.example.com/health", "200", &[]);
+    // Documents are persisted in order, so the skipped one would be there.
+    run(&prism, 38105, "http://www.example.com/", "200", &[]);
+    assert!(memory::find(38104).is_none());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn reloads_apply_to_transactions_started_afterwards() {
+    let (prism, path, _serial) = setup(RULES);
+    let started = begin(&prism, 38201, "http://www.example.com/login", "200", &[]);
+
+    let (code, errors) = reload(
+        &path,
+        "[[rule]]\nname = \"everything\"\ntags = [\"edited\"]\n",
+    );
+    assert_eq!(code, 0);
+    assert_eq!(errors, "");
+    finish(started);
+    assert_eq!(common::document(38201)["tags"], json!(["session"]));
+    let edited = run(&prism, 38202, "http://www.example.com/login", "200", &[]);
+    assert_eq!(edited["tags"], json!(["edited"]));
+
+    // Invalid rules are all reported, and the rules in use kept.
+    let (code, errors) = reload(
+        &path,
+        r#"
+[[rule]]
+name = "fine"
+tags = ["fine"]
+
+[[rule]]
+name = "broken"
+path = "^/(login"
+tags = ["broken"]
+
+[[rule]]
+status = "5xx"
+tags = ["unnamed"]
+
+[[rule]]
+name = "idle"
+host = "*.example.com"
+"#,
+    );
+    assert_eq!(code, PrismError::InvalidConfig.code());
+    assert!(errors.starts_with("rule 2 (broken): path: "), "{}", errors);
+    assert!(
+        errors.contains(
+            "\nrule 3: status: \"5xx\" is neither a status code nor a range like 500-599\n"
+        ),
+        "{}",
+        errors
+    );
+    assert!(
+        errors.ends_with("\nrule 4 (idle): actions: the rule has no action"),
+        "{}",
+        errors
+    );
+    let kept = run(&prism, 38203, "http://www.example.com/", "200", &[]);
+    assert_eq!(kept["tags"], json!(["edited"]));
+
+    let (code, errors) = reload(&path, "[[rule]]\nname = \"typo\"\ntag = [\"x\"]\n");
+    assert_eq!(code, PrismError::InvalidConfig.code());
+    assert!(
+        errors.starts_with(&path.display().to_string()),
+        "{}",
+        errors
+    );
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,129 @@
+//! The external scanner, run as `tests/fixtures/scanner/scan.sh` on the
+//! bodies of transactions, with its verdict persisted as `scanner_result`.
+
+mod common;
+
+use prism::{config, Prism};
+use serde_json::Value;
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant};
+
+const SCANNER_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn setup() -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.scanner.command = Some(format!(
+            "sh {}/tests/fixtures/scanner/scan.sh {{id}}",
+            env!("CARGO_MANIFEST_DIR")
+        ));
+        config.scanner.timeout_ms = SCANNER_TIMEOUT.as_millis() as u64;
+    })
+}
+
+/// Runs `body` through a transaction and returns its scanner result.
+fn scan(prism: &Prism, id: i64, body: &[u8]) -> Value {
+    let document = common::run(
+        prism,
+        id,
+        &format!("http://scanner.example.com/{}", id),
+        &[("Content-Type", "text/plain")],
+        body,
+    );
+    document["scanner_result"].clone()
+}
+
+#[test]
+fn processes_left_behind_do_not_hold_the_scan() {
+    let (prism, _serial) = setup();
+    let started = Instant::now();
+    let result = scan(&prism, 23101, b"hello");
+    assert!(
+        started.elapsed() < SCANNER_TIMEOUT * 3,
+        "{:?}",
+        started.elapsed()
+    );
+    assert_eq!(result["verdict"], "clean");
+    assert_eq!(result["exit_code"], 0);
+    assert_eq!(result["detail"], "");
+}
+
+#[test]
+fn clean_bodies_are_clean() {
+    let (prism, _serial) = setup();
+    let result = scan(&prism, 23001, b"nothing to see");
+    assert_eq!(result["verdict"], "clean");
+    assert_eq!(result["exit_code"], 0);
+    assert_eq!(result["detail"], "clean: 14 bytes");
+}
+
+#[test]
+fn bodies_with_the_signature_are_flagged() {
+    let (prism, _serial) = setup();
+    let result = scan(&prism, 23002, b"before PRISM-TEST-SIGNATURE after");
+    assert_eq!(result["verdict"], "flagged");
+    assert_eq!(result["exit_code"], 1);
+    assert_eq!(result["detail"], "stdin: Prism.Test.Signature FOUND");
+}
+
+#[test]
+fn hung_scanners_time_out() {
+    let (prism, _serial) = setup();
+    let started = Instant::now();
+    let body = b"scanned for too long".to_vec();
+    let output = common::relay(
+        &prism,
+        23102,
+        "http://scanner.example.com/23102",
+        &[("Content-Type", "text/plain")],
+        &body,
+    );
+    // Scans never hold the data path.
+    assert_eq!(output, body);
+    let result = common::document(23102)["scanner_result"].clone();
+    assert!(
+        started.elapsed() < SCANNER_TIMEOUT * 3,
+        "{:?}",
+        started.elapsed()
+    );
+    assert_eq!(result["verdict"], "timeout");
+    assert_eq!(result["exit_code"], Value::Null);
+}
+
+#[test]
+fn other_exit_codes_are_errors() {
+    let (prism, _serial) = setup();
+    let result = scan(&prism, 23103, b"anything");
+    assert_eq!(result["verdict"], "error");
+    assert_eq!(result["exit_code"], 2);
+    assert_eq!(result["detail"], "scanner database missing");
+}
+
+#[test]
+fn long_output_is_cut_to_the_detail() {
+    let (prism, _serial) = setup();
+    let started = Instant::now();
+    let result = scan(&prism, 23104, b"anything");
+    assert!(
+        started.elapsed() < SCANNER_TIMEOUT,
+        "{:?}",
+        started.elapsed()
+    );
+    assert_eq!(result["verdict"], "clean");
+    assert_eq!(result["detail"], "x".repeat(1024));
+}
+
+#[test]
+fn reloaded_scanner_applies_to_the_next_scan() {
+    let (prism, _serial) = setup();
+    assert_eq!(scan(&prism, 23201, b"hello")["detail"], "clean: 5 bytes");
+
+    let mut reloaded = (*config::get()).clone();
+    reloaded.scanner.command = Some("cat >/dev/null; echo reloaded {id}".to_string());
+    config::set(reloaded);
+    assert_eq!(scan(&prism, 23202, b"hello")["detail"], "reloaded 23202");
+
+    let mut reloaded = (*config::get()).clone();
+    reloaded.scanner.command = None;
+    config::set(reloaded);
+    assert_eq!(scan(&prism, 23203, b"hello"), Value::Null);
+}
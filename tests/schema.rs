@@ -0,0 +1,63 @@
+//! Schema version of documents, and documents built as older versions were.
+
+mod common;
+
+use common::setup;
+use prism::config::Config;
+use prism::Prism;
+use serde_json::Value;
+
+/// Runs a transaction through and returns its document.
+fn run(prism: &Prism, id: i64) -> Value {
+    common::run(
+        prism,
+        id,
+        "http://schema.example.com/",
+        &[("Content-Type", "text/plain")],
+        b"hello",
+    )
+}
+
+#[test]
+fn documents_record_their_schema_version() {
+    let (prism, _serial) = setup(|_| {});
+    let document = run(&prism, 9001);
+    assert_eq!(document["schema_version"], 9);
+    assert!(document["document_id"].is_string());
+    assert_eq!(document["has_body"], true);
+}
+
+#[test]
+fn older_schema_versions_leave_newer_fields_out() {
+    let (prism, _serial) = setup(|config| config.backend.schema_version = Some(1));
+    let document = run(&prism, 9002);
+    for field in [
+        "schema_version",
+        "document_id",
+        "run_id",
+        "has_body",
+        "compression_ratio",
+        "bytes_saved",
+    ] {
+        assert!(document.get(field).is_none(), "{}", field);
+    }
+    assert_eq!(document["method"], "GET");
+    assert_eq!(document["body"], "hello");
+}
+
+#[test]
+fn unknown_schema_versions_are_rejected() {
+    let _serial = common::serial();
+    for version in [0, 10] {
+        let mut config = Config::load(None).unwrap();
+        config.backend.schema_version = Some(version);
+        let errors = config.validated().unwrap_err();
+        assert!(
+            errors
+                .iter()
+                .any(|error| error.to_string().contains("backend.schema_version")),
+            "{:?}",
+            errors
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Bodies arriving a few bytes at a time, so the decoder reads less than
+//! the encoder asks for into buffers partly filled already, or many pieces
+//! queued at once, several taken by a read and some split across reads:
+//! the output and the captured body hold exactly the bytes decoded.
+
+mod common;
+
+use common::{gunzip, gzip, setup, TIMEOUT};
+use prism::memory;
+
+const HEADERS: [(&str, &str); 2] = [("Content-Type", "text/plain"), ("Content-Encoding", "gzip")];
+
+fn page() -> Vec<u8> {
+    (0..2000)
+        .map(|line| format!("line {} of a body read in short reads\n", line))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Relays `body` in pieces of the sizes of `pieces` in turn, handing output
+/// back through a buffer of `buffer` bytes after each, and returns the
+/// output.
+fn drip(id: i64, body: &[u8], pieces: &[usize], buffer: usize) -> Vec<u8> {
+    let (prism, _serial) = setup(|_| {});
+    let mut handle = prism.begin(id, "GET", "http://short.example.com/", &HEADERS);
+    handle.status(200);
+    let mut output = Vec::new();
+    let mut chunk = vec![0; buffer];
+    let mut offset = 0;
+    for size in pieces.iter().cycle() {
+        if offset == body.len() {
+            break;
+        }
+        let end = (offset + size).min(body.len());
+        handle.receive(&body[offset..end]).unwrap();
+        offset = end;
+        let size = handle.poll_output(&mut chunk);
+        output.extend_from_slice(&chunk[..size]);
+    }
+    handle.done();
+    while !handle.finished() {
+        let size = handle.poll_output(&mut chunk);
+        output.extend_from_slice(&chunk[..size]);
+    }
+    output
+}
+
+#[test]
+fn bodies_read_a_byte_at_a_time_are_relayed_whole() {
+    let page = page();
+    let output = drip(28001, &gzip(&page), &[1], 4096);
+    assert_eq!(gunzip(&output), page);
+    assert_eq!(memory::wait(28001, TIMEOUT).unwrap().body, page);
+}
+
+#[test]
+fn bodies_read_in_uneven_pieces_are_relayed_whole() {
+    let page = page();
+    let output = drip(28002, &gzip(&page), &[7, 1, 13, 2, 511, 3], 100);
+    assert_eq!(gunzip(&output), page);
+    assert_eq!(memory::wait(28002, TIMEOUT).unwrap().body, page);
+}
+
+#[test]
+fn unencoded_bodies_read_in_short_reads_are_relayed_whole() {
+    let (prism, _serial) = setup(|_| {});
+    let page = page();
+    let mut handle = prism.begin(
+        28003,
+        "GET",
+        "http://short.example.com/plain",
+        &[("Content-Type", "text/plain")],
+    );
+    handle.status(200);
+    let mut output = Vec::new();
+    let mut chunk = [0; 64];
+    for piece in page.chunks(5) {
+        handle.receive(piece).unwrap();
+        let size = handle.poll_output(&mut chunk);
+        output.extend_from_slice(&chunk[..size]);
+    }
+    handle.done();
+    output.extend(common::drain(&mut handle));
+    assert_eq!(output, page);
+    assert_eq!(memory::wait(28003, TIMEOUT).unwrap().body, page);
+}
+
+/// Receives `body` in pieces of the sizes of `pieces` in turn, each queued
+/// on its own, and hands output back only every `batch` pieces, so the
+/// decoder takes several queued pieces per read. Reads are at most
+/// `read_size` bytes, splitting the larger pieces across reads. Returns the
+/// output.
+fn batched(id: i64, body: &[u8], pieces: &[usize], batch: usize, read_size: usize) -> Vec<u8> {
+    let (prism, _serial) = setup(|config| {
+        config.limits.coalesce_size = 0;
+        config.limits.input_buffer_size = read_size;
+    });
+    let mut handle = prism.begin(id, "GET", "http://short.example.com/batched", &HEADERS);
+    handle.status(200);
+    let mut output = Vec::new();
+    let mut offset = 0;
+    for (index, size) in pieces.iter().cycle().enumerate() {
+        if offset == body.len() {
+            break;
+        }
+        let end = (offset + size).min(body.len());
+        handle.receive(&body[offset..end]).unwrap();
+        offset = end;
+        if index % batch == batch - 1 {
+            output.extend(common::take(&mut handle));
+        }
+    }
+    handle.done();
+    output.extend(common::drain(&mut handle));
+    output
+}
+
+#[test]
+fn many_queued_pieces_are_taken_by_one_read() {
+    let page = page();
+    let output = batched(28101, &gzip(&page), &[3, 1, 2], 50, 32 * 1024);
+    assert_eq!(gunzip(&output), page);
+    assert_eq!(memory::wait(28101, TIMEOUT).unwrap().body, page);
+}
+
+#[test]
+fn pieces_split_across_reads_carry_over_in_order() {
+    let page = page();
+    // Around the read size, over it and well past it, so what is left of
+    // a piece is read along with the pieces queued after it.
+    let pieces = [1, 250, 7, 99, 100, 101, 3, 1000];
+    let output = batched(28102, &gzip(&page), &pieces, 5, 100);
+    assert_eq!(gunzip(&output), page);
+    assert_eq!(memory::wait(28102, TIMEOUT).unwrap().body, page);
+}
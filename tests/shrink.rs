@@ -0,0 +1,93 @@
+//! The registry giving back the capacity a burst of transactions grew it
+//! to, once it stayed mostly empty for a while. The clock is manual, so the
+//! registry is only checked as often as a test advances it.
+
+mod common;
+
+use prism::clock::{self, ManualClock};
+use prism::config;
+use prism::{Prism, TransactionHandle};
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
+
+const FLOOR: usize = 64;
+const BURST: i64 = 5000;
+
+fn setup() -> (Prism, Arc<ManualClock>, MutexGuard<'static, ()>) {
+    let serial = common::serial();
+    let manual = ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap());
+    clock::set(manual.clone());
+    common::capture_logs();
+    let mut config = common::config();
+    config.limits.registry_capacity_floor = FLOOR;
+    (Prism::new(config).unwrap(), manual, serial)
+}
+
+fn begin(prism: &Prism, id: i64) -> TransactionHandle {
+    prism.begin(id, "GET", "http://shrink.example.com/", &[])
+}
+
+/// Begins `BURST` transactions at once from `first`, then ends them all.
+fn burst(prism: &Prism, first: i64) {
+    let handles: Vec<_> = (first..first + BURST).map(|id| begin(prism, id)).collect();
+    drop(handles);
+}
+
+/// Capacity of the transactions map, as last logged on cleanup.
+fn capacity() -> usize {
+    let cleanups = common::logged(|logged| logged.message.starts_with("Cleanup "));
+    let last = cleanups.last().expect("no cleanup logged");
+    let capacities = last.split(" @ ").nth(1).unwrap();
+    capacities.split(" & ").next().unwrap().parse().unwrap()
+}
+
+/// Advances the clock by `seconds`, then runs a transaction through so the
+/// registry is checked.
+fn idle(prism: &Prism, manual: &ManualClock, seconds: u64, id: i64) {
+    manual.advance(Duration::from_secs(seconds));
+    drop(begin(prism, id));
+}
+
+#[test]
+fn capacity_is_given_back_after_a_burst() {
+    let (prism, manual, _serial) = setup();
+    burst(&prism, 54001);
+    assert!(capacity() >= BURST as usize);
+
+    // Mostly empty from the first check, but not for long enough yet.
+    idle(&prism, &manual, 5, 59001);
+    idle(&prism, &manual, 20, 59002);
+    assert!(capacity() >= BURST as usize);
+    idle(&prism, &manual, 15, 59003);
+    assert!(capacity() <= 4 * FLOOR, "capacity {}", capacity());
+    let shrunk = common::logged(|logged| {
+        logged
+            .message
+            .starts_with("Shrunk the transaction registry")
+    });
+    assert_eq!(shrunk.len(), 1, "{:?}", shrunk);
+    clock::reset();
+}
+
+#[test]
+fn reloaded_floor_applies_to_the_next_check() {
+    let (prism, manual, _serial) = setup();
+    burst(&prism, 64001);
+    let grown = capacity();
+
+    // Above what the burst grew the registry to, so nothing is given back.
+    let mut reloaded = (*config::get()).clone();
+    reloaded.limits.registry_capacity_floor = 4 * BURST as usize;
+    config::set(reloaded);
+    idle(&prism, &manual, 5, 69001);
+    idle(&prism, &manual, 40, 69002);
+    assert_eq!(capacity(), grown);
+
+    let mut reloaded = (*config::get()).clone();
+    reloaded.limits.registry_capacity_floor = FLOOR;
+    config::set(reloaded);
+    idle(&prism, &manual, 5, 69003);
+    idle(&prism, &manual, 40, 69004);
+    assert!(capacity() <= 4 * FLOOR, "capacity {}", capacity());
+    clock::reset();
+}
@@ -0,0 +1,105 @@
+//! Transactions not done `limits.slow_threshold` after they started: warned
+//! about once, and persisted as slow. Driven on a manual clock.
+
+mod common;
+
+use common::{take, TIMEOUT};
+use prism::clock::{self, ManualClock};
+use prism::memory;
+use prism::{Prism, TransactionHandle};
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
+
+const THRESHOLD: Duration = Duration::from_secs(30);
+
+/// An instance reporting slow transactions, on a manual clock, keeping
+/// what it logs.
+fn setup() -> (Prism, Arc<ManualClock>, MutexGuard<'static, ()>) {
+    let manual = ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap());
+    let (prism, serial) = common::setup(|config| {
+        clock::set(manual.clone());
+        config.limits.coalesce_size = 0;
+        config.limits.slow_threshold = Some(THRESHOLD.as_secs());
+    });
+    common::capture_logs();
+    (prism, manual, serial)
+}
+
+fn begin(prism: &Prism, id: i64) -> TransactionHandle {
+    let handle = prism.begin(
+        id,
+        "GET",
+        &format!("http://slow.example.com/{}", id),
+        &[("Content-Type", "text/plain")],
+    );
+    handle.status(200);
+    handle
+}
+
+/// The warnings about transaction `id` being slow.
+fn slow_warnings(id: i64) -> Vec<String> {
+    let prefix = format!("Transaction {} for ", id);
+    common::warnings()
+        .into_iter()
+        .filter(|warning| warning.starts_with(&prefix) && warning.contains(" is slow: "))
+        .collect()
+}
+
+#[test]
+fn stalled_transactions_are_reported_once() {
+    let (prism, manual, _serial) = setup();
+    let id = 35001;
+    let mut handle = begin(&prism, id);
+    handle.receive(b"hello world\n").unwrap();
+    take(&mut handle);
+
+    manual.advance(THRESHOLD - Duration::from_secs(5));
+    handle.receive(b"hello world\n").unwrap();
+    assert!(slow_warnings(id).is_empty());
+
+    // Idle since the last chunk, found when another transaction starts.
+    manual.advance(Duration::from_secs(10));
+    drop(begin(&prism, 35101));
+    let warnings = slow_warnings(id);
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert_eq!(
+        warnings[0],
+        format!(
+            "Transaction {} for http://slow.example.com/{} is slow: not done after 35.0s, 24 bytes received, idle for 10.0s",
+            id, id
+        )
+    );
+
+    for _ in 0..3 {
+        manual.advance(THRESHOLD);
+        handle.receive(b"hello world\n").unwrap();
+    }
+    assert_eq!(slow_warnings(id).len(), 1);
+    handle.done();
+    take(&mut handle);
+    drop(handle);
+
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.json["slow"], true);
+    clock::reset();
+}
+
+#[test]
+fn transactions_done_in_time_are_not_slow() {
+    let (prism, manual, _serial) = setup();
+    let id = 35002;
+    let mut handle = begin(&prism, id);
+    handle.receive(b"hello world\n").unwrap();
+    handle.done();
+    take(&mut handle);
+
+    // Done already, however long the client takes to finish.
+    manual.advance(THRESHOLD * 2);
+    drop(begin(&prism, 35102));
+    drop(handle);
+
+    assert!(slow_warnings(id).is_empty());
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.json["slow"], false);
+    clock::reset();
+}
@@ -0,0 +1,184 @@
+//! The `tracing` spans of a transaction, kept by a subscriber of this test
+//! binary: a `transaction` span carrying the id, method and host, closed at
+//! cleanup, with `encode`, `decode` and `persist` spans under it and events
+//! for the first and last bytes received and sent.
+
+mod common;
+
+use common::{gzip, lock, TIMEOUT};
+use prism::memory;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+struct Recorded {
+    name: &'static str,
+    parent: Option<u64>,
+    fields: HashMap<String, String>,
+    references: usize,
+}
+
+/// Spans by id less one, and events with their span.
+static SPANS: Mutex<Vec<Recorded>> = Mutex::new(Vec::new());
+static EVENTS: Mutex<Vec<(Option<u64>, String)>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// Spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+struct Fields<'a>(&'a mut HashMap<String, String>);
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+/// Keeps every span and event.
+struct Capture;
+
+fn current() -> Option<u64> {
+    ENTERED.with(|entered| entered.borrow().last().copied())
+}
+
+impl Subscriber for Capture {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let parent = match attributes.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attributes.is_contextual() => current(),
+            None => None,
+        };
+        let mut fields = HashMap::new();
+        attributes.record(&mut Fields(&mut fields));
+        let mut spans = lock(&SPANS);
+        spans.push(Recorded {
+            name: attributes.metadata().name(),
+            parent,
+            fields,
+            references: 1,
+        });
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut spans = lock(&SPANS);
+        let recorded = &mut spans[span.into_u64() as usize - 1];
+        values.record(&mut Fields(&mut recorded.fields));
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let parent = match event.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if event.is_contextual() => current(),
+            None => None,
+        };
+        let mut fields = HashMap::new();
+        event.record(&mut Fields(&mut fields));
+        let message = fields.remove("message").unwrap_or_default();
+        lock(&EVENTS).push((parent, message));
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, _: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().pop());
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        lock(&SPANS)[span.into_u64() as usize - 1].references += 1;
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = lock(&SPANS);
+        let recorded = &mut spans[span.into_u64() as usize - 1];
+        recorded.references -= 1;
+        recorded.references == 0
+    }
+}
+
+/// Ids of the spans named `name`.
+fn named(name: &str) -> Vec<u64> {
+    lock(&SPANS)
+        .iter()
+        .enumerate()
+        .filter(|(_, span)| span.name == name)
+        .map(|(index, _)| index as u64 + 1)
+        .collect()
+}
+
+fn parent(span: u64) -> Option<u64> {
+    lock(&SPANS)[span as usize - 1].parent
+}
+
+#[test]
+fn transaction_spans_nest_their_phases() {
+    let _subscriber = tracing::subscriber::set_default(Capture);
+    let (prism, _serial) = common::setup(|config| config.limits.coalesce_size = 0);
+    let id = 63001;
+    let body = b"<p>span</p>\n".repeat(100);
+    common::relay(
+        &prism,
+        id,
+        "http://spans.example.com/page",
+        &[("Content-Type", "text/html"), ("Content-Encoding", "gzip")],
+        &gzip(&body),
+    );
+    memory::wait(id, TIMEOUT).expect("document persisted");
+
+    let transactions = named("transaction");
+    assert_eq!(transactions.len(), 1);
+    let transaction = transactions[0];
+    {
+        let spans = lock(&SPANS);
+        let recorded = &spans[transaction as usize - 1];
+        assert_eq!(recorded.parent, None);
+        assert_eq!(recorded.fields["id"], id.to_string());
+        assert_eq!(recorded.fields["method"], "GET");
+        assert_eq!(recorded.fields["host"], "spans.example.com");
+        // Closed at cleanup.
+        assert_eq!(recorded.references, 0);
+    }
+
+    let encodes = named("encode");
+    assert!(!encodes.is_empty());
+    for encode in &encodes {
+        assert_eq!(parent(*encode), Some(transaction));
+    }
+    let decodes = named("decode");
+    assert!(!decodes.is_empty());
+    for decode in decodes {
+        assert!(encodes.contains(&parent(decode).unwrap()));
+    }
+    assert_eq!(named("persist").len(), 1);
+    assert_eq!(parent(named("persist")[0]), Some(transaction));
+
+    let events: Vec<String> = lock(&EVENTS)
+        .iter()
+        .filter(|(parent, _)| *parent == Some(transaction))
+        .map(|(_, message)| message.clone())
+        .collect();
+    assert_eq!(
+        events,
+        [
+            "first byte received",
+            "body received",
+            "first byte sent",
+            "body sent"
+        ]
+    );
+}
@@ -0,0 +1,119 @@
+//! Captured bodies past `limits.spill_threshold` moved to a file under
+//! `limits.spool_dir`, read back for persistence and removed with the
+//! transaction.
+
+mod common;
+
+use common::{document, take, temporary, TIMEOUT};
+use prism::{memory, Prism};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::MutexGuard;
+
+const HEADERS: [(&str, &str); 1] = [("Content-Type", "application/octet-stream")];
+const THRESHOLD: usize = 1024 * 1024;
+const CHUNK: usize = 1024 * 1024;
+
+fn setup(spool_dir: &Path) -> (Prism, MutexGuard<'static, ()>) {
+    common::setup(|config| {
+        config.limits.spool_dir = Some(spool_dir.to_path_buf());
+        config.limits.spill_threshold = THRESHOLD;
+    })
+}
+
+/// A directory of its own for the spool files of a test.
+fn spool_dir() -> PathBuf {
+    let dir = temporary("spool");
+    std::fs::create_dir(&dir).unwrap();
+    dir
+}
+
+fn spooled(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect()
+}
+
+/// Chunk `index` of a synthetic body, no two chunks alike.
+fn chunk(index: usize) -> Vec<u8> {
+    (0..CHUNK)
+        .map(|offset| ((index * CHUNK + offset) % 251) as u8)
+        .collect()
+}
+
+#[test]
+fn large_bodies_are_spilled_and_persisted_whole() {
+    let dir = spool_dir();
+    let (prism, _serial) = setup(&dir);
+    let mut handle = prism.begin(27001, "GET", "http://spool.example.com/image.iso", &HEADERS);
+    handle.status(200);
+
+    let mut hash = Sha256::new();
+    let mut relayed = Sha256::new();
+    for index in 0..100 {
+        let data = chunk(index);
+        hash.update(&data);
+        handle.receive(&data).unwrap();
+        relayed.update(take(&mut handle));
+    }
+    let file = dir.join(format!("prism-{}-27001.body", std::process::id()));
+    assert_eq!(spooled(&dir), vec![file]);
+
+    handle.done();
+    relayed.update(common::drain(&mut handle));
+    drop(handle);
+    assert!(spooled(&dir).is_empty());
+
+    let persisted = memory::wait(27001, TIMEOUT).unwrap();
+    assert!(!persisted.truncated);
+    assert_eq!(persisted.body.len(), 100 * CHUNK);
+    let hash = hash.finalize();
+    assert_eq!(Sha256::digest(&persisted.body), hash);
+    assert_eq!(relayed.finalize(), hash);
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[test]
+fn spool_files_of_aborted_transactions_are_removed() {
+    let dir = spool_dir();
+    let (prism, _serial) = setup(&dir);
+    let mut handle = prism.begin(27002, "GET", "http://spool.example.com/aborted", &HEADERS);
+    handle.status(200);
+    for index in 0..2 {
+        handle.receive(&chunk(index)).unwrap();
+        take(&mut handle);
+    }
+    assert_eq!(spooled(&dir).len(), 1);
+
+    drop(handle);
+    assert!(spooled(&dir).is_empty());
+    assert!(memory::find(27002).is_none());
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[test]
+fn bodies_failing_to_spill_are_truncated() {
+    let dir = temporary("missing-spool");
+    let (prism, _serial) = setup(&dir);
+    let mut handle = prism.begin(27003, "GET", "http://spool.example.com/full", &HEADERS);
+    handle.status(200);
+    let mut body = Vec::new();
+    for index in 0..3 {
+        let data = chunk(index);
+        handle.receive(&data).unwrap();
+        body.extend(take(&mut handle));
+    }
+    handle.done();
+    body.extend(common::drain(&mut handle));
+    drop(handle);
+
+    // Relayed whole all the same.
+    assert_eq!(body.len(), 3 * CHUNK);
+    let persisted = memory::wait(27003, TIMEOUT).unwrap();
+    assert!(persisted.truncated);
+    assert!(persisted.body.len() <= THRESHOLD);
+    assert!(body.starts_with(&persisted.body));
+    assert_eq!(document(27003)["truncated"], true);
+    assert!(!dir.exists());
+}
@@ -0,0 +1,150 @@
+//! Clients that stop taking the output of their transaction: reported once
+//! output waited past `limits.stalled_consumer_timeout`, and no longer
+//! relayed when `limits.stalled_consumer_action` is `abort`.
+
+mod common;
+
+use common::{counter, state, take, TIMEOUT};
+use prism::clock::{self, ManualClock};
+use prism::memory;
+use prism::{Prism, TransactionHandle};
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
+
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An instance reporting stalled clients, on a manual clock.
+fn setup(action: &str) -> (Prism, Arc<ManualClock>, MutexGuard<'static, ()>) {
+    let manual = ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap());
+    let (prism, serial) = common::setup(|config| {
+        clock::set(manual.clone());
+        config.limits.coalesce_size = 0;
+        config.limits.stalled_consumer_timeout = Some(STALL_TIMEOUT.as_secs());
+        config.limits.stalled_consumer_action = action.to_string();
+    });
+    (prism, manual, serial)
+}
+
+fn begin(prism: &Prism, id: i64) -> TransactionHandle {
+    let handle = prism.begin(
+        id,
+        "GET",
+        &format!("http://stalled.example.com/{}", id),
+        &[("Content-Type", "text/plain")],
+    );
+    handle.status(200);
+    handle
+}
+
+#[test]
+fn clients_keeping_up_are_not_stalled() {
+    let (prism, manual, _serial) = setup("log");
+    let id = 19001;
+    let before = counter(&prism, "stalled_consumers");
+    let mut handle = begin(&prism, id);
+    let mut output = Vec::new();
+    for _ in 0..3 {
+        handle.receive(b"hello world\n").unwrap();
+        output.extend(take(&mut handle));
+        // The origin is slow, but nothing waits for the client meanwhile.
+        manual.advance(STALL_TIMEOUT * 2);
+    }
+    assert!(!state(&prism, id)["client_stalled"].as_bool().unwrap());
+    handle.done();
+    output.extend(take(&mut handle));
+    assert!(handle.finished());
+    drop(handle);
+
+    assert_eq!(output, b"hello world\n".repeat(3));
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert!(document.json.get("client_stalled").is_none());
+    assert_eq!(counter(&prism, "stalled_consumers"), before);
+    clock::reset();
+}
+
+#[test]
+fn stalled_clients_are_reported_and_still_relayed() {
+    let (prism, manual, _serial) = setup("log");
+    let id = 19002;
+    let before = counter(&prism, "stalled_consumers");
+    let mut handle = begin(&prism, id);
+    handle.receive(b"hello world\n").unwrap();
+    let mut output = take(&mut handle);
+
+    // The client stops taking output while the origin keeps sending.
+    handle.receive(b"held for the client\n").unwrap();
+    manual.advance(STALL_TIMEOUT - Duration::from_secs(1));
+    handle.receive(b"held for the client\n").unwrap();
+    assert!(!state(&prism, id)["client_stalled"].as_bool().unwrap());
+    manual.advance(Duration::from_secs(2));
+    handle.receive(b"held for the client\n").unwrap();
+    let stalled = state(&prism, id);
+    assert!(stalled["client_stalled"].as_bool().unwrap());
+    assert!(!stalled["relay_aborted"].as_bool().unwrap());
+    assert_eq!(stalled["bytes_sent"], 12);
+    assert!(stalled["output_waiting"].as_u64().unwrap() >= STALL_TIMEOUT.as_millis() as u64);
+    assert_eq!(counter(&prism, "stalled_consumers"), before + 1);
+
+    // Reported once, and relayed whole once the client resumes.
+    manual.advance(STALL_TIMEOUT * 2);
+    handle.receive(b"held for the client\n").unwrap();
+    assert_eq!(counter(&prism, "stalled_consumers"), before + 1);
+    handle.done();
+    output.extend(take(&mut handle));
+    assert!(handle.finished());
+    drop(handle);
+
+    let body = [
+        b"hello world\n".to_vec(),
+        b"held for the client\n".repeat(3),
+    ]
+    .concat();
+    assert_eq!(output, body);
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.json["client_stalled"], true);
+    assert_eq!(document.body, body);
+    clock::reset();
+}
+
+#[test]
+fn stalled_clients_are_aborted_when_configured() {
+    let (prism, manual, _serial) = setup("abort");
+    let id = 19003;
+    let before = counter(&prism, "stalled_consumers_aborted");
+    let mut handle = begin(&prism, id);
+    handle.receive(b"hello world\n").unwrap();
+    let mut output = take(&mut handle);
+
+    handle.receive(b"held for the client\n").unwrap();
+    manual.advance(STALL_TIMEOUT + Duration::from_secs(1));
+    handle.receive(b"held for the client\n").unwrap();
+    let aborted = state(&prism, id);
+    assert!(aborted["client_stalled"].as_bool().unwrap());
+    assert!(aborted["relay_aborted"].as_bool().unwrap());
+    // The output held for the client was dropped.
+    assert_eq!(aborted["footprint"]["queued"], 0);
+    assert!(aborted["output_waiting"].is_null());
+    assert_eq!(counter(&prism, "stalled_consumers_aborted"), before + 1);
+
+    // Nothing more is handed back, but the body is still captured.
+    handle.receive(b"received after\n").unwrap();
+    handle.done();
+    output.extend(take(&mut handle));
+    assert!(handle.finished());
+    drop(handle);
+
+    assert_eq!(output, b"hello world\n");
+    let document = memory::wait(id, TIMEOUT).expect("document persisted");
+    assert_eq!(document.json["client_stalled"], true);
+    assert_eq!(
+        document.body,
+        [
+            b"hello world\n".to_vec(),
+            b"held for the client\n".repeat(2),
+            b"received after\n".to_vec(),
+        ]
+        .concat()
+    );
+    assert_eq!(document.emitted_length, 12);
+    clock::reset();
+}
@@ -0,0 +1,122 @@
+//! StatsD emission to `telemetry.statsd_address`, received on a local UDP
+//! socket after driving transactions through the exported functions.
+//! Emission is set up once per process, so a single test drives every
+//! transaction.
+
+#![cfg(feature = "metrics")]
+
+mod common;
+
+use common::{configure, gzip, receive, MEMORY_BACKEND, TIMEOUT};
+use prism::memory;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+/// Largest datagram the library sends.
+const MAX_DATAGRAM: usize = 1432;
+
+/// The lines received until `done` is satisfied with them, or the timeout.
+fn receive_lines(socket: &UdpSocket, done: impl Fn(&[String]) -> bool) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut datagram = [0; 65536];
+    let deadline = Instant::now() + TIMEOUT;
+    while !done(&lines) && Instant::now() < deadline {
+        let Ok(size) = socket.recv(&mut datagram) else {
+            continue;
+        };
+        assert!(size <= MAX_DATAGRAM, "{} bytes datagram", size);
+        let text = std::str::from_utf8(&datagram[..size]).unwrap();
+        lines.extend(text.split('\n').map(str::to_string));
+    }
+    lines
+}
+
+/// The lines of `lines` for metric `name`, split into value, type and tags.
+fn metric<'a>(lines: &'a [String], name: &str) -> Vec<(&'a str, &'a str, &'a str)> {
+    lines
+        .iter()
+        .filter_map(|line| {
+            let (metric, rest) = line.split_once(':')?;
+            if metric != name {
+                return None;
+            }
+            let mut fields = rest.split('|');
+            let value = fields.next()?;
+            let kind = fields.next()?;
+            let tags = fields.next().and_then(|tags| tags.strip_prefix('#'))?;
+            Some((value, kind, tags))
+        })
+        .collect()
+}
+
+#[test]
+fn datagrams_carry_counters_timings_and_tags() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    configure(&format!(
+        "{}\n[logging]\nbackend = \"stderr\"\n\n[telemetry]\nstatsd_address = \"{}\"\nstatsd_prefix = \"test.\"\nstatsd_tags = true\n",
+        MEMORY_BACKEND,
+        socket.local_addr().unwrap()
+    ));
+    prism::init();
+
+    let page = b"<p>counted</p>\n".repeat(100);
+    let encoded = gzip(&page);
+    let encoding = [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+    common::begin(34001, "http://statsd.example.com/", &encoding);
+    receive(34001, &encoded);
+    common::finish(34001);
+    prism::cleanup(34001);
+    // Enough transactions for their lines to take several datagrams.
+    for id in 34101..34141 {
+        common::begin(id, "http://statsd.example.com/plain", &[]);
+        receive(id, &page);
+        common::finish(id);
+        prism::cleanup(id);
+    }
+    common::begin(34201, "http://statsd.example.com/aborted", &[]);
+    receive(34201, &page[..10]);
+    prism::cleanup(34201);
+    memory::wait(34001, TIMEOUT).expect("document persisted");
+
+    let lines = receive_lines(&socket, |lines| {
+        metric(lines, "test.transactions").len() == 42 && metric(lines, "test.persist").len() == 41
+    });
+
+    let gzip_tags = "encoding:gzip,status_class:2xx,outcome:completed";
+    let transactions = metric(&lines, "test.transactions");
+    assert_eq!(transactions.len(), 42);
+    assert!(transactions.contains(&("1", "c", gzip_tags)));
+    assert_eq!(
+        transactions
+            .iter()
+            .filter(|(_, _, tags)| *tags == "encoding:identity,status_class:2xx,outcome:completed")
+            .count(),
+        40
+    );
+    assert!(transactions
+        .iter()
+        .any(|(_, _, tags)| tags.ends_with("outcome:aborted")));
+
+    let received = encoded.len().to_string();
+    assert!(metric(&lines, "test.bytes.received").contains(&(received.as_str(), "c", gzip_tags)));
+    let durations = metric(&lines, "test.transaction.duration");
+    assert_eq!(durations.len(), 42);
+    for (value, kind, _) in durations {
+        assert_eq!(kind, "ms");
+        assert!(value.parse::<f64>().unwrap() >= 0.0, "{}", value);
+    }
+
+    let persisted = metric(&lines, "test.persist");
+    assert_eq!(persisted.len(), 41);
+    assert!(persisted
+        .iter()
+        .all(|metric| *metric == ("1", "c", "backend:memory,result:success")));
+    let latencies = metric(&lines, "test.persist.latency");
+    assert!(latencies
+        .iter()
+        .all(|(_, kind, tags)| *kind == "ms" && *tags == "backend:memory,result:success"));
+    prism::shutdown();
+}
@@ -0,0 +1,101 @@
+//! The summary line logged every `telemetry.summary_interval` seconds by
+//! the ticker `init()` starts, with the deltas since the previous one.
+//! Counters are shared by the whole process, so a single test drives every
+//! transaction.
+
+mod common;
+
+use common::{configure, receive, MEMORY_BACKEND, TIMEOUT};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The summaries logged so far, by key.
+fn summaries() -> Vec<HashMap<String, String>> {
+    common::logged(|logged| logged.message.starts_with("Summary "))
+        .iter()
+        .map(|line| {
+            line.split(' ')
+                .skip(1)
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        })
+        .collect()
+}
+
+/// Sum of `key` over `summaries`.
+fn total(summaries: &[HashMap<String, String>], key: &str) -> u64 {
+    summaries
+        .iter()
+        .map(|summary| summary[key].parse::<u64>().unwrap())
+        .sum()
+}
+
+/// Waits until the summaries account for `completed` transactions, and
+/// returns them.
+fn wait_for(completed: u64) -> Vec<HashMap<String, String>> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let summaries = summaries();
+        if total(&summaries, "completed") >= completed {
+            return summaries;
+        }
+        assert!(Instant::now() < deadline, "no summary of {}", completed);
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn exchange(id: i64, body: &[u8]) {
+    common::begin(
+        id,
+        "http://summary.example.com/",
+        &[("Content-Type", "text/plain")],
+    );
+    receive(id, body);
+    common::finish(id);
+    prism::cleanup(id);
+}
+
+#[test]
+fn summaries_report_deltas_until_shutdown() {
+    common::capture_logs();
+    configure(&format!(
+        "{}\n[telemetry]\nsummary_interval = 1\n",
+        MEMORY_BACKEND
+    ));
+    prism::init();
+
+    exchange(60001, b"first body");
+    exchange(60002, b"second");
+    let first = wait_for(2);
+    assert_eq!(total(&first, "completed"), 2);
+    assert_eq!(total(&first, "bytes_in"), 16);
+    assert_eq!(total(&first, "bytes_out"), 16);
+    assert_eq!(total(&first, "persist_failures"), 0);
+    let last = first.last().unwrap();
+    assert_eq!(last["interval"], "1s");
+    assert_eq!(last["active"], "0");
+
+    // Nothing happens meanwhile, so nothing is logged.
+    std::thread::sleep(Duration::from_millis(2500));
+    assert_eq!(summaries().len(), first.len());
+
+    // Still in flight, so only counted as active.
+    common::begin(60003, "http://summary.example.com/", &[]);
+    receive(60003, b"held");
+    exchange(60004, b"third");
+    let second = wait_for(3);
+    let since = &second[first.len()..];
+    assert_eq!(total(since, "completed"), 1);
+    assert_eq!(total(since, "bytes_in"), 5);
+    assert_eq!(total(since, "bytes_out"), 5);
+    assert_eq!(since.last().unwrap()["active"], "1");
+    common::finish(60003);
+    prism::cleanup(60003);
+
+    prism::shutdown();
+    let stopped = summaries().len();
+    exchange(60005, b"after shutdown");
+    std::thread::sleep(Duration::from_millis(1500));
+    assert_eq!(summaries().len(), stopped);
+}
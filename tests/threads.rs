@@ -0,0 +1,98 @@
+//! Transactions moving across threads: received on one, handed back on
+//! another, the way a proxy with a thread pool drives them.
+
+mod common;
+
+use common::{gunzip, gzip, setup, take, TIMEOUT};
+use prism::{memory, Prism, TransactionHandle};
+use std::thread;
+
+/// Fails the build unless `T` moves across threads.
+fn assert_send<T: Send>() {}
+
+fn page() -> Vec<u8> {
+    (0..3000)
+        .map(|row| format!("<li>threads row {}</li>\n", row))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Receives `body` on a thread of its own, then hands the output back on
+/// another, returning it.
+fn across_threads(prism: &Prism, id: i64, headers: &[(&str, &str)], body: Vec<u8>) -> Vec<u8> {
+    let handle = prism.begin(id, "GET", "http://threads.example.com/", headers);
+    handle.status(200);
+    let handle = thread::spawn(move || {
+        for chunk in body.chunks(1000) {
+            handle.receive(chunk).unwrap();
+        }
+        handle.done();
+        handle
+    })
+    .join()
+    .unwrap();
+    thread::spawn(move || {
+        let mut handle = handle;
+        common::drain(&mut handle)
+    })
+    .join()
+    .unwrap()
+}
+
+#[test]
+fn instances_and_handles_are_send() {
+    assert_send::<Prism>();
+    assert_send::<TransactionHandle>();
+}
+
+#[test]
+fn identity_bodies_are_relayed_across_threads() {
+    let (prism, _serial) = setup(|_| {});
+    let page = page();
+    let output = across_threads(
+        &prism,
+        41001,
+        &[("Content-Type", "text/html")],
+        page.clone(),
+    );
+    assert_eq!(output, page);
+    assert_eq!(memory::wait(41001, TIMEOUT).unwrap().body, page);
+}
+
+#[test]
+fn gzip_bodies_are_relayed_across_threads() {
+    let (prism, _serial) = setup(|_| {});
+    let page = page();
+    let headers = [("Content-Type", "text/html"), ("Content-Encoding", "gzip")];
+    let output = across_threads(&prism, 41101, &headers, gzip(&page));
+    assert_eq!(gunzip(&output), page);
+    assert_eq!(memory::wait(41101, TIMEOUT).unwrap().body, page);
+}
+
+#[test]
+fn threads_take_turns_on_a_transaction() {
+    let (prism, _serial) = setup(|config| config.limits.coalesce_size = 0);
+    let mut handle = prism.begin(41201, "GET", "http://threads.example.com/", &[]);
+    handle.status(200);
+    let mut output = Vec::new();
+    for chunk in [&b"first "[..], b"second ", b"third"] {
+        // Received on one thread, handed back on the next.
+        handle = thread::spawn(move || {
+            handle.receive(chunk).unwrap();
+            handle
+        })
+        .join()
+        .unwrap();
+        let (returned, taken) = thread::spawn(move || {
+            let taken = take(&mut handle);
+            (handle, taken)
+        })
+        .join()
+        .unwrap();
+        handle = returned;
+        output.extend(taken);
+    }
+    handle.done();
+    output.extend(common::drain(&mut handle));
+    assert_eq!(output, b"first second third");
+}
@@ -0,0 +1,84 @@
+//! Repeated errors logged at most once per `logging.throttle_window`, the
+//! next line after the window saying how many were suppressed meanwhile.
+//! Driven by bodies failing to decode, on a manual clock. The window and
+//! the errors seen are kept for the whole process, so a single test runs
+//! them all.
+
+mod common;
+
+use prism::clock::{self, ManualClock};
+use prism::Prism;
+use std::time::Duration;
+
+const WINDOW: Duration = Duration::from_secs(60);
+/// A gzip header followed by a deflate block of the reserved type.
+const CORRUPTED: &[u8] = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03\xff\xff\xff\xff";
+
+/// Relays `count` bodies failing to decode from `first`.
+fn fail(prism: &Prism, first: i64, count: i64) {
+    for id in first..first + count {
+        common::relay(
+            prism,
+            id,
+            "http://throttle.example.com/",
+            &[("Content-Encoding", "gzip")],
+            CORRUPTED,
+        );
+    }
+}
+
+/// The decode errors logged, with the suffix of each.
+fn logged() -> Vec<String> {
+    common::logged(|logged| logged.message.starts_with("Failed reading for id "))
+        .iter()
+        .map(|line| match line.find(" (...and ") {
+            Some(suffix) => line[suffix..].to_string(),
+            None => "".to_string(),
+        })
+        .collect()
+}
+
+#[test]
+fn errors_past_the_first_are_counted_until_the_window_rolls() {
+    let manual = ManualClock::at("2024-01-01T00:00:00Z".parse().unwrap());
+    let (prism, _serial) = common::setup(|config| {
+        clock::set(manual.clone());
+        config.logging.throttle_window = WINDOW.as_secs();
+    });
+    common::capture_logs();
+    fail(&prism, 61001, 5);
+    assert_eq!(logged(), [""]);
+
+    manual.advance(WINDOW - Duration::from_secs(1));
+    fail(&prism, 61101, 1);
+    assert_eq!(logged(), [""]);
+
+    manual.advance(Duration::from_secs(1));
+    fail(&prism, 61201, 2);
+    assert_eq!(logged(), ["", " (...and 5 similar errors suppressed)"]);
+
+    manual.advance(WINDOW);
+    fail(&prism, 61301, 1);
+    assert_eq!(
+        logged(),
+        [
+            "",
+            " (...and 5 similar errors suppressed)",
+            " (...and 1 similar error suppressed)"
+        ]
+    );
+
+    // Nothing suppressed in the window, so nothing added.
+    manual.advance(WINDOW);
+    fail(&prism, 61401, 1);
+    assert_eq!(logged().len(), 4);
+    assert_eq!(logged()[3], "");
+
+    // Large counts are separated in thousands.
+    manual.advance(WINDOW);
+    fail(&prism, 62001, 1235);
+    manual.advance(WINDOW);
+    fail(&prism, 64001, 1);
+    assert_eq!(logged()[5], " (...and 1,234 similar errors suppressed)");
+    clock::reset();
+}
@@ -0,0 +1,98 @@
+//! Per-transaction tracing: chunk level lines logged under `prism::trace`
+//! for the transactions traced with `set_trace()`, or from the start when
+//! their uri matches `logging.trace_uris`, and for those only.
+
+mod common;
+
+use common::{drain, take};
+use prism::{Prism, TransactionHandle};
+use std::sync::MutexGuard;
+
+/// The pattern is read by the first transaction, so every test uses it.
+fn setup() -> (Prism, MutexGuard<'static, ()>) {
+    let (prism, serial) = common::setup(|config| {
+        config.limits.coalesce_size = 0;
+        config.logging.trace_uris = Some("/traced/".to_string());
+    });
+    common::capture_logs();
+    (prism, serial)
+}
+
+fn begin(prism: &Prism, id: i64, path: &str) -> TransactionHandle {
+    let uri = format!("http://trace.example.com{}{}", path, id);
+    let handle = prism.begin(id, "GET", &uri, &[("Content-Type", "text/plain")]);
+    handle.status(200);
+    handle
+}
+
+/// The trace lines of transaction `id`.
+fn traced(id: i64) -> Vec<String> {
+    let prefix = format!("Transaction {} trace: ", id);
+    common::logged(|logged| logged.target == "prism::trace" && logged.message.starts_with(&prefix))
+}
+
+#[test]
+fn only_the_traced_transaction_is_traced() {
+    let (prism, _serial) = setup();
+    let mut quiet = begin(&prism, 59501, "/");
+    let mut traced_handle = begin(&prism, 59502, "/");
+    traced_handle.set_trace(true).unwrap();
+
+    // Interleaved, as concurrent transactions are.
+    for chunk in [&b"hello "[..], b"concurrent ", b"world"] {
+        quiet.receive(chunk).unwrap();
+        traced_handle.receive(chunk).unwrap();
+        take(&mut quiet);
+        take(&mut traced_handle);
+    }
+    quiet.done();
+    traced_handle.done();
+    drain(&mut quiet);
+    drain(&mut traced_handle);
+
+    assert!(traced(59501).is_empty(), "{:?}", traced(59501));
+    let lines = traced(59502);
+    for size in [6, 11, 5] {
+        assert!(
+            lines.contains(&format!("Transaction 59502 trace: received {} bytes", size)),
+            "{:?}",
+            lines
+        );
+    }
+    assert!(lines.iter().any(|line| line.contains("send returned ")));
+    let others = common::logged(|logged| {
+        logged.target == "prism::trace" && !logged.message.starts_with("Transaction 59502 ")
+    });
+    assert!(others.is_empty(), "{:?}", others);
+}
+
+#[test]
+fn tracing_stops_when_disabled() {
+    let (prism, _serial) = setup();
+    let mut handle = begin(&prism, 59601, "/");
+    handle.set_trace(true).unwrap();
+    handle.receive(b"traced").unwrap();
+    handle.set_trace(false).unwrap();
+    handle.receive(b"quiet").unwrap();
+    handle.done();
+    drain(&mut handle);
+
+    let lines = traced(59601);
+    assert!(lines.contains(&"Transaction 59601 trace: received 6 bytes".to_string()));
+    assert!(!lines.iter().any(|line| line.contains("received 5 bytes")));
+}
+
+#[test]
+fn matching_uris_are_traced_from_the_start() {
+    let (prism, _serial) = setup();
+    let mut matching = begin(&prism, 59701, "/traced/");
+    let mut other = begin(&prism, 59702, "/");
+    for handle in [&mut matching, &mut other] {
+        handle.receive(b"hello").unwrap();
+        handle.done();
+        drain(handle);
+    }
+
+    assert!(traced(59701).contains(&"Transaction 59701 trace: received 5 bytes".to_string()));
+    assert!(traced(59702).is_empty());
+}